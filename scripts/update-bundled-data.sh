@@ -0,0 +1,20 @@
+#!/bin/sh
+# This script refreshes the copies of the model specific parameters
+# and scene libraries that are built into govee2mqtt, and which are
+# used when they cannot be fetched at runtime.
+# Pass the SKUs whose scene libraries should be refreshed; the default
+# is to refresh those that are already present in assets/scenes.
+set -e
+cd "$(dirname "$0")/.."
+
+curl -sSf -o assets/model_specific_parameters.json \
+  https://raw.githubusercontent.com/AlgoClaw/Govee/refs/heads/main/decoded/v1.2/model_specific_parameters.json
+
+SKUS=${*:-$(ls assets/scenes | sed 's/\.json$//')}
+for sku in $SKUS ; do
+  curl -sSf -o "assets/scenes/$sku.json" \
+    -H "AppVersion: 5.6.01" \
+    "https://app2.govee.com/appsku/v1/light-effect-libraries?sku=$sku"
+done
+
+echo "Add any new SKUs to BUNDLED_SCENE_LIBRARIES in src/undoc_api.rs"
@@ -0,0 +1,315 @@
+//! Decoding of the BLE advertisements that are broadcast by Govee
+//! thermometer/hygrometer sensors and lights.
+//! The formats here are derived from observing the broadcasts of the
+//! various devices, as the format is not documented by Govee.
+use serde::Serialize;
+
+/// Manufacturer id used by the H5072, H5075 and similar sensors
+const MFR_GOVEE_H5075: u16 = 0xec88;
+/// Manufacturer id used by the H5179
+const MFR_GOVEE_H5179: u16 = 0x8801;
+/// Manufacturer id used by the H5100, H5101, H5102, H5104, H5174 and H5177
+const MFR_GOVEE_H5100: u16 = 0x0001;
+
+/// An advertisement received from a BLE device
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Advertisement {
+    /// The address, formatted as `AA:BB:CC:DD:EE:FF`
+    pub address: String,
+    pub data: Vec<u8>,
+    pub rssi: i8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+pub struct SensorReading {
+    pub temperature_celsius: f64,
+    pub humidity_percent: f64,
+    pub battery_percent: u8,
+}
+
+/// A decoded Govee sensor advertisement
+#[derive(Debug, Clone, PartialEq)]
+pub struct GoveeSensorAdvert {
+    pub address: String,
+    pub model: String,
+    pub name: String,
+    pub reading: SensorReading,
+}
+
+/// An advertisement from a Govee device that isn't a sensor, such
+/// as a light. Lights advertise for as long as they are powered.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct GoveeDeviceAdvert {
+    pub address: String,
+    pub model: String,
+}
+
+/// Parses the payload of an HCI LE Advertising Report event
+/// (that is, the bytes following the LE Meta Event subevent code)
+pub fn parse_le_advertising_report(data: &[u8]) -> Vec<Advertisement> {
+    let mut result = vec![];
+    let Some((&num_reports, mut data)) = data.split_first() else {
+        return result;
+    };
+
+    for _ in 0..num_reports {
+        // event_type(1) address_type(1) address(6) length(1)
+        if data.len() < 9 {
+            break;
+        }
+        let address = data[2..8]
+            .iter()
+            .rev()
+            .map(|b| format!("{b:02X}"))
+            .collect::<Vec<_>>()
+            .join(":");
+        let len = data[8] as usize;
+        let Some(ad) = data.get(9..9 + len) else {
+            break;
+        };
+        let rssi = data.get(9 + len).copied().unwrap_or(0) as i8;
+        result.push(Advertisement {
+            address,
+            data: ad.to_vec(),
+            rssi,
+        });
+        data = data.get(10 + len..).unwrap_or(&[]);
+    }
+
+    result
+}
+
+/// Extracts the local name and manufacturer specific data
+/// from the advertising data structures
+fn parse_ad_structures(mut data: &[u8]) -> (Option<String>, Vec<(u16, &[u8])>) {
+    let mut name = None;
+    let mut mfr = vec![];
+
+    while let Some((&len, rest)) = data.split_first() {
+        let len = len as usize;
+        if len == 0 || rest.len() < len {
+            break;
+        }
+        let (ad_type, value) = (rest[0], &rest[1..len]);
+        match ad_type {
+            // Shortened and Complete Local Name
+            0x08 | 0x09 => {
+                name.replace(String::from_utf8_lossy(value).to_string());
+            }
+            // Manufacturer Specific Data
+            0xff if value.len() >= 2 => {
+                mfr.push((u16::from_le_bytes([value[0], value[1]]), &value[2..]));
+            }
+            _ => {}
+        }
+        data = &rest[len..];
+    }
+
+    (name, mfr)
+}
+
+/// Extract the model number from names like `GVH5075_ABCD`
+/// or `Govee_H5179_ABCD`
+fn model_from_name(name: &str) -> Option<String> {
+    let idx = name.find("H5")?;
+    let model = name.get(idx..idx + 5)?;
+    Some(model.to_string())
+}
+
+/// Extract the model number from names like `ihoment_H6199_ABCD`
+/// or `Govee_H6076_ABCD`
+fn device_model_from_name(name: &str) -> Option<String> {
+    name.split('_')
+        .find(|part| {
+            part.len() == 5
+                && part.starts_with('H')
+                && part
+                    .bytes()
+                    .all(|b| b.is_ascii_uppercase() || b.is_ascii_digit())
+        })
+        .map(|model| model.to_string())
+}
+
+/// Decodes the packed representation used by the H5075 family, where
+/// temperature and humidity are encoded in a single 24-bit value
+fn decode_packed_temp_humidity(bytes: &[u8]) -> (f64, f64) {
+    let raw = u32::from_be_bytes([0, bytes[0], bytes[1], bytes[2]]);
+    let is_negative = raw & 0x80_0000 != 0;
+    let value = raw & 0x7f_ffff;
+    let temperature = (value / 1000) as f64 / 10.;
+    let humidity = (value % 1000) as f64 / 10.;
+    (
+        if is_negative {
+            -temperature
+        } else {
+            temperature
+        },
+        humidity,
+    )
+}
+
+fn decode_reading(mfr_id: u16, data: &[u8]) -> Option<SensorReading> {
+    match (mfr_id, data.len()) {
+        (MFR_GOVEE_H5075, 6..) => {
+            let (temperature_celsius, humidity_percent) = decode_packed_temp_humidity(&data[1..4]);
+            Some(SensorReading {
+                temperature_celsius,
+                humidity_percent,
+                battery_percent: data[4],
+            })
+        }
+        (MFR_GOVEE_H5179, 9..) => {
+            let temperature = i16::from_le_bytes([data[4], data[5]]);
+            let humidity = u16::from_le_bytes([data[6], data[7]]);
+            Some(SensorReading {
+                temperature_celsius: temperature as f64 / 100.,
+                humidity_percent: humidity as f64 / 100.,
+                battery_percent: data[8],
+            })
+        }
+        (MFR_GOVEE_H5100, 6) => {
+            let (temperature_celsius, humidity_percent) = decode_packed_temp_humidity(&data[2..5]);
+            Some(SensorReading {
+                temperature_celsius,
+                humidity_percent,
+                battery_percent: data[5],
+            })
+        }
+        _ => None,
+    }
+}
+
+/// Decode an advertisement, returning None if it isn't
+/// a recognized Govee sensor broadcast
+pub fn decode_govee_sensor(advert: &Advertisement) -> Option<GoveeSensorAdvert> {
+    let (name, mfr) = parse_ad_structures(&advert.data);
+    let name = name?;
+    let model = model_from_name(&name)?;
+
+    let reading = mfr
+        .iter()
+        .find_map(|(mfr_id, data)| decode_reading(*mfr_id, data))?;
+
+    Some(GoveeSensorAdvert {
+        address: advert.address.clone(),
+        model,
+        name,
+        reading,
+    })
+}
+
+/// Decode an advertisement from a Govee device other than a sensor,
+/// returning None if it isn't recognized as one
+pub fn decode_govee_device(advert: &Advertisement) -> Option<GoveeDeviceAdvert> {
+    let (name, _mfr) = parse_ad_structures(&advert.data);
+    let model = device_model_from_name(&name?)?;
+    Some(GoveeDeviceAdvert {
+        address: advert.address.clone(),
+        model,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn advert(name: &str, mfr_id: u16, mfr_data: &[u8]) -> Advertisement {
+        let mut data = vec![name.len() as u8 + 1, 0x09];
+        data.extend_from_slice(name.as_bytes());
+        data.push(mfr_data.len() as u8 + 3);
+        data.push(0xff);
+        data.extend_from_slice(&mfr_id.to_le_bytes());
+        data.extend_from_slice(mfr_data);
+        Advertisement {
+            address: "A4:C1:38:00:11:22".to_string(),
+            data,
+            rssi: -60,
+        }
+    }
+
+    #[test]
+    fn h5075() {
+        let decoded = decode_govee_sensor(&advert(
+            "GVH5075_1122",
+            MFR_GOVEE_H5075,
+            &[0x00, 0x03, 0xa1, 0x6e, 0x64, 0x00],
+        ))
+        .unwrap();
+        assert_eq!(decoded.model, "H5075");
+        assert_eq!(
+            decoded.reading,
+            SensorReading {
+                temperature_celsius: 23.7,
+                humidity_percent: 93.4,
+                battery_percent: 100
+            }
+        );
+
+        let below_zero = decode_govee_sensor(&advert(
+            "GVH5075_1122",
+            MFR_GOVEE_H5075,
+            &[0x00, 0x80, 0xc3, 0x54, 0x50, 0x00],
+        ))
+        .unwrap();
+        assert_eq!(below_zero.reading.temperature_celsius, -5.0);
+        assert_eq!(below_zero.reading.humidity_percent, 0.4);
+    }
+
+    #[test]
+    fn h5179() {
+        let decoded = decode_govee_sensor(&advert(
+            "Govee_H5179_1122",
+            MFR_GOVEE_H5179,
+            &[0x01, 0x00, 0x01, 0x01, 0xe4, 0x08, 0x45, 0x11, 0x64],
+        ))
+        .unwrap();
+        assert_eq!(decoded.model, "H5179");
+        assert_eq!(decoded.reading.temperature_celsius, 22.76);
+        assert_eq!(decoded.reading.humidity_percent, 44.21);
+        assert_eq!(decoded.reading.battery_percent, 100);
+    }
+
+    #[test]
+    fn device() {
+        let decoded = decode_govee_device(&advert("ihoment_H6199_1122", 0x8803, &[0xec, 0x00]));
+        assert_eq!(
+            decoded,
+            Some(GoveeDeviceAdvert {
+                address: "A4:C1:38:00:11:22".to_string(),
+                model: "H6199".to_string(),
+            })
+        );
+        assert_eq!(
+            decode_govee_device(&advert("Govee_H6076_1122", 0x8803, &[]))
+                .unwrap()
+                .model,
+            "H6076"
+        );
+        assert_eq!(decode_govee_device(&advert("Hue Lamp", 0x8803, &[])), None);
+        assert_eq!(
+            decode_govee_device(&advert("Phone_Hello", 0x8803, &[])),
+            None
+        );
+    }
+
+    #[test]
+    fn le_report() {
+        let ad = advert(
+            "GVH5100_1122",
+            MFR_GOVEE_H5100,
+            &[1, 1, 3, 0xa1, 0x6e, 0x50],
+        );
+        let mut report = vec![1, 0x00, 0x00, 0x22, 0x11, 0x00, 0x38, 0xc1, 0xa4];
+        report.push(ad.data.len() as u8);
+        report.extend_from_slice(&ad.data);
+        report.push(-60i8 as u8);
+
+        let adverts = parse_le_advertising_report(&report);
+        assert_eq!(adverts, vec![ad]);
+
+        let decoded = decode_govee_sensor(&adverts[0]).unwrap();
+        assert_eq!(decoded.model, "H5100");
+        assert_eq!(decoded.reading.temperature_celsius, 23.7);
+        assert_eq!(decoded.reading.battery_percent, 80);
+    }
+}
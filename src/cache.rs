@@ -13,14 +13,16 @@ use std::time::Duration;
 pub static CACHE: Lazy<ArcSwap<Cache>> =
     Lazy::new(|| open_cache().expect("failed to initialize cache").into());
 
-fn cache_file_name() -> PathBuf {
-    let cache_dir = std::env::var("GOVEE_CACHE_DIR")
+pub fn cache_dir() -> PathBuf {
+    std::env::var("GOVEE_CACHE_DIR")
         .ok()
         .map(PathBuf::from)
         .or_else(|| dirs_next::cache_dir())
-        .expect("failed to resolve cache dir");
+        .expect("failed to resolve cache dir")
+}
 
-    cache_dir.join("govee2mqtt-cache.sqlite")
+fn cache_file_name() -> PathBuf {
+    cache_dir().join("govee2mqtt-cache.sqlite")
 }
 
 fn open_cache() -> anyhow::Result<Arc<Cache>> {
@@ -46,6 +48,20 @@ pub fn purge_cache() -> anyhow::Result<()> {
     Ok(())
 }
 
+/// Copy any changes that are still in the write-ahead log into
+/// the cache file itself, so that it is complete when we exit
+pub fn flush_cache() -> anyhow::Result<()> {
+    if Lazy::get(&CACHE).is_none() {
+        return Ok(());
+    }
+    let cache_file = cache_file_name();
+    let conn = sqlite_cache::rusqlite::Connection::open(&cache_file)
+        .with_context(|| format!("opening cache file {cache_file:?}"))?;
+    conn.query_row("pragma wal_checkpoint(truncate)", [], |_| Ok(()))
+        .with_context(|| format!("checkpointing cache file {cache_file:?}"))?;
+    Ok(())
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 struct CacheEntry<T> {
     expires: DateTime<Utc>,
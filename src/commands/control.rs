@@ -0,0 +1,157 @@
+use crate::opt_env_var;
+use anyhow::Context;
+use reqwest::Url;
+use serde_json::Value as JsonValue;
+use std::time::Duration;
+
+/// Control a device via the HTTP API of a running `govee serve`,
+/// so that it can be addressed by name and controlled via
+/// whichever transport the service would use.
+#[derive(clap::Parser, Debug)]
+pub struct ControlCommand {
+    /// The URL of the running service.
+    /// The default is http://localhost:8056.
+    /// You may also set this via the GOVEE_SERVICE_URL
+    /// environment variable.
+    #[arg(long)]
+    url: Option<String>,
+
+    /// The name or id of the device
+    device: String,
+
+    #[command(subcommand)]
+    cmd: SubCommand,
+}
+
+#[derive(clap::Parser, Debug)]
+enum SubCommand {
+    On,
+    Off,
+    Brightness {
+        percent: u8,
+    },
+    Temperature {
+        kelvin: u32,
+    },
+    Color {
+        color: csscolorparser::Color,
+    },
+    Scene {
+        /// List available scenes
+        #[arg(long)]
+        list: bool,
+
+        /// Name of a scene to activate
+        #[arg(required_unless_present = "list")]
+        scene: Option<String>,
+    },
+}
+
+/// Build the URL for an API call, escaping the segments so
+/// that device and scene names may contain any characters
+fn api_url(base: &str, segments: &[&str]) -> anyhow::Result<Url> {
+    let mut url = Url::parse(base).with_context(|| format!("parsing service url {base}"))?;
+    url.path_segments_mut()
+        .map_err(|_| anyhow::anyhow!("{base} cannot be used as a service url"))?
+        .pop_if_empty()
+        .push("api")
+        .extend(segments);
+    Ok(url)
+}
+
+impl ControlCommand {
+    fn base_url(&self) -> anyhow::Result<String> {
+        match &self.url {
+            Some(url) => Ok(url.clone()),
+            None => Ok(opt_env_var("GOVEE_SERVICE_URL")?
+                .unwrap_or_else(|| "http://localhost:8056".to_string())),
+        }
+    }
+
+    async fn call(&self, segments: &[&str]) -> anyhow::Result<JsonValue> {
+        let url = api_url(&self.base_url()?, segments)?;
+        let response = reqwest::Client::builder()
+            .timeout(Duration::from_secs(60))
+            .build()?
+            .get(url.clone())
+            .send()
+            .await
+            .with_context(|| format!("requesting {url}. Is govee serve running?"))?;
+
+        let status = response.status();
+        let body: JsonValue = response
+            .json()
+            .await
+            .with_context(|| format!("parsing response from {url}"))?;
+        if !status.is_success() {
+            let msg = body["msg"].as_str().unwrap_or(status.as_str());
+            anyhow::bail!("{msg}");
+        }
+        Ok(body)
+    }
+
+    pub async fn run(&self, _args: &crate::Args) -> anyhow::Result<()> {
+        let device = self.device.as_str();
+        match &self.cmd {
+            SubCommand::On => {
+                self.call(&["device", device, "power", "on"]).await?;
+            }
+            SubCommand::Off => {
+                self.call(&["device", device, "power", "off"]).await?;
+            }
+            SubCommand::Brightness { percent } => {
+                self.call(&["device", device, "brightness", &percent.to_string()])
+                    .await?;
+            }
+            SubCommand::Temperature { kelvin } => {
+                self.call(&["device", device, "colortemp", &kelvin.to_string()])
+                    .await?;
+            }
+            SubCommand::Color { color } => {
+                self.call(&["device", device, "color", &color.to_hex_string()])
+                    .await?;
+            }
+            SubCommand::Scene { list: true, .. } => {
+                let scenes = self.call(&["device", device, "scenes"]).await?;
+                for scene in scenes.as_array().into_iter().flatten() {
+                    if let Some(name) = scene.as_str() {
+                        println!("{name}");
+                    }
+                }
+            }
+            SubCommand::Scene { scene, .. } => {
+                let scene = scene.as_deref().expect("scene is required");
+                self.call(&["device", device, "scene", scene]).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn control_api_url() {
+        assert_eq!(
+            api_url(
+                "http://localhost:8056",
+                &["device", "Floor Lamp", "power", "on"]
+            )
+            .unwrap()
+            .as_str(),
+            "http://localhost:8056/api/device/Floor%20Lamp/power/on"
+        );
+        assert_eq!(
+            api_url(
+                "http://hass.local:8056/",
+                &["device", "Lamp", "scene", "Rain/Snow"]
+            )
+            .unwrap()
+            .as_str(),
+            "http://hass.local:8056/api/device/Lamp/scene/Rain%2FSnow"
+        );
+    }
+}
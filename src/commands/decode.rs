@@ -0,0 +1,80 @@
+use crate::ble::Base64HexBytes;
+use std::io::Read;
+
+#[derive(clap::Parser, Debug)]
+pub struct DecodeCommand {
+    /// The SKU of the device that sent or received the packets.
+    /// If omitted, only the packets that are common to all devices
+    /// can be decoded.
+    #[arg(long, default_value = "*")]
+    sku: String,
+
+    /// The base64 encoded packets to decode. These may also be
+    /// pasted from a JSON capture such as `"command":["...","..."]`.
+    /// If none are specified, they are read from stdin.
+    packets: Vec<String>,
+}
+
+/// Extract the base64 encoded packets from text that may have been
+/// copied from a JSON array of packets
+fn split_packets(text: &str) -> Vec<String> {
+    text.split(|c: char| c.is_whitespace() || matches!(c, ',' | '"' | '[' | ']'))
+        .filter(|s| !s.is_empty())
+        .map(|s| s.to_string())
+        .collect()
+}
+
+impl DecodeCommand {
+    pub async fn run(&self, _args: &crate::Args) -> anyhow::Result<()> {
+        let text = if self.packets.is_empty() {
+            let mut text = String::new();
+            std::io::stdin().read_to_string(&mut text)?;
+            text
+        } else {
+            self.packets.join(" ")
+        };
+
+        let packets = split_packets(&text)
+            .iter()
+            .map(|s| Base64HexBytes::from_base64(s))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        if packets.is_empty() {
+            anyhow::bail!("No packets to decode");
+        }
+
+        // Packets such as scenes are decoded using the model
+        // specific parameters
+        crate::ble::load_model_specific_parameters().await;
+
+        for packet in &packets {
+            println!("{:02x?}", packet.bytes());
+            println!("{:#?}", packet.decode_for_sku(&self.sku));
+        }
+
+        // Multi-line commands, such as scenes, only make
+        // sense when decoded as a whole
+        if packets.len() > 1 {
+            println!();
+            println!("All {} packets together:", packets.len());
+            println!(
+                "{:#?}",
+                Base64HexBytes::concat(&packets).decode_for_sku(&self.sku)
+            );
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn packets_from_json() {
+        assert_eq!(
+            split_packets(r#"["MwEBAAAAAAAAAAAAAAAAAAAAADM=", "MwUEewA="]"#),
+            vec!["MwEBAAAAAAAAAAAAAAAAAAAAADM=", "MwUEewA="]
+        );
+    }
+}
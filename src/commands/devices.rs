@@ -0,0 +1,181 @@
+use crate::commands::list::discover_devices;
+use crate::service::device::Device;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+
+#[derive(clap::Parser, Debug)]
+pub struct DevicesCommand {
+    /// Don't wait for LAN discovery
+    #[arg(long)]
+    skip_lan: bool,
+
+    /// Output the devices as a JSON array
+    #[arg(long, conflicts_with = "csv")]
+    json: bool,
+
+    /// Output the devices as CSV
+    #[arg(long)]
+    csv: bool,
+}
+
+#[derive(Serialize, Debug)]
+struct DeviceRow {
+    sku: String,
+    id: String,
+    name: String,
+    room: Option<String>,
+    ip: Option<String>,
+    transports: Vec<&'static str>,
+    firmware: Option<String>,
+    last_seen: Option<DateTime<Utc>>,
+}
+
+impl DeviceRow {
+    const HEADERS: [&'static str; 8] = [
+        "SKU",
+        "ID",
+        "NAME",
+        "ROOM",
+        "IP",
+        "TRANSPORTS",
+        "FIRMWARE",
+        "LAST SEEN",
+    ];
+
+    fn new(device: &Device) -> Self {
+        Self {
+            sku: device.sku.clone(),
+            id: device.id.clone(),
+            name: device.name(),
+            room: device.room_name().map(|room| room.to_string()),
+            ip: device.ip_addr().map(|ip| ip.to_string()),
+            transports: device.transports(),
+            firmware: device.firmware_version().map(|v| v.to_string()),
+            last_seen: device.last_reachable(),
+        }
+    }
+
+    fn fields(&self) -> [String; 8] {
+        [
+            self.sku.clone(),
+            self.id.clone(),
+            self.name.clone(),
+            self.room.clone().unwrap_or_default(),
+            self.ip.clone().unwrap_or_default(),
+            self.transports.join(","),
+            self.firmware.clone().unwrap_or_default(),
+            self.last_seen
+                .map(|t| t.to_rfc3339_opts(chrono::SecondsFormat::Secs, true))
+                .unwrap_or_default(),
+        ]
+    }
+}
+
+fn csv_field(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
+
+fn format_csv(rows: &[DeviceRow]) -> String {
+    let mut result = String::new();
+    let lines = std::iter::once(DeviceRow::HEADERS.map(|h| h.to_lowercase().replace(' ', "_")))
+        .chain(rows.iter().map(|row| row.fields()));
+    for line in lines {
+        let fields: Vec<String> = line.iter().map(|f| csv_field(f)).collect();
+        result.push_str(&fields.join(","));
+        result.push('\n');
+    }
+    result
+}
+
+fn format_table(rows: &[DeviceRow]) -> String {
+    let lines: Vec<[String; 8]> = std::iter::once(DeviceRow::HEADERS.map(|h| h.to_string()))
+        .chain(rows.iter().map(|row| row.fields()))
+        .collect();
+
+    let mut widths = [0; 8];
+    for line in &lines {
+        for (width, field) in widths.iter_mut().zip(line) {
+            *width = (*width).max(field.chars().count());
+        }
+    }
+
+    let mut result = String::new();
+    for line in &lines {
+        let fields: Vec<String> = line
+            .iter()
+            .zip(widths)
+            .map(|(field, width)| format!("{field:<width$}"))
+            .collect();
+        result.push_str(fields.join("  ").trim_end());
+        result.push('\n');
+    }
+    result
+}
+
+impl DevicesCommand {
+    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        let state = discover_devices(args, self.skip_lan).await?;
+
+        let mut devices = state.devices().await;
+        devices.sort_by_key(|d| (d.room_name().map(|name| name.to_string()), d.name()));
+        let rows: Vec<DeviceRow> = devices.iter().map(DeviceRow::new).collect();
+
+        if self.json {
+            println!("{}", serde_json::to_string_pretty(&rows)?);
+        } else if self.csv {
+            print!("{}", format_csv(&rows));
+        } else {
+            print!("{}", format_table(&rows));
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn device_output_formats() {
+        let rows = vec![
+            DeviceRow {
+                sku: "H6072".to_string(),
+                id: "AA:BB:CC:DD:EE:FF:00:11".to_string(),
+                name: "Floor Lamp, Left".to_string(),
+                room: Some("Lounge".to_string()),
+                ip: Some("10.0.0.5".to_string()),
+                transports: vec!["lan", "iot"],
+                firmware: Some("1.00.19".to_string()),
+                last_seen: None,
+            },
+            DeviceRow {
+                sku: "H7160".to_string(),
+                id: "AA:BB:CC:DD:EE:FF:00:22".to_string(),
+                name: "Humidifier".to_string(),
+                room: None,
+                ip: None,
+                transports: vec!["platform"],
+                firmware: None,
+                last_seen: None,
+            },
+        ];
+
+        assert_eq!(
+            format_csv(&rows),
+            "sku,id,name,room,ip,transports,firmware,last_seen\n\
+             H6072,AA:BB:CC:DD:EE:FF:00:11,\"Floor Lamp, Left\",Lounge,10.0.0.5,\"lan,iot\",1.00.19,\n\
+             H7160,AA:BB:CC:DD:EE:FF:00:22,Humidifier,,,platform,,\n"
+        );
+
+        let table = format_table(&rows);
+        let lines: Vec<&str> = table.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[0].starts_with("SKU    ID                       NAME"));
+        assert!(lines[2].ends_with("platform"));
+    }
+}
@@ -0,0 +1,29 @@
+use crate::commands::list::discover_devices;
+
+/// Print the information that is known about a device as JSON,
+/// with addresses and credentials redacted, so that it can be
+/// attached to an issue report
+#[derive(clap::Parser, Debug)]
+pub struct DiagnoseCommand {
+    /// The name or id of the device
+    #[arg(long)]
+    device: String,
+
+    /// Don't wait for LAN discovery
+    #[arg(long)]
+    skip_lan: bool,
+}
+
+impl DiagnoseCommand {
+    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        let state = discover_devices(args, self.skip_lan).await?;
+        let device = state
+            .resolve_device(&self.device)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("device '{}' not found", self.device))?;
+
+        let diagnostics = state.device_diagnostics(&device).await;
+        println!("{}", serde_json::to_string_pretty(&diagnostics)?);
+        Ok(())
+    }
+}
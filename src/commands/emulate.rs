@@ -0,0 +1,52 @@
+use crate::fake_device::{run_fake_devices, FakeDevice};
+use std::net::Ipv4Addr;
+use std::sync::Arc;
+
+#[derive(clap::Parser, Debug)]
+pub struct EmulateCommand {
+    /// The SKU of the devices to emulate
+    #[arg(long, default_value = "H6159")]
+    sku: String,
+
+    /// The number of devices to emulate
+    #[arg(long, default_value_t = 1)]
+    count: u8,
+
+    /// The address of the first device. Each subsequent device
+    /// is assigned the next address. The address must be local
+    /// to this host; the default is in the loopback range, which
+    /// means that govee2mqtt must run on the same host and be
+    /// told to `--scan` the emulated addresses.
+    #[arg(long, default_value = "127.0.0.10")]
+    ip: Ipv4Addr,
+}
+
+impl EmulateCommand {
+    pub async fn run(&self, _args: &crate::Args) -> anyhow::Result<()> {
+        let base = u32::from(self.ip);
+        let mut devices = vec![];
+        for i in 0..self.count {
+            let ip = base
+                .checked_add(i as u32)
+                .map(Ipv4Addr::from)
+                .ok_or_else(|| anyhow::anyhow!("Ran out of addresses after {}", self.ip))?;
+            let id = format!("AA:BB:CC:DD:EE:FF:00:{:02X}", i + 1);
+            devices.push(Arc::new(FakeDevice::new(&self.sku, &id, ip.into())));
+        }
+
+        for device in &devices {
+            let info = device.info();
+            println!(
+                "{ip:<16} {sku:<7} {id}",
+                ip = info.ip,
+                sku = info.sku,
+                id = info.device
+            );
+        }
+        let addresses: Vec<String> = devices.iter().map(|d| d.info().ip.to_string()).collect();
+        println!();
+        println!("Discover them with: GOVEE_LAN_SCAN={}", addresses.join(","));
+
+        run_fake_devices(devices).await
+    }
+}
@@ -1,20 +1,44 @@
-use crate::ble::{Base64HexBytes, SetSceneCode};
-use crate::lan_api::{Client, DiscoOptions, LanDevice as ActualLanDevice};
-use crate::govee_scenes::get_parsed_scenes_for_sku;
+use crate::ble::Base64HexBytes;
+use crate::commands::list::discover_devices;
+use crate::lan_api::{Client, LanDevice as ActualLanDevice};
+use crate::scene_resolver::{resolve_scene, scenes_for_sku, SceneSelector};
+use crate::service::scene_preview::{preview_order, ScenePreviewStatus};
 use anyhow::{anyhow, Context}; // Added Context
 use clap_num::maybe_hex;
+use serde_json::Value as JsonValue;
 use std::net::IpAddr;
+use std::time::Duration;
+use tokio::task::JoinSet;
+use tokio::time::Instant;
 
 #[derive(clap::Parser, Debug)]
+#[command(group(clap::ArgGroup::new("target").required(true).multiple(true)))]
 pub struct LanControlCommand {
-    #[arg(long)]
-    pub ip: IpAddr,
+    /// The IP address of the device.
+    /// Can be specified multiple times.
+    #[arg(long, group = "target")]
+    pub ip: Vec<IpAddr>,
+
+    /// The name of the device. The name is resolved to an id
+    /// via the Govee APIs, then the device is located via
+    /// LAN discovery. Can be specified multiple times.
+    #[arg(long, group = "target")]
+    pub name: Vec<String>,
+
+    /// The id of the device, which is located via LAN discovery.
+    /// Can be specified multiple times.
+    #[arg(long, group = "target")]
+    pub id: Vec<String>,
+
+    /// Control every device that responds to LAN discovery
+    #[arg(long, group = "target")]
+    pub all: bool,
 
     #[command(subcommand)]
     cmd: SubCommand,
 }
 
-#[derive(clap::Parser, Debug)]
+#[derive(clap::Parser, Debug, Clone)]
 enum SubCommand {
     On,
     Off,
@@ -31,20 +55,155 @@ enum SubCommand {
         #[arg(value_parser=maybe_hex::<u8>)]
         data: Vec<u8>,
     },
+    /// Send a packet using a codec from a codec file.
+    /// The fields are specified as NAME=VALUE
+    Packet {
+        name: String,
+        #[arg(value_parser=parse_field)]
+        fields: Vec<(String, JsonValue)>,
+    },
     Scene {
         #[arg(long)]
         list: bool,
-        #[arg(required_unless_present = "list")]
+        /// If there is no scene with a matching name,
+        /// use the one with the most similar name
+        #[arg(long)]
+        fuzzy: bool,
+        /// Activate the scene with this numeric code rather than
+        /// looking it up by name
+        #[arg(long, value_parser=maybe_hex::<u16>, conflicts_with_all = ["list", "scene"])]
+        code: Option<u16>,
+        /// The param id of the scene with the specified code
+        #[arg(long, requires = "code")]
+        param_id: Option<u32>,
+        #[arg(required_unless_present_any = ["list", "code"])]
         scene: Option<String>,
     },
+    /// Cycle through each of the scenes for the device, printing
+    /// the name and code of each, to help identify them
+    PreviewScenes {
+        /// How many seconds to show each scene for
+        #[arg(long, default_value_t = 5.0)]
+        dwell: f64,
+        /// The name of the scene with which to begin
+        #[arg(long)]
+        start: Option<String>,
+    },
+}
+
+/// Parse NAME=VALUE, where VALUE is JSON such as a number or boolean
+fn parse_field(s: &str) -> anyhow::Result<(String, JsonValue)> {
+    let (name, value) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow!("expected NAME=VALUE, but got {s}"))?;
+    let value = serde_json::from_str(value).unwrap_or_else(|_| JsonValue::from(value));
+    Ok((name.to_string(), value))
 }
 
 impl LanControlCommand {
-    pub async fn run(&self, _args: &crate::Args) -> anyhow::Result<()> {
-        let (client, _scan) = Client::new(DiscoOptions::default()).await?;
-        let device: ActualLanDevice = client.scan_ip(self.ip).await?;
+    /// Locate the devices, either by probing their IP addresses,
+    /// or by waiting for them to respond to LAN discovery
+    async fn resolve_devices(&self, args: &crate::Args) -> anyhow::Result<Vec<ActualLanDevice>> {
+        let mut devices = vec![];
+        // Only one client can listen for the responses, so the same
+        // one is used to probe the IP addresses and for discovery
+        let (client, mut scan) = Client::new(args.lan_disco_args.to_disco_options()?).await?;
+        for ip in &self.ip {
+            devices.push(client.scan_ip(*ip).await?);
+        }
+        if self.id.is_empty() && self.name.is_empty() && !self.all {
+            return Ok(devices);
+        }
+
+        let mut ids = self.id.clone();
+        if !self.name.is_empty() {
+            let state = discover_devices(args, true).await?;
+            for name in &self.name {
+                let device = state
+                    .resolve_device(name)
+                    .await
+                    .ok_or_else(|| anyhow!("device '{name}' not found"))?;
+                ids.push(device.id);
+            }
+        }
+        let is_wanted =
+            |device: &ActualLanDevice| ids.iter().any(|id| device.device.eq_ignore_ascii_case(id));
+
+        let timeout = args.lan_disco_args.disco_timeout()?;
+        let deadline = Instant::now() + Duration::from_secs(timeout);
+        while let Ok(Some(device)) = tokio::time::timeout_at(deadline, scan.recv()).await {
+            if devices.iter().any(|d| d.device == device.device) {
+                continue;
+            }
+            if self.all || is_wanted(&device) {
+                devices.push(device);
+            }
+            // When --all is used, we wait for as many as will respond
+            if !self.all
+                && ids
+                    .iter()
+                    .all(|id| devices.iter().any(|d| d.device.eq_ignore_ascii_case(id)))
+            {
+                break;
+            }
+        }
+
+        let missing: Vec<&str> = ids
+            .iter()
+            .filter(|id| !devices.iter().any(|d| d.device.eq_ignore_ascii_case(id)))
+            .map(|id| id.as_str())
+            .collect();
+        if !missing.is_empty() {
+            anyhow::bail!(
+                "{} did not respond to LAN discovery within {timeout} seconds",
+                missing.join(", ")
+            );
+        }
+        if devices.is_empty() {
+            anyhow::bail!("no devices responded to LAN discovery within {timeout} seconds");
+        }
+        Ok(devices)
+    }
 
-        match &self.cmd {
+    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        let devices = self.resolve_devices(args).await?;
+        if let [device] = devices.as_slice() {
+            return Self::run_command(&self.cmd, device).await;
+        }
+
+        let total = devices.len();
+        let mut tasks = JoinSet::new();
+        for device in devices {
+            let cmd = self.cmd.clone();
+            tasks.spawn(async move {
+                let result = Self::run_command(&cmd, &device).await;
+                (device, result)
+            });
+        }
+
+        let mut failures = 0;
+        while let Some(result) = tasks.join_next().await {
+            let (device, result) = result?;
+            let label = format!("{} {} {}", device.sku, device.device, device.ip);
+            match result {
+                Ok(()) => println!("ok      {label}"),
+                Err(err) => {
+                    failures += 1;
+                    println!("FAILED  {label}: {err:#}");
+                }
+            }
+        }
+
+        if failures > 0 {
+            anyhow::bail!("{failures} of {total} device(s) failed");
+        }
+        println!("{total} device(s) succeeded");
+        Ok(())
+    }
+
+    /// Send the command to a single device
+    async fn run_command(cmd: &SubCommand, device: &ActualLanDevice) -> anyhow::Result<()> {
+        match cmd {
             SubCommand::On => {
                 device.send_turn(true).await?;
             }
@@ -63,68 +222,85 @@ impl LanControlCommand {
                     .send_color_rgb(crate::lan_api::DeviceColor { r, g, b })
                     .await?;
             }
-            SubCommand::Scene { list, scene } => {
-                let parsed_scenes = get_parsed_scenes_for_sku(&device.sku).await
-                    .with_context(|| format!("Failed to get parsed scenes for SKU {}", device.sku))?;
-
+            SubCommand::Scene {
+                code: Some(code),
+                param_id,
+                ..
+            } => {
+                crate::ble::load_model_specific_parameters().await;
+                let resolved = resolve_scene(
+                    &device.sku,
+                    &SceneSelector::Code {
+                        code: *code,
+                        param_id: *param_id,
+                    },
+                )
+                .await?;
+                println!("Sending scene code {code}. Encoded: {:?}", resolved.commands);
+                device.send_real(resolved.commands).await?;
+            }
+            SubCommand::Scene {
+                list,
+                fuzzy,
+                scene,
+                param_id,
+                ..
+            } => {
+                // clap doesn't enforce `requires` when a conflicting
+                // argument is present, so check it here
+                if param_id.is_some() {
+                    anyhow::bail!("--param-id can only be used together with --code");
+                }
+                crate::ble::load_model_specific_parameters().await;
                 if *list {
+                    let parsed_scenes = scenes_for_sku(&device.sku).await
+                        .with_context(|| format!("Failed to get scenes for SKU {}", device.sku))?;
                     if parsed_scenes.is_empty() {
                         println!("No scenes found for device SKU: {}", device.sku);
                     } else {
                         println!("Available scenes for {}:", device.sku);
-                        for scene_info in parsed_scenes {
+                        for scene_info in parsed_scenes.iter() {
                             println!("- {}", scene_info.display_name);
                         }
                     }
                 } else {
-                    let desired_scene_name_str = scene.as_ref().ok_or_else(|| anyhow!("Scene name must be provided if not listing"))?;
-
-                    if let Some(target_scene) = parsed_scenes.iter().find(|s| s.display_name == *desired_scene_name_str) {
-                        log::info!("Setting scene '{}' for device {} via LAN.", target_scene.display_name, device.sku);
-
-                        if let Some(ref override_commands_b64) = target_scene.override_cmd_b64 {
-                            log::info!("Using override LAN/BLE commands for scene: {}", target_scene.display_name);
-                            // The send_real function on LanDevice expects Vec<String> of base64 commands.
-                            // This matches the structure of override_cmd_b64.
-                            device.send_real(override_commands_b64.clone()).await?;
-                            println!("Successfully set scene '{}' using override commands.", target_scene.display_name);
-                        } else if !target_scene.api_scence_param.is_empty() {
-                            log::info!("Encoding API LAN/BLE commands for scene: {}", target_scene.display_name);
-                            let scene_to_set = SetSceneCode::new(
-                                target_scene.scene_code,
-                                target_scene.api_scence_param.clone(), // Corrected field name
-                                target_scene.sku.clone(), // This should be device.sku
-                            );
-
-                            // SetSceneCode::encode() returns a single Vec<u8> which might be multiple packets.
-                            // Base64HexBytes::encode_for_sku also returns a Base64HexBytes struct that wraps this.
-                            // The `base64()` method on Base64HexBytes then chunks it into Vec<String>.
-                            match Base64HexBytes::encode_for_sku(&device.sku, &scene_to_set) {
-                                Ok(encoded_command_container) => {
-                                    let commands_b64 = encoded_command_container.base64();
-                                    if !commands_b64.is_empty() {
-                                        device.send_real(commands_b64).await?;
-                                        println!("Successfully set scene '{}' using encoded API parameters.", target_scene.display_name);
-                                    } else {
-                                        anyhow::bail!("SetSceneCode::encode produced an empty command set for scene '{}'", target_scene.display_name);
-                                    }
-                                }
-                                Err(e) => {
-                                    anyhow::bail!("Failed to encode scene '{}' for LAN control: {}", target_scene.display_name, e);
-                                }
-                            }
-                        } else {
-                            anyhow::bail!("Scene '{}' found, but it has neither override commands nor API parameters for encoding.", target_scene.display_name);
-                        }
-                    } else {
-                        let available_scene_names: Vec<&str> = parsed_scenes.iter().map(|s| s.display_name.as_str()).collect();
-                        anyhow::bail!(
-                            "Scene '{}' not found for device SKU '{}'. Available scenes: {:?}",
-                            desired_scene_name_str,
-                            device.sku,
-                            available_scene_names
-                        );
-                    }
+                    let name = scene.as_ref().ok_or_else(|| anyhow!("Scene name must be provided if not listing"))?;
+                    let resolved = resolve_scene(
+                        &device.sku,
+                        &SceneSelector::Name {
+                            name: name.to_string(),
+                            fuzzy: *fuzzy,
+                        },
+                    )
+                    .await?;
+                    let name = resolved.name.as_deref().unwrap_or(name).to_string();
+                    log::info!("Setting scene '{name}' for device {} via LAN.", device.sku);
+                    device.send_real(resolved.commands).await?;
+                    println!("Successfully set scene '{name}'.");
+                }
+            }
+            SubCommand::PreviewScenes { dwell, start } => {
+                let dwell = Duration::try_from_secs_f64(*dwell)
+                    .map_err(|err| anyhow!("invalid --dwell {dwell}: {err}"))?;
+                crate::ble::load_model_specific_parameters().await;
+                let all_scenes = scenes_for_sku(&device.sku)
+                    .await
+                    .with_context(|| format!("Failed to get scenes for SKU {}", device.sku))?;
+                let scenes = preview_order(&all_scenes, start.as_deref())?;
+                let offset = all_scenes.len() - scenes.len();
+                for (index, scene) in scenes.iter().enumerate() {
+                    let status = ScenePreviewStatus::new(offset + index, all_scenes.len(), scene);
+                    println!("{} {status}", device.device);
+                    let resolved = resolve_scene(
+                        &device.sku,
+                        &SceneSelector::Name {
+                            name: scene.display_name.to_string(),
+                            fuzzy: false,
+                        },
+                    )
+                    .await?;
+                    device.send_real(resolved.commands).await?;
+                    tokio::time::sleep(dwell).await;
                 }
             }
             SubCommand::Command { data } => {
@@ -135,6 +311,13 @@ impl LanControlCommand {
                 println!("Sending custom command. Encoded: {:?}", encoded);
                 device.send_real(encoded).await?;
             }
+            SubCommand::Packet { name, fields } => {
+                let fields = fields.iter().cloned().collect();
+                let encoded =
+                    Base64HexBytes::encode_custom_for_sku(&device.sku, name, &fields)?.base64();
+                println!("Sending {name}. Encoded: {encoded:?}");
+                device.send_real(encoded).await?;
+            }
         }
         Ok(())
     }
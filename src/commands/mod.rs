@@ -1,7 +1,14 @@
+pub mod control;
+pub mod decode;
+pub mod devices;
+pub mod diagnose;
+pub mod emulate;
 pub mod http_control;
 pub mod lan_control;
 pub mod lan_disco;
 pub mod list;
 pub mod list_http;
+pub mod scenes;
+pub mod secrets;
 pub mod serve;
 pub mod undoc;
@@ -0,0 +1,231 @@
+use crate::govee_scenes::{
+    get_api_scenes_for_sku, validate_override_data, JsonSceneOverrideEntry, ParsedScene,
+};
+use crate::scene_resolver::encode_scene;
+use anyhow::Context;
+use std::collections::HashMap;
+use std::path::PathBuf;
+
+/// The length of each BLE packet, including its checksum
+const PACKET_LEN: usize = 20;
+
+#[derive(clap::Parser, Debug)]
+pub struct ScenesCommand {
+    #[command(subcommand)]
+    cmd: SubCommand,
+}
+
+#[derive(clap::Parser, Debug)]
+enum SubCommand {
+    /// Fetch the scenes for a SKU from the Govee API and write them
+    /// as a scene override file, so that the scenes continue to
+    /// work without access to the Govee API.
+    Export {
+        /// The SKU whose scenes should be exported
+        #[arg(long)]
+        sku: String,
+
+        /// Where to write the override file. To be found, the file
+        /// must be placed in the scene override directory and its
+        /// name must contain the SKU, such as `H6065.json`.
+        /// If omitted, the file is written to stdout.
+        #[arg(long)]
+        out: Option<PathBuf>,
+    },
+
+    /// Check an override file for problems, such as malformed
+    /// packets or duplicate scene names, before it is used
+    Validate {
+        /// The override file to check
+        file: PathBuf,
+    },
+}
+
+/// Encode each of the scenes into an override entry, logging
+/// and skipping those that cannot be encoded
+fn override_entries(sku: &str, scenes: &[ParsedScene]) -> Vec<JsonSceneOverrideEntry> {
+    scenes
+        .iter()
+        .filter_map(|scene| match encode_scene(sku, scene) {
+            Ok(cmd_b64) => Some(JsonSceneOverrideEntry {
+                name: scene.display_name.to_string(),
+                cmd_b64,
+            }),
+            Err(err) => {
+                log::warn!("Skipping scene '{}': {err:#}", scene.display_name);
+                None
+            }
+        })
+        .collect()
+}
+
+/// Check the contents of an override file, returning a description
+/// of each problem that is found, along with the number of scenes
+fn diagnose_override_data(data: &[u8]) -> (usize, Vec<String>) {
+    let entries: Vec<JsonSceneOverrideEntry> = match serde_json::from_slice(data) {
+        Ok(entries) => entries,
+        Err(err) => return (0, vec![format!("invalid override file: {err}")]),
+    };
+    let mut problems = vec![];
+    let mut names: HashMap<String, usize> = HashMap::new();
+
+    for (idx, entry) in entries.iter().enumerate() {
+        let scene = format!("scene #{} '{}'", idx + 1, entry.name);
+        if entry.name.trim().is_empty() {
+            problems.push(format!("{scene}: the name is empty"));
+        } else if let Some(first) = names.insert(entry.name.to_ascii_lowercase(), idx) {
+            problems.push(format!(
+                "{scene}: the name is the same as that of scene #{}",
+                first + 1
+            ));
+        }
+        if entry.cmd_b64.is_empty() {
+            problems.push(format!("{scene}: cmd_b64 has no packets"));
+        }
+
+        for (line, cmd) in entry.cmd_b64.iter().enumerate() {
+            let packet = format!("{scene}: packet {} '{cmd}'", line + 1);
+            let bytes = match data_encoding::BASE64.decode(cmd.as_bytes()) {
+                Ok(bytes) => bytes,
+                Err(err) => {
+                    problems.push(format!("{packet}: invalid base64: {err}"));
+                    continue;
+                }
+            };
+            if bytes.len() != PACKET_LEN {
+                problems.push(format!(
+                    "{packet}: is {} bytes long, but should be {PACKET_LEN}",
+                    bytes.len()
+                ));
+                continue;
+            }
+            let checksum = crate::ble::calculate_checksum(&bytes);
+            if bytes[PACKET_LEN - 1] != checksum {
+                problems.push(format!(
+                    "{packet}: the checksum is {:02x}, but should be {checksum:02x}",
+                    bytes[PACKET_LEN - 1]
+                ));
+            }
+        }
+    }
+
+    (entries.len(), problems)
+}
+
+impl ScenesCommand {
+    pub async fn run(&self, _args: &crate::Args) -> anyhow::Result<()> {
+        match &self.cmd {
+            SubCommand::Export { sku, out } => {
+                // Scenes are encoded using the model specific parameters
+                crate::ble::load_model_specific_parameters().await;
+
+                let scenes = get_api_scenes_for_sku(sku)
+                    .await
+                    .with_context(|| format!("fetching the scenes for {sku}"))?;
+                let entries = override_entries(sku, &scenes);
+                anyhow::ensure!(!entries.is_empty(), "No scenes could be encoded for {sku}");
+
+                let data = serde_json::to_string_pretty(&entries)?;
+                validate_override_data(data.as_bytes())
+                    .context("validating the exported override file")?;
+
+                match out {
+                    Some(path) => {
+                        std::fs::write(path, &data)
+                            .with_context(|| format!("writing scenes to {path:?}"))?;
+                        eprintln!(
+                            "Exported {} of {} scenes for {sku} to {path:?}",
+                            entries.len(),
+                            scenes.len()
+                        );
+                    }
+                    None => println!("{data}"),
+                }
+                Ok(())
+            }
+            SubCommand::Validate { file } => {
+                let data = std::fs::read(file).with_context(|| format!("reading {file:?}"))?;
+                let (count, problems) = diagnose_override_data(&data);
+                for problem in &problems {
+                    println!("{}: {problem}", file.display());
+                }
+                anyhow::ensure!(
+                    problems.is_empty(),
+                    "{} problem(s) found in {file:?}",
+                    problems.len()
+                );
+                println!("{}: {count} scenes OK", file.display());
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn entries() {
+        let scene = |name: &str, param: &str| ParsedScene {
+            display_name: name.to_string(),
+            scene_code: 10,
+            api_scence_param: param.to_string(),
+            sku: "H6072".to_string(),
+            source_api_scene_name: name.to_string(),
+            source_api_effect_name: None,
+            source_api_scene_id: 1010,
+            source_api_scence_param_id: 2010,
+            override_cmd_b64: None,
+            category: None,
+        };
+        let overridden = ParsedScene {
+            override_cmd_b64: Some(vec!["MwUEewA=".to_string()]),
+            ..scene("Custom", "")
+        };
+
+        // A scene without parameters or override commands is skipped
+        let entries = override_entries("H6072", &[overridden, scene("Broken", "")]);
+        assert_eq!(entries.len(), 1);
+        assert_eq!(entries[0].name, "Custom");
+        assert_eq!(entries[0].cmd_b64, vec!["MwUEewA="]);
+
+        let data = serde_json::to_string(&entries).unwrap();
+        assert_eq!(validate_override_data(data.as_bytes()).unwrap(), 1);
+    }
+
+    #[test]
+    fn diagnose() {
+        let good = crate::ble::Base64HexBytes::with_bytes(vec![0x33, 0x05, 0x04, 0x7b]).base64();
+        let good = &good[0];
+        let mut bad_checksum = data_encoding::BASE64.decode(good.as_bytes()).unwrap();
+        bad_checksum[19] ^= 0xff;
+        let bad_checksum = data_encoding::BASE64.encode(&bad_checksum);
+
+        let data = serde_json::json!([
+            {"name": "Sunrise", "cmd_b64": [good]},
+            {"name": "sunrise", "cmd_b64": [good, "MwUEewA=", "not base64!"]},
+            {"name": " ", "cmd_b64": []},
+            {"name": "Forest", "cmd_b64": [bad_checksum]},
+        ]);
+        let (count, problems) = diagnose_override_data(data.to_string().as_bytes());
+        assert_eq!(count, 4);
+        assert_eq!(problems.len(), 6, "{problems:#?}");
+        assert!(problems[0].starts_with("scene #2 'sunrise': the name is the same"));
+        assert!(problems[1].contains("packet 2 'MwUEewA=': is 5 bytes long"));
+        assert!(problems[2].contains("packet 3 'not base64!': invalid base64"));
+        assert!(problems[5].starts_with("scene #4 'Forest': packet 1"));
+        assert!(problems[5].ends_with("but should be 49"));
+
+        let (count, problems) = diagnose_override_data(b"[{\"name\": \"Sunrise\"}]");
+        assert_eq!(count, 0);
+        assert!(problems[0].contains("missing field `cmd_b64`"));
+
+        let (_, problems) = diagnose_override_data(
+            serde_json::json!([{"name": "Sunrise", "cmd_b64": [good]}])
+                .to_string()
+                .as_bytes(),
+        );
+        assert!(problems.is_empty());
+    }
+}
@@ -0,0 +1,49 @@
+use crate::secrets::{secret_store, EMAIL_SECRET, PASSWORD_SECRET};
+
+/// Manage the encrypted secrets file
+#[derive(clap::Parser, Debug)]
+pub struct SecretsCommand {
+    #[command(subcommand)]
+    cmd: SubCommand,
+}
+
+#[derive(clap::Parser, Debug)]
+enum SubCommand {
+    /// Store the Govee account email and password, as passed via
+    /// --govee-email and --govee-password or the environment,
+    /// in the secrets file, so that they need not be passed again
+    StoreCredentials {},
+    /// Remove everything from the secrets file, including any
+    /// stored login token
+    Clear {},
+}
+
+impl SecretsCommand {
+    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        let store = secret_store().ok_or_else(|| {
+            anyhow::anyhow!(
+                "Please specify the secrets file either via the \
+                --secrets-file parameter or by setting $GOVEE_SECRETS_FILE"
+            )
+        })?;
+
+        match &self.cmd {
+            SubCommand::StoreCredentials {} => {
+                let email = args.undoc_args.email()?;
+                let password = args.undoc_args.password()?;
+                // Verify the credentials before we keep them
+                let client = crate::undoc_api::GoveeUndocumentedApi::new(&email, &password);
+                client.login_account().await?;
+
+                store.set(EMAIL_SECRET, &email).await?;
+                store.set(PASSWORD_SECRET, &password).await?;
+                println!("Stored credentials for {email} in {:?}", store.path());
+            }
+            SubCommand::Clear {} => {
+                store.clear().await?;
+                println!("Cleared {:?}", store.path());
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,128 +1,293 @@
-use crate::lan_api::Client as LanClient;
-use crate::service::device::Device;
-use crate::service::hass::spawn_hass_integration;
+use crate::ble::{load_model_specific_parameters, MODEL_SPECIFIC_PARAMETERS_REFRESH};
+use crate::hass_mqtt::instance::EntityInstance;
+use crate::hass_mqtt::sensor::ModelParamsSensor;
+use crate::lan_api::{truthy, Client as LanClient};
+use crate::opt_env_var;
+use crate::scene_sync::{run_scene_sync, SceneSyncOptions};
+use crate::service::account_sync::run_account_sync;
+use crate::service::ble_scanner::run_ble_scanner;
+use crate::service::discovery_overrides::load_discovery_override_file;
+use crate::service::firmware::run_firmware_check;
+use crate::service::hass::{set_topic_layout, spawn_hass_integration};
 use crate::service::http::run_http_server;
 use crate::service::iot::start_iot_client;
+use crate::service::light_group::load_light_groups_file;
+use crate::service::mdns::run_mdns_responder;
+use crate::service::packet_capture::{enable_packet_capture, run_packet_capture};
+use crate::service::poll_intervals::load_poll_intervals_file;
+use crate::service::poll_scheduler::PollScheduler;
+use crate::service::power::load_power_model_file;
+use crate::service::scene_filter::{load_scene_filter_file, SceneFilters};
+use crate::service::schedules::run_schedule_sync;
+use crate::service::shutdown::wait_for_termination;
 use crate::service::state::StateHandle;
+use crate::service::transport::load_transport_priority_file;
+use crate::service::wakeup::{load_alarms_file, run_alarm_scheduler};
+use crate::service::watchdog::{run_watchdog, Heartbeat, Subsystem, SupervisedTask};
 use crate::version_info::govee_version;
 use anyhow::Context;
-use chrono::Utc;
 use once_cell::sync::Lazy;
-use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
-use tokio::time::{sleep, Duration};
+use tokio::time::{sleep_until, Duration, Instant};
 
-pub const POLL_INTERVAL: Lazy<chrono::Duration> = Lazy::new(|| chrono::Duration::seconds(900));
+pub static POLL_INTERVAL: Lazy<chrono::Duration> = Lazy::new(|| chrono::Duration::seconds(900));
 
 #[derive(clap::Parser, Debug)]
 pub struct ServeCommand {
     /// The port on which the HTTP API will listen
     #[arg(long, default_value_t = 8056)]
     http_port: u16,
-}
-
-async fn poll_single_device(state: &StateHandle, device: &Device) -> anyhow::Result<()> {
-    let now = Utc::now();
-
-    if device.is_ble_only_device() == Some(true) {
-        // We can't poll this device, we have no ble support
-        return Ok(());
-    }
-
-    let poll_interval = device.preferred_poll_interval();
-
-    let can_update = match &device.last_polled {
-        None => true,
-        Some(last) => now - last > poll_interval,
-    };
-
-    if !can_update {
-        return Ok(());
-    }
-
-    let device_state = device.device_state();
-    let needs_update = match &device_state {
-        None => true,
-        Some(state) => now - state.updated > poll_interval,
-    };
-
-    if !needs_update {
-        return Ok(());
-    }
 
-    let needs_platform = device.needs_platform_poll();
+    /// A JSON file describing wake-up alarms to load at startup.
+    /// You may also set this via the GOVEE_ALARMS_FILE environment
+    /// variable.
+    #[arg(long)]
+    alarms_file: Option<PathBuf>,
+
+    /// A JSON file describing groups of devices that should
+    /// be presented as a single light.
+    /// You may also set this via the GOVEE_LIGHT_GROUPS_FILE
+    /// environment variable.
+    #[arg(long)]
+    light_groups_file: Option<PathBuf>,
+
+    /// A JSON file that specifies the order in which the LAN, IoT
+    /// and Platform APIs are tried when controlling devices, per SKU
+    /// and per device. You may also set this via the
+    /// GOVEE_TRANSPORT_PRIORITY_FILE environment variable.
+    #[arg(long)]
+    transport_priority_file: Option<PathBuf>,
+
+    /// A JSON file that specifies how frequently devices are polled
+    /// via the LAN, IoT and Platform APIs, globally, per SKU and per
+    /// device. You may also set this via the GOVEE_POLL_INTERVALS_FILE
+    /// environment variable.
+    #[arg(long)]
+    poll_intervals_file: Option<PathBuf>,
+
+    /// A JSON file that restricts and orders the scenes that are
+    /// offered for devices, per SKU and per device. You may also set
+    /// this via the GOVEE_SCENE_FILTER_FILE environment variable.
+    #[arg(long)]
+    scene_filter_file: Option<PathBuf>,
+
+    /// A JSON file describing the power drawn by lights, per SKU
+    /// and per device, from which their power consumption is
+    /// estimated. You may also set this via the
+    /// GOVEE_POWER_MODEL_FILE environment variable.
+    #[arg(long)]
+    power_model_file: Option<PathBuf>,
+
+    /// A JSON file of merge patches, per SKU and per device, that
+    /// are applied to the hass discovery payloads before they are
+    /// published. You may also set this via the
+    /// GOVEE_DISCOVERY_OVERRIDE_FILE environment variable.
+    #[arg(long)]
+    discovery_override_file: Option<PathBuf>,
+
+    /// A directory containing scene override JSON files. Changes
+    /// to the files are picked up without restarting.
+    /// The default is /JSONs.
+    /// You may also set this via the GOVEE_SCENE_OVERRIDE_DIR
+    /// environment variable.
+    #[arg(long)]
+    scene_override_dir: Option<PathBuf>,
+
+    /// Periodically download scene override files from this URL.
+    /// The URL must return a JSON list of the files, either as an
+    /// array of file names relative to the URL, or in the form
+    /// returned by the GitHub contents API. Local override files
+    /// take precedence over the downloaded files.
+    /// You may also set this via the GOVEE_SCENE_SYNC_URL
+    /// environment variable.
+    #[arg(long)]
+    scene_sync_url: Option<String>,
+
+    /// How many hours to wait between syncs of the scene
+    /// override files. The default is 24 hours.
+    /// You may also set this via the GOVEE_SCENE_SYNC_INTERVAL
+    /// environment variable.
+    #[arg(long)]
+    scene_sync_interval: Option<u64>,
+
+    /// Passively scan for the BLE advertisements of Govee
+    /// thermo-hygrometers using the specified bluetooth adapter
+    /// index (eg: 0 for hci0). This requires CAP_NET_RAW and
+    /// CAP_NET_ADMIN. You may also set this via the GOVEE_BLE_ADAPTER
+    /// environment variable.
+    #[arg(long)]
+    ble_adapter: Option<u16>,
+
+    /// How many seconds to wait for a prior control request for
+    /// a device to complete before failing a new request.
+    /// The default is 30 seconds.
+    /// You may also set this via the GOVEE_CONTROL_TIMEOUT
+    /// environment variable.
+    #[arg(long)]
+    control_timeout: Option<u64>,
+
+    /// How many milliseconds to wait between the intermediate steps
+    /// that are sent via the LAN API when a light command specifies
+    /// a transition. The default is 250 milliseconds.
+    /// You may also set this via the GOVEE_TRANSITION_INTERVAL
+    /// environment variable.
+    #[arg(long)]
+    transition_interval: Option<u64>,
+
+    /// The minimum number of milliseconds between successive light
+    /// commands for a device. Commands that arrive more rapidly than
+    /// this, such as those from a color wheel, are merged so that
+    /// the device receives the latest value. 0 disables the delay.
+    /// The default is 250 milliseconds.
+    /// You may also set this via the GOVEE_MIN_COMMAND_INTERVAL
+    /// environment variable.
+    #[arg(long)]
+    min_command_interval: Option<u64>,
+
+    /// Don't advertise this service via mDNS.
+    /// You may also set GOVEE_NO_MDNS=true via the environment.
+    #[arg(long)]
+    no_mdns: bool,
+
+    /// Browse for other instances of this service via mDNS,
+    /// and warn if any are found.
+    /// You may also set GOVEE_MDNS_BROWSE=true via the environment.
+    #[arg(long)]
+    mdns_browse: bool,
+
+    /// Don't send any control commands to devices; instead, log
+    /// them and simulate their effect on the state of the device.
+    /// You may also set GOVEE_DRY_RUN=true via the environment.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Report the expected effect of control commands to Home
+    /// Assistant immediately, rather than waiting for the device
+    /// to report its new state. The state is corrected if the
+    /// device reports something different.
+    /// You may also set GOVEE_OPTIMISTIC=true via the environment.
+    #[arg(long)]
+    optimistic: bool,
+
+    /// Report the state of devices, but reject requests to control
+    /// them. This can be changed at runtime via the Read Only switch
+    /// in Home Assistant.
+    /// You may also set GOVEE_READ_ONLY=true via the environment.
+    #[arg(long)]
+    read_only: bool,
+
+    /// Report the state of the specified device, but reject requests
+    /// to control it. The device may be specified by name, id or
+    /// IP address. Can be specified multiple times.
+    /// You may also set GOVEE_READ_ONLY_DEVICES=a,b via the environment.
+    #[arg(long = "read-only-device")]
+    read_only_devices: Vec<String>,
+
+    /// Publish the raw LAN and IoT traffic for each device to
+    /// gv2mqtt/debug/DEVICE, to help with decoding the protocol
+    /// for unsupported devices.
+    /// You may also set GOVEE_DEBUG_PACKETS=true via the environment.
+    #[arg(long)]
+    debug_packets: bool,
+}
 
-    // Don't interrogate via HTTP if we can use the LAN.
-    // If we have LAN and the device is stale, it is likely
-    // offline and there is little sense in burning up request
-    // quota to the platform API for it
-    if device.lan_device.is_some() && !needs_platform {
-        log::trace!("LAN-available device {device} needs a status update; it's likely offline.");
-        return Ok(());
-    }
+impl ServeCommand {
+    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        log::info!("Starting service. version {}", govee_version());
+        // The layout must be known before any topics are computed
+        set_topic_layout(args.hass_args.topic_layout()?)?;
+        let state = Arc::new(crate::service::state::State::new());
 
-    if !needs_platform {
-        if state.poll_iot_api(&device).await? {
-            return Ok(());
+        tokio::select! {
+            result = self.serve(args, state.clone()) => result,
+            result = wait_for_termination() => {
+                result?;
+                state.shutdown().await;
+                Ok(())
+            }
         }
     }
 
-    state.poll_platform_api(&device).await?;
+    async fn serve(&self, args: &crate::Args, state: StateHandle) -> anyhow::Result<()> {
+        let shutdown = state.shutdown_token();
 
-    Ok(())
-}
+        let control_timeout = match self.control_timeout {
+            Some(secs) => Some(secs),
+            None => opt_env_var::<u64>("GOVEE_CONTROL_TIMEOUT")?,
+        };
+        if let Some(secs) = control_timeout {
+            state.set_control_timeout(Duration::from_secs(secs)).await;
+        }
 
-async fn periodic_state_poll(state: StateHandle) -> anyhow::Result<()> {
-    sleep(Duration::from_secs(20)).await;
-    loop {
-        for d in state.devices().await {
-            if let Err(err) = poll_single_device(&state, &d).await {
-                log::error!("while polling {d}: {err:#}");
-            }
+        let transition_interval = match self.transition_interval {
+            Some(ms) => Some(ms),
+            None => opt_env_var::<u64>("GOVEE_TRANSITION_INTERVAL")?,
+        };
+        if let Some(ms) = transition_interval {
+            anyhow::ensure!(ms > 0, "the transition interval must be greater than zero");
+            state
+                .set_transition_interval(Duration::from_millis(ms))
+                .await;
         }
 
-        sleep(Duration::from_secs(60)).await;
-    }
-}
+        let min_command_interval = match self.min_command_interval {
+            Some(ms) => Some(ms),
+            None => opt_env_var::<u64>("GOVEE_MIN_COMMAND_INTERVAL")?,
+        };
+        if let Some(ms) = min_command_interval {
+            state
+                .set_min_command_interval(Duration::from_millis(ms))
+                .await;
+        }
 
-impl ServeCommand {
-    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
-        log::info!("Starting service. version {}", govee_version());
-        let state = Arc::new(crate::service::state::State::new());
+        let mut dry_run = self.dry_run;
+        if let Some(v) = opt_env_var::<String>("GOVEE_DRY_RUN")? {
+            dry_run = truthy(&v)?;
+        }
+        if dry_run {
+            log::warn!("Dry-run mode: control commands will be simulated rather than sent");
+            state.set_dry_run(true).await;
+        }
 
-        // First, use the HTTP APIs to determine the list of devices and
-        // their names.
+        let mut optimistic = self.optimistic;
+        if let Some(v) = opt_env_var::<String>("GOVEE_OPTIMISTIC")? {
+            optimistic = truthy(&v)?;
+        }
+        state.set_optimistic(optimistic).await;
 
-        if let Ok(client) = args.api_args.api_client() {
-            log::info!("Querying platform API for device list");
-            for info in client.get_devices().await? {
-                let mut device = state.device_mut(&info.sku, &info.device).await;
-                device.set_http_device_info(info);
-            }
+        let mut read_only = self.read_only;
+        if let Some(v) = opt_env_var::<String>("GOVEE_READ_ONLY")? {
+            read_only = truthy(&v)?;
+        }
+        if read_only {
+            log::warn!("Read-only mode: requests to control devices will be rejected");
+        }
+        state.set_read_only(read_only).await;
+
+        let mut read_only_devices = self.read_only_devices.clone();
+        if let Some(v) = opt_env_var::<String>("GOVEE_READ_ONLY_DEVICES")? {
+            read_only_devices.extend(
+                v.split(',')
+                    .map(|label| label.trim().to_string())
+                    .filter(|label| !label.is_empty()),
+            );
+        }
+        state.set_read_only_devices(read_only_devices).await;
 
-            state.set_platform_client(client).await;
+        let mut debug_packets = self.debug_packets;
+        if let Some(v) = opt_env_var::<String>("GOVEE_DEBUG_PACKETS")? {
+            debug_packets = truthy(&v)?;
         }
-        if let Ok(client) = args.undoc_args.api_client() {
-            log::info!("Querying undocumented API for device + room list");
-            let acct = client.login_account_cached().await?;
-            let info = client.get_device_list(&acct.token).await?;
-            let mut group_by_id = HashMap::new();
-            for group in info.groups {
-                group_by_id.insert(group.group_id, group.group_name);
-            }
-            for entry in info.devices {
-                let mut device = state.device_mut(&entry.sku, &entry.device).await;
-                let room_name = group_by_id.get(&entry.group_id).map(|name| name.as_str());
-                device.set_undoc_device_info(entry, room_name);
+        if debug_packets {
+            if let Some(rx) = enable_packet_capture() {
+                shutdown.spawn(run_packet_capture(state.clone(), rx));
             }
-
-            start_iot_client(args, state.clone(), Some(acct)).await?;
-
-            state.set_undoc_client(client).await;
         }
 
-        // Now start discovery
-
+        // Start LAN discovery first, so that it can proceed while
+        // we wait for the HTTP APIs
+        let mut lan_deadline = None;
         let options = args.lan_disco_args.to_disco_options()?;
         if !options.is_empty() {
             log::info!("Starting LAN discovery");
@@ -130,8 +295,35 @@ impl ServeCommand {
             let (client, mut scan) = LanClient::new(options).await?;
 
             state.set_lan_client(client.clone()).await;
+            if let Some(task) = client.take_listener_task() {
+                let heartbeat = client.listener_heartbeat();
+                let client = client.clone();
+                state
+                    .supervise(SupervisedTask::new(
+                        Subsystem::LanListener,
+                        heartbeat,
+                        task,
+                        move || client.spawn_listener(),
+                    ))
+                    .await;
+            }
 
-            tokio::spawn(async move {
+            // Devices report their status when it changes, whether or
+            // not we asked, so that we can pick up changes that were
+            // made via the Govee app without waiting to poll for them
+            let mut status_updates = client.subscribe_status().await;
+            {
+                let state = state.clone();
+                shutdown.spawn(async move {
+                    while let Some((ip, status)) = status_updates.recv().await {
+                        if let Err(err) = state.handle_lan_status_update(ip, status).await {
+                            log::error!("LAN status update from {ip}: {err:#}");
+                        }
+                    }
+                });
+            }
+
+            shutdown.spawn(async move {
                 while let Some(lan_device) = scan.recv().await {
                     log::trace!("LAN disco: {lan_device:?}");
                     state
@@ -144,12 +336,15 @@ impl ServeCommand {
                     tokio::spawn(async move {
                         if let Ok(status) = client.query_status(&lan_device).await {
                             state
-                                .device_mut(&lan_device.sku, &lan_device.device)
-                                .await
-                                .set_lan_device_status(status);
+                                .record_lan_device_status(
+                                    &lan_device.sku,
+                                    &lan_device.device,
+                                    status,
+                                )
+                                .await;
 
                             log::trace!("LAN disco: update and notify {}", lan_device.device);
-                            state.notify_of_state_change(&lan_device.device).await.ok();
+                            state.emit_state_changed(&lan_device.device);
                         }
                     });
                 }
@@ -160,8 +355,56 @@ impl ServeCommand {
             // devices that didn't respond in the section below, in the
             // interest of reducing false positives we need to wait long
             // enough to provide high-signal warnings.
-            log::info!("Waiting 10 seconds for LAN API discovery");
-            sleep(Duration::from_secs(10)).await;
+            lan_deadline.replace(Instant::now() + Duration::from_secs(10));
+        }
+
+        // Meanwhile, use the HTTP APIs to determine the list of devices
+        // and their names. The two APIs are queried concurrently.
+        let platform = async {
+            if let Ok(client) = args.api_args.api_client() {
+                log::info!("Querying platform API for device list");
+                let devices = client.get_devices().await?;
+                client.grant_startup_budget(devices.len());
+                for info in devices {
+                    let mut device = state.device_mut(&info.sku, &info.device).await;
+                    device.set_http_device_info(info);
+                }
+
+                state.set_platform_client(client).await;
+            }
+            anyhow::Ok(())
+        };
+        let undoc = async {
+            if let Ok(client) = args.undoc_args.api_client() {
+                log::info!("Querying undocumented API for device + room list");
+                let acct = client.login_account_cached().await?;
+                let info = client.get_device_list(&acct.token).await?;
+                state.apply_undoc_device_list(info).await;
+
+                start_iot_client(args, state.clone(), Some(acct)).await?;
+
+                // Log in again before the token expires
+                shutdown.spawn(client.clone().run_login_refresh());
+                state.set_undoc_client(client).await;
+
+                // Keep the schedules from the Govee Home app up to date
+                shutdown.spawn(run_schedule_sync(state.clone()));
+                // and check whether newer firmware is available
+                shutdown.spawn(run_firmware_check(state.clone()));
+            }
+            anyhow::Ok(())
+        };
+        let (platform, undoc) = tokio::join!(platform, undoc);
+        platform?;
+        undoc?;
+
+        // Pick up devices that are added to or removed from the
+        // account, along with the rooms that they are assigned to
+        shutdown.spawn(run_account_sync(state.clone()));
+
+        if let Some(deadline) = lan_deadline {
+            log::info!("Waiting for LAN API discovery");
+            sleep_until(deadline).await;
         }
 
         log::info!("Devices returned from Govee's APIs");
@@ -224,19 +467,238 @@ impl ServeCommand {
             log::info!("");
         }
 
+        // Keep the model specific parameters up to date. The bundled
+        // copy is used until they have been fetched.
+        {
+            let state = state.clone();
+            shutdown.spawn(async move {
+                loop {
+                    let params = load_model_specific_parameters().await;
+                    log::info!(
+                        "Using {:?} model specific parameters for {} model(s)",
+                        params.source,
+                        params.params.len()
+                    );
+                    if let Some(client) = state.get_hass_client().await {
+                        if let Err(err) = ModelParamsSensor::new().notify_state(&client).await {
+                            log::error!("reporting model parameters: {err:#}");
+                        }
+                    }
+                    tokio::time::sleep(MODEL_SPECIFIC_PARAMETERS_REFRESH).await;
+                }
+            });
+        }
+
         // Start periodic status polling
         {
+            let heartbeat = Heartbeat::default();
+            let start = {
+                let state = state.clone();
+                let heartbeat = heartbeat.clone();
+                move || {
+                    let state = state.clone();
+                    let heartbeat = heartbeat.clone();
+                    let token = state.shutdown_token();
+                    tokio::spawn(async move {
+                        let scheduler = PollScheduler::new(state, heartbeat);
+                        if let Some(Err(err)) = token.run_until(scheduler.run()).await {
+                            log::error!("PollScheduler: {err:#}");
+                        }
+                    })
+                }
+            };
+            state
+                .supervise(SupervisedTask::spawn(
+                    Subsystem::PollScheduler,
+                    heartbeat,
+                    start,
+                ))
+                .await;
+        }
+
+        // Restart the above tasks should they fail
+        shutdown.spawn(run_watchdog(state.clone()));
+
+        // Start the wake-up alarm scheduler
+        {
+            let alarms_file = match &self.alarms_file {
+                Some(path) => Some(path.clone()),
+                None => opt_env_var::<PathBuf>("GOVEE_ALARMS_FILE")?,
+            };
+            if let Some(path) = alarms_file {
+                let alarms = load_alarms_file(&path)?;
+                log::info!("Loaded {} alarm(s) from {path:?}", alarms.len());
+                state.set_alarms(alarms).await;
+            }
+
             let state = state.clone();
-            tokio::spawn(async move {
-                if let Err(err) = periodic_state_poll(state).await {
-                    log::error!("periodic_state_poll: {err:#}");
+            shutdown.spawn(async move {
+                if let Err(err) = run_alarm_scheduler(state).await {
+                    log::error!("run_alarm_scheduler: {err:#}");
                 }
             });
         }
 
+        // Load light groups
+        {
+            let light_groups_file = match &self.light_groups_file {
+                Some(path) => Some(path.clone()),
+                None => opt_env_var::<PathBuf>("GOVEE_LIGHT_GROUPS_FILE")?,
+            };
+            if let Some(path) = light_groups_file {
+                let groups = load_light_groups_file(&path)?;
+                log::info!("Loaded {} light group(s) from {path:?}", groups.len());
+                state.set_light_groups(groups).await;
+            }
+        }
+
+        // Load transport priorities
+        {
+            let transport_priority_file = match &self.transport_priority_file {
+                Some(path) => Some(path.clone()),
+                None => opt_env_var::<PathBuf>("GOVEE_TRANSPORT_PRIORITY_FILE")?,
+            };
+            if let Some(path) = transport_priority_file {
+                let priority = load_transport_priority_file(&path)?;
+                log::info!("Loaded transport priorities from {path:?}");
+                state.set_transport_priority(priority).await;
+            }
+        }
+
+        // Load poll intervals
+        {
+            let poll_intervals_file = match &self.poll_intervals_file {
+                Some(path) => Some(path.clone()),
+                None => opt_env_var::<PathBuf>("GOVEE_POLL_INTERVALS_FILE")?,
+            };
+            if let Some(path) = poll_intervals_file {
+                let intervals = load_poll_intervals_file(&path)?;
+                log::info!("Loaded poll intervals from {path:?}");
+                state.set_poll_intervals(intervals).await;
+            }
+        }
+
+        if let Some(dir) = &self.scene_override_dir {
+            crate::govee_scenes::set_scene_override_dir(dir.clone());
+        }
+        log::info!(
+            "Scene overrides are loaded from {:?}",
+            crate::govee_scenes::scene_override_dir()
+        );
+
+        let scene_sync_url = match &self.scene_sync_url {
+            Some(url) => Some(url.clone()),
+            None => opt_env_var::<String>("GOVEE_SCENE_SYNC_URL")?,
+        };
+        if let Some(url) = scene_sync_url {
+            let hours = match self.scene_sync_interval {
+                Some(hours) => hours,
+                None => opt_env_var::<u64>("GOVEE_SCENE_SYNC_INTERVAL")?.unwrap_or(24),
+            };
+            log::info!("Scene overrides will be synced from {url} every {hours} hour(s)");
+            shutdown.spawn(run_scene_sync(SceneSyncOptions {
+                url,
+                interval: Duration::from_secs(hours.max(1) * 3600),
+            }));
+        }
+
+        // Load scene filters
+        {
+            let scene_filter_file = match &self.scene_filter_file {
+                Some(path) => Some(path.clone()),
+                None => opt_env_var::<PathBuf>("GOVEE_SCENE_FILTER_FILE")?,
+            };
+            if let Some(path) = scene_filter_file {
+                let file = load_scene_filter_file(&path)?;
+                log::info!("Loaded scene filters from {path:?}");
+                state
+                    .set_scene_filters(SceneFilters { file, mqtt: None })
+                    .await;
+            }
+        }
+
+        // Load power models
+        {
+            let power_model_file = match &self.power_model_file {
+                Some(path) => Some(path.clone()),
+                None => opt_env_var::<PathBuf>("GOVEE_POWER_MODEL_FILE")?,
+            };
+            if let Some(path) = power_model_file {
+                let models = load_power_model_file(&path)?;
+                log::info!("Loaded power models from {path:?}");
+                state.set_power_models(models).await;
+            }
+        }
+
+        // Load discovery overrides
+        {
+            let discovery_override_file = match &self.discovery_override_file {
+                Some(path) => Some(path.clone()),
+                None => opt_env_var::<PathBuf>("GOVEE_DISCOVERY_OVERRIDE_FILE")?,
+            };
+            if let Some(path) = discovery_override_file {
+                let overrides = load_discovery_override_file(&path)?;
+                log::info!("Loaded discovery overrides from {path:?}");
+                state.set_discovery_overrides(overrides).await;
+            }
+        }
+
+        // Start listening for BLE sensor advertisements
+        {
+            let ble_adapter = match self.ble_adapter {
+                Some(adapter) => Some(adapter),
+                None => opt_env_var::<u16>("GOVEE_BLE_ADAPTER")?,
+            };
+            if let Some(adapter) = ble_adapter {
+                let heartbeat = Heartbeat::default();
+                let start = {
+                    let state = state.clone();
+                    let heartbeat = heartbeat.clone();
+                    move || {
+                        let state = state.clone();
+                        let heartbeat = heartbeat.clone();
+                        let token = state.shutdown_token();
+                        tokio::spawn(async move {
+                            let scanner = run_ble_scanner(state, adapter, heartbeat);
+                            if let Some(Err(err)) = token.run_until(scanner).await {
+                                log::error!("run_ble_scanner: {err:#}");
+                            }
+                        })
+                    }
+                };
+                state
+                    .supervise(SupervisedTask::spawn(
+                        Subsystem::BleScanner,
+                        heartbeat,
+                        start,
+                    ))
+                    .await;
+            }
+        }
+
         // start advertising on local mqtt
         spawn_hass_integration(state.clone(), &args.hass_args).await?;
 
+        // Advertise ourselves on the local network
+        {
+            let mut no_mdns = self.no_mdns;
+            if let Some(v) = opt_env_var::<String>("GOVEE_NO_MDNS")? {
+                no_mdns = truthy(&v)?;
+            }
+            let mut browse = self.mdns_browse;
+            if let Some(v) = opt_env_var::<String>("GOVEE_MDNS_BROWSE")? {
+                browse = truthy(&v)?;
+            }
+            if !no_mdns {
+                let http_port = self.http_port;
+                shutdown.spawn(async move {
+                    if let Err(err) = run_mdns_responder(http_port, browse).await {
+                        log::warn!("mDNS advertisement failed: {err:#}");
+                    }
+                });
+            }
+        }
+
         run_http_server(state.clone(), self.http_port)
             .await
             .with_context(|| format!("Starting HTTP service on port {}", self.http_port))
@@ -0,0 +1,298 @@
+//! Emulates Govee devices that speak the LAN protocol, so that
+//! govee2mqtt can be exercised without any real hardware.
+//!
+//! The protocol uses fixed port numbers and the client identifies
+//! devices by the address that their responses come from, so each
+//! emulated device is bound to its own address. On Linux, the whole
+//! of 127.0.0.0/8 is routed to the loopback interface, which makes
+//! it easy to run a number of devices on the same host.
+use crate::lan_api::{
+    DeviceColor, DeviceStatus, LanDevice, Request, RequestMessage, Response, ResponseWrapper,
+    CMD_PORT, LISTEN_PORT, MULTICAST, SCAN_PORT,
+};
+use anyhow::Context;
+use parking_lot::Mutex;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::net::UdpSocket;
+use tokio::task::JoinSet;
+
+pub struct FakeDevice {
+    info: LanDevice,
+    status: Mutex<DeviceStatus>,
+}
+
+impl FakeDevice {
+    pub fn new(sku: &str, id: &str, ip: IpAddr) -> Self {
+        Self {
+            info: LanDevice {
+                ip,
+                device: id.to_string(),
+                sku: sku.to_string(),
+                ble_version_hard: "3.01.01".to_string(),
+                ble_version_soft: "1.00.00".to_string(),
+                wifi_version_hard: "1.00.10".to_string(),
+                wifi_version_soft: "1.00.00".to_string(),
+            },
+            status: Mutex::new(DeviceStatus {
+                on: false,
+                brightness: 100,
+                color: DeviceColor {
+                    r: 255,
+                    g: 255,
+                    b: 255,
+                },
+                color_temperature_kelvin: 0,
+                op: None,
+            }),
+        }
+    }
+
+    pub fn info(&self) -> &LanDevice {
+        &self.info
+    }
+
+    /// Apply the request to the state of the device, returning
+    /// the response that should be sent back, if any
+    pub fn handle_request(&self, request: Request) -> Option<Response> {
+        let mut status = self.status.lock();
+        match request {
+            Request::Scan { .. } => return Some(Response::Scan(self.info.clone())),
+            Request::DevStatus {} => return Some(Response::DevStatus(status.clone())),
+            Request::Turn { value } => {
+                status.on = value != 0;
+            }
+            Request::Brightness { value } => {
+                status.brightness = value.min(100);
+            }
+            Request::Color {
+                color,
+                color_temperature_kelvin,
+            } => {
+                if color_temperature_kelvin != 0 {
+                    status.color_temperature_kelvin = color_temperature_kelvin;
+                } else {
+                    status.color = color;
+                    status.color_temperature_kelvin = 0;
+                }
+            }
+            Request::PtReal { command } => {
+                log::info!("{}: ignoring ptReal {command:?}", self.info.device);
+            }
+        }
+        log::info!("{}: {:?}", self.info.device, *status);
+        None
+    }
+}
+
+/// Create a UDP socket that can share its port with the other
+/// emulated devices
+fn bind_shared(addr: SocketAddr) -> anyhow::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket.bind(&addr.into())?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+fn bind_multicast() -> anyhow::Result<UdpSocket> {
+    let IpAddr::V4(multicast) = MULTICAST else {
+        unreachable!("MULTICAST is v4");
+    };
+    let socket = bind_shared((Ipv4Addr::UNSPECIFIED, SCAN_PORT).into())?;
+    socket.join_multicast_v4(multicast, Ipv4Addr::UNSPECIFIED)?;
+    Ok(socket)
+}
+
+async fn respond(socket: &UdpSocket, to: IpAddr, response: Response) -> anyhow::Result<()> {
+    let data = serde_json::to_string(&ResponseWrapper { msg: response })?;
+    socket.send_to(data.as_bytes(), (to, LISTEN_PORT)).await?;
+    Ok(())
+}
+
+fn parse_request(data: &[u8]) -> anyhow::Result<Request> {
+    let message: RequestMessage = serde_json::from_slice(data)
+        .with_context(|| format!("Parsing: {}", String::from_utf8_lossy(data)))?;
+    Ok(message.msg)
+}
+
+/// Serve a single device, answering scan requests sent directly
+/// to its address, and control requests
+async fn serve_device(device: Arc<FakeDevice>, cmd: Arc<UdpSocket>) -> anyhow::Result<()> {
+    let scan = bind_shared((device.info.ip, SCAN_PORT).into())
+        .with_context(|| format!("binding scan port on {}", device.info.ip))?;
+
+    let mut scan_buf = [0u8; 4096];
+    let mut cmd_buf = [0u8; 4096];
+    loop {
+        let (len, addr, data) = tokio::select! {
+            result = scan.recv_from(&mut scan_buf) => {
+                let (len, addr) = result?;
+                (len, addr, &scan_buf)
+            }
+            result = cmd.recv_from(&mut cmd_buf) => {
+                let (len, addr) = result?;
+                (len, addr, &cmd_buf)
+            }
+        };
+
+        match parse_request(&data[0..len]) {
+            Ok(request) => {
+                log::trace!("{}: {request:?} from {addr}", device.info.device);
+                if let Some(response) = device.handle_request(request) {
+                    respond(&cmd, addr.ip(), response).await?;
+                }
+            }
+            Err(err) => log::error!("{}: {err:#}", device.info.device),
+        }
+    }
+}
+
+/// Answer scan requests sent to the multicast group on behalf of
+/// all of the devices
+async fn serve_multicast(
+    socket: UdpSocket,
+    devices: Vec<(Arc<FakeDevice>, Arc<UdpSocket>)>,
+) -> anyhow::Result<()> {
+    let mut buf = [0u8; 4096];
+    loop {
+        let (len, addr) = socket.recv_from(&mut buf).await?;
+        match parse_request(&buf[0..len]) {
+            Ok(request @ Request::Scan { .. }) => {
+                log::trace!("{request:?} from {addr}");
+                for (device, cmd) in &devices {
+                    respond(cmd, addr.ip(), Response::Scan(device.info.clone())).await?;
+                }
+            }
+            Ok(request) => log::warn!("Ignoring {request:?} from {addr} on the scan port"),
+            Err(err) => log::error!("{err:#}"),
+        }
+    }
+}
+
+/// Run the devices until an error occurs
+pub async fn run_fake_devices(devices: Vec<Arc<FakeDevice>>) -> anyhow::Result<()> {
+    let mut tasks = JoinSet::new();
+    let mut sockets = vec![];
+
+    for device in devices {
+        // Responses are sent from the command socket so that
+        // they originate from the address of the device
+        let cmd = Arc::new(
+            bind_shared((device.info.ip, CMD_PORT).into())
+                .with_context(|| format!("binding command port on {}", device.info.ip))?,
+        );
+        sockets.push((device.clone(), cmd.clone()));
+        tasks.spawn(serve_device(device, cmd));
+    }
+
+    match bind_multicast() {
+        Ok(socket) => {
+            tasks.spawn(serve_multicast(socket, sockets));
+        }
+        Err(err) => {
+            log::warn!("Multicast discovery is not available: {err:#}");
+        }
+    }
+
+    while let Some(result) = tasks.join_next().await {
+        result??;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn fake_device_requests() {
+        let device = FakeDevice::new(
+            "H6159",
+            "AA:BB:CC:DD:EE:FF:00:01",
+            "127.0.0.10".parse().unwrap(),
+        );
+
+        let request: Request =
+            parse_request(br#"{"msg":{"cmd":"scan","data":{"account_topic":"reserve"}}}"#).unwrap();
+        match device.handle_request(request) {
+            Some(Response::Scan(info)) => assert_eq!(info.sku, "H6159"),
+            other => panic!("unexpected {other:?}"),
+        }
+
+        assert!(device.handle_request(Request::Turn { value: 1 }).is_none());
+        device.handle_request(Request::Brightness { value: 42 });
+        device.handle_request(Request::Color {
+            color: DeviceColor { r: 0, g: 0, b: 0 },
+            color_temperature_kelvin: 3000,
+        });
+        assert_eq!(
+            *device.status.lock(),
+            DeviceStatus {
+                on: true,
+                brightness: 42,
+                color: DeviceColor {
+                    r: 255,
+                    g: 255,
+                    b: 255
+                },
+                color_temperature_kelvin: 3000,
+                op: None,
+            }
+        );
+
+        device.handle_request(Request::Color {
+            color: DeviceColor { r: 255, g: 0, b: 0 },
+            color_temperature_kelvin: 0,
+        });
+        match device.handle_request(Request::DevStatus {}) {
+            Some(Response::DevStatus(status)) => {
+                assert_eq!(status.color, DeviceColor { r: 255, g: 0, b: 0 });
+                assert_eq!(status.color_temperature_kelvin, 0);
+            }
+            other => panic!("unexpected {other:?}"),
+        }
+    }
+
+    /// Drive an emulated device via the LAN client. Only Linux
+    /// routes all of 127.0.0.0/8 to the loopback interface.
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn lan_client_round_trip() {
+        use crate::lan_api::{Client, DiscoOptions};
+
+        let ip: IpAddr = "127.0.0.77".parse().unwrap();
+        let device = Arc::new(FakeDevice::new("H6159", "AA:BB:CC:DD:EE:FF:00:77", ip));
+        let server = tokio::spawn(run_fake_devices(vec![device.clone()]));
+
+        let (client, _scan) = Client::new(DiscoOptions {
+            enable_multicast: false,
+            ..DiscoOptions::default()
+        })
+        .await
+        .unwrap();
+
+        let lan_device = client.scan_ip(ip).await.unwrap();
+        assert_eq!(lan_device.device, "AA:BB:CC:DD:EE:FF:00:77");
+        assert_eq!(lan_device.sku, "H6159");
+
+        lan_device.send_turn(true).await.unwrap();
+        lan_device.send_brightness(42).await.unwrap();
+        lan_device
+            .send_color_rgb(DeviceColor { r: 0, g: 0, b: 255 })
+            .await
+            .unwrap();
+
+        let status = client.query_status(&lan_device).await.unwrap();
+        assert!(status.on);
+        assert_eq!(status.brightness, 42);
+        assert_eq!(status.color, DeviceColor { r: 0, g: 0, b: 255 });
+        assert_eq!(*device.status.lock(), status);
+
+        server.abort();
+    }
+}
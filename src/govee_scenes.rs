@@ -1,10 +1,14 @@
+use crate::opt_env_var;
 use crate::undoc_api::{GoveeUndocumentedApi, LightEffectEntry}; // For API fallback
 use anyhow::{Context, Result};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
 use std::collections::HashMap;
 use std::fs::{self, File}; // Added fs for read_dir
 use std::io::BufReader;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
 
 #[derive(Debug, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
 pub struct ParsedScene {
@@ -17,94 +21,227 @@ pub struct ParsedScene {
     pub source_api_scene_id: u32,       // API scene ID, or default for override
     pub source_api_scence_param_id: u32, // API param ID, or default for override
     pub override_cmd_b64: Option<Vec<String>>, // Populated from JSON override
+    #[serde(default)]
+    pub category: Option<String>, // API scene category, such as Nature; None for override
 }
 
-// Struct to represent an entry in the JSON override file (internal to this module)
-#[derive(Debug, Clone, Deserialize)]
-struct JsonSceneOverrideEntry {
-    name: String,
-    cmd_b64: Vec<String>, // This field in the JSON contains the final command lines
+// Struct to represent an entry in the JSON override file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JsonSceneOverrideEntry {
+    pub name: String,
+    pub cmd_b64: Vec<String>, // This field in the JSON contains the final command lines
 }
 
-pub async fn get_parsed_scenes_for_sku(sku: &str) -> Result<Vec<ParsedScene>> {
-    let override_dir = PathBuf::from("/JSONs");
-    let mut found_override_file: Option<PathBuf> = None;
-
-    if override_dir.is_dir() {
-        match fs::read_dir(&override_dir) {
-            Ok(entries) => {
-                let mut matching_files = Vec::new();
-                for entry in entries {
-                    if let Ok(entry) = entry {
-                        let path = entry.path();
-                        if path.is_file() {
-                            if let Some(filename_str) = path.file_name().and_then(|name| name.to_str()) {
-                                if filename_str.contains(sku) && filename_str.to_lowercase().ends_with(".json") {
-                                    matching_files.push(path.clone());
-                                }
-                            }
-                        }
-                    }
-                }
+/// The directory that is searched for scene override files when
+/// it has not been configured
+pub const DEFAULT_SCENE_OVERRIDE_DIR: &str = "/JSONs";
+
+static OVERRIDE_DIR: Lazy<Mutex<PathBuf>> = Lazy::new(|| {
+    let dir = opt_env_var::<PathBuf>("GOVEE_SCENE_OVERRIDE_DIR")
+        .ok()
+        .flatten()
+        .unwrap_or_else(|| PathBuf::from(DEFAULT_SCENE_OVERRIDE_DIR));
+    Mutex::new(dir)
+});
+
+static REMOTE_OVERRIDE_DIR: Lazy<Mutex<Option<PathBuf>>> = Lazy::new(|| Mutex::new(None));
+
+/// The scenes loaded from override files, keyed by SKU. None
+/// records that there is no override file for that SKU.
+static OVERRIDE_CACHE: Lazy<Mutex<HashMap<String, Option<Vec<ParsedScene>>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
 
-                if !matching_files.is_empty() {
-                    if matching_files.len() > 1 {
-                        log::warn!(
-                            "Multiple override files found for SKU '{}' in {:?}: {:?}. Using the first one: {:?}",
-                            sku,
-                            override_dir,
-                            matching_files,
-                            matching_files[0]
-                        );
+pub fn scene_override_dir() -> PathBuf {
+    OVERRIDE_DIR.lock().clone()
+}
+
+/// Set the directory holding the overrides that are synced from
+/// a remote repository. These are used only for SKUs that have no
+/// file in the local override directory.
+pub fn set_remote_scene_override_dir(dir: Option<PathBuf>) {
+    *REMOTE_OVERRIDE_DIR.lock() = dir;
+    invalidate_scene_overrides();
+}
+
+/// The directories to search for override files, in order of precedence
+fn scene_override_dirs() -> Vec<PathBuf> {
+    let mut dirs = vec![scene_override_dir()];
+    dirs.extend(REMOTE_OVERRIDE_DIR.lock().clone());
+    dirs
+}
+
+pub fn set_scene_override_dir(dir: PathBuf) {
+    *OVERRIDE_DIR.lock() = dir;
+    invalidate_scene_overrides();
+}
+
+/// Forget the previously loaded override files, so that they
+/// are read again the next time that they are needed
+pub fn invalidate_scene_overrides() {
+    OVERRIDE_CACHE.lock().clear();
+    crate::scene_resolver::invalidate_scene_cache();
+}
+
+/// Summarizes the json files in the override directory, so that
+/// changes to them can be detected by comparing fingerprints
+pub fn scene_override_fingerprint() -> Vec<(PathBuf, Option<SystemTime>, u64)> {
+    let mut result = vec![];
+    for dir in scene_override_dirs() {
+        let Ok(entries) = fs::read_dir(dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if !path.to_string_lossy().to_lowercase().ends_with(".json") {
+                continue;
+            }
+            if let Ok(meta) = entry.metadata() {
+                result.push((path, meta.modified().ok(), meta.len()));
+            }
+        }
+    }
+    result.sort();
+    result
+}
+
+fn find_override_file(override_dir: &Path, sku: &str) -> Option<PathBuf> {
+    if !override_dir.is_dir() {
+        log::debug!(
+            "Override directory {:?} does not exist or is not a directory.",
+            override_dir
+        );
+        return None;
+    }
+
+    match fs::read_dir(override_dir) {
+        Ok(entries) => {
+            let mut matching_files = Vec::new();
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.is_file() {
+                    if let Some(filename_str) = path.file_name().and_then(|name| name.to_str()) {
+                        if filename_str.contains(sku)
+                            && filename_str.to_lowercase().ends_with(".json")
+                        {
+                            matching_files.push(path.clone());
+                        }
                     }
-                    found_override_file = Some(matching_files[0].clone());
                 }
             }
-            Err(e) => {
-                log::warn!("Failed to read override directory {:?}: {}", override_dir, e);
+            matching_files.sort();
+
+            if matching_files.len() > 1 {
+                log::warn!(
+                    "Multiple override files found for SKU '{}' in {:?}: {:?}. Using the first one: {:?}",
+                    sku,
+                    override_dir,
+                    matching_files,
+                    matching_files[0]
+                );
             }
+            matching_files.into_iter().next()
+        }
+        Err(e) => {
+            log::warn!(
+                "Failed to read override directory {:?}: {}",
+                override_dir,
+                e
+            );
+            None
         }
-    } else {
-        log::info!("Override directory {:?} does not exist or is not a directory.", override_dir);
     }
+}
+
+fn load_override_file(override_file_path: &Path, sku: &str) -> Result<Vec<ParsedScene>> {
+    log::info!(
+        "Attempting to load scenes from override file: {:?}",
+        override_file_path
+    );
+    // Try to open and read the file
+    let file = File::open(override_file_path)
+        .with_context(|| format!("Failed to open override file: {:?}", override_file_path))?;
+    let reader = BufReader::new(file);
+
+    // Parse the JSON content
+    let json_scenes: Vec<JsonSceneOverrideEntry> =
+        serde_json::from_reader(reader).with_context(|| {
+            format!(
+                "Failed to parse JSON from override file: {:?}",
+                override_file_path
+            )
+        })?;
+
+    // Convert JsonSceneOverrideEntry to ParsedScene
+    let mut parsed_scenes: Vec<ParsedScene> = json_scenes
+        .into_iter()
+        .map(|json_entry| ParsedScene {
+            display_name: json_entry.name.clone(),
+            override_cmd_b64: Some(json_entry.cmd_b64),
+            api_scence_param: String::new(),
+            sku: sku.to_string(),
+            scene_code: 0,
+            source_api_scene_name: json_entry.name,
+            source_api_effect_name: None,
+            source_api_scene_id: 0,
+            source_api_scence_param_id: 0,
+            category: None,
+        })
+        .collect();
 
+    parsed_scenes.sort_by(|a, b| a.display_name.cmp(&b.display_name));
 
-    if let Some(override_file_path) = found_override_file {
-        log::info!("Attempting to load scenes from override file: {:?}", override_file_path);
-        // Try to open and read the file
-        let file = File::open(&override_file_path)
-            .with_context(|| format!("Failed to open override file: {:?}", override_file_path))?;
-        let reader = BufReader::new(file);
-
-        // Parse the JSON content
-        let json_scenes: Vec<JsonSceneOverrideEntry> = serde_json::from_reader(reader)
-            .with_context(|| format!("Failed to parse JSON from override file: {:?}", override_file_path))?;
-
-        // Convert JsonSceneOverrideEntry to ParsedScene
-        let mut parsed_scenes: Vec<ParsedScene> = json_scenes
-            .into_iter()
-            .map(|json_entry| ParsedScene {
-                display_name: json_entry.name.clone(),
-                override_cmd_b64: Some(json_entry.cmd_b64), 
-                api_scence_param: String::new(), 
-                sku: sku.to_string(),
-                scene_code: 0, 
-                source_api_scene_name: json_entry.name, 
-                source_api_effect_name: None,      
-                source_api_scene_id: 0,            
-                source_api_scence_param_id: 0,     
-            })
-            .collect();
-
-        parsed_scenes.sort_by(|a, b| a.display_name.cmp(&b.display_name));
-        
-        log::info!("Successfully loaded {} scenes from override file {:?} for SKU: {}", parsed_scenes.len(), override_file_path, sku);
-        return Ok(parsed_scenes);
-    } else {
-         log::info!("No suitable override file found for SKU: {}. Falling back to API.", sku);
+    log::info!(
+        "Successfully loaded {} scenes from override file {:?} for SKU: {}",
+        parsed_scenes.len(),
+        override_file_path,
+        sku
+    );
+    Ok(parsed_scenes)
+}
+
+/// Check that data is a well formed override file, returning
+/// the number of scenes that it contains
+pub fn validate_override_data(data: &[u8]) -> Result<usize> {
+    let entries: Vec<JsonSceneOverrideEntry> = serde_json::from_slice(data)?;
+    for entry in &entries {
+        if entry.name.trim().is_empty() {
+            anyhow::bail!("a scene has an empty name");
+        }
+        if entry.cmd_b64.is_empty() {
+            anyhow::bail!("scene '{}' has no commands", entry.name);
+        }
+        for cmd in &entry.cmd_b64 {
+            data_encoding::BASE64
+                .decode(cmd.as_bytes())
+                .with_context(|| format!("scene '{}': decoding {cmd}", entry.name))?;
+        }
     }
+    Ok(entries.len())
+}
+
+/// Returns the scenes from the override file for the SKU, if any.
+/// The result is cached until invalidate_scene_overrides is called.
+pub fn get_override_scenes(sku: &str) -> Result<Option<Vec<ParsedScene>>> {
+    if let Some(cached) = OVERRIDE_CACHE.lock().get(sku) {
+        return Ok(cached.clone());
+    }
+
+    let override_file = scene_override_dirs()
+        .iter()
+        .find_map(|dir| find_override_file(dir, sku));
+    let scenes = match override_file {
+        Some(path) => Some(load_override_file(&path, sku)?),
+        None => None,
+    };
+    OVERRIDE_CACHE
+        .lock()
+        .insert(sku.to_string(), scenes.clone());
+    Ok(scenes)
+}
 
-    // Fallback to API if override file is not found
+/// Returns the scenes for the SKU from the Govee API. Use
+/// scene_resolver::scenes_for_sku to include the overrides.
+pub async fn get_api_scenes_for_sku(sku: &str) -> Result<Vec<ParsedScene>> {
     let mut parsed_scenes_intermediate: Vec<ParsedScene> = Vec::new();
     // Ensure GoveeUndocumentedApi client is initialized if needed, or passed in.
     // For simplicity, assuming it can be instantiated here or is globally available.
@@ -112,6 +249,9 @@ pub async fn get_parsed_scenes_for_sku(sku: &str) -> Result<Vec<ParsedScene>> {
     let categories_from_api = GoveeUndocumentedApi::get_scenes_for_device(sku).await?;
 
     for category_api_data in categories_from_api {
+        let category = Some(category_api_data.category_name.trim())
+            .filter(|name| !name.is_empty())
+            .map(|name| name.to_string());
         for scene_api_data in &category_api_data.scenes {
             let main_api_scene_name = &scene_api_data.scene_name;
             let source_api_scene_id = scene_api_data.scene_id;
@@ -135,6 +275,7 @@ pub async fn get_parsed_scenes_for_sku(sku: &str) -> Result<Vec<ParsedScene>> {
                         source_api_scene_id,
                         source_api_scence_param_id: effect_entry.scence_param_id,
                         override_cmd_b64: None, 
+                        category: category.clone(),
                     });
                 }
                 created_combined_name_for_this_main_scene = true;
@@ -156,6 +297,7 @@ pub async fn get_parsed_scenes_for_sku(sku: &str) -> Result<Vec<ParsedScene>> {
                         source_api_scene_id,
                         source_api_scence_param_id: first_effect.scence_param_id,
                         override_cmd_b64: None, 
+                        category: category.clone(),
                     });
                 }
             }
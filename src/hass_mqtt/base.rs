@@ -1,5 +1,6 @@
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::topic_safe_id;
+use crate::service::hass::{availability_topic, device_availability_topic, topic_safe_id};
+use crate::service::light_group::LightGroupConfig;
 use crate::version_info::govee_version;
 use serde::Serialize;
 
@@ -8,7 +9,8 @@ const URL: &str = "https://github.com/wez/govee2mqtt";
 
 #[derive(Serialize, Clone, Debug, Default)]
 pub struct EntityConfig {
-    pub availability_topic: String,
+    #[serde(flatten)]
+    pub availability: Availability,
     pub name: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub device_class: Option<&'static str>,
@@ -21,6 +23,47 @@ pub struct EntityConfig {
     pub icon: Option<String>,
 }
 
+#[derive(Serialize, Clone, Debug)]
+pub struct AvailabilityTopic {
+    pub topic: String,
+}
+
+/// <https://www.home-assistant.io/integrations/sensor.mqtt/#availability>
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Availability {
+    pub availability: Vec<AvailabilityTopic>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub availability_mode: Option<&'static str>,
+}
+
+impl Availability {
+    /// The entity is available whenever we are connected to the broker
+    pub fn service() -> Self {
+        Self {
+            availability: vec![AvailabilityTopic {
+                topic: availability_topic(),
+            }],
+            availability_mode: None,
+        }
+    }
+
+    /// The entity is available only while we are connected to the broker
+    /// and the device has recently been reachable
+    pub fn for_device(device: &ServiceDevice) -> Self {
+        Self {
+            availability: vec![
+                AvailabilityTopic {
+                    topic: availability_topic(),
+                },
+                AvailabilityTopic {
+                    topic: device_availability_topic(device),
+                },
+            ],
+            availability_mode: Some("all"),
+        }
+    }
+}
+
 #[derive(Serialize, Clone, Debug)]
 pub struct Origin {
     pub name: &'static str,
@@ -75,6 +118,19 @@ impl Device {
         }
     }
 
+    pub fn for_light_group(group: &LightGroupConfig) -> Self {
+        Self {
+            name: group.name.to_string(),
+            manufacturer: "Govee".to_string(),
+            model: "Light Group".to_string(),
+            sw_version: None,
+            suggested_area: None,
+            via_device: Some("gv2mqtt".to_string()),
+            identifiers: vec![format!("gv2mqtt-group-{}", group.topic_id())],
+            connections: vec![],
+        }
+    }
+
     pub fn this_service() -> Self {
         Self {
             name: "Govee to MQTT".to_string(),
@@ -0,0 +1,357 @@
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::platform_api::DeviceType;
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{base_topic, topic_safe_id, HassClient};
+use crate::service::state::StateHandle;
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::json;
+
+/// <https://www.home-assistant.io/integrations/binary_sensor.mqtt/>
+#[derive(Serialize, Clone, Debug)]
+pub struct BinarySensorConfig {
+    #[serde(flatten)]
+    pub base: EntityConfig,
+
+    /// We will publish ON/OFF here
+    pub state_topic: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attributes_topic: Option<String>,
+}
+
+impl BinarySensorConfig {
+    pub async fn publish(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("binary_sensor", state, client, &self.base, self).await
+    }
+
+    pub async fn notify_state(&self, client: &HassClient, on: bool) -> anyhow::Result<()> {
+        client
+            .publish(&self.state_topic, if on { "ON" } else { "OFF" })
+            .await
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IceMakerSensorKind {
+    Making,
+    BasketFull,
+    WaterEmpty,
+}
+
+impl IceMakerSensorKind {
+    pub const ALL: [Self; 3] = [Self::Making, Self::BasketFull, Self::WaterEmpty];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Making => "Making Ice",
+            Self::BasketFull => "Basket Full",
+            Self::WaterEmpty => "Water Empty",
+        }
+    }
+
+    fn id(&self) -> &'static str {
+        match self {
+            Self::Making => "making",
+            Self::BasketFull => "basket-full",
+            Self::WaterEmpty => "water-empty",
+        }
+    }
+
+    fn device_class(&self) -> Option<&'static str> {
+        match self {
+            Self::Making => Some("running"),
+            Self::BasketFull => None,
+            Self::WaterEmpty => Some("problem"),
+        }
+    }
+
+    fn icon(&self) -> &'static str {
+        match self {
+            Self::Making => "mdi:snowflake",
+            Self::BasketFull => "mdi:basket",
+            Self::WaterEmpty => "mdi:water-off",
+        }
+    }
+}
+
+pub struct IceMakerBinarySensor {
+    sensor: BinarySensorConfig,
+    kind: IceMakerSensorKind,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl IceMakerBinarySensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, kind: IceMakerSensorKind) -> Self {
+        let unique_id = format!(
+            "gv2mqtt-{id}-ice-maker-{kind}",
+            id = topic_safe_id(device),
+            kind = kind.id()
+        );
+
+        Self {
+            sensor: BinarySensorConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some(kind.name().to_string()),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: kind.device_class(),
+                    icon: Some(kind.icon().to_string()),
+                },
+                state_topic: format!("{}/binary_sensor/{unique_id}/state", base_topic()),
+                json_attributes_topic: None,
+            },
+            kind,
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for IceMakerBinarySensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let Some(status) = device.ice_maker_status else {
+            // We haven't received a status packet yet
+            return Ok(());
+        };
+
+        let on = match self.kind {
+            IceMakerSensorKind::Making => status.making,
+            IceMakerSensorKind::BasketFull => status.basket_full,
+            IceMakerSensorKind::WaterEmpty => status.water_empty,
+        };
+        self.sensor.notify_state(client, on).await
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WarningSensorKind {
+    WaterEmpty,
+    TankRemoved,
+    FilterReplace,
+}
+
+impl WarningSensorKind {
+    pub const ALL: [Self; 3] = [Self::WaterEmpty, Self::TankRemoved, Self::FilterReplace];
+
+    /// Only the warnings that make sense for the type of device
+    /// are offered, as a sensor that never turns on is just noise
+    pub fn applies_to(&self, device_type: DeviceType) -> bool {
+        match self {
+            Self::WaterEmpty | Self::TankRemoved => matches!(
+                device_type,
+                DeviceType::Humidifier | DeviceType::Dehumidifier | DeviceType::AromaDiffuser
+            ),
+            Self::FilterReplace => device_type == DeviceType::AirPurifier,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::WaterEmpty => "Water Shortage",
+            Self::TankRemoved => "Tank Removed",
+            Self::FilterReplace => "Replace Filter",
+        }
+    }
+
+    fn id(&self) -> &'static str {
+        match self {
+            Self::WaterEmpty => "water-empty",
+            Self::TankRemoved => "tank-removed",
+            Self::FilterReplace => "filter-replace",
+        }
+    }
+
+    fn icon(&self) -> &'static str {
+        match self {
+            Self::WaterEmpty => "mdi:water-off",
+            Self::TankRemoved => "mdi:cup-off",
+            Self::FilterReplace => "mdi:air-filter",
+        }
+    }
+}
+
+pub struct WarningBinarySensor {
+    sensor: BinarySensorConfig,
+    kind: WarningSensorKind,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl WarningBinarySensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, kind: WarningSensorKind) -> Self {
+        let unique_id = format!(
+            "gv2mqtt-{id}-warning-{kind}",
+            id = topic_safe_id(device),
+            kind = kind.id()
+        );
+
+        Self {
+            sensor: BinarySensorConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some(kind.name().to_string()),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("problem"),
+                    icon: Some(kind.icon().to_string()),
+                },
+                state_topic: format!("{}/binary_sensor/{unique_id}/state", base_topic()),
+                json_attributes_topic: None,
+            },
+            kind,
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for WarningBinarySensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let Some(warnings) = device.warnings else {
+            // We haven't received a warning packet yet
+            return Ok(());
+        };
+
+        let on = match self.kind {
+            WarningSensorKind::WaterEmpty => warnings.water_empty(),
+            WarningSensorKind::TankRemoved => warnings.tank_removed(),
+            WarningSensorKind::FilterReplace => warnings.filter_replace(),
+        };
+        self.sensor.notify_state(client, on).await
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PresenceSensorKind {
+    Presence,
+    Motion,
+}
+
+impl PresenceSensorKind {
+    pub const ALL: [Self; 2] = [Self::Presence, Self::Motion];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Presence => "Presence",
+            Self::Motion => "Motion",
+        }
+    }
+
+    fn id(&self) -> &'static str {
+        match self {
+            Self::Presence => "presence",
+            Self::Motion => "motion",
+        }
+    }
+
+    fn device_class(&self) -> &'static str {
+        match self {
+            Self::Presence => "occupancy",
+            Self::Motion => "motion",
+        }
+    }
+}
+
+pub struct PresenceBinarySensor {
+    sensor: BinarySensorConfig,
+    kind: PresenceSensorKind,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl PresenceBinarySensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, kind: PresenceSensorKind) -> Self {
+        let unique_id = format!(
+            "gv2mqtt-{id}-{kind}",
+            id = topic_safe_id(device),
+            kind = kind.id()
+        );
+
+        Self {
+            sensor: BinarySensorConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some(kind.name().to_string()),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some(kind.device_class()),
+                    icon: None,
+                },
+                state_topic: format!("{}/binary_sensor/{unique_id}/state", base_topic()),
+                json_attributes_topic: Some(format!(
+                    "{}/binary_sensor/{unique_id}/attributes",
+                    base_topic()
+                )),
+            },
+            kind,
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for PresenceBinarySensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let Some(status) = device.presence_status else {
+            // Nothing has been detected since we started
+            return Ok(());
+        };
+
+        let on = match self.kind {
+            PresenceSensorKind::Presence => status.presence,
+            PresenceSensorKind::Motion => status.motion,
+        };
+        self.sensor.notify_state(client, on).await?;
+
+        if let Some(topic) = &self.sensor.json_attributes_topic {
+            // Not all sensors measure the distance to the detected body
+            let distance = (status.distance > 0).then_some(status.distance);
+            client
+                .publish_obj(
+                    topic,
+                    json!({
+                        "distance_cm": distance,
+                        "last_updated": device.last_presence_update,
+                    }),
+                )
+                .await?;
+        }
+        Ok(())
+    }
+}
@@ -1,9 +1,10 @@
-use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::platform_api::DeviceCapability;
 use crate::service::device::Device as ServiceDevice;
 use crate::service::hass::{
-    availability_topic, camel_case_to_space_separated, topic_safe_id, topic_safe_string, HassClient,
+    base_topic, camel_case_to_space_separated, device_topic_id, topic_safe_id, topic_safe_string,
+    HassClient,
 };
 use crate::service::state::StateHandle;
 use async_trait::async_trait;
@@ -26,11 +27,11 @@ impl ButtonConfig {
         instance: &DeviceCapability,
     ) -> anyhow::Result<Self> {
         let command_topic = format!(
-            "gv2mqtt/switch/{id}/command/{inst}",
-            id = topic_safe_id(device),
+            "{}/switch/{id}/command/{inst}",
+            base_topic(),
+            id = device_topic_id(device),
             inst = instance.instance
         );
-        let availability_topic = availability_topic();
         let unique_id = format!(
             "gv2mqtt-{id}-{inst}",
             id = topic_safe_id(device),
@@ -39,7 +40,7 @@ impl ButtonConfig {
 
         Ok(Self {
             base: EntityConfig {
-                availability_topic,
+                availability: Availability::for_device(device),
                 name: Some(camel_case_to_space_separated(&instance.instance)),
                 device_class: None,
                 origin: Origin::default(),
@@ -58,7 +59,7 @@ impl ButtonConfig {
         let unique_id = format!("global-{}", topic_safe_string(&name));
         Self {
             base: EntityConfig {
-                availability_topic: availability_topic(),
+                availability: Availability::service(),
                 name: Some(name.to_string()),
                 entity_category: None,
                 origin: Origin::default(),
@@ -85,13 +86,14 @@ impl ButtonConfig {
             mode = topic_safe_string(mode_name),
         );
         let command_topic = format!(
-            "gv2mqtt/number/{id}/command/{mode}/{mode_num}",
-            id = topic_safe_id(device),
+            "{}/number/{id}/command/{mode}/{mode_num}",
+            base_topic(),
+            id = device_topic_id(device),
             mode = topic_safe_string(mode_name),
         );
         Self {
             base: EntityConfig {
-                availability_topic: availability_topic(),
+                availability: Availability::for_device(device),
                 name: Some(name.to_string()),
                 entity_category: None,
                 origin: Origin::default(),
@@ -111,12 +113,13 @@ impl ButtonConfig {
             id = topic_safe_id(device)
         );
         let command_topic = format!(
-            "gv2mqtt/{id}/request-platform-data",
-            id = topic_safe_id(device)
+            "{}/{id}/request-platform-data",
+            base_topic(),
+            id = device_topic_id(device)
         );
         Self {
             base: EntityConfig {
-                availability_topic: availability_topic(),
+                availability: Availability::service(),
                 name: Some("Request Platform API State".to_string()),
                 entity_category: Some("diagnostic".to_string()),
                 origin: Origin::default(),
@@ -1,9 +1,12 @@
-use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
-use crate::hass_mqtt::instance::EntityInstance;
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::hass_mqtt::number::NumberConfig;
-use crate::platform_api::{DeviceCapability, DeviceParameters};
+use crate::hass_mqtt::work_mode::ParsedWorkMode;
+use crate::platform_api::{DeviceCapability, DeviceCapabilityKind, DeviceParameters};
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, topic_safe_string, HassClient};
+use crate::service::hass::{
+    base_topic, device_topic_id, topic_safe_id, topic_safe_string, HassClient, IdParameter,
+};
 use crate::service::state::StateHandle;
 use crate::temperature::{
     TemperatureScale, TemperatureUnits, TemperatureValue, DEVICE_CLASS_TEMPERATURE,
@@ -11,11 +14,12 @@ use crate::temperature::{
 use anyhow::anyhow;
 use axum::async_trait;
 use mosquitto_rs::router::{Params, Payload, State};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 
-// TODO: register an actual climate entity.
-// I don't have one of these devices, so it is currently guesswork!
+// The target temperature of devices other than heaters is exposed
+// as a number entity. I don't have one of these devices, so it is
+// currently guesswork!
 
 pub struct TargetTemperatureEntity {
     number: NumberConfig,
@@ -92,19 +96,21 @@ impl TargetTemperatureEntity {
 
         let name = "Target Temperature".to_string();
         let command_topic = format!(
-            "gv2mqtt/{id}/set-temperature/{inst}/{units}",
-            id = topic_safe_id(device),
+            "{}/{id}/set-temperature/{inst}/{units}",
+            base_topic(),
+            id = device_topic_id(device),
             inst = topic_safe_string(&instance.instance)
         );
         let state_topic = format!(
-            "gv2mqtt/{id}/advise-set-temperature",
-            id = topic_safe_id(device),
+            "{}/{id}/advise-set-temperature",
+            base_topic(),
+            id = device_topic_id(device),
         );
 
         Ok(Self {
             number: NumberConfig {
                 base: EntityConfig {
-                    availability_topic: availability_topic(),
+                    availability: Availability::for_device(device),
                     name: Some(name),
                     entity_category: None,
                     origin: Origin::default(),
@@ -134,52 +140,33 @@ impl EntityInstance for TargetTemperatureEntity {
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
-        let device = self
-            .state
-            .device_by_id(&self.device_id)
-            .await
-            .expect("device to exist");
-
-        let quirk = device.resolve_quirk();
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
 
         log::debug!("notify_state for {device} {}", self.instance_name);
 
-        if let Some(cap) = device.get_state_capability_by_instance(&self.instance_name) {
-            log::debug!("have: {cap:?}");
-
-            let units = cap
-                .state
-                .pointer("/value/unit")
-                .and_then(|unit| {
-                    unit.as_str()
-                        .and_then(|s| TemperatureScale::from_str(s).map(Into::into).ok())
-                })
-                .or_else(|| quirk.and_then(|q| q.platform_temperature_sensor_units))
-                .unwrap_or(TemperatureUnits::Celsius);
-
-            log::debug!("units are reported as {units:?}");
-
-            let value = match cap
-                .state
-                .pointer("/value/targetTemperature")
-                .and_then(|v| v.as_f64())
-                .map(|v| TemperatureValue::new(v, units))
-            {
-                Some(v) => {
-                    let pref_units = self.state.get_temperature_scale().await;
-                    log::debug!("reported temp is {v}, pref_units: {pref_units}");
-                    let value = v.as_unit(pref_units.into()).value();
-                    format!("{value:.2}")
-                }
-                None => "".to_string(),
-            };
+        if device
+            .get_state_capability_by_instance(&self.instance_name)
+            .is_none()
+        {
+            return Ok(());
+        }
 
-            log::debug!("setting value to {value}");
+        let value = match device.target_temperature(&self.instance_name, TemperatureUnits::Celsius)
+        {
+            Some(v) => {
+                let pref_units = self.state.get_temperature_scale().await;
+                log::debug!("reported temp is {v}, pref_units: {pref_units}");
+                let value = v.as_unit(pref_units.into()).value();
+                format!("{value:.2}")
+            }
+            None => "".to_string(),
+        };
 
-            return self.number.notify_state(&client, &value).await;
-        }
+        log::debug!("setting value to {value}");
 
-        Ok(())
+        self.number.notify_state(client, &value).await
     }
 }
 
@@ -211,3 +198,393 @@ pub async fn mqtt_set_temperature(
 
     Ok(())
 }
+
+const MODE_OFF: &str = "off";
+/// One of the heat levels of the gear mode
+const MODE_HEAT: &str = "heat";
+/// Circulate air without heating
+const MODE_FAN: &str = "fan_only";
+/// Heat to, and hold, the target temperature
+const MODE_AUTO: &str = "auto";
+/// The range of a heater thermostat, when the device doesn't report one
+const DEFAULT_HEATER_RANGE_CELSIUS: (f64, f64) = (5., 30.);
+
+/// <https://www.home-assistant.io/integrations/climate.mqtt/>
+#[derive(Serialize, Clone, Debug)]
+pub struct ClimateConfig {
+    #[serde(flatten)]
+    pub base: EntityConfig,
+
+    pub mode_command_topic: String,
+    pub mode_state_topic: String,
+    pub modes: Vec<&'static str>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset_mode_command_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset_mode_state_topic: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub preset_modes: Vec<String>,
+
+    pub temperature_command_topic: String,
+    pub temperature_state_topic: String,
+    pub current_temperature_topic: String,
+
+    pub min_temp: f64,
+    pub max_temp: f64,
+    pub temp_step: f64,
+    /// "C" or "F"
+    pub temperature_unit: &'static str,
+    pub precision: f64,
+
+    pub optimistic: bool,
+}
+
+/// The work modes of a space heater
+#[derive(Clone, Debug, Default)]
+pub struct HeaterModes {
+    /// The gear mode, and the names and values of its heat levels
+    pub heat: Option<(i64, Vec<(String, i64)>)>,
+    pub fan: Option<(i64, i64)>,
+    pub auto: Option<(i64, i64)>,
+}
+
+impl HeaterModes {
+    pub fn with_device(device: &ServiceDevice) -> anyhow::Result<Self> {
+        let work_modes = ParsedWorkMode::with_device(device)?;
+        Ok(Self::with_work_modes(&work_modes))
+    }
+
+    pub fn with_work_modes(work_modes: &ParsedWorkMode) -> Self {
+        let mut modes = Self::default();
+        for mode in work_modes.modes.values() {
+            let Some(work_mode) = mode.value.as_i64() else {
+                continue;
+            };
+            if mode.name.eq_ignore_ascii_case("gearMode") {
+                let levels = match &mode.value_range {
+                    Some(range) => range.clone().map(|v| (v.to_string(), v)).collect(),
+                    None => mode
+                        .values
+                        .iter()
+                        .filter_map(|v| {
+                            let value = v.value.as_i64()?;
+                            let name = v.name.clone().unwrap_or_else(|| value.to_string());
+                            Some((name, value))
+                        })
+                        .collect(),
+                };
+                modes.heat.replace((work_mode, levels));
+            } else if mode.name.eq_ignore_ascii_case("Fan") {
+                modes.fan.replace((work_mode, mode.default_value()));
+            } else if mode.name.eq_ignore_ascii_case("Auto") {
+                modes.auto.replace((work_mode, mode.default_value()));
+            }
+        }
+        modes
+    }
+
+    fn hass_mode_for(&self, work_mode: i64) -> &'static str {
+        match (self.fan, self.auto) {
+            (Some((fan, _)), _) if fan == work_mode => MODE_FAN,
+            (_, Some((auto, _))) if auto == work_mode => MODE_AUTO,
+            _ => MODE_HEAT,
+        }
+    }
+
+    fn hass_modes(&self) -> Vec<&'static str> {
+        let mut modes = vec![MODE_OFF];
+        if self.heat.is_some() {
+            modes.push(MODE_HEAT);
+        }
+        if self.fan.is_some() {
+            modes.push(MODE_FAN);
+        }
+        if self.auto.is_some() {
+            modes.push(MODE_AUTO);
+        }
+        modes
+    }
+
+    fn preset_modes(&self) -> Vec<String> {
+        self.heat
+            .as_ref()
+            .map(|(_, levels)| levels.iter().map(|(name, _)| name.clone()).collect())
+            .unwrap_or_default()
+    }
+
+    fn level_name(&self, level: i64) -> Option<&str> {
+        let (_, levels) = self.heat.as_ref()?;
+        levels
+            .iter()
+            .find(|(_, value)| *value == level)
+            .map(|(name, _)| name.as_str())
+    }
+
+    fn level_by_name(&self, name: &str) -> Option<i64> {
+        let (_, levels) = self.heat.as_ref()?;
+        levels
+            .iter()
+            .find(|(level, _)| level.eq_ignore_ascii_case(name))
+            .map(|(_, value)| *value)
+    }
+}
+
+fn temperature_capability(device: &ServiceDevice) -> Option<&DeviceCapability> {
+    device.http_device_info.as_ref().and_then(|info| {
+        info.capabilities
+            .iter()
+            .find(|cap| cap.kind == DeviceCapabilityKind::TemperatureSetting)
+    })
+}
+
+#[derive(Clone)]
+pub struct HeaterClimate {
+    climate: ClimateConfig,
+    modes: HeaterModes,
+    temperature_instance: Option<String>,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl HeaterClimate {
+    pub async fn new(device: &ServiceDevice, state: &StateHandle) -> anyhow::Result<Self> {
+        let id = topic_safe_id(device);
+        let topic_id = device_topic_id(device);
+        let use_iot = device.iot_api_supported() && state.get_iot_client().await.is_some();
+        let modes = HeaterModes::with_device(device).unwrap_or_default();
+        let scale = state.get_temperature_scale().await;
+
+        let temp_cap = temperature_capability(device);
+        let constraints = temp_cap
+            .and_then(|cap| parse_temperature_constraints(cap).ok())
+            .unwrap_or_else(|| {
+                let (min, max) = DEFAULT_HEATER_RANGE_CELSIUS;
+                TemperatureConstraints {
+                    min: TemperatureValue::with_celsius(min),
+                    max: TemperatureValue::with_celsius(max),
+                }
+            })
+            .as_unit(scale.into());
+
+        let preset_modes = modes.preset_modes();
+        let has_presets = !preset_modes.is_empty();
+
+        Ok(Self {
+            climate: ClimateConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: None,
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: format!("gv2mqtt-{id}-climate"),
+                    device_class: None,
+                    icon: Some("mdi:heat-wave".to_string()),
+                },
+                mode_command_topic: format!("{}/climate/{topic_id}/set-mode", base_topic()),
+                mode_state_topic: format!("{}/climate/{topic_id}/notify-mode", base_topic()),
+                modes: modes.hass_modes(),
+                preset_mode_command_topic: has_presets
+                    .then(|| format!("{}/climate/{topic_id}/set-preset", base_topic())),
+                preset_mode_state_topic: has_presets
+                    .then(|| format!("{}/climate/{topic_id}/notify-preset", base_topic())),
+                preset_modes,
+                temperature_command_topic: format!(
+                    "{}/{topic_id}/set-temperature/{inst}/{units}",
+                    base_topic(),
+                    inst = topic_safe_string(
+                        temp_cap
+                            .map(|cap| cap.instance.as_str())
+                            .unwrap_or("targetTemperature")
+                    ),
+                    units = scale
+                ),
+                temperature_state_topic: format!(
+                    "{}/climate/{topic_id}/notify-target",
+                    base_topic()
+                ),
+                current_temperature_topic: format!(
+                    "{}/climate/{topic_id}/notify-current",
+                    base_topic()
+                ),
+                min_temp: constraints.min.value().floor(),
+                max_temp: constraints.max.value().ceil(),
+                temp_step: 1.0,
+                temperature_unit: scale.hass_temperature_unit(),
+                precision: 1.0,
+                optimistic: !use_iot,
+            },
+            modes,
+            temperature_instance: temp_cap.map(|cap| cap.instance.to_string()),
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for HeaterClimate {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("climate", state, client, &self.climate.base, &self.climate).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let pref_units: TemperatureUnits = self.state.get_temperature_scale().await.into();
+
+        let is_on = device.device_state().map(|s| s.on).unwrap_or(false);
+        let (work_mode, param) = match device.humidifier_work_mode {
+            Some(mode) => (
+                Some(mode as i64),
+                device
+                    .humidifier_param_by_mode
+                    .get(&mode)
+                    .map(|&param| param as i64),
+            ),
+            None => {
+                let value = device
+                    .get_state_capability_by_instance("workMode")
+                    .map(|cap| &cap.state);
+                (
+                    value
+                        .and_then(|v| v.pointer("/value/workMode"))
+                        .and_then(|v| v.as_i64()),
+                    value
+                        .and_then(|v| v.pointer("/value/modeValue"))
+                        .and_then(|v| v.as_i64()),
+                )
+            }
+        };
+        let mode = match work_mode {
+            Some(work_mode) if is_on => self.modes.hass_mode_for(work_mode),
+            _ => MODE_OFF,
+        };
+        client.publish(&self.climate.mode_state_topic, mode).await?;
+
+        if let Some(topic) = &self.climate.preset_mode_state_topic {
+            let preset = match param {
+                Some(level) if mode == MODE_HEAT => self.modes.level_name(level),
+                _ => None,
+            };
+            client.publish(topic, preset.unwrap_or("None")).await?;
+        }
+
+        let current = device.current_temperature();
+        if let Some(current) = current {
+            let value = current.as_unit(pref_units).value();
+            client
+                .publish(
+                    &self.climate.current_temperature_topic,
+                    format!("{value:.1}"),
+                )
+                .await?;
+        }
+
+        let target = device.heater_target_temperature.or_else(|| {
+            device.target_temperature(
+                self.temperature_instance.as_deref()?,
+                TemperatureUnits::Fahrenheit,
+            )
+        });
+        if let Some(target) = target {
+            let value = target.as_unit(pref_units).value();
+            client
+                .publish(&self.climate.temperature_state_topic, format!("{value:.1}"))
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Power on the heater, if needed, and select a work mode
+async fn heater_select_mode(
+    state: &StateHandle,
+    device: &ServiceDevice,
+    work_mode: i64,
+    value: i64,
+) -> anyhow::Result<()> {
+    if !device.device_state().map(|s| s.on).unwrap_or(false) {
+        state.device_power_on(device, true).await?;
+    }
+    state.heater_set_parameter(device, work_mode, value).await
+}
+
+pub async fn mqtt_climate_set_mode(
+    Payload(mode): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_climate_set_mode: {id}: {mode}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let modes = HeaterModes::with_device(&device)?;
+    let (work_mode, value) = match mode.as_str() {
+        MODE_OFF => return state.device_power_on(&device, false).await,
+        MODE_HEAT => modes.heat.as_ref().and_then(|(gear, levels)| {
+            // Keep the current heat level, if we know it
+            let level = device
+                .humidifier_param_by_mode
+                .get(&(*gear as u8))
+                .map(|&level| level as i64)
+                .or_else(|| levels.first().map(|(_, value)| *value))?;
+            Some((*gear, level))
+        }),
+        MODE_FAN => modes.fan,
+        MODE_AUTO => modes.auto,
+        _ => None,
+    }
+    .ok_or_else(|| anyhow!("mode {mode} is not supported by {device}"))?;
+
+    heater_select_mode(&state, &device, work_mode, value).await
+}
+
+pub async fn mqtt_climate_set_preset(
+    Payload(preset): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_climate_set_preset: {id}: {preset}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let modes = HeaterModes::with_device(&device)?;
+    let (gear, _) = modes
+        .heat
+        .as_ref()
+        .ok_or_else(|| anyhow!("{device} has no heat levels"))?;
+    let level = modes
+        .level_by_name(&preset)
+        .ok_or_else(|| anyhow!("heat level {preset} is not supported by {device}"))?;
+
+    heater_select_mode(&state, &device, *gear, level).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::from_json;
+
+    #[test]
+    fn heater_modes() {
+        let cap: DeviceCapability =
+            from_json(include_str!("../../test-data/work-mode-h7131.json")).unwrap();
+        let wm = ParsedWorkMode::with_capability(&cap).unwrap();
+        let modes = HeaterModes::with_work_modes(&wm);
+
+        assert_eq!(
+            modes.hass_modes(),
+            vec![MODE_OFF, MODE_HEAT, MODE_FAN, MODE_AUTO]
+        );
+        assert_eq!(modes.preset_modes(), vec!["Low", "Medium", "High"]);
+        assert_eq!(modes.hass_mode_for(1), MODE_HEAT);
+        assert_eq!(modes.hass_mode_for(9), MODE_FAN);
+        assert_eq!(modes.hass_mode_for(3), MODE_AUTO);
+        assert_eq!(modes.level_name(2), Some("Medium"));
+        assert_eq!(modes.level_by_name("high"), Some(3));
+        assert_eq!(modes.fan, Some((9, 0)));
+    }
+}
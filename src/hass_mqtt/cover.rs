@@ -1,13 +1,268 @@
-use crate::hass_mqtt::base::EntityConfig;
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::hass_mqtt::work_mode::ParsedWorkMode;
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{base_topic, device_topic_id, topic_safe_id, HassClient, IdParameter};
+use crate::service::state::StateHandle;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use mosquitto_rs::router::{Params, Payload, State};
 use serde::Serialize;
 
+/// The payloads that HASS publishes to the command topic
+const PAYLOAD_OPEN: &str = "OPEN";
+const PAYLOAD_CLOSE: &str = "CLOSE";
+const PAYLOAD_STOP: &str = "STOP";
+
+/// <https://www.home-assistant.io/integrations/cover.mqtt/>
 #[derive(Serialize, Clone, Debug)]
 pub struct CoverConfig {
     #[serde(flatten)]
     pub base: EntityConfig,
 
-    pub state_topic: String,
-    pub position_topic: String,
-    pub set_position_topic: String,
+    /// HASS will publish OPEN, CLOSE or STOP here
     pub command_topic: String,
+    /// Null disables the stop button for motors that can't stop
+    pub payload_stop: Option<&'static str>,
+    /// We will publish open, closed, opening, closing or stopped here
+    pub state_topic: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub position_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub set_position_topic: Option<String>,
+
+    pub optimistic: bool,
+}
+
+/// The work modes of a curtain or blind motor
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct CoverModes {
+    /// The work mode whose value is the position, from
+    /// 0 (fully closed) to 100 (fully open)
+    pub position: Option<i64>,
+    pub open: Option<(i64, i64)>,
+    pub close: Option<(i64, i64)>,
+    pub stop: Option<(i64, i64)>,
+}
+
+impl CoverModes {
+    pub fn with_device(device: &ServiceDevice) -> anyhow::Result<Self> {
+        let work_modes = ParsedWorkMode::with_device(device)?;
+        Ok(Self::with_work_modes(&work_modes))
+    }
+
+    pub fn with_work_modes(work_modes: &ParsedWorkMode) -> Self {
+        let mut modes = Self::default();
+        for mode in work_modes.modes.values() {
+            let Some(work_mode) = mode.value.as_i64() else {
+                continue;
+            };
+            let entry = (work_mode, mode.default_value());
+            match mode.name.to_ascii_lowercase().as_str() {
+                "position" | "percent" => {
+                    modes.position.replace(work_mode);
+                }
+                "open" => {
+                    modes.open.replace(entry);
+                }
+                "close" => {
+                    modes.close.replace(entry);
+                }
+                "stop" | "pause" => {
+                    modes.stop.replace(entry);
+                }
+                _ => {}
+            }
+        }
+        modes
+    }
+
+    /// The work mode and parameter that carry out the HASS
+    /// command. Open and close are implemented by moving to
+    /// the end positions if there are no dedicated modes.
+    pub fn command(&self, command: &str) -> Option<(i64, i64)> {
+        match command {
+            PAYLOAD_OPEN => self.open.or_else(|| Some((self.position?, 100))),
+            PAYLOAD_CLOSE => self.close.or_else(|| Some((self.position?, 0))),
+            PAYLOAD_STOP => self.stop,
+            _ => None,
+        }
+    }
+
+    /// Map the reported work mode and parameter to the HASS
+    /// state and, if known, the position
+    pub fn state_for(&self, work_mode: i64, param: Option<i64>) -> (&'static str, Option<i64>) {
+        if Some(work_mode) == self.position {
+            let position = param.map(|p| p.clamp(0, 100));
+            let state = if position == Some(0) {
+                "closed"
+            } else {
+                "open"
+            };
+            (state, position)
+        } else if self.open.map(|(mode, _)| mode) == Some(work_mode) {
+            ("opening", None)
+        } else if self.close.map(|(mode, _)| mode) == Some(work_mode) {
+            ("closing", None)
+        } else {
+            ("stopped", None)
+        }
+    }
+}
+
+pub struct CurtainCover {
+    cover: CoverConfig,
+    modes: CoverModes,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl CurtainCover {
+    pub async fn new(device: &ServiceDevice, state: &StateHandle) -> anyhow::Result<Self> {
+        let id = topic_safe_id(device);
+        let topic_id = device_topic_id(device);
+        let modes = CoverModes::with_device(device).unwrap_or_default();
+
+        let (position_topic, set_position_topic) = if modes.position.is_some() {
+            (
+                Some(format!("{}/cover/{topic_id}/position", base_topic())),
+                Some(format!("{}/cover/{topic_id}/set-position", base_topic())),
+            )
+        } else {
+            (None, None)
+        };
+
+        Ok(Self {
+            cover: CoverConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: None,
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: format!("gv2mqtt-{id}-cover"),
+                    device_class: Some("curtain"),
+                    icon: None,
+                },
+                command_topic: format!("{}/cover/{topic_id}/command", base_topic()),
+                payload_stop: modes.stop.map(|_| PAYLOAD_STOP),
+                state_topic: format!("{}/cover/{topic_id}/state", base_topic()),
+                position_topic,
+                set_position_topic,
+                optimistic: false,
+            },
+            modes,
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for CurtainCover {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("cover", state, client, &self.cover.base, &self.cover).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let Some((work_mode, param)) = device
+            .get_state_capability_by_instance("workMode")
+            .and_then(|cap| {
+                let mode = cap.state.pointer("/value/workMode")?.as_i64()?;
+                let param = cap
+                    .state
+                    .pointer("/value/modeValue")
+                    .and_then(|v| v.as_i64());
+                Some((mode, param))
+            })
+        else {
+            return Ok(());
+        };
+
+        let (cover_state, position) = self.modes.state_for(work_mode, param);
+        client.publish(&self.cover.state_topic, cover_state).await?;
+        if let (Some(topic), Some(position)) = (&self.cover.position_topic, position) {
+            client.publish(topic, position.to_string()).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// HASS sends OPEN, CLOSE or STOP
+pub async fn mqtt_cover_command(
+    Payload(command): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_cover_command: {id}: {command}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let modes = CoverModes::with_device(&device)?;
+    let (work_mode, value) = modes
+        .command(&command)
+        .ok_or_else(|| anyhow!("{command} is not supported by {device}"))?;
+
+    state.cover_set_parameter(&device, work_mode, value).await
+}
+
+/// HASS sends the position in the range 0 (closed) to 100 (open)
+pub async fn mqtt_cover_set_position(
+    Payload(position): Payload<i64>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_cover_set_position: {id}: {position}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let modes = CoverModes::with_device(&device)?;
+    let work_mode = modes
+        .position
+        .ok_or_else(|| anyhow!("{device} doesn't support setting the position"))?;
+
+    state
+        .cover_set_parameter(&device, work_mode, position.clamp(0, 100))
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::{from_json, DeviceCapability};
+
+    #[test]
+    fn curtain_modes() {
+        let cap: DeviceCapability =
+            from_json(include_str!("../../test-data/work-mode-curtain.json")).unwrap();
+        let wm = ParsedWorkMode::with_capability(&cap).unwrap();
+        let modes = CoverModes::with_work_modes(&wm);
+
+        assert_eq!(
+            modes,
+            CoverModes {
+                position: Some(1),
+                open: Some((2, 0)),
+                close: Some((3, 0)),
+                stop: Some((4, 0)),
+            }
+        );
+        assert_eq!(modes.command(PAYLOAD_STOP), Some((4, 0)));
+        assert_eq!(modes.state_for(1, Some(0)), ("closed", Some(0)));
+        assert_eq!(modes.state_for(1, Some(40)), ("open", Some(40)));
+        assert_eq!(modes.state_for(3, Some(0)), ("closing", None));
+        assert_eq!(modes.state_for(4, Some(0)), ("stopped", None));
+
+        let position_only = CoverModes {
+            position: Some(1),
+            ..CoverModes::default()
+        };
+        assert_eq!(position_only.command(PAYLOAD_OPEN), Some((1, 100)));
+        assert_eq!(position_only.command(PAYLOAD_CLOSE), Some((1, 0)));
+        assert_eq!(position_only.command(PAYLOAD_STOP), None);
+    }
 }
@@ -1,47 +1,62 @@
-use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
+use crate::hass_mqtt::binary_sensor::{
+    IceMakerBinarySensor, IceMakerSensorKind, PresenceBinarySensor, PresenceSensorKind,
+    WarningBinarySensor, WarningSensorKind,
+};
 use crate::hass_mqtt::button::ButtonConfig;
-use crate::hass_mqtt::climate::TargetTemperatureEntity;
+use crate::hass_mqtt::climate::{HeaterClimate, TargetTemperatureEntity};
+use crate::hass_mqtt::cover::CurtainCover;
+use crate::hass_mqtt::fan::PurifierFan;
 use crate::hass_mqtt::humidifier::Humidifier;
 use crate::hass_mqtt::instance::EntityList;
-use crate::hass_mqtt::light::DeviceLight;
-use crate::hass_mqtt::number::WorkModeNumber;
+use crate::hass_mqtt::light::{DeviceLight, GroupLight};
+use crate::hass_mqtt::number::{MistLevelNumber, OffTimerNumber, WorkModeNumber};
 use crate::hass_mqtt::scene::SceneConfig;
-use crate::hass_mqtt::select::{SceneModeSelect, WorkModeSelect};
-use crate::hass_mqtt::sensor::{CapabilitySensor, DeviceStatusDiagnostic, GlobalFixedDiagnostic};
-use crate::hass_mqtt::switch::CapabilitySwitch;
+use crate::hass_mqtt::select::{MistLevelSelect, SceneModeSelect, WorkModeSelect};
+use crate::hass_mqtt::sensor::{
+    BleSensor, BleSensorKind, CapabilitySensor, DeviceDiagnosticKind, DeviceDiagnosticSensor,
+    DeviceStatusDiagnostic, GlobalFixedDiagnostic, IotHealthSensor, ModelParamsSensor,
+    OffTimerSensor, PlatformQuotaSensor, PlugEnergyKind, PlugEnergySensor, PowerEstimateKind,
+    PowerEstimateSensor, ScheduleSensor,
+};
+use crate::hass_mqtt::switch::{CapabilitySwitch, IceMakerSwitch, ReadOnlySwitch, ToggleSwitch};
+use crate::hass_mqtt::update::FirmwareUpdate;
+use crate::hass_mqtt::water_heater::KettleWaterHeater;
 use crate::hass_mqtt::work_mode::ParsedWorkMode;
 use crate::platform_api::{DeviceCapability, DeviceCapabilityKind, DeviceType};
-use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, oneclick_topic, purge_cache_topic};
+use crate::service::device::{Device as ServiceDevice, DeviceToggle, MistLevels};
+use crate::service::hass::{oneclick_topic, purge_cache_topic};
 use crate::service::state::StateHandle;
 use crate::version_info::govee_version;
-use anyhow::Context;
 
 use uuid::Uuid;
 
-pub async fn enumerate_all_entites(state: &StateHandle) -> anyhow::Result<EntityList> {
+/// Enumerates the entities that belong to the bridge itself,
+/// rather than to any one device
+pub async fn enumerate_bridge_entities(state: &StateHandle) -> anyhow::Result<EntityList> {
     let mut entities = EntityList::new();
 
     enumerate_global_entities(state, &mut entities).await?;
     enumerate_scenes(state, &mut entities).await?;
-
-    let devices = state.devices().await;
-
-    for d in &devices {
-        enumerate_entities_for_device(d, state, &mut entities)
-            .await
-            .with_context(|| format!("Config::for_device({d})"))?;
-    }
+    enumerate_light_groups(state, &mut entities).await;
 
     Ok(entities)
 }
 
 async fn enumerate_global_entities(
-    _state: &StateHandle,
+    state: &StateHandle,
     entities: &mut EntityList,
 ) -> anyhow::Result<()> {
     entities.add(GlobalFixedDiagnostic::new("Version", govee_version()));
     entities.add(ButtonConfig::new("Purge Caches", purge_cache_topic()));
+    entities.add(ReadOnlySwitch::global(state));
+    entities.add(ModelParamsSensor::new());
+    if state.get_platform_client().await.is_some() {
+        entities.add(PlatformQuotaSensor::new(state));
+    }
+    if state.get_iot_health().await.is_some() {
+        entities.add(IotHealthSensor::new(state));
+    }
     Ok(())
 }
 
@@ -56,7 +71,7 @@ async fn enumerate_scenes(state: &StateHandle, entities: &mut EntityList) -> any
                     );
                     entities.add(SceneConfig {
                         base: EntityConfig {
-                            availability_topic: availability_topic(),
+                            availability: Availability::service(),
                             name: Some(oc.name.to_string()),
                             entity_category: None,
                             origin: Origin::default(),
@@ -79,6 +94,12 @@ async fn enumerate_scenes(state: &StateHandle, entities: &mut EntityList) -> any
     Ok(())
 }
 
+async fn enumerate_light_groups(state: &StateHandle, entities: &mut EntityList) {
+    for group in state.get_light_groups().await {
+        entities.add(GroupLight::new(&group, state).await);
+    }
+}
+
 async fn entities_for_work_mode<'a>(
     d: &ServiceDevice,
     state: &StateHandle,
@@ -149,15 +170,39 @@ pub async fn enumerate_entities_for_device<'a>(
     state: &StateHandle,
     entities: &mut EntityList,
 ) -> anyhow::Result<()> {
+    // Passively observed sensors may not be controllable at all,
+    // so these are registered ahead of the check below
+    if d.ble_sensor_reading.is_some() {
+        for kind in BleSensorKind::ALL {
+            entities.add(BleSensor::new(d, state, kind).await);
+        }
+    }
+
     if !d.is_controllable() {
         return Ok(());
     }
 
     entities.add(DeviceStatusDiagnostic::new(d, state));
+    for kind in DeviceDiagnosticKind::for_device(d) {
+        entities.add(DeviceDiagnosticSensor::new(d, state, kind));
+    }
+    entities.add(OffTimerNumber::new(d, state));
+    entities.add(OffTimerSensor::new(d, state));
+    if d.undoc_device_info.is_some() && state.get_undoc_client().await.is_some() {
+        entities.add(ScheduleSensor::new(d, state));
+        entities.add(FirmwareUpdate::new(d, state));
+    }
     entities.add(ButtonConfig::request_platform_data_for_device(d));
+    entities.add(ReadOnlySwitch::for_device(d, state));
 
     if d.supports_rgb() || d.get_color_temperature_range().is_some() || d.supports_brightness() {
         entities.add(DeviceLight::for_device(&d, state, None).await?);
+
+        if state.power_model_for_device(d).await.is_some() {
+            for kind in PowerEstimateKind::ALL {
+                entities.add(PowerEstimateSensor::new(d, state, kind));
+            }
+        }
     }
 
     if matches!(
@@ -167,6 +212,76 @@ pub async fn enumerate_entities_for_device<'a>(
         entities.add(Humidifier::new(&d, state).await?);
     }
 
+    let is_purifier = d.device_type() == DeviceType::AirPurifier;
+    if is_purifier {
+        entities.add(PurifierFan::new(d, state).await?);
+    }
+
+    let is_kettle = d.device_type() == DeviceType::Kettle;
+    if is_kettle {
+        entities.add(KettleWaterHeater::new(d, state).await?);
+    }
+
+    let is_curtain = d.device_type() == DeviceType::Curtain;
+    if is_curtain {
+        entities.add(CurtainCover::new(d, state).await?);
+    }
+
+    let is_heater = d.device_type() == DeviceType::Heater;
+    if is_heater {
+        entities.add(HeaterClimate::new(d, state).await?);
+    }
+
+    for toggle in DeviceToggle::ALL {
+        if d.supports_toggle(toggle) {
+            entities.add(ToggleSwitch::new(d, state, toggle));
+        }
+    }
+
+    match d.mist_levels() {
+        Some(MistLevels::Range(min, max)) => {
+            entities.add(MistLevelNumber::new(d, state, min, max));
+        }
+        Some(MistLevels::Named(names)) => {
+            entities.add(MistLevelSelect::new(d, state, names));
+        }
+        None => {}
+    }
+
+    if d.device_type() == DeviceType::IceMaker {
+        entities.add(IceMakerSwitch::new(d, state));
+        for kind in IceMakerSensorKind::ALL {
+            entities.add(IceMakerBinarySensor::new(d, state, kind));
+        }
+    }
+
+    if d.reports_warnings() {
+        for kind in WarningSensorKind::ALL {
+            if kind.applies_to(d.device_type()) {
+                entities.add(WarningBinarySensor::new(d, state, kind));
+            }
+        }
+    }
+
+    if d.device_type() == DeviceType::Socket
+        && d.get_capability_by_instance("powerSwitch").is_none()
+        && d.iot_api_supported()
+    {
+        entities.add(CapabilitySwitch::power_switch(d, state).await?);
+    }
+
+    if d.reports_plug_energy() {
+        for kind in PlugEnergyKind::ALL {
+            entities.add(PlugEnergySensor::new(d, state, kind));
+        }
+    }
+
+    if d.is_presence_sensor() {
+        for kind in PresenceSensorKind::ALL {
+            entities.add(PresenceBinarySensor::new(d, state, kind));
+        }
+    }
+
     if d.device_type() != DeviceType::Light {
         if let Some(scenes) = SceneModeSelect::new(d, state).await? {
             entities.add(scenes);
@@ -176,6 +291,9 @@ pub async fn enumerate_entities_for_device<'a>(
     if let Some(info) = &d.http_device_info {
         for cap in &info.capabilities {
             match &cap.kind {
+                // These are exposed by the ToggleSwitch entities above
+                DeviceCapabilityKind::Toggle
+                    if DeviceToggle::from_instance(&cap.instance).is_some() => {}
                 DeviceCapabilityKind::Toggle | DeviceCapabilityKind::OnOff => {
                     entities.add(CapabilitySwitch::new(&d, state, cap).await?);
                 }
@@ -188,6 +306,12 @@ pub async fn enumerate_entities_for_device<'a>(
 
                 DeviceCapabilityKind::Range if cap.instance == "brightness" => {}
                 DeviceCapabilityKind::Range if cap.instance == "humidity" => {}
+                // The fan entity exposes the speeds and presets
+                DeviceCapabilityKind::WorkMode if is_purifier => {}
+                // The cover entity exposes the position and movement
+                DeviceCapabilityKind::WorkMode if is_curtain => {}
+                // The climate entity exposes the heat levels and modes
+                DeviceCapabilityKind::WorkMode if is_heater => {}
                 DeviceCapabilityKind::WorkMode => {
                     entities_for_work_mode(d, state, cap, entities).await?;
                 }
@@ -196,6 +320,10 @@ pub async fn enumerate_entities_for_device<'a>(
                     entities.add(CapabilitySensor::new(&d, state, cap).await?);
                 }
 
+                // The water heater entity exposes the target temperature
+                DeviceCapabilityKind::TemperatureSetting if is_kettle => {}
+                // The climate entity exposes the target temperature
+                DeviceCapabilityKind::TemperatureSetting if is_heater => {}
                 DeviceCapabilityKind::TemperatureSetting => {
                     entities.add(TargetTemperatureEntity::new(&d, state, cap).await?);
                 }
@@ -209,7 +337,7 @@ pub async fn enumerate_entities_for_device<'a>(
             }
         }
 
-        if let Some(segments) = info.supports_segmented_rgb() {
+        if let Some(segments) = d.segment_range() {
             for n in segments {
                 entities.add(DeviceLight::for_device(&d, state, Some(n)).await?);
             }
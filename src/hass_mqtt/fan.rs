@@ -0,0 +1,392 @@
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::hass_mqtt::work_mode::ParsedWorkMode;
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{base_topic, device_topic_id, topic_safe_id, HassClient, IdParameter};
+use crate::service::state::StateHandle;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use mosquitto_rs::router::{Params, Payload, State};
+use serde::Serialize;
+
+/// Work modes whose values select a fixed fan speed
+const SPEED_MODE_NAMES: &[&str] = &["gearMode", "FanSpeed", "Manual"];
+
+/// Some devices (eg: H7122) express each fan speed as a separate
+/// work mode rather than as the value of a single mode.
+/// The order of this list defines the order of the speeds.
+const NAMED_SPEEDS: &[&str] = &["Low", "Medium", "Mid", "High", "Turbo"];
+
+/// <https://www.home-assistant.io/integrations/fan.mqtt/>
+#[derive(Serialize, Clone, Debug)]
+pub struct FanConfig {
+    #[serde(flatten)]
+    pub base: EntityConfig,
+
+    /// HASS will publish ON/OFF here to control the power
+    pub command_topic: String,
+    /// We will publish ON/OFF here
+    pub state_topic: String,
+
+    /// HASS will publish the speed, in the range
+    /// speed_range_min..=speed_range_max, here
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percentage_command_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub percentage_state_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed_range_min: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub speed_range_max: Option<usize>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset_mode_command_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset_mode_state_topic: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub preset_modes: Vec<String>,
+
+    pub optimistic: bool,
+}
+
+/// A work mode + parameter combination that can be sent to the device
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FanMode {
+    pub label: String,
+    pub work_mode: i64,
+    pub param: i64,
+    /// If false, any parameter value for work_mode is considered
+    /// to match this mode when reporting state
+    pub match_param: bool,
+}
+
+impl FanMode {
+    fn matches(&self, work_mode: i64, param: Option<i64>) -> bool {
+        self.work_mode == work_mode && (!self.match_param || param == Some(self.param))
+    }
+}
+
+/// The workMode capability of a purifier or fan, re-interpreted
+/// as an ordered list of speeds and a list of preset modes
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FanModes {
+    pub speeds: Vec<FanMode>,
+    pub presets: Vec<FanMode>,
+}
+
+impl FanModes {
+    pub fn with_device(device: &ServiceDevice) -> anyhow::Result<Self> {
+        let work_modes = ParsedWorkMode::with_device(device)?;
+        Ok(Self::with_work_modes(&work_modes))
+    }
+
+    pub fn with_work_modes(work_modes: &ParsedWorkMode) -> Self {
+        let mut modes = Self::default();
+
+        for name in SPEED_MODE_NAMES {
+            let Some(mode) = work_modes.mode_by_name(name) else {
+                continue;
+            };
+            let Some(work_mode) = mode.value.as_i64() else {
+                continue;
+            };
+
+            if let Some(range) = mode.contiguous_value_range() {
+                for param in range {
+                    modes.speeds.push(FanMode {
+                        label: param.to_string(),
+                        work_mode,
+                        param,
+                        match_param: true,
+                    });
+                }
+            } else {
+                for value in &mode.values {
+                    if let Some(param) = value.value.as_i64() {
+                        modes.speeds.push(FanMode {
+                            label: value.computed_label.to_string(),
+                            work_mode,
+                            param,
+                            match_param: true,
+                        });
+                    }
+                }
+                modes.speeds.sort_by_key(|speed| speed.param);
+            }
+
+            if !modes.speeds.is_empty() {
+                break;
+            }
+        }
+
+        if modes.speeds.is_empty() {
+            for name in NAMED_SPEEDS {
+                if let Some(mode) = work_modes.mode_by_name(name) {
+                    if let Some(work_mode) = mode.value.as_i64() {
+                        modes.speeds.push(FanMode {
+                            label: mode.label().to_string(),
+                            work_mode,
+                            param: mode.default_value(),
+                            match_param: false,
+                        });
+                    }
+                }
+            }
+        }
+
+        for mode in work_modes.modes.values() {
+            let Some(work_mode) = mode.value.as_i64() else {
+                continue;
+            };
+            if modes
+                .speeds
+                .iter()
+                .any(|speed| speed.work_mode == work_mode)
+            {
+                continue;
+            }
+            modes.presets.push(FanMode {
+                label: mode.label().to_string(),
+                work_mode,
+                param: mode.default_value(),
+                match_param: false,
+            });
+        }
+
+        modes
+    }
+
+    pub fn speed_index(&self, work_mode: i64, param: Option<i64>) -> Option<usize> {
+        self.speeds
+            .iter()
+            .position(|speed| speed.matches(work_mode, param))
+    }
+
+    pub fn preset(&self, work_mode: i64, param: Option<i64>) -> Option<&FanMode> {
+        self.presets
+            .iter()
+            .find(|preset| preset.matches(work_mode, param))
+    }
+
+    pub fn preset_by_label(&self, label: &str) -> Option<&FanMode> {
+        self.presets.iter().find(|preset| preset.label == label)
+    }
+}
+
+#[derive(Clone)]
+pub struct PurifierFan {
+    fan: FanConfig,
+    modes: FanModes,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl PurifierFan {
+    pub async fn new(device: &ServiceDevice, state: &StateHandle) -> anyhow::Result<Self> {
+        let id = topic_safe_id(device);
+        let topic_id = device_topic_id(device);
+        let use_iot = device.iot_api_supported() && state.get_iot_client().await.is_some();
+        let modes = FanModes::with_device(device).unwrap_or_default();
+
+        let (percentage_command_topic, percentage_state_topic, speed_range_min, speed_range_max) =
+            if modes.speeds.is_empty() {
+                (None, None, None, None)
+            } else {
+                (
+                    Some(format!("{}/fan/{topic_id}/set-speed", base_topic())),
+                    Some(format!("{}/fan/{topic_id}/notify-speed", base_topic())),
+                    Some(1),
+                    Some(modes.speeds.len()),
+                )
+            };
+
+        let (preset_mode_command_topic, preset_mode_state_topic) = if modes.presets.is_empty() {
+            (None, None)
+        } else {
+            (
+                Some(format!("{}/fan/{topic_id}/set-preset", base_topic())),
+                Some(format!("{}/fan/{topic_id}/notify-preset", base_topic())),
+            )
+        };
+
+        Ok(Self {
+            fan: FanConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: None,
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: format!("gv2mqtt-{id}-fan"),
+                    device_class: None,
+                    icon: Some("mdi:air-purifier".to_string()),
+                },
+                // Route power to the general power switch handler
+                command_topic: format!("{}/switch/{topic_id}/command/powerSwitch", base_topic()),
+                state_topic: format!("{}/fan/{topic_id}/state", base_topic()),
+                percentage_command_topic,
+                percentage_state_topic,
+                speed_range_min,
+                speed_range_max,
+                preset_mode_command_topic,
+                preset_mode_state_topic,
+                preset_modes: modes.presets.iter().map(|p| p.label.to_string()).collect(),
+                optimistic: !use_iot,
+            },
+            modes,
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for PurifierFan {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("fan", state, client, &self.fan.base, &self.fan).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let is_on = device.device_state().map(|s| s.on).unwrap_or(false);
+        client
+            .publish(&self.fan.state_topic, if is_on { "ON" } else { "OFF" })
+            .await?;
+
+        let current = match device.humidifier_work_mode {
+            Some(mode) => Some((
+                mode as i64,
+                device
+                    .humidifier_param_by_mode
+                    .get(&mode)
+                    .map(|&p| p as i64),
+            )),
+            None => device
+                .get_state_capability_by_instance("workMode")
+                .and_then(|cap| {
+                    let mode = cap.state.pointer("/value/workMode")?.as_i64()?;
+                    let param = cap
+                        .state
+                        .pointer("/value/modeValue")
+                        .and_then(|v| v.as_i64());
+                    Some((mode, param))
+                }),
+        };
+
+        let Some((work_mode, param)) = current else {
+            return Ok(());
+        };
+
+        if let Some(idx) = self.modes.speed_index(work_mode, param) {
+            if let Some(topic) = &self.fan.percentage_state_topic {
+                client.publish(topic, (idx + 1).to_string()).await?;
+            }
+            if let Some(topic) = &self.fan.preset_mode_state_topic {
+                client.publish(topic, "None").await?;
+            }
+        } else if let Some(preset) = self.modes.preset(work_mode, param) {
+            if let Some(topic) = &self.fan.preset_mode_state_topic {
+                client.publish(topic, preset.label.to_string()).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// HASS sends the speed in the range 1..=number of speeds,
+/// or 0 to indicate that the fan should be turned off
+pub async fn mqtt_fan_set_speed(
+    Payload(speed): Payload<usize>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_fan_set_speed: {id}: {speed}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    if speed == 0 {
+        return state.device_power_on(&device, false).await;
+    }
+
+    let modes = FanModes::with_device(&device)?;
+    let mode = modes
+        .speeds
+        .get(speed - 1)
+        .ok_or_else(|| anyhow!("speed {speed} is out of range for {device}"))?;
+
+    state
+        .purifier_set_parameter(&device, mode.work_mode, mode.param)
+        .await
+}
+
+pub async fn mqtt_fan_set_preset(
+    Payload(preset): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_fan_set_preset: {id}: {preset}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let modes = FanModes::with_device(&device)?;
+    let mode = modes
+        .preset_by_label(&preset)
+        .ok_or_else(|| anyhow!("preset {preset} not found for {device}"))?;
+
+    state
+        .purifier_set_parameter(&device, mode.work_mode, mode.param)
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::{from_json, DeviceCapability, DeviceType, HttpDeviceInfo};
+
+    #[test]
+    fn speed_range_mode() {
+        let cap: DeviceCapability =
+            from_json(include_str!("../../test-data/work-mode-issue-93.json")).unwrap();
+        let wm = ParsedWorkMode::with_capability(&cap).unwrap();
+        let modes = FanModes::with_work_modes(&wm);
+
+        assert_eq!(modes.speeds.len(), 8);
+        assert_eq!(modes.speed_index(1, Some(3)), Some(2));
+        assert_eq!(
+            modes
+                .presets
+                .iter()
+                .map(|p| p.label.as_str())
+                .collect::<Vec<_>>(),
+            vec!["Auto", "Custom", "Nature", "Sleep", "Storm"]
+        );
+        assert_eq!(modes.preset(3, Some(0)).unwrap().label, "Auto");
+    }
+
+    #[test]
+    fn named_speed_modes() {
+        let resp: serde_json::Value =
+            from_json(include_str!("../../test-data/list_devices_issue4.json")).unwrap();
+        let devices: Vec<HttpDeviceInfo> = serde_json::from_value(resp["data"].clone()).unwrap();
+        let purifier = devices
+            .iter()
+            .find(|d| d.device_type == DeviceType::AirPurifier)
+            .unwrap();
+        let cap = purifier.capability_by_instance("workMode").unwrap();
+        let wm = ParsedWorkMode::with_capability(cap).unwrap();
+        let modes = FanModes::with_work_modes(&wm);
+
+        assert_eq!(
+            modes
+                .speeds
+                .iter()
+                .map(|p| p.label.as_str())
+                .collect::<Vec<_>>(),
+            vec!["Low", "Medium", "High"]
+        );
+        assert_eq!(modes.speed_index(2, Some(0)), Some(1));
+        assert_eq!(modes.preset(16, None).unwrap().label, "Sleep");
+    }
+}
@@ -1,19 +1,20 @@
-use crate::ble::TargetHumidity;
-use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::hass_mqtt::work_mode::ParsedWorkMode;
 use crate::platform_api::{DeviceParameters, DeviceType, IntegerRange};
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, HassClient, IdParameter};
+use crate::service::hass::{base_topic, device_topic_id, topic_safe_id, HassClient, IdParameter};
 use crate::service::state::StateHandle;
 use anyhow::anyhow;
 use async_trait::async_trait;
 use mosquitto_rs::router::{Params, Payload, State};
 use serde::Serialize;
-use serde_json::json;
 
 pub const DEVICE_CLASS_HUMIDITY: &str = "humidity";
 
+pub const MODE_AUTO: &str = "auto";
+pub const MODE_MANUAL: &str = "manual";
+
 /// <https://www.home-assistant.io/integrations/humidifier.mqtt>
 #[derive(Serialize, Clone, Debug)]
 pub struct HumidifierConfig {
@@ -43,6 +44,83 @@ pub struct HumidifierConfig {
     pub modes: Vec<String>,
 
     pub state_topic: String,
+
+    /// we will publish the current humidity here
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub current_humidity_topic: Option<String>,
+}
+
+/// One of the work modes of a humidifier, as presented to hass
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct HumidifierMode {
+    pub hass_name: String,
+    pub work_mode: i64,
+    pub default_value: i64,
+}
+
+/// Maps the work modes of a humidifier onto the hass modes.
+/// The Govee names are lowercased so that the common modes
+/// (auto, sleep and so on) are translated by hass; the manual
+/// mist level mode is always presented as manual.
+#[derive(Clone, Debug, Default)]
+pub struct HumidifierModes {
+    pub modes: Vec<HumidifierMode>,
+    /// The work mode in which the device maintains the target humidity
+    pub auto: Option<i64>,
+}
+
+impl HumidifierModes {
+    pub fn with_device(device: &ServiceDevice) -> anyhow::Result<Self> {
+        let work_modes = ParsedWorkMode::with_device(device)?;
+        Ok(Self::with_work_modes(&work_modes))
+    }
+
+    pub fn with_work_modes(work_modes: &ParsedWorkMode) -> Self {
+        let mut modes = Self::default();
+        for mode in work_modes.modes.values() {
+            let Some(work_mode) = mode.value.as_i64() else {
+                continue;
+            };
+            let hass_name = match mode.name.to_lowercase().as_str() {
+                "gearmode" | "manual" => MODE_MANUAL.to_string(),
+                name => name.to_string(),
+            };
+            if hass_name == MODE_AUTO {
+                modes.auto.replace(work_mode);
+            }
+            modes.modes.push(HumidifierMode {
+                hass_name,
+                work_mode,
+                default_value: mode.default_value(),
+            });
+        }
+        modes.modes.sort_by_key(|mode| mode.work_mode);
+        modes
+    }
+
+    pub fn hass_modes(&self) -> Vec<String> {
+        self.modes
+            .iter()
+            .map(|mode| mode.hass_name.clone())
+            .collect()
+    }
+
+    pub fn hass_mode_for(&self, work_mode: i64) -> Option<&str> {
+        self.modes
+            .iter()
+            .find(|mode| mode.work_mode == work_mode)
+            .map(|mode| mode.hass_name.as_str())
+    }
+
+    /// Also accepts the Govee names, so that automations that
+    /// were written against those continue to work
+    pub fn by_name(&self, name: &str) -> Option<&HumidifierMode> {
+        let name = match name.to_lowercase().as_str() {
+            "gearmode" => MODE_MANUAL.to_string(),
+            name => name.to_string(),
+        };
+        self.modes.iter().find(|mode| mode.hass_name == name)
+    }
 }
 
 #[derive(Clone)]
@@ -67,27 +145,36 @@ impl Humidifier {
         // command_topic controls the power state; just route it to
         // the general power switch handler
         let command_topic = format!(
-            "gv2mqtt/switch/{id}/command/powerSwitch",
-            id = topic_safe_id(device)
+            "{}/switch/{id}/command/powerSwitch",
+            base_topic(),
+            id = device_topic_id(device)
         );
 
         let target_humidity_command_topic = format!(
-            "gv2mqtt/humidifier/{id}/set-target",
-            id = topic_safe_id(device)
+            "{}/humidifier/{id}/set-target",
+            base_topic(),
+            id = device_topic_id(device)
         );
         let target_humidity_state_topic = format!(
-            "gv2mqtt/humidifier/{id}/notify-target",
-            id = topic_safe_id(device)
+            "{}/humidifier/{id}/notify-target",
+            base_topic(),
+            id = device_topic_id(device)
+        );
+        let state_topic = format!(
+            "{}/humidifier/{id}/state",
+            base_topic(),
+            id = device_topic_id(device)
         );
-        let state_topic = format!("gv2mqtt/humidifier/{id}/state", id = topic_safe_id(device));
 
         let mode_command_topic = format!(
-            "gv2mqtt/humidifier/{id}/set-mode",
-            id = topic_safe_id(device)
+            "{}/humidifier/{id}/set-mode",
+            base_topic(),
+            id = device_topic_id(device)
         );
         let mode_state_topic = format!(
-            "gv2mqtt/humidifier/{id}/notify-mode",
-            id = topic_safe_id(device)
+            "{}/humidifier/{id}/notify-mode",
+            base_topic(),
+            id = device_topic_id(device)
         );
 
         let unique_id = format!("gv2mqtt-{id}-humidifier", id = topic_safe_id(device),);
@@ -95,11 +182,22 @@ impl Humidifier {
         let mut min_humidity = None;
         let mut max_humidity = None;
 
-        let work_mode = ParsedWorkMode::with_device(device).ok();
-        let modes = work_mode
-            .as_ref()
-            .map(|wm| wm.get_mode_names())
-            .unwrap_or(vec![]);
+        let modes = HumidifierModes::with_device(device)
+            .map(|modes| modes.hass_modes())
+            .unwrap_or_default();
+
+        let current_humidity_topic = if device
+            .get_capability_by_instance("sensorHumidity")
+            .is_some()
+        {
+            Some(format!(
+                "{}/humidifier/{id}/notify-humidity",
+                base_topic(),
+                id = device_topic_id(device)
+            ))
+        } else {
+            None
+        };
 
         if let Some(info) = &device.http_device_info {
             if let Some(cap) = info.capability_by_instance("humidity") {
@@ -121,7 +219,7 @@ impl Humidifier {
         Ok(Self {
             humidifier: HumidifierConfig {
                 base: EntityConfig {
-                    availability_topic: availability_topic(),
+                    availability: Availability::for_device(device),
                     name: if matches!(
                         device.device_type(),
                         DeviceType::Humidifier | DeviceType::Dehumidifier
@@ -148,6 +246,7 @@ impl Humidifier {
                 mode_state_topic,
                 modes,
                 state_topic,
+                current_humidity_topic,
                 optimistic,
             },
             device_id: device.id.to_string(),
@@ -170,11 +269,9 @@ impl EntityInstance for Humidifier {
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
-        let device = self
-            .state
-            .device_by_id(&self.device_id)
-            .await
-            .expect("device to exist");
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
 
         match device.device_state() {
             Some(device_state) => {
@@ -216,28 +313,27 @@ impl EntityInstance for Humidifier {
                 .await?;
         }
 
-        if let Some(mode_value) = device.humidifier_work_mode {
-            if let Ok(work_mode) = ParsedWorkMode::with_device(&device) {
-                let mode_value_json = json!(mode_value);
-                if let Some(mode) = work_mode.mode_for_value(&mode_value_json) {
-                    client
-                        .publish(&self.humidifier.mode_state_topic, mode.name.to_string())
-                        .await?;
-                }
-            }
-        } else {
-            let work_modes = ParsedWorkMode::with_device(&device)?;
-
-            if let Some(cap) = device.get_state_capability_by_instance("workMode") {
-                if let Some(mode_num) = cap.state.pointer("/value/workMode") {
-                    if let Some(mode) = work_modes.mode_for_value(mode_num) {
-                        return client
-                            .publish(&self.humidifier.mode_state_topic, mode.name.to_string())
-                            .await;
-                    }
-                }
+        if let Some(topic) = &self.humidifier.current_humidity_topic {
+            if let Some(humidity) = device.current_humidity_percent() {
+                client.publish(topic, format!("{humidity:.0}")).await?;
             }
         }
+
+        let modes = HumidifierModes::with_device(&device)?;
+        let work_mode = device
+            .humidifier_work_mode
+            .map(|mode| mode as i64)
+            .or_else(|| {
+                device
+                    .get_state_capability_by_instance("workMode")
+                    .and_then(|cap| cap.state.pointer("/value/workMode"))
+                    .and_then(|mode| mode.as_i64())
+            });
+        if let Some(mode) = work_mode.and_then(|mode| modes.hass_mode_for(mode)) {
+            client
+                .publish(&self.humidifier.mode_state_topic, mode.to_string())
+                .await?;
+        }
         Ok(())
     }
 }
@@ -250,76 +346,71 @@ pub async fn mqtt_device_set_work_mode(
     log::info!("mqtt_humidifier_set_mode: {id}: {mode}");
     let device = state.resolve_device_for_control(&id).await?;
 
-    let work_modes = ParsedWorkMode::with_device(&device)?;
-    let work_mode = work_modes
-        .mode_by_name(&mode)
+    let modes = HumidifierModes::with_device(&device)?;
+    let mode = modes
+        .by_name(&mode)
         .ok_or_else(|| anyhow!("mode {mode} not found"))?;
-    let mode_num = work_mode
-        .value
-        .as_i64()
-        .ok_or_else(|| anyhow::anyhow!("expected workMode to be a number"))?;
 
-    let value = work_mode.default_value();
+    if modes.auto == Some(mode.work_mode) {
+        if let Some(percent) = device.target_humidity_percent {
+            return state
+                .humidifier_set_target_humidity(&device, modes.auto, percent)
+                .await;
+        }
+    }
+
+    // Keep the current parameter for the mode, such as the
+    // mist level, if we know it
+    let value = device
+        .humidifier_param_by_mode
+        .get(&(mode.work_mode as u8))
+        .map(|&param| param as i64)
+        .unwrap_or(mode.default_value);
 
     state
-        .humidifier_set_parameter(&device, mode_num, value)
+        .humidifier_set_parameter(&device, mode.work_mode, value)
         .await?;
 
     Ok(())
 }
 
 pub async fn mqtt_humidifier_set_target(
-    Payload(percent): Payload<i64>,
+    Payload(percent): Payload<u8>,
     Params(IdParameter { id }): Params<IdParameter>,
     State(state): State<StateHandle>,
 ) -> anyhow::Result<()> {
     log::info!("mqtt_humidifier_set_target: {id}: {percent}");
 
     let device = state.resolve_device_for_control(&id).await?;
-
-    let use_iot = device.pollable_via_iot() && state.get_iot_client().await.is_some();
-
-    if !use_iot {
-        if let Some(info) = &device.http_device_info {
-            if let Some(cap) = info.capability_by_instance("humidity") {
-                state.device_control(&device, cap, percent).await?;
-
-                // We're running in optimistic mode; stash
-                // the last set value so that we can report it
-                // to hass
-                state
-                    .device_mut(&device.sku, &device.id)
-                    .await
-                    .set_target_humidity(percent as u8);
-
-                // For the H7160 at least, setting the humidity
-                // will put the device into auto mode and turn
-                // it on, however, we don't know that the device
-                // is actually turned on.
-                //
-                // This is handled by the device_was_controlled
-                // stuff; it will cause us to poll the device
-                // after a short delay, and that should fix up
-                // the reported device state.
-                return Ok(());
-            }
-        }
-    }
-
-    let work_modes = ParsedWorkMode::with_device(&device)?;
-    let work_mode = work_modes
-        .mode_by_name("Auto")
-        .ok_or_else(|| anyhow!("mode Auto not found"))?;
-    let mode_num = work_mode
-        .value
-        .as_i64()
-        .ok_or_else(|| anyhow::anyhow!("expected workMode to be a number"))?;
-
-    let value = TargetHumidity::from_percent(percent as u8);
+    let auto = HumidifierModes::with_device(&device)
+        .ok()
+        .and_then(|modes| modes.auto);
 
     state
-        .humidifier_set_parameter(&device, mode_num, value.into_inner().into())
-        .await?;
+        .humidifier_set_target_humidity(&device, auto, percent)
+        .await
+}
 
-    Ok(())
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::{from_json, DeviceCapability};
+
+    #[test]
+    fn humidifier_modes() {
+        let cap: DeviceCapability =
+            from_json(include_str!("../../test-data/work-mode-h7141.json")).unwrap();
+        let wm = ParsedWorkMode::with_capability(&cap).unwrap();
+        let modes = HumidifierModes::with_work_modes(&wm);
+
+        assert_eq!(modes.hass_modes(), vec!["manual", "custom", "auto"]);
+        assert_eq!(modes.auto, Some(3));
+        assert_eq!(modes.hass_mode_for(1), Some(MODE_MANUAL));
+        assert_eq!(modes.hass_mode_for(3), Some(MODE_AUTO));
+        assert_eq!(modes.hass_mode_for(7), None);
+        assert_eq!(modes.by_name("Manual").unwrap().work_mode, 1);
+        assert_eq!(modes.by_name("custom").unwrap().work_mode, 2);
+        assert_eq!(modes.by_name("auto").unwrap().default_value, 40);
+        assert!(modes.by_name("turbo").is_none());
+    }
 }
@@ -19,15 +19,40 @@ pub async fn publish_entity_config<T: Serialize>(
     base: &EntityConfig,
     config: &T,
 ) -> anyhow::Result<()> {
-    // TODO: remember all published topics for future GC
-
     let disco = state.get_hass_disco_prefix().await;
     let topic = format!(
         "{disco}/{integration}/{unique_id}/config",
         unique_id = base.unique_id
     );
 
-    client.publish_obj(topic, config).await
+    let config = serde_json::to_value(config)?;
+    match state
+        .apply_discovery_overrides(integration, base, config)
+        .await
+    {
+        Some(config) => {
+            state.record_discovery_topic(&topic, true).await;
+            client.publish_obj(topic, config).await
+        }
+        None => {
+            state.record_discovery_topic(&topic, false).await;
+            client.publish(topic, "").await
+        }
+    }
+}
+
+/// Remove a previously published entity from hass
+pub async fn remove_entity_config(
+    integration: &str,
+    state: &StateHandle,
+    client: &HassClient,
+    unique_id: &str,
+) -> anyhow::Result<()> {
+    let disco = state.get_hass_disco_prefix().await;
+    let topic = format!("{disco}/{integration}/{unique_id}/config");
+
+    state.record_discovery_topic(&topic, false).await;
+    client.publish(topic, "").await
 }
 
 #[derive(Default, Clone)]
@@ -1,11 +1,13 @@
-use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::platform_api::DeviceType;
 use crate::service::device::Device as ServiceDevice;
 use crate::service::hass::{
-    availability_topic, kelvin_to_mired, light_segment_state_topic, light_state_topic,
-    topic_safe_id, HassClient,
+    base_topic, device_topic_id, kelvin_to_mired, light_attributes_topic,
+    light_group_command_topic, light_group_state_topic, light_segment_state_topic,
+    light_state_topic, topic_safe_id, HassClient,
 };
+use crate::service::light_group::LightGroupConfig;
 use crate::service::state::StateHandle;
 use async_trait::async_trait;
 use serde::Serialize;
@@ -22,6 +24,10 @@ pub struct LightConfig {
     /// The docs say that this is optional, but hass errors out if
     /// it is not passed
     pub state_topic: String,
+    /// Carries the segment count, segment colors and gradient
+    /// state, for devices that have them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attributes_topic: Option<String>,
     pub optimistic: bool,
     pub supported_color_modes: Vec<String>,
     /// Flag that defines if the light supports brightness.
@@ -46,6 +52,15 @@ pub struct LightConfig {
     pub payload_available: String,
 }
 
+/// The color modes that we advertise for a light that supports
+/// rgb. The device only understands rgb, but accepting hs and xy
+/// lets HASS pass through what the user picked from the color wheel
+/// rather than its own rgb approximation.
+/// We always report state in rgb mode.
+fn rgb_color_modes() -> Vec<String> {
+    vec!["hs".to_string(), "rgb".to_string(), "xy".to_string()]
+}
+
 impl LightConfig {
     pub async fn publish(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
         publish_entity_config("light", state, client, &self.base, self).await
@@ -62,7 +77,18 @@ pub struct DeviceLight {
 #[async_trait]
 impl EntityInstance for DeviceLight {
     async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
-        self.light.publish(&state, &client).await
+        self.light.publish(state, client).await?;
+
+        // Only the light for the whole device has attributes;
+        // segments share its scenes
+        if self.light.json_attributes_topic.is_some() {
+            if let Some(device) = state.device_by_id(&self.device_id).await {
+                if let Err(err) = state.publish_scene_attributes(&device, client).await {
+                    log::warn!("Unable to publish the scenes of {device}: {err:#}");
+                }
+            }
+        }
+        Ok(())
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -70,15 +96,49 @@ impl EntityInstance for DeviceLight {
             return Ok(());
         }
 
-        let device = self
-            .state
-            .device_by_id(&self.device_id)
-            .await
-            .expect("device to exist");
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
+
+        if let Some(topic) = &self.light.json_attributes_topic {
+            let segment_count = device.segment_count();
+            if segment_count.is_some()
+                || !device.segment_colors.is_empty()
+                || device.gradient.is_some()
+            {
+                let segments: Vec<_> = device
+                    .segment_colors
+                    .iter()
+                    .map(|(n, color)| {
+                        json!({
+                            "segment": n + 1,
+                            "brightness": color.brightness,
+                            "r": color.r,
+                            "g": color.g,
+                            "b": color.b,
+                        })
+                    })
+                    .collect();
+                client
+                    .publish_obj(
+                        topic,
+                        &json!({
+                            "segment_count": segment_count,
+                            "segments": segments,
+                            "gradient": device.gradient,
+                        }),
+                    )
+                    .await?;
+            }
+        }
 
         match device.device_state() {
-            Some(device_state) => {
+            Some(mut device_state) => {
                 log::trace!("LightConfig::notify_state: state is {device_state:?}");
+                if let Some(scene) = &device_state.scene {
+                    device_state.scene =
+                        Some(self.state.categorize_scene_name(&device, scene).await);
+                }
 
                 let is_on = device_state.light_on.unwrap_or(false);
 
@@ -134,10 +194,15 @@ impl DeviceLight {
         let device_type = device.device_type();
 
         let command_topic = match segment {
-            None => format!("gv2mqtt/light/{id}/command", id = topic_safe_id(device)),
+            None => format!(
+                "{}/light/{id}/command",
+                base_topic(),
+                id = device_topic_id(device)
+            ),
             Some(seg) => format!(
-                "gv2mqtt/light/{id}/command/{seg}",
-                id = topic_safe_id(device)
+                "{}/light/{id}/command/{seg}",
+                base_topic(),
+                id = device_topic_id(device)
             ),
         };
 
@@ -151,7 +216,10 @@ impl DeviceLight {
             Some(seg) => light_segment_state_topic(device, seg),
             None => light_state_topic(device),
         };
-        let availability_topic = availability_topic();
+        let json_attributes_topic = match segment {
+            Some(_) => None,
+            None => Some(light_attributes_topic(device)),
+        };
         let unique_id = format!(
             "gv2mqtt-{id}{seg}",
             id = topic_safe_id(device),
@@ -173,7 +241,7 @@ impl DeviceLight {
         let mut supported_color_modes = vec![];
 
         if segment.is_some() || device.supports_rgb() {
-            supported_color_modes.push("rgb".to_string());
+            supported_color_modes.extend(rgb_color_modes());
         }
 
         let (min_mireds, max_mireds) = if segment.is_some() {
@@ -207,7 +275,7 @@ impl DeviceLight {
         Ok(Self {
             light: LightConfig {
                 base: EntityConfig {
-                    availability_topic,
+                    availability: Availability::for_device(device),
                     name,
                     device_class: None,
                     origin: Origin::default(),
@@ -219,6 +287,7 @@ impl DeviceLight {
                 schema: "json".to_string(),
                 command_topic,
                 state_topic,
+                json_attributes_topic,
                 supported_color_modes,
                 brightness,
                 brightness_scale: 100,
@@ -235,3 +304,105 @@ impl DeviceLight {
         })
     }
 }
+
+/// A light that represents a group of devices
+#[derive(Clone)]
+pub struct GroupLight {
+    light: LightConfig,
+    group: LightGroupConfig,
+    state: StateHandle,
+}
+
+#[async_trait]
+impl EntityInstance for GroupLight {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.light.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        self.state
+            .publish_light_group_state(&self.group, client)
+            .await
+    }
+}
+
+impl GroupLight {
+    pub fn unique_id(group: &LightGroupConfig) -> String {
+        format!("gv2mqtt-group-{}", group.topic_id())
+    }
+
+    /// The group supports the union of the color modes of its members,
+    /// but only the scenes that all of its members have in common
+    pub async fn new(group: &LightGroupConfig, state: &StateHandle) -> Self {
+        let members = state.light_group_members(group).await;
+
+        let mut supports_rgb = false;
+        let mut kelvin_range: Option<(u32, u32)> = None;
+        let mut effect_list: Option<Vec<String>> = None;
+
+        for device in &members {
+            supports_rgb |= device.supports_rgb();
+            if let Some((min, max)) = device.get_color_temperature_range() {
+                kelvin_range = Some(match kelvin_range {
+                    Some((lo, hi)) => (lo.min(min), hi.max(max)),
+                    None => (min, max),
+                });
+            }
+
+            let scenes = match state.device_list_scenes(device).await {
+                Ok(scenes) => scenes,
+                Err(err) => {
+                    log::error!("Unable to list scenes for {device}: {err:#}");
+                    vec![]
+                }
+            };
+            effect_list = Some(match effect_list {
+                Some(common) => common.into_iter().filter(|s| scenes.contains(s)).collect(),
+                None => scenes,
+            });
+        }
+
+        let mut supported_color_modes = vec![];
+        if supports_rgb {
+            supported_color_modes.extend(rgb_color_modes());
+        }
+        let (min_mireds, max_mireds) = match kelvin_range {
+            Some((min, max)) => {
+                supported_color_modes.push("color_temp".to_string());
+                (Some(kelvin_to_mired(max)), Some(kelvin_to_mired(min)))
+            }
+            None => (None, None),
+        };
+
+        Self {
+            light: LightConfig {
+                base: EntityConfig {
+                    availability: Availability::service(),
+                    name: None,
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_light_group(group),
+                    unique_id: Self::unique_id(group),
+                    entity_category: None,
+                    icon: None,
+                },
+                schema: "json".to_string(),
+                command_topic: light_group_command_topic(group),
+                state_topic: light_group_state_topic(group),
+                json_attributes_topic: None,
+                supported_color_modes,
+                brightness: true,
+                brightness_scale: 100,
+                effect: true,
+                effect_list: effect_list.unwrap_or_default(),
+                payload_available: "online".to_string(),
+                max_mireds,
+                min_mireds,
+                optimistic: false,
+                icon: Some("mdi:lightbulb-group".to_string()),
+            },
+            group: group.clone(),
+            state: state.clone(),
+        }
+    }
+}
@@ -1,7 +1,10 @@
-use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, topic_safe_string, HassClient};
+use crate::service::hass::{
+    base_topic, device_topic_id, topic_safe_id, topic_safe_string, HassClient, IdParameter,
+};
+use crate::service::off_timer::MAX_OFF_TIMER_MINUTES;
 use crate::service::state::StateHandle;
 use anyhow::anyhow;
 use async_trait::async_trait;
@@ -62,8 +65,9 @@ impl WorkModeNumber {
         range: Option<Range<i64>>,
     ) -> Self {
         let command_topic = format!(
-            "gv2mqtt/number/{id}/command/{mode}/{mode_num}",
-            id = topic_safe_id(device),
+            "{}/number/{id}/command/{mode}/{mode_num}",
+            base_topic(),
+            id = device_topic_id(device),
             mode = topic_safe_string(mode_name),
             mode_num = work_mode
                 .as_i64()
@@ -71,12 +75,12 @@ impl WorkModeNumber {
                 .unwrap_or_else(|| "work-mode-was-not-int".to_string()),
         );
         let state_topic = format!(
-            "gv2mqtt/number/{id}/state/{mode}",
-            id = topic_safe_id(device),
+            "{}/number/{id}/state/{mode}",
+            base_topic(),
+            id = device_topic_id(device),
             mode = topic_safe_string(mode_name)
         );
 
-        let availability_topic = availability_topic();
         let unique_id = format!(
             "gv2mqtt-{id}-{mode}-number",
             id = topic_safe_id(device),
@@ -86,7 +90,7 @@ impl WorkModeNumber {
         Self {
             number: NumberConfig {
                 base: EntityConfig {
-                    availability_topic,
+                    availability: Availability::for_device(device),
                     name: Some(label),
                     device_class: None,
                     origin: Origin::default(),
@@ -126,11 +130,9 @@ impl EntityInstance for WorkModeNumber {
             .as_ref()
             .ok_or_else(|| anyhow!("state_topic is None!?"))?;
 
-        let device = self
-            .state
-            .device_by_id(&self.device_id)
-            .await
-            .expect("device to exist");
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
 
         if let Some(cap) = device.get_state_capability_by_instance("workMode") {
             if let Some(work_mode) = cap.state.pointer("/value/workMode") {
@@ -167,6 +169,175 @@ impl EntityInstance for WorkModeNumber {
     }
 }
 
+/// The mist level of a humidifier with numbered levels
+pub struct MistLevelNumber {
+    number: NumberConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl MistLevelNumber {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, min: u8, max: u8) -> Self {
+        let command_topic = format!(
+            "{}/{id}/set-mist-level",
+            base_topic(),
+            id = device_topic_id(device)
+        );
+        let state_topic = format!(
+            "{}/{id}/notify-mist-level",
+            base_topic(),
+            id = device_topic_id(device)
+        );
+        let unique_id = format!("gv2mqtt-{id}-mist-level", id = topic_safe_id(device));
+
+        Self {
+            number: NumberConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Mist Level".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: Some("mdi:weather-fog".to_string()),
+                },
+                command_topic,
+                state_topic: Some(state_topic),
+                min: Some(min.into()),
+                max: Some(max.into()),
+                step: 1f32,
+                unit_of_measurement: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for MistLevelNumber {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.number.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
+
+        match device.mist_level() {
+            Some(level) => self.number.notify_state(client, &level.to_string()).await,
+            None => Ok(()),
+        }
+    }
+}
+
+/// Sets the off timer of a device, in minutes; zero cancels it
+pub struct OffTimerNumber {
+    number: NumberConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl OffTimerNumber {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let command_topic = format!(
+            "{}/{id}/set-off-timer",
+            base_topic(),
+            id = device_topic_id(device)
+        );
+        let state_topic = format!(
+            "{}/{id}/notify-off-timer",
+            base_topic(),
+            id = device_topic_id(device)
+        );
+        let unique_id = format!("gv2mqtt-{id}-off-timer", id = topic_safe_id(device));
+
+        Self {
+            number: NumberConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Off Timer".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: Some("config".to_string()),
+                    icon: Some("mdi:timer-off-outline".to_string()),
+                },
+                command_topic,
+                state_topic: Some(state_topic),
+                min: Some(0.),
+                max: Some(MAX_OFF_TIMER_MINUTES.into()),
+                step: 1f32,
+                unit_of_measurement: Some("min"),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for OffTimerNumber {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.number.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let minutes = device
+            .off_timer
+            .map(|timer| timer.remaining_minutes())
+            .unwrap_or(0);
+        self.number.notify_state(client, &minutes.to_string()).await
+    }
+}
+
+pub async fn mqtt_set_off_timer(
+    Payload(minutes): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_set_off_timer: {id}: {minutes}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    // hass may send a float value for the number
+    let minutes: f64 = minutes
+        .trim()
+        .parse()
+        .map_err(|err| anyhow!("invalid off timer {minutes}: {err:#}"))?;
+    anyhow::ensure!(minutes >= 0., "the off timer cannot be negative");
+
+    state
+        .device_set_off_timer(&device, minutes.round() as u16)
+        .await
+}
+
+/// Handles both the number and the select flavors of
+/// the mist level entity
+pub async fn mqtt_set_mist_level(
+    Payload(value): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_set_mist_level: {id}: {value}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let levels = device
+        .mist_levels()
+        .ok_or_else(|| anyhow!("{device} has no mist levels"))?;
+    let level = levels
+        .parse_level(&value)
+        .ok_or_else(|| anyhow!("{value} is not a valid mist level for {device}"))?;
+
+    state.device_set_mist_level(&device, level).await
+}
+
 #[derive(Deserialize)]
 pub struct IdAndModeName {
     id: String,
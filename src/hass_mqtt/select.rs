@@ -1,8 +1,8 @@
-use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::hass_mqtt::work_mode::ParsedWorkMode;
-use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, HassClient, IdParameter};
+use crate::service::device::{Device as ServiceDevice, MistLevels};
+use crate::service::hass::{base_topic, device_topic_id, topic_safe_id, HassClient, IdParameter};
 use crate::service::state::StateHandle;
 use anyhow::Context;
 use axum::async_trait;
@@ -34,15 +34,22 @@ pub struct WorkModeSelect {
 
 impl WorkModeSelect {
     pub fn new(device: &ServiceDevice, work_modes: &ParsedWorkMode, state: &StateHandle) -> Self {
-        let command_topic = format!("gv2mqtt/{id}/set-work-mode", id = topic_safe_id(device),);
-        let state_topic = format!("gv2mqtt/{id}/notify-work-mode", id = topic_safe_id(device));
-        let availability_topic = availability_topic();
+        let command_topic = format!(
+            "{}/{id}/set-work-mode",
+            base_topic(),
+            id = device_topic_id(device),
+        );
+        let state_topic = format!(
+            "{}/{id}/notify-work-mode",
+            base_topic(),
+            id = device_topic_id(device)
+        );
         let unique_id = format!("gv2mqtt-{id}-workMode", id = topic_safe_id(device),);
 
         Self {
             select: SelectConfig {
                 base: EntityConfig {
-                    availability_topic,
+                    availability: Availability::for_device(device),
                     name: Some("Mode".to_string()),
                     device_class: None,
                     origin: Origin::default(),
@@ -68,11 +75,9 @@ impl EntityInstance for WorkModeSelect {
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
-        let device = self
-            .state
-            .device_by_id(&self.device_id)
-            .await
-            .expect("device to exist");
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
 
         if let Some(mode_value) = device.humidifier_work_mode {
             if let Ok(work_mode) = ParsedWorkMode::with_device(&device) {
@@ -100,6 +105,76 @@ impl EntityInstance for WorkModeSelect {
     }
 }
 
+/// The mist intensity of an aroma diffuser with named levels
+pub struct MistLevelSelect {
+    select: SelectConfig,
+    device_id: String,
+    state: StateHandle,
+    levels: MistLevels,
+}
+
+impl MistLevelSelect {
+    pub fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        names: &'static [&'static str],
+    ) -> Self {
+        let command_topic = format!(
+            "{}/{id}/set-mist-level",
+            base_topic(),
+            id = device_topic_id(device)
+        );
+        let state_topic = format!(
+            "{}/{id}/notify-mist-level",
+            base_topic(),
+            id = device_topic_id(device)
+        );
+        let unique_id = format!("gv2mqtt-{id}-mist-level", id = topic_safe_id(device));
+
+        Self {
+            select: SelectConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Mist Intensity".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: Some("mdi:weather-fog".to_string()),
+                },
+                command_topic,
+                state_topic,
+                options: names.iter().map(|name| name.to_string()).collect(),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            levels: MistLevels::Named(names),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for MistLevelSelect {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.select.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
+
+        if let Some(name) = device
+            .mist_level()
+            .and_then(|level| self.levels.name_for_level(level))
+        {
+            client.publish(&self.select.state_topic, name).await?;
+        }
+        Ok(())
+    }
+}
+
 pub struct SceneModeSelect {
     select: SelectConfig,
     device_id: String,
@@ -113,15 +188,22 @@ impl SceneModeSelect {
             return Ok(None);
         }
 
-        let command_topic = format!("gv2mqtt/{id}/set-mode-scene", id = topic_safe_id(device));
-        let state_topic = format!("gv2mqtt/{id}/notify-mode-scene", id = topic_safe_id(device));
-        let availability_topic = availability_topic();
+        let command_topic = format!(
+            "{}/{id}/set-mode-scene",
+            base_topic(),
+            id = device_topic_id(device)
+        );
+        let state_topic = format!(
+            "{}/{id}/notify-mode-scene",
+            base_topic(),
+            id = device_topic_id(device)
+        );
         let unique_id = format!("gv2mqtt-{id}-mode-scene", id = topic_safe_id(device));
 
         Ok(Some(Self {
             select: SelectConfig {
                 base: EntityConfig {
-                    availability_topic,
+                    availability: Availability::for_device(device),
                     name: Some("Mode/Scene".to_string()),
                     device_class: None,
                     origin: Origin::default(),
@@ -147,19 +229,16 @@ impl EntityInstance for SceneModeSelect {
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
-        let device = self
-            .state
-            .device_by_id(&self.device_id)
-            .await
-            .expect("device to exist");
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
 
         if let Some(device_state) = device.device_state() {
-            client
-                .publish(
-                    &self.select.state_topic,
-                    device_state.scene.as_deref().unwrap_or(""),
-                )
-                .await?;
+            let scene = match &device_state.scene {
+                Some(scene) => self.state.categorize_scene_name(&device, scene).await,
+                None => String::new(),
+            };
+            client.publish(&self.select.state_topic, scene).await?;
         }
 
         Ok(())
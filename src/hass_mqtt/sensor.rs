@@ -1,11 +1,10 @@
 use crate::commands::serve::POLL_INTERVAL;
-use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
 use crate::hass_mqtt::humidifier::DEVICE_CLASS_HUMIDITY;
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::platform_api::DeviceCapability;
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, topic_safe_string, HassClient};
-use crate::service::quirks::HumidityUnits;
+use crate::service::hass::{base_topic, topic_safe_id, topic_safe_string, HassClient};
 use crate::service::state::StateHandle;
 use crate::temperature::{TemperatureUnits, TemperatureValue, DEVICE_CLASS_TEMPERATURE};
 use async_trait::async_trait;
@@ -73,7 +72,7 @@ impl GlobalFixedDiagnostic {
         Self {
             sensor: SensorConfig {
                 base: EntityConfig {
-                    availability_topic: availability_topic(),
+                    availability: Availability::service(),
                     name: Some(name),
                     entity_category: Some("diagnostic".to_string()),
                     origin: Origin::default(),
@@ -82,7 +81,7 @@ impl GlobalFixedDiagnostic {
                     device_class: None,
                     icon: None,
                 },
-                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_topic: format!("{}/sensor/{unique_id}/state", base_topic()),
                 state_class: None,
                 unit_of_measurement: None,
                 json_attributes_topic: None,
@@ -92,6 +91,174 @@ impl GlobalFixedDiagnostic {
     }
 }
 
+/// Reports the number of Platform API requests that remain
+/// available in the daily quota
+#[derive(Clone)]
+pub struct PlatformQuotaSensor {
+    sensor: SensorConfig,
+    state: StateHandle,
+}
+
+impl PlatformQuotaSensor {
+    pub fn new(state: &StateHandle) -> Self {
+        let unique_id = "global-platform-api-quota-remaining".to_string();
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability: Availability::service(),
+                    name: Some("Platform API Quota Remaining".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::this_service(),
+                    unique_id: unique_id.clone(),
+                    device_class: None,
+                    icon: Some("mdi:api".to_string()),
+                },
+                state_topic: format!("{}/sensor/{unique_id}/state", base_topic()),
+                state_class: Some(StateClass::Measurement),
+                unit_of_measurement: Some("requests"),
+                json_attributes_topic: None,
+            },
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for PlatformQuotaSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        match self.state.get_platform_client().await {
+            Some(platform) => {
+                let remaining = platform.quota_remaining().to_string();
+                self.sensor.notify_state(client, &remaining).await
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// Reports the health of the connection to the IoT service
+#[derive(Clone)]
+pub struct IotHealthSensor {
+    sensor: SensorConfig,
+    state: StateHandle,
+}
+
+impl IotHealthSensor {
+    pub fn new(state: &StateHandle) -> Self {
+        let unique_id = "global-iot-connection".to_string();
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability: Availability::service(),
+                    name: Some("IoT Connection".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::this_service(),
+                    unique_id: unique_id.clone(),
+                    device_class: None,
+                    icon: Some("mdi:cloud-sync".to_string()),
+                },
+                state_topic: format!("{}/sensor/{unique_id}/state", base_topic()),
+                state_class: None,
+                unit_of_measurement: None,
+                json_attributes_topic: Some(format!(
+                    "{}/sensor/{unique_id}/attributes",
+                    base_topic()
+                )),
+            },
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for IotHealthSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(health) = self.state.get_iot_health().await else {
+            return Ok(());
+        };
+        if let Some(topic) = &self.sensor.json_attributes_topic {
+            client.publish_obj(topic, &health).await?;
+        }
+        self.sensor
+            .notify_state(client, health.status.as_str())
+            .await
+    }
+}
+
+/// Reports where the model specific parameters, which are needed
+/// to encode scenes for BLE and LAN control, were loaded from
+pub struct ModelParamsSensor {
+    sensor: SensorConfig,
+}
+
+impl ModelParamsSensor {
+    pub fn new() -> Self {
+        let unique_id = "global-model-params".to_string();
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability: Availability::service(),
+                    name: Some("Model Parameters".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::this_service(),
+                    unique_id: unique_id.clone(),
+                    device_class: None,
+                    icon: Some("mdi:file-download".to_string()),
+                },
+                state_topic: format!("{}/sensor/{unique_id}/state", base_topic()),
+                state_class: None,
+                unit_of_measurement: None,
+                json_attributes_topic: Some(format!(
+                    "{}/sensor/{unique_id}/attributes",
+                    base_topic()
+                )),
+            },
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for ModelParamsSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let params = crate::ble::get_model_specific_parameters();
+        if let Some(topic) = &self.sensor.json_attributes_topic {
+            client
+                .publish_obj(
+                    topic,
+                    json!({
+                        "source": params.source,
+                        "models": params.params.len(),
+                        "updated": params.updated,
+                        "last_error": params.last_error,
+                    }),
+                )
+                .await?;
+        }
+        let source = serde_json::to_value(params.source)?;
+        self.sensor
+            .notify_state(client, source.as_str().unwrap_or("unknown"))
+            .await
+    }
+}
+
 #[derive(Clone)]
 pub struct CapabilitySensor {
     sensor: SensorConfig,
@@ -140,7 +307,7 @@ impl CapabilitySensor {
         Ok(Self {
             sensor: SensorConfig {
                 base: EntityConfig {
-                    availability_topic: availability_topic(),
+                    availability: Availability::for_device(device),
                     name: Some(name),
                     entity_category: Some("diagnostic".to_string()),
                     origin: Origin::default(),
@@ -149,7 +316,7 @@ impl CapabilitySensor {
                     device_class,
                     icon: None,
                 },
-                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_topic: format!("{}/sensor/{unique_id}/state", base_topic()),
                 state_class: state_class,
                 unit_of_measurement,
                 json_attributes_topic: None,
@@ -168,11 +335,9 @@ impl EntityInstance for CapabilitySensor {
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
-        let device = self
-            .state
-            .device_by_id(&self.device_id)
-            .await
-            .expect("device to exist");
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
 
         let quirk = device.resolve_quirk();
 
@@ -198,20 +363,10 @@ impl EntityInstance for CapabilitySensor {
                         None => "".to_string(),
                     }
                 }
-                "sensorHumidity" => {
-                    let units = quirk
-                        .and_then(|q| q.platform_humidity_sensor_units)
-                        .unwrap_or(HumidityUnits::RelativePercent);
-                    match cap
-                        .state
-                        .pointer("/value")
-                        .and_then(|v| v.as_f64())
-                        .map(|v| units.from_reading_to_relative_percent(v))
-                    {
-                        Some(v) => format!("{v:.2}"),
-                        None => "".to_string(),
-                    }
-                }
+                "sensorHumidity" => match device.current_humidity_percent() {
+                    Some(v) => format!("{v:.2}"),
+                    None => "".to_string(),
+                },
                 _ => cap.state.to_string(),
             };
 
@@ -225,6 +380,135 @@ impl EntityInstance for CapabilitySensor {
     }
 }
 
+/// The minutes remaining until the off timer turns the device off
+pub struct OffTimerSensor {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl OffTimerSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("sensor-{id}-gv2mqtt-off-timer", id = topic_safe_id(device));
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Off Timer Remaining".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("duration"),
+                    icon: Some("mdi:timer-sand".to_string()),
+                },
+                state_topic: format!("{}/sensor/{unique_id}/state", base_topic()),
+                state_class: None,
+                json_attributes_topic: None,
+                unit_of_measurement: Some("min"),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for OffTimerSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let minutes = device
+            .off_timer
+            .map(|timer| timer.remaining_minutes())
+            .unwrap_or(0);
+        self.sensor.notify_state(client, &minutes.to_string()).await
+    }
+}
+
+/// The number of enabled schedules that were configured via the
+/// Govee Home app, with the details of each in the attributes
+pub struct ScheduleSensor {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl ScheduleSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("sensor-{id}-gv2mqtt-schedules", id = topic_safe_id(device));
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability: Availability::service(),
+                    name: Some("Schedules".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: None,
+                    icon: Some("mdi:calendar-clock".to_string()),
+                },
+                state_topic: format!("{}/sensor/{unique_id}/state", base_topic()),
+                state_class: None,
+                json_attributes_topic: Some(format!(
+                    "{}/sensor/{unique_id}/attributes",
+                    base_topic()
+                )),
+                unit_of_measurement: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for ScheduleSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
+        let Some(schedules) = &device.schedules else {
+            return Ok(());
+        };
+
+        if let Some(topic) = &self.sensor.json_attributes_topic {
+            let schedules: Vec<_> = schedules
+                .iter()
+                .map(|schedule| {
+                    json!({
+                        "id": schedule.timer_id,
+                        "name": schedule.name,
+                        "enabled": schedule.enabled,
+                        "time": schedule.time(),
+                        "days": schedule.days(),
+                        "action": if schedule.turn_on { "on" } else { "off" },
+                    })
+                })
+                .collect();
+            client
+                .publish_obj(topic, json!({"schedules": schedules}))
+                .await?;
+        }
+
+        let enabled = schedules.iter().filter(|s| s.enabled).count();
+        self.sensor.notify_state(client, &enabled.to_string()).await
+    }
+}
+
 pub struct DeviceStatusDiagnostic {
     sensor: SensorConfig,
     device_id: String,
@@ -238,7 +522,7 @@ impl DeviceStatusDiagnostic {
         Self {
             sensor: SensorConfig {
                 base: EntityConfig {
-                    availability_topic: availability_topic(),
+                    availability: Availability::service(),
                     name: Some("Status".to_string()),
                     entity_category: Some("diagnostic".to_string()),
                     origin: Origin::default(),
@@ -247,9 +531,12 @@ impl DeviceStatusDiagnostic {
                     device_class: None,
                     icon: None,
                 },
-                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_topic: format!("{}/sensor/{unique_id}/state", base_topic()),
                 state_class: None,
-                json_attributes_topic: Some(format!("gv2mqtt/sensor/{unique_id}/attributes")),
+                json_attributes_topic: Some(format!(
+                    "{}/sensor/{unique_id}/attributes",
+                    base_topic()
+                )),
                 unit_of_measurement: None,
             },
             device_id: device.id.to_string(),
@@ -265,11 +552,9 @@ impl EntityInstance for DeviceStatusDiagnostic {
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
-        let device = self
-            .state
-            .device_by_id(&self.device_id)
-            .await
-            .expect("device to exist");
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
 
         let iot_state = device.compute_iot_device_state();
         let lan_state = device.compute_lan_device_state();
@@ -309,3 +594,469 @@ impl EntityInstance for DeviceStatusDiagnostic {
         Ok(())
     }
 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DeviceDiagnosticKind {
+    Firmware,
+    WifiSignal,
+    IpAddress,
+    LastSeenLan,
+    LastSeenIot,
+    LastSeenPlatform,
+    PlatformRequests,
+}
+
+impl DeviceDiagnosticKind {
+    /// Returns the kinds of diagnostic that are applicable to device
+    pub fn for_device(device: &ServiceDevice) -> Vec<Self> {
+        let transports = device.transports();
+        let mut kinds = vec![Self::Firmware];
+        if device.undoc_device_info.is_some() {
+            kinds.push(Self::WifiSignal);
+        }
+        if transports.contains(&"lan") {
+            kinds.push(Self::IpAddress);
+            kinds.push(Self::LastSeenLan);
+        }
+        if transports.contains(&"iot") {
+            kinds.push(Self::LastSeenIot);
+        }
+        if transports.contains(&"platform") {
+            kinds.push(Self::LastSeenPlatform);
+            kinds.push(Self::PlatformRequests);
+        }
+        kinds
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Firmware => "Firmware Version",
+            Self::WifiSignal => "WiFi Signal",
+            Self::IpAddress => "IP Address",
+            Self::LastSeenLan => "Last Seen via LAN",
+            Self::LastSeenIot => "Last Seen via IoT",
+            Self::LastSeenPlatform => "Last Seen via Platform API",
+            Self::PlatformRequests => "Platform API Requests Today",
+        }
+    }
+
+    fn id(&self) -> &'static str {
+        match self {
+            Self::Firmware => "firmware",
+            Self::WifiSignal => "wifi-signal",
+            Self::IpAddress => "ip-address",
+            Self::LastSeenLan => "last-seen-lan",
+            Self::LastSeenIot => "last-seen-iot",
+            Self::LastSeenPlatform => "last-seen-platform",
+            Self::PlatformRequests => "platform-requests",
+        }
+    }
+}
+
+/// Reports an aspect of the connectivity of a device, so that
+/// it can be seen on the device page in hass
+pub struct DeviceDiagnosticSensor {
+    sensor: SensorConfig,
+    kind: DeviceDiagnosticKind,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl DeviceDiagnosticSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, kind: DeviceDiagnosticKind) -> Self {
+        let unique_id = format!(
+            "sensor-{id}-gv2mqtt-{kind}",
+            id = topic_safe_id(device),
+            kind = kind.id()
+        );
+
+        let (device_class, state_class, unit_of_measurement, icon) = match kind {
+            DeviceDiagnosticKind::Firmware => (None, None, None, Some("mdi:chip")),
+            DeviceDiagnosticKind::WifiSignal => (
+                Some("signal_strength"),
+                Some(StateClass::Measurement),
+                Some("dBm"),
+                None,
+            ),
+            DeviceDiagnosticKind::IpAddress => (None, None, None, Some("mdi:ip-network")),
+            DeviceDiagnosticKind::LastSeenLan
+            | DeviceDiagnosticKind::LastSeenIot
+            | DeviceDiagnosticKind::LastSeenPlatform => (Some("timestamp"), None, None, None),
+            DeviceDiagnosticKind::PlatformRequests => (
+                None,
+                Some(StateClass::Measurement),
+                Some("requests"),
+                Some("mdi:api"),
+            ),
+        };
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability: Availability::service(),
+                    name: Some(kind.name().to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class,
+                    icon: icon.map(|icon| icon.to_string()),
+                },
+                state_topic: format!("{}/sensor/{unique_id}/state", base_topic()),
+                state_class,
+                unit_of_measurement,
+                json_attributes_topic: None,
+            },
+            kind,
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for DeviceDiagnosticSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let timestamp = |t: Option<chrono::DateTime<Utc>>| t.map(|t| t.to_rfc3339());
+
+        let value = match self.kind {
+            DeviceDiagnosticKind::Firmware => device.firmware_version().map(|v| v.to_string()),
+            DeviceDiagnosticKind::WifiSignal => {
+                device.undoc_device_info.as_ref().and_then(|info| {
+                    info.entry
+                        .device_ext
+                        .device_settings
+                        .wifi_level
+                        .map(|level| level.to_string())
+                })
+            }
+            DeviceDiagnosticKind::IpAddress => device.ip_addr().map(|ip| ip.to_string()),
+            DeviceDiagnosticKind::LastSeenLan => timestamp(device.last_lan_device_status_update),
+            DeviceDiagnosticKind::LastSeenIot => timestamp(device.last_iot_device_status_update),
+            DeviceDiagnosticKind::LastSeenPlatform => {
+                timestamp(device.last_http_device_state_update)
+            }
+            DeviceDiagnosticKind::PlatformRequests => self
+                .state
+                .get_platform_client()
+                .await
+                .map(|client| client.device_requests_today(&device.id).to_string()),
+        };
+
+        match value {
+            Some(value) => self.sensor.notify_state(client, &value).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BleSensorKind {
+    Temperature,
+    Humidity,
+    Battery,
+}
+
+impl BleSensorKind {
+    pub const ALL: [Self; 3] = [Self::Temperature, Self::Humidity, Self::Battery];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Temperature => "Temperature",
+            Self::Humidity => "Humidity",
+            Self::Battery => "Battery",
+        }
+    }
+
+    fn id(&self) -> &'static str {
+        match self {
+            Self::Temperature => "temperature",
+            Self::Humidity => "humidity",
+            Self::Battery => "battery",
+        }
+    }
+}
+
+/// Reports a reading that was passively received from the
+/// BLE advertisements of a thermo-hygrometer
+pub struct BleSensor {
+    sensor: SensorConfig,
+    kind: BleSensorKind,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl BleSensor {
+    pub async fn new(device: &ServiceDevice, state: &StateHandle, kind: BleSensorKind) -> Self {
+        let unique_id = format!(
+            "sensor-{id}-ble-{kind}",
+            id = topic_safe_id(device),
+            kind = kind.id()
+        );
+
+        let (device_class, unit_of_measurement, entity_category) = match kind {
+            BleSensorKind::Temperature => (
+                DEVICE_CLASS_TEMPERATURE,
+                state.get_temperature_scale().await.unit_of_measurement(),
+                None,
+            ),
+            BleSensorKind::Humidity => (DEVICE_CLASS_HUMIDITY, "%", None),
+            BleSensorKind::Battery => ("battery", "%", Some("diagnostic".to_string())),
+        };
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some(kind.name().to_string()),
+                    entity_category,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some(device_class),
+                    icon: None,
+                },
+                state_topic: format!("{}/sensor/{unique_id}/state", base_topic()),
+                state_class: Some(StateClass::Measurement),
+                unit_of_measurement: Some(unit_of_measurement),
+                json_attributes_topic: None,
+            },
+            kind,
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for BleSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let Some(reading) = device.ble_sensor_reading else {
+            return Ok(());
+        };
+
+        let value = match self.kind {
+            BleSensorKind::Temperature => {
+                let value =
+                    TemperatureValue::new(reading.temperature_celsius, TemperatureUnits::Celsius)
+                        .as_unit(self.state.get_temperature_scale().await.into())
+                        .value();
+                format!("{value:.1}")
+            }
+            BleSensorKind::Humidity => format!("{:.1}", reading.humidity_percent),
+            BleSensorKind::Battery => reading.battery_percent.to_string(),
+        };
+        self.sensor.notify_state(client, &value).await
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PowerEstimateKind {
+    Power,
+    Energy,
+}
+
+impl PowerEstimateKind {
+    pub const ALL: [Self; 2] = [Self::Power, Self::Energy];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Power => "Estimated Power",
+            Self::Energy => "Estimated Energy",
+        }
+    }
+
+    fn id(&self) -> &'static str {
+        match self {
+            Self::Power => "power",
+            Self::Energy => "energy",
+        }
+    }
+}
+
+/// Reports the power consumption of a light, as estimated from
+/// its state and the power model configured for it
+pub struct PowerEstimateSensor {
+    sensor: SensorConfig,
+    kind: PowerEstimateKind,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl PowerEstimateSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, kind: PowerEstimateKind) -> Self {
+        let unique_id = format!(
+            "sensor-{id}-gv2mqtt-estimated-{kind}",
+            id = topic_safe_id(device),
+            kind = kind.id()
+        );
+
+        let (state_class, unit_of_measurement) = match kind {
+            PowerEstimateKind::Power => (StateClass::Measurement, "W"),
+            PowerEstimateKind::Energy => (StateClass::TotalIncreasing, "kWh"),
+        };
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some(kind.name().to_string()),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some(kind.id()),
+                    icon: None,
+                },
+                state_topic: format!("{}/sensor/{unique_id}/state", base_topic()),
+                state_class: Some(state_class),
+                unit_of_measurement: Some(unit_of_measurement),
+                json_attributes_topic: None,
+            },
+            kind,
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for PowerEstimateSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let Some(meter) = self.state.update_power_estimate(&device).await else {
+            return Ok(());
+        };
+
+        let value = match self.kind {
+            PowerEstimateKind::Power => format!("{:.1}", meter.watts),
+            PowerEstimateKind::Energy => format!("{:.4}", meter.kwh),
+        };
+        self.sensor.notify_state(client, &value).await
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PlugEnergyKind {
+    Power,
+    Energy,
+    Voltage,
+    Current,
+}
+
+impl PlugEnergyKind {
+    pub const ALL: [Self; 4] = [Self::Power, Self::Energy, Self::Voltage, Self::Current];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Power => "Power",
+            Self::Energy => "Energy",
+            Self::Voltage => "Voltage",
+            Self::Current => "Current",
+        }
+    }
+
+    fn id(&self) -> &'static str {
+        match self {
+            Self::Power => "power",
+            Self::Energy => "energy",
+            Self::Voltage => "voltage",
+            Self::Current => "current",
+        }
+    }
+}
+
+/// Reports the load that a smart plug has measured
+pub struct PlugEnergySensor {
+    sensor: SensorConfig,
+    kind: PlugEnergyKind,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl PlugEnergySensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, kind: PlugEnergyKind) -> Self {
+        let unique_id = format!(
+            "sensor-{id}-gv2mqtt-plug-{kind}",
+            id = topic_safe_id(device),
+            kind = kind.id()
+        );
+
+        let (state_class, unit_of_measurement) = match kind {
+            PlugEnergyKind::Power => (StateClass::Measurement, "W"),
+            PlugEnergyKind::Energy => (StateClass::TotalIncreasing, "kWh"),
+            PlugEnergyKind::Voltage => (StateClass::Measurement, "V"),
+            PlugEnergyKind::Current => (StateClass::Measurement, "A"),
+        };
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some(kind.name().to_string()),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some(kind.id()),
+                    icon: None,
+                },
+                state_topic: format!("{}/sensor/{unique_id}/state", base_topic()),
+                state_class: Some(state_class),
+                unit_of_measurement: Some(unit_of_measurement),
+                json_attributes_topic: None,
+            },
+            kind,
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for PlugEnergySensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let Some(energy) = device.plug_energy else {
+            return Ok(());
+        };
+
+        let value = match self.kind {
+            PlugEnergyKind::Power => format!("{:.2}", energy.watts()),
+            PlugEnergyKind::Energy => format!("{:.3}", energy.kwh()),
+            PlugEnergyKind::Voltage => format!("{:.2}", energy.volts()),
+            PlugEnergyKind::Current => format!("{:.2}", energy.amps()),
+        };
+        self.sensor.notify_state(client, &value).await
+    }
+}
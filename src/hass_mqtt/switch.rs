@@ -1,11 +1,12 @@
-use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
-use crate::platform_api::DeviceCapability;
-use crate::service::device::Device as ServiceDevice;
+use crate::platform_api::{DeviceCapability, DeviceCapabilityKind};
+use crate::service::device::{outlet_number, Device as ServiceDevice, DeviceToggle};
 use crate::service::hass::{
-    availability_topic, camel_case_to_space_separated, switch_instance_state_topic, topic_safe_id,
-    HassClient,
+    base_topic, camel_case_to_space_separated, device_topic_id, read_only_command_topic,
+    read_only_state_topic, switch_instance_state_topic, topic_safe_id, HassClient,
 };
+use crate::service::read_only::READ_ONLY_INSTANCE;
 use crate::service::state::StateHandle;
 use async_trait::async_trait;
 use serde::Serialize;
@@ -25,23 +26,33 @@ impl SwitchConfig {
         instance: &DeviceCapability,
     ) -> anyhow::Result<Self> {
         let command_topic = format!(
-            "gv2mqtt/switch/{id}/command/{inst}",
-            id = topic_safe_id(device),
+            "{}/switch/{id}/command/{inst}",
+            base_topic(),
+            id = device_topic_id(device),
             inst = instance.instance
         );
         let state_topic = switch_instance_state_topic(device, &instance.instance);
-        let availability_topic = availability_topic();
         let unique_id = format!(
             "gv2mqtt-{id}-{inst}",
             id = topic_safe_id(device),
             inst = instance.instance
         );
 
+        // Give each outlet of a power strip its own switch, leaving
+        // powerSwitch to control all of them at once
+        let (name, device_class) = match outlet_number(&instance.instance) {
+            Some(n) => (format!("Outlet {n}"), Some("outlet")),
+            None if instance.instance == "powerSwitch" && !device.outlets().is_empty() => {
+                ("All Outlets".to_string(), Some("outlet"))
+            }
+            None => (camel_case_to_space_separated(&instance.instance), None),
+        };
+
         Ok(Self {
             base: EntityConfig {
-                availability_topic,
-                name: Some(camel_case_to_space_separated(&instance.instance)),
-                device_class: None,
+                availability: Availability::for_device(device),
+                name: Some(name),
+                device_class,
                 origin: Origin::default(),
                 device: Device::for_device(device),
                 unique_id,
@@ -79,6 +90,20 @@ impl CapabilitySwitch {
             instance_name: instance.instance.to_string(),
         })
     }
+
+    /// The power switch of a device whose capabilities we didn't
+    /// obtain from the Platform API, such as a smart plug that is
+    /// controlled via IoT
+    pub async fn power_switch(device: &ServiceDevice, state: &StateHandle) -> anyhow::Result<Self> {
+        let instance = DeviceCapability {
+            kind: DeviceCapabilityKind::OnOff,
+            instance: "powerSwitch".to_string(),
+            parameters: None,
+            alarm_type: None,
+            event_state: None,
+        };
+        Self::new(device, state, &instance).await
+    }
 }
 
 #[async_trait]
@@ -88,11 +113,9 @@ impl EntityInstance for CapabilitySwitch {
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
-        let device = self
-            .state
-            .device_by_id(&self.device_id)
-            .await
-            .expect("device to exist");
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
 
         if self.instance_name == "powerSwitch" {
             if let Some(state) = device.device_state() {
@@ -142,3 +165,222 @@ impl EntityInstance for CapabilitySwitch {
         Ok(())
     }
 }
+
+/// Switches a feature of a heater, fan or light, such as oscillation
+pub struct ToggleSwitch {
+    switch: SwitchConfig,
+    toggle: DeviceToggle,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl ToggleSwitch {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, toggle: DeviceToggle) -> Self {
+        let id = topic_safe_id(device);
+        let topic_id = device_topic_id(device);
+        let instance = toggle.instance();
+        let (name, icon) = match toggle {
+            DeviceToggle::Oscillation => ("Oscillation", "mdi:arrow-oscillating"),
+            DeviceToggle::ChildLock => ("Child Lock", "mdi:lock"),
+            DeviceToggle::Gradient => ("Gradient", "mdi:gradient-horizontal"),
+        };
+        Self {
+            switch: SwitchConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some(name.to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: format!("gv2mqtt-{id}-{instance}"),
+                    entity_category: None,
+                    icon: Some(icon.to_string()),
+                },
+                command_topic: format!("{}/switch/{topic_id}/command/{instance}", base_topic()),
+                state_topic: switch_instance_state_topic(device, instance),
+            },
+            toggle,
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for ToggleSwitch {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.switch.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
+
+        // Prefer the state reported via IoT, as the Platform API
+        // state is only as fresh as the last poll
+        let on = device.toggle_state(self.toggle).or_else(|| {
+            device
+                .get_state_capability_by_instance(self.toggle.instance())
+                .and_then(|cap| cap.state.pointer("/value"))
+                .and_then(|v| v.as_i64())
+                .map(|n| n != 0)
+        });
+
+        match on {
+            Some(on) => {
+                client
+                    .publish(&self.switch.state_topic, if on { "ON" } else { "OFF" })
+                    .await
+            }
+            None => Ok(()),
+        }
+    }
+}
+
+/// The switch instance name used to start and stop making ice
+pub const ICE_MAKING_INSTANCE: &str = "iceMaking";
+
+/// Starts and stops an ice maker
+pub struct IceMakerSwitch {
+    switch: SwitchConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl IceMakerSwitch {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let id = topic_safe_id(device);
+        let topic_id = device_topic_id(device);
+        Self {
+            switch: SwitchConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Make Ice".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: format!("gv2mqtt-{id}-{ICE_MAKING_INSTANCE}"),
+                    entity_category: None,
+                    icon: Some("mdi:snowflake".to_string()),
+                },
+                command_topic: format!(
+                    "{}/switch/{topic_id}/command/{ICE_MAKING_INSTANCE}",
+                    base_topic()
+                ),
+                state_topic: switch_instance_state_topic(device, ICE_MAKING_INSTANCE),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for IceMakerSwitch {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.switch.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let making = match device.ice_maker_status {
+            Some(status) => status.making,
+            None => match device.device_state() {
+                Some(state) => state.on,
+                None => return Ok(()),
+            },
+        };
+
+        client
+            .publish(&self.switch.state_topic, if making { "ON" } else { "OFF" })
+            .await
+    }
+}
+
+/// Controls whether requests to control a device, or all devices,
+/// are rejected
+pub struct ReadOnlySwitch {
+    switch: SwitchConfig,
+    /// None for the switch that applies to all devices
+    device_id: Option<String>,
+    state: StateHandle,
+}
+
+impl ReadOnlySwitch {
+    pub fn global(state: &StateHandle) -> Self {
+        Self {
+            switch: SwitchConfig {
+                base: EntityConfig {
+                    availability: Availability::service(),
+                    name: Some("Read Only".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::this_service(),
+                    unique_id: "global-read-only".to_string(),
+                    entity_category: Some("config".to_string()),
+                    icon: Some("mdi:lock-outline".to_string()),
+                },
+                command_topic: read_only_command_topic(),
+                state_topic: read_only_state_topic(),
+            },
+            device_id: None,
+            state: state.clone(),
+        }
+    }
+
+    pub fn for_device(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let id = topic_safe_id(device);
+        let topic_id = device_topic_id(device);
+        Self {
+            switch: SwitchConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: Some("Read Only".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: format!("gv2mqtt-{id}-{READ_ONLY_INSTANCE}"),
+                    entity_category: Some("config".to_string()),
+                    icon: Some("mdi:lock-outline".to_string()),
+                },
+                command_topic: format!(
+                    "{}/switch/{topic_id}/command/{READ_ONLY_INSTANCE}",
+                    base_topic()
+                ),
+                state_topic: switch_instance_state_topic(device, READ_ONLY_INSTANCE),
+            },
+            device_id: Some(device.id.to_string()),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for ReadOnlySwitch {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.switch.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let read_only = match &self.device_id {
+            Some(device_id) => {
+                let Some(device) = self.state.device_for_entity(device_id).await else {
+                    return Ok(());
+                };
+                self.state.device_read_only_setting(&device).await
+            }
+            None => self.state.is_read_only().await,
+        };
+
+        client
+            .publish(
+                &self.switch.state_topic,
+                if read_only { "ON" } else { "OFF" },
+            )
+            .await
+    }
+}
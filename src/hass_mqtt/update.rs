@@ -0,0 +1,97 @@
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{base_topic, install_firmware_topic, topic_safe_id, HassClient};
+use crate::service::state::StateHandle;
+use async_trait::async_trait;
+use serde::Serialize;
+use serde_json::json;
+
+/// <https://www.home-assistant.io/integrations/update.mqtt/>
+#[derive(Serialize, Clone, Debug)]
+pub struct UpdateConfig {
+    #[serde(flatten)]
+    pub base: EntityConfig,
+
+    /// We will publish a JSON object with the installed
+    /// and latest versions here
+    pub state_topic: String,
+    /// Publishing payload_install here starts the update
+    pub command_topic: String,
+    pub payload_install: &'static str,
+}
+
+impl UpdateConfig {
+    pub async fn publish(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("update", state, client, &self.base, self).await
+    }
+}
+
+/// Reports whether Govee has newer firmware for a device,
+/// and the progress of installing it
+pub struct FirmwareUpdate {
+    update: UpdateConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl FirmwareUpdate {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!("update-{id}-firmware", id = topic_safe_id(device));
+
+        Self {
+            update: UpdateConfig {
+                base: EntityConfig {
+                    availability: Availability::service(),
+                    name: Some("Firmware".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("firmware"),
+                    icon: None,
+                },
+                state_topic: format!("{}/update/{unique_id}/state", base_topic()),
+                command_topic: install_firmware_topic(device),
+                payload_install: "install",
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for FirmwareUpdate {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.update.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let Some(installed) = device.firmware_version() else {
+            return Ok(());
+        };
+        let update = device.firmware_update.as_ref();
+        let latest = update
+            .filter(|update| update.need_update)
+            .and_then(|update| update.version_soft.as_deref())
+            .unwrap_or(installed);
+
+        client
+            .publish_obj(
+                &self.update.state_topic,
+                json!({
+                    "installed_version": installed,
+                    "latest_version": latest,
+                    "release_summary": update.and_then(|update| update.update_desc.as_deref()),
+                    "in_progress": device.firmware_update_progress.is_some(),
+                    "update_percentage": device.firmware_update_progress,
+                }),
+            )
+            .await
+    }
+}
@@ -0,0 +1,289 @@
+use crate::hass_mqtt::base::{Availability, Device, EntityConfig, Origin};
+use crate::hass_mqtt::climate::parse_temperature_constraints;
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::hass_mqtt::work_mode::ParsedWorkMode;
+use crate::platform_api::{DeviceCapability, DeviceCapabilityKind};
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{
+    base_topic, device_topic_id, topic_safe_id, topic_safe_string, HassClient, IdParameter,
+};
+use crate::service::state::StateHandle;
+use crate::temperature::TemperatureUnits;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use mosquitto_rs::router::{Params, Payload, State};
+use serde::Serialize;
+
+/// HASS only permits a fixed set of operation modes for water heaters,
+/// so we map the kettle work modes onto the closest equivalents.
+const MODE_OFF: &str = "off";
+/// Boil the water
+const MODE_BOIL: &str = "performance";
+/// Heat to, and hold, the target temperature
+const MODE_KEEP_WARM: &str = "eco";
+
+/// <https://www.home-assistant.io/integrations/water_heater.mqtt/>
+#[derive(Serialize, Clone, Debug)]
+pub struct WaterHeaterConfig {
+    #[serde(flatten)]
+    pub base: EntityConfig,
+
+    /// HASS will publish ON/OFF here to control the power
+    pub power_command_topic: String,
+
+    pub mode_command_topic: String,
+    pub mode_state_topic: String,
+    pub modes: Vec<&'static str>,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature_command_topic: Option<String>,
+    pub temperature_state_topic: String,
+    pub current_temperature_topic: String,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_temp: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_temp: Option<f64>,
+    /// "C" or "F"
+    pub temperature_unit: &'static str,
+    pub precision: f64,
+
+    pub optimistic: bool,
+}
+
+/// The boil and keep-warm work modes of a kettle
+#[derive(Clone, Debug, Default)]
+pub struct KettleModes {
+    pub boil: Option<(i64, i64)>,
+    pub keep_warm: Option<(i64, i64)>,
+}
+
+impl KettleModes {
+    pub fn with_device(device: &ServiceDevice) -> anyhow::Result<Self> {
+        let work_modes = ParsedWorkMode::with_device(device)?;
+        Ok(Self::with_work_modes(&work_modes))
+    }
+
+    pub fn with_work_modes(work_modes: &ParsedWorkMode) -> Self {
+        let mut modes = Self::default();
+        for mode in work_modes.modes.values() {
+            let Some(work_mode) = mode.value.as_i64() else {
+                continue;
+            };
+            let entry = (work_mode, mode.default_value());
+            if mode.name.eq_ignore_ascii_case("Boiling") {
+                modes.boil.replace(entry);
+            } else if mode.name.eq_ignore_ascii_case("DIY") || modes.keep_warm.is_none() {
+                modes.keep_warm.replace(entry);
+            }
+        }
+        modes
+    }
+
+    fn hass_mode_for(&self, work_mode: i64) -> &'static str {
+        match self.boil {
+            Some((boil, _)) if boil == work_mode => MODE_BOIL,
+            _ => MODE_KEEP_WARM,
+        }
+    }
+
+    fn hass_modes(&self) -> Vec<&'static str> {
+        let mut modes = vec![MODE_OFF];
+        if self.boil.is_some() {
+            modes.push(MODE_BOIL);
+        }
+        if self.keep_warm.is_some() {
+            modes.push(MODE_KEEP_WARM);
+        }
+        modes
+    }
+}
+
+fn temperature_capability(device: &ServiceDevice) -> Option<&DeviceCapability> {
+    device.http_device_info.as_ref().and_then(|info| {
+        info.capabilities
+            .iter()
+            .find(|cap| cap.kind == DeviceCapabilityKind::TemperatureSetting)
+    })
+}
+
+#[derive(Clone)]
+pub struct KettleWaterHeater {
+    heater: WaterHeaterConfig,
+    modes: KettleModes,
+    temperature_instance: Option<String>,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl KettleWaterHeater {
+    pub async fn new(device: &ServiceDevice, state: &StateHandle) -> anyhow::Result<Self> {
+        let id = topic_safe_id(device);
+        let topic_id = device_topic_id(device);
+        let use_iot = device.iot_api_supported() && state.get_iot_client().await.is_some();
+        let modes = KettleModes::with_device(device).unwrap_or_default();
+        let scale = state.get_temperature_scale().await;
+
+        let temp_cap = temperature_capability(device);
+        let constraints = temp_cap
+            .and_then(|cap| parse_temperature_constraints(cap).ok())
+            .map(|c| c.as_unit(scale.into()));
+
+        let temperature_command_topic = temp_cap.map(|cap| {
+            format!(
+                "{}/{topic_id}/set-temperature/{inst}/{units}",
+                base_topic(),
+                inst = topic_safe_string(&cap.instance),
+                units = scale
+            )
+        });
+
+        Ok(Self {
+            heater: WaterHeaterConfig {
+                base: EntityConfig {
+                    availability: Availability::for_device(device),
+                    name: None,
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: format!("gv2mqtt-{id}-water-heater"),
+                    device_class: None,
+                    icon: Some("mdi:kettle".to_string()),
+                },
+                // Route power to the general power switch handler
+                power_command_topic: format!(
+                    "{}/switch/{topic_id}/command/powerSwitch",
+                    base_topic()
+                ),
+                mode_command_topic: format!("{}/water_heater/{topic_id}/set-mode", base_topic()),
+                mode_state_topic: format!("{}/water_heater/{topic_id}/notify-mode", base_topic()),
+                modes: modes.hass_modes(),
+                temperature_command_topic,
+                temperature_state_topic: format!(
+                    "{}/water_heater/{topic_id}/notify-target",
+                    base_topic()
+                ),
+                current_temperature_topic: format!(
+                    "{}/water_heater/{topic_id}/notify-current",
+                    base_topic()
+                ),
+                min_temp: constraints.as_ref().map(|c| c.min.value().floor()),
+                max_temp: constraints.as_ref().map(|c| c.max.value().ceil()),
+                temperature_unit: scale.hass_temperature_unit(),
+                precision: 1.0,
+                optimistic: !use_iot,
+            },
+            modes,
+            temperature_instance: temp_cap.map(|cap| cap.instance.to_string()),
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for KettleWaterHeater {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config(
+            "water_heater",
+            state,
+            client,
+            &self.heater.base,
+            &self.heater,
+        )
+        .await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let Some(device) = self.state.device_for_entity(&self.device_id).await else {
+            return Ok(());
+        };
+
+        let pref_units: TemperatureUnits = self.state.get_temperature_scale().await.into();
+
+        let is_on = device.device_state().map(|s| s.on).unwrap_or(false);
+        let work_mode = match device.humidifier_work_mode {
+            Some(mode) => Some(mode as i64),
+            None => device
+                .get_state_capability_by_instance("workMode")
+                .and_then(|cap| cap.state.pointer("/value/workMode"))
+                .and_then(|v| v.as_i64()),
+        };
+        let mode = match work_mode {
+            Some(work_mode) if is_on => self.modes.hass_mode_for(work_mode),
+            _ => MODE_OFF,
+        };
+        client.publish(&self.heater.mode_state_topic, mode).await?;
+
+        let current = device
+            .kettle_current_temperature
+            .or_else(|| device.current_temperature());
+        if let Some(current) = current {
+            let value = current.as_unit(pref_units).value();
+            client
+                .publish(
+                    &self.heater.current_temperature_topic,
+                    format!("{value:.1}"),
+                )
+                .await?;
+        }
+
+        let target = device.kettle_target_temperature.or_else(|| {
+            device.target_temperature(
+                self.temperature_instance.as_deref()?,
+                TemperatureUnits::Fahrenheit,
+            )
+        });
+        if let Some(target) = target {
+            let value = target.as_unit(pref_units).value();
+            client
+                .publish(&self.heater.temperature_state_topic, format!("{value:.1}"))
+                .await?;
+        }
+
+        Ok(())
+    }
+}
+
+pub async fn mqtt_water_heater_set_mode(
+    Payload(mode): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_water_heater_set_mode: {id}: {mode}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let modes = KettleModes::with_device(&device)?;
+    let (work_mode, value) = match mode.as_str() {
+        MODE_OFF => return state.device_power_on(&device, false).await,
+        MODE_BOIL => modes.boil,
+        MODE_KEEP_WARM => modes.keep_warm,
+        _ => None,
+    }
+    .ok_or_else(|| anyhow!("mode {mode} is not supported by {device}"))?;
+
+    state.kettle_set_parameter(&device, work_mode, value).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::from_json;
+
+    #[test]
+    fn kettle_modes() {
+        let cap: DeviceCapability =
+            from_json(include_str!("../../test-data/work-mode-issue-100.json")).unwrap();
+        let wm = ParsedWorkMode::with_capability(&cap).unwrap();
+        let modes = KettleModes::with_work_modes(&wm);
+
+        assert_eq!(modes.boil, Some((2, 0)));
+        assert_eq!(modes.keep_warm.map(|(mode, _)| mode), Some(1));
+        assert_eq!(modes.hass_mode_for(2), MODE_BOIL);
+        assert_eq!(modes.hass_mode_for(3), MODE_KEEP_WARM);
+        assert_eq!(
+            modes.hass_modes(),
+            vec![MODE_OFF, MODE_BOIL, MODE_KEEP_WARM]
+        );
+    }
+}
@@ -1,31 +1,37 @@
-use crate::ble::{Base64HexBytes, SetSceneCode};
+use crate::ble::{Base64HexBytes, GoveeBlePacket};
 use crate::opt_env_var;
 use crate::platform_api::from_json;
-// Import for centralized scene parsing:
-use crate::govee_scenes::get_parsed_scenes_for_sku;
+use crate::scene_resolver::{resolve_scene, SceneSelector};
+use crate::service::packet_capture::{capture_packet, Direction};
+use crate::service::watchdog::Heartbeat;
 use anyhow::Context;
 use if_addrs::IfAddr;
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
+use std::collections::HashMap;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::UdpSocket;
+use tokio::sync::mpsc::error::TrySendError;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
-use tokio::sync::Mutex;
+use tokio::sync::{Mutex, OnceCell};
+use tokio::task::JoinHandle;
 use tokio::time::Instant;
 
 // <https://app-h5.govee.com/user-manual/wlan-guide>
 
 /// The port on which govee devices listen for scan requests
-const SCAN_PORT: u16 = 4001;
+pub(crate) const SCAN_PORT: u16 = 4001;
 /// The port on which a client needs to listen to receive responses
 /// from govee devices
-const LISTEN_PORT: u16 = 4002;
+pub(crate) const LISTEN_PORT: u16 = 4002;
 /// The port on which govee devices listen for control requests
-const CMD_PORT: u16 = 4003;
+pub(crate) const CMD_PORT: u16 = 4003;
 /// The multicast group of which govee LAN-API enabled devices are members
-const MULTICAST: IpAddr = IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250));
+pub(crate) const MULTICAST: IpAddr = IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250));
 
 #[derive(clap::Parser, Debug)]
 pub struct LanDiscoArguments {
@@ -51,6 +57,23 @@ pub struct LanDiscoArguments {
     #[arg(long, global = true)]
     pub scan: Vec<IpAddr>,
 
+    /// Send multicast discovery via the named network interface, rather
+    /// than only the interface associated with the default route.
+    /// May be an interface name or one of its IPv4 addresses, or `all`
+    /// to use every non-loopback interface. Can be specified multiple
+    /// times. When combined with --broadcast-all, only the broadcast
+    /// addresses of these interfaces will be used.
+    /// You may also set GOVEE_LAN_INTERFACES=eth0,eth1 via the environment.
+    #[arg(long = "interface", global = true)]
+    pub interfaces: Vec<String>,
+
+    /// A JSON file declaring LAN devices statically, for networks
+    /// where discovery doesn't work. When set, multicast discovery is
+    /// disabled.
+    /// You may also set GOVEE_LAN_DEVICES_FILE via the environment.
+    #[arg(long, global = true)]
+    pub lan_devices_file: Option<PathBuf>,
+
     /// How long to wait for discovery to complete, in seconds
     /// You may also set GOVEE_LAN_DISCO_TIMEOUT via the environment.
     #[arg(long, default_value_t = 3, global = true)]
@@ -82,6 +105,8 @@ impl LanDiscoArguments {
             additional_addresses: self.scan.clone(),
             broadcast_all_interfaces: self.broadcast_all,
             global_broadcast: self.global_broadcast,
+            interfaces: self.interfaces.clone(),
+            static_devices: vec![],
         };
 
         if let Some(v) = opt_env_var::<String>("GOVEE_LAN_NO_MULTICAST")? {
@@ -106,6 +131,25 @@ impl LanDiscoArguments {
             }
         }
 
+        let lan_devices_file = match &self.lan_devices_file {
+            Some(path) => Some(path.clone()),
+            None => opt_env_var::<PathBuf>("GOVEE_LAN_DEVICES_FILE")?,
+        };
+        if let Some(path) = lan_devices_file {
+            options.static_devices = load_lan_devices_file(&path)?;
+            // The devices are known, so there is no need to multicast
+            options.enable_multicast = false;
+        }
+
+        if let Some(v) = opt_env_var::<String>("GOVEE_LAN_INTERFACES")? {
+            for iface in v.split(',') {
+                let iface = iface.trim();
+                if !iface.is_empty() {
+                    options.interfaces.push(iface.to_string());
+                }
+            }
+        }
+
         Ok(options)
     }
 
@@ -129,12 +173,18 @@ pub struct DiscoOptions {
     pub broadcast_all_interfaces: bool,
     /// Broadcast to the global broadcast address
     pub global_broadcast: bool,
+    /// Perform discovery via these interfaces, identified by name
+    /// or address, or `all`. When empty, multicast uses the default route.
+    pub interfaces: Vec<String>,
+    /// Devices that are declared statically rather than discovered
+    pub static_devices: Vec<LanDevice>,
 }
 
 impl DiscoOptions {
     pub fn is_empty(&self) -> bool {
         !self.enable_multicast
             && self.additional_addresses.is_empty()
+            && self.static_devices.is_empty()
             && !self.broadcast_all_interfaces
             && !self.global_broadcast
     }
@@ -147,6 +197,8 @@ impl Default for DiscoOptions {
             additional_addresses: vec![],
             broadcast_all_interfaces: false,
             global_broadcast: false,
+            interfaces: vec![],
+            static_devices: vec![],
         }
     }
 }
@@ -173,8 +225,8 @@ pub enum Request {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-struct RequestMessage {
-    msg: Request,
+pub(crate) struct RequestMessage {
+    pub msg: Request,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq)]
@@ -192,12 +244,51 @@ pub struct LanDevice {
     pub wifi_version_soft: String,
 }
 
+/// An entry in the static LAN device registry
+#[derive(Deserialize, Debug, Clone)]
+struct StaticLanDevice {
+    ip: IpAddr,
+    sku: String,
+    #[serde(alias = "id")]
+    device: String,
+}
+
+#[derive(Deserialize, Debug)]
+struct StaticLanDeviceFile {
+    devices: Vec<StaticLanDevice>,
+}
+
+impl From<StaticLanDevice> for LanDevice {
+    fn from(d: StaticLanDevice) -> Self {
+        // We won't know the versions unless the device
+        // responds to a scan request
+        Self {
+            ip: d.ip,
+            device: d.device,
+            sku: d.sku,
+            ble_version_hard: String::new(),
+            ble_version_soft: String::new(),
+            wifi_version_hard: String::new(),
+            wifi_version_soft: String::new(),
+        }
+    }
+}
+
+pub fn load_lan_devices_file(path: &Path) -> anyhow::Result<Vec<LanDevice>> {
+    let data = std::fs::read_to_string(path)
+        .with_context(|| format!("reading LAN devices from {path:?}"))?;
+    let file: StaticLanDeviceFile =
+        from_json(&data).with_context(|| format!("parsing LAN devices from {path:?}"))?;
+    Ok(file.devices.into_iter().map(Into::into).collect())
+}
+
 impl LanDevice {
     pub async fn send_request(&self, msg: Request) -> anyhow::Result<()> {
         log::trace!("LanDevice::send_request to {:?} {msg:?}", self.ip);
-        let client = udp_socket_for_target(self.ip).await?;
+        let socket = shared_socket_for_target(self.ip).await?;
         let data = serde_json::to_string(&RequestMessage { msg })?;
-        client.send_to(data.as_bytes(), (self.ip, CMD_PORT)).await?;
+        capture_packet("lan", Direction::Tx, &self.device, data.as_bytes());
+        socket.send_to(data.as_bytes(), (self.ip, CMD_PORT)).await?;
 
         Ok(())
     }
@@ -238,74 +329,25 @@ impl LanDevice {
         .await
     }
 
-    /// Sets a scene on the device by its name using the centralized scene parsing logic.
-    pub async fn set_scene_by_name(
-        &self,
-        desired_scene_name_input: &str,
-    ) -> anyhow::Result<()> {
-        let parsed_scenes = get_parsed_scenes_for_sku(&self.sku).await?;
-
-        if let Some(target_scene) = parsed_scenes
-            .iter()
-            .find(|s| s.display_name == desired_scene_name_input)
-        {
-            // Handle override commands first
-            if let Some(ref override_commands) = target_scene.override_cmd_b64 {
-                log::info!(
-                    "Sending LAN scene packet for '{}' (Device SKU: {}) using override commands. Encoded: {:?}",
-                    target_scene.display_name,
-                    self.sku,
-                    override_commands
-                );
-                return self.send_real(override_commands.clone()).await;
-            }
-
-            // Fallback to encoding if no override and api_scence_param is available
-            if !target_scene.api_scence_param.is_empty() {
-                let scene_to_set = SetSceneCode::new(
-                    target_scene.scene_code,
-                    target_scene.api_scence_param.clone(), // Corrected
-                    target_scene.sku.clone(),
-                );
-
-                let encoded_command_container = Base64HexBytes::encode_for_sku(
-                    &self.sku,
-                    &scene_to_set,
-                )?;
-                let commands_b64 = encoded_command_container.base64();
-
-                log::info!(
-                    "Sending LAN scene packet for '{}' (Device SKU: {}). ParsedScene Code: {}, ParsedScene Param: '{}'. Encoded: {:?}",
-                    target_scene.display_name,
-                    self.sku,
-                    target_scene.scene_code,
-                    if target_scene.api_scence_param.len() > 20 { // Corrected
-                        format!("{}...", &target_scene.api_scence_param[..20]) // Corrected
-                    } else {
-                        target_scene.api_scence_param.clone() // Corrected
-                    },
-                    commands_b64
-                );
-                if !commands_b64.is_empty() {
-                    return self.send_real(commands_b64).await;
-                } else {
-                    anyhow::bail!("SetSceneCode::encode produced an empty command set for scene '{}'", target_scene.display_name);
-                }
-            }
-            
-            anyhow::bail!(
-                "Scene '{}' found for device SKU '{}', but it has neither override commands nor API parameters for encoding.",
-                desired_scene_name_input,
-                self.sku
-            );
-        }
-
-        anyhow::bail!(
-            "Unable to find scene matching '{}' for device SKU '{}', device ID '{}'",
-            desired_scene_name_input,
+    /// Sets a scene on the device by its name, as resolved by the SceneResolver
+    pub async fn set_scene_by_name(&self, scene: &str) -> anyhow::Result<()> {
+        let resolved = resolve_scene(
+            &self.sku,
+            &SceneSelector::Name {
+                name: scene.to_string(),
+                fuzzy: false,
+            },
+        )
+        .await
+        .with_context(|| format!("setting scene for {}", self.device))?;
+        log::info!(
+            "Sending LAN scene packets for '{}' to {} {}: {:?}",
+            resolved.name.as_deref().unwrap_or(scene),
             self.sku,
-            self.device
+            self.device,
+            resolved.commands
         );
+        self.send_real(resolved.commands).await
     }
 }
 
@@ -332,6 +374,36 @@ pub struct DeviceStatus {
     pub color: DeviceColor,
     #[serde(rename = "colorTemInKelvin")]
     pub color_temperature_kelvin: u32,
+    /// Some firmwares append op codes describing state that the
+    /// other fields don't cover, such as segment colors
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub op: Option<StatusOp>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct StatusOp {
+    /// base64 encoded BLE style packets
+    #[serde(default)]
+    pub command: Vec<String>,
+}
+
+impl DeviceStatus {
+    /// Decode the op codes that accompanied the status
+    pub fn decode_op(&self, sku: &str) -> Vec<GoveeBlePacket> {
+        let Some(op) = &self.op else {
+            return vec![];
+        };
+        op.command
+            .iter()
+            .filter_map(|cmd| match Base64HexBytes::from_base64(cmd) {
+                Ok(bytes) => Some(bytes.decode_for_sku(sku)),
+                Err(err) => {
+                    log::warn!("Ignoring op code in status for {sku}: {err:#}");
+                    None
+                }
+            })
+            .collect()
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
@@ -341,6 +413,56 @@ pub struct DeviceColor {
     pub b: u8,
 }
 
+impl DeviceColor {
+    /// Convert from hue (0-360) and saturation (0-100) at full value
+    pub fn from_hs(hue: f32, saturation: f32) -> Self {
+        let [r, g, b, _] = csscolorparser::Color::from_hsva(
+            hue.rem_euclid(360.),
+            (saturation / 100.).clamp(0., 1.),
+            1.,
+            1.,
+        )
+        .to_rgba8();
+        Self { r, g, b }
+    }
+
+    /// Convert from CIE 1931 xy chromaticity coordinates at full
+    /// brightness. Colors outside of the sRGB gamut are clipped.
+    pub fn from_xy(x: f32, y: f32) -> Self {
+        if y <= 0. {
+            return Self::default();
+        }
+        let big_x = x / y;
+        let big_z = (1. - x - y) / y;
+
+        // XYZ (D65) to linear sRGB
+        let r = 3.2406 * big_x - 1.5372 - 0.4986 * big_z;
+        let g = -0.9689 * big_x + 1.8758 + 0.0415 * big_z;
+        let b = 0.0557 * big_x - 0.2040 + 1.0570 * big_z;
+
+        let (r, g, b) = (r.max(0.), g.max(0.), b.max(0.));
+        let max = r.max(g).max(b);
+        if max <= 0. {
+            return Self::default();
+        }
+
+        fn gamma(c: f32) -> u8 {
+            let c = if c <= 0.0031308 {
+                12.92 * c
+            } else {
+                1.055 * c.powf(1. / 2.4) - 0.055
+            };
+            (c.clamp(0., 1.) * 255.).round() as u8
+        }
+
+        Self {
+            r: gamma(r / max),
+            g: gamma(g / max),
+            b: gamma(b / max),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "cmd", content = "data")]
 pub enum Response {
@@ -351,8 +473,8 @@ pub enum Response {
 }
 
 #[derive(Serialize, Deserialize, Debug)]
-struct ResponseWrapper {
-    msg: Response,
+pub(crate) struct ResponseWrapper {
+    pub msg: Response,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -361,14 +483,56 @@ pub enum AccountTopic {
     Reserve,
 }
 
-struct ClientListener {
-    addr: IpAddr,
-    tx: Sender<Response>,
+/// Routes the responses that arrive on the listen socket to the
+/// tasks that are waiting for a response from each device address
+#[derive(Default)]
+struct ResponseMux {
+    listeners: HashMap<IpAddr, Vec<Sender<Response>>>,
+    /// How many responses were dropped because the
+    /// listener wasn't keeping up
+    dropped: u64,
+}
+
+impl ResponseMux {
+    fn add_listener(&mut self, addr: IpAddr) -> Receiver<Response> {
+        let (tx, rx) = channel(8);
+        self.listeners.entry(addr).or_default().push(tx);
+        rx
+    }
+
+    /// Deliver response to the listeners for addr. A listener that
+    /// isn't keeping up misses the response, rather than holding up
+    /// the delivery of responses from other devices.
+    fn route(&mut self, addr: IpAddr, response: &Response) {
+        let Some(listeners) = self.listeners.get_mut(&addr) else {
+            return;
+        };
+        let dropped = &mut self.dropped;
+        listeners.retain(|tx| match tx.try_send(response.clone()) {
+            Ok(()) => true,
+            Err(TrySendError::Full(_)) => {
+                *dropped += 1;
+                log::warn!(
+                    "Dropping LAN response from {addr}: listener is busy \
+                    ({dropped} responses dropped so far)"
+                );
+                true
+            }
+            Err(TrySendError::Closed(_)) => false,
+        });
+        if listeners.is_empty() {
+            self.listeners.remove(&addr);
+        }
+    }
 }
 
-#[derive(Default)]
 struct ClientInner {
-    mux: Mutex<Vec<ClientListener>>,
+    mux: Mutex<ResponseMux>,
+    status_subscribers: Mutex<Vec<Sender<(IpAddr, DeviceStatus)>>>,
+    listener: DiscoListener,
+    /// The listener task started by `Client::new`, until it
+    /// is handed over to the watchdog
+    listener_task: parking_lot::Mutex<Option<JoinHandle<()>>>,
 }
 
 #[derive(Clone)]
@@ -382,6 +546,26 @@ struct Broadcaster {
     socket: UdpSocket,
 }
 
+/// Allow the shared socket to send to broadcast addresses and to
+/// receive responses to multicast requests, as Broadcaster::new
+/// does for the sockets that it creates
+fn configure_shared_socket(socket: &UdpSocket) -> std::io::Result<()> {
+    if !socket.local_addr()?.is_ipv4() {
+        return Ok(());
+    }
+    socket.set_broadcast(true)?;
+    let IpAddr::V4(multicast) = MULTICAST else {
+        unreachable!("MULTICAST is v4");
+    };
+    // Not every host has a route for multicast, and
+    // unicast and broadcast requests work without it
+    if let Err(err) = socket.join_multicast_v4(multicast, Ipv4Addr::UNSPECIFIED) {
+        log::warn!("Cannot join {multicast}: {err:#}");
+    }
+    socket.set_multicast_loop_v4(false)?;
+    Ok(())
+}
+
 async fn udp_socket_for_target(addr: IpAddr) -> std::io::Result<UdpSocket> {
     match addr {
         IpAddr::V4(_) => UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await,
@@ -389,6 +573,38 @@ async fn udp_socket_for_target(addr: IpAddr) -> std::io::Result<UdpSocket> {
     }
 }
 
+/// The socket bound to LISTEN_PORT by discovery, which is also used to
+/// send requests, so that all of our traffic with devices flows via a
+/// single long-lived socket
+static LISTEN_SOCKET: Lazy<parking_lot::Mutex<Option<Arc<UdpSocket>>>> =
+    Lazy::new(|| parking_lot::Mutex::new(None));
+/// Used to send requests before discovery has started, and for IPv6
+static FALLBACK_V4_SOCKET: OnceCell<Arc<UdpSocket>> = OnceCell::const_new();
+static FALLBACK_V6_SOCKET: OnceCell<Arc<UdpSocket>> = OnceCell::const_new();
+
+/// Returns the socket via which to send requests to addr.
+/// Devices always respond to LISTEN_PORT, whichever socket
+/// the request was sent from.
+async fn shared_socket_for_target(addr: IpAddr) -> std::io::Result<Arc<UdpSocket>> {
+    let fallback = match addr {
+        IpAddr::V4(_) => {
+            if let Some(socket) = LISTEN_SOCKET.lock().clone() {
+                return Ok(socket);
+            }
+            &FALLBACK_V4_SOCKET
+        }
+        IpAddr::V6(_) => &FALLBACK_V6_SOCKET,
+    };
+    fallback
+        .get_or_try_init(|| async {
+            let socket = udp_socket_for_target(addr).await?;
+            configure_shared_socket(&socket)?;
+            Ok(Arc::new(socket))
+        })
+        .await
+        .cloned()
+}
+
 impl Broadcaster {
     pub async fn new(addr: IpAddr) -> std::io::Result<Self> {
         let socket = udp_socket_for_target(addr).await?;
@@ -411,6 +627,23 @@ impl Broadcaster {
         Ok(Self { addr, socket })
     }
 
+    /// Create a broadcaster that sends to a multicast addr via
+    /// the interface with the specified local address
+    pub fn new_via_interface(addr: Ipv4Addr, iface: Ipv4Addr) -> std::io::Result<Self> {
+        use socket2::{Domain, Protocol, Socket, Type};
+
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+        socket.bind(&SocketAddr::from((iface, 0)).into())?;
+        socket.set_multicast_if_v4(&iface)?;
+        socket.set_multicast_loop_v4(false)?;
+        socket.set_nonblocking(true)?;
+
+        Ok(Self {
+            addr: addr.into(),
+            socket: UdpSocket::from_std(socket.into())?,
+        })
+    }
+
     pub async fn broadcast<B: AsRef<[u8]>>(&self, bytes: B) -> std::io::Result<()> {
         self.socket
             .send_to(bytes.as_ref(), (self.addr, SCAN_PORT))
@@ -419,10 +652,66 @@ impl Broadcaster {
     }
 }
 
+/// Returns true if the interface with the specified name and address
+/// was selected by the user
+fn interface_matches(name: &str, ip: IpAddr, wanted: &[String]) -> bool {
+    wanted.iter().any(|w| {
+        w.eq_ignore_ascii_case("all")
+            || w == name
+            || w.parse::<IpAddr>().map(|w| w == ip).unwrap_or(false)
+    })
+}
+
+/// Resolve the IPv4 addresses of the selected interfaces
+fn multicast_interfaces(wanted: &[String]) -> Vec<(String, Ipv4Addr)> {
+    let mut result = vec![];
+    match if_addrs::get_if_addrs() {
+        Ok(ifaces) => {
+            for iface in ifaces {
+                if iface.is_loopback() {
+                    continue;
+                }
+                if let IfAddr::V4(v4) = &iface.addr {
+                    if interface_matches(&iface.name, v4.ip.into(), wanted) {
+                        result.push((iface.name.clone(), v4.ip));
+                    }
+                }
+            }
+        }
+        Err(err) => {
+            log::error!("get_if_addrs: {err:#}");
+        }
+    }
+    result
+}
+
 async fn send_scan(options: &DiscoOptions) -> anyhow::Result<()> {
+    let mut broadcasters = vec![];
     let mut addresses = options.additional_addresses.clone();
     if options.enable_multicast {
-        addresses.push(MULTICAST);
+        if options.interfaces.is_empty() {
+            addresses.push(MULTICAST);
+        } else {
+            let IpAddr::V4(multicast) = MULTICAST else {
+                unreachable!("MULTICAST is v4");
+            };
+            let ifaces = multicast_interfaces(&options.interfaces);
+            if ifaces.is_empty() {
+                log::warn!(
+                    "None of the interfaces {:?} have an IPv4 address",
+                    options.interfaces
+                );
+            }
+            for (name, ip) in ifaces {
+                log::debug!("Adding multicast via if {name} {ip}");
+                match Broadcaster::new_via_interface(multicast, ip) {
+                    Ok(b) => broadcasters.push(b),
+                    Err(err) => {
+                        log::error!("{multicast} via {name} {ip}: {err:#}");
+                    }
+                }
+            }
+        }
     }
     if options.global_broadcast {
         addresses.push(Ipv4Addr::BROADCAST.into());
@@ -434,6 +723,11 @@ async fn send_scan(options: &DiscoOptions) -> anyhow::Result<()> {
                     if iface.is_loopback() {
                         continue;
                     }
+                    if !options.interfaces.is_empty()
+                        && !interface_matches(&iface.name, iface.ip(), &options.interfaces)
+                    {
+                        continue;
+                    }
                     let bcast = match iface.addr {
                         IfAddr::V4(v4) => v4.broadcast.map(IpAddr::V4),
                         IfAddr::V6(v6) => v6.broadcast.map(IpAddr::V6),
@@ -450,7 +744,6 @@ async fn send_scan(options: &DiscoOptions) -> anyhow::Result<()> {
         }
     }
 
-    let mut broadcasters = vec![];
     for addr in addresses {
         match Broadcaster::new(addr).await {
             Ok(b) => broadcasters.push(b),
@@ -475,10 +768,16 @@ async fn send_scan(options: &DiscoOptions) -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn lan_disco(
+/// What we need in order to (re)start the task that listens for
+/// discovery and status responses
+struct DiscoListener {
     options: DiscoOptions,
-    inner: Arc<ClientInner>,
-) -> anyhow::Result<Receiver<LanDevice>> {
+    listen: Arc<UdpSocket>,
+    tx: Sender<LanDevice>,
+    heartbeat: Heartbeat,
+}
+
+async fn lan_disco(options: DiscoOptions) -> anyhow::Result<(DiscoListener, Receiver<LanDevice>)> {
     let listen = UdpSocket::bind(("0.0.0.0", LISTEN_PORT)).await.context(
         "Cannot bind to UDP Port 4002, which is required \
         for the Govee LAN API to function. Most likely cause is that you \
@@ -488,92 +787,149 @@ async fn lan_disco(
         Consider disabling `Govee LAN Control` or setting `lanDisable` in \
         `homebridge-govee`.",
     )?;
+    configure_shared_socket(&listen)?;
+    let listen = Arc::new(listen);
+    LISTEN_SOCKET.lock().replace(listen.clone());
     let (tx, rx) = channel(8);
 
-    async fn process_packet(
-        addr: SocketAddr,
-        data: &[u8],
-        inner: &Arc<ClientInner>,
-        tx: &Sender<LanDevice>,
-    ) -> anyhow::Result<()> {
-        log::trace!(
-            "process_packet: addr={addr:?} data={}",
-            String::from_utf8_lossy(data)
-        );
-
-        let response: ResponseWrapper = from_json(data)
-            .with_context(|| format!("Parsing: {}", String::from_utf8_lossy(data)))?;
+    Ok((
+        DiscoListener {
+            options,
+            listen,
+            tx,
+            heartbeat: Heartbeat::default(),
+        },
+        rx,
+    ))
+}
 
-        let mut mux = inner.mux.lock().await;
-        mux.retain(|l| !l.tx.is_closed());
-        for l in mux.iter() {
-            if l.addr == addr.ip() {
-                l.tx.send(response.msg.clone()).await.ok();
+async fn process_packet(
+    addr: SocketAddr,
+    data: &[u8],
+    inner: &Arc<ClientInner>,
+) -> anyhow::Result<()> {
+    log::trace!(
+        "process_packet: addr={addr:?} data={}",
+        String::from_utf8_lossy(data)
+    );
+    capture_packet("lan", Direction::Rx, &addr.ip().to_string(), data);
+
+    let response: ResponseWrapper =
+        from_json(data).with_context(|| format!("Parsing: {}", String::from_utf8_lossy(data)))?;
+
+    inner.mux.lock().await.route(addr.ip(), &response.msg);
+
+    match response.msg {
+        Response::Scan(info) => {
+            inner.listener.tx.send(info).await?;
+        }
+        Response::DevStatus(status) => {
+            let mut subscribers = inner.status_subscribers.lock().await;
+            subscribers.retain(|tx| !tx.is_closed());
+            for tx in subscribers.iter() {
+                tx.send((addr.ip(), status.clone())).await.ok();
             }
         }
+    }
 
-        if let Response::Scan(info) = response.msg {
-            tx.send(info).await?;
-        }
+    Ok(())
+}
 
-        Ok(())
+async fn run_disco(inner: Arc<ClientInner>) -> anyhow::Result<()> {
+    let DiscoListener {
+        options,
+        listen,
+        tx,
+        heartbeat,
+    } = &inner.listener;
+    heartbeat.beat();
+
+    for device in &options.static_devices {
+        log::debug!("Static LAN device: {device:?}");
+        tx.send(device.clone()).await?;
     }
 
-    async fn run_disco(
-        options: &DiscoOptions,
-        listen: UdpSocket,
-        tx: Sender<LanDevice>,
-        inner: Arc<ClientInner>,
-    ) -> anyhow::Result<()> {
-        send_scan(options).await?;
+    send_scan(options).await?;
 
-        let mut retry_interval = Duration::from_secs(2);
-        let max_retry = Duration::from_secs(60);
-        let mut last_send = Instant::now();
-        loop {
-            let mut buf = [0u8; 4096];
+    let mut retry_interval = Duration::from_secs(2);
+    let max_retry = Duration::from_secs(60);
+    let mut last_send = Instant::now();
+    loop {
+        let mut buf = [0u8; 4096];
 
-            let deadline = last_send + retry_interval;
-            match tokio::time::timeout_at(deadline, listen.recv_from(&mut buf)).await {
-                Ok(Ok((len, addr))) => {
-                    if let Err(err) = process_packet(addr, &buf[0..len], &inner, &tx).await {
-                        log::error!("process_packet: {err:#}");
-                    }
-                }
-                Ok(Err(err)) => {
-                    log::error!("recv_from: {err:#}");
-                }
-                Err(_) => {
-                    send_scan(options).await?;
-                    last_send = Instant::now();
-                    retry_interval = (retry_interval * 2).min(max_retry);
+        let deadline = last_send + retry_interval;
+        match tokio::time::timeout_at(deadline, listen.recv_from(&mut buf)).await {
+            Ok(Ok((len, addr))) => {
+                if let Err(err) = process_packet(addr, &buf[0..len], &inner).await {
+                    log::error!("process_packet: {err:#}");
                 }
             }
+            Ok(Err(err)) => {
+                log::error!("recv_from: {err:#}");
+            }
+            Err(_) => {
+                send_scan(options).await?;
+                last_send = Instant::now();
+                retry_interval = (retry_interval * 2).min(max_retry);
+            }
         }
+        heartbeat.beat();
     }
-
-    tokio::spawn(async move {
-        if let Err(err) = run_disco(&options, listen, tx, inner).await {
-            log::error!("Error at the disco: {err:#}");
-        }
-    });
-
-    Ok(rx)
 }
 
 impl Client {
     pub async fn new(options: DiscoOptions) -> anyhow::Result<(Self, Receiver<LanDevice>)> {
-        let inner = Arc::new(ClientInner::default());
-        let rx = lan_disco(options, Arc::clone(&inner)).await?;
+        let (listener, rx) = lan_disco(options).await?;
+        let client = Self {
+            inner: Arc::new(ClientInner {
+                mux: Default::default(),
+                status_subscribers: Default::default(),
+                listener,
+                listener_task: Default::default(),
+            }),
+        };
+        let task = client.spawn_listener();
+        client.inner.listener_task.lock().replace(task);
+
+        Ok((client, rx))
+    }
+
+    /// Start a task that listens for discovery and status responses,
+    /// such as to replace one that has stalled
+    pub fn spawn_listener(&self) -> JoinHandle<()> {
+        let inner = Arc::clone(&self.inner);
+        tokio::spawn(async move {
+            if let Err(err) = run_disco(inner).await {
+                log::error!("Error at the disco: {err:#}");
+            }
+        })
+    }
+
+    /// Hand over the listener task that was started by `new`,
+    /// so that the watchdog can supervise it
+    pub fn take_listener_task(&self) -> Option<JoinHandle<()>> {
+        self.inner.listener_task.lock().take()
+    }
 
-        Ok((Self { inner }, rx))
+    /// Returns the heartbeat of the listener task, which beats
+    /// each time that it receives a response or rescans
+    pub fn listener_heartbeat(&self) -> Heartbeat {
+        self.inner.listener.heartbeat.clone()
     }
 
     async fn add_listener(&self, addr: IpAddr) -> anyhow::Result<Receiver<Response>> {
-        let (tx, rx) = channel(1);
-        let mut mux = self.inner.mux.lock().await;
-        mux.push(ClientListener { addr, tx });
-        Ok(rx)
+        Ok(self.inner.mux.lock().await.add_listener(addr))
+    }
+
+    /// Returns a receiver that yields the status of a device, along
+    /// with its address, each time that one is received. That includes
+    /// both responses to `query_status` and the status that some
+    /// devices send unsolicited when they are controlled by other
+    /// means, such as the Govee app.
+    pub async fn subscribe_status(&self) -> Receiver<(IpAddr, DeviceStatus)> {
+        let (tx, rx) = channel(32);
+        self.inner.status_subscribers.lock().await.push(tx);
+        rx
     }
 
     /// Interrogate `addr` by sending a scan request to it.
@@ -584,14 +940,16 @@ impl Client {
     pub async fn scan_ip(&self, addr: IpAddr) -> anyhow::Result<LanDevice> {
         let mut rx = self.add_listener(addr).await?;
 
-        let bcast = Broadcaster::new(addr).await?;
         let scan = serde_json::to_string(&RequestMessage {
             msg: Request::Scan {
                 account_topic: AccountTopic::Reserve,
             },
         })
         .expect("to serialize scan message");
-        bcast.broadcast(scan).await?;
+        shared_socket_for_target(addr)
+            .await?
+            .send_to(scan.as_bytes(), (addr, SCAN_PORT))
+            .await?;
 
         loop {
             match tokio::time::timeout(Duration::from_secs(10), rx.recv()).await {
@@ -600,7 +958,7 @@ impl Client {
                 }
                 Ok(Some(_)) => {}
                 Ok(None) => anyhow::bail!("listener thread terminated"),
-                Err(_) => anyhow::bail!("timeout waiting for response"),
+                Err(_) => return Err(LanTimeout::Response.into()),
             }
         }
     }
@@ -621,6 +979,148 @@ impl Client {
             }
         }
 
-        anyhow::bail!("timed out waiting for status");
+        Err(LanTimeout::Status.into())
+    }
+}
+
+/// Returned when a LAN device doesn't respond to us in time
+#[derive(thiserror::Error, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LanTimeout {
+    #[error("timeout waiting for response")]
+    Response,
+    #[error("timed out waiting for status")]
+    Status,
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn color_conversion() {
+        assert_eq!(
+            DeviceColor::from_hs(0., 100.),
+            DeviceColor { r: 255, g: 0, b: 0 }
+        );
+        assert_eq!(
+            DeviceColor::from_hs(240., 50.),
+            DeviceColor {
+                r: 128,
+                g: 128,
+                b: 255
+            }
+        );
+        assert_eq!(
+            DeviceColor::from_hs(0., 0.),
+            DeviceColor {
+                r: 255,
+                g: 255,
+                b: 255
+            }
+        );
+        // The sRGB primaries
+        assert_eq!(
+            DeviceColor::from_xy(0.64, 0.33),
+            DeviceColor { r: 255, g: 0, b: 0 }
+        );
+        assert_eq!(
+            DeviceColor::from_xy(0.15, 0.06),
+            DeviceColor { r: 0, g: 0, b: 255 }
+        );
+        // D65 white point
+        assert_eq!(
+            DeviceColor::from_xy(0.3127, 0.329),
+            DeviceColor {
+                r: 255,
+                g: 255,
+                b: 255
+            }
+        );
+    }
+
+    #[test]
+    fn static_devices() {
+        let file: StaticLanDeviceFile = from_json(
+            r#"{"devices": [
+                {"ip": "10.0.0.5", "sku": "H6159", "device": "AA:BB:CC:DD:EE:FF:00:11"},
+                {"ip": "10.0.0.6", "sku": "H619A", "id": "11:22:33:44:55:66:77:88"}
+            ]}"#,
+        )
+        .unwrap();
+        let devices: Vec<LanDevice> = file.devices.into_iter().map(Into::into).collect();
+        assert_eq!(devices.len(), 2);
+        assert_eq!(devices[1].ip, "10.0.0.6".parse::<IpAddr>().unwrap());
+        assert_eq!(devices[1].sku, "H619A");
+        assert_eq!(devices[1].device, "11:22:33:44:55:66:77:88");
+    }
+
+    #[test]
+    fn status_op_codes() {
+        let response: ResponseWrapper = from_json(
+            r#"{"msg": {"cmd": "devStatus", "data": {
+                "onOff": 1, "brightness": 100,
+                "color": {"r": 255, "g": 0, "b": 0}, "colorTemInKelvin": 0,
+                "op": {"command": ["qhQBAAAAAAAAAAAAAAAAAAAAAL8=", "qgUEUwsAAAAAAAAAAAAAAAAAAPM="]}
+            }}}"#,
+        )
+        .unwrap();
+        let Response::DevStatus(status) = response.msg else {
+            panic!("expected devStatus");
+        };
+        assert_eq!(
+            status.decode_op("H6199"),
+            vec![
+                GoveeBlePacket::NotifyGradient(crate::ble::NotifyGradient { on: true }),
+                GoveeBlePacket::NotifySceneCode(crate::ble::NotifySceneCode { code: 2899 }),
+            ]
+        );
+    }
+
+    #[test]
+    fn interface_selection() {
+        let ip: IpAddr = "192.168.1.10".parse().unwrap();
+        assert!(interface_matches("eth0", ip, &["eth0".to_string()]));
+        assert!(interface_matches("eth0", ip, &["192.168.1.10".to_string()]));
+        assert!(interface_matches("eth0", ip, &["ALL".to_string()]));
+        assert!(!interface_matches(
+            "eth0",
+            ip,
+            &["eth1".to_string(), "10.0.0.1".to_string()]
+        ));
+    }
+
+    #[tokio::test]
+    async fn response_routing() {
+        let a: IpAddr = "10.0.0.1".parse().unwrap();
+        let b: IpAddr = "10.0.0.2".parse().unwrap();
+        let mut mux = ResponseMux::default();
+        let mut rx_a = mux.add_listener(a);
+        let rx_gone = mux.add_listener(b);
+        drop(rx_gone);
+
+        mux.route(a, &Response::DevStatus(DeviceStatus::default()));
+        mux.route(b, &Response::DevStatus(DeviceStatus::default()));
+        assert!(matches!(rx_a.try_recv(), Ok(Response::DevStatus(_))));
+        assert!(rx_a.try_recv().is_err());
+        // Closed listeners are forgotten
+        assert!(!mux.listeners.contains_key(&b));
+
+        // A listener that falls behind doesn't block routing,
+        // but we keep count of the responses that it missed
+        for _ in 0..20 {
+            mux.route(a, &Response::DevStatus(DeviceStatus::default()));
+        }
+        assert!(mux.listeners.contains_key(&a));
+        assert_eq!(mux.dropped, 12);
+    }
+
+    #[tokio::test]
+    async fn shared_socket() {
+        let ip: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let first = shared_socket_for_target(ip).await.unwrap();
+        let second = shared_socket_for_target(ip).await.unwrap();
+        assert!(Arc::ptr_eq(&first, &second));
+        // so that requests may be sent to broadcast addresses
+        assert!(first.broadcast().unwrap());
     }
 }
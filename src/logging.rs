@@ -0,0 +1,145 @@
+//! Logging setup. Log records are written either as human readable
+//! lines or, when GOVEE_LOG_FORMAT=json, as one JSON object per line.
+//! The RUST_LOG filter that is in effect at startup can be extended
+//! at runtime via set_log_filter, so that the chatter from a single
+//! module can be enabled without restarting.
+use crate::{opt_env_var, resolve_timezone};
+use arc_swap::ArcSwap;
+use chrono::Utc;
+use log::{LevelFilter, Log, Metadata, Record};
+use once_cell::sync::Lazy;
+use std::io::Write;
+use std::sync::Arc;
+
+struct RuntimeFilter {
+    /// The directives that were layered over RUST_LOG
+    spec: String,
+    filter: env_filter::Filter,
+}
+
+static FILTER: Lazy<ArcSwap<RuntimeFilter>> =
+    Lazy::new(|| ArcSwap::from_pointee(build_filter("").expect("the startup filter is valid")));
+
+fn build_filter(spec: &str) -> anyhow::Result<RuntimeFilter> {
+    let mut builder = env_filter::Builder::new();
+    builder.filter_level(LevelFilter::Info);
+    if let Ok(startup) = std::env::var("RUST_LOG") {
+        builder.parse(&startup);
+    }
+    builder
+        .try_parse(spec)
+        .map_err(|err| anyhow::anyhow!("invalid log filter '{spec}': {err}"))?;
+    Ok(RuntimeFilter {
+        spec: spec.to_string(),
+        filter: builder.build(),
+    })
+}
+
+/// Layer spec, which uses the same syntax as RUST_LOG, over the
+/// filter from RUST_LOG. An empty spec restores the startup filter.
+pub fn set_log_filter(spec: &str) -> anyhow::Result<()> {
+    let filter = build_filter(spec.trim())?;
+    log::set_max_level(filter.filter.filter());
+    FILTER.store(Arc::new(filter));
+    log::info!("log filter is now '{}'", log_filter());
+    Ok(())
+}
+
+/// Returns the directives that were most recently passed to
+/// set_log_filter
+pub fn log_filter() -> String {
+    FILTER.load().spec.clone()
+}
+
+/// Applies the current runtime filter before handing the
+/// record to env_logger for formatting
+struct RuntimeLogger {
+    inner: env_logger::Logger,
+}
+
+impl Log for RuntimeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        FILTER.load().filter.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        if FILTER.load().filter.matches(record) {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush()
+    }
+}
+
+pub fn setup_logger() {
+    let tz = resolve_timezone();
+    let utc_suffix = if tz == chrono_tz::UTC { "Z" } else { "" };
+    let json = match opt_env_var::<String>("GOVEE_LOG_FORMAT") {
+        Ok(format) => format.is_some_and(|f| f.eq_ignore_ascii_case("json")),
+        Err(err) => {
+            eprintln!("{err:#}");
+            false
+        }
+    };
+
+    let mut builder = env_logger::builder();
+    // The runtime filter decides what is logged
+    builder.filter_level(LevelFilter::Trace);
+
+    if json {
+        builder.format(move |buf, record| {
+            let line = serde_json::json!({
+                "timestamp": Utc::now().with_timezone(&tz).to_rfc3339(),
+                "level": record.level().as_str(),
+                "target": record.target(),
+                "module": record.module_path(),
+                "message": record.args().to_string(),
+            });
+            writeln!(buf, "{line}")
+        });
+    } else {
+        // A bit of boilerplate here to get timestamps printed in local time.
+        // <https://github.com/rust-cli/env_logger/issues/158>
+        builder.format(move |buf, record| {
+            let level_style = buf.default_level_style(record.level());
+            write!(
+                buf,
+                "[{}{utc_suffix} ",
+                Utc::now().with_timezone(&tz).format("%Y-%m-%dT%H:%M:%S")
+            )?;
+            write!(buf, "{level_style}{:<5}{level_style:#}", record.level())?;
+            if let Some(path) = record.module_path() {
+                write!(buf, " {}", path)?;
+            }
+            writeln!(buf, "] {}", record.args())
+        });
+    }
+
+    let logger = RuntimeLogger {
+        inner: builder.build(),
+    };
+    log::set_max_level(FILTER.load().filter.filter());
+    if let Err(err) = log::set_boxed_logger(Box::new(logger)) {
+        eprintln!("failed to install logger: {err}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn runtime_log_filter() {
+        let filter = build_filter("govee::lan_api=trace").unwrap().filter;
+        let enabled = |level, target| {
+            filter.enabled(&Metadata::builder().level(level).target(target).build())
+        };
+        assert!(enabled(log::Level::Trace, "govee::lan_api"));
+        assert!(!enabled(log::Level::Debug, "govee::service::iot"));
+        assert!(enabled(log::Level::Info, "govee::service::iot"));
+
+        assert!(build_filter("govee=bogus").is_err());
+    }
+}
@@ -1,18 +1,30 @@
 use crate::lan_api::LanDiscoArguments;
+use crate::packet_codecs::PacketCodecArguments;
 use crate::platform_api::GoveeApiArguments;
+use crate::secrets::SecretsArguments;
 use crate::service::hass::HassArguments;
 use crate::undoc_api::UndocApiArguments;
 use clap::Parser;
 use std::str::FromStr;
 
 mod ble;
+mod ble_advert;
 mod cache;
 mod commands;
+mod fake_device;
 mod hass_mqtt;
 mod lan_api;
+mod logging;
+mod packet_codecs;
 #[macro_use]
 mod platform_api;
+mod platform_capability;
+mod rate_limit;
 mod rest_api;
+mod scene_match;
+mod scene_resolver;
+mod scene_sync;
+mod secrets;
 mod service;
 mod temperature;
 mod undoc_api;
@@ -31,6 +43,10 @@ pub struct Args {
     undoc_args: UndocApiArguments,
     #[command(flatten)]
     hass_args: HassArguments,
+    #[command(flatten)]
+    codec_args: PacketCodecArguments,
+    #[command(flatten)]
+    secrets_args: SecretsArguments,
 
     #[command(subcommand)]
     cmd: SubCommand,
@@ -38,23 +54,40 @@ pub struct Args {
 
 #[derive(clap::Parser, Debug)]
 pub enum SubCommand {
+    Control(commands::control::ControlCommand),
+    Decode(commands::decode::DecodeCommand),
+    Devices(commands::devices::DevicesCommand),
+    Diagnose(commands::diagnose::DiagnoseCommand),
+    Emulate(commands::emulate::EmulateCommand),
     LanControl(commands::lan_control::LanControlCommand),
     LanDisco(commands::lan_disco::LanDiscoCommand),
     ListHttp(commands::list_http::ListHttpCommand),
     List(commands::list::ListCommand),
     HttpControl(commands::http_control::HttpControlCommand),
+    Scenes(commands::scenes::ScenesCommand),
+    Secrets(commands::secrets::SecretsCommand),
     Serve(commands::serve::ServeCommand),
     Undoc(commands::undoc::UndocCommand),
 }
 
 impl Args {
     pub async fn run(&self) -> anyhow::Result<()> {
+        self.codec_args.load()?;
+        self.secrets_args.load().await?;
+
         match &self.cmd {
+            SubCommand::Control(cmd) => cmd.run(self).await,
+            SubCommand::Decode(cmd) => cmd.run(self).await,
+            SubCommand::Devices(cmd) => cmd.run(self).await,
+            SubCommand::Diagnose(cmd) => cmd.run(self).await,
+            SubCommand::Emulate(cmd) => cmd.run(self).await,
             SubCommand::LanControl(cmd) => cmd.run(self).await,
             SubCommand::LanDisco(cmd) => cmd.run(self).await,
             SubCommand::ListHttp(cmd) => cmd.run(self).await,
             SubCommand::HttpControl(cmd) => cmd.run(self).await,
             SubCommand::List(cmd) => cmd.run(self).await,
+            SubCommand::Scenes(cmd) => cmd.run(self).await,
+            SubCommand::Secrets(cmd) => cmd.run(self).await,
             SubCommand::Serve(cmd) => cmd.run(self).await,
             SubCommand::Undoc(cmd) => cmd.run(self).await,
         }
@@ -76,40 +109,13 @@ where
     }
 }
 
-fn setup_logger() {
-    fn resolve_timezone() -> chrono_tz::Tz {
-        std::env::var("TZ")
-            .or_else(|_| iana_time_zone::get_timezone())
-            .ok()
-            .and_then(|name| name.parse().ok())
-            .unwrap_or(chrono_tz::UTC)
-    }
-
-    let tz = resolve_timezone();
-    let utc_suffix = if tz == chrono_tz::UTC { "Z" } else { "" };
-
-    env_logger::builder()
-        // A bit of boilerplate here to get timestamps printed in local time.
-        // <https://github.com/rust-cli/env_logger/issues/158>
-        .format(move |buf, record| {
-            use chrono::Utc;
-            use std::io::Write;
-
-            let level_style = buf.default_level_style(record.level());
-            write!(
-                buf,
-                "[{}{utc_suffix} ",
-                Utc::now().with_timezone(&tz).format("%Y-%m-%dT%H:%M:%S")
-            )?;
-            write!(buf, "{level_style}{:<5}{level_style:#}", record.level())?;
-            if let Some(path) = record.module_path() {
-                write!(buf, " {}", path)?;
-            }
-            writeln!(buf, "] {}", record.args())
-        })
-        .filter_level(log::LevelFilter::Info)
-        .parse_env("RUST_LOG")
-        .init();
+/// Determine the local timezone, preferring $TZ over the system setting
+pub fn resolve_timezone() -> chrono_tz::Tz {
+    std::env::var("TZ")
+        .or_else(|_| iana_time_zone::get_timezone())
+        .ok()
+        .and_then(|name| name.parse().ok())
+        .unwrap_or(chrono_tz::UTC)
 }
 
 #[tokio::main(worker_threads = 2)]
@@ -119,7 +125,7 @@ async fn main() -> anyhow::Result<()> {
         eprintln!("Loading environment overrides from {path:?}");
     }
 
-    setup_logger();
+    logging::setup_logger();
 
     let args = Args::parse();
     args.run().await
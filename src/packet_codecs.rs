@@ -0,0 +1,327 @@
+//! Packet codecs that are defined declaratively in JSON files,
+//! so that support for the packets used by new devices can be
+//! added without recompiling govee2mqtt.
+//!
+//! A codec file looks like this:
+//!
+//! ```json
+//! {
+//!   "codecs": [
+//!     {
+//!       "name": "NotifyHeaterTemperature",
+//!       "skus": ["H7130", "H7131"],
+//!       "layout": ["0xaa", "0x11", "on", "target:u16"]
+//!     }
+//!   ]
+//! }
+//! ```
+//!
+//! The layout describes the packet, excluding its checksum. Each entry
+//! is either a literal byte, given as a number or a hex string, or
+//! a field, given as its name optionally followed by `:u8`, `:u16`
+//! or `:bool`. Fields are `u8` unless otherwise specified.
+use crate::ble::DecodePacketParam;
+use crate::opt_env_var;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+
+#[derive(clap::Parser, Debug)]
+pub struct PacketCodecArguments {
+    /// A JSON file defining additional packet codecs.
+    /// Can be specified multiple times.
+    /// You may also set GOVEE_PACKET_CODEC_FILES=a.json,b.json via the environment.
+    #[arg(long = "packet-codec-file", global = true)]
+    pub packet_codec_files: Vec<PathBuf>,
+}
+
+impl PacketCodecArguments {
+    pub fn codec_files(&self) -> anyhow::Result<Vec<PathBuf>> {
+        let mut files = self.packet_codec_files.clone();
+        if let Some(v) = opt_env_var::<String>("GOVEE_PACKET_CODEC_FILES")? {
+            for path in v.split(',') {
+                let path = path.trim();
+                if !path.is_empty() {
+                    files.push(PathBuf::from(path));
+                }
+            }
+        }
+        Ok(files)
+    }
+
+    /// Load the codec files and make their codecs available
+    /// to the packet manager
+    pub fn load(&self) -> anyhow::Result<()> {
+        let mut codecs = vec![];
+        for path in self.codec_files()? {
+            let file = load_codec_file(&path)?;
+            log::info!("Loaded {} packet codec(s) from {path:?}", file.codecs.len());
+            codecs.extend(file.codecs);
+        }
+        if !codecs.is_empty() {
+            crate::ble::register_custom_codecs(codecs);
+        }
+        Ok(())
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum FieldKind {
+    U8,
+    U16,
+    Bool,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(try_from = "RawLayoutEntry")]
+pub enum LayoutEntry {
+    Byte(u8),
+    Field { name: String, kind: FieldKind },
+}
+
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum RawLayoutEntry {
+    Number(u8),
+    Text(String),
+}
+
+impl TryFrom<RawLayoutEntry> for LayoutEntry {
+    type Error = anyhow::Error;
+
+    fn try_from(raw: RawLayoutEntry) -> anyhow::Result<Self> {
+        let text = match raw {
+            RawLayoutEntry::Number(byte) => return Ok(Self::Byte(byte)),
+            RawLayoutEntry::Text(text) => text,
+        };
+
+        if let Some(hex) = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")) {
+            let byte = u8::from_str_radix(hex, 16)
+                .with_context(|| format!("parsing {text} as a hex byte"))?;
+            return Ok(Self::Byte(byte));
+        }
+
+        let (name, kind) = match text.split_once(':') {
+            Some((name, "u8")) => (name, FieldKind::U8),
+            Some((name, "u16")) => (name, FieldKind::U16),
+            Some((name, "bool")) => (name, FieldKind::Bool),
+            Some((_, kind)) => {
+                anyhow::bail!("field {text} has unknown type {kind}, expected u8, u16 or bool")
+            }
+            None => (text.as_str(), FieldKind::U8),
+        };
+        if name.is_empty() {
+            anyhow::bail!("a field in the layout has an empty name");
+        }
+        Ok(Self::Field {
+            name: name.to_string(),
+            kind,
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct CodecDefinition {
+    pub name: String,
+    /// The SKUs that use this packet; `*` matches any SKU
+    pub skus: Vec<String>,
+    pub layout: Vec<LayoutEntry>,
+}
+
+/// A packet that was decoded by a codec from a codec file
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CustomPacket {
+    pub name: String,
+    pub fields: BTreeMap<String, JsonValue>,
+}
+
+impl CodecDefinition {
+    fn validate(&self) -> anyhow::Result<()> {
+        if self.name.is_empty() {
+            anyhow::bail!("a codec has an empty name");
+        }
+        if self.skus.is_empty() {
+            anyhow::bail!("codec {} has no skus", self.name);
+        }
+        // Without a leading literal, the codec would claim
+        // packets that are meant for other codecs
+        if !matches!(self.layout.first(), Some(LayoutEntry::Byte(_))) {
+            anyhow::bail!("the layout of codec {} must begin with a byte", self.name);
+        }
+        let mut names = HashSet::new();
+        for entry in &self.layout {
+            if let LayoutEntry::Field { name, .. } = entry {
+                if !names.insert(name) {
+                    anyhow::bail!("codec {} has more than one field named {name}", self.name);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn matches_sku(&self, sku: &str) -> bool {
+        self.skus
+            .iter()
+            .any(|s| s == "*" || s.eq_ignore_ascii_case(sku))
+    }
+
+    /// Encode the packet, excluding its checksum
+    pub fn encode(&self, fields: &BTreeMap<String, JsonValue>) -> anyhow::Result<Vec<u8>> {
+        if let Some(unknown) = fields.keys().find(|key| {
+            !self
+                .layout
+                .iter()
+                .any(|entry| matches!(entry, LayoutEntry::Field { name, .. } if name == *key))
+        }) {
+            anyhow::bail!("codec {} has no field named {unknown}", self.name);
+        }
+
+        let mut data = vec![];
+        for entry in &self.layout {
+            let (name, kind) = match entry {
+                LayoutEntry::Byte(byte) => {
+                    data.push(*byte);
+                    continue;
+                }
+                LayoutEntry::Field { name, kind } => (name, kind),
+            };
+            let value = fields
+                .get(name)
+                .ok_or_else(|| anyhow::anyhow!("codec {} requires field {name}", self.name))?;
+            let context = || format!("codec {} field {name}: {value}", self.name);
+            match kind {
+                FieldKind::U8 => u8::deserialize(value)
+                    .with_context(context)?
+                    .encode_param(&mut data),
+                FieldKind::U16 => u16::deserialize(value)
+                    .with_context(context)?
+                    .encode_param(&mut data),
+                FieldKind::Bool => {
+                    let on = match value {
+                        JsonValue::Bool(on) => *on,
+                        JsonValue::Number(n) => n.as_u64() != Some(0),
+                        _ => anyhow::bail!("{}: expected a boolean", context()),
+                    };
+                    on.encode_param(&mut data)
+                }
+            }
+        }
+        Ok(data)
+    }
+
+    /// Decode a packet, excluding its checksum
+    pub fn decode(&self, mut data: &[u8]) -> anyhow::Result<CustomPacket> {
+        let mut fields = BTreeMap::new();
+        for entry in &self.layout {
+            match entry {
+                LayoutEntry::Byte(expected) => {
+                    let byte = data.first();
+                    anyhow::ensure!(
+                        byte == Some(expected),
+                        "expected {expected} but got {byte:?}"
+                    );
+                    data = &data[1..];
+                }
+                LayoutEntry::Field { name, kind } => {
+                    let value = match kind {
+                        FieldKind::U8 => {
+                            let mut value = 0u8;
+                            data = value.decode_param(data)?;
+                            JsonValue::from(value)
+                        }
+                        FieldKind::U16 => {
+                            let mut value = 0u16;
+                            data = value.decode_param(data)?;
+                            JsonValue::from(value)
+                        }
+                        FieldKind::Bool => {
+                            let mut value = false;
+                            data = value.decode_param(data)?;
+                            JsonValue::from(value)
+                        }
+                    };
+                    fields.insert(name.clone(), value);
+                }
+            }
+        }
+        anyhow::ensure!(
+            data.iter().all(|&b| b == 0),
+            "unexpected trailing data {data:02x?}"
+        );
+        Ok(CustomPacket {
+            name: self.name.clone(),
+            fields,
+        })
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct CodecFile {
+    pub codecs: Vec<CodecDefinition>,
+}
+
+pub fn load_codec_file(path: &Path) -> anyhow::Result<CodecFile> {
+    let data = std::fs::read_to_string(path)
+        .with_context(|| format!("reading packet codecs from {path:?}"))?;
+    let file: CodecFile = serde_json::from_str(&data)
+        .with_context(|| format!("parsing packet codecs from {path:?}"))?;
+    for codec in &file.codecs {
+        codec
+            .validate()
+            .with_context(|| format!("validating packet codecs from {path:?}"))?;
+    }
+    Ok(file)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn codec_definition() {
+        let file: CodecFile = serde_json::from_str(
+            r#"{"codecs": [{
+                "name": "NotifyHeaterTemperature",
+                "skus": ["H7130"],
+                "layout": ["0xaa", 17, "on:bool", "target:u16", "mode"]
+            }]}"#,
+        )
+        .unwrap();
+        let codec = &file.codecs[0];
+        codec.validate().unwrap();
+        assert!(codec.matches_sku("h7130"));
+        assert!(!codec.matches_sku("H7131"));
+
+        let fields: BTreeMap<String, JsonValue> =
+            serde_json::from_str(r#"{"on": true, "target": 300, "mode": 2}"#).unwrap();
+        let data = codec.encode(&fields).unwrap();
+        assert_eq!(data, vec![0xaa, 0x11, 0x01, 0x2c, 0x01, 0x02]);
+
+        let mut padded = data.clone();
+        padded.resize(19, 0);
+        assert_eq!(
+            codec.decode(&padded).unwrap(),
+            CustomPacket {
+                name: "NotifyHeaterTemperature".to_string(),
+                fields,
+            }
+        );
+        assert!(codec.decode(&[0xaa, 0x12, 0x01, 0x2c, 0x01, 0x02]).is_err());
+
+        let missing: BTreeMap<String, JsonValue> = serde_json::from_str(r#"{"on": true}"#).unwrap();
+        assert!(codec.encode(&missing).is_err());
+
+        assert!(serde_json::from_str::<CodecFile>(
+            r#"{"codecs": [{"name": "x", "skus": ["*"], "layout": ["value:u32"]}]}"#
+        )
+        .is_err());
+        let unanchored: CodecFile = serde_json::from_str(
+            r#"{"codecs": [{"name": "x", "skus": ["*"], "layout": ["value"]}]}"#,
+        )
+        .unwrap();
+        assert!(unanchored.codecs[0].validate().is_err());
+    }
+}
@@ -1,15 +1,22 @@
 use crate::cache::{cache_get, CacheComputeResult, CacheGetOptions};
 use crate::hass_mqtt::climate::parse_temperature_constraints;
+use crate::lan_api::truthy;
 use crate::opt_env_var;
+use crate::rate_limit::{parse_retry_after, platform_limiter_for_key, RateLimiter};
 use crate::service::state::sort_and_dedup_scenes;
-use crate::temperature::{TemperatureUnits, TemperatureValue};
+use crate::temperature::TemperatureValue;
 use crate::undoc_api::GoveeUndocumentedApi;
 use anyhow::Context;
+use parking_lot::Mutex;
 use reqwest::Method;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::{json, Value as JsonValue};
 use std::collections::HashMap;
-use std::time::Duration;
+use std::future::Future;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use thiserror::Error;
 
 // This file implements the Govee Platform API V1 as described at:
@@ -20,8 +27,12 @@ use thiserror::Error;
 // <https://govee.readme.io/reference/getlightdeviceinfo>
 
 const SERVER: &str = "https://openapi.api.govee.com";
+/// How many times we will try a request that was rejected
+/// due to rate limiting
+const MAX_THROTTLED_ATTEMPTS: u32 = 4;
 pub const ONE_WEEK: Duration = Duration::from_secs(86400 * 7);
 pub const FIVE_MINUTES: Duration = Duration::from_secs(5 * 60);
+const DEVICE_LIST_CACHE_KEY: &str = "device-list";
 
 fn endpoint(url: &str) -> String {
     format!("{SERVER}{url}")
@@ -33,6 +44,19 @@ pub struct GoveeApiArguments {
     /// the GOVEE_API_KEY environment variable.
     #[arg(long, global = true)]
     pub api_key: Option<String>,
+
+    /// How many seconds to cache the device list, capabilities and
+    /// scene lists returned by the Platform API before fetching them
+    /// again. If not passed here, it will be read from the
+    /// GOVEE_PLATFORM_CACHE_TTL environment variable.
+    #[arg(long, global = true)]
+    pub platform_cache_ttl: Option<u64>,
+
+    /// Only cache Platform API responses in memory, rather than
+    /// also persisting them in the cache directory.
+    /// You may also set GOVEE_PLATFORM_NO_DISK_CACHE=true via the environment.
+    #[arg(long, global = true)]
+    pub platform_no_disk_cache: bool,
 }
 
 impl GoveeApiArguments {
@@ -52,36 +76,246 @@ impl GoveeApiArguments {
         })
     }
 
+    pub fn cache_options(&self) -> anyhow::Result<PlatformCacheOptions> {
+        let ttl = match self.platform_cache_ttl {
+            Some(secs) => Some(secs),
+            None => opt_env_var::<u64>("GOVEE_PLATFORM_CACHE_TTL")?,
+        };
+        let mut disk = !self.platform_no_disk_cache;
+        if let Some(v) = opt_env_var::<String>("GOVEE_PLATFORM_NO_DISK_CACHE")? {
+            disk = !truthy(&v)?;
+        }
+        Ok(PlatformCacheOptions {
+            ttl: ttl.map(Duration::from_secs),
+            disk,
+        })
+    }
+
     pub fn api_client(&self) -> anyhow::Result<GoveeApiClient> {
         let key = self.api_key()?;
-        Ok(GoveeApiClient::new(key))
+        Ok(GoveeApiClient::new(key).with_cache_options(self.cache_options()?))
+    }
+}
+
+/// Controls how responses that rarely change, such as the device
+/// list and scene lists, are cached
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PlatformCacheOptions {
+    /// Overrides the default time to live for each kind of response
+    pub ttl: Option<Duration>,
+    /// Whether to also persist responses in the disk cache
+    pub disk: bool,
+}
+
+impl Default for PlatformCacheOptions {
+    fn default() -> Self {
+        Self {
+            ttl: None,
+            disk: true,
+        }
+    }
+}
+
+/// Recent responses, held in memory so that repeated lookups
+/// don't need to consult the disk cache or the network
+#[derive(Default)]
+struct MemoryCache {
+    entries: Mutex<HashMap<String, (Instant, JsonValue)>>,
+}
+
+impl MemoryCache {
+    fn get(&self, key: &str) -> Option<JsonValue> {
+        let mut entries = self.entries.lock();
+        match entries.get(key) {
+            Some((expires, value)) if Instant::now() < *expires => Some(value.clone()),
+            Some(_) => {
+                entries.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    fn insert(&self, key: &str, ttl: Duration, value: JsonValue) {
+        self.entries
+            .lock()
+            .insert(key.to_string(), (Instant::now() + ttl, value));
+    }
+
+    fn remove(&self, key: &str) {
+        self.entries.lock().remove(key);
+    }
+
+    fn clear(&self) {
+        self.entries.lock().clear();
+    }
+}
+
+/// Counts the requests that were made on behalf of each device
+/// today, so that the devices that use the most of the daily
+/// quota can be identified
+#[derive(Default)]
+struct DeviceRequestCounts {
+    counts: Mutex<(Option<chrono::NaiveDate>, HashMap<String, u32>)>,
+}
+
+impl DeviceRequestCounts {
+    fn note_request(&self, device: &str) {
+        let today = chrono::Utc::now().date_naive();
+        let mut counts = self.counts.lock();
+        if counts.0 != Some(today) {
+            *counts = (Some(today), HashMap::new());
+        }
+        *counts.1.entry(device.to_string()).or_default() += 1;
+    }
+
+    fn requests_today(&self, device: &str) -> u32 {
+        let counts = self.counts.lock();
+        if counts.0 != Some(chrono::Utc::now().date_naive()) {
+            return 0;
+        }
+        counts.1.get(device).copied().unwrap_or(0)
     }
 }
 
 #[derive(Clone)]
 pub struct GoveeApiClient {
     key: String,
+    limiter: Arc<RateLimiter>,
+    cache_options: PlatformCacheOptions,
+    memory_cache: Arc<MemoryCache>,
+    device_requests: Arc<DeviceRequestCounts>,
+    /// Set while the API key is being rejected by the server
+    auth_failure: Arc<Mutex<Option<String>>>,
 }
 
 impl GoveeApiClient {
     pub fn new<K: Into<String>>(key: K) -> Self {
-        Self { key: key.into() }
+        let key = key.into();
+        let limiter = platform_limiter_for_key(&key);
+        Self {
+            key,
+            limiter,
+            cache_options: PlatformCacheOptions::default(),
+            memory_cache: Arc::new(MemoryCache::default()),
+            device_requests: Arc::new(DeviceRequestCounts::default()),
+            auth_failure: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn with_cache_options(mut self, cache_options: PlatformCacheOptions) -> Self {
+        self.cache_options = cache_options;
+        self
+    }
+
+    /// Discard the responses that are cached in memory
+    pub fn clear_memory_cache(&self) {
+        self.memory_cache.clear();
+    }
+
+    /// Discard the cached device list, so that changes that
+    /// we made, such as renaming a device, are picked up
+    pub fn invalidate_device_list(&self) {
+        self.memory_cache.remove(DEVICE_LIST_CACHE_KEY);
+        crate::cache::invalidate_key("http-api", DEVICE_LIST_CACHE_KEY).ok();
+    }
+
+    /// Fetch data that rarely changes via the memory cache and,
+    /// unless it is disabled, the disk cache. default_ttl applies
+    /// unless a ttl was configured via the cache options.
+    async fn cached<T, Fut>(
+        &self,
+        key: &str,
+        default_ttl: Duration,
+        negative_ttl: Duration,
+        future: Fut,
+    ) -> anyhow::Result<T>
+    where
+        T: Serialize + DeserializeOwned + std::fmt::Debug + Clone,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        if let Some(value) = self.memory_cache.get(key) {
+            match serde_json::from_value(value) {
+                Ok(value) => {
+                    log::trace!("memory cache hit for {key}");
+                    return Ok(value);
+                }
+                Err(err) => log::warn!("Error parsing memory cache entry {key}: {err:#}"),
+            }
+        }
+
+        let ttl = self.cache_options.ttl.unwrap_or(default_ttl);
+        // Set when the request failed and the disk cache gave us
+        // a stale value instead; we want to retry that sooner
+        let fetch_failed = AtomicBool::new(false);
+        let value = if self.cache_options.disk {
+            cache_get(
+                CacheGetOptions {
+                    topic: "http-api",
+                    key,
+                    soft_ttl: ttl,
+                    hard_ttl: ONE_WEEK.max(ttl),
+                    negative_ttl,
+                    allow_stale: true,
+                },
+                async {
+                    match future.await {
+                        Ok(value) => Ok(CacheComputeResult::Value(value)),
+                        Err(err) => {
+                            fetch_failed.store(true, Ordering::Relaxed);
+                            Err(err)
+                        }
+                    }
+                },
+            )
+            .await?
+        } else {
+            future.await?
+        };
+
+        let memory_ttl = if fetch_failed.load(Ordering::Relaxed) {
+            negative_ttl.min(ttl)
+        } else {
+            ttl
+        };
+        self.memory_cache
+            .insert(key, memory_ttl, serde_json::to_value(&value)?);
+        Ok(value)
+    }
+
+    /// Returns the number of requests that we believe remain
+    /// available in today's quota
+    pub fn quota_remaining(&self) -> u32 {
+        self.limiter.quota_remaining()
+    }
+
+    /// Let the requests that are made for each of num_devices
+    /// as we enumerate them at startup proceed without pacing
+    pub fn grant_startup_budget(&self, num_devices: usize) {
+        self.limiter.grant_startup_budget(num_devices);
+    }
+
+    /// Returns the number of requests that were made today
+    /// on behalf of the device with the specified id
+    pub fn device_requests_today(&self, device: &str) -> u32 {
+        self.device_requests.requests_today(device)
+    }
+
+    /// Returns the reason that the most recent request was
+    /// rejected, if the server didn't accept our API key
+    pub fn auth_failure(&self) -> Option<String> {
+        self.auth_failure.lock().clone()
     }
 
     pub async fn get_devices(&self) -> anyhow::Result<Vec<HttpDeviceInfo>> {
-        cache_get(
-            CacheGetOptions {
-                topic: "http-api",
-                key: "device-list",
-                soft_ttl: Duration::from_secs(900),
-                hard_ttl: ONE_WEEK,
-                negative_ttl: Duration::from_secs(60),
-                allow_stale: true,
-            },
+        self.cached(
+            DEVICE_LIST_CACHE_KEY,
+            Duration::from_secs(900),
+            Duration::from_secs(60),
             async {
                 let url = endpoint("/router/api/v1/user/devices");
                 let resp: GetDevicesResponse = self.get_request_with_json_response(url).await?;
-                Ok(CacheComputeResult::Value(resp.data))
+                Ok(resp.data)
             },
         )
         .await
@@ -104,6 +338,11 @@ impl GoveeApiClient {
         capability: &DeviceCapability,
         value: V,
     ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        let value: JsonValue = value.into();
+        capability
+            .validate_value(&value)
+            .with_context(|| format!("control {} of {}", capability.instance, device.device))?;
+
         let url = endpoint("/router/api/v1/device/control");
         let request = ControlDeviceRequest {
             request_id: "uuid".to_string(),
@@ -113,11 +352,12 @@ impl GoveeApiClient {
                 capability: ControlDeviceCapability {
                     kind: capability.kind.clone(),
                     instance: capability.instance.to_string(),
-                    value: value.into(),
+                    value,
                 },
             },
         };
 
+        self.device_requests.note_request(&device.device);
         let resp: ControlDeviceResponse = self
             .request_with_json_response(Method::POST, url, &request)
             .await?;
@@ -140,6 +380,7 @@ impl GoveeApiClient {
             },
         };
 
+        self.device_requests.note_request(&device.device);
         let resp: GetDeviceStateResponse = self
             .request_with_json_response(Method::POST, url, &request)
             .await?;
@@ -156,32 +397,23 @@ impl GoveeApiClient {
         }
 
         let key = format!("scene-list-diy-{}-{}", device.sku, device.device);
-        cache_get(
-            CacheGetOptions {
-                topic: "http-api",
-                key: &key,
-                soft_ttl: Duration::from_secs(300),
-                hard_ttl: ONE_WEEK,
-                negative_ttl: FIVE_MINUTES,
-                allow_stale: true,
-            },
-            async {
-                let url = endpoint("/router/api/v1/device/diy-scenes");
-                let request = GetDeviceScenesRequest {
-                    request_id: "uuid".to_string(),
-                    payload: GetDeviceScenesPayload {
-                        sku: device.sku.to_string(),
-                        device: device.device.to_string(),
-                    },
-                };
-
-                let resp: GetDeviceScenesResponse = self
-                    .request_with_json_response(Method::POST, url, &request)
-                    .await?;
-
-                Ok(CacheComputeResult::Value(resp.payload.capabilities))
-            },
-        )
+        self.cached(&key, FIVE_MINUTES, FIVE_MINUTES, async {
+            let url = endpoint("/router/api/v1/device/diy-scenes");
+            let request = GetDeviceScenesRequest {
+                request_id: "uuid".to_string(),
+                payload: GetDeviceScenesPayload {
+                    sku: device.sku.to_string(),
+                    device: device.device.to_string(),
+                },
+            };
+
+            self.device_requests.note_request(&device.device);
+            let resp: GetDeviceScenesResponse = self
+                .request_with_json_response(Method::POST, url, &request)
+                .await?;
+
+            Ok(resp.payload.capabilities)
+        })
         .await
     }
 
@@ -194,32 +426,23 @@ impl GoveeApiClient {
         }
 
         let key = format!("scene-list-{}-{}", device.sku, device.device);
-        cache_get(
-            CacheGetOptions {
-                topic: "http-api",
-                key: &key,
-                soft_ttl: Duration::from_secs(300),
-                hard_ttl: ONE_WEEK,
-                negative_ttl: FIVE_MINUTES,
-                allow_stale: true,
-            },
-            async {
-                let url = endpoint("/router/api/v1/device/scenes");
-                let request = GetDeviceScenesRequest {
-                    request_id: "uuid".to_string(),
-                    payload: GetDeviceScenesPayload {
-                        sku: device.sku.to_string(),
-                        device: device.device.to_string(),
-                    },
-                };
-
-                let resp: GetDeviceScenesResponse = self
-                    .request_with_json_response(Method::POST, url, &request)
-                    .await?;
-
-                Ok(CacheComputeResult::Value(resp.payload.capabilities))
-            },
-        )
+        self.cached(&key, FIVE_MINUTES, FIVE_MINUTES, async {
+            let url = endpoint("/router/api/v1/device/scenes");
+            let request = GetDeviceScenesRequest {
+                request_id: "uuid".to_string(),
+                payload: GetDeviceScenesPayload {
+                    sku: device.sku.to_string(),
+                    device: device.device.to_string(),
+                },
+            };
+
+            self.device_requests.note_request(&device.device);
+            let resp: GetDeviceScenesResponse = self
+                .request_with_json_response(Method::POST, url, &request)
+                .await?;
+
+            Ok(resp.payload.capabilities)
+        })
         .await
     }
 
@@ -374,22 +597,24 @@ impl GoveeApiClient {
             .capability_by_instance(instance_name)
             .ok_or_else(|| anyhow::anyhow!("device has no {instance_name}"))?;
 
-        let constraints = parse_temperature_constraints(cap)?.as_unit(TemperatureUnits::Celsius);
-
-        let min = constraints.min.as_celsius();
-        let max = constraints.max.as_celsius();
-        let celsius = target.as_celsius().max(min).min(max);
-        let clamped = celsius.max(min).min(max);
-        if clamped != celsius {
+        // Send the temperature in the units that the device
+        // declares, regardless of those in which it was requested
+        let constraints = parse_temperature_constraints(cap)?;
+        let units = constraints.min.unit();
+        let requested = target.as_unit(units);
+        let constrained = requested.constrain(&constraints.min, &constraints.max);
+        if constrained != requested {
             log::info!(
-                "set_target_temperature: constraining requested {celsius} to \
-                       {clamped} because min={min} and max={max}"
+                "set_target_temperature: constraining requested {requested} to \
+                 {constrained} because min={} and max={}",
+                constraints.min,
+                constraints.max
             );
         }
 
         let value = json!({
-            "temperature": celsius,
-            "unit": "Celsius",
+            "temperature": constrained.value().round() as i64,
+            "unit": units.scale().platform_unit(),
         });
 
         self.control_device(&device, &cap, value).await
@@ -829,6 +1054,7 @@ pub enum DeviceType {
     AromaDiffuser = "devices.types.aroma_diffuser",
     Fan = "devices.types.fan",
     Kettle = "devices.types.kettle",
+    Curtain = "devices.types.curtain",
 }
 }
 
@@ -1002,6 +1228,10 @@ impl HttpRequestFailed {
     pub fn from_err(err: &anyhow::Error) -> Option<&Self> {
         err.root_cause().downcast_ref::<Self>()
     }
+
+    pub fn status(&self) -> reqwest::StatusCode {
+        self.status
+    }
 }
 
 pub async fn json_body<T: serde::de::DeserializeOwned>(
@@ -1075,15 +1305,9 @@ impl GoveeApiClient {
         &self,
         url: T,
     ) -> anyhow::Result<R> {
-        let response = reqwest::Client::builder()
-            .timeout(Duration::from_secs(60))
-            .build()?
-            .request(Method::GET, url)
-            .header("Govee-API-Key", &self.key)
-            .send()
-            .await?;
-
-        http_response_body(response).await
+        let url = url.into_url()?;
+        self.rate_limited_request(|client| client.request(Method::GET, url.clone()))
+            .await
     }
 
     async fn request_with_json_response<
@@ -1096,16 +1320,100 @@ impl GoveeApiClient {
         url: T,
         body: &B,
     ) -> anyhow::Result<R> {
-        let response = reqwest::Client::builder()
+        let url = url.into_url()?;
+        self.rate_limited_request(|client| client.request(method.clone(), url.clone()).json(body))
+            .await
+    }
+
+    /// Issue a request, pacing it according to our rate limiter,
+    /// and retrying with backoff if the server tells us that
+    /// we have made too many requests
+    async fn rate_limited_request<R: serde::de::DeserializeOwned>(
+        &self,
+        make_request: impl Fn(&reqwest::Client) -> reqwest::RequestBuilder,
+    ) -> anyhow::Result<R> {
+        let client = reqwest::Client::builder()
             .timeout(Duration::from_secs(60))
-            .build()?
-            .request(method, url)
-            .header("Govee-API-Key", &self.key)
-            .json(body)
-            .send()
-            .await?;
+            .build()?;
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            self.limiter.acquire().await;
+
+            let response = make_request(&client)
+                .header("Govee-API-Key", &self.key)
+                .send()
+                .await?;
+
+            let retry_after = response
+                .headers()
+                .get(reqwest::header::RETRY_AFTER)
+                .and_then(|v| v.to_str().ok())
+                .and_then(parse_retry_after);
+            let remaining = ["X-RateLimit-Remaining", "API-RateLimit-Remaining"]
+                .iter()
+                .find_map(|name| response.headers().get(*name))
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.trim().parse::<u32>().ok());
+
+            let status = response.status();
+            let throttled_status = status == reqwest::StatusCode::TOO_MANY_REQUESTS;
+
+            let result = if throttled_status {
+                Err(anyhow::anyhow!(
+                    "request {} was rate limited",
+                    response.url()
+                ))
+            } else {
+                http_response_body(response).await
+            };
+
+            let rejected = |status: reqwest::StatusCode| {
+                matches!(
+                    status,
+                    reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN
+                )
+            };
+            match &result {
+                Ok(_) => {
+                    self.auth_failure.lock().take();
+                }
+                Err(err)
+                    if rejected(status)
+                        || HttpRequestFailed::from_err(err)
+                            .map(|err| rejected(err.status()))
+                            .unwrap_or(false) =>
+                {
+                    self.auth_failure.lock().replace(format!("{err:#}"));
+                }
+                Err(_) => {}
+            }
+
+            let throttled = throttled_status
+                || result
+                    .as_ref()
+                    .err()
+                    .and_then(HttpRequestFailed::from_err)
+                    .map(|err| err.status() == reqwest::StatusCode::TOO_MANY_REQUESTS)
+                    .unwrap_or(false);
+
+            if !throttled {
+                if result.is_ok() {
+                    self.limiter.note_success(remaining);
+                }
+                return result;
+            }
 
-        http_response_body(response).await
+            let delay = self.limiter.note_throttled(retry_after);
+            if attempt >= MAX_THROTTLED_ATTEMPTS {
+                return result;
+            }
+            log::warn!(
+                "Platform API is rate limiting us, will retry in {delay:?} \
+                 (attempt {attempt} of {MAX_THROTTLED_ATTEMPTS})"
+            );
+        }
     }
 }
 
@@ -1113,6 +1421,50 @@ impl GoveeApiClient {
 mod test {
     use super::*;
 
+    #[test]
+    fn device_request_counts() {
+        let counts = DeviceRequestCounts::default();
+        counts.note_request("AA:BB");
+        counts.note_request("AA:BB");
+        counts.note_request("CC:DD");
+        assert_eq!(counts.requests_today("AA:BB"), 2);
+        assert_eq!(counts.requests_today("CC:DD"), 1);
+        assert_eq!(counts.requests_today("EE:FF"), 0);
+    }
+
+    #[tokio::test]
+    async fn memory_cache() {
+        let client = GoveeApiClient::new("test").with_cache_options(PlatformCacheOptions {
+            ttl: Some(Duration::from_secs(60)),
+            disk: false,
+        });
+
+        let first: u32 = client
+            .cached("key", FIVE_MINUTES, FIVE_MINUTES, async { Ok(1) })
+            .await
+            .unwrap();
+        // The second computation is not run, as the first is cached
+        let second: u32 = client
+            .cached("key", FIVE_MINUTES, FIVE_MINUTES, async {
+                anyhow::bail!("should not be called")
+            })
+            .await
+            .unwrap();
+        assert_eq!((first, second), (1, 1));
+
+        client.clear_memory_cache();
+        let third: u32 = client
+            .cached("key", FIVE_MINUTES, FIVE_MINUTES, async { Ok(3) })
+            .await
+            .unwrap();
+        assert_eq!(third, 3);
+
+        client
+            .memory_cache
+            .insert("expired", Duration::ZERO, json!(1));
+        assert!(client.memory_cache.get("expired").is_none());
+    }
+
     const SCENE_LIST: &str = include_str!("../test-data/scenes.json");
 
     #[test]
@@ -0,0 +1,387 @@
+//! A typed view of the Platform API capability metadata, so that the
+//! values that we send can be checked against what the device declares
+//! that it accepts, rather than having Govee reject them with an
+//! unhelpful error, or worse, silently accept them.
+use crate::platform_api::{
+    DeviceCapability, DeviceCapabilityKind, DeviceParameters, EnumOption, IntegerRange,
+};
+use serde_json::Value as JsonValue;
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CapabilityModel {
+    /// on_off and toggle capabilities
+    Toggle {
+        on: JsonValue,
+        off: JsonValue,
+    },
+    /// An integer within a range, such as the brightness
+    Range {
+        min: u32,
+        max: u32,
+        unit: Option<String>,
+    },
+    /// One of a set of named values
+    Mode {
+        options: Vec<(String, JsonValue)>,
+    },
+    /// A 24-bit RGB color
+    ColorRgb,
+    ColorTemperature {
+        min: u32,
+        max: u32,
+    },
+    /// A setting that is applied to some of the segments of a device
+    Segmented {
+        segments: SegmentRange,
+        fields: Vec<(String, CapabilityModel)>,
+    },
+    /// An object whose fields are themselves typed
+    Struct {
+        fields: Vec<(String, CapabilityModel)>,
+    },
+    /// Parameters that we don't know how to check
+    Unchecked,
+}
+
+/// The segment indices that a segmented capability accepts
+#[derive(Debug, Clone, PartialEq)]
+pub enum SegmentRange {
+    Range { min: u32, max: u32 },
+    Values(Vec<u32>),
+}
+
+impl SegmentRange {
+    fn contains(&self, segment: u64) -> bool {
+        match self {
+            Self::Range { min, max } => (*min as u64..=*max as u64).contains(&segment),
+            Self::Values(values) => values.iter().any(|v| *v as u64 == segment),
+        }
+    }
+}
+
+impl std::fmt::Display for SegmentRange {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Range { min, max } => write!(fmt, "{min}-{max}"),
+            Self::Values(values) => match (values.iter().min(), values.iter().max()) {
+                (Some(min), Some(max)) => write!(fmt, "{min}-{max}"),
+                _ => write!(fmt, "none"),
+            },
+        }
+    }
+}
+
+impl CapabilityModel {
+    pub fn from_capability(cap: &DeviceCapability) -> Self {
+        match (&cap.kind, &cap.parameters) {
+            (
+                DeviceCapabilityKind::OnOff | DeviceCapabilityKind::Toggle,
+                Some(DeviceParameters::Enum { options }),
+            ) => {
+                let value = |name| {
+                    options
+                        .iter()
+                        .find(|opt| opt.name.eq_ignore_ascii_case(name))
+                        .map(|opt| opt.value.clone())
+                };
+                match (value("on"), value("off")) {
+                    (Some(on), Some(off)) => Self::Toggle { on, off },
+                    _ => Self::from_parameters(&cap.parameters),
+                }
+            }
+            (DeviceCapabilityKind::ColorSetting, Some(DeviceParameters::Integer { range, .. })) => {
+                match cap.instance.as_str() {
+                    "colorRgb" => Self::ColorRgb,
+                    "colorTemperatureK" => Self::ColorTemperature {
+                        min: range.min,
+                        max: range.max,
+                    },
+                    _ => Self::from_parameters(&cap.parameters),
+                }
+            }
+            (
+                DeviceCapabilityKind::SegmentColorSetting,
+                Some(DeviceParameters::Struct { fields }),
+            ) => {
+                let segments = fields.iter().find_map(|field| match &field.field_type {
+                    DeviceParameters::Array {
+                        element_range,
+                        options,
+                        ..
+                    } if field.field_name == "segment" => match element_range {
+                        Some(range) => Some(SegmentRange::Range {
+                            min: range.min,
+                            max: range.max,
+                        }),
+                        None => Some(SegmentRange::Values(
+                            options.iter().map(|opt| opt.value).collect(),
+                        )),
+                    },
+                    _ => None,
+                });
+                match segments {
+                    Some(segments) => Self::Segmented {
+                        segments,
+                        fields: fields
+                            .iter()
+                            .filter(|field| field.field_name != "segment")
+                            .map(|field| {
+                                (
+                                    field.field_name.to_string(),
+                                    Self::from_field(&field.field_name, &field.field_type),
+                                )
+                            })
+                            .collect(),
+                    },
+                    None => Self::from_parameters(&cap.parameters),
+                }
+            }
+            _ => Self::from_parameters(&cap.parameters),
+        }
+    }
+
+    fn from_field(name: &str, params: &DeviceParameters) -> Self {
+        match params {
+            // The rgb field of segment and music settings is
+            // described as an integer range, but it is a color
+            DeviceParameters::Integer { .. } if name == "rgb" => Self::ColorRgb,
+            _ => Self::from_parameters(&Some(params.clone())),
+        }
+    }
+
+    fn from_parameters(params: &Option<DeviceParameters>) -> Self {
+        match params {
+            Some(DeviceParameters::Integer {
+                unit,
+                range: IntegerRange { min, max, .. },
+            }) => Self::Range {
+                min: *min,
+                max: *max,
+                unit: unit.clone(),
+            },
+            // The options of scene capabilities are listed separately,
+            // and those of some work mode fields describe nested
+            // parameters rather than values, so we can only check
+            // options that all have values
+            Some(DeviceParameters::Enum { options })
+                if !options.is_empty() && options.iter().all(|opt| !opt.value.is_null()) =>
+            {
+                Self::Mode {
+                    options: options
+                        .iter()
+                        .map(|EnumOption { name, value, .. }| (name.to_string(), value.clone()))
+                        .collect(),
+                }
+            }
+            Some(DeviceParameters::Struct { fields }) => Self::Struct {
+                fields: fields
+                    .iter()
+                    .map(|field| {
+                        (
+                            field.field_name.to_string(),
+                            Self::from_field(&field.field_name, &field.field_type),
+                        )
+                    })
+                    .collect(),
+            },
+            _ => Self::Unchecked,
+        }
+    }
+
+    /// Check that value is acceptable to the capability named instance
+    pub fn validate(&self, instance: &str, value: &JsonValue) -> anyhow::Result<()> {
+        match self {
+            Self::Toggle { on, off } => {
+                anyhow::ensure!(
+                    value == on || value == off,
+                    "{instance}: {value} is neither on ({on}) nor off ({off})"
+                );
+            }
+            Self::Range { min, max, unit } => {
+                let number = as_number(instance, value)?;
+                if number < *min as f64 || number > *max as f64 {
+                    let unit = unit
+                        .as_deref()
+                        .map(|unit| format!(" {}", unit.trim_start_matches("unit.")))
+                        .unwrap_or_default();
+                    anyhow::bail!("{instance}: {value} is out of range {min}-{max}{unit}");
+                }
+            }
+            Self::Mode { options } => {
+                if !options.iter().any(|(_, option)| option == value) {
+                    let options = options
+                        .iter()
+                        .map(|(name, value)| format!("{name} ({value})"))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    anyhow::bail!("{instance}: {value} is not one of {options}");
+                }
+            }
+            Self::ColorRgb => {
+                let number = as_number(instance, value)?;
+                anyhow::ensure!(
+                    number >= 0. && number <= 0xff_ff_ff as f64 && number.fract() == 0.,
+                    "{instance}: {value} is not a 24-bit RGB color"
+                );
+            }
+            Self::ColorTemperature { min, max } => {
+                let number = as_number(instance, value)?;
+                if number < *min as f64 || number > *max as f64 {
+                    anyhow::bail!("{instance}: {value}K is out of range {min}K-{max}K");
+                }
+            }
+            Self::Segmented { segments, fields } => {
+                let segment_list = value
+                    .get("segment")
+                    .and_then(|s| s.as_array())
+                    .ok_or_else(|| anyhow::anyhow!("{instance}: {value} has no segment list"))?;
+                anyhow::ensure!(
+                    !segment_list.is_empty(),
+                    "{instance}: the segment list is empty"
+                );
+                for segment in segment_list {
+                    match segment.as_u64() {
+                        Some(n) if segments.contains(n) => {}
+                        _ => anyhow::bail!(
+                            "{instance}: segment {segment} is out of range {segments}"
+                        ),
+                    }
+                }
+                validate_fields(instance, fields, value)?;
+            }
+            Self::Struct { fields } => validate_fields(instance, fields, value)?,
+            Self::Unchecked => {}
+        }
+        Ok(())
+    }
+}
+
+fn as_number(instance: &str, value: &JsonValue) -> anyhow::Result<f64> {
+    value
+        .as_f64()
+        .ok_or_else(|| anyhow::anyhow!("{instance}: expected a number, but got {value}"))
+}
+
+/// Check the fields of value that are present. Govee marks some fields
+/// as required that it will happily accept without, so we don't
+/// insist on those.
+fn validate_fields(
+    instance: &str,
+    fields: &[(String, CapabilityModel)],
+    value: &JsonValue,
+) -> anyhow::Result<()> {
+    let object = value
+        .as_object()
+        .ok_or_else(|| anyhow::anyhow!("{instance}: expected an object, but got {value}"))?;
+    for (name, model) in fields {
+        match object.get(name) {
+            None | Some(JsonValue::Null) => {}
+            Some(field) => model.validate(&format!("{instance}.{name}"), field)?,
+        }
+    }
+    Ok(())
+}
+
+impl DeviceCapability {
+    pub fn model(&self) -> CapabilityModel {
+        CapabilityModel::from_capability(self)
+    }
+
+    /// Check that value is acceptable to this capability
+    pub fn validate_value(&self, value: &JsonValue) -> anyhow::Result<()> {
+        self.model().validate(&self.instance, value)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::{from_json, HttpDeviceInfo};
+    use serde_json::json;
+
+    #[test]
+    fn validate() {
+        let list: JsonValue =
+            serde_json::from_str(include_str!("../test-data/list_devices_2.json")).unwrap();
+        let devices: Vec<HttpDeviceInfo> = serde_json::from_value(list["data"].clone()).unwrap();
+        let cap = |instance: &str| {
+            devices
+                .iter()
+                .find_map(|d| d.capability_by_instance(instance))
+                .unwrap_or_else(|| panic!("no {instance}"))
+                .clone()
+        };
+
+        let power = cap("powerSwitch");
+        assert!(matches!(power.model(), CapabilityModel::Toggle { .. }));
+        power.validate_value(&json!(1)).unwrap();
+        assert_eq!(
+            power.validate_value(&json!(2)).unwrap_err().to_string(),
+            "powerSwitch: 2 is neither on (1) nor off (0)"
+        );
+
+        let brightness = cap("brightness");
+        brightness.validate_value(&json!(100)).unwrap();
+        assert_eq!(
+            brightness
+                .validate_value(&json!(150))
+                .unwrap_err()
+                .to_string(),
+            "brightness: 150 is out of range 1-100 percent"
+        );
+
+        let rgb = cap("colorRgb");
+        assert_eq!(rgb.model(), CapabilityModel::ColorRgb);
+        rgb.validate_value(&json!(0xff_ff_ff)).unwrap();
+        assert!(rgb.validate_value(&json!(0x1_00_00_00)).is_err());
+
+        let segmented = cap("segmentedBrightness");
+        assert!(matches!(
+            segmented.model(),
+            CapabilityModel::Segmented {
+                segments: SegmentRange::Range { min: 0, max: 14 },
+                ..
+            }
+        ));
+        segmented
+            .validate_value(&json!({"segment": [0, 14], "brightness": 50}))
+            .unwrap();
+        assert_eq!(
+            segmented
+                .validate_value(&json!({"segment": [15], "brightness": 50}))
+                .unwrap_err()
+                .to_string(),
+            "segmentedBrightness: segment 15 is out of range 0-14"
+        );
+        assert_eq!(
+            segmented
+                .validate_value(&json!({"segment": [1], "brightness": 101}))
+                .unwrap_err()
+                .to_string(),
+            "segmentedBrightness.brightness: 101 is out of range 0-100"
+        );
+
+        // The music mode rgb is optional in practice
+        let music = cap("musicMode");
+        music
+            .validate_value(&json!({"musicMode": 5, "sensitivity": 100, "autoColor": 1}))
+            .unwrap();
+        assert!(music
+            .validate_value(&json!({"musicMode": 42, "sensitivity": 100}))
+            .unwrap_err()
+            .to_string()
+            .starts_with("musicMode.musicMode: 42 is not one of"));
+    }
+
+    #[test]
+    fn work_mode_is_lenient() {
+        let cap: DeviceCapability =
+            from_json(include_str!("../test-data/work-mode-h7131.json")).unwrap();
+        // The modeValue options describe nested parameters
+        cap.validate_value(&json!({"workMode": 1, "modeValue": 3}))
+            .unwrap();
+        assert!(cap
+            .validate_value(&json!({"workMode": 2, "modeValue": 3}))
+            .is_err());
+    }
+}
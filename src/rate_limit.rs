@@ -0,0 +1,287 @@
+use chrono::{NaiveDate, Utc};
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::sync::Arc;
+use tokio::time::{Duration, Instant};
+
+/// The Govee Platform API allows 10,000 requests per account per day
+pub const PLATFORM_DAILY_QUOTA: u32 = 10_000;
+/// How many requests we allow to be issued in a burst before
+/// we start to pace them out
+const PLATFORM_BURST: f64 = 60.;
+/// How many requests we allow for each device as we enumerate them at
+/// startup, in addition to the burst: one for its state and two for
+/// its scenes
+const STARTUP_REQUESTS_PER_DEVICE: u32 = 3;
+/// The most that we allow for startup, whatever the number of devices
+const MAX_STARTUP_BUDGET: u32 = PLATFORM_DAILY_QUOTA / 10;
+/// How long the startup budget is available before it lapses
+const STARTUP_WINDOW: Duration = Duration::from_secs(600);
+/// The longest that we will back off for when we are being throttled
+/// and the server didn't tell us how long to wait
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+static PLATFORM_LIMITERS: Lazy<Mutex<HashMap<String, Arc<RateLimiter>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Returns the rate limiter associated with the specified API key.
+/// The limiter is shared by all clients using the same key, as
+/// Govee applies its quota to the account rather than the connection.
+pub fn platform_limiter_for_key(key: &str) -> Arc<RateLimiter> {
+    PLATFORM_LIMITERS
+        .lock()
+        .entry(key.to_string())
+        .or_insert_with(|| {
+            Arc::new(RateLimiter::new(
+                PLATFORM_BURST,
+                PLATFORM_DAILY_QUOTA as f64 / 86400.,
+                PLATFORM_DAILY_QUOTA,
+            ))
+        })
+        .clone()
+}
+
+#[derive(Debug)]
+struct LimiterState {
+    tokens: f64,
+    last_refill: Instant,
+    blocked_until: Option<Instant>,
+    consecutive_throttles: u32,
+    day: NaiveDate,
+    used_today: u32,
+    reported_remaining: Option<u32>,
+    /// Requests that may be made without waiting for
+    /// tokens, until startup_expires
+    startup_budget: u32,
+    startup_expires: Option<Instant>,
+}
+
+/// A token bucket that paces requests so that we stay within
+/// a daily quota, and which can be told to hold off entirely
+/// when the server indicates that we are being throttled.
+#[derive(Debug)]
+pub struct RateLimiter {
+    capacity: f64,
+    refill_per_second: f64,
+    daily_quota: u32,
+    state: Mutex<LimiterState>,
+}
+
+impl RateLimiter {
+    pub fn new(capacity: f64, refill_per_second: f64, daily_quota: u32) -> Self {
+        Self {
+            capacity,
+            refill_per_second,
+            daily_quota,
+            state: Mutex::new(LimiterState {
+                tokens: capacity,
+                last_refill: Instant::now(),
+                blocked_until: None,
+                consecutive_throttles: 0,
+                day: Utc::now().date_naive(),
+                used_today: 0,
+                reported_remaining: None,
+                startup_budget: 0,
+                startup_expires: None,
+            }),
+        }
+    }
+
+    /// Attempt to take a token. Returns None if one was taken,
+    /// otherwise returns the duration to wait before trying again.
+    fn try_take(&self, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock();
+
+        if let Some(until) = state.blocked_until {
+            if until > now {
+                return Some(until - now);
+            }
+            state.blocked_until.take();
+        }
+
+        let elapsed = now.duration_since(state.last_refill).as_secs_f64();
+        state.tokens = (state.tokens + elapsed * self.refill_per_second).min(self.capacity);
+        state.last_refill = now;
+
+        if state.startup_expires.is_some_and(|expires| expires <= now) {
+            state.startup_budget = 0;
+            state.startup_expires.take();
+        }
+
+        if state.startup_budget > 0 || state.tokens >= 1. {
+            // Spend the startup budget first, so that the
+            // burst remains available for control requests
+            if state.startup_budget > 0 {
+                state.startup_budget -= 1;
+            } else {
+                state.tokens -= 1.;
+            }
+
+            let today = Utc::now().date_naive();
+            if state.day != today {
+                state.day = today;
+                state.used_today = 0;
+                state.reported_remaining.take();
+            }
+            state.used_today += 1;
+            if let Some(remaining) = state.reported_remaining.as_mut() {
+                *remaining = remaining.saturating_sub(1);
+            }
+            None
+        } else {
+            Some(Duration::from_secs_f64(
+                (1. - state.tokens) / self.refill_per_second,
+            ))
+        }
+    }
+
+    /// Allow the requests that are made as we enumerate num_devices
+    /// devices at startup to proceed without waiting, rather than
+    /// having those beyond the burst paced out over the day.
+    /// They still count towards the daily quota.
+    pub fn grant_startup_budget(&self, num_devices: usize) {
+        let budget = u32::try_from(num_devices)
+            .unwrap_or(u32::MAX)
+            .saturating_mul(STARTUP_REQUESTS_PER_DEVICE)
+            .min(MAX_STARTUP_BUDGET);
+        let mut state = self.state.lock();
+        state.startup_budget = budget;
+        state
+            .startup_expires
+            .replace(Instant::now() + STARTUP_WINDOW);
+    }
+
+    /// Wait until we are permitted to issue a request
+    pub async fn acquire(&self) {
+        while let Some(delay) = self.try_take(Instant::now()) {
+            log::debug!("Platform API rate limit: waiting {delay:?} before next request");
+            tokio::time::sleep(delay).await;
+        }
+    }
+
+    /// Record a successful request, along with the server-reported
+    /// remaining quota, if any
+    pub fn note_success(&self, remaining: Option<u32>) {
+        let mut state = self.state.lock();
+        state.consecutive_throttles = 0;
+        if remaining.is_some() {
+            state.reported_remaining = remaining;
+        }
+    }
+
+    /// Record that the server throttled us. Returns the duration
+    /// that we will hold off for before issuing further requests.
+    pub fn note_throttled(&self, retry_after: Option<Duration>) -> Duration {
+        let mut state = self.state.lock();
+        state.consecutive_throttles += 1;
+        let delay = retry_after
+            .unwrap_or_else(|| backoff_delay(state.consecutive_throttles))
+            .min(MAX_BACKOFF);
+        state.blocked_until.replace(Instant::now() + delay);
+        // We don't know how much we have used, but we know that
+        // there isn't any more available right now
+        state.tokens = 0.;
+        delay
+    }
+
+    /// Returns the number of requests that we believe remain
+    /// available for today
+    pub fn quota_remaining(&self) -> u32 {
+        let state = self.state.lock();
+        if state.day != Utc::now().date_naive() {
+            return self.daily_quota;
+        }
+        state
+            .reported_remaining
+            .unwrap_or_else(|| self.daily_quota.saturating_sub(state.used_today))
+    }
+}
+
+/// Computes an exponential backoff delay for the given attempt number
+pub fn backoff_delay(attempt: u32) -> Duration {
+    Duration::from_secs(1u64 << attempt.min(16)).min(MAX_BACKOFF)
+}
+
+/// Parse a Retry-After header value, which may be expressed either
+/// as a number of seconds, or as an HTTP date
+pub fn parse_retry_after(value: &str) -> Option<Duration> {
+    let value = value.trim();
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+    let when = chrono::DateTime::parse_from_rfc2822(value).ok()?;
+    let delta = when.with_timezone(&Utc) - Utc::now();
+    Some(delta.to_std().unwrap_or(Duration::ZERO))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn retry_after() {
+        assert_eq!(parse_retry_after("120"), Some(Duration::from_secs(120)));
+        assert_eq!(
+            parse_retry_after("Wed, 21 Oct 2015 07:28:00 GMT"),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(parse_retry_after("soon"), None);
+    }
+
+    #[test]
+    fn token_bucket() {
+        let limiter = RateLimiter::new(2., 1., 100);
+        let now = Instant::now();
+        assert_eq!(limiter.try_take(now), None);
+        assert_eq!(limiter.try_take(now), None);
+        assert_eq!(limiter.try_take(now), Some(Duration::from_secs(1)));
+        assert_eq!(limiter.try_take(now + Duration::from_secs(1)), None);
+        assert_eq!(limiter.quota_remaining(), 97);
+
+        limiter.note_success(Some(50));
+        assert_eq!(limiter.quota_remaining(), 50);
+
+        let delay = limiter.note_throttled(None);
+        assert_eq!(delay, Duration::from_secs(2));
+        assert!(limiter.try_take(Instant::now()).is_some());
+    }
+
+    #[tokio::test]
+    async fn startup_budget() {
+        let limiter = RateLimiter::new(
+            PLATFORM_BURST,
+            PLATFORM_DAILY_QUOTA as f64 / 86400.,
+            PLATFORM_DAILY_QUOTA,
+        );
+        limiter.grant_startup_budget(100);
+
+        // Enumerating 100 devices doesn't wait for tokens
+        let startup = async {
+            for _ in 0..100 * STARTUP_REQUESTS_PER_DEVICE {
+                limiter.acquire().await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(5), startup)
+            .await
+            .expect("startup to complete without being paced");
+        assert_eq!(
+            limiter.quota_remaining(),
+            PLATFORM_DAILY_QUOTA - 100 * STARTUP_REQUESTS_PER_DEVICE
+        );
+
+        // and leaves the burst for what follows
+        let now = Instant::now();
+        for _ in 0..PLATFORM_BURST as u32 {
+            assert_eq!(limiter.try_take(now), None);
+        }
+        assert!(limiter.try_take(now).is_some());
+
+        // The budget lapses if it isn't used
+        let limiter = RateLimiter::new(1., 1., 100);
+        limiter.grant_startup_budget(100);
+        let later = Instant::now() + STARTUP_WINDOW;
+        assert_eq!(limiter.try_take(later), None);
+        assert!(limiter.try_take(later).is_some());
+    }
+}
@@ -0,0 +1,141 @@
+//! Matches the scene names supplied by users against the names
+//! of the scenes that are available for a device, so that eg:
+//! `sunrise` selects `Sunrise (2)` when that is the only variant.
+
+/// The lowest similarity for which a scene is considered
+/// to be a plausible match for the requested name
+const MIN_SIMILARITY: f64 = 0.5;
+
+/// Reduce the name to its lowercase letters and digits
+pub fn normalize_scene_name(name: &str) -> String {
+    name.chars()
+        .filter(|c| c.is_alphanumeric())
+        .flat_map(|c| c.to_lowercase())
+        .collect()
+}
+
+/// Strip the ` (N)` suffix that disambiguates scenes that share a name
+fn base_name(name: &str) -> &str {
+    if let Some(prefix) = name.strip_suffix(')') {
+        if let Some((base, n)) = prefix.rsplit_once(" (") {
+            if !n.is_empty() && n.chars().all(|c| c.is_ascii_digit()) {
+                return base;
+            }
+        }
+    }
+    name
+}
+
+/// Find the scene that matches name. In order of preference, this
+/// is an exact match, a case-insensitive match, an unambiguous match
+/// ignoring punctuation and spacing, or a match that also ignores
+/// the disambiguating suffix.
+pub fn find_scene<'a, S: AsRef<str>>(scenes: &'a [S], name: &str) -> Option<&'a str> {
+    let scenes = || scenes.iter().map(|s| s.as_ref());
+    let normalized = normalize_scene_name(name);
+
+    let unique = |mut candidates: Vec<&'a str>| {
+        candidates.dedup();
+        if candidates.len() == 1 {
+            candidates.pop()
+        } else {
+            None
+        }
+    };
+
+    scenes()
+        .find(|s| *s == name)
+        .or_else(|| scenes().find(|s| s.eq_ignore_ascii_case(name)))
+        .or_else(|| {
+            unique(
+                scenes()
+                    .filter(|s| normalize_scene_name(s) == normalized)
+                    .collect(),
+            )
+        })
+        .or_else(|| {
+            // Prefer the first variant, so that `sunrise` selects
+            // `Sunrise (1)` when there are several of them
+            let mut candidates: Vec<&str> = scenes()
+                .filter(|s| normalize_scene_name(base_name(s)) == normalized)
+                .collect();
+            candidates.sort();
+            candidates.first().copied()
+        })
+}
+
+/// Returns the scene whose name is most similar to name,
+/// for use as a suggestion when find_scene fails
+pub fn closest_scene<'a, S: AsRef<str>>(scenes: &'a [S], name: &str) -> Option<&'a str> {
+    let normalized = normalize_scene_name(name);
+    scenes
+        .iter()
+        .map(|s| {
+            let similarity =
+                strsim::normalized_levenshtein(&normalize_scene_name(s.as_ref()), &normalized);
+            (similarity, s.as_ref())
+        })
+        .filter(|(similarity, _)| *similarity >= MIN_SIMILARITY)
+        .max_by(|(a, _), (b, _)| a.total_cmp(b))
+        .map(|(_, s)| s)
+}
+
+/// Resolve name to one of the scenes. If there is no match, the
+/// closest scene is used when fuzzy is true, or otherwise suggested
+/// in the error.
+pub fn resolve_scene_name<S: AsRef<str>>(
+    scenes: &[S],
+    name: &str,
+    fuzzy: bool,
+) -> anyhow::Result<String> {
+    if let Some(scene) = find_scene(scenes, name) {
+        return Ok(scene.to_string());
+    }
+    match closest_scene(scenes, name) {
+        Some(scene) if fuzzy => {
+            log::info!("Using scene '{scene}' as the closest match for '{name}'");
+            Ok(scene.to_string())
+        }
+        Some(scene) => anyhow::bail!("Scene '{name}' not found. Did you mean '{scene}'?"),
+        None => anyhow::bail!("Scene '{name}' not found"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scene_matching() {
+        let scenes = [
+            "Aurora",
+            "Forest",
+            "Sunrise (1)",
+            "Sunrise (2)",
+            "Sunset",
+            "Rock 'n' Roll",
+        ];
+
+        assert_eq!(find_scene(&scenes, "Forest"), Some("Forest"));
+        assert_eq!(find_scene(&scenes, "aurora"), Some("Aurora"));
+        assert_eq!(find_scene(&scenes, "rock n roll"), Some("Rock 'n' Roll"));
+        assert_eq!(find_scene(&scenes, "sunrise"), Some("Sunrise (1)"));
+        assert_eq!(find_scene(&scenes, "sunrise 2"), Some("Sunrise (2)"));
+        assert_eq!(find_scene(&scenes, "Sunsett"), None);
+
+        assert_eq!(closest_scene(&scenes, "Sunsett"), Some("Sunset"));
+        assert_eq!(closest_scene(&scenes, "Volcano"), None);
+
+        assert_eq!(
+            resolve_scene_name(&scenes, "Sunsett", true).unwrap(),
+            "Sunset"
+        );
+        assert_eq!(
+            format!(
+                "{:#}",
+                resolve_scene_name(&scenes, "Sunsett", false).unwrap_err()
+            ),
+            "Scene 'Sunsett' not found. Did you mean 'Sunset'?"
+        );
+    }
+}
@@ -0,0 +1,238 @@
+//! Resolves scene names and codes to the packets that activate them
+//! via the LAN and BLE-over-IoT protocols, so that each of the ways
+//! of setting a scene agrees on which scenes exist and how they are
+//! encoded.
+use crate::ble::{Base64HexBytes, SetSceneCode};
+use crate::govee_scenes::{get_api_scenes_for_sku, get_override_scenes, ParsedScene};
+use crate::scene_match::resolve_scene_name;
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How long the merged scenes for a SKU are remembered. The scenes
+/// from the Govee API are also cached on disk, so this mostly saves
+/// re-parsing them for each scene change.
+const CACHE_TTL: Duration = Duration::from_secs(3600);
+
+/// The scenes for a SKU, and when they were fetched
+type CachedScenes = (Instant, Arc<Vec<ParsedScene>>);
+
+static SCENE_CACHE: Lazy<Mutex<HashMap<String, CachedScenes>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Forget the scenes for all SKUs, such as when the override
+/// files have changed
+pub fn invalidate_scene_cache() {
+    SCENE_CACHE.lock().clear();
+}
+
+/// Returns the scenes for sku: those from its override file, if any,
+/// along with the scenes from the Govee API that aren't overridden
+pub async fn scenes_for_sku(sku: &str) -> anyhow::Result<Arc<Vec<ParsedScene>>> {
+    if let Some((fetched, scenes)) = SCENE_CACHE.lock().get(sku) {
+        if fetched.elapsed() < CACHE_TTL {
+            return Ok(scenes.clone());
+        }
+    }
+
+    let overrides = get_override_scenes(sku)?;
+    let api = get_api_scenes_for_sku(sku).await;
+    let scenes = Arc::new(merge_scenes(sku, overrides, api)?);
+    SCENE_CACHE
+        .lock()
+        .insert(sku.to_string(), (Instant::now(), scenes.clone()));
+    Ok(scenes)
+}
+
+fn merge_scenes(
+    sku: &str,
+    overrides: Option<Vec<ParsedScene>>,
+    api: anyhow::Result<Vec<ParsedScene>>,
+) -> anyhow::Result<Vec<ParsedScene>> {
+    let Some(mut scenes) = overrides else {
+        return api;
+    };
+    match api {
+        Ok(api) => {
+            let overridden: HashSet<String> = scenes
+                .iter()
+                .map(|scene| scene.display_name.to_ascii_lowercase())
+                .collect();
+            scenes
+                .extend(api.into_iter().filter(|scene| {
+                    !overridden.contains(&scene.display_name.to_ascii_lowercase())
+                }));
+            scenes.sort_by(|a, b| a.display_name.cmp(&b.display_name));
+        }
+        Err(err) => {
+            log::warn!("Using only the override scenes for {sku}: {err:#}");
+        }
+    }
+    Ok(scenes)
+}
+
+/// Identifies the scene to be activated
+#[derive(Debug, Clone, PartialEq)]
+pub enum SceneSelector {
+    /// A scene name, which is matched tolerating differences in case
+    /// and punctuation, and, if fuzzy, partial names
+    Name { name: String, fuzzy: bool },
+    /// A numeric scene code, bypassing the scene names. When param_id
+    /// is specified, the parameters of the scene with that code and
+    /// param id are included.
+    Code { code: u16, param_id: Option<u32> },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct ResolvedScene {
+    /// The name of the scene, if it was selected by name
+    pub name: Option<String>,
+    /// The base64 encoded packets that activate the scene
+    pub commands: Vec<String>,
+}
+
+pub async fn resolve_scene(sku: &str, selector: &SceneSelector) -> anyhow::Result<ResolvedScene> {
+    match selector {
+        SceneSelector::Name { name, fuzzy } => {
+            let scenes = scenes_for_sku(sku).await?;
+            let names: Vec<&str> = scenes.iter().map(|s| s.display_name.as_str()).collect();
+            let resolved = resolve_scene_name(&names, name, *fuzzy)
+                .map_err(|err| anyhow::anyhow!("{err:#} for SKU {sku}"))?;
+            let scene = scenes
+                .iter()
+                .find(|s| s.display_name == resolved)
+                .expect("resolved name to be one of the scenes");
+            Ok(ResolvedScene {
+                name: Some(scene.display_name.to_string()),
+                commands: encode_scene(sku, scene)?,
+            })
+        }
+        SceneSelector::Code { code, param_id } => {
+            let command = scene_code_command(sku, *code, *param_id).await?;
+            Ok(ResolvedScene {
+                name: None,
+                commands: Base64HexBytes::encode_for_sku(sku, &command)?.base64(),
+            })
+        }
+    }
+}
+
+/// Build the command that activates the scene with the given code.
+/// When param_id is specified, the parameters of the scene are found
+/// in the scenes for the SKU; otherwise the code is sent without
+/// parameters.
+pub async fn scene_code_command(
+    sku: &str,
+    code: u16,
+    param_id: Option<u32>,
+) -> anyhow::Result<SetSceneCode> {
+    let param = match param_id {
+        None => String::new(),
+        Some(param_id) => scenes_for_sku(sku)
+            .await?
+            .iter()
+            .find(|s| s.scene_code == code && s.source_api_scence_param_id == param_id)
+            .map(|s| s.api_scence_param.to_string())
+            .ok_or_else(|| {
+                anyhow::anyhow!(
+                    "No scene with code {code} and param id {param_id} is known for {sku}"
+                )
+            })?,
+    };
+    Ok(SetSceneCode::new(code, param, sku.to_string()))
+}
+
+/// Returns the base64 encoded packets that activate scene
+pub fn encode_scene(sku: &str, scene: &ParsedScene) -> anyhow::Result<Vec<String>> {
+    if let Some(commands) = &scene.override_cmd_b64 {
+        return Ok(commands.clone());
+    }
+    anyhow::ensure!(
+        !scene.api_scence_param.is_empty(),
+        "Scene '{}' for SKU {sku} has neither override commands nor API parameters for encoding",
+        scene.display_name
+    );
+    let command = SetSceneCode::new(
+        scene.scene_code,
+        scene.api_scence_param.clone(),
+        sku.to_string(),
+    );
+    let commands = Base64HexBytes::encode_for_sku(sku, &command)
+        .with_context(|| format!("Encoding scene '{}' for {sku}", scene.display_name))?
+        .base64();
+    anyhow::ensure!(
+        !commands.is_empty(),
+        "Encoding scene '{}' for {sku} produced no commands",
+        scene.display_name
+    );
+    Ok(commands)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn scene(name: &str, override_cmd: bool) -> ParsedScene {
+        ParsedScene {
+            display_name: name.to_string(),
+            scene_code: 1,
+            api_scence_param: String::new(),
+            sku: "H6072".to_string(),
+            source_api_scene_name: name.to_string(),
+            source_api_effect_name: None,
+            source_api_scene_id: 0,
+            source_api_scence_param_id: 0,
+            override_cmd_b64: override_cmd.then(|| vec!["owABAQ==".to_string()]),
+            category: None,
+        }
+    }
+
+    #[test]
+    fn merge() {
+        let names = |scenes: Vec<ParsedScene>| -> Vec<(String, bool)> {
+            scenes
+                .into_iter()
+                .map(|s| (s.display_name, s.override_cmd_b64.is_some()))
+                .collect()
+        };
+
+        let api = || Ok(vec![scene("Aurora", false), scene("sunrise", false)]);
+        assert_eq!(
+            names(merge_scenes("H6072", None, api()).unwrap()),
+            vec![
+                ("Aurora".to_string(), false),
+                ("sunrise".to_string(), false)
+            ]
+        );
+        assert_eq!(
+            names(merge_scenes("H6072", Some(vec![scene("Sunrise", true)]), api()).unwrap()),
+            vec![("Aurora".to_string(), false), ("Sunrise".to_string(), true)]
+        );
+
+        // The overrides are still usable when the API is not
+        assert_eq!(
+            names(
+                merge_scenes(
+                    "H6072",
+                    Some(vec![scene("Sunrise", true)]),
+                    Err(anyhow::anyhow!("offline"))
+                )
+                .unwrap()
+            ),
+            vec![("Sunrise".to_string(), true)]
+        );
+        assert!(merge_scenes("H6072", None, Err(anyhow::anyhow!("offline"))).is_err());
+    }
+
+    #[test]
+    fn encode() {
+        assert_eq!(
+            encode_scene("H6072", &scene("Sunrise", true)).unwrap(),
+            vec!["owABAQ==".to_string()]
+        );
+        assert!(encode_scene("H6072", &scene("Aurora", false)).is_err());
+    }
+}
@@ -0,0 +1,236 @@
+//! Keeps a local copy of the scene override files that are
+//! published in a remote repository, so that community decodings
+//! of scenes are picked up automatically.
+//!
+//! The synced files are kept in a versioned directory beneath the
+//! cache directory, with a `current` symlink pointing to the most
+//! recent good set. Replacing the symlink is atomic, so scene
+//! lookups never see a partially synced set of files.
+use crate::govee_scenes::{
+    invalidate_scene_overrides, set_remote_scene_override_dir, validate_override_data,
+};
+use anyhow::Context;
+use reqwest::Url;
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// One entry from the listing of the remote files. This is either
+/// a file name relative to the listing, or an object in the form
+/// returned by the GitHub contents API.
+#[derive(Deserialize, Debug)]
+#[serde(untagged)]
+enum ListingEntry {
+    Name(String),
+    Object {
+        name: String,
+        #[serde(default)]
+        download_url: Option<String>,
+        #[serde(default, rename = "type")]
+        kind: Option<String>,
+    },
+}
+
+pub struct SceneSyncOptions {
+    /// Returns the listing of the override files
+    pub url: String,
+    pub interval: Duration,
+}
+
+fn sync_root() -> PathBuf {
+    crate::cache::cache_dir().join("govee2mqtt-scene-overrides")
+}
+
+/// Only plain json file names are accepted, so that the remote
+/// cannot cause files to be written outside of the sync directory
+fn is_acceptable_name(name: &str) -> bool {
+    !name.contains(['/', '\\']) && !name.starts_with('.') && name.to_lowercase().ends_with(".json")
+}
+
+/// Resolve the listing into the names and urls of the override files
+fn parse_listing(base: &Url, entries: Vec<ListingEntry>) -> Vec<(String, Url)> {
+    let mut result = vec![];
+    for entry in entries {
+        let (name, url) = match entry {
+            ListingEntry::Name(name) => (name.clone(), base.join(&name)),
+            ListingEntry::Object {
+                name,
+                download_url,
+                kind,
+            } => {
+                if kind.as_deref().is_some_and(|kind| kind != "file") {
+                    continue;
+                }
+                let url = match &download_url {
+                    Some(url) => Url::parse(url),
+                    None => base.join(&name),
+                };
+                (name, url)
+            }
+        };
+        if !is_acceptable_name(&name) {
+            log::trace!("scene sync: ignoring {name}");
+            continue;
+        }
+        match url {
+            Ok(url) => result.push((name, url)),
+            Err(err) => log::warn!("scene sync: {name}: {err:#}"),
+        }
+    }
+    result
+}
+
+async fn fetch(client: &reqwest::Client, url: Url) -> anyhow::Result<Vec<u8>> {
+    let response = client
+        .get(url.clone())
+        .header("User-Agent", "govee2mqtt")
+        .send()
+        .await?
+        .error_for_status()
+        .with_context(|| format!("fetching {url}"))?;
+    Ok(response.bytes().await?.to_vec())
+}
+
+/// Fetch the override files, skipping any that fail validation
+async fn fetch_overrides(url: &str) -> anyhow::Result<BTreeMap<String, Vec<u8>>> {
+    let base = Url::parse(url).with_context(|| format!("parsing scene sync url {url}"))?;
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()?;
+
+    let listing: Vec<ListingEntry> = serde_json::from_slice(&fetch(&client, base.clone()).await?)
+        .with_context(|| format!("parsing the listing from {url}"))?;
+
+    let mut files = BTreeMap::new();
+    for (name, file_url) in parse_listing(&base, listing) {
+        let data = match fetch(&client, file_url).await {
+            Ok(data) => data,
+            Err(err) => {
+                log::warn!("scene sync: {err:#}");
+                continue;
+            }
+        };
+        match validate_override_data(&data) {
+            Ok(count) => {
+                log::trace!("scene sync: {name} has {count} scenes");
+                files.insert(name, data);
+            }
+            Err(err) => log::warn!("scene sync: {name} is not a valid override file: {err:#}"),
+        }
+    }
+    Ok(files)
+}
+
+fn read_files(dir: &Path) -> BTreeMap<String, Vec<u8>> {
+    let mut files = BTreeMap::new();
+    if let Ok(entries) = std::fs::read_dir(dir) {
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().to_string();
+            if let Ok(data) = std::fs::read(entry.path()) {
+                files.insert(name, data);
+            }
+        }
+    }
+    files
+}
+
+/// Write the files to a new version directory and make it current,
+/// then remove the versions that are no longer current
+fn install(root: &Path, files: &BTreeMap<String, Vec<u8>>) -> anyhow::Result<()> {
+    let version = uuid::Uuid::new_v4().simple().to_string();
+    let version_dir = root.join(&version);
+    std::fs::create_dir_all(&version_dir).with_context(|| format!("creating {version_dir:?}"))?;
+    for (name, data) in files {
+        let path = version_dir.join(name);
+        std::fs::write(&path, data).with_context(|| format!("writing {path:?}"))?;
+    }
+
+    let link = root.join(format!("current.{version}"));
+    std::os::unix::fs::symlink(&version, &link)
+        .with_context(|| format!("creating symlink {link:?}"))?;
+    std::fs::rename(&link, root.join("current"))
+        .with_context(|| format!("renaming {link:?} to current"))?;
+
+    for entry in std::fs::read_dir(root)?.flatten() {
+        let name = entry.file_name();
+        if name != "current" && name.to_string_lossy() != version {
+            let path = entry.path();
+            if path.is_dir() && !path.is_symlink() {
+                std::fs::remove_dir_all(&path).ok();
+            } else {
+                std::fs::remove_file(&path).ok();
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Sync the override files from url, returning true if they changed
+pub async fn sync_scene_overrides(url: &str) -> anyhow::Result<bool> {
+    let files = fetch_overrides(url).await?;
+    if files.is_empty() {
+        anyhow::bail!("{url} has no valid override files");
+    }
+
+    let root = sync_root();
+    if read_files(&root.join("current")) == files {
+        return Ok(false);
+    }
+
+    install(&root, &files)?;
+    invalidate_scene_overrides();
+    log::info!("Synced {} scene override file(s) from {url}", files.len());
+    Ok(true)
+}
+
+/// Periodically sync the override files. The previously synced
+/// files remain in use if the remote cannot be reached.
+pub async fn run_scene_sync(options: SceneSyncOptions) {
+    set_remote_scene_override_dir(Some(sync_root().join("current")));
+    loop {
+        if let Err(err) = sync_scene_overrides(&options.url).await {
+            log::warn!("Unable to sync scene overrides: {err:#}");
+        }
+        tokio::time::sleep(options.interval).await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn scene_sync_listing() {
+        let base = Url::parse("https://example.com/scenes/index.json").unwrap();
+        let listing: Vec<ListingEntry> = serde_json::from_str(
+            r#"[
+                "H6072.json",
+                "../escape.json",
+                {"name": "H6159.json", "type": "file",
+                 "download_url": "https://raw.example.com/H6159.json"},
+                {"name": "subdir", "type": "dir"},
+                {"name": "README.md", "type": "file"}
+            ]"#,
+        )
+        .unwrap();
+
+        let names: Vec<(String, String)> = parse_listing(&base, listing)
+            .into_iter()
+            .map(|(name, url)| (name, url.to_string()))
+            .collect();
+        assert_eq!(
+            names,
+            vec![
+                (
+                    "H6072.json".to_string(),
+                    "https://example.com/scenes/H6072.json".to_string()
+                ),
+                (
+                    "H6159.json".to_string(),
+                    "https://raw.example.com/H6159.json".to_string()
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,343 @@
+//! Encrypted storage for the Govee account credentials and the login
+//! token that is obtained with them. The secrets are held in a single
+//! file that is encrypted with AES-256-GCM, using a key derived from
+//! a passphrase, so that they don't need to be passed via the
+//! environment, and so that the login token survives a restart
+//! without being written to the cache in the clear.
+use crate::opt_env_var;
+use anyhow::Context;
+use once_cell::sync::OnceCell;
+use openssl::hash::MessageDigest;
+use openssl::symm::{decrypt_aead, encrypt_aead, Cipher};
+use parking_lot::Mutex;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+/// The names under which the Govee account credentials are stored
+pub const EMAIL_SECRET: &str = "govee_email";
+pub const PASSWORD_SECRET: &str = "govee_password";
+
+const FORMAT_VERSION: u32 = 1;
+const PBKDF2_ITERATIONS: usize = 100_000;
+const SALT_LEN: usize = 16;
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+
+static SECRETS: OnceCell<SecretStore> = OnceCell::new();
+
+/// Returns the secret store, if one was configured via
+/// --secrets-file and --secrets-passphrase
+pub fn secret_store() -> Option<&'static SecretStore> {
+    SECRETS.get()
+}
+
+#[derive(clap::Parser, Debug)]
+pub struct SecretsArguments {
+    /// A file in which to store the Govee login token, and optionally
+    /// the account credentials, encrypted with --secrets-passphrase.
+    /// You may also set this via the GOVEE_SECRETS_FILE environment
+    /// variable.
+    #[arg(long, global = true)]
+    pub secrets_file: Option<PathBuf>,
+
+    /// The passphrase that is used to encrypt the secrets file.
+    /// You may also set this via the GOVEE_SECRETS_PASSPHRASE
+    /// environment variable, or set GOVEE_SECRETS_PASSPHRASE_FILE
+    /// to the path of a file that contains it.
+    #[arg(long, global = true)]
+    pub secrets_passphrase: Option<String>,
+}
+
+impl SecretsArguments {
+    pub fn opt_secrets_file(&self) -> anyhow::Result<Option<PathBuf>> {
+        match &self.secrets_file {
+            Some(path) => Ok(Some(path.clone())),
+            None => opt_env_var("GOVEE_SECRETS_FILE"),
+        }
+    }
+
+    pub fn opt_passphrase(&self) -> anyhow::Result<Option<String>> {
+        if let Some(passphrase) = &self.secrets_passphrase {
+            return Ok(Some(passphrase.to_string()));
+        }
+        if let Some(passphrase) = opt_env_var("GOVEE_SECRETS_PASSPHRASE")? {
+            return Ok(Some(passphrase));
+        }
+        match opt_env_var::<PathBuf>("GOVEE_SECRETS_PASSPHRASE_FILE")? {
+            Some(path) => {
+                let passphrase = std::fs::read_to_string(&path)
+                    .with_context(|| format!("reading secrets passphrase from {path:?}"))?;
+                Ok(Some(passphrase.trim_end_matches(['\r', '\n']).to_string()))
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// Make the secret store available, if it is configured
+    pub async fn load(&self) -> anyhow::Result<()> {
+        let Some(path) = self.opt_secrets_file()? else {
+            return Ok(());
+        };
+        let passphrase = self.opt_passphrase()?.ok_or_else(|| {
+            anyhow::anyhow!(
+                "A passphrase is required to use the secrets file {path:?}. \
+                 Please specify it either via the --secrets-passphrase parameter \
+                 or by setting $GOVEE_SECRETS_PASSPHRASE"
+            )
+        })?;
+        anyhow::ensure!(
+            !passphrase.is_empty(),
+            "the secrets passphrase must not be empty"
+        );
+
+        // Fails now, rather than when we first need a secret,
+        // if the passphrase doesn't match the file
+        let store = SecretStore::open(path, passphrase).await?;
+        SECRETS
+            .set(store)
+            .map_err(|_| anyhow::anyhow!("the secret store has already been configured"))
+    }
+}
+
+/// The on-disk representation of the secrets file
+#[derive(Deserialize, Serialize, Debug)]
+struct SecretsFile {
+    version: u32,
+    salt: String,
+    nonce: String,
+    tag: String,
+    data: String,
+}
+
+/// The secrets are decrypted when the store is opened and kept in
+/// memory, so that reading them requires neither file IO nor
+/// deriving the key, which is deliberately slow. Changes are
+/// written back to the file from a blocking task.
+pub struct SecretStore {
+    path: PathBuf,
+    /// The salt from which key was derived, which is kept
+    /// for the life of the file
+    salt: [u8; SALT_LEN],
+    key: [u8; 32],
+    secrets: Mutex<BTreeMap<String, JsonValue>>,
+    /// Serializes writes of the file
+    write_lock: tokio::sync::Mutex<()>,
+}
+
+fn derive_key(passphrase: &str, salt: &[u8]) -> anyhow::Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    openssl::pkcs5::pbkdf2_hmac(
+        passphrase.as_bytes(),
+        salt,
+        PBKDF2_ITERATIONS,
+        MessageDigest::sha256(),
+        &mut key,
+    )?;
+    Ok(key)
+}
+
+impl SecretStore {
+    /// Open the secrets file at path, decrypting it with passphrase.
+    /// The file is created when a secret is first stored.
+    pub async fn open<P: Into<PathBuf>, S: Into<String>>(
+        path: P,
+        passphrase: S,
+    ) -> anyhow::Result<Self> {
+        let path = path.into();
+        let passphrase = passphrase.into();
+        tokio::task::spawn_blocking(move || Self::open_blocking(path, &passphrase)).await?
+    }
+
+    fn open_blocking(path: PathBuf, passphrase: &str) -> anyhow::Result<Self> {
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {
+                let mut salt = [0u8; SALT_LEN];
+                openssl::rand::rand_bytes(&mut salt)?;
+                let key = derive_key(passphrase, &salt)?;
+                return Ok(Self::new(path, salt, key, BTreeMap::new()));
+            }
+            Err(err) => return Err(err).with_context(|| format!("reading secrets from {path:?}")),
+        };
+
+        let file: SecretsFile = serde_json::from_slice(&data)
+            .with_context(|| format!("parsing secrets file {path:?}"))?;
+        anyhow::ensure!(
+            file.version == FORMAT_VERSION,
+            "secrets file {path:?} has unsupported version {}",
+            file.version
+        );
+
+        let salt: [u8; SALT_LEN] = hex::decode(&file.salt)?
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("secrets file {path:?} has an invalid salt"))?;
+        let nonce = hex::decode(&file.nonce)?;
+        let tag = hex::decode(&file.tag)?;
+        let data = hex::decode(&file.data)?;
+        let key = derive_key(passphrase, &salt)?;
+
+        let plain = decrypt_aead(Cipher::aes_256_gcm(), &key, Some(&nonce), &[], &data, &tag)
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "unable to decrypt secrets file {path:?}; is the passphrase correct?"
+                )
+            })?;
+        let secrets = serde_json::from_slice(&plain)?;
+        Ok(Self::new(path, salt, key, secrets))
+    }
+
+    fn new(
+        path: PathBuf,
+        salt: [u8; SALT_LEN],
+        key: [u8; 32],
+        secrets: BTreeMap<String, JsonValue>,
+    ) -> Self {
+        Self {
+            path,
+            salt,
+            key,
+            secrets: Mutex::new(secrets),
+            write_lock: tokio::sync::Mutex::new(()),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn get<T: DeserializeOwned>(&self, key: &str) -> anyhow::Result<Option<T>> {
+        match self.secrets.lock().get(key) {
+            Some(value) => Ok(Some(serde_json::from_value(value.clone())?)),
+            None => Ok(None),
+        }
+    }
+
+    pub async fn set<T: Serialize>(&self, key: &str, value: &T) -> anyhow::Result<()> {
+        let value = serde_json::to_value(value)?;
+        self.update(|secrets| {
+            secrets.insert(key.to_string(), value);
+            true
+        })
+        .await
+    }
+
+    pub async fn remove(&self, key: &str) -> anyhow::Result<()> {
+        self.update(|secrets| secrets.remove(key).is_some()).await
+    }
+
+    pub async fn clear(&self) -> anyhow::Result<()> {
+        self.update(|secrets| {
+            secrets.clear();
+            true
+        })
+        .await
+    }
+
+    /// Apply a change to the secrets, and save them if
+    /// apply returns true to indicate that they changed
+    async fn update<F>(&self, apply: F) -> anyhow::Result<()>
+    where
+        F: FnOnce(&mut BTreeMap<String, JsonValue>) -> bool,
+    {
+        let _guard = self.write_lock.lock().await;
+        let plain = {
+            let mut secrets = self.secrets.lock();
+            if !apply(&mut secrets) {
+                return Ok(());
+            }
+            serde_json::to_vec(&*secrets)?
+        };
+
+        let path = self.path.clone();
+        let salt = self.salt;
+        let key = self.key;
+        tokio::task::spawn_blocking(move || save(&path, &salt, &key, &plain)).await?
+    }
+}
+
+fn save(path: &Path, salt: &[u8], key: &[u8], plain: &[u8]) -> anyhow::Result<()> {
+    // The key was derived once, so each save uses a new nonce
+    // with it, rather than a new salt
+    let mut nonce = [0u8; NONCE_LEN];
+    openssl::rand::rand_bytes(&mut nonce)?;
+
+    let mut tag = [0u8; TAG_LEN];
+    let data = encrypt_aead(
+        Cipher::aes_256_gcm(),
+        key,
+        Some(&nonce),
+        &[],
+        plain,
+        &mut tag,
+    )?;
+
+    let file = SecretsFile {
+        version: FORMAT_VERSION,
+        salt: hex::encode(salt),
+        nonce: hex::encode(nonce),
+        tag: hex::encode(tag),
+        data: hex::encode(data),
+    };
+
+    // Write to a temporary file that only we can read, and then
+    // move it into place, so that the file is never left truncated
+    let temp = path.with_extension("tmp");
+    {
+        use std::io::Write;
+        let mut options = std::fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::OpenOptionsExt;
+            options.mode(0o600);
+        }
+        let mut f = options
+            .open(&temp)
+            .with_context(|| format!("creating {temp:?}"))?;
+        f.write_all(&serde_json::to_vec_pretty(&file)?)?;
+        f.sync_all()?;
+    }
+    std::fs::rename(&temp, path).with_context(|| format!("renaming {temp:?} -> {path:?}"))?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn round_trip() {
+        let path = std::env::temp_dir().join(format!(
+            "govee-secrets-{}.json",
+            uuid::Uuid::new_v4().simple()
+        ));
+
+        let store = SecretStore::open(&path, "hunter2").await.unwrap();
+        assert_eq!(store.get::<String>("email").unwrap(), None);
+        store.set("email", &"me@example.com").await.unwrap();
+        assert_eq!(
+            store.get::<String>("email").unwrap().as_deref(),
+            Some("me@example.com")
+        );
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert!(!contents.contains("example.com"));
+
+        let reopened = SecretStore::open(&path, "hunter2").await.unwrap();
+        assert_eq!(
+            reopened.get::<String>("email").unwrap().as_deref(),
+            Some("me@example.com")
+        );
+        assert!(SecretStore::open(&path, "hunter3").await.is_err());
+
+        store.remove("email").await.unwrap();
+        assert_eq!(store.get::<String>("email").unwrap(), None);
+        let reopened = SecretStore::open(&path, "hunter2").await.unwrap();
+        assert_eq!(reopened.get::<String>("email").unwrap(), None);
+
+        std::fs::remove_file(&path).ok();
+    }
+}
@@ -0,0 +1,243 @@
+//! Keeps the set of devices in step with the Govee account, so that
+//! devices that are added to, or removed from, the account appear in,
+//! or disappear from, hass without restarting.
+use crate::service::device::Device;
+use crate::service::events::DeviceEvent;
+use crate::service::scene_attributes::scene_attributes_topic;
+use crate::service::state::{State as ServiceState, StateHandle};
+use std::collections::HashSet;
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+
+/// How often the device lists are fetched from Govee. The Platform
+/// API device list is cached for 15 minutes by default, so checking
+/// more frequently than this wouldn't notice changes any sooner.
+const ACCOUNT_SYNC_INTERVAL: Duration = Duration::from_secs(15 * 60);
+
+/// The ids of the devices in each of the account device lists.
+/// A list that we couldn't fetch is None, so that we don't
+/// mistake a failed request for the removal of every device.
+#[derive(Default, Debug)]
+pub struct AccountDeviceIds {
+    pub platform: Option<HashSet<String>>,
+    pub undoc: Option<HashSet<String>>,
+}
+
+/// How a known device relates to the account device lists
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccountChange {
+    Unchanged,
+    /// The device is no longer in one or both of the lists, but is
+    /// still known via the other list, the LAN or BLE
+    Detached {
+        platform: bool,
+        undoc: bool,
+    },
+    /// The device is no longer in the account, and we don't know
+    /// of it via any other means
+    Removed,
+}
+
+impl AccountDeviceIds {
+    pub fn change_for(&self, device: &Device) -> AccountChange {
+        let missing = |ids: &Option<HashSet<String>>, known: bool| {
+            known && ids.as_ref().is_some_and(|ids| !ids.contains(&device.id))
+        };
+        let platform = missing(&self.platform, device.http_device_info.is_some());
+        let undoc = missing(&self.undoc, device.undoc_device_info.is_some());
+        if !platform && !undoc {
+            return AccountChange::Unchanged;
+        }
+
+        let retained = (device.http_device_info.is_some() && !platform)
+            || (device.undoc_device_info.is_some() && !undoc)
+            || device.lan_device.is_some()
+            || device.ble_sensor_reading.is_some();
+        if retained {
+            AccountChange::Detached { platform, undoc }
+        } else {
+            AccountChange::Removed
+        }
+    }
+}
+
+impl ServiceState {
+    /// Fetch the device lists from the Govee account, and add or
+    /// remove devices to match. Devices whose room changed are
+    /// re-registered with hass.
+    pub async fn sync_account_devices(self: &Arc<Self>) -> anyhow::Result<()> {
+        let known: HashSet<String> = self
+            .devices()
+            .await
+            .into_iter()
+            .map(|device| device.id)
+            .collect();
+        let mut ids = AccountDeviceIds::default();
+        let mut changed = vec![];
+
+        if let Some(client) = self.get_platform_client().await {
+            match client.get_devices().await {
+                Ok(list) => {
+                    let mut platform = HashSet::new();
+                    for info in list {
+                        platform.insert(info.device.to_string());
+                        let mut device = self.device_mut(&info.sku, &info.device).await;
+                        device.set_http_device_info(info);
+                    }
+                    ids.platform.replace(platform);
+                }
+                Err(err) => log::warn!("Unable to fetch the Platform API device list: {err:#}"),
+            }
+        }
+
+        if let Some(undoc) = self.get_undoc_client().await {
+            let result = async {
+                let acct = undoc.login_account_cached().await?;
+                undoc.get_device_list(&acct.token).await
+            }
+            .await;
+            match result {
+                Ok(info) => {
+                    ids.undoc.replace(
+                        info.devices
+                            .iter()
+                            .map(|entry| entry.device.to_string())
+                            .collect(),
+                    );
+                    changed = self.apply_undoc_device_list(info).await;
+                }
+                Err(err) => log::warn!("Unable to fetch the undoc API device list: {err:#}"),
+            }
+        }
+
+        for device in self.devices().await {
+            if !known.contains(&device.id) {
+                log::info!("{device} was added to the Govee account");
+                self.emit_event(DeviceEvent::DeviceAdded {
+                    device_id: device.id.clone(),
+                });
+                continue;
+            }
+            match ids.change_for(&device) {
+                AccountChange::Unchanged => {}
+                AccountChange::Detached { platform, undoc } => {
+                    log::info!(
+                        "{device} is no longer listed by the Govee account \
+                         (platform={platform} undoc={undoc}), but is still reachable"
+                    );
+                    {
+                        let mut device = self.device_mut(&device.sku, &device.id).await;
+                        if platform {
+                            device.http_device_info.take();
+                        }
+                        if undoc {
+                            device.undoc_device_info.take();
+                        }
+                    }
+                    changed.push(device.id.clone());
+                }
+                AccountChange::Removed => {
+                    log::info!("{device} was removed from the Govee account");
+                    self.forget_device(&device.id).await;
+                }
+            }
+        }
+
+        for id in changed {
+            self.emit_event(DeviceEvent::DeviceAdded { device_id: id });
+        }
+        Ok(())
+    }
+
+    /// Stop anything that we are running on behalf of the device,
+    /// and remove it, along with its entities in hass
+    async fn forget_device(&self, device_id: &str) {
+        self.stop_local_effect(device_id).await;
+        self.cancel_ramp(device_id).await;
+        self.cancel_scene_preview(device_id).await;
+        if let Some(timer) = self.take_off_timer_task(device_id).await {
+            timer.abort();
+        }
+        if let (Some(hass), Some(device)) = (
+            self.get_hass_client().await,
+            self.device_by_id(device_id).await,
+        ) {
+            // The scene metadata is retained, so remove it explicitly
+            if let Err(err) = hass.clear_retained(scene_attributes_topic(&device)).await {
+                log::warn!("Unable to remove the scenes of {device}: {err:#}");
+            }
+        }
+        if self.remove_device(device_id).await.is_some() {
+            self.emit_event(DeviceEvent::DeviceRemoved {
+                device_id: device_id.to_string(),
+            });
+        }
+    }
+}
+
+/// Periodically fetch the devices and rooms from the Govee account
+pub async fn run_account_sync(state: StateHandle) {
+    loop {
+        sleep(ACCOUNT_SYNC_INTERVAL).await;
+        if let Err(err) = state.sync_account_devices().await {
+            log::warn!("Unable to sync devices with the Govee account: {err:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::ble_advert::SensorReading;
+    use crate::platform_api::HttpDeviceInfo;
+
+    #[test]
+    fn change_for() {
+        let mut device = Device::new("H6000", "AA:BB");
+        device.set_http_device_info(HttpDeviceInfo {
+            sku: "H6000".to_string(),
+            device: "AA:BB".to_string(),
+            device_name: "Lamp".to_string(),
+            device_type: Default::default(),
+            capabilities: vec![],
+        });
+        let listed = || Some(HashSet::from(["AA:BB".to_string()]));
+
+        let ids = AccountDeviceIds {
+            platform: listed(),
+            undoc: listed(),
+        };
+        assert_eq!(ids.change_for(&device), AccountChange::Unchanged);
+
+        // A list that we failed to fetch doesn't remove anything
+        let ids = AccountDeviceIds {
+            platform: None,
+            undoc: Some(HashSet::new()),
+        };
+        assert_eq!(ids.change_for(&device), AccountChange::Unchanged);
+
+        let ids = AccountDeviceIds {
+            platform: Some(HashSet::new()),
+            undoc: None,
+        };
+        assert_eq!(ids.change_for(&device), AccountChange::Removed);
+
+        // A device that is also known via BLE is retained
+        device.ble_sensor_reading = Some(SensorReading {
+            temperature_celsius: 20.0,
+            humidity_percent: 50.0,
+            battery_percent: 100,
+        });
+        assert_eq!(
+            ids.change_for(&device),
+            AccountChange::Detached {
+                platform: true,
+                undoc: false
+            }
+        );
+
+        // as is one that was never in the account
+        let local = Device::new("H6000", "CC:DD");
+        assert_eq!(ids.change_for(&local), AccountChange::Unchanged);
+    }
+}
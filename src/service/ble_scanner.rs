@@ -0,0 +1,419 @@
+//! Passive scanning for the BLE advertisements broadcast by Govee
+//! thermo-hygrometers, so that their readings can be made available
+//! without the Govee gateway or cloud, and by Govee lights, so that
+//! we can tell when their power has been cut.
+//! We talk directly to the kernel HCI layer via a raw socket, which
+//! requires CAP_NET_RAW and CAP_NET_ADMIN (or running as root).
+#[cfg(target_os = "linux")]
+use crate::ble_advert::{decode_govee_device, decode_govee_sensor, parse_le_advertising_report};
+use crate::ble_advert::{GoveeDeviceAdvert, GoveeSensorAdvert};
+use crate::service::events::DeviceEvent;
+use crate::service::state::StateHandle;
+use crate::service::watchdog::Heartbeat;
+use chrono::{DateTime, Utc};
+use std::sync::atomic::{AtomicI64, Ordering};
+use tokio::sync::mpsc::Receiver;
+#[cfg(target_os = "linux")]
+use tokio::sync::mpsc::{channel, Sender};
+
+/// How long a light that was advertising may be silent before we
+/// consider it to be unpowered. Powered lights advertise several
+/// times per second.
+pub const BLE_SILENCE_WINDOW: chrono::Duration = chrono::Duration::seconds(60);
+
+/// How often to record that a device is still advertising, or that a
+/// sensor is still reporting the same reading. This needs to be well
+/// within BLE_SILENCE_WINDOW.
+const BLE_ADVERT_RECORD_INTERVAL: chrono::Duration = chrono::Duration::seconds(15);
+
+/// The unix time at which the scanner most recently received any
+/// advertisement, from any device, or 0 if it never has
+static LAST_ADVERT_HEARD: AtomicI64 = AtomicI64::new(0);
+
+/// The time at which the scanner most recently received any
+/// advertisement, which tells us that it is still working
+pub fn last_advert_heard() -> Option<DateTime<Utc>> {
+    match LAST_ADVERT_HEARD.load(Ordering::Relaxed) {
+        0 => None,
+        secs => DateTime::from_timestamp(secs, 0),
+    }
+}
+
+enum ScannedAdvert {
+    Sensor(GoveeSensorAdvert),
+    Device(GoveeDeviceAdvert),
+}
+
+/// Spawn the scanner for the specified adapter index (the N in hciN)
+/// and feed the decoded readings into the state
+pub async fn run_ble_scanner(
+    state: StateHandle,
+    adapter: u16,
+    heartbeat: Heartbeat,
+) -> anyhow::Result<()> {
+    let mut rx = spawn_hci_scanner(adapter, heartbeat)?;
+    log::info!("Passively scanning for BLE sensors on hci{adapter}");
+
+    while let Some(advert) = rx.recv().await {
+        let result = match advert {
+            ScannedAdvert::Sensor(advert) => handle_advert(&state, advert).await,
+            ScannedAdvert::Device(advert) => handle_device_advert(&state, advert).await,
+        };
+        if let Err(err) = result {
+            log::error!("ble scanner: {err:#}");
+        }
+    }
+
+    anyhow::bail!("BLE scanner on hci{adapter} stopped");
+}
+
+async fn handle_advert(state: &StateHandle, advert: GoveeSensorAdvert) -> anyhow::Result<()> {
+    let existing = state.device_by_ble_address(&advert.address).await;
+    if let Some(device) = &existing {
+        // Sensors repeat the same reading many times over, so only
+        // record it when it changes, or every so often otherwise
+        let unchanged = device.ble_sensor_reading == Some(advert.reading);
+        let recorded_recently = device
+            .last_ble_sensor_reading_update
+            .is_some_and(|update| Utc::now() - update < BLE_ADVERT_RECORD_INTERVAL);
+        if unchanged && recorded_recently {
+            return Ok(());
+        }
+    }
+    let is_new = existing.is_none();
+    let id = existing
+        .map(|d| d.id)
+        .unwrap_or_else(|| advert.address.clone());
+
+    let changed = state
+        .device_mut(&advert.model, &id)
+        .await
+        .set_ble_sensor_reading(advert.reading);
+
+    if is_new {
+        log::info!(
+            "ble scanner: discovered {} {} {}",
+            advert.name,
+            advert.model,
+            advert.address
+        );
+        state.emit_event(DeviceEvent::DeviceAdded { device_id: id });
+    } else if changed {
+        log::trace!("ble scanner: {id} {:?}", advert.reading);
+        state.emit_state_changed(&id);
+    }
+
+    Ok(())
+}
+
+/// Record that a known device is advertising, which means that it is
+/// powered. Adverts from devices that we don't otherwise know about
+/// are ignored, as we have no means to control them.
+async fn handle_device_advert(
+    state: &StateHandle,
+    advert: GoveeDeviceAdvert,
+) -> anyhow::Result<()> {
+    let Some(device) = state.device_by_ble_address(&advert.address).await else {
+        return Ok(());
+    };
+    if device
+        .last_ble_advert
+        .is_some_and(|last| Utc::now() - last < BLE_ADVERT_RECORD_INTERVAL)
+    {
+        return Ok(());
+    }
+
+    let was_silent = {
+        let mut device = state.device_mut(&device.sku, &device.id).await;
+        let was_silent = !device.is_ble_advertising(BLE_SILENCE_WINDOW);
+        device.last_ble_advert.replace(Utc::now());
+        was_silent
+    };
+
+    if was_silent {
+        log::debug!("ble scanner: {device} ({}) is advertising", advert.model);
+        if let Some(device) = state.device_by_id(&device.id).await {
+            state.publish_device_availability(&device, false).await?;
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn spawn_hci_scanner(
+    _adapter: u16,
+    _heartbeat: Heartbeat,
+) -> anyhow::Result<Receiver<ScannedAdvert>> {
+    anyhow::bail!("BLE scanning is only supported on Linux");
+}
+
+#[cfg(target_os = "linux")]
+fn spawn_hci_scanner(
+    adapter: u16,
+    heartbeat: Heartbeat,
+) -> anyhow::Result<Receiver<ScannedAdvert>> {
+    let socket = hci::HciSocket::open(adapter)?;
+    socket.start_passive_scan()?;
+
+    let (tx, rx) = channel(32);
+    std::thread::Builder::new()
+        .name(format!("hci{adapter}-scanner"))
+        .spawn(move || read_events(socket, tx, heartbeat))?;
+    Ok(rx)
+}
+
+#[cfg(target_os = "linux")]
+/// Beats the heartbeat for each advertisement. Should the receiver
+/// stop keeping up, blocking_send blocks and so does the heartbeat.
+/// Returns, closing the socket, once the receiver is dropped, such
+/// as when the watchdog restarts the scanner.
+fn read_events(socket: hci::HciSocket, tx: Sender<ScannedAdvert>, heartbeat: Heartbeat) {
+    let mut buf = [0u8; 260];
+    loop {
+        if tx.is_closed() {
+            return;
+        }
+        let event = match socket.read(&mut buf) {
+            Ok(event) => event,
+            Err(err)
+                if matches!(
+                    err.kind(),
+                    std::io::ErrorKind::WouldBlock
+                        | std::io::ErrorKind::TimedOut
+                        | std::io::ErrorKind::Interrupted
+                ) =>
+            {
+                continue;
+            }
+            Err(err) => {
+                log::error!("ble scanner: read failed: {err:#}");
+                return;
+            }
+        };
+
+        let [hci::HCI_EVENT_PKT, hci::EVT_LE_META_EVENT, _plen, hci::EVT_LE_ADVERTISING_REPORT, report @ ..] =
+            event
+        else {
+            continue;
+        };
+
+        LAST_ADVERT_HEARD.store(Utc::now().timestamp(), Ordering::Relaxed);
+        heartbeat.beat();
+
+        for advert in parse_le_advertising_report(report) {
+            let decoded = match decode_govee_sensor(&advert) {
+                Some(sensor) => ScannedAdvert::Sensor(sensor),
+                None => match decode_govee_device(&advert) {
+                    Some(device) => ScannedAdvert::Device(device),
+                    None => continue,
+                },
+            };
+            if tx.blocking_send(decoded).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod hci {
+    use anyhow::Context;
+    use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+    use std::time::Duration;
+
+    const BTPROTO_HCI: libc::c_int = 1;
+    const HCI_CHANNEL_RAW: u16 = 0;
+    const SOL_HCI: libc::c_int = 0;
+    const HCI_FILTER: libc::c_int = 2;
+
+    const HCI_COMMAND_PKT: u8 = 0x01;
+    pub const HCI_EVENT_PKT: u8 = 0x04;
+    pub const EVT_LE_META_EVENT: u8 = 0x3e;
+    pub const EVT_LE_ADVERTISING_REPORT: u8 = 0x02;
+
+    /// How long a read may block, so that the reader can notice
+    /// when it is no longer wanted even if nothing is advertising
+    const READ_TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// OGF_LE_CTL << 10 | OCF_LE_SET_SCAN_PARAMETERS
+    const LE_SET_SCAN_PARAMETERS: u16 = 0x200b;
+    /// OGF_LE_CTL << 10 | OCF_LE_SET_SCAN_ENABLE
+    const LE_SET_SCAN_ENABLE: u16 = 0x200c;
+
+    #[repr(C)]
+    struct SockAddrHci {
+        hci_family: libc::sa_family_t,
+        hci_dev: u16,
+        hci_channel: u16,
+    }
+
+    #[repr(C)]
+    struct HciFilter {
+        type_mask: u32,
+        event_mask: [u32; 2],
+        opcode: u16,
+    }
+
+    pub struct HciSocket {
+        fd: OwnedFd,
+    }
+
+    impl HciSocket {
+        pub fn open(adapter: u16) -> anyhow::Result<Self> {
+            let fd = unsafe {
+                libc::socket(
+                    libc::AF_BLUETOOTH,
+                    libc::SOCK_RAW | libc::SOCK_CLOEXEC,
+                    BTPROTO_HCI,
+                )
+            };
+            if fd < 0 {
+                return Err(std::io::Error::last_os_error()).context("opening HCI socket");
+            }
+            let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+
+            let addr = SockAddrHci {
+                hci_family: libc::AF_BLUETOOTH as libc::sa_family_t,
+                hci_dev: adapter,
+                hci_channel: HCI_CHANNEL_RAW,
+            };
+            let res = unsafe {
+                libc::bind(
+                    fd.as_raw_fd(),
+                    &addr as *const SockAddrHci as *const libc::sockaddr,
+                    std::mem::size_of::<SockAddrHci>() as libc::socklen_t,
+                )
+            };
+            if res < 0 {
+                return Err(std::io::Error::last_os_error())
+                    .with_context(|| format!("binding to hci{adapter}"));
+            }
+
+            // We're only interested in LE meta events
+            let mut filter = HciFilter {
+                type_mask: 1 << HCI_EVENT_PKT,
+                event_mask: [0; 2],
+                opcode: 0,
+            };
+            filter.event_mask[(EVT_LE_META_EVENT >> 5) as usize] |= 1 << (EVT_LE_META_EVENT & 31);
+            let res = unsafe {
+                libc::setsockopt(
+                    fd.as_raw_fd(),
+                    SOL_HCI,
+                    HCI_FILTER,
+                    &filter as *const HciFilter as *const libc::c_void,
+                    std::mem::size_of::<HciFilter>() as libc::socklen_t,
+                )
+            };
+            if res < 0 {
+                return Err(std::io::Error::last_os_error()).context("setting HCI filter");
+            }
+
+            let timeout = libc::timeval {
+                tv_sec: READ_TIMEOUT.as_secs() as libc::time_t,
+                tv_usec: READ_TIMEOUT.subsec_micros() as libc::suseconds_t,
+            };
+            let res = unsafe {
+                libc::setsockopt(
+                    fd.as_raw_fd(),
+                    libc::SOL_SOCKET,
+                    libc::SO_RCVTIMEO,
+                    &timeout as *const libc::timeval as *const libc::c_void,
+                    std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+                )
+            };
+            if res < 0 {
+                return Err(std::io::Error::last_os_error()).context("setting HCI read timeout");
+            }
+
+            Ok(Self { fd })
+        }
+
+        fn send_command(&self, opcode: u16, params: &[u8]) -> anyhow::Result<()> {
+            let mut packet = vec![HCI_COMMAND_PKT];
+            packet.extend_from_slice(&opcode.to_le_bytes());
+            packet.push(params.len() as u8);
+            packet.extend_from_slice(params);
+
+            let res = unsafe {
+                libc::write(
+                    self.fd.as_raw_fd(),
+                    packet.as_ptr() as *const libc::c_void,
+                    packet.len(),
+                )
+            };
+            if res < 0 {
+                return Err(std::io::Error::last_os_error())
+                    .with_context(|| format!("sending HCI command {opcode:04x}"));
+            }
+            Ok(())
+        }
+
+        pub fn start_passive_scan(&self) -> anyhow::Result<()> {
+            // Ensure that we can change the parameters if
+            // something else left a scan running
+            self.send_command(LE_SET_SCAN_ENABLE, &[0x00, 0x00])?;
+            self.send_command(
+                LE_SET_SCAN_PARAMETERS,
+                &[
+                    0x00, // passive
+                    0x10, 0x00, // interval: 10ms
+                    0x10, 0x00, // window: 10ms
+                    0x00, // own address: public
+                    0x00, // accept all advertisements
+                ],
+            )?;
+            // enable, but don't filter duplicates, as the sensors
+            // repeat the same advertisement with updated readings
+            self.send_command(LE_SET_SCAN_ENABLE, &[0x01, 0x00])
+        }
+
+        /// Fails with WouldBlock should nothing arrive within READ_TIMEOUT
+        pub fn read<'a>(&self, buf: &'a mut [u8]) -> std::io::Result<&'a [u8]> {
+            let len = unsafe {
+                libc::read(
+                    self.fd.as_raw_fd(),
+                    buf.as_mut_ptr() as *mut libc::c_void,
+                    buf.len(),
+                )
+            };
+            if len < 0 {
+                return Err(std::io::Error::last_os_error());
+            }
+            Ok(&buf[..len as usize])
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn device_adverts() {
+        let state = StateHandle::default();
+        drop(state.device_mut("H6072", "1A:2B:A4:C1:38:00:11:22").await);
+        let advert = GoveeDeviceAdvert {
+            address: "A4:C1:38:00:11:22".to_string(),
+            model: "H6072".to_string(),
+        };
+
+        handle_device_advert(&state, advert.clone()).await.unwrap();
+        let device = state.device_by_id("1A:2B:A4:C1:38:00:11:22").await.unwrap();
+        let first = device.last_ble_advert.expect("advert to be recorded");
+
+        // Adverts that follow soon after are not recorded again
+        handle_device_advert(&state, advert).await.unwrap();
+        let device = state.device_by_id("1A:2B:A4:C1:38:00:11:22").await.unwrap();
+        assert_eq!(device.last_ble_advert, Some(first));
+
+        // nor are adverts from devices that we don't know about
+        let unknown = GoveeDeviceAdvert {
+            address: "A4:C1:38:00:11:23".to_string(),
+            model: "H6072".to_string(),
+        };
+        handle_device_advert(&state, unknown).await.unwrap();
+        assert_eq!(state.devices().await.len(), 1);
+    }
+}
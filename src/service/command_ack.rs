@@ -0,0 +1,117 @@
+//! Reports the outcome of each command that is received via MQTT,
+//! so that automations can detect commands that failed rather than
+//! firing and forgetting. A result is published to the per-device
+//! `gv2mqtt/{id}/result` topic, or to `gv2mqtt/result` for commands
+//! that don't target a device.
+use crate::service::device::Device;
+use crate::service::hass::{base_topic, device_topic_id, topic_layout};
+use crate::service::state::State as ServiceState;
+use chrono::{DateTime, Utc};
+use mosquitto_rs::router::RouterError;
+use mosquitto_rs::Message;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CommandStatus {
+    Ack,
+    Err,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CommandResult {
+    correlation_id: String,
+    topic: String,
+    status: CommandStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    timestamp: DateTime<Utc>,
+}
+
+/// Commands with a JSON object payload may carry a `correlation_id`
+/// which is echoed back in the result. Otherwise a new id is
+/// generated, which is still useful for matching up log entries.
+fn correlation_id(payload: &[u8]) -> String {
+    let id = serde_json::from_slice::<JsonValue>(payload)
+        .ok()
+        .and_then(|value| match value.get("correlation_id")? {
+            JsonValue::String(s) => Some(s.to_string()),
+            JsonValue::Number(n) => Some(n.to_string()),
+            _ => None,
+        });
+    id.unwrap_or_else(|| uuid::Uuid::new_v4().simple().to_string())
+}
+
+pub fn command_result_topic(device: Option<&Device>) -> String {
+    match device {
+        Some(device) => format!("{}/{id}/result", base_topic(), id = device_topic_id(device)),
+        None => format!("{}/result", base_topic()),
+    }
+}
+
+impl ServiceState {
+    /// Find the device that is addressed by a command topic
+    async fn device_for_command_topic(&self, relative: &str) -> Option<Device> {
+        for segment in relative.split('/') {
+            if let Some(device) = self.resolve_device(segment).await {
+                return Some(device);
+            }
+        }
+        None
+    }
+
+    /// Publishes the outcome of dispatching msg
+    pub async fn publish_command_result(&self, msg: &Message, result: &Result<(), RouterError>) {
+        // Neither the hass status topic nor messages that don't
+        // match a route are commands
+        let Some(relative) = topic_layout().relative_topic(&msg.topic) else {
+            return;
+        };
+        if matches!(result, Err(RouterError::MatchError(_))) {
+            return;
+        }
+        let Some(hass) = self.get_hass_client().await else {
+            return;
+        };
+
+        let device = self.device_for_command_topic(relative).await;
+        let (status, error) = match result {
+            Ok(()) => (CommandStatus::Ack, None),
+            Err(err) => (CommandStatus::Err, Some(format!("{err:#}"))),
+        };
+        let report = CommandResult {
+            correlation_id: correlation_id(&msg.payload),
+            topic: msg.topic.to_string(),
+            status,
+            error,
+            timestamp: Utc::now(),
+        };
+
+        if let Err(err) = hass
+            .publish_obj(command_result_topic(device.as_ref()), report)
+            .await
+        {
+            log::warn!(
+                "Failed to publish command result for {}: {err:#}",
+                msg.topic
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn command_correlation_id() {
+        assert_eq!(
+            correlation_id(br#"{"state":"ON","correlation_id":"abc"}"#),
+            "abc"
+        );
+        assert_eq!(correlation_id(br#"{"correlation_id":42}"#), "42");
+        assert_eq!(correlation_id(b"ON").len(), 32);
+        assert_ne!(correlation_id(b"ON"), correlation_id(b"ON"));
+    }
+}
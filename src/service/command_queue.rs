@@ -0,0 +1,246 @@
+use crate::lan_api::LanTimeout;
+use crate::platform_api::HttpRequestFailed;
+use crate::service::device::Device;
+use crate::service::hass::errors_topic;
+use crate::service::state::State;
+use chrono::Utc;
+use serde_json::{json, Value as JsonValue};
+use std::future::Future;
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+
+/// How many times we'll try a command before giving up on it
+const MAX_ATTEMPTS: u32 = 3;
+/// The delay before the first retry; doubles for each subsequent attempt
+const INITIAL_RETRY_DELAY: Duration = Duration::from_millis(500);
+
+/// Returns true if the error looks like it is the result of
+/// a temporary condition, such as a timeout or a lost connection,
+/// such that trying the same thing again has a reasonable chance
+/// of success.
+pub fn is_transient(err: &anyhow::Error) -> bool {
+    for cause in err.chain() {
+        if cause.downcast_ref::<LanTimeout>().is_some()
+            || cause
+                .downcast_ref::<tokio::time::error::Elapsed>()
+                .is_some()
+        {
+            return true;
+        }
+        if let Some(err) = cause.downcast_ref::<mosquitto_rs::Error>() {
+            if is_transient_mqtt_error(err) {
+                return true;
+            }
+        }
+        if let Some(err) = cause.downcast_ref::<reqwest::Error>() {
+            if err.is_timeout() || err.is_connect() {
+                return true;
+            }
+        }
+        if let Some(err) = cause.downcast_ref::<HttpRequestFailed>() {
+            if err.status().is_server_error() {
+                return true;
+            }
+        }
+        if let Some(err) = cause.downcast_ref::<std::io::Error>() {
+            if is_transient_io_error(err) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+fn is_transient_io_error(err: &std::io::Error) -> bool {
+    use std::io::ErrorKind;
+    matches!(
+        err.kind(),
+        ErrorKind::TimedOut
+            | ErrorKind::ConnectionRefused
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::NotConnected
+            | ErrorKind::WouldBlock
+    )
+}
+
+/// Only a lost or not yet established connection to the broker is
+/// worth retrying; other errors, such as a payload that is too large
+/// or a topic that we aren't allowed to publish to, will recur.
+fn is_transient_mqtt_error(err: &mosquitto_rs::Error) -> bool {
+    use libmosquitto_sys::mosq_err_t;
+    use mosquitto_rs::Error;
+    match err {
+        Error::Mosq(code) => matches!(
+            code,
+            mosq_err_t::MOSQ_ERR_NO_CONN
+                | mosq_err_t::MOSQ_ERR_CONN_LOST
+                | mosq_err_t::MOSQ_ERR_CONN_PENDING
+                | mosq_err_t::MOSQ_ERR_KEEPALIVE
+        ),
+        Error::IO(err) => is_transient_io_error(err),
+        _ => false,
+    }
+}
+
+fn retry_delay(attempt: u32) -> Duration {
+    INITIAL_RETRY_DELAY * 2u32.pow(attempt.saturating_sub(1))
+}
+
+impl State {
+    /// Run a control command for a device.
+    /// Commands for a given device are queued behind the device's
+    /// control semaphore (see `resolve_device_for_control`), so they
+    /// run in the order that they were received.
+    /// In optimistic mode, the expected effect of the command is
+    /// reported before it is sent, and discarded if it fails.
+    /// Transient failures are retried with backoff.  If the command
+    /// cannot be completed, the details are published to the
+    /// `gv2mqtt/errors` topic to aid in debugging.
+    /// In dry-run mode, commands are not sent to the device;
+    /// instead their effect on its state is simulated.
+    pub async fn run_device_command<T, F, Fut>(
+        self: &Arc<Self>,
+        device: &Device,
+        command: &str,
+        payload: JsonValue,
+        func: F,
+    ) -> anyhow::Result<T>
+    where
+        T: Default,
+        F: Fn() -> Fut,
+        Fut: Future<Output = anyhow::Result<T>>,
+    {
+        // An explicit command takes precedence over any effect
+        // that we might be rendering on the device
+        self.stop_local_effect(&device.id).await;
+
+        if self.simulate_if_dry_run(device, command, &payload).await? {
+            return Ok(T::default());
+        }
+
+        let optimistic = self
+            .apply_optimistic_command(device, command, &payload)
+            .await;
+
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match (func)().await {
+                Ok(result) => {
+                    if optimistic {
+                        self.settle_optimistic_command(device).await;
+                    }
+                    return Ok(result);
+                }
+                Err(err) if attempt < MAX_ATTEMPTS && is_transient(&err) => {
+                    let delay = retry_delay(attempt);
+                    log::warn!(
+                        "{command} for {device} failed: {err:#}. \
+                         Will retry in {delay:?} (attempt {attempt} of {MAX_ATTEMPTS})"
+                    );
+                    sleep(delay).await;
+                }
+                Err(err) => {
+                    if optimistic {
+                        self.discard_optimistic_state(device, None).await;
+                    }
+                    self.publish_dead_letter(device, command, payload, attempt, &err)
+                        .await;
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    /// If we are in dry-run mode, log the command rather than sending
+    /// it, and update the simulated state of the device accordingly.
+    /// Returns true if the command should not be sent.
+    pub async fn simulate_if_dry_run(
+        self: &Arc<Self>,
+        device: &Device,
+        command: &str,
+        payload: &JsonValue,
+    ) -> anyhow::Result<bool> {
+        if !self.is_dry_run().await {
+            return Ok(false);
+        }
+
+        log::info!("DRY RUN: not sending {command} {payload} to {device}");
+        self.device_mut(&device.sku, &device.id)
+            .await
+            .apply_simulated_command(command, payload);
+        self.emit_state_changed(&device.id);
+        Ok(true)
+    }
+
+    async fn publish_dead_letter(
+        &self,
+        device: &Device,
+        command: &str,
+        payload: JsonValue,
+        attempts: u32,
+        err: &anyhow::Error,
+    ) {
+        let Some(hass) = self.get_hass_client().await else {
+            return;
+        };
+
+        let report = json!({
+            "device": device.id,
+            "sku": device.sku,
+            "name": device.name(),
+            "command": command,
+            "payload": payload,
+            "attempts": attempts,
+            "transient": is_transient(err),
+            "error": format!("{err:#}"),
+            "timestamp": Utc::now(),
+        });
+
+        if let Err(err) = hass.publish_obj(errors_topic(), report).await {
+            log::error!("Failed to publish {command} failure for {device}: {err:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn transient_errors() {
+        assert!(is_transient(&LanTimeout::Status.into()));
+        assert!(is_transient(
+            &anyhow::Error::from(LanTimeout::Response).context("setting brightness")
+        ));
+        assert!(is_transient(&anyhow::Error::from(std::io::Error::from(
+            std::io::ErrorKind::TimedOut
+        ))));
+        assert!(!is_transient(&anyhow::anyhow!("device not found")));
+
+        use libmosquitto_sys::mosq_err_t;
+        use mosquitto_rs::Error as MqttError;
+        assert!(is_transient(&anyhow::Error::from(MqttError::Mosq(
+            mosq_err_t::MOSQ_ERR_CONN_LOST
+        ))));
+        assert!(is_transient(&anyhow::Error::from(MqttError::IO(
+            std::io::Error::from(std::io::ErrorKind::ConnectionReset)
+        ))));
+        assert!(!is_transient(&anyhow::Error::from(MqttError::Mosq(
+            mosq_err_t::MOSQ_ERR_PAYLOAD_SIZE
+        ))));
+        assert!(!is_transient(&anyhow::Error::from(MqttError::Mosq(
+            mosq_err_t::MOSQ_ERR_ACL_DENIED
+        ))));
+        assert!(!is_transient(&anyhow::Error::from(MqttError::Resolution(
+            "no such host".to_string()
+        ))));
+    }
+
+    #[test]
+    fn backoff() {
+        assert_eq!(retry_delay(1), Duration::from_millis(500));
+        assert_eq!(retry_delay(2), Duration::from_millis(1000));
+    }
+}
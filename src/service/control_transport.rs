@@ -0,0 +1,377 @@
+//! Each of the means of controlling a device implements ControlTransport,
+//! so that a command can be dispatched to whichever of them is able to
+//! reach the device, in the order configured for the command.
+use crate::lan_api::DeviceColor;
+use crate::service::device::Device;
+use crate::service::state::{State as ServiceState, StateHandle};
+use crate::service::transport::Transport;
+use async_trait::async_trait;
+
+/// A command that can be sent via more than one transport
+#[derive(Clone, Debug, PartialEq)]
+pub enum TransportCommand {
+    Power(bool),
+    /// Toggle just the light of a device that has other functions.
+    /// instance is the Platform API capability that controls it.
+    LightPower {
+        on: bool,
+        instance: String,
+    },
+    Brightness(u8),
+    ColorTemperature(u32),
+    ColorRgb(DeviceColor),
+    Scene(String),
+    /// Base64 encoded packets, sent as they are
+    Raw(Vec<String>),
+}
+
+impl TransportCommand {
+    /// The command type used to configure the transport order
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Power(_) => "power",
+            Self::LightPower { .. } => "light_power",
+            Self::Brightness(_) => "brightness",
+            Self::ColorTemperature(_) => "color_temperature",
+            Self::ColorRgb(_) => "color_rgb",
+            Self::Scene(_) => "scene",
+            Self::Raw(_) => "raw",
+        }
+    }
+
+    fn describe(&self) -> String {
+        match self {
+            Self::Power(_) => "power state".to_string(),
+            Self::LightPower { .. } => "light power state".to_string(),
+            Self::Brightness(_) => "brightness".to_string(),
+            Self::ColorTemperature(_) => "color temperature".to_string(),
+            Self::ColorRgb(_) => "color".to_string(),
+            Self::Scene(scene) => format!("scene '{scene}'"),
+            Self::Raw(commands) => format!("raw command {commands:?}"),
+        }
+    }
+
+    /// Whether a failure should be followed by trying the next
+    /// transport, rather than being reported. Not every transport
+    /// knows how to encode every scene, so scenes fall back.
+    fn falls_back_on_error(&self) -> bool {
+        matches!(self, Self::Scene(_))
+    }
+}
+
+#[async_trait]
+pub trait ControlTransport: Send + Sync {
+    fn transport(&self) -> Transport;
+
+    /// Send command to device. Returns Ok(false) if the device
+    /// cannot be reached, or the command cannot be expressed,
+    /// via this transport.
+    async fn send(
+        &self,
+        state: &StateHandle,
+        device: &Device,
+        command: &TransportCommand,
+    ) -> anyhow::Result<bool>;
+}
+
+pub struct LanTransport;
+
+#[async_trait]
+impl ControlTransport for LanTransport {
+    fn transport(&self) -> Transport {
+        Transport::Lan
+    }
+
+    async fn send(
+        &self,
+        state: &StateHandle,
+        device: &Device,
+        command: &TransportCommand,
+    ) -> anyhow::Result<bool> {
+        if let TransportCommand::Scene(scene) = command {
+            return state.try_set_scene_via_lan(device, scene).await;
+        }
+        let Some(lan_dev) = &device.lan_device else {
+            return Ok(false);
+        };
+        log::info!("Using LAN API to set {device} {}", command.describe());
+        match command {
+            TransportCommand::Power(on) | TransportCommand::LightPower { on, .. } => {
+                let on = *on;
+                lan_dev.send_turn(on).await?;
+                state
+                    .poll_lan_api(lan_dev, |status| status.on == on)
+                    .await?;
+            }
+            TransportCommand::Brightness(percent) => {
+                let percent = *percent;
+                lan_dev.send_brightness(percent).await?;
+                state
+                    .poll_lan_api(lan_dev, |status| status.brightness == percent)
+                    .await?;
+            }
+            TransportCommand::ColorTemperature(kelvin) => {
+                let kelvin = *kelvin;
+                lan_dev.send_color_temperature_kelvin(kelvin).await?;
+                state
+                    .poll_lan_api(lan_dev, |status| status.color_temperature_kelvin == kelvin)
+                    .await?;
+                clear_active_scene(state, device).await;
+            }
+            TransportCommand::ColorRgb(color) => {
+                let color = *color;
+                lan_dev.send_color_rgb(color).await?;
+                state
+                    .poll_lan_api(lan_dev, |status| status.color == color)
+                    .await?;
+                clear_active_scene(state, device).await;
+            }
+            TransportCommand::Raw(commands) => {
+                lan_dev.send_real(commands.clone()).await?;
+            }
+            TransportCommand::Scene(_) => unreachable!(),
+        }
+        Ok(true)
+    }
+}
+
+pub struct IotTransport;
+
+#[async_trait]
+impl ControlTransport for IotTransport {
+    fn transport(&self) -> Transport {
+        Transport::Iot
+    }
+
+    async fn send(
+        &self,
+        state: &StateHandle,
+        device: &Device,
+        command: &TransportCommand,
+    ) -> anyhow::Result<bool> {
+        if let TransportCommand::Scene(scene) = command {
+            return state.try_set_scene_via_iot(device, scene).await;
+        }
+        let Some((iot, info)) = state.iot_for_device(device).await else {
+            return Ok(false);
+        };
+        log::info!("Using IoT API to set {device} {}", command.describe());
+        match command {
+            TransportCommand::Power(on) | TransportCommand::LightPower { on, .. } => {
+                iot.set_power_state(&info.entry, *on).await?
+            }
+            TransportCommand::Brightness(percent) => {
+                iot.set_brightness(&info.entry, *percent).await?
+            }
+            TransportCommand::ColorTemperature(kelvin) => {
+                iot.set_color_temperature(&info.entry, *kelvin).await?
+            }
+            TransportCommand::ColorRgb(DeviceColor { r, g, b }) => {
+                iot.set_color_rgb(&info.entry, *r, *g, *b).await?
+            }
+            TransportCommand::Raw(commands) => {
+                state
+                    .iot_send_real(&iot, &info.entry, commands.clone())
+                    .await?;
+            }
+            TransportCommand::Scene(_) => unreachable!(),
+        }
+        Ok(true)
+    }
+}
+
+pub struct PlatformTransport;
+
+#[async_trait]
+impl ControlTransport for PlatformTransport {
+    fn transport(&self) -> Transport {
+        Transport::Platform
+    }
+
+    async fn send(
+        &self,
+        state: &StateHandle,
+        device: &Device,
+        command: &TransportCommand,
+    ) -> anyhow::Result<bool> {
+        match command {
+            TransportCommand::Scene(scene) => {
+                return state.try_set_scene_via_platform(device, scene).await;
+            }
+            // The Platform API has no means of sending packets
+            TransportCommand::Raw(_) => return Ok(false),
+            _ => {}
+        }
+        let Some((client, info)) = state.platform_for_device(device).await else {
+            return Ok(false);
+        };
+        log::info!("Using Platform API to set {device} {}", command.describe());
+        match command {
+            TransportCommand::Power(on) => {
+                client.set_power_state(info, *on).await?;
+            }
+            TransportCommand::LightPower { on, instance } => {
+                client.set_toggle_state(info, instance, *on).await?;
+            }
+            TransportCommand::Brightness(percent) => {
+                client.set_brightness(info, *percent).await?;
+            }
+            TransportCommand::ColorTemperature(kelvin) => {
+                client.set_color_temperature(info, *kelvin).await?;
+                clear_active_scene(state, device).await;
+            }
+            TransportCommand::ColorRgb(DeviceColor { r, g, b }) => {
+                client.set_color_rgb(info, *r, *g, *b).await?;
+                clear_active_scene(state, device).await;
+            }
+            TransportCommand::Scene(_) | TransportCommand::Raw(_) => unreachable!(),
+        }
+        Ok(true)
+    }
+}
+
+/// A color set via LAN or the Platform API replaces the scene,
+/// but the device won't tell us so
+async fn clear_active_scene(state: &StateHandle, device: &Device) {
+    state
+        .device_mut(&device.sku, &device.id)
+        .await
+        .set_active_scene(None);
+}
+
+pub fn control_transport(transport: Transport) -> &'static dyn ControlTransport {
+    match transport {
+        Transport::Lan => &LanTransport,
+        Transport::Iot => &IotTransport,
+        Transport::Platform => &PlatformTransport,
+    }
+}
+
+/// Send command via the first of transports that is able to
+/// handle it, returning the transport that was used
+pub async fn dispatch(
+    transports: &[&dyn ControlTransport],
+    state: &StateHandle,
+    device: &Device,
+    command: &TransportCommand,
+) -> anyhow::Result<Transport> {
+    for transport in transports {
+        match transport.send(state, device, command).await {
+            Ok(true) => return Ok(transport.transport()),
+            Ok(false) => {}
+            Err(err) if command.falls_back_on_error() => {
+                log::warn!(
+                    "{:?} failed to set {} for {device}: {err:#}. Trying other methods.",
+                    transport.transport(),
+                    command.describe()
+                );
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    anyhow::bail!(
+        "Unable to set {} for {device} using any available method",
+        command.describe()
+    );
+}
+
+impl ServiceState {
+    /// Send command to device via the configured transport order
+    pub async fn send_transport_command(
+        self: &StateHandle,
+        device: &Device,
+        command: TransportCommand,
+    ) -> anyhow::Result<Transport> {
+        let transports: Vec<&dyn ControlTransport> = self
+            .transport_order(device, command.name())
+            .await
+            .into_iter()
+            .map(control_transport)
+            .collect();
+        dispatch(&transports, self, device, &command).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    struct FakeTransport {
+        transport: Transport,
+        result: fn() -> anyhow::Result<bool>,
+    }
+
+    #[async_trait]
+    impl ControlTransport for FakeTransport {
+        fn transport(&self) -> Transport {
+            self.transport
+        }
+
+        async fn send(
+            &self,
+            _state: &StateHandle,
+            _device: &Device,
+            _command: &TransportCommand,
+        ) -> anyhow::Result<bool> {
+            (self.result)()
+        }
+    }
+
+    #[tokio::test]
+    async fn fallback() {
+        let state = Arc::new(ServiceState::new());
+        let device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:00:01");
+        let unreachable = FakeTransport {
+            transport: Transport::Lan,
+            result: || Ok(false),
+        };
+        let failing = FakeTransport {
+            transport: Transport::Iot,
+            result: || anyhow::bail!("timed out"),
+        };
+        let working = FakeTransport {
+            transport: Transport::Platform,
+            result: || Ok(true),
+        };
+
+        // Transports that can't reach the device are skipped
+        let used = dispatch(
+            &[&unreachable, &working, &failing],
+            &state,
+            &device,
+            &TransportCommand::Power(true),
+        )
+        .await
+        .unwrap();
+        assert_eq!(used, Transport::Platform);
+
+        // A failure is reported, except for scenes
+        assert!(dispatch(
+            &[&failing, &working],
+            &state,
+            &device,
+            &TransportCommand::Brightness(50),
+        )
+        .await
+        .is_err());
+        let used = dispatch(
+            &[&failing, &working],
+            &state,
+            &device,
+            &TransportCommand::Scene("Sunrise".to_string()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(used, Transport::Platform);
+
+        assert!(dispatch(
+            &[&unreachable],
+            &state,
+            &device,
+            &TransportCommand::Power(false)
+        )
+        .await
+        .is_err());
+    }
+}
@@ -1,4 +1,5 @@
 use crate::service::device::Device;
+use std::sync::Arc;
 use tokio::sync::oneshot::Sender as OneShotSender;
 use tokio::sync::OwnedSemaphorePermit;
 
@@ -22,6 +23,11 @@ pub struct Coordinator {
     // resources and/or trigger follow up work in other tasks.
     #[allow(unused)]
     permit: OwnedSemaphorePermit,
+    /// The State holds a weak reference to this so that it can
+    /// tell whether we are still in flight when control of the
+    /// device is force released
+    #[allow(unused)]
+    holder: Arc<()>,
     #[allow(unused)]
     trigger_poll: OneShotSender<()>,
 }
@@ -30,11 +36,13 @@ impl Coordinator {
     pub fn new(
         device: Device,
         permit: OwnedSemaphorePermit,
+        holder: Arc<()>,
         trigger_poll: OneShotSender<()>,
     ) -> Self {
         Self {
             device,
             permit,
+            holder,
             trigger_poll,
         }
     }
@@ -0,0 +1,185 @@
+//! Publishes a last-gasp crash report to MQTT when we panic, so that
+//! hass automations can raise an alert when the bridge dies.
+use crate::service::hass::{base_topic, HassClient};
+use crate::version_info::govee_version;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use std::any::Any;
+use std::time::Duration;
+
+/// How long to wait for the crash report to be published
+const CRASH_PUBLISH_TIMEOUT: Duration = Duration::from_secs(2);
+/// How many of our frames of the backtrace to include
+const MAX_BACKTRACE_FRAMES: usize = 16;
+/// The name of the thread that publishes the report
+const REPORTER_THREAD: &str = "crash-reporter";
+
+/// The client via which crash reports are published
+static CRASH_CLIENT: Lazy<parking_lot::Mutex<Option<HassClient>>> =
+    Lazy::new(|| parking_lot::Mutex::new(None));
+
+/// Published to `gv2mqtt/service/crash` when we panic
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct CrashReport {
+    pub message: String,
+    /// Where the panic occurred, as `file:line:column`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub location: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub thread: Option<String>,
+    /// The frames of the backtrace that are in govee2mqtt itself,
+    /// innermost first
+    pub backtrace: Vec<String>,
+    pub version: String,
+    pub timestamp: DateTime<Utc>,
+}
+
+pub fn crash_topic() -> String {
+    format!("{}/service/crash", base_topic())
+}
+
+/// Returns the message that was passed to `panic!`
+pub fn panic_message(payload: &(dyn Any + Send)) -> String {
+    payload
+        .downcast_ref::<&str>()
+        .map(|s| s.to_string())
+        .or_else(|| payload.downcast_ref::<String>().cloned())
+        .unwrap_or_else(|| "unknown cause".to_string())
+}
+
+/// Reduce a rendered backtrace to our own frames, each as
+/// `function (file:line:column)`, omitting the frames of
+/// the standard library, tokio and so on
+fn summarize_backtrace(backtrace: &str) -> Vec<String> {
+    let mut frames: Vec<(String, Option<String>)> = vec![];
+    for line in backtrace.lines() {
+        let line = line.trim();
+        if let Some(location) = line.strip_prefix("at ") {
+            if let Some((_, loc)) = frames.last_mut() {
+                // Prefer the path relative to the crate
+                let location = match location.find("src/") {
+                    Some(idx) => &location[idx..],
+                    None => location,
+                };
+                loc.get_or_insert_with(|| location.to_string());
+            }
+        } else if let Some((number, function)) = line.split_once(": ") {
+            if number.chars().all(|c| c.is_ascii_digit()) {
+                frames.push((function.to_string(), None));
+            }
+        }
+    }
+
+    frames
+        .into_iter()
+        .filter(|(function, _)| function.starts_with("govee::"))
+        .take(MAX_BACKTRACE_FRAMES)
+        .map(|(function, location)| match location {
+            Some(location) => format!("{function} ({location})"),
+            None => function,
+        })
+        .collect()
+}
+
+/// Install a panic hook that publishes a crash report via client,
+/// before handing over to the prior hook, which prints the panic.
+/// The client is replaced if this is called again.
+pub fn install_crash_reporter(client: HassClient) {
+    if CRASH_CLIENT.lock().replace(client).is_some() {
+        return;
+    }
+
+    let prior = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        let thread = std::thread::current();
+        // Don't report a failure to report
+        if thread.name() != Some(REPORTER_THREAD) {
+            let report = CrashReport {
+                message: panic_message(info.payload()),
+                location: info.location().map(|loc| loc.to_string()),
+                thread: thread.name().map(|name| name.to_string()),
+                backtrace: summarize_backtrace(
+                    &std::backtrace::Backtrace::force_capture().to_string(),
+                ),
+                version: govee_version().to_string(),
+                timestamp: Utc::now(),
+            };
+            publish_crash_report(report);
+        }
+        prior(info);
+    }));
+}
+
+fn publish_crash_report(report: CrashReport) {
+    let Some(client) = CRASH_CLIENT.lock().clone() else {
+        return;
+    };
+
+    // We may be panicking on a runtime thread, where we cannot block
+    // on the publish, so do it from a thread of our own
+    let publisher = std::thread::Builder::new()
+        .name(REPORTER_THREAD.to_string())
+        .spawn(move || {
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_time()
+                .build()?;
+            runtime.block_on(async {
+                tokio::time::timeout(
+                    CRASH_PUBLISH_TIMEOUT,
+                    client.publish_obj(crash_topic(), &report),
+                )
+                .await?
+            })
+        });
+
+    let result = match publisher {
+        Ok(publisher) => publisher
+            .join()
+            .unwrap_or_else(|_| Err(anyhow::anyhow!("the reporter panicked"))),
+        Err(err) => Err(err.into()),
+    };
+    if let Err(err) = result {
+        log::error!("Publishing crash report: {err:#}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn backtrace() {
+        let backtrace = r#"   0: std::backtrace_rs::backtrace::libunwind::trace
+             at /rustc/abc/library/std/src/../../backtrace/src/backtrace/libunwind.rs:117:9
+   1: std::backtrace::Backtrace::create
+             at /rustc/abc/library/std/src/backtrace.rs:331:13
+   2: govee::service::crash::install_crash_reporter::{{closure}}
+             at ./src/service/crash.rs:97:17
+   3: govee::service::poll_scheduler::PollScheduler::tick::{{closure}}
+             at /root/govee2mqtt/src/service/poll_scheduler.rs:160:13
+   4: tokio::runtime::task::core::Core<T,S>::poll
+             at /cargo/registry/tokio-1.44.2/src/runtime/task/core.rs:331:17
+   5: govee::main
+"#;
+        assert_eq!(
+            summarize_backtrace(backtrace),
+            vec![
+                "govee::service::crash::install_crash_reporter::{{closure}} (src/service/crash.rs:97:17)",
+                "govee::service::poll_scheduler::PollScheduler::tick::{{closure}} (src/service/poll_scheduler.rs:160:13)",
+                "govee::main",
+            ]
+        );
+        assert!(summarize_backtrace("disabled backtrace").is_empty());
+    }
+
+    #[test]
+    fn message() {
+        let payload: Box<dyn Any + Send> = Box::new("oops");
+        assert_eq!(panic_message(payload.as_ref()), "oops");
+        let payload: Box<dyn Any + Send> = Box::new(format!("failed {}", 42));
+        assert_eq!(panic_message(payload.as_ref()), "failed 42");
+        let payload: Box<dyn Any + Send> = Box::new(42);
+        assert_eq!(panic_message(payload.as_ref()), "unknown cause");
+    }
+}
@@ -0,0 +1,65 @@
+//! Coalescing of rapid light commands. The color wheel and brightness
+//! slider in Home Assistant can send dozens of commands per second,
+//! far more than a device can keep up with. A command that arrives
+//! while an earlier one for the same device is still waiting its turn
+//! is merged into it, and the commands that are sent to a device are
+//! spaced out by a minimum interval, so that the device tracks the
+//! latest value at a steady rate rather than working through a backlog.
+use crate::service::device::Device;
+use crate::service::hass::HassLightCommand;
+use crate::service::state::State as ServiceState;
+use tokio::time::{sleep, Duration, Instant};
+
+/// How long to wait between successive light commands for a device,
+/// unless overridden via --min-command-interval
+pub const DEFAULT_MIN_COMMAND_INTERVAL: Duration = Duration::from_millis(250);
+
+#[derive(Default)]
+pub struct LightCommandSlot {
+    /// The command that is waiting to be sent, merged with
+    /// any that arrived while it was waiting
+    pending: Option<HassLightCommand>,
+    /// When we last sent a command to the device
+    last_sent: Option<Instant>,
+}
+
+impl ServiceState {
+    /// Queue a light command for device, merging it into any
+    /// command that is already waiting to be sent
+    pub async fn queue_light_command(&self, device: &Device, command: HassLightCommand) {
+        let mut slot = self.light_command_slot(&device.id).await;
+        let command = match slot.pending.take() {
+            Some(pending) => pending.merge(command),
+            None => command,
+        };
+        slot.pending.replace(command);
+    }
+
+    /// Called by the holder of the control permit for device to
+    /// obtain the next command to send, waiting until the minimum
+    /// interval since the prior command has elapsed.
+    /// Returns None if the command that the caller queued has
+    /// already been sent as part of an earlier merged command.
+    pub async fn next_light_command(&self, device: &Device) -> Option<HassLightCommand> {
+        let last_sent = {
+            let slot = self.light_command_slot(&device.id).await;
+            slot.pending.as_ref()?;
+            slot.last_sent
+        };
+
+        let interval = self.get_min_command_interval().await;
+        if let Some(last_sent) = last_sent {
+            let elapsed = last_sent.elapsed();
+            if elapsed < interval {
+                // Anything that arrives while we sleep is merged
+                // into the command that we are about to send
+                sleep(interval - elapsed).await;
+            }
+        }
+
+        let mut slot = self.light_command_slot(&device.id).await;
+        let command = slot.pending.take()?;
+        slot.last_sent.replace(Instant::now());
+        Some(command)
+    }
+}
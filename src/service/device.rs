@@ -1,15 +1,140 @@
-use crate::ble::NotifyHumidifierNightlightParams;
-use crate::commands::serve::POLL_INTERVAL;
+use crate::ble::{
+    GoveeBlePacket, NotifyHumidifierNightlightParams, NotifyIceMakerStatus, NotifyPlugEnergy,
+    NotifyPresence, NotifySceneCode, NotifySegmentColors, NotifyWarnings, SegmentColor,
+    CHILD_LOCK_SKUS, DIFFUSER_SKUS, MIST_LEVEL_SKUS, OSCILLATION_SKUS, PLUG_ENERGY_SKUS,
+    PRESENCE_SKUS, WARNING_SKUS,
+};
+use crate::ble_advert::SensorReading;
 use crate::lan_api::{DeviceColor, DeviceStatus as LanDeviceStatus, LanDevice};
 use crate::platform_api::{
-    DeviceCapability, DeviceCapabilityState, DeviceType, HttpDeviceInfo, HttpDeviceState,
+    DeviceCapability, DeviceCapabilityKind, DeviceCapabilityState, DeviceType, HttpDeviceInfo,
+    HttpDeviceState,
 };
-use crate::service::quirks::{resolve_quirk, Quirk, BULB};
+use crate::service::power::EnergyMeter;
+use crate::service::quirks::{resolve_quirk, HumidityUnits, Quirk, BULB};
+use crate::temperature::{TemperatureScale, TemperatureUnits, TemperatureValue};
+use crate::undoc_api::{DeviceSchedule, FirmwareUpdateInfo};
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::net::IpAddr;
+use std::str::FromStr;
+
+/// Features of heaters, fans and lights that can be switched on and
+/// off, either via packets or via a Platform API toggle capability
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum DeviceToggle {
+    Oscillation,
+    ChildLock,
+    /// Blends the colors of adjacent segments of a strip
+    Gradient,
+}
+
+impl DeviceToggle {
+    pub const ALL: [Self; 3] = [Self::Oscillation, Self::ChildLock, Self::Gradient];
+
+    /// The Platform API capability instance for this toggle
+    pub fn instance(&self) -> &'static str {
+        match self {
+            Self::Oscillation => "oscillationToggle",
+            Self::ChildLock => "childLock",
+            Self::Gradient => "gradientToggle",
+        }
+    }
+
+    pub fn from_instance(instance: &str) -> Option<Self> {
+        Self::ALL
+            .into_iter()
+            .find(|toggle| toggle.instance() == instance)
+    }
+
+    /// The SKUs that can be controlled via packets
+    pub fn packet_skus(&self) -> &'static [&'static str] {
+        match self {
+            Self::Oscillation => OSCILLATION_SKUS,
+            Self::ChildLock => CHILD_LOCK_SKUS,
+            // Strips that support gradients are recognized by their
+            // reporting the gradient state instead
+            Self::Gradient => &[],
+        }
+    }
+}
+
+/// The Platform API toggle instances that switch the individual
+/// outlets of a power strip are named socketToggle1, socketToggle2...
+const OUTLET_INSTANCE_PREFIX: &str = "socketToggle";
+
+/// Returns the outlet number if instance switches one of the
+/// outlets of a multi-outlet power strip
+pub fn outlet_number(instance: &str) -> Option<u32> {
+    let prefix = instance.get(..OUTLET_INSTANCE_PREFIX.len())?;
+    if !prefix.eq_ignore_ascii_case(OUTLET_INSTANCE_PREFIX) {
+        return None;
+    }
+    instance[OUTLET_INSTANCE_PREFIX.len()..].parse().ok()
+}
+
+/// The work mode whose parameter is the mist level
+pub const MIST_LEVEL_WORK_MODE: u8 = 1;
+
+/// The mist intensities of the aroma diffusers, in ascending order
+const DIFFUSER_MIST_LEVELS: &[&str] = &["Low", "Medium", "High"];
+
+/// The mist levels of a humidifier or diffuser
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MistLevels {
+    /// Numbered levels, from min to max inclusive
+    Range(u8, u8),
+    /// Named levels; the first is level 1
+    Named(&'static [&'static str]),
+}
+
+impl MistLevels {
+    pub fn name_for_level(&self, level: u8) -> Option<&'static str> {
+        match self {
+            Self::Range(..) => None,
+            Self::Named(names) => names.get((level as usize).checked_sub(1)?).copied(),
+        }
+    }
+
+    /// Parse either a level number or a level name
+    pub fn parse_level(&self, value: &str) -> Option<u8> {
+        let value = value.trim();
+        match self {
+            Self::Range(min, max) => value
+                .parse::<u8>()
+                .ok()
+                .filter(|level| (*min..=*max).contains(level)),
+            Self::Named(names) => names
+                .iter()
+                .position(|name| name.eq_ignore_ascii_case(value))
+                .map(|idx| idx as u8 + 1),
+        }
+    }
+}
+
+/// A countdown after which the device is turned off
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct OffTimer {
+    pub deadline: DateTime<Utc>,
+    /// If true, we turn the device off when the countdown expires,
+    /// rather than the device itself
+    pub local: bool,
+}
+
+impl OffTimer {
+    pub fn remaining(&self) -> chrono::Duration {
+        (self.deadline - Utc::now()).max(chrono::Duration::zero())
+    }
+
+    /// The remaining time, rounded up to the next whole minute
+    pub fn remaining_minutes(&self) -> i64 {
+        let seconds = self.remaining().num_seconds();
+        (seconds + 59) / 60
+    }
+}
 
 #[derive(Default, Clone, Debug)]
 pub struct Device {
@@ -35,13 +160,79 @@ pub struct Device {
 
     pub iot_device_status: Option<LanDeviceStatus>,
     pub last_iot_device_status_update: Option<DateTime<Utc>>,
+    /// The most recent message received for the device via IoT
+    pub last_iot_payload: Option<JsonValue>,
 
     pub nightlight_state: Option<NotifyHumidifierNightlightParams>,
     pub target_humidity_percent: Option<u8>,
     pub humidifier_work_mode: Option<u8>,
     pub humidifier_param_by_mode: HashMap<u8, u8>,
+    /// The water temperature most recently reported by a kettle
+    pub kettle_current_temperature: Option<TemperatureValue>,
+    /// The target temperature most recently reported by a kettle
+    pub kettle_target_temperature: Option<TemperatureValue>,
+    /// The thermostat target most recently reported by a heater
+    pub heater_target_temperature: Option<TemperatureValue>,
+    /// The toggle states most recently reported via IoT
+    pub toggle_states: HashMap<DeviceToggle, bool>,
+    pub ice_maker_status: Option<NotifyIceMakerStatus>,
+    pub warnings: Option<NotifyWarnings>,
+    pub off_timer: Option<OffTimer>,
+    /// The timers that were configured via the Govee Home app
+    pub schedules: Option<Vec<DeviceSchedule>>,
+    /// The most recent firmware offered for the device by Govee
+    pub firmware_update: Option<FirmwareUpdateInfo>,
+    /// The percentage complete of a firmware update that
+    /// we started, while it is in progress
+    pub firmware_update_progress: Option<u8>,
+    pub presence_status: Option<NotifyPresence>,
+    pub last_presence_update: Option<DateTime<Utc>>,
+    /// The load most recently reported by a smart plug
+    pub plug_energy: Option<NotifyPlugEnergy>,
+    /// The segment colors most recently reported by the device,
+    /// keyed by the zero-based segment index
+    pub segment_colors: BTreeMap<u32, SegmentColor>,
+    /// Whether the device most recently reported gradient mode
+    pub gradient: Option<bool>,
+    /// The estimated power consumption, for lights with a power model
+    pub energy_meter: EnergyMeter,
+
+    /// The most recent reading passively received via
+    /// BLE advertisement from a thermo-hygrometer
+    pub ble_sensor_reading: Option<SensorReading>,
+    pub last_ble_sensor_reading_update: Option<DateTime<Utc>>,
+    /// The time at which a light was most recently heard
+    /// advertising via BLE, which it does while powered
+    pub last_ble_advert: Option<DateTime<Utc>>,
+
+    /// The state that we pretend the device is in when running
+    /// in dry-run mode
+    pub simulated_status: Option<LanDeviceStatus>,
+    pub last_simulated_status_update: Option<DateTime<Utc>>,
+
+    /// The state that we expect the device to be in as a result
+    /// of a command, reported until the device confirms or
+    /// contradicts it
+    optimistic_status: Option<OptimisticStatus>,
+
+    /// The brightness that a light had before a transition faded
+    /// it out, so that it can be restored when next turned on
+    pub fade_out_brightness: Option<u8>,
+
+    /// Set via the Read Only switch in Home Assistant, taking
+    /// precedence over --read-only-device
+    pub read_only: Option<bool>,
 
     pub last_polled: Option<DateTime<Utc>>,
+    /// The number of consecutive polls that the device
+    /// didn't respond to via any transport
+    pub poll_failures: u32,
+    /// When the device was quarantined for not responding to polls
+    pub quarantined_since: Option<DateTime<Utc>>,
+    /// When we last issued a control request to the device
+    pub last_controlled: Option<DateTime<Utc>>,
+    /// When we last observed the reported state actually change
+    pub last_changed: Option<DateTime<Utc>>,
 
     active_scene: Option<ActiveSceneInfo>,
 }
@@ -52,6 +243,15 @@ impl std::fmt::Display for Device {
     }
 }
 
+#[derive(Clone, Debug)]
+struct OptimisticStatus {
+    status: LanDeviceStatus,
+    updated: DateTime<Utc>,
+    /// True while the command is still being sent, during which
+    /// time reports from the device are likely to be stale
+    pending: bool,
+}
+
 /// Govee doesn't report the active scene or music mode,
 /// so we maintain our own idea of it, clearing it when
 /// the color of the light is changed
@@ -97,6 +297,37 @@ pub struct UndocDeviceInfo {
     pub entry: crate::undoc_api::DeviceEntry,
 }
 
+/// Determine the color temperature range of a device. An exact range
+/// from the quirks table wins, then the range reported by the Platform
+/// API, falling back to the generic range for the quirk.
+fn resolve_color_temperature_range(
+    quirk: Option<&Quirk>,
+    platform_range: Option<(u32, u32)>,
+) -> Option<(u32, u32)> {
+    match quirk {
+        Some(quirk) => match quirk.kelvin_range {
+            Some(range) => Some(range),
+            None => quirk
+                .color_temp_range
+                .map(|generic| platform_range.unwrap_or(generic)),
+        },
+        None => platform_range,
+    }
+}
+
+/// Determine the indices of the segments of a device. A count from
+/// the quirks table wins over the range reported by the Platform API.
+fn resolve_segment_range(
+    quirk: Option<&Quirk>,
+    platform_range: Option<std::ops::Range<u32>>,
+) -> Option<std::ops::Range<u32>> {
+    match quirk.and_then(|quirk| quirk.segment_count) {
+        Some(count) => Some(0..count),
+        None => platform_range,
+    }
+    .filter(|range| !range.is_empty())
+}
+
 impl Device {
     /// Create a new device given just its sku and id.
     /// No other facts are known or reflected by it at this time;
@@ -130,6 +361,17 @@ impl Device {
         None
     }
 
+    /// Record a new name for the device, after it was
+    /// changed via the Govee API
+    pub fn set_govee_name(&mut self, name: &str) {
+        if let Some(info) = &mut self.http_device_info {
+            info.device_name = name.to_string();
+        }
+        if let Some(info) = &mut self.undoc_device_info {
+            info.entry.device_name = name.to_string();
+        }
+    }
+
     pub fn room_name(&self) -> Option<&str> {
         if let Some(info) = &self.undoc_device_info {
             return info.room_name.as_deref();
@@ -155,17 +397,18 @@ impl Device {
         format!("{}_{}", self.sku, &id[id.len().saturating_sub(4)..])
     }
 
-    pub fn preferred_poll_interval(&self) -> chrono::Duration {
+    /// Adjusts the configured poll interval for the device
+    pub fn preferred_poll_interval(&self, configured: chrono::Duration) -> chrono::Duration {
         match self.device_type() {
             // If the kettle is on, read its temperature more frequently
             DeviceType::Kettle => {
                 if self.device_state().map(|s| s.on).unwrap_or(false) {
-                    chrono::Duration::seconds(60)
+                    configured.min(chrono::Duration::seconds(60))
                 } else {
-                    *POLL_INTERVAL
+                    configured
                 }
             }
-            _ => *POLL_INTERVAL,
+            _ => configured,
         }
     }
 
@@ -177,6 +420,23 @@ impl Device {
         self.last_polled.replace(Utc::now());
     }
 
+    pub fn set_last_controlled(&mut self) {
+        self.last_controlled.replace(Utc::now());
+    }
+
+    /// Returns a tuple of the user-visible aspects of the current state,
+    /// so that we can tell whether an update actually changed anything
+    fn state_fingerprint(&self) -> Option<(bool, u8, DeviceColor, u32)> {
+        self.device_state()
+            .map(|s| (s.on, s.brightness, s.color, s.kelvin))
+    }
+
+    fn note_state_change(&mut self, prior: Option<(bool, u8, DeviceColor, u32)>) {
+        if self.state_fingerprint() != prior {
+            self.last_changed.replace(Utc::now());
+        }
+    }
+
     pub fn set_nightlight_state(&mut self, params: NotifyHumidifierNightlightParams) {
         self.nightlight_state.replace(params);
     }
@@ -185,11 +445,187 @@ impl Device {
         self.target_humidity_percent.replace(percent);
     }
 
+    /// Record the parameter for mode, without changing the current mode
+    pub fn set_humidifier_param(&mut self, mode: u8, param: u8) {
+        self.humidifier_param_by_mode.insert(mode, param);
+    }
+
     pub fn set_humidifier_work_mode_and_param(&mut self, mode: u8, param: u8) {
         self.humidifier_work_mode.replace(mode);
         self.humidifier_param_by_mode.insert(mode, param);
     }
 
+    pub fn set_kettle_temperatures(&mut self, current: TemperatureValue, target: TemperatureValue) {
+        self.kettle_current_temperature.replace(current);
+        self.kettle_target_temperature.replace(target);
+    }
+
+    pub fn set_heater_target_temperature(&mut self, target: TemperatureValue) {
+        self.heater_target_temperature.replace(target);
+    }
+
+    pub fn set_toggle_state(&mut self, toggle: DeviceToggle, on: bool) {
+        match toggle {
+            DeviceToggle::Gradient => {
+                self.gradient.replace(on);
+            }
+            _ => {
+                self.toggle_states.insert(toggle, on);
+            }
+        }
+    }
+
+    /// The state of the toggle as most recently reported by
+    /// the device via packets, or set by us
+    pub fn toggle_state(&self, toggle: DeviceToggle) -> Option<bool> {
+        match toggle {
+            DeviceToggle::Gradient => self.gradient,
+            _ => self.toggle_states.get(&toggle).copied(),
+        }
+    }
+
+    /// Record the state of a capability in the Platform API state, for
+    /// a change that the API won't tell us about until we next poll it
+    pub fn set_capability_state(
+        &mut self,
+        kind: DeviceCapabilityKind,
+        instance: &str,
+        value: JsonValue,
+    ) {
+        let state = self
+            .http_device_state
+            .get_or_insert_with(|| HttpDeviceState {
+                sku: self.sku.to_string(),
+                device: self.id.to_string(),
+                capabilities: vec![],
+            });
+        let value = serde_json::json!({ "value": value });
+        match state
+            .capabilities
+            .iter_mut()
+            .find(|c| c.instance.eq_ignore_ascii_case(instance))
+        {
+            Some(cap) => {
+                cap.state = value;
+            }
+            None => {
+                state.capabilities.push(DeviceCapabilityState {
+                    kind,
+                    instance: instance.to_string(),
+                    state: value,
+                });
+            }
+        }
+    }
+
+    pub fn set_outlet_state(&mut self, instance: &str, on: bool) {
+        self.set_capability_state(
+            DeviceCapabilityKind::Toggle,
+            instance,
+            if on { 1 } else { 0 }.into(),
+        );
+    }
+
+    pub fn set_work_mode_state(&mut self, work_mode: i64, value: i64) {
+        self.set_capability_state(
+            DeviceCapabilityKind::WorkMode,
+            "workMode",
+            serde_json::json!({"workMode": work_mode, "modeValue": value}),
+        );
+    }
+
+    /// The numbers and Platform API instances of the outlets of
+    /// a multi-outlet power strip, in order
+    pub fn outlets(&self) -> Vec<(u32, &DeviceCapability)> {
+        let mut outlets: Vec<_> = self
+            .http_device_info
+            .iter()
+            .flat_map(|info| info.capabilities.iter())
+            .filter(|cap| cap.kind == DeviceCapabilityKind::Toggle)
+            .filter_map(|cap| Some((outlet_number(&cap.instance)?, cap)))
+            .collect();
+        outlets.sort_by_key(|(number, _)| *number);
+        outlets
+    }
+
+    pub fn set_ice_maker_status(&mut self, status: NotifyIceMakerStatus) {
+        self.ice_maker_status.replace(status);
+    }
+
+    /// Start a countdown of minutes, or cancel it if minutes is zero
+    pub fn set_off_timer(&mut self, minutes: u16, local: bool) {
+        self.off_timer = (minutes > 0).then(|| OffTimer {
+            deadline: Utc::now() + chrono::Duration::minutes(minutes.into()),
+            local,
+        });
+    }
+
+    /// Update the schedules, returning true if they changed
+    pub fn set_schedules(&mut self, schedules: Vec<DeviceSchedule>) -> bool {
+        let changed = self.schedules.as_ref() != Some(&schedules);
+        self.schedules.replace(schedules);
+        changed
+    }
+
+    /// Update the firmware info, returning true if it changed
+    pub fn set_firmware_update(&mut self, info: FirmwareUpdateInfo) -> bool {
+        let changed = self.firmware_update.as_ref() != Some(&info);
+        self.firmware_update.replace(info);
+        changed
+    }
+
+    pub fn set_warnings(&mut self, warnings: NotifyWarnings) {
+        self.warnings.replace(warnings);
+    }
+
+    pub fn set_presence_status(&mut self, status: NotifyPresence) {
+        self.presence_status.replace(status);
+        self.last_presence_update.replace(Utc::now());
+    }
+
+    pub fn set_plug_energy(&mut self, energy: NotifyPlugEnergy) {
+        self.plug_energy.replace(energy);
+    }
+
+    pub fn set_segment_colors(&mut self, colors: &NotifySegmentColors) {
+        let first = colors.first_segment();
+        for (n, color) in colors.segments.iter().enumerate() {
+            self.segment_colors.insert(first + n as u32, *color);
+        }
+    }
+
+    /// Apply the packets that accompanied a status report,
+    /// returning the scene code if one was reported, as
+    /// resolving that to a name is up to the caller
+    pub fn apply_status_op(&mut self, packets: &[GoveeBlePacket]) -> Option<u16> {
+        let mut scene_code = None;
+        for packet in packets {
+            match packet {
+                GoveeBlePacket::NotifySegmentColors(colors) => {
+                    self.set_segment_colors(colors);
+                }
+                GoveeBlePacket::NotifyGradient(gradient) => {
+                    self.gradient.replace(gradient.on);
+                }
+                GoveeBlePacket::NotifySceneCode(NotifySceneCode { code }) => {
+                    scene_code.replace(*code);
+                }
+                _ => {
+                    log::trace!("Ignoring {packet:?} in status of {self}");
+                }
+            }
+        }
+        scene_code
+    }
+
+    /// Update the BLE sensor reading, returning true if it changed
+    pub fn set_ble_sensor_reading(&mut self, reading: SensorReading) -> bool {
+        let changed = self.ble_sensor_reading != Some(reading);
+        self.ble_sensor_reading.replace(reading);
+        self.last_ble_sensor_reading_update.replace(Utc::now());
+        changed
+    }
+
     /// Update the LAN device information
     pub fn set_lan_device(&mut self, device: LanDevice) {
         self.lan_device.replace(device);
@@ -206,13 +642,22 @@ impl Device {
         self.lan_device_status.replace(status);
         self.last_lan_device_status_update.replace(Utc::now());
         self.clear_scene_if_color_changed();
+        if changed {
+            self.last_changed.replace(Utc::now());
+        }
         changed
     }
 
     pub fn set_iot_device_status(&mut self, status: LanDeviceStatus) {
+        let prior = self.state_fingerprint();
         self.iot_device_status.replace(status);
         self.last_iot_device_status_update.replace(Utc::now());
         self.clear_scene_if_color_changed();
+        self.note_state_change(prior);
+    }
+
+    pub fn set_last_iot_payload(&mut self, payload: JsonValue) {
+        self.last_iot_payload.replace(payload);
     }
 
     pub fn set_http_device_info(&mut self, info: HttpDeviceInfo) {
@@ -221,9 +666,11 @@ impl Device {
     }
 
     pub fn set_http_device_state(&mut self, state: HttpDeviceState) {
+        let prior = self.state_fingerprint();
         self.http_device_state.replace(state);
         self.last_http_device_state_update.replace(Utc::now());
         self.clear_scene_if_color_changed();
+        self.note_state_change(prior);
     }
 
     pub fn set_undoc_device_info(
@@ -260,6 +707,161 @@ impl Device {
         })
     }
 
+    pub fn compute_simulated_device_state(&self) -> Option<DeviceState> {
+        let updated = self.last_simulated_status_update?;
+        let status = self.simulated_status.as_ref()?;
+
+        Some(DeviceState {
+            on: status.on,
+            light_on: Some(status.on),
+            online: None,
+            brightness: status.brightness,
+            color: status.color,
+            kelvin: status.color_temperature_kelvin,
+            scene: self.active_scene.as_ref().map(|info| info.name.to_string()),
+            source: "Simulation",
+            updated,
+        })
+    }
+
+    /// Pretend that the command, as passed to run_device_command,
+    /// was applied to the device. Used in dry-run mode.
+    pub fn apply_simulated_command(&mut self, command: &str, payload: &JsonValue) {
+        let status = match &self.simulated_status {
+            Some(status) => status.clone(),
+            None => self.current_status(),
+        };
+        let status = self.status_after_command(status, command, payload);
+
+        self.simulated_status.replace(status);
+        self.last_simulated_status_update.replace(Utc::now());
+    }
+
+    /// Report the effect of the command, as passed to
+    /// run_device_command, ahead of the device confirming it
+    pub fn apply_optimistic_command(&mut self, command: &str, payload: &JsonValue) {
+        let status = self.status_after_command(self.current_status(), command, payload);
+        self.optimistic_status.replace(OptimisticStatus {
+            status,
+            updated: Utc::now(),
+            pending: true,
+        });
+    }
+
+    /// Called once the command has been sent. Reports from the
+    /// device that are received after this point take precedence
+    /// over the optimistic state. Returns the time at which the
+    /// command was sent.
+    pub fn settle_optimistic_status(&mut self) -> Option<DateTime<Utc>> {
+        let optimistic = self.optimistic_status.as_mut()?;
+        optimistic.pending = false;
+        optimistic.updated = Utc::now();
+        Some(optimistic.updated)
+    }
+
+    /// Discard the optimistic state. If settled_at is specified,
+    /// the state is only discarded if no other command has since
+    /// replaced it. Returns true if the state was discarded.
+    pub fn clear_optimistic_status(&mut self, settled_at: Option<DateTime<Utc>>) -> bool {
+        let matches = match (&self.optimistic_status, settled_at) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(optimistic), Some(settled_at)) => {
+                !optimistic.pending && optimistic.updated == settled_at
+            }
+        };
+        if matches {
+            self.optimistic_status.take();
+        }
+        matches
+    }
+
+    pub fn compute_optimistic_device_state(&self) -> Option<DeviceState> {
+        let optimistic = self.optimistic_status.as_ref()?;
+        let status = &optimistic.status;
+
+        Some(DeviceState {
+            on: status.on,
+            light_on: Some(status.on),
+            online: None,
+            brightness: status.brightness,
+            color: status.color,
+            kelvin: status.color_temperature_kelvin,
+            scene: self.active_scene.as_ref().map(|info| info.name.to_string()),
+            source: "Optimistic",
+            updated: optimistic.updated,
+        })
+    }
+
+    fn current_status(&self) -> LanDeviceStatus {
+        self.device_state()
+            .map(|s| LanDeviceStatus {
+                on: s.light_on.unwrap_or(s.on),
+                brightness: s.brightness,
+                color: s.color,
+                color_temperature_kelvin: s.kelvin,
+                op: None,
+            })
+            .unwrap_or_default()
+    }
+
+    fn status_after_command(
+        &mut self,
+        mut status: LanDeviceStatus,
+        command: &str,
+        payload: &JsonValue,
+    ) -> LanDeviceStatus {
+        match command {
+            "power" | "light_power" => {
+                status.on = payload["on"].as_bool().unwrap_or(status.on);
+            }
+            "brightness" => {
+                let brightness = payload["brightness"].as_u64().unwrap_or(100) as u8;
+                status.brightness = brightness;
+                status.on = brightness != 0;
+            }
+            "color_rgb" => {
+                status.color = DeviceColor {
+                    r: payload["r"].as_u64().unwrap_or(0) as u8,
+                    g: payload["g"].as_u64().unwrap_or(0) as u8,
+                    b: payload["b"].as_u64().unwrap_or(0) as u8,
+                };
+                status.color_temperature_kelvin = 0;
+                status.on = true;
+                self.set_active_scene(None);
+            }
+            "color_temperature" => {
+                status.color_temperature_kelvin = payload["kelvin"].as_u64().unwrap_or(0) as u32;
+                status.on = true;
+                self.set_active_scene(None);
+            }
+            "scene" => {
+                status.on = true;
+                self.set_active_scene(payload["scene"].as_str());
+            }
+            "scene_code" => {
+                status.on = true;
+                self.set_active_scene(None);
+            }
+            "outlet" => {
+                if let (Some(instance), Some(on)) =
+                    (payload["instance"].as_str(), payload["on"].as_bool())
+                {
+                    self.set_outlet_state(instance, on);
+                }
+            }
+            "cover_parameter" => {
+                if let (Some(work_mode), Some(value)) =
+                    (payload["work_mode"].as_i64(), payload["value"].as_i64())
+                {
+                    self.set_work_mode_state(work_mode, value);
+                }
+            }
+            _ => {}
+        }
+        status
+    }
+
     pub fn compute_lan_device_state(&self) -> Option<DeviceState> {
         let updated = self.last_lan_device_status_update?;
         let status = self.lan_device_status.as_ref()?;
@@ -350,8 +952,27 @@ impl Device {
 
     /// Returns the most recently received state information
     pub fn device_state(&self) -> Option<DeviceState> {
+        // In dry-run mode, the simulation overrides whatever
+        // the device itself might report
+        if let Some(state) = self.compute_simulated_device_state() {
+            return Some(state);
+        }
+
+        let optimistic = self.compute_optimistic_device_state();
+        if self
+            .optimistic_status
+            .as_ref()
+            .map(|o| o.pending)
+            .unwrap_or(false)
+        {
+            return optimistic;
+        }
+
         let mut candidates = vec![];
 
+        if let Some(state) = optimistic {
+            candidates.push(state);
+        }
         if let Some(state) = self.compute_lan_device_state() {
             candidates.push(state);
         }
@@ -367,6 +988,11 @@ impl Device {
         candidates.pop()
     }
 
+    /// The name of the scene that we believe to be active
+    pub fn active_scene(&self) -> Option<&str> {
+        self.active_scene.as_ref().map(|info| info.name.as_str())
+    }
+
     /// Records the active scene name
     pub fn set_active_scene(&mut self, scene: Option<&str>) {
         match scene {
@@ -499,6 +1125,58 @@ impl Device {
             .and_then(|info| info.capability_by_instance(instance))
     }
 
+    /// The relative humidity reported by the Platform API sensorHumidity
+    /// capability, adjusted for the units used by the device
+    pub fn current_humidity_percent(&self) -> Option<f64> {
+        let units = self
+            .resolve_quirk()
+            .and_then(|q| q.platform_humidity_sensor_units)
+            .unwrap_or(HumidityUnits::RelativePercent);
+        self.get_state_capability_by_instance("sensorHumidity")?
+            .state
+            .pointer("/value")
+            .and_then(|v| v.as_f64())
+            .map(|v| units.from_reading_to_relative_percent(v))
+    }
+
+    /// The current temperature reported by the Platform API
+    /// sensorTemperature capability, in the units used by the device
+    pub fn current_temperature(&self) -> Option<TemperatureValue> {
+        let value = self
+            .get_state_capability_by_instance("sensorTemperature")?
+            .state
+            .pointer("/value")?
+            .as_f64()?;
+        Some(TemperatureValue::new(
+            value,
+            self.platform_temperature_units(TemperatureUnits::Fahrenheit),
+        ))
+    }
+
+    /// The target temperature reported by the Platform API for the
+    /// temperature setting instance. The state usually specifies
+    /// its unit, but we fall back to that of the device otherwise.
+    pub fn target_temperature(
+        &self,
+        instance: &str,
+        default_units: TemperatureUnits,
+    ) -> Option<TemperatureValue> {
+        let state = &self.get_state_capability_by_instance(instance)?.state;
+        let value = state.pointer("/value/targetTemperature")?.as_f64()?;
+        let units = state
+            .pointer("/value/unit")
+            .and_then(|unit| unit.as_str())
+            .and_then(|s| TemperatureScale::from_str(s).map(Into::into).ok())
+            .unwrap_or_else(|| self.platform_temperature_units(default_units));
+        Some(TemperatureValue::new(value, units))
+    }
+
+    fn platform_temperature_units(&self, default_units: TemperatureUnits) -> TemperatureUnits {
+        self.resolve_quirk()
+            .and_then(|q| q.platform_temperature_sensor_units)
+            .unwrap_or(default_units)
+    }
+
     pub fn get_light_power_toggle_instance_name(&self) -> Option<&'static str> {
         match self.device_type() {
             DeviceType::Light => Some("powerSwitch"),
@@ -522,18 +1200,51 @@ impl Device {
     }
 
     pub fn get_color_temperature_range(&self) -> Option<(u32, u32)> {
-        if let Some(quirk) = self.resolve_quirk() {
-            return quirk.color_temp_range;
-        }
+        let platform_range = if self.avoid_platform_api() {
+            None
+        } else {
+            self.http_device_info
+                .as_ref()
+                .and_then(|info| info.get_color_temperature_range())
+        };
+        resolve_color_temperature_range(self.resolve_quirk().as_ref(), platform_range)
+    }
 
-        if self.lan_device.is_some() {
-            // LAN API support suggests that it is a light
-            return Some((2000, 9000));
+    /// The indices of the individually addressable segments, if any
+    pub fn segment_range(&self) -> Option<std::ops::Range<u32>> {
+        let platform_range = self
+            .http_device_info
+            .as_ref()
+            .and_then(|info| info.supports_segmented_rgb());
+        resolve_segment_range(self.resolve_quirk().as_ref(), platform_range)
+    }
+
+    pub fn segment_count(&self) -> Option<u32> {
+        self.segment_range().map(|range| range.len() as u32)
+    }
+
+    /// Check that segment is one of the segments of the device,
+    /// so that a command addressing it can succeed
+    pub fn validate_segment(&self, segment: u32) -> anyhow::Result<()> {
+        match self.segment_range() {
+            Some(range) if range.contains(&segment) => Ok(()),
+            Some(range) => anyhow::bail!(
+                "{self} has {count} segments, numbered {first} to {last}; \
+                 segment {segment} does not exist",
+                count = range.len(),
+                first = range.start,
+                last = range.end - 1,
+            ),
+            None => anyhow::bail!("{self} does not have individually addressable segments"),
         }
+    }
 
-        self.http_device_info
-            .as_ref()
-            .and_then(|info| info.get_color_temperature_range())
+    /// Constrain kelvin to the range supported by the device
+    pub fn clamp_color_temperature(&self, kelvin: u32) -> u32 {
+        match self.get_color_temperature_range() {
+            Some((min, max)) => kelvin.clamp(min, max),
+            None => kelvin,
+        }
     }
 
     pub fn supports_brightness(&self) -> bool {
@@ -595,6 +1306,171 @@ impl Device {
         }
     }
 
+    /// Returns the time at which we most recently received a response
+    /// or report from the device, via any of the available transports
+    pub fn last_reachable(&self) -> Option<DateTime<Utc>> {
+        [
+            self.last_lan_device_status_update,
+            self.last_iot_device_status_update,
+            self.last_http_device_state_update,
+            self.last_ble_sensor_reading_update,
+            self.last_presence_update,
+            self.last_ble_advert,
+        ]
+        .into_iter()
+        .flatten()
+        .max()
+    }
+
+    /// Returns true if we have heard from the device within threshold
+    pub fn is_reachable(&self, threshold: chrono::Duration) -> bool {
+        self.last_reachable()
+            .map(|updated| Utc::now() - updated <= threshold)
+            .unwrap_or(false)
+    }
+
+    /// Returns true if the device was heard advertising via BLE within window
+    pub fn is_ble_advertising(&self, window: chrono::Duration) -> bool {
+        self.last_ble_advert
+            .is_some_and(|advert| Utc::now() - advert <= window)
+    }
+
+    /// Returns true if the device used to advertise via BLE, but has
+    /// been silent for longer than window while the scanner, which
+    /// last heard an advertisement at last_heard, continued to work.
+    /// This suggests that its power was cut, such as at the wall switch.
+    pub fn is_ble_silent(
+        &self,
+        last_heard: Option<DateTime<Utc>>,
+        window: chrono::Duration,
+    ) -> bool {
+        match (self.last_ble_advert, last_heard) {
+            (Some(advert), Some(heard)) => heard - advert > window,
+            _ => false,
+        }
+    }
+
+    /// Returns true if the device was quarantined and we haven't
+    /// since heard from it, whether via a status report or discovery
+    pub fn is_quarantined(&self) -> bool {
+        let Some(since) = self.quarantined_since else {
+            return false;
+        };
+        ![self.last_reachable(), self.last_lan_device_update]
+            .into_iter()
+            .flatten()
+            .any(|heard| heard > since)
+    }
+
+    /// Returns the names of the transports via which
+    /// we are able to communicate with the device
+    pub fn transports(&self) -> Vec<&'static str> {
+        let mut transports = vec![];
+        if self.lan_device.is_some() {
+            transports.push("lan");
+        }
+        if self
+            .undoc_device_info
+            .as_ref()
+            .is_some_and(|info| info.entry.device_topic().is_ok())
+        {
+            transports.push("iot");
+        }
+        if self.http_device_info.is_some() {
+            transports.push("platform");
+        }
+        if self.ble_sensor_reading.is_some() {
+            transports.push("ble");
+        }
+        transports
+    }
+
+    /// Returns the firmware version reported by the device,
+    /// or by the Govee account if we haven't heard from the
+    /// device via the LAN
+    pub fn firmware_version(&self) -> Option<&str> {
+        let lan = self
+            .lan_device
+            .as_ref()
+            .map(|lan| lan.wifi_version_soft.as_str());
+        let undoc = || {
+            self.undoc_device_info
+                .as_ref()
+                .map(|info| info.entry.version_soft.as_str())
+        };
+        lan.filter(|v| !v.is_empty())
+            .or_else(|| undoc().filter(|v| !v.is_empty()))
+    }
+
+    pub fn supports_toggle(&self, toggle: DeviceToggle) -> bool {
+        if toggle.packet_skus().contains(&self.sku.as_str()) {
+            return true;
+        }
+        if toggle == DeviceToggle::Gradient && self.gradient.is_some() {
+            return true;
+        }
+        self.http_device_info
+            .as_ref()
+            .map(|info| {
+                info.capabilities.iter().any(|cap| {
+                    cap.kind == DeviceCapabilityKind::Toggle && cap.instance == toggle.instance()
+                })
+            })
+            .unwrap_or(false)
+    }
+
+    /// How the mist level can be controlled via packets, if at all
+    pub fn mist_levels(&self) -> Option<MistLevels> {
+        if DIFFUSER_SKUS.contains(&self.sku.as_str()) {
+            Some(MistLevels::Named(DIFFUSER_MIST_LEVELS))
+        } else if MIST_LEVEL_SKUS.contains(&self.sku.as_str()) {
+            Some(MistLevels::Range(1, 9))
+        } else {
+            None
+        }
+    }
+
+    /// The current mist level, as reported via packets or
+    /// by the Platform API while in the manual mode
+    pub fn mist_level(&self) -> Option<u8> {
+        if let Some(level) = self.humidifier_param_by_mode.get(&MIST_LEVEL_WORK_MODE) {
+            return Some(*level);
+        }
+        let cap = self.get_state_capability_by_instance("workMode")?;
+        if cap.state.pointer("/value/workMode")?.as_u64()? != MIST_LEVEL_WORK_MODE as u64 {
+            return None;
+        }
+        cap.state
+            .pointer("/value/modeValue")?
+            .as_u64()
+            .and_then(|level| level.try_into().ok())
+    }
+
+    /// Returns true if the device reports its warning conditions
+    /// via status packets
+    pub fn reports_warnings(&self) -> bool {
+        WARNING_SKUS.contains(&self.sku.as_str())
+    }
+
+    /// Returns true if this is a presence/motion sensor
+    pub fn reports_plug_energy(&self) -> bool {
+        PLUG_ENERGY_SKUS.contains(&self.sku.as_str()) || self.plug_energy.is_some()
+    }
+
+    pub fn is_presence_sensor(&self) -> bool {
+        if PRESENCE_SKUS.contains(&self.sku.as_str()) {
+            return true;
+        }
+        self.http_device_info
+            .as_ref()
+            .map(|info| {
+                info.capabilities.iter().any(|cap| {
+                    cap.kind == DeviceCapabilityKind::Event && cap.instance == "bodyAppearedEvent"
+                })
+            })
+            .unwrap_or(false)
+    }
+
     pub fn is_controllable(&self) -> bool {
         match self.is_ble_only_device() {
             Some(true) => false,
@@ -607,6 +1483,237 @@ impl Device {
 mod test {
     use super::*;
 
+    #[test]
+    fn color_temperature_range() {
+        let light = Quirk::light("H6000", BULB);
+        assert_eq!(
+            resolve_color_temperature_range(Some(&light), Some((2700, 6500))),
+            Some((2700, 6500))
+        );
+        assert_eq!(
+            resolve_color_temperature_range(Some(&light), None),
+            Some((2000, 9000))
+        );
+
+        let exact = Quirk::light("H6000", BULB).with_kelvin_range(2200, 6500);
+        assert_eq!(
+            resolve_color_temperature_range(Some(&exact), Some((2000, 9000))),
+            Some((2200, 6500))
+        );
+
+        let heater = Quirk::space_heater("H7130");
+        assert_eq!(
+            resolve_color_temperature_range(Some(&heater), Some((2000, 9000))),
+            None
+        );
+
+        let bulb = Device::new("H6003", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert_eq!(bulb.get_color_temperature_range(), Some((2700, 6500)));
+        assert_eq!(
+            resolve_color_temperature_range(None, Some((2700, 6500))),
+            Some((2700, 6500))
+        );
+    }
+
+    #[test]
+    fn segment_range() {
+        let light = Quirk::light("H6000", BULB);
+        assert_eq!(
+            resolve_segment_range(Some(&light), Some(0..15)),
+            Some(0..15)
+        );
+        assert_eq!(resolve_segment_range(Some(&light), None), None);
+        assert_eq!(resolve_segment_range(None, Some(0..0)), None);
+
+        let counted = Quirk::light("H6000", BULB).with_segment_count(10);
+        assert_eq!(
+            resolve_segment_range(Some(&counted), Some(0..15)),
+            Some(0..10)
+        );
+
+        let device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert_eq!(device.segment_count(), None);
+        assert!(device.validate_segment(0).is_err());
+
+        let strip = Device::new("H617C", "AA:BB:CC:DD:EE:FF:42:2B");
+        assert_eq!(strip.segment_count(), Some(15));
+        assert!(strip.validate_segment(14).is_ok());
+        assert!(strip.validate_segment(15).is_err());
+    }
+
+    #[test]
+    fn reachability() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        let threshold = chrono::Duration::minutes(30);
+        assert!(!device.is_reachable(threshold));
+
+        device
+            .last_lan_device_status_update
+            .replace(Utc::now() - chrono::Duration::hours(1));
+        assert!(!device.is_reachable(threshold));
+
+        device
+            .last_iot_device_status_update
+            .replace(Utc::now() - chrono::Duration::minutes(5));
+        assert!(device.is_reachable(threshold));
+    }
+
+    #[test]
+    fn ble_silence() {
+        let mut device = Device::new("H6199", "AA:BB:A4:C1:38:00:11:22");
+        let window = chrono::Duration::seconds(60);
+        let now = Utc::now();
+        assert!(!device.is_ble_silent(Some(now), window));
+
+        device
+            .last_ble_advert
+            .replace(now - chrono::Duration::seconds(10));
+        assert!(device.is_ble_advertising(window));
+        assert!(device.is_reachable(window));
+        assert!(!device.is_ble_silent(Some(now), window));
+
+        device
+            .last_ble_advert
+            .replace(now - chrono::Duration::minutes(5));
+        assert!(!device.is_ble_advertising(window));
+        assert!(device.is_ble_silent(Some(now), window));
+        // If the scanner has stopped hearing anything, the
+        // silence doesn't tell us anything about the device
+        assert!(!device.is_ble_silent(device.last_ble_advert, window));
+        assert!(!device.is_ble_silent(None, window));
+    }
+
+    #[test]
+    fn outlets() {
+        assert_eq!(outlet_number("socketToggle1"), Some(1));
+        assert_eq!(outlet_number("SocketToggle12"), Some(12));
+        assert_eq!(outlet_number("socketToggle"), None);
+        assert_eq!(outlet_number("oscillationToggle"), None);
+        assert_eq!(outlet_number("powerSwitch"), None);
+
+        let mut device = Device::new("H5082", "AA:BB:CC:DD:EE:FF:42:2A");
+        device.set_outlet_state("socketToggle2", true);
+        assert_eq!(
+            device
+                .get_state_capability_by_instance("socketToggle2")
+                .map(|cap| cap.state.clone()),
+            Some(serde_json::json!({"value": 1}))
+        );
+        device.set_outlet_state("socketToggle2", false);
+        assert_eq!(
+            device
+                .get_state_capability_by_instance("socketToggle2")
+                .map(|cap| cap.state.clone()),
+            Some(serde_json::json!({"value": 0}))
+        );
+    }
+
+    #[test]
+    fn mist_levels() {
+        let mut diffuser = Device::new("H7161", "AA:BB:CC:DD:EE:FF:42:2A");
+        let levels = diffuser.mist_levels().unwrap();
+        assert_eq!(levels.parse_level("high"), Some(3));
+        assert_eq!(levels.parse_level("2"), None);
+        assert_eq!(levels.name_for_level(1), Some("Low"));
+        assert_eq!(levels.name_for_level(0), None);
+        assert_eq!(diffuser.mist_level(), None);
+        diffuser.set_humidifier_param(MIST_LEVEL_WORK_MODE, 2);
+        assert_eq!(diffuser.mist_level(), Some(2));
+
+        let humidifier = Device::new("H7160", "AA:BB:CC:DD:EE:FF:42:2B");
+        let levels = humidifier.mist_levels().unwrap();
+        assert_eq!(levels, MistLevels::Range(1, 9));
+        assert_eq!(levels.parse_level("9"), Some(9));
+        assert_eq!(levels.parse_level("10"), None);
+
+        assert!(Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2C")
+            .mist_levels()
+            .is_none());
+    }
+
+    #[test]
+    fn off_timer() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        device.set_off_timer(30, true);
+        let timer = device.off_timer.unwrap();
+        assert!(timer.local);
+        assert_eq!(timer.remaining_minutes(), 30);
+
+        let expired = OffTimer {
+            deadline: Utc::now() - chrono::Duration::minutes(1),
+            local: false,
+        };
+        assert_eq!(expired.remaining_minutes(), 0);
+
+        device.set_off_timer(0, false);
+        assert!(device.off_timer.is_none());
+    }
+
+    #[test]
+    fn simulated_commands() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert!(device.device_state().is_none());
+
+        device.apply_simulated_command("brightness", &serde_json::json!({"brightness": 40}));
+        let state = device.device_state().unwrap();
+        assert!(state.on);
+        assert_eq!(state.brightness, 40);
+        assert_eq!(state.source, "Simulation");
+
+        device.apply_simulated_command("scene", &serde_json::json!({"scene": "Aurora"}));
+        assert_eq!(
+            device.device_state().unwrap().scene.as_deref(),
+            Some("Aurora")
+        );
+
+        device.apply_simulated_command("color_temperature", &serde_json::json!({"kelvin": 4000}));
+        let state = device.device_state().unwrap();
+        assert_eq!(state.kelvin, 4000);
+        assert_eq!(state.scene, None);
+
+        device.apply_simulated_command("power", &serde_json::json!({"on": false}));
+        assert!(!device.device_state().unwrap().on);
+    }
+
+    #[test]
+    fn optimistic_state() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        device.set_lan_device_status(LanDeviceStatus {
+            on: false,
+            brightness: 50,
+            ..LanDeviceStatus::default()
+        });
+
+        device.apply_optimistic_command("power", &serde_json::json!({"on": true}));
+        // A report received while the command is being sent is
+        // likely to be stale, so the optimistic state is preferred
+        device.set_lan_device_status(LanDeviceStatus {
+            on: false,
+            brightness: 50,
+            ..LanDeviceStatus::default()
+        });
+        let state = device.device_state().unwrap();
+        assert!(state.on);
+        assert_eq!(state.brightness, 50);
+        assert_eq!(state.source, "Optimistic");
+
+        let settled_at = device.settle_optimistic_status();
+        assert!(device.device_state().unwrap().on);
+
+        // A subsequent report contradicts it
+        device.set_lan_device_status(LanDeviceStatus {
+            on: false,
+            brightness: 50,
+            ..LanDeviceStatus::default()
+        });
+        let state = device.device_state().unwrap();
+        assert!(!state.on);
+        assert_eq!(state.source, "LAN API");
+
+        assert!(device.clear_optimistic_status(settled_at));
+        assert!(!device.clear_optimistic_status(settled_at));
+    }
+
     #[test]
     fn name_compute() {
         let device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
@@ -0,0 +1,117 @@
+//! The configuration files that have entries for SKUs and for
+//! individual devices share this layout and lookup, so that a
+//! device is identified in the same way by all of them.
+use crate::service::device::Device;
+use crate::service::state::device_matches_label;
+use anyhow::Context;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct DeviceConfigFile<T> {
+    /// Keyed by SKU
+    #[serde(default = "HashMap::new")]
+    pub skus: HashMap<String, T>,
+    /// Keyed by device name, id, topic id or IP address.
+    /// These take precedence over the entries for the SKU
+    #[serde(default = "HashMap::new")]
+    pub devices: HashMap<String, T>,
+}
+
+impl<T> Default for DeviceConfigFile<T> {
+    fn default() -> Self {
+        Self {
+            skus: HashMap::new(),
+            devices: HashMap::new(),
+        }
+    }
+}
+
+impl<T> DeviceConfigFile<T> {
+    pub fn is_empty(&self) -> bool {
+        self.skus.is_empty() && self.devices.is_empty()
+    }
+
+    /// The entries for the SKU of device
+    pub fn sku_entries<'a: 'd, 'd>(
+        &'a self,
+        device: &'d Device,
+    ) -> impl Iterator<Item = &'a T> + 'd {
+        self.skus
+            .iter()
+            .filter(|(sku, _)| device.sku.eq_ignore_ascii_case(sku))
+            .map(|(_, entry)| entry)
+    }
+
+    /// The entries whose label refers to device
+    pub fn device_entries<'a: 'd, 'd>(
+        &'a self,
+        device: &'d Device,
+    ) -> impl Iterator<Item = &'a T> + 'd {
+        self.devices
+            .iter()
+            .filter(|(label, _)| device_matches_label(device, label))
+            .map(|(_, entry)| entry)
+    }
+
+    pub fn for_sku(&self, device: &Device) -> Option<&T> {
+        self.sku_entries(device).next()
+    }
+
+    pub fn for_device(&self, device: &Device) -> Option<&T> {
+        self.device_entries(device).next()
+    }
+
+    /// The most specific entry for device
+    pub fn lookup(&self, device: &Device) -> Option<&T> {
+        self.for_device(device).or_else(|| self.for_sku(device))
+    }
+
+    /// Validate each of the entries, which are identified
+    /// in any error by their SKU or label
+    pub fn validate_entries(
+        &self,
+        validate: impl Fn(&T, &str) -> anyhow::Result<()>,
+    ) -> anyhow::Result<()> {
+        for (key, entry) in self.skus.iter().chain(self.devices.iter()) {
+            validate(entry, key)?;
+        }
+        Ok(())
+    }
+}
+
+/// Read a JSON configuration file; what describes its contents
+/// in any error
+pub fn load_config_file<T: DeserializeOwned>(path: &Path, what: &str) -> anyhow::Result<T> {
+    let data =
+        std::fs::read_to_string(path).with_context(|| format!("reading {what} from {path:?}"))?;
+    serde_json::from_str(&data).with_context(|| format!("parsing {what} from {path:?}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        let file: DeviceConfigFile<u32> = serde_json::from_str(
+            r#"{
+                "skus": {"H6072": 1},
+                "devices": {"AABBCCDDEEFF0011": 2, "10.0.0.5": 3}
+            }"#,
+        )
+        .unwrap();
+        let device = Device::new("H6072", "AA:BB:CC:DD:EE:FF:00:11");
+        let other = Device::new("h6072", "AA:BB:CC:DD:EE:FF:00:22");
+        let unlisted = Device::new("H6159", "AA:BB:CC:DD:EE:FF:00:33");
+
+        // Devices may be identified by their topic safe id
+        assert_eq!(file.lookup(&device), Some(&2));
+        assert_eq!(file.for_sku(&device), Some(&1));
+        assert_eq!(file.lookup(&other), Some(&1));
+        assert_eq!(file.lookup(&unlisted), None);
+        assert!(DeviceConfigFile::<u32>::default().is_empty());
+    }
+}
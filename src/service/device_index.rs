@@ -0,0 +1,243 @@
+//! The copy of the devices that readers use, indexed by each of the
+//! labels that can be used to refer to a device, so that resolving a
+//! label doesn't require examining every device.
+use crate::service::device::Device;
+use crate::service::state::device_labels;
+use std::collections::HashMap;
+use std::sync::Arc;
+
+#[derive(Clone, Default)]
+pub struct DeviceIndex {
+    by_id: HashMap<String, Arc<Device>>,
+    /// Maps the lowercased labels to the device id
+    by_label: HashMap<String, String>,
+    /// Maps the BLE address of each device to the device id
+    by_ble_address: HashMap<String, String>,
+}
+
+/// Govee device ids for BLE devices are the MAC address with
+/// two additional bytes prefixed, so we index on the suffix
+fn ble_address_key(id: &str) -> Option<String> {
+    let hex = id.replace(':', "").to_ascii_uppercase();
+    if !hex.is_ascii() || hex.len() < 12 {
+        return None;
+    }
+    Some(hex[hex.len() - 12..].to_string())
+}
+
+impl DeviceIndex {
+    /// Returns a copy of the index that includes the changes to device
+    pub fn with_device(&self, device: Device) -> Self {
+        let mut index = self.clone();
+        let labels = device_labels(&device);
+
+        let prior_labels = index
+            .by_id
+            .get(&device.id)
+            .map(|prior| device_labels(prior));
+        index
+            .by_id
+            .insert(device.id.clone(), Arc::new(device.clone()));
+
+        // A label that the device no longer has passes to any
+        // other device that shares it
+        for label in prior_labels.unwrap_or_default() {
+            if labels.contains(&label) || index.by_label.get(&label) != Some(&device.id) {
+                continue;
+            }
+            match index
+                .by_id
+                .values()
+                .find(|other| other.id != device.id && device_labels(other).contains(&label))
+            {
+                Some(other) => {
+                    let id = other.id.clone();
+                    index.by_label.insert(label, id);
+                }
+                None => {
+                    index.by_label.remove(&label);
+                }
+            }
+        }
+        for label in labels {
+            index
+                .by_label
+                .entry(label)
+                .or_insert_with(|| device.id.clone());
+        }
+        if let Some(address) = ble_address_key(&device.id) {
+            index
+                .by_ble_address
+                .entry(address)
+                .or_insert_with(|| device.id.clone());
+        }
+
+        index
+    }
+
+    /// Returns a copy of the index that no longer includes
+    /// the device with id
+    pub fn without_device(&self, id: &str) -> Self {
+        let mut index = self.clone();
+        let Some(prior) = index.by_id.remove(id) else {
+            return index;
+        };
+
+        // Its labels pass to any other device that shares them
+        for label in device_labels(&prior) {
+            if index.by_label.get(&label).map(|other| other.as_str()) != Some(id) {
+                continue;
+            }
+            match index
+                .by_id
+                .values()
+                .find(|other| device_labels(other).contains(&label))
+            {
+                Some(other) => {
+                    let other_id = other.id.clone();
+                    index.by_label.insert(label, other_id);
+                }
+                None => {
+                    index.by_label.remove(&label);
+                }
+            }
+        }
+        if let Some(address) = ble_address_key(id) {
+            if index
+                .by_ble_address
+                .get(&address)
+                .map(|other| other.as_str())
+                == Some(id)
+            {
+                match index
+                    .by_id
+                    .values()
+                    .find(|other| ble_address_key(&other.id).as_ref() == Some(&address))
+                {
+                    Some(other) => {
+                        let other_id = other.id.clone();
+                        index.by_ble_address.insert(address, other_id);
+                    }
+                    None => {
+                        index.by_ble_address.remove(&address);
+                    }
+                }
+            }
+        }
+
+        index
+    }
+
+    pub fn get(&self, id: &str) -> Option<&Arc<Device>> {
+        self.by_id.get(id)
+    }
+
+    /// Find the device that label refers to, by name, id,
+    /// topic id or address
+    pub fn resolve(&self, label: &str) -> Option<&Arc<Device>> {
+        self.by_id.get(label).or_else(|| {
+            let id = self.by_label.get(&label.to_ascii_lowercase())?;
+            self.by_id.get(id)
+        })
+    }
+
+    /// Find the device that is advertising from address
+    pub fn find_by_ble_address(&self, address: &str) -> Option<&Arc<Device>> {
+        let id = self.by_ble_address.get(&ble_address_key(address)?)?;
+        self.by_id.get(id)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &Arc<Device>> {
+        self.by_id.values()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::service::hass::topic_safe_id;
+
+    #[test]
+    fn resolve() {
+        let lamp = Device::new("H6000", "AA:BB:CC:DD:EE:FF:00:01");
+        let strip = Device::new("H6199", "AA:BB:CC:DD:EE:FF:00:02");
+        let index = DeviceIndex::default()
+            .with_device(lamp.clone())
+            .with_device(strip.clone());
+
+        assert_eq!(index.values().count(), 2);
+        assert_eq!(index.resolve(&lamp.id).unwrap().id, lamp.id);
+        assert_eq!(
+            index.resolve("aa:bb:cc:dd:ee:ff:00:02").unwrap().id,
+            strip.id
+        );
+        assert_eq!(
+            index
+                .resolve(&topic_safe_id(&strip).to_ascii_uppercase())
+                .unwrap()
+                .id,
+            strip.id
+        );
+        assert_eq!(index.resolve(&strip.computed_name()).unwrap().id, strip.id);
+        assert!(index.resolve("nothing").is_none());
+
+        // Updating a device replaces its labels
+        let lan_device = |ip: &str| crate::lan_api::LanDevice {
+            ip: ip.parse().unwrap(),
+            device: lamp.id.clone(),
+            sku: lamp.sku.clone(),
+            ble_version_hard: String::new(),
+            ble_version_soft: String::new(),
+            wifi_version_hard: String::new(),
+            wifi_version_soft: String::new(),
+        };
+        let mut moved = lamp.clone();
+        moved.set_lan_device(lan_device("10.0.0.2"));
+        let index = index.with_device(moved.clone());
+        assert_eq!(index.resolve("10.0.0.2").unwrap().id, lamp.id);
+
+        moved.set_lan_device(lan_device("10.0.0.3"));
+        let index = index.with_device(moved.clone());
+        assert!(index.resolve("10.0.0.2").is_none());
+        assert_eq!(index.resolve("10.0.0.3").unwrap().id, lamp.id);
+
+        // and a label that is shared passes to the other device
+        let mut strip = strip;
+        strip.set_lan_device(lan_device("10.0.0.3"));
+        let index = index.with_device(strip.clone());
+        assert_eq!(index.resolve("10.0.0.3").unwrap().id, lamp.id);
+        moved.set_lan_device(lan_device("10.0.0.4"));
+        let index = index.with_device(moved);
+        assert_eq!(index.resolve("10.0.0.3").unwrap().id, strip.id);
+
+        // Removing a device removes its labels
+        let index = index.without_device(&strip.id);
+        assert_eq!(index.values().count(), 1);
+        assert!(index.get(&strip.id).is_none());
+        assert!(index.resolve("10.0.0.3").is_none());
+        assert!(index.resolve(&strip.computed_name()).is_none());
+        assert_eq!(index.resolve("10.0.0.4").unwrap().id, lamp.id);
+    }
+
+    #[test]
+    fn ble_address() {
+        let sensor = Device::new("H5075", "1A:2B:a4:c1:38:00:11:22");
+        let index = DeviceIndex::default().with_device(sensor.clone());
+        assert_eq!(
+            index.find_by_ble_address("A4:C1:38:00:11:22").unwrap().id,
+            sensor.id
+        );
+        assert!(index.find_by_ble_address("A4:C1:38:00:11:23").is_none());
+
+        // A sensor that we discovered via BLE has the address as its id
+        let discovered = Device::new("H5075", "A4:C1:38:00:11:23");
+        let index = index.with_device(discovered.clone());
+        assert_eq!(
+            index.find_by_ble_address("A4:C1:38:00:11:23").unwrap().id,
+            discovered.id
+        );
+
+        let index = index.without_device(&sensor.id);
+        assert!(index.find_by_ble_address("A4:C1:38:00:11:22").is_none());
+    }
+}
@@ -0,0 +1,152 @@
+//! Collects what is known about a device into a single JSON
+//! document that is suitable for attaching to an issue report.
+//! Addresses, ids and credentials are redacted.
+use crate::service::device::Device;
+use crate::service::hass::{base_topic, device_topic_id, IdParameter};
+use crate::service::state::{State as ServiceState, StateHandle};
+use crate::version_info::govee_version;
+use mosquitto_rs::router::{Params, State};
+use serde_json::{json, Value as JsonValue};
+
+/// Values for these keys, compared case-insensitively,
+/// are replaced in the diagnostics
+const REDACTED_KEYS: &[&str] = &[
+    "address",
+    "device",
+    "deviceid",
+    "email",
+    "ip",
+    "mac",
+    "password",
+    "secretcode",
+    "ssid",
+    "token",
+    "topic",
+    "wifimac",
+    "wifiname",
+];
+
+/// Replace the sensitive values in value. Strings that hold
+/// embedded JSON, as some of the undoc API fields do, are
+/// expanded so that their contents can be redacted too.
+pub fn redact(value: JsonValue) -> JsonValue {
+    match value {
+        JsonValue::Object(map) => map
+            .into_iter()
+            .map(|(key, value)| {
+                let redacted =
+                    !value.is_null() && REDACTED_KEYS.iter().any(|k| k.eq_ignore_ascii_case(&key));
+                if redacted {
+                    (key, JsonValue::String("REDACTED".to_string()))
+                } else {
+                    (key, redact(value))
+                }
+            })
+            .collect(),
+        JsonValue::Array(items) => items.into_iter().map(redact).collect(),
+        JsonValue::String(s) if s.starts_with('{') || s.starts_with('[') => {
+            match serde_json::from_str(&s) {
+                Ok(embedded) => redact(embedded),
+                Err(_) => JsonValue::String(s),
+            }
+        }
+        value => value,
+    }
+}
+
+impl ServiceState {
+    pub async fn device_diagnostics(&self, device: &Device) -> JsonValue {
+        let scenes = match self.device_list_scenes(device).await {
+            Ok(scenes) => json!(scenes),
+            Err(err) => json!({"error": format!("{err:#}")}),
+        };
+
+        redact(json!({
+            "version": govee_version(),
+            "sku": device.sku,
+            "device": device.id,
+            "name": device.name(),
+            "room": device.room_name(),
+            "transports": device.transports(),
+            "platform": {
+                "info": device.http_device_info,
+                "state": device.http_device_state,
+                "updated": device.last_http_device_state_update,
+            },
+            "undoc": device.undoc_device_info.as_ref().map(|info| &info.entry),
+            "lan": {
+                "discovery": device.lan_device,
+                "status": device.lan_device_status,
+                "updated": device.last_lan_device_status_update,
+            },
+            "iot": {
+                "status": device.iot_device_status,
+                "payload": device.last_iot_payload,
+                "updated": device.last_iot_device_status_update,
+            },
+            "scenes": scenes,
+        }))
+    }
+}
+
+pub fn diagnostics_topic(device: &Device) -> String {
+    format!(
+        "{}/{id}/diagnostics",
+        base_topic(),
+        id = device_topic_id(device)
+    )
+}
+
+/// Publishes the diagnostics for the device to diagnostics_topic
+pub async fn mqtt_device_diagnose(
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_device_diagnose: {id}");
+    let device = state.resolve_device_read_only(&id).await?;
+    let diagnostics = state.device_diagnostics(&device).await;
+
+    let hass = state
+        .get_hass_client()
+        .await
+        .ok_or_else(|| anyhow::anyhow!("no hass client"))?;
+    hass.publish_obj(diagnostics_topic(&device), diagnostics)
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn redaction() {
+        let value = json!({
+            "sku": "H6072",
+            "device": "AA:BB:CC:DD:EE:FF:00:11",
+            "lan": {"discovery": {"ip": "10.0.0.5", "sku": "H6072"}},
+            "undoc": {
+                "deviceExt": {
+                    "deviceSettings": "{\"wifiMac\":\"aa:bb\",\"topic\":\"secret\",\"ic\":1}",
+                },
+            },
+            "iot": {"payload": null, "ip": null},
+            "scenes": ["Aurora", "{not json"],
+        });
+
+        assert_eq!(
+            redact(value),
+            json!({
+                "sku": "H6072",
+                "device": "REDACTED",
+                "lan": {"discovery": {"ip": "REDACTED", "sku": "H6072"}},
+                "undoc": {
+                    "deviceExt": {
+                        "deviceSettings": {"wifiMac": "REDACTED", "topic": "REDACTED", "ic": 1},
+                    },
+                },
+                "iot": {"payload": null, "ip": null},
+                "scenes": ["Aurora", "{not json"],
+            })
+        );
+    }
+}
@@ -0,0 +1,177 @@
+//! Allows the hass discovery payloads to be customized without code
+//! changes. Each payload is adjusted by the JSON merge patches
+//! (RFC 7386) that match its entity before it is published.
+use crate::hass_mqtt::base::EntityConfig;
+use crate::service::device::Device;
+use crate::service::device_config::{load_config_file, DeviceConfigFile};
+use crate::service::hass::topic_safe_id;
+use crate::service::state::State as ServiceState;
+use serde_json::Value as JsonValue;
+use std::collections::HashMap;
+use std::path::Path;
+
+/// Merge patches keyed by the entities that they apply to:
+/// `*` for all of the entities of the device, an integration such
+/// as `sensor`, or the unique id of the entity, or its suffix
+/// after the device id, such as `powerSwitch`
+pub type EntityPatches = HashMap<String, JsonValue>;
+
+/// The patches for the SKU are applied first, so that those for
+/// the device take precedence over them
+pub type DiscoveryOverrideFile = DeviceConfigFile<EntityPatches>;
+
+/// How specifically the key identifies the entity; more
+/// specific patches are applied later
+fn key_rank(key: &str, integration: &str, unique_id: &str) -> Option<u8> {
+    if key == "*" {
+        Some(0)
+    } else if key.eq_ignore_ascii_case(integration) {
+        Some(1)
+    } else if unique_id == key || unique_id.ends_with(&format!("-{key}")) {
+        Some(2)
+    } else {
+        None
+    }
+}
+
+fn matching_patches<'a>(
+    patches: &'a EntityPatches,
+    integration: &str,
+    unique_id: &str,
+) -> Vec<&'a JsonValue> {
+    let mut matches: Vec<_> = patches
+        .iter()
+        .filter_map(|(key, patch)| Some((key_rank(key, integration, unique_id)?, patch)))
+        .collect();
+    matches.sort_by_key(|(rank, _)| *rank);
+    matches.into_iter().map(|(_, patch)| patch).collect()
+}
+
+impl DiscoveryOverrideFile {
+    /// The patches that apply to an entity of device, in the
+    /// order in which they are to be applied
+    pub fn patches_for(
+        &self,
+        device: &Device,
+        integration: &str,
+        unique_id: &str,
+    ) -> Vec<&JsonValue> {
+        self.sku_entries(device)
+            .chain(self.device_entries(device))
+            .flat_map(|entities| matching_patches(entities, integration, unique_id))
+            .collect()
+    }
+}
+
+/// Apply an RFC 7386 JSON merge patch to target
+pub fn merge_patch(target: &mut JsonValue, patch: &JsonValue) {
+    let JsonValue::Object(patch) = patch else {
+        *target = patch.clone();
+        return;
+    };
+    if !target.is_object() {
+        *target = JsonValue::Object(Default::default());
+    }
+    let target = target.as_object_mut().expect("target to be an object");
+    for (key, value) in patch {
+        if value.is_null() {
+            target.remove(key);
+        } else {
+            merge_patch(target.entry(key.as_str()).or_insert(JsonValue::Null), value);
+        }
+    }
+}
+
+pub fn load_discovery_override_file(path: &Path) -> anyhow::Result<DiscoveryOverrideFile> {
+    load_config_file(path, "discovery overrides")
+}
+
+impl ServiceState {
+    /// Apply the configured overrides to the discovery payload of
+    /// an entity. Returns None if the entity has been disabled by
+    /// a patch of null.
+    pub async fn apply_discovery_overrides(
+        &self,
+        integration: &str,
+        base: &EntityConfig,
+        mut config: JsonValue,
+    ) -> Option<JsonValue> {
+        let overrides = self.get_discovery_overrides().await;
+        if overrides.is_empty() {
+            return Some(config);
+        }
+
+        let devices = self.devices().await;
+        let Some(device) = devices.iter().find(|d| {
+            let identifier = format!("gv2mqtt-{}", topic_safe_id(d));
+            base.device.identifiers.contains(&identifier)
+        }) else {
+            return Some(config);
+        };
+
+        for patch in overrides.patches_for(device, integration, &base.unique_id) {
+            merge_patch(&mut config, patch);
+        }
+        if config.is_null() {
+            log::info!("{} is disabled by the discovery overrides", base.unique_id);
+            return None;
+        }
+        Some(config)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn patches() {
+        let mut config = json!({"name": "Power Switch", "icon": "mdi:power", "optimistic": false});
+        merge_patch(
+            &mut config,
+            &json!({"icon": null, "entity_category": "config"}),
+        );
+        assert_eq!(
+            config,
+            json!({"name": "Power Switch", "optimistic": false, "entity_category": "config"})
+        );
+        merge_patch(&mut config, &JsonValue::Null);
+        assert!(config.is_null());
+
+        let file: DiscoveryOverrideFile = serde_json::from_value(json!({
+            "skus": {
+                "H6072": {
+                    "*": {"icon": "mdi:lamp"},
+                    "powerSwitch": {"name": "Power"},
+                    "sensor": {"entity_category": "diagnostic"}
+                }
+            },
+            "devices": {
+                "AA:BB:CC:DD:EE:FF:42:2A": {"powerSwitch": null}
+            }
+        }))
+        .unwrap();
+
+        let device = Device::new("H6072", "AA:BB:CC:DD:EE:FF:42:2A");
+        let unique_id = format!("gv2mqtt-{}-powerSwitch", topic_safe_id(&device));
+        assert_eq!(
+            file.patches_for(&device, "switch", &unique_id),
+            vec![
+                &json!({"icon": "mdi:lamp"}),
+                &json!({"name": "Power"}),
+                &JsonValue::Null
+            ]
+        );
+        assert_eq!(
+            file.patches_for(&device, "sensor", "gv2mqtt-other"),
+            vec![
+                &json!({"icon": "mdi:lamp"}),
+                &json!({"entity_category": "diagnostic"})
+            ]
+        );
+
+        let other = Device::new("H6199", "AA:BB:CC:DD:EE:FF:42:2B");
+        assert!(file.patches_for(&other, "switch", &unique_id).is_empty());
+    }
+}
@@ -0,0 +1,72 @@
+//! Removes entities that hass still knows about but that no longer
+//! exist, such as those of devices that were removed from the Govee
+//! account, or whose unique id changed along with the SKU or quirks.
+use crate::service::state::{State as ServiceState, StateHandle};
+use mosquitto_rs::router::{Params, Payload, State};
+use serde::Deserialize;
+use std::sync::Arc;
+
+/// The prefix of the unique ids of all of our entities
+const UNIQUE_ID_PREFIX: &str = "gv2mqtt-";
+
+impl ServiceState {
+    /// Publish fresh discovery configs for the current entities,
+    /// and then delete any previously seen configs that were not
+    /// republished. Returns the deleted topics.
+    pub async fn purge_discovery(self: &Arc<Self>) -> anyhow::Result<Vec<String>> {
+        let hass = self
+            .get_hass_client()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("hass client is not available"))?;
+
+        let prior = self.take_discovery_topics().await;
+        hass.register_with_hass(self).await?;
+        let current = self.get_discovery_topics().await;
+
+        let mut stale: Vec<String> = prior.difference(&current).cloned().collect();
+        stale.sort();
+        for topic in &stale {
+            log::info!("Removing stale discovery config {topic}");
+            hass.clear_retained(topic).await?;
+        }
+        log::info!(
+            "Purged {} stale discovery configs; {} are current",
+            stale.len(),
+            current.len()
+        );
+        Ok(stale)
+    }
+}
+
+#[derive(Deserialize)]
+pub struct DiscoveryConfigParams {
+    integration: String,
+    unique_id: String,
+}
+
+/// Observes the discovery configs on the broker, so that those
+/// left over from earlier runs can be purged
+pub async fn mqtt_discovery_config(
+    Payload(payload): Payload<String>,
+    Params(DiscoveryConfigParams {
+        integration,
+        unique_id,
+    }): Params<DiscoveryConfigParams>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    if !unique_id.starts_with(UNIQUE_ID_PREFIX) {
+        return Ok(());
+    }
+    let disco = state.get_hass_disco_prefix().await;
+    let topic = format!("{disco}/{integration}/{unique_id}/config");
+    state
+        .record_discovery_topic(&topic, !payload.is_empty())
+        .await;
+    Ok(())
+}
+
+pub async fn mqtt_purge_discovery(State(state): State<StateHandle>) -> anyhow::Result<()> {
+    log::info!("mqtt_purge_discovery");
+    state.purge_discovery().await?;
+    Ok(())
+}
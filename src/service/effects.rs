@@ -0,0 +1,721 @@
+use crate::lan_api::DeviceColor;
+use crate::platform_api::DeviceType;
+use crate::service::device::Device;
+use crate::service::hass::IdParameter;
+use crate::service::snapshot::DeviceSnapshot;
+use crate::service::state::{State, StateHandle};
+use mosquitto_rs::router::{Params, Payload, State as RouterState};
+use serde::Deserialize;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use tokio::time::{sleep, Duration, Instant};
+
+/// How frequently we send updates to devices that we can reach via the LAN
+const LAN_FRAME_INTERVAL: Duration = Duration::from_millis(250);
+/// How frequently we send the intermediate steps of a transition,
+/// unless overridden via --transition-interval
+pub const DEFAULT_TRANSITION_INTERVAL: Duration = LAN_FRAME_INTERVAL;
+/// How frequently we send updates to devices that we control via IoT.
+/// This is much lower than LAN as each frame is a round trip through
+/// the Govee cloud.
+const IOT_FRAME_INTERVAL: Duration = Duration::from_secs(1);
+
+const COLOR_CYCLE_PERIOD: f64 = 30.;
+const BREATHE_PERIOD: f64 = 6.;
+const SUNRISE_DURATION: Duration = Duration::from_secs(15 * 60);
+
+/// An effect that is rendered by govee2mqtt itself, by streaming
+/// a sequence of color changes to the device, rather than relying
+/// on the scenes that are built into the device firmware.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LocalEffect {
+    ColorCycle,
+    Breathe,
+    Candle,
+    Sunrise,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct EffectFrame {
+    pub color: DeviceColor,
+    pub brightness: Option<u8>,
+}
+
+impl LocalEffect {
+    pub const ALL: [LocalEffect; 4] = [
+        LocalEffect::ColorCycle,
+        LocalEffect::Breathe,
+        LocalEffect::Candle,
+        LocalEffect::Sunrise,
+    ];
+
+    /// The name that is shown in the effect list in Home Assistant
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::ColorCycle => "Local Color Cycle",
+            Self::Breathe => "Local Breathe",
+            Self::Candle => "Local Candle",
+            Self::Sunrise => "Local Sunrise",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .find(|effect| effect.name().eq_ignore_ascii_case(name))
+            .copied()
+    }
+
+    /// If the effect has a natural end, returns its duration.
+    /// Effects without a duration run until cancelled.
+    pub fn duration(&self) -> Option<Duration> {
+        match self {
+            Self::Sunrise => Some(SUNRISE_DURATION),
+            _ => None,
+        }
+    }
+
+    /// Compute the frame to show at the specified point in time.
+    /// `base` is the color of the device at the time the effect started,
+    /// and `seed` is state used for pseudo-random effects.
+    pub fn frame(&self, elapsed: Duration, base: DeviceColor, seed: &mut u32) -> EffectFrame {
+        let secs = elapsed.as_secs_f64();
+        match self {
+            Self::ColorCycle => EffectFrame {
+                color: hue_to_rgb((secs * 360. / COLOR_CYCLE_PERIOD) % 360.),
+                brightness: None,
+            },
+            Self::Breathe => {
+                let phase = (secs / BREATHE_PERIOD) * std::f64::consts::TAU;
+                let level = 0.5 - 0.5 * phase.cos();
+                EffectFrame {
+                    color: base,
+                    brightness: Some((10. + 90. * level).round() as u8),
+                }
+            }
+            Self::Candle => {
+                let noise = xorshift(seed) % 100;
+                EffectFrame {
+                    color: DeviceColor {
+                        r: 255,
+                        g: 120 + (noise % 30) as u8,
+                        b: 20,
+                    },
+                    brightness: Some(45 + (noise * 55 / 100) as u8),
+                }
+            }
+            Self::Sunrise => sunrise_frame(elapsed, SUNRISE_DURATION),
+        }
+    }
+}
+
+/// Computes a frame along a sunrise ramp that takes `duration`
+/// to go from a dim red glow to bright warm white
+pub fn sunrise_frame(elapsed: Duration, duration: Duration) -> EffectFrame {
+    const STOPS: [(f64, DeviceColor, f64); 4] = [
+        (0., rgb(120, 10, 0), 1.),
+        (0.3, rgb(255, 60, 0), 20.),
+        (0.7, rgb(255, 150, 40), 60.),
+        (1., rgb(255, 220, 170), 100.),
+    ];
+
+    let progress = if duration.is_zero() {
+        1.
+    } else {
+        (elapsed.as_secs_f64() / duration.as_secs_f64()).clamp(0., 1.)
+    };
+
+    let upper = STOPS
+        .iter()
+        .position(|(pos, _, _)| *pos >= progress)
+        .unwrap_or(STOPS.len() - 1)
+        .max(1);
+    let (p0, c0, b0) = STOPS[upper - 1];
+    let (p1, c1, b1) = STOPS[upper];
+    let t = (progress - p0) / (p1 - p0);
+
+    let lerp = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+
+    EffectFrame {
+        color: DeviceColor {
+            r: lerp(c0.r, c1.r),
+            g: lerp(c0.g, c1.g),
+            b: lerp(c0.b, c1.b),
+        },
+        brightness: Some((b0 + (b1 - b0) * t).round().max(1.) as u8),
+    }
+}
+
+/// Computes the frame that is `progress` (0.0-1.0) of the way
+/// along a transition from `from` to `to`
+pub fn transition_frame(from: &EffectFrame, to: &EffectFrame, progress: f64) -> EffectFrame {
+    let t = progress.clamp(0., 1.);
+    let lerp = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+
+    EffectFrame {
+        color: DeviceColor {
+            r: lerp(from.color.r, to.color.r),
+            g: lerp(from.color.g, to.color.g),
+            b: lerp(from.color.b, to.color.b),
+        },
+        brightness: match (from.brightness, to.brightness) {
+            (Some(a), Some(b)) => Some(lerp(a, b)),
+            (_, b) => b,
+        },
+    }
+}
+
+/// The state that a light command transitions towards
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TransitionTarget {
+    /// The target brightness, if the command changes it
+    pub brightness: Option<u8>,
+    /// The target color, if the command changes it
+    pub color: Option<DeviceColor>,
+    /// True if the command turns the light off
+    pub off: bool,
+}
+
+/// The step that applies the originating command once a
+/// transition has reached its target
+pub type TransitionCompletion = Pin<Box<dyn Future<Output = ()> + Send>>;
+
+const fn rgb(r: u8, g: u8, b: u8) -> DeviceColor {
+    DeviceColor { r, g, b }
+}
+
+/// Convert a hue in degrees to a fully saturated RGB color
+pub fn hue_to_rgb(hue: f64) -> DeviceColor {
+    let h = (hue.rem_euclid(360.)) / 60.;
+    let x = 1. - ((h % 2.) - 1.).abs();
+    let (r, g, b) = match h as u32 {
+        0 => (1., x, 0.),
+        1 => (x, 1., 0.),
+        2 => (0., 1., x),
+        3 => (0., x, 1.),
+        4 => (x, 0., 1.),
+        _ => (1., 0., x),
+    };
+    DeviceColor {
+        r: (r * 255.0f64).round() as u8,
+        g: (g * 255.0f64).round() as u8,
+        b: (b * 255.0f64).round() as u8,
+    }
+}
+
+fn xorshift(state: &mut u32) -> u32 {
+    let mut x = *state;
+    x ^= x << 13;
+    x ^= x >> 17;
+    x ^= x << 5;
+    *state = x;
+    x
+}
+
+/// Describes a notification flash
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub struct FlashParams {
+    /// How many times to flash
+    #[serde(default = "default_flash_count")]
+    pub count: u32,
+    /// Any CSS color specification, such as `red` or `#ff0000`
+    #[serde(default = "default_flash_color")]
+    pub color: String,
+    /// How long the light stays on, and then off, for each flash
+    #[serde(default = "default_flash_interval_ms")]
+    pub interval_ms: u64,
+}
+
+fn default_flash_count() -> u32 {
+    3
+}
+
+fn default_flash_color() -> String {
+    "white".to_string()
+}
+
+fn default_flash_interval_ms() -> u64 {
+    500
+}
+
+impl Default for FlashParams {
+    fn default() -> Self {
+        Self {
+            count: default_flash_count(),
+            color: default_flash_color(),
+            interval_ms: default_flash_interval_ms(),
+        }
+    }
+}
+
+impl FlashParams {
+    pub fn rgb(&self) -> anyhow::Result<DeviceColor> {
+        let color = csscolorparser::parse(&self.color)
+            .map_err(|err| anyhow::anyhow!("error parsing color '{}': {err}", self.color))?;
+        let [r, g, b, _a] = color.to_rgba8();
+        Ok(DeviceColor { r, g, b })
+    }
+}
+
+/// The means by which we deliver frames to the device
+enum FrameSink {
+    Lan(crate::lan_api::LanDevice),
+    Iot(
+        crate::service::iot::IotClient,
+        Box<crate::undoc_api::DeviceEntry>,
+    ),
+}
+
+impl FrameSink {
+    fn interval(&self) -> Duration {
+        match self {
+            Self::Lan(_) => LAN_FRAME_INTERVAL,
+            Self::Iot(..) => IOT_FRAME_INTERVAL,
+        }
+    }
+
+    async fn send_power(&self, on: bool) -> anyhow::Result<()> {
+        match self {
+            Self::Lan(device) => device.send_turn(on).await,
+            Self::Iot(iot, entry) => iot.set_power_state(entry, on).await,
+        }
+    }
+
+    async fn send(&self, frame: &EffectFrame, prior: Option<&EffectFrame>) -> anyhow::Result<()> {
+        let color_changed = prior.map(|p| p.color != frame.color).unwrap_or(true);
+        let brightness_changed = prior
+            .map(|p| p.brightness != frame.brightness)
+            .unwrap_or(true);
+
+        match self {
+            Self::Lan(device) => {
+                if color_changed {
+                    device.send_color_rgb(frame.color).await?;
+                }
+                if let Some(brightness) = frame.brightness.filter(|_| brightness_changed) {
+                    device.send_brightness(brightness).await?;
+                }
+            }
+            Self::Iot(iot, entry) => {
+                if color_changed {
+                    let DeviceColor { r, g, b } = frame.color;
+                    iot.set_color_rgb(entry, r, g, b).await?;
+                }
+                if let Some(brightness) = frame.brightness.filter(|_| brightness_changed) {
+                    iot.set_brightness(entry, brightness).await?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+impl State {
+    /// Returns true if we are able to run local effects on the device
+    pub async fn device_supports_local_effects(&self, device: &Device) -> bool {
+        if !device.supports_rgb() {
+            return false;
+        }
+        self.effect_sink_for_device(device).await.is_some()
+    }
+
+    async fn effect_sink_for_device(&self, device: &Device) -> Option<FrameSink> {
+        if self.is_dry_run().await {
+            return None;
+        }
+        if let Some(lan_dev) = &device.lan_device {
+            return Some(FrameSink::Lan(lan_dev.clone()));
+        }
+        if device.iot_api_supported() {
+            if let Some(iot) = self.get_iot_client().await {
+                if let Some(info) = &device.undoc_device_info {
+                    return Some(FrameSink::Iot(iot, Box::new(info.entry.clone())));
+                }
+            }
+        }
+        None
+    }
+
+    /// Start running a local effect on the device, replacing any
+    /// local effect that may already be running on it
+    pub async fn start_local_effect(
+        self: &Arc<Self>,
+        device: &Device,
+        effect: LocalEffect,
+    ) -> anyhow::Result<()> {
+        let sink = self.effect_sink_for_device(device).await.ok_or_else(|| {
+            anyhow::anyhow!(
+                "Local effects require LAN or IoT control, which are not available for {device}"
+            )
+        })?;
+
+        self.stop_local_effect(&device.id).await;
+
+        let base = device
+            .device_state()
+            .map(|s| s.color)
+            .filter(|c| *c != DeviceColor::default())
+            .unwrap_or(DeviceColor {
+                r: 255,
+                g: 255,
+                b: 255,
+            });
+        let device_name = device.to_string();
+        let device_id = device.id.clone();
+
+        log::info!("Starting local effect {} on {device}", effect.name());
+
+        let state = self.clone();
+        let task = tokio::spawn(async move {
+            let start = Instant::now();
+            let interval = sink.interval();
+            let mut seed = 0x9e37_79b9u32;
+            let mut prior: Option<EffectFrame> = None;
+
+            loop {
+                let mut elapsed = start.elapsed();
+                let finished = match effect.duration() {
+                    Some(duration) if elapsed >= duration => {
+                        elapsed = duration;
+                        true
+                    }
+                    _ => false,
+                };
+
+                let frame = effect.frame(elapsed, base, &mut seed);
+                if prior.as_ref() != Some(&frame) {
+                    if let Err(err) = sink.send(&frame, prior.as_ref()).await {
+                        log::error!("Local effect {} on {device_name}: {err:#}", effect.name());
+                    }
+                    prior.replace(frame);
+                }
+
+                if finished {
+                    break;
+                }
+                sleep(interval).await;
+            }
+
+            log::info!("Local effect {} on {device_name} completed", effect.name());
+            state.take_local_effect(&device_id).await;
+        });
+
+        if let Some(prior) = self.set_local_effect(&device.id, task.abort_handle()).await {
+            prior.abort();
+        }
+
+        Ok(())
+    }
+
+    /// Start ramping the brightness and color of the device from its
+    /// current state to `target` over `duration`, by streaming
+    /// intermediate steps via the LAN API, as Govee firmware has no
+    /// native support for transitions. Once the target is reached,
+    /// `completion` runs so that the command is applied and reported
+    /// as usual.
+    /// Returns false without starting anything if the device cannot
+    /// be controlled via the LAN, or if there is nothing to ramp;
+    /// the caller should then apply the command immediately.
+    pub async fn start_light_transition(
+        self: &Arc<Self>,
+        device: &Device,
+        target: TransitionTarget,
+        duration: Duration,
+        completion: TransitionCompletion,
+    ) -> anyhow::Result<bool> {
+        let Some(sink @ FrameSink::Lan(_)) = self.effect_sink_for_device(device).await else {
+            return Ok(false);
+        };
+
+        let current = device.device_state();
+        let is_on = current
+            .as_ref()
+            .map(|s| s.light_on.unwrap_or(s.on))
+            .unwrap_or(false);
+        if !is_on && target.off {
+            return Ok(false);
+        }
+        let current_brightness = current.as_ref().map(|s| s.brightness).unwrap_or(0);
+        let current_color = current
+            .as_ref()
+            .map(|s| s.color)
+            .filter(|c| *c != DeviceColor::default());
+
+        let from = EffectFrame {
+            color: current_color.or(target.color).unwrap_or(rgb(255, 255, 255)),
+            brightness: Some(if is_on { current_brightness } else { 0 }),
+        };
+        let to_brightness = match target.brightness {
+            _ if target.off => 0,
+            Some(brightness) => brightness,
+            None if is_on && current_brightness > 0 => current_brightness,
+            // Coming on from off; return to the level it was at
+            // before it was last faded out, or was last on at
+            None => device
+                .fade_out_brightness
+                .or(Some(current_brightness).filter(|b| *b > 0))
+                .unwrap_or(100),
+        };
+        let to = EffectFrame {
+            color: target.color.unwrap_or(from.color),
+            brightness: Some(to_brightness),
+        };
+        if from == to {
+            return Ok(false);
+        }
+
+        self.stop_local_effect(&device.id).await;
+        self.device_mut(&device.sku, &device.id)
+            .await
+            .fade_out_brightness = if target.off {
+            Some(current_brightness).filter(|b| *b > 0)
+        } else {
+            None
+        };
+
+        let interval = self.get_transition_interval().await;
+        let device_name = device.to_string();
+        let device_id = device.id.clone();
+
+        log::info!("Transitioning {device} over {duration:?}: {from:?} -> {to:?}");
+
+        let state = self.clone();
+        let task = tokio::spawn(async move {
+            let start = Instant::now();
+            // Zero brightness is not something that we can send,
+            // so the ramp bottoms out at 1%
+            let clamp = |frame: EffectFrame| EffectFrame {
+                brightness: frame.brightness.map(|b| b.max(1)),
+                ..frame
+            };
+            let mut prior: Option<EffectFrame> = None;
+
+            if !is_on {
+                let first = clamp(from);
+                let result = match sink.send(&first, None).await {
+                    Ok(()) => sink.send_power(true).await,
+                    err => err,
+                };
+                if let Err(err) = result {
+                    log::error!("Transition on {device_name}: {err:#}");
+                }
+                prior.replace(first);
+            }
+
+            loop {
+                let progress = if duration.is_zero() {
+                    1.
+                } else {
+                    start.elapsed().as_secs_f64() / duration.as_secs_f64()
+                };
+
+                let frame = clamp(transition_frame(&from, &to, progress));
+                if prior.as_ref() != Some(&frame) {
+                    if let Err(err) = sink.send(&frame, prior.as_ref()).await {
+                        log::error!("Transition on {device_name}: {err:#}");
+                    }
+                    prior.replace(frame);
+                }
+
+                if progress >= 1. {
+                    break;
+                }
+                sleep(interval).await;
+            }
+
+            state.take_local_effect(&device_id).await;
+            completion.await;
+        });
+
+        if let Some(prior) = self.set_local_effect(&device.id, task.abort_handle()).await {
+            prior.abort();
+        }
+
+        Ok(true)
+    }
+
+    /// Flash the device in the specified color, and then restore
+    /// it to the state that it was in prior to flashing
+    pub async fn flash_device(
+        self: &Arc<Self>,
+        label: &str,
+        params: FlashParams,
+    ) -> anyhow::Result<()> {
+        let color = params.rgb()?;
+        let device = self.resolve_device_for_control(label).await?;
+        self.stop_local_effect(&device.id).await;
+        // This is kept locally, so that any snapshot that the
+        // user has taken remains available to them
+        let snapshot = DeviceSnapshot::of_device(&device)?;
+
+        log::info!("Flashing {device}: {params:?}");
+        let result = self.run_flash(&device, color, &params).await;
+
+        // Release control of the device so that it can be restored
+        drop(device);
+        let restored = self.restore_device_to(label, &snapshot).await;
+        result.and(restored)
+    }
+
+    async fn run_flash(
+        self: &Arc<Self>,
+        device: &Device,
+        color: DeviceColor,
+        params: &FlashParams,
+    ) -> anyhow::Result<()> {
+        let interval = Duration::from_millis(params.interval_ms);
+        // Prefer to send directly via LAN or IoT, as the latency
+        // of the Platform API makes for a rather sluggish flash
+        let sink = self.effect_sink_for_device(device).await;
+        let is_light = device.device_type() == DeviceType::Light;
+        let frame = EffectFrame {
+            color,
+            brightness: Some(100),
+        };
+
+        for _ in 0..params.count {
+            match &sink {
+                Some(sink) => {
+                    sink.send_power(true).await?;
+                    sink.send(&frame, None).await?;
+                }
+                None => {
+                    self.device_set_color_rgb(device, color.r, color.g, color.b)
+                        .await?;
+                    self.device_set_brightness(device, 100).await?;
+                }
+            }
+            sleep(interval).await;
+
+            match &sink {
+                Some(sink) => sink.send_power(false).await?,
+                None if is_light => self.device_light_power_on(device, false).await?,
+                None => self.device_set_brightness(device, 0).await?,
+            }
+            sleep(interval).await;
+        }
+        Ok(())
+    }
+
+    /// Stop any local effect that is running on the device.
+    /// Returns true if an effect was stopped.
+    pub async fn stop_local_effect(&self, device_id: &str) -> bool {
+        match self.take_local_effect(device_id).await {
+            Some(handle) => {
+                log::info!("Stopping local effect on {device_id}");
+                handle.abort();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Flash the device. The payload is an optional JSON FlashParams object
+pub async fn mqtt_device_flash(
+    Payload(payload): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    RouterState(state): RouterState<StateHandle>,
+) -> anyhow::Result<()> {
+    let payload = payload.trim();
+    let params = if payload.is_empty() {
+        FlashParams::default()
+    } else {
+        serde_json::from_str(payload)?
+    };
+    state.flash_device(&id, params).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn flash_keeps_user_snapshot() {
+        let state = Arc::new(State::new());
+        state.set_dry_run(true).await;
+        state
+            .device_mut("H6000", "AA:BB")
+            .await
+            .set_lan_device_status(crate::lan_api::DeviceStatus {
+                on: true,
+                brightness: 50,
+                ..Default::default()
+            });
+        let device = state.device_by_id("AA:BB").await.unwrap();
+        let snapshot = state.snapshot_device(&device).await.unwrap();
+
+        let params = FlashParams {
+            count: 1,
+            interval_ms: 1,
+            ..FlashParams::default()
+        };
+        state.flash_device("AA:BB", params).await.unwrap();
+
+        // The device is back on at its prior brightness, and the
+        // user's snapshot is still there to be restored
+        let device = state.device_by_id("AA:BB").await.unwrap();
+        let restored = DeviceSnapshot::of_device(&device).unwrap();
+        assert!(restored.on);
+        assert_eq!(restored.brightness, 50);
+        assert_eq!(state.take_snapshot("AA:BB").await, Some(snapshot));
+    }
+
+    #[test]
+    fn hue() {
+        assert_eq!(hue_to_rgb(0.), rgb(255, 0, 0));
+        assert_eq!(hue_to_rgb(120.), rgb(0, 255, 0));
+        assert_eq!(hue_to_rgb(240.), rgb(0, 0, 255));
+        assert_eq!(hue_to_rgb(360.), rgb(255, 0, 0));
+    }
+
+    #[test]
+    fn sunrise() {
+        let duration = Duration::from_secs(100);
+
+        let start = sunrise_frame(Duration::ZERO, duration);
+        assert_eq!(start.color, rgb(120, 10, 0));
+        assert_eq!(start.brightness, Some(1));
+
+        let end = sunrise_frame(duration, duration);
+        assert_eq!(end.color, rgb(255, 220, 170));
+        assert_eq!(end.brightness, Some(100));
+
+        assert_eq!(
+            LocalEffect::from_name("local sunrise"),
+            Some(LocalEffect::Sunrise)
+        );
+    }
+
+    #[test]
+    fn transition() {
+        let from = EffectFrame {
+            color: rgb(255, 0, 0),
+            brightness: Some(0),
+        };
+        let to = EffectFrame {
+            color: rgb(0, 0, 255),
+            brightness: Some(100),
+        };
+
+        assert_eq!(transition_frame(&from, &to, 0.), from);
+        assert_eq!(transition_frame(&from, &to, 1.), to);
+        assert_eq!(transition_frame(&from, &to, 2.), to);
+        assert_eq!(
+            transition_frame(&from, &to, 0.5),
+            EffectFrame {
+                color: rgb(128, 0, 128),
+                brightness: Some(50),
+            }
+        );
+    }
+
+    #[test]
+    fn flash_params() {
+        let params: FlashParams = serde_json::from_str(r##"{"color": "#00ff00"}"##).unwrap();
+        assert_eq!(params.count, 3);
+        assert_eq!(params.rgb().unwrap(), rgb(0, 255, 0));
+        assert_eq!(FlashParams::default().rgb().unwrap(), rgb(255, 255, 255));
+    }
+}
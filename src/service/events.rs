@@ -0,0 +1,188 @@
+//! Changes to the devices are broadcast as events, so that the code
+//! that changes a device doesn't need to know about each of the
+//! consumers, such as hass, that need to hear about it.
+use crate::service::state::{State as ServiceState, StateHandle};
+use crate::service::transport::Transport;
+use crate::service::watchdog::Heartbeat;
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+use tokio::time::{timeout, Duration};
+
+/// How many events may be queued for a subscriber before it
+/// starts to miss them
+const EVENT_CAPACITY: usize = 256;
+/// How often the hass publisher lets the watchdog know that it
+/// is still running when there are no events to publish
+const IDLE_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceEvent {
+    /// The state of the device changed
+    StateChanged { device_id: String },
+    /// The device was discovered after startup
+    DeviceAdded { device_id: String },
+    /// The device was removed, such as from the Govee account
+    DeviceRemoved { device_id: String },
+    /// We lost our connection to the transport, so the devices
+    /// that rely on it may become unavailable
+    TransportLost { transport: Transport },
+}
+
+pub struct EventBus {
+    tx: broadcast::Sender<DeviceEvent>,
+}
+
+impl Default for EventBus {
+    fn default() -> Self {
+        let (tx, _rx) = broadcast::channel(EVENT_CAPACITY);
+        Self { tx }
+    }
+}
+
+impl EventBus {
+    pub fn emit(&self, event: DeviceEvent) {
+        log::trace!("event: {event:?}");
+        // An error just means that there are no subscribers
+        self.tx.send(event).ok();
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<DeviceEvent> {
+        self.tx.subscribe()
+    }
+}
+
+impl ServiceState {
+    /// Tell hass, and any other subscribers, that the state
+    /// of the device has changed
+    pub fn emit_state_changed(&self, device_id: &str) {
+        self.emit_event(DeviceEvent::StateChanged {
+            device_id: device_id.to_string(),
+        });
+    }
+
+    /// Publish the availability and state of the device
+    /// and of the light groups that include it
+    async fn publish_state_change(self: &StateHandle, device_id: &str) -> anyhow::Result<()> {
+        // The device may have been removed since the event was queued
+        let Some(canonical_device) = self.device_for_entity(device_id).await else {
+            return Ok(());
+        };
+
+        self.publish_device_availability(&canonical_device, false)
+            .await?;
+
+        if let Some(hass) = self.get_hass_client().await {
+            hass.advise_hass_of_light_state(&canonical_device, self, false)
+                .await?;
+        }
+
+        self.notify_light_groups_of_state_change(&canonical_device)
+            .await?;
+
+        Ok(())
+    }
+
+    async fn publish_event(self: &StateHandle, event: DeviceEvent) -> anyhow::Result<()> {
+        match event {
+            DeviceEvent::StateChanged { device_id } => self.publish_state_change(&device_id).await,
+            DeviceEvent::DeviceAdded { device_id } => {
+                let Some(hass) = self.get_hass_client().await else {
+                    return Ok(());
+                };
+                let Some(device) = self.device_for_entity(&device_id).await else {
+                    return Ok(());
+                };
+                hass.advise_hass_of_new_device(&device, self).await
+            }
+            DeviceEvent::DeviceRemoved { device_id } => {
+                if self.get_hass_client().await.is_none() {
+                    return Ok(());
+                }
+                let stale = self.purge_discovery().await?;
+                log::info!("Removed {} discovery configs of {device_id}", stale.len());
+                Ok(())
+            }
+            DeviceEvent::TransportLost { .. } => self.publish_all_device_availability(false).await,
+        }
+    }
+}
+
+/// Publishes the changes to the devices to hass. Runs until
+/// the shutdown is triggered.
+pub async fn run_hass_publisher(state: StateHandle, heartbeat: Heartbeat) {
+    let mut events = state.subscribe_events();
+    loop {
+        heartbeat.beat();
+        let Ok(event) = timeout(IDLE_HEARTBEAT_INTERVAL, events.recv()).await else {
+            continue;
+        };
+        match event {
+            Ok(event) => {
+                if let Err(err) = state.publish_event(event.clone()).await {
+                    log::error!("publishing {event:?}: {err:#}");
+                }
+            }
+            Err(RecvError::Lagged(missed)) => {
+                // We don't know which devices changed, so publish
+                // all of them
+                log::warn!("hass publisher missed {missed} events; republishing all devices");
+                for device in state.devices().await {
+                    heartbeat.beat();
+                    if let Err(err) = state.publish_state_change(&device.id).await {
+                        log::error!("publishing state of {device}: {err:#}");
+                    }
+                }
+            }
+            Err(RecvError::Closed) => break,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn bus() {
+        let state = ServiceState::new();
+        // Emitting with no subscribers is not an error
+        state.emit_state_changed("one");
+
+        let mut events = state.subscribe_events();
+        state.emit_state_changed("two");
+        state.emit_event(DeviceEvent::TransportLost {
+            transport: Transport::Iot,
+        });
+        assert_eq!(
+            events.recv().await.unwrap(),
+            DeviceEvent::StateChanged {
+                device_id: "two".to_string()
+            }
+        );
+        assert_eq!(
+            events.recv().await.unwrap(),
+            DeviceEvent::TransportLost {
+                transport: Transport::Iot
+            }
+        );
+    }
+
+    #[tokio::test]
+    async fn removed_device() {
+        let state = std::sync::Arc::new(ServiceState::new());
+        drop(state.device_mut("H6072", "AA:BB").await);
+
+        // The device is removed while its events are still queued
+        let mut events = state.subscribe_events();
+        state.emit_event(DeviceEvent::DeviceAdded {
+            device_id: "AA:BB".to_string(),
+        });
+        state.emit_state_changed("AA:BB");
+        state.remove_device("AA:BB").await;
+
+        for _ in 0..2 {
+            let event = events.recv().await.unwrap();
+            state.publish_event(event).await.unwrap();
+        }
+    }
+}
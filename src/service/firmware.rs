@@ -0,0 +1,166 @@
+//! Periodically asks Govee whether newer firmware is available
+//! for the devices in the account, so that hass can show an
+//! update entity for them, and allows the update to be installed.
+use crate::service::device::Device;
+use crate::service::hass::IdParameter;
+use crate::service::state::{State as ServiceState, StateHandle};
+use mosquitto_rs::router::{Params, State};
+use std::sync::Arc;
+use tokio::time::{sleep, Duration, Instant};
+
+/// How often to check for firmware updates
+const FIRMWARE_CHECK_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// How often to poll the progress of an update that is being installed
+const PROGRESS_POLL_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Give up reporting the progress of an update after this long
+const INSTALL_TIMEOUT: Duration = Duration::from_secs(15 * 60);
+
+impl ServiceState {
+    /// Check for newer firmware for device, returning true
+    /// if what we know about it changed
+    pub async fn refresh_device_firmware(&self, device: &Device) -> anyhow::Result<bool> {
+        let info = device
+            .undoc_device_info
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("{device} is not known to the undoc API"))?;
+        let undoc = self
+            .get_undoc_client()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Undoc API client is not available"))?;
+        let acct = undoc.login_account_cached().await?;
+        let update = undoc
+            .check_firmware_update(&acct.token, &info.entry)
+            .await?;
+
+        Ok(self
+            .device_mut(&device.sku, &device.id)
+            .await
+            .set_firmware_update(update))
+    }
+
+    /// Start installing the latest firmware on device. The progress
+    /// of the update is tracked in the background.
+    pub async fn device_install_firmware(self: &Arc<Self>, device: &Device) -> anyhow::Result<()> {
+        let info = device
+            .undoc_device_info
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("{device} is not known to the undoc API"))?;
+        if !device
+            .firmware_update
+            .as_ref()
+            .is_some_and(|update| update.need_update)
+        {
+            anyhow::bail!("There is no firmware update available for {device}");
+        }
+        if device.firmware_update_progress.is_some() {
+            anyhow::bail!("A firmware update is already in progress for {device}");
+        }
+
+        if self.is_dry_run().await {
+            log::info!("DRY RUN: not installing firmware on {device}");
+            return Ok(());
+        }
+
+        let undoc = self
+            .get_undoc_client()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Undoc API client is not available"))?;
+        let acct = undoc.login_account_cached().await?;
+        undoc
+            .start_firmware_update(&acct.token, &info.entry)
+            .await?;
+        log::info!("Started firmware update for {device}");
+
+        self.set_firmware_update_progress(device, Some(0)).await;
+        tokio::spawn(track_firmware_update(self.clone(), device.clone()));
+        Ok(())
+    }
+
+    async fn set_firmware_update_progress(self: &Arc<Self>, device: &Device, progress: Option<u8>) {
+        self.device_mut(&device.sku, &device.id)
+            .await
+            .firmware_update_progress = progress;
+        self.emit_state_changed(&device.id);
+    }
+}
+
+/// Report the progress of an update until it completes, fails
+/// or takes too long, then check which firmware is installed
+async fn track_firmware_update(state: StateHandle, device: Device) {
+    let deadline = Instant::now() + INSTALL_TIMEOUT;
+    let mut last_progress = 0;
+
+    while Instant::now() < deadline {
+        sleep(PROGRESS_POLL_INTERVAL).await;
+
+        let progress = async {
+            let info = device
+                .undoc_device_info
+                .as_ref()
+                .ok_or_else(|| anyhow::anyhow!("{device} is not known to the undoc API"))?;
+            let undoc = state
+                .get_undoc_client()
+                .await
+                .ok_or_else(|| anyhow::anyhow!("Undoc API client is not available"))?;
+            let acct = undoc.login_account_cached().await?;
+            undoc
+                .get_firmware_update_progress(&acct.token, &info.entry)
+                .await
+        }
+        .await;
+
+        match progress {
+            Ok(progress) if progress.failed => {
+                log::error!("Firmware update for {device} failed");
+                break;
+            }
+            Ok(progress) if progress.progress >= 100 => {
+                log::info!("Firmware update for {device} is complete");
+                break;
+            }
+            Ok(progress) => {
+                if progress.progress != last_progress {
+                    last_progress = progress.progress;
+                    state
+                        .set_firmware_update_progress(&device, Some(progress.progress))
+                        .await;
+                }
+            }
+            Err(err) => log::warn!("Unable to get firmware update progress for {device}: {err:#}"),
+        }
+    }
+
+    state.set_firmware_update_progress(&device, None).await;
+    if let Err(err) = state.refresh_device_firmware(&device).await {
+        log::warn!("Unable to check firmware for {device}: {err:#}");
+    }
+    state.emit_state_changed(&device.id);
+}
+
+pub async fn run_firmware_check(state: StateHandle) {
+    loop {
+        for device in state.devices().await {
+            if device.undoc_device_info.is_none() {
+                continue;
+            }
+            match state.refresh_device_firmware(&device).await {
+                Ok(true) => state.emit_state_changed(&device.id),
+                Ok(false) => {}
+                Err(err) => log::warn!("Unable to check firmware for {device}: {err:#}"),
+            }
+        }
+        sleep(FIRMWARE_CHECK_INTERVAL).await;
+    }
+}
+
+/// Starts installing the latest firmware on the device
+pub async fn mqtt_device_install_firmware(
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_device_install_firmware: {id}");
+    let device = state.resolve_device_read_only(&id).await?;
+    state.device_install_firmware(&device).await
+}
@@ -1,24 +1,64 @@
-use crate::hass_mqtt::climate::mqtt_set_temperature;
-use crate::hass_mqtt::enumerator::{enumerate_all_entites, enumerate_entities_for_device};
+use crate::hass_mqtt::climate::{
+    mqtt_climate_set_mode, mqtt_climate_set_preset, mqtt_set_temperature,
+};
+use crate::hass_mqtt::cover::{mqtt_cover_command, mqtt_cover_set_position};
+use crate::hass_mqtt::enumerator::{enumerate_bridge_entities, enumerate_entities_for_device};
+use crate::hass_mqtt::fan::{mqtt_fan_set_preset, mqtt_fan_set_speed};
 use crate::hass_mqtt::humidifier::{mqtt_device_set_work_mode, mqtt_humidifier_set_target};
-use crate::hass_mqtt::instance::EntityList;
-use crate::hass_mqtt::number::mqtt_number_command;
+use crate::hass_mqtt::instance::{EntityInstance, EntityList};
+use crate::hass_mqtt::number::{mqtt_number_command, mqtt_set_mist_level, mqtt_set_off_timer};
 use crate::hass_mqtt::select::mqtt_set_mode_scene;
-use crate::lan_api::DeviceColor;
+use crate::hass_mqtt::sensor::PlatformQuotaSensor;
+use crate::hass_mqtt::switch::ICE_MAKING_INSTANCE;
+use crate::hass_mqtt::water_heater::mqtt_water_heater_set_mode;
+use crate::lan_api::{truthy, DeviceColor};
 use crate::opt_env_var;
 use crate::platform_api::{from_json, DeviceType};
-use crate::service::device::Device as ServiceDevice;
+use crate::service::crash::install_crash_reporter;
+use crate::service::device::{outlet_number, Device as ServiceDevice, DeviceToggle};
+use crate::service::diagnostics::mqtt_device_diagnose;
+use crate::service::discovery_purge::{mqtt_discovery_config, mqtt_purge_discovery};
+use crate::service::effects::{mqtt_device_flash, TransitionCompletion, TransitionTarget};
+use crate::service::events::run_hass_publisher;
+use crate::service::firmware::mqtt_device_install_firmware;
+use crate::service::health::{SubsystemHealth, SubsystemStatus};
+use crate::service::light_group::{
+    mqtt_light_group_command, mqtt_light_group_delete, mqtt_light_group_set, LightGroupConfig,
+};
+use crate::service::raw_command::mqtt_device_raw_command;
+use crate::service::read_only::{
+    mqtt_set_device_read_only, mqtt_set_global_read_only, READ_ONLY_INSTANCE,
+};
+use crate::service::rename::mqtt_device_rename;
+use crate::service::scene_filter::mqtt_scene_filters_config;
+use crate::service::scene_preview::mqtt_device_preview_scenes;
+use crate::service::schedules::mqtt_set_schedule_enabled;
+use crate::service::segments::mqtt_device_set_segments;
+use crate::service::snapshot::{mqtt_device_restore, mqtt_device_snapshot};
 use crate::service::state::StateHandle;
+use crate::service::wakeup::{mqtt_alarm_delete, mqtt_alarm_set, mqtt_device_ramp};
+use crate::service::watchdog::{Heartbeat, Subsystem, SupervisedTask};
 use crate::temperature::TemperatureScale;
 use anyhow::Context;
 use async_channel::Receiver;
-use mosquitto_rs::router::{MqttRouter, Params, Payload, State};
-use mosquitto_rs::{Client, Event, QoS};
+use mosquitto_rs::router::{MakeDispatcher, MqttRouter, Params, Payload, State};
+use mosquitto_rs::{Client, ClientOption, Event, ProtocolVersion, QoS};
+use once_cell::sync::{Lazy, OnceCell};
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
 
 const HASS_REGISTER_DELAY: tokio::time::Duration = tokio::time::Duration::from_secs(15);
+const QUOTA_REPORT_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_secs(300);
+const AVAILABILITY_CHECK_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_secs(60);
+const SCENE_OVERRIDE_CHECK_INTERVAL: tokio::time::Duration = tokio::time::Duration::from_secs(5);
+/// How many devices to enumerate at once when registering with hass
+const REGISTER_PARALLELISM: usize = 4;
 
 #[derive(clap::Parser, Debug)]
 pub struct HassArguments {
@@ -46,9 +86,76 @@ pub struct HassArguments {
     #[arg(long, global = true)]
     mqtt_bind_address: Option<String>,
 
+    /// The MQTT protocol version to use when connecting to the broker;
+    /// one of "3.1", "3.1.1" or "5". The default is that of the
+    /// mosquitto library, which is "3.1.1".
+    /// You may also set this via the GOVEE_MQTT_PROTOCOL_VERSION
+    /// environment variable.
+    #[arg(long, global = true)]
+    mqtt_protocol_version: Option<String>,
+
+    /// Connect to the broker using TLS. This is implied by any of
+    /// the other --mqtt-tls, --mqtt-ca and --mqtt-client options.
+    /// When TLS is enabled the default port is 8883.
+    /// You may also set GOVEE_MQTT_TLS=true via the environment.
+    #[arg(long, global = true)]
+    mqtt_tls: bool,
+
+    /// A PEM encoded CA certificate file to use to verify the broker.
+    /// If neither this nor --mqtt-ca-path are set, the system
+    /// certificates in /etc/ssl/certs are used.
+    /// You may also set this via the GOVEE_MQTT_CA_FILE environment variable.
+    #[arg(long, global = true)]
+    mqtt_ca_file: Option<PathBuf>,
+
+    /// A directory of PEM encoded CA certificates to use to verify
+    /// the broker.
+    /// You may also set this via the GOVEE_MQTT_CA_PATH environment variable.
+    #[arg(long, global = true)]
+    mqtt_ca_path: Option<PathBuf>,
+
+    /// A PEM encoded client certificate to present to the broker.
+    /// Requires --mqtt-client-key.
+    /// You may also set this via the GOVEE_MQTT_CLIENT_CERT environment variable.
+    #[arg(long, global = true)]
+    mqtt_client_cert: Option<PathBuf>,
+
+    /// The unencrypted PEM encoded private key for --mqtt-client-cert.
+    /// You may also set this via the GOVEE_MQTT_CLIENT_KEY environment variable.
+    #[arg(long, global = true)]
+    mqtt_client_key: Option<PathBuf>,
+
+    /// The ALPN protocol to negotiate with the broker, for brokers
+    /// that share their port with other services.
+    /// You may also set this via the GOVEE_MQTT_TLS_ALPN environment variable.
+    #[arg(long, global = true)]
+    mqtt_tls_alpn: Option<String>,
+
     #[arg(long, global = true, default_value = "homeassistant")]
     hass_discovery_prefix: String,
 
+    /// The prefix for the topics that we publish and subscribe to,
+    /// other than the discovery topics. The default is "gv2mqtt".
+    /// You may also set this via the GOVEE_MQTT_BASE_TOPIC environment
+    /// variable.
+    #[arg(long, global = true)]
+    mqtt_base_topic: Option<String>,
+
+    /// A template for the device component of per-device topics.
+    /// {id}, {sku}, {name} and {room} are replaced with the
+    /// corresponding device properties. The default is "{id}".
+    /// You may also set this via the GOVEE_MQTT_DEVICE_TOPIC_TEMPLATE
+    /// environment variable.
+    #[arg(long, global = true)]
+    mqtt_device_topic_template: Option<String>,
+
+    /// Continue to publish and subscribe to the default topics
+    /// in addition to those from the configured layout, so that
+    /// existing automations keep working while migrating.
+    /// You may also set GOVEE_MQTT_LEGACY_TOPICS=true via the environment.
+    #[arg(long, global = true)]
+    mqtt_legacy_topics: bool,
+
     /// The temperature scale to use when showing temperature values as
     /// entities in home assistant. Can be either "C" or "F" for Celsius
     /// or Fahrenheit respectively.
@@ -56,6 +163,14 @@ pub struct HassArguments {
     /// variable.
     #[arg(long, global = true)]
     temperature_scale: Option<String>,
+
+    /// How many seconds a device may go without responding before
+    /// its entities are marked as unavailable in home assistant.
+    /// The default is 2100 (35 minutes).
+    /// You may also set this via the GOVEE_OFFLINE_THRESHOLD environment
+    /// variable.
+    #[arg(long, global = true)]
+    offline_threshold: Option<i64>,
 }
 
 impl HassArguments {
@@ -78,8 +193,89 @@ impl HassArguments {
     pub fn mqtt_port(&self) -> anyhow::Result<u16> {
         match self.mqtt_port {
             Some(p) => Ok(p),
-            None => Ok(opt_env_var("GOVEE_MQTT_PORT")?.unwrap_or(1883)),
+            None => {
+                let default_port = if self.mqtt_tls()?.is_some() {
+                    8883
+                } else {
+                    1883
+                };
+                Ok(opt_env_var("GOVEE_MQTT_PORT")?.unwrap_or(default_port))
+            }
+        }
+    }
+
+    /// Returns the configured protocol version, or None to use
+    /// the default of the mosquitto library.
+    /// MQTT 5 is opt-in, because mosquitto-rs doesn't yet expose the
+    /// properties that would make it worthwhile, and some brokers
+    /// don't support it.
+    pub fn mqtt_protocol_version(&self) -> anyhow::Result<Option<ProtocolVersion>> {
+        let version = match &self.mqtt_protocol_version {
+            Some(v) => Some(v.to_string()),
+            None => opt_env_var("GOVEE_MQTT_PROTOCOL_VERSION")?,
+        };
+        match version.as_deref().map(str::trim) {
+            None => Ok(None),
+            Some("5") | Some("5.0") => Ok(Some(ProtocolVersion::V5)),
+            Some("3.1.1") => Ok(Some(ProtocolVersion::V311)),
+            Some("3.1") => Ok(Some(ProtocolVersion::V31)),
+            Some(v) => anyhow::bail!(
+                "'{v}' is not a supported MQTT protocol version. \
+                 Use one of 3.1, 3.1.1 or 5"
+            ),
+        }
+    }
+
+    /// Returns the TLS configuration, if TLS is enabled
+    pub fn mqtt_tls(&self) -> anyhow::Result<Option<MqttTlsConfig>> {
+        fn path_arg(arg: &Option<PathBuf>, env: &str) -> anyhow::Result<Option<PathBuf>> {
+            match arg {
+                Some(p) => Ok(Some(p.clone())),
+                None => Ok(opt_env_var::<String>(env)?.map(PathBuf::from)),
+            }
+        }
+
+        let ca_file = path_arg(&self.mqtt_ca_file, "GOVEE_MQTT_CA_FILE")?;
+        let ca_path = path_arg(&self.mqtt_ca_path, "GOVEE_MQTT_CA_PATH")?;
+        let client_cert = path_arg(&self.mqtt_client_cert, "GOVEE_MQTT_CLIENT_CERT")?;
+        let client_key = path_arg(&self.mqtt_client_key, "GOVEE_MQTT_CLIENT_KEY")?;
+        let alpn = match &self.mqtt_tls_alpn {
+            Some(alpn) => Some(alpn.to_string()),
+            None => opt_env_var("GOVEE_MQTT_TLS_ALPN")?,
+        };
+        let mut enabled = self.mqtt_tls;
+        if let Some(v) = opt_env_var::<String>("GOVEE_MQTT_TLS")? {
+            enabled = truthy(&v)?;
+        }
+
+        let implied = ca_file.is_some()
+            || ca_path.is_some()
+            || client_cert.is_some()
+            || client_key.is_some()
+            || alpn.is_some();
+        if !enabled && !implied {
+            return Ok(None);
         }
+
+        anyhow::ensure!(
+            client_cert.is_some() == client_key.is_some(),
+            "The MQTT client certificate and key either both need to be set, \
+             or both need to be unset"
+        );
+
+        let ca_path = if ca_file.is_none() && ca_path.is_none() {
+            Some(PathBuf::from(SYSTEM_CA_PATH))
+        } else {
+            ca_path
+        };
+
+        Ok(Some(MqttTlsConfig {
+            ca_file,
+            ca_path,
+            client_cert,
+            client_key,
+            alpn,
+        }))
     }
 
     pub fn mqtt_username(&self) -> anyhow::Result<Option<String>> {
@@ -96,6 +292,37 @@ impl HassArguments {
         }
     }
 
+    pub fn topic_layout(&self) -> anyhow::Result<TopicLayout> {
+        let base = match &self.mqtt_base_topic {
+            Some(base) => Some(base.to_string()),
+            None => opt_env_var("GOVEE_MQTT_BASE_TOPIC")?,
+        };
+        let device_template = match &self.mqtt_device_topic_template {
+            Some(template) => Some(template.to_string()),
+            None => opt_env_var("GOVEE_MQTT_DEVICE_TOPIC_TEMPLATE")?,
+        };
+        let mut legacy = self.mqtt_legacy_topics;
+        if let Some(v) = opt_env_var::<String>("GOVEE_MQTT_LEGACY_TOPICS")? {
+            legacy = truthy(&v)?;
+        }
+
+        TopicLayout::new(
+            base.as_deref().unwrap_or(LEGACY_BASE_TOPIC),
+            device_template
+                .as_deref()
+                .unwrap_or(DEFAULT_DEVICE_TOPIC_TEMPLATE),
+            legacy,
+        )
+    }
+
+    pub fn offline_threshold(&self) -> anyhow::Result<Option<chrono::Duration>> {
+        let secs = match self.offline_threshold {
+            Some(secs) => Some(secs),
+            None => opt_env_var("GOVEE_OFFLINE_THRESHOLD")?,
+        };
+        Ok(secs.map(chrono::Duration::seconds))
+    }
+
     pub fn temperature_scale(&self) -> anyhow::Result<TemperatureScale> {
         match &self.temperature_scale {
             Some(s) => Ok(s.parse()?),
@@ -106,76 +333,277 @@ impl HassArguments {
     }
 }
 
+/// Where the CA certificates are found when neither a CA file
+/// nor a CA path are configured
+const SYSTEM_CA_PATH: &str = "/etc/ssl/certs";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttTlsConfig {
+    ca_file: Option<PathBuf>,
+    ca_path: Option<PathBuf>,
+    client_cert: Option<PathBuf>,
+    client_key: Option<PathBuf>,
+    alpn: Option<String>,
+}
+
+impl MqttTlsConfig {
+    /// Configures client to use TLS. This must be called before connecting.
+    pub fn apply(&self, client: &Client) -> anyhow::Result<()> {
+        client
+            .configure_tls(
+                self.ca_file.as_ref(),
+                self.ca_path.as_ref(),
+                self.client_cert.as_ref(),
+                self.client_key.as_ref(),
+                None,
+            )
+            .with_context(|| format!("configuring MQTT TLS with {self:?}"))?;
+        if let Some(alpn) = &self.alpn {
+            client
+                .set_option(&ClientOption::TlsALPN(alpn))
+                .with_context(|| format!("setting MQTT TLS ALPN to {alpn}"))?;
+        }
+        Ok(())
+    }
+}
+
+/// A message that was published for an entity
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PublishedMessage {
+    pub topic: String,
+    pub payload: String,
+    pub retain: bool,
+}
+
+/// The messages of the state that was published for
+/// the entities of a device
+pub type PublishedState = Vec<PublishedMessage>;
+
 #[derive(Clone)]
 pub struct HassClient {
     client: Client,
+    /// When set, publish records the messages here rather than
+    /// sending them, so that they can be compared with those that
+    /// were previously published
+    capture: Option<Arc<Mutex<PublishedState>>>,
 }
 
 impl HassClient {
-    async fn register_with_hass(&self, state: &StateHandle) -> anyhow::Result<()> {
-        let entities = enumerate_all_entites(state).await?;
-
-        // Register the configs
-        log::trace!("register_with_hass: register entities");
+    pub async fn register_with_hass(&self, state: &StateHandle) -> anyhow::Result<()> {
+        // Register the entities of the bridge itself first
+        log::trace!("register_with_hass: register bridge entities");
+        let entities = enumerate_bridge_entities(state).await?;
         entities.publish_config(state, self).await?;
+        self.settle_and_mark_online(entities.len()).await?;
+        entities.notify_state(self).await.context("notify_state")?;
 
-        // Allow hass extra time to register the entities before
-        // we mark them as available
-        let delay = tokio::time::Duration::from_millis((10 * entities.len()) as u64);
-        log::info!(
-            "Wait {delay:?} for hass to settle on {} entity configs",
-            entities.len()
-        );
-        tokio::time::sleep(delay).await;
+        // and then the devices, each as soon as its entities have been
+        // enumerated; that may require fetching its scene list, so
+        // several devices are enumerated at once
+        log::trace!("register_with_hass: register devices");
+        let permits = Arc::new(Semaphore::new(REGISTER_PARALLELISM));
+        let mut tasks = JoinSet::new();
+        for device in state.devices().await {
+            let permit = permits.clone().acquire_owned().await?;
+            let client = self.clone();
+            let state = state.clone();
+            tasks.spawn(async move {
+                let _permit = permit;
+                let result = client.register_device(&device, &state).await;
+                if let Err(err) = &result {
+                    log::error!("Registering {device} with hass: {err:#}");
+                }
+                result.is_ok()
+            });
+        }
+        let mut failed = 0;
+        while let Some(registered) = tasks.join_next().await {
+            if !registered.unwrap_or(false) {
+                failed += 1;
+            }
+        }
 
-        // Mark as available
-        log::trace!("register_with_hass: mark as online");
-        self.publish(availability_topic(), "online")
-            .await
-            .context("online -> availability_topic")?;
+        log::trace!("register_with_hass: done");
+        anyhow::ensure!(failed == 0, "{failed} device(s) could not be registered");
 
-        // report initial state
-        log::trace!("register_with_hass: reporting state");
-        entities.notify_state(self).await.context("notify_state")?;
+        Ok(())
+    }
 
-        log::trace!("register_with_hass: done");
+    /// Register the entities of a device, mark it as available
+    /// and report its initial state
+    async fn register_device(
+        &self,
+        device: &ServiceDevice,
+        state: &StateHandle,
+    ) -> anyhow::Result<()> {
+        let mut entities = EntityList::new();
+        enumerate_entities_for_device(device, state, &mut entities)
+            .await
+            .with_context(|| format!("Config::for_device({device})"))?;
+        entities.publish_config(state, self).await?;
+        self.settle_and_mark_online(entities.len()).await?;
+        state
+            .publish_device_availability(device, true)
+            .await
+            .context("publish_device_availability")?;
+        self.notify_device_state(device, &entities, state, true)
+            .await
+            .context("notify_state")?;
+        Ok(())
+    }
 
+    /// Publish the state of the entities of a device, unless it is the
+    /// same as the state that we last published for it. force publishes
+    /// it regardless, such as when the entities were just registered.
+    async fn notify_device_state(
+        &self,
+        device: &ServiceDevice,
+        entities: &EntityList,
+        state: &StateHandle,
+        force: bool,
+    ) -> anyhow::Result<()> {
+        let capture = Arc::new(Mutex::new(vec![]));
+        let capturing = Self {
+            client: self.client.clone(),
+            capture: Some(capture.clone()),
+        };
+        entities.notify_state(&capturing).await?;
+        let messages = std::mem::take(&mut *capture.lock());
+
+        if !state
+            .record_published_state(&device.id, &messages, force)
+            .await
+        {
+            log::trace!("state of {device} is unchanged; not publishing it");
+            return Ok(());
+        }
+        for message in messages {
+            if let Err(err) = self
+                .publish_message(message.topic, message.payload, message.retain)
+                .await
+            {
+                // hass may not have received all of the state, so it
+                // must not be treated as unchanged next time
+                state.forget_published_state(&device.id).await;
+                return Err(err);
+            }
+        }
         Ok(())
     }
 
+    /// Allow hass extra time to register the entities before we mark
+    /// them as available. Availability isn't retained, so it is
+    /// published again for each batch of entities.
+    async fn settle_and_mark_online(&self, num_entities: usize) -> anyhow::Result<()> {
+        let delay = tokio::time::Duration::from_millis((10 * num_entities) as u64);
+        log::debug!("Wait {delay:?} for hass to settle on {num_entities} entity configs");
+        tokio::time::sleep(delay).await;
+
+        self.publish(availability_topic(), "online")
+            .await
+            .context("online -> availability_topic")
+    }
+
     pub async fn publish<T: AsRef<str> + std::fmt::Display, P: AsRef<[u8]> + std::fmt::Display>(
         &self,
         topic: T,
         payload: P,
     ) -> anyhow::Result<()> {
-        log::trace!("{topic} -> {payload}");
+        self.publish_message(topic, payload, false).await
+    }
+
+    /// All of our messages are published via this, so that
+    /// they are captured when capture is set
+    async fn publish_message<
+        T: AsRef<str> + std::fmt::Display,
+        P: AsRef<[u8]> + std::fmt::Display,
+    >(
+        &self,
+        topic: T,
+        payload: P,
+        retain: bool,
+    ) -> anyhow::Result<()> {
+        if let Some(capture) = &self.capture {
+            capture.lock().push(PublishedMessage {
+                topic: topic.to_string(),
+                payload: payload.to_string(),
+                retain,
+            });
+            return Ok(());
+        }
+        if retain {
+            log::trace!("{topic} -> (retained) {payload}");
+        } else {
+            log::trace!("{topic} -> {payload}");
+        }
+        if let Some(legacy) = topic_layout().legacy_topic(topic.as_ref()) {
+            self.client
+                .publish(legacy, payload.as_ref(), QoS::AtMostOnce, retain)
+                .await?;
+        }
         self.client
-            .publish(topic, payload, QoS::AtMostOnce, false)
+            .publish(topic, payload, QoS::AtMostOnce, retain)
             .await?;
         Ok(())
     }
 
+    /// Publish an empty retained payload to topic, which deletes
+    /// both the retained message and the entity that it configures
+    pub async fn clear_retained<T: AsRef<str> + std::fmt::Display>(
+        &self,
+        topic: T,
+    ) -> anyhow::Result<()> {
+        self.publish_message(topic, "", true).await
+    }
+
+    /// Publish payload as a retained message, so that subscribers
+    /// receive it whenever they subscribe
+    pub async fn publish_retained_obj<T: AsRef<str> + std::fmt::Display, P: Serialize>(
+        &self,
+        topic: T,
+        payload: P,
+    ) -> anyhow::Result<()> {
+        let payload = serde_json::to_string(&payload)?;
+        self.publish_message(topic, payload, true).await
+    }
+
     pub async fn publish_obj<T: AsRef<str> + std::fmt::Display, P: Serialize>(
         &self,
         topic: T,
         payload: P,
     ) -> anyhow::Result<()> {
         let payload = serde_json::to_string(&payload)?;
-        log::trace!("{topic} -> {payload}");
-        self.client
-            .publish(topic, payload, QoS::AtMostOnce, false)
+        self.publish(topic, payload).await
+    }
+
+    /// Publish the state of the device, if it changed since we last
+    /// published it, or if force is set
+    pub async fn advise_hass_of_light_state(
+        &self,
+        device: &ServiceDevice,
+        state: &StateHandle,
+        force: bool,
+    ) -> anyhow::Result<()> {
+        let mut entities = EntityList::new();
+        enumerate_entities_for_device(device, state, &mut entities).await?;
+        self.notify_device_state(device, &entities, state, force)
             .await?;
+
         Ok(())
     }
 
-    pub async fn advise_hass_of_light_state(
+    /// Register the entities for a device that was discovered
+    /// after we had already registered with hass
+    pub async fn advise_hass_of_new_device(
         &self,
         device: &ServiceDevice,
         state: &StateHandle,
     ) -> anyhow::Result<()> {
         let mut entities = EntityList::new();
         enumerate_entities_for_device(device, state, &mut entities).await?;
-        entities.notify_state(self).await?;
+        entities.publish_config(state, self).await?;
+        self.notify_device_state(device, &entities, state, true)
+            .await?;
 
         Ok(())
     }
@@ -200,36 +628,259 @@ pub fn topic_safe_id(device: &ServiceDevice) -> String {
     id
 }
 
+/// The base topic that was used before the topic layout
+/// became configurable
+pub const LEGACY_BASE_TOPIC: &str = "gv2mqtt";
+
+/// Keeps the historical per-device topic names
+const DEFAULT_DEVICE_TOPIC_TEMPLATE: &str = "{id}";
+
+static TOPIC_LAYOUT: OnceCell<TopicLayout> = OnceCell::new();
+
+/// Maps the device component produced by device_topic_id back
+/// to the topic_safe_id that the legacy topics use
+static LEGACY_DEVICE_IDS: Lazy<Mutex<HashMap<String, String>>> = Lazy::new(Default::default);
+
+/// Describes how the topics that we publish and subscribe to
+/// are named. Discovery topics and entity unique ids are not
+/// affected, so changing the layout doesn't orphan entities.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicLayout {
+    base: String,
+    device_template: String,
+    legacy: bool,
+}
+
+impl Default for TopicLayout {
+    fn default() -> Self {
+        Self {
+            base: LEGACY_BASE_TOPIC.to_string(),
+            device_template: DEFAULT_DEVICE_TOPIC_TEMPLATE.to_string(),
+            legacy: false,
+        }
+    }
+}
+
+impl TopicLayout {
+    pub fn new(base: &str, device_template: &str, legacy: bool) -> anyhow::Result<Self> {
+        let base = base.trim_matches('/');
+        anyhow::ensure!(
+            !base.is_empty() && !base.contains(['+', '#']),
+            "'{base}' is not a valid MQTT base topic"
+        );
+        anyhow::ensure!(
+            !device_template.is_empty() && !device_template.contains(['/', '+', '#']),
+            "the device topic template '{device_template}' must be non-empty \
+             and must not contain '/', '+' or '#'"
+        );
+        // Without the id, devices with the same name, or the same
+        // sku in the same room, would share their topics
+        anyhow::ensure!(
+            device_template.contains("{id}"),
+            "the device topic template '{device_template}' must include {{id}}, \
+             so that the topics of each device are unique"
+        );
+        Ok(Self {
+            base: base.to_string(),
+            device_template: device_template.to_string(),
+            legacy,
+        })
+    }
+
+    /// Expands the device topic template for device.
+    /// Supports {id}, {sku}, {name} and {room} placeholders.
+    pub fn device_topic_id(&self, device: &ServiceDevice) -> String {
+        fn safe(s: &str) -> String {
+            topic_safe_string(s).replace(['+', '#'], "_")
+        }
+
+        let legacy_id = topic_safe_id(device);
+        let id = self
+            .device_template
+            .replace("{id}", &legacy_id)
+            .replace("{sku}", &safe(&device.sku))
+            .replace("{name}", &safe(&device.name()))
+            .replace("{room}", &safe(device.room_name().unwrap_or("unassigned")));
+
+        if self.legacy && id != legacy_id {
+            LEGACY_DEVICE_IDS.lock().insert(id.clone(), legacy_id);
+        }
+        id
+    }
+
+    /// When compatibility mode is enabled, returns the topic that
+    /// would have been used for topic prior to changing the layout
+    pub fn legacy_topic(&self, topic: &str) -> Option<String> {
+        if !self.legacy {
+            return None;
+        }
+        let rest = topic.strip_prefix(&self.base)?.strip_prefix('/')?;
+
+        let ids = LEGACY_DEVICE_IDS.lock();
+        let mut legacy = LEGACY_BASE_TOPIC.to_string();
+        for segment in rest.split('/') {
+            legacy.push('/');
+            legacy.push_str(ids.get(segment).map(String::as_str).unwrap_or(segment));
+        }
+
+        (legacy != topic).then_some(legacy)
+    }
+
+    /// Returns topic with the base topic, or in compatibility
+    /// mode the legacy base topic, removed
+    pub fn relative_topic<'a>(&self, topic: &'a str) -> Option<&'a str> {
+        let relative = topic
+            .strip_prefix(&self.base)
+            .and_then(|t| t.strip_prefix('/'));
+        if relative.is_none() && self.legacy {
+            return topic
+                .strip_prefix(LEGACY_BASE_TOPIC)
+                .and_then(|t| t.strip_prefix('/'));
+        }
+        relative
+    }
+}
+
+/// Configures the topic layout. This must happen before any
+/// topics are computed.
+pub fn set_topic_layout(layout: TopicLayout) -> anyhow::Result<()> {
+    TOPIC_LAYOUT
+        .set(layout)
+        .map_err(|_| anyhow::anyhow!("the topic layout was already configured"))
+}
+
+pub fn topic_layout() -> &'static TopicLayout {
+    TOPIC_LAYOUT.get_or_init(TopicLayout::default)
+}
+
+/// The prefix for all of our non-discovery topics
+pub fn base_topic() -> &'static str {
+    &topic_layout().base
+}
+
+/// The device component of per-device topics
+pub fn device_topic_id(device: &ServiceDevice) -> String {
+    topic_layout().device_topic_id(device)
+}
+
 pub fn switch_instance_state_topic(device: &ServiceDevice, instance: &str) -> String {
     format!(
-        "gv2mqtt/switch/{id}/{instance}/state",
-        id = topic_safe_id(device)
+        "{}/switch/{id}/{instance}/state",
+        base_topic(),
+        id = device_topic_id(device)
     )
 }
 
 pub fn light_state_topic(device: &ServiceDevice) -> String {
-    format!("gv2mqtt/light/{id}/state", id = topic_safe_id(device))
+    format!(
+        "{}/light/{id}/state",
+        base_topic(),
+        id = device_topic_id(device)
+    )
+}
+
+pub fn light_attributes_topic(device: &ServiceDevice) -> String {
+    format!(
+        "{}/light/{id}/attributes",
+        base_topic(),
+        id = device_topic_id(device)
+    )
 }
 
 pub fn light_segment_state_topic(device: &ServiceDevice, segment: u32) -> String {
     format!(
-        "gv2mqtt/light/{id}/state/{segment}",
-        id = topic_safe_id(device)
+        "{}/light/{id}/state/{segment}",
+        base_topic(),
+        id = device_topic_id(device)
     )
 }
 
 /// All entities use the same topic so that we can mark unavailable
 /// via last-will
 pub fn availability_topic() -> String {
-    "gv2mqtt/availability".to_string()
+    format!("{}/availability", base_topic())
+}
+
+/// Reports whether an individual device has recently been reachable
+pub fn device_availability_topic(device: &ServiceDevice) -> String {
+    format!(
+        "{}/{id}/availability",
+        base_topic(),
+        id = device_topic_id(device)
+    )
 }
 
 pub fn oneclick_topic() -> String {
-    "gv2mqtt/oneclick".to_string()
+    format!("{}/oneclick", base_topic())
+}
+
+pub fn read_only_command_topic() -> String {
+    format!("{}/read-only/set", base_topic())
+}
+
+pub fn read_only_state_topic() -> String {
+    format!("{}/read-only/state", base_topic())
 }
 
 pub fn purge_cache_topic() -> String {
-    "gv2mqtt/purge-caches".to_string()
+    format!("{}/purge-caches", base_topic())
+}
+
+/// Publishing to this topic deletes the discovery configs of
+/// entities that no longer exist and re-registers the rest
+pub fn purge_discovery_topic() -> String {
+    format!("{}/purge-discovery", base_topic())
+}
+
+/// Publishing a RUST_LOG style filter to this topic adjusts
+/// the log levels without restarting. An empty payload
+/// restores the filter that was in effect at startup.
+pub fn log_filter_topic() -> String {
+    format!("{}/log-filter/set", base_topic())
+}
+
+/// Publishing to this topic causes the scene override files
+/// to be re-read and the entities to be re-registered
+pub fn reload_scene_overrides_topic() -> String {
+    format!("{}/scene-overrides/reload", base_topic())
+}
+
+/// Publishing to this topic starts installing the latest
+/// firmware on the device
+pub fn install_firmware_topic(device: &ServiceDevice) -> String {
+    format!(
+        "{}/{id}/install-firmware",
+        base_topic(),
+        id = device_topic_id(device)
+    )
+}
+
+/// Control commands that could not be completed are reported here
+pub fn errors_topic() -> String {
+    format!("{}/errors", base_topic())
+}
+
+/// The current set of wake-up alarms is published here
+pub fn alarms_state_topic() -> String {
+    format!("{}/alarms/state", base_topic())
+}
+
+/// The current set of light groups is published here
+pub fn light_groups_state_topic() -> String {
+    format!("{}/light-groups/state", base_topic())
+}
+
+/// Scene filters may be published, retained, to this topic
+pub fn scene_filters_config_topic() -> String {
+    format!("{}/scene-filters/config", base_topic())
+}
+
+pub fn light_group_state_topic(group: &LightGroupConfig) -> String {
+    format!("{}/group/{id}/state", base_topic(), id = group.topic_id())
+}
+
+pub fn light_group_command_topic(group: &LightGroupConfig) -> String {
+    format!("{}/group/{id}/command", base_topic(), id = group.topic_id())
 }
 
 #[derive(Deserialize)]
@@ -250,13 +901,103 @@ async fn mqtt_request_platform_data(
     Ok(())
 }
 
+/// Force release the control permit for a device whose prior
+/// control request appears to be stuck
+async fn mqtt_release_control(
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let device = state.resolve_device_read_only(&id).await?;
+    state.force_release_control(&device).await;
+    Ok(())
+}
+
+/// A scene code, either as a bare number, or as an object
+/// that optionally includes the param id
+#[derive(Deserialize, Debug, PartialEq)]
+#[serde(untagged)]
+enum SceneCodeRequest {
+    Code(u16),
+    WithParam { code: u16, param_id: Option<u32> },
+}
+
+/// Activate a scene by its numeric code, bypassing name resolution.
+/// The payload is either `123` or `{"code": 123, "param_id": 456}`
+async fn mqtt_set_scene_code(
+    Payload(payload): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_set_scene_code: {id}: {payload}");
+    let device = state.resolve_device_for_control(&id).await?;
+    let (code, param_id) =
+        match serde_json::from_str(&payload).context("parsing scene code request")? {
+            SceneCodeRequest::Code(code) => (code, None),
+            SceneCodeRequest::WithParam { code, param_id } => (code, param_id),
+        };
+    state.device_set_scene_code(&device, code, param_id).await
+}
+
+/// The color portion of a light command. Which of these HASS sends
+/// depends on the supported_color_modes of the light, and on how
+/// the user chose the color.
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum HassColor {
+    Rgb(DeviceColor),
+    Hs { h: f32, s: f32 },
+    Xy { x: f32, y: f32 },
+}
+
+impl HassColor {
+    pub fn to_rgb(self) -> DeviceColor {
+        match self {
+            Self::Rgb(color) => color,
+            Self::Hs { h, s } => DeviceColor::from_hs(h, s),
+            Self::Xy { x, y } => DeviceColor::from_xy(x, y),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
-struct HassLightCommand {
+pub struct HassLightCommand {
     state: String,
     color_temp: Option<u32>,
-    color: Option<DeviceColor>,
+    color: Option<HassColor>,
     effect: Option<String>,
     brightness: Option<u8>,
+    /// How many seconds to take to reach the new state
+    transition: Option<f64>,
+}
+
+impl HassLightCommand {
+    /// Combine this command with a newer one that arrived before
+    /// this one could be sent. Properties of the newer command take
+    /// precedence, but those that it leaves unspecified are kept.
+    pub fn merge(self, newer: Self) -> Self {
+        if newer.state == "OFF" {
+            return newer;
+        }
+
+        // Color, color temperature and effect are alternatives
+        // to each other, so they are replaced as a set
+        let replaces_color =
+            newer.color.is_some() || newer.color_temp.is_some() || newer.effect.is_some();
+        let (color, color_temp, effect) = if replaces_color {
+            (newer.color, newer.color_temp, newer.effect)
+        } else {
+            (self.color, self.color_temp, self.effect)
+        };
+
+        Self {
+            state: newer.state,
+            color_temp,
+            color,
+            effect,
+            brightness: newer.brightness.or(self.brightness),
+            transition: newer.transition,
+        }
+    }
 }
 
 /// HASS is sending a command to a light
@@ -265,31 +1006,85 @@ async fn mqtt_light_command(
     Params(IdParameter { id }): Params<IdParameter>,
     State(state): State<StateHandle>,
 ) -> anyhow::Result<()> {
-    let device = state.resolve_device_for_control(&id).await?;
-
     let command: HassLightCommand = serde_json::from_str(&payload)?;
+    let device = state.resolve_device_read_only(&id).await?;
     log::info!("Command for {device}: {payload}");
+    state.queue_light_command(&device, command).await;
+
+    let device = state.resolve_device_for_control(&id).await?;
+    let Some(command) = state.next_light_command(&device).await else {
+        log::debug!("Command for {device} was merged into an earlier one");
+        return Ok(());
+    };
+
+    apply_light_command(&state, &device, &command).await
+}
 
+/// Apply a light command to a device
+pub async fn apply_light_command(
+    state: &StateHandle,
+    device: &ServiceDevice,
+    command: &HassLightCommand,
+) -> anyhow::Result<()> {
     let is_light = device.device_type() == DeviceType::Light;
 
+    if let Some(transition) = command.transition.filter(|t| t.is_finite() && *t > 0.) {
+        if is_light && command.effect.is_none() {
+            let target = TransitionTarget {
+                brightness: command.brightness,
+                color: command.color.map(HassColor::to_rgb),
+                off: command.state == "OFF",
+            };
+            let completion = finish_light_transition(
+                state.clone(),
+                device.id.clone(),
+                HassLightCommand {
+                    transition: None,
+                    ..command.clone()
+                },
+            );
+            if state
+                .start_light_transition(
+                    device,
+                    target,
+                    Duration::from_secs_f64(transition),
+                    completion,
+                )
+                .await
+                .context("mqtt_light_command: state.start_light_transition")?
+            {
+                return Ok(());
+            }
+        }
+    }
+
     if command.state == "OFF" {
         if is_light {
             state
-                .device_light_power_on(&device, false)
+                .device_light_power_on(device, false)
                 .await
                 .context("mqtt_light_command: state.device_power_on")?;
         } else {
             state
-                .device_set_brightness(&device, 0)
+                .device_set_brightness(device, 0)
                 .await
                 .context("mqtt_light_command: state.device_set_brightness")?;
         }
     } else {
         let mut power_on = true;
 
-        if let Some(brightness) = command.brightness {
+        // If a transition faded the light out, bring it back
+        // to the level that it was at before that
+        let fade_out_brightness = state
+            .device_mut(&device.sku, &device.id)
+            .await
+            .fade_out_brightness
+            .take();
+        let brightness = command.brightness.or(fade_out_brightness);
+
+        if let Some(brightness) = brightness {
             state
-                .device_set_brightness(&device, brightness)
+                .device_set_brightness(device, brightness)
                 .await
                 .context("mqtt_light_command: state.device_set_brightness")?;
             power_on = false;
@@ -297,7 +1092,7 @@ async fn mqtt_light_command(
 
         if let Some(effect) = &command.effect {
             state
-                .device_set_scene(&device, effect)
+                .device_set_scene(device, effect)
                 .await
                 .context("mqtt_light_command: state.device_set_scene")?;
             // It doesn't make sense to vary color properties
@@ -307,16 +1102,26 @@ async fn mqtt_light_command(
             return Ok(());
         }
 
-        if let Some(color) = &command.color {
-            state
-                .device_set_color_rgb(&device, color.r, color.g, color.b)
-                .await
-                .context("mqtt_light_command: state.device_set_color_rgb")?;
+        if let Some(color) = command.color {
+            match color {
+                HassColor::Rgb(color) => state
+                    .device_set_color_rgb(device, color.r, color.g, color.b)
+                    .await
+                    .context("mqtt_light_command: state.device_set_color_rgb")?,
+                HassColor::Hs { h, s } => state
+                    .device_set_color_hs(device, h, s)
+                    .await
+                    .context("mqtt_light_command: state.device_set_color_hs")?,
+                HassColor::Xy { x, y } => state
+                    .device_set_color_xy(device, x, y)
+                    .await
+                    .context("mqtt_light_command: state.device_set_color_xy")?,
+            }
             power_on = false;
         }
         if let Some(color_temp) = command.color_temp {
             state
-                .device_set_color_temperature(&device, mired_to_kelvin(color_temp))
+                .device_set_color_temperature(device, mired_to_kelvin(color_temp))
                 .await
                 .context("mqtt_light_command: state.device_set_color_temperature")?;
             power_on = false;
@@ -325,16 +1130,16 @@ async fn mqtt_light_command(
         if power_on {
             if is_light {
                 state
-                    .device_light_power_on(&device, true)
+                    .device_light_power_on(device, true)
                     .await
                     .context("mqtt_light_command: state.device_power_on")?;
-            } else if command.brightness.is_none() {
+            } else if brightness.is_none() {
                 // The device is not primarily a light and we don't have
                 // a guaranteed way to power it on without setting the
                 // brightness to something, and we know we didn't set
                 // the brightness just now, so let's turn it on 100%
                 state
-                    .device_set_brightness(&device, 100)
+                    .device_set_brightness(device, 100)
                     .await
                     .context("mqtt_light_command: state.device_set_brightness")?;
             }
@@ -344,6 +1149,26 @@ async fn mqtt_light_command(
     Ok(())
 }
 
+/// Applies a light command once a transition has ramped the light
+/// to its target. This is boxed so that spawning the transition
+/// doesn't make the type of apply_light_command recursive.
+fn finish_light_transition(
+    state: StateHandle,
+    device_id: String,
+    command: HassLightCommand,
+) -> TransitionCompletion {
+    Box::pin(async move {
+        let result = async {
+            let device = state.resolve_device_for_control(&device_id).await?;
+            apply_light_command(&state, &device, &command).await
+        }
+        .await;
+        if let Err(err) = result {
+            log::error!("Completing transition on {device_id}: {err:#}");
+        }
+    })
+}
+
 #[derive(Deserialize)]
 struct IdAndSeg {
     id: String,
@@ -357,10 +1182,22 @@ async fn mqtt_light_segment_command(
 ) -> anyhow::Result<()> {
     let device = state.resolve_device_for_control(&id).await?;
     let segment: u32 = segment.parse()?;
+    device.validate_segment(segment)?;
 
     let command: HassLightCommand = from_json(&payload)?;
     log::info!("Command for {device} segment {segment}: {payload}");
 
+    if state
+        .simulate_if_dry_run(
+            &device,
+            "segment",
+            &serde_json::json!({"segment": segment, "command": payload}),
+        )
+        .await?
+    {
+        return Ok(());
+    }
+
     if let Some(client) = state.get_platform_client().await {
         let info = device
             .http_device_info
@@ -383,7 +1220,7 @@ async fn mqtt_light_segment_command(
             //   on again when there are segments involved
             // client.set_segment_brightness(&info, segment, 0).await?;
         }
-        if let Some(color) = &command.color {
+        if let Some(color) = command.color.map(|c| c.to_rgb()) {
             client
                 .set_segment_rgb(&info, segment, color.r, color.g, color.b)
                 .await?;
@@ -395,9 +1232,51 @@ async fn mqtt_light_segment_command(
     Ok(())
 }
 
+/// Re-read the scene override files and re-register with hass,
+/// so that the effect lists reflect their contents
+async fn reload_scene_overrides(state: &StateHandle) -> anyhow::Result<()> {
+    crate::govee_scenes::invalidate_scene_overrides();
+    state
+        .get_hass_client()
+        .await
+        .expect("have hass client")
+        .register_with_hass(state)
+        .await
+        .context("register_with_hass")
+}
+
+async fn mqtt_reload_scene_overrides(State(state): State<StateHandle>) -> anyhow::Result<()> {
+    log::info!("mqtt_reload_scene_overrides");
+    reload_scene_overrides(&state).await
+}
+
+/// Periodically check the scene override directory, and reload
+/// the overrides when any of the files are added, changed or removed
+async fn run_scene_override_watcher(state: StateHandle) {
+    let mut fingerprint = crate::govee_scenes::scene_override_fingerprint();
+    loop {
+        tokio::time::sleep(SCENE_OVERRIDE_CHECK_INTERVAL).await;
+        let current = crate::govee_scenes::scene_override_fingerprint();
+        if current == fingerprint {
+            continue;
+        }
+        fingerprint = current;
+        log::info!(
+            "Scene override files in {:?} changed, reloading",
+            crate::govee_scenes::scene_override_dir()
+        );
+        if let Err(err) = reload_scene_overrides(&state).await {
+            log::error!("reload_scene_overrides: {err:#}");
+        }
+    }
+}
+
 async fn mqtt_purge_caches(State(state): State<StateHandle>) -> anyhow::Result<()> {
     log::info!("mqtt_purge_caches");
     crate::cache::purge_cache()?;
+    if let Some(client) = state.get_platform_client().await {
+        client.clear_memory_cache();
+    }
     state
         .get_hass_client()
         .await
@@ -407,12 +1286,22 @@ async fn mqtt_purge_caches(State(state): State<StateHandle>) -> anyhow::Result<(
         .context("register_with_hass")
 }
 
+async fn mqtt_set_log_filter(Payload(payload): Payload<String>) -> anyhow::Result<()> {
+    log::info!("mqtt_set_log_filter: {payload}");
+    crate::logging::set_log_filter(&payload)
+}
+
 async fn mqtt_oneclick(
     Payload(name): Payload<String>,
     State(state): State<StateHandle>,
 ) -> anyhow::Result<()> {
     log::info!("mqtt_oneclick: {name}");
 
+    if state.is_dry_run().await {
+        log::info!("DRY RUN: not activating one-click {name}");
+        return Ok(());
+    }
+
     let undoc = state
         .get_undoc_client()
         .await
@@ -443,6 +1332,9 @@ async fn mqtt_switch_command(
     State(state): State<StateHandle>,
 ) -> anyhow::Result<()> {
     log::info!("{instance} for {id}: {command}");
+    if instance == READ_ONLY_INSTANCE {
+        return mqtt_set_device_read_only(&state, &id, &command).await;
+    }
     let device = state.resolve_device_for_control(&id).await?;
 
     let on = match command.as_str() {
@@ -453,6 +1345,21 @@ async fn mqtt_switch_command(
 
     if instance == "powerSwitch" {
         state.device_power_on(&device, on).await?;
+    } else if instance == ICE_MAKING_INSTANCE {
+        state.ice_maker_set_making(&device, on).await?;
+    } else if let Some(toggle) = DeviceToggle::from_instance(&instance) {
+        state.device_set_toggle(&device, toggle, on).await?;
+    } else if outlet_number(&instance).is_some() {
+        state.device_set_outlet(&device, &instance, on).await?;
+    } else if state
+        .simulate_if_dry_run(
+            &device,
+            "toggle",
+            &serde_json::json!({"instance": instance, "on": on}),
+        )
+        .await?
+    {
+        // Nothing more to do in dry-run mode
     } else if let Some(client) = state.get_platform_client().await {
         if let Some(http_dev) = &device.http_device_info {
             client.set_toggle_state(http_dev, &instance, on).await?;
@@ -500,6 +1407,23 @@ async fn mqtt_homeassitant_status(
     Ok(())
 }
 
+/// Registers handler for topic and, when compatibility mode
+/// is enabled, for its legacy equivalent too
+async fn route<T, F>(
+    router: &mut MqttRouter<StateHandle>,
+    topic: String,
+    handler: F,
+) -> anyhow::Result<()>
+where
+    F: MakeDispatcher<T, StateHandle> + Copy,
+{
+    if let Some(legacy) = topic_layout().legacy_topic(&topic) {
+        router.route(legacy, handler).await?;
+    }
+    router.route(topic, handler).await?;
+    Ok(())
+}
+
 async fn run_mqtt_loop(
     state: StateHandle,
     subscriber: Receiver<Event>,
@@ -519,55 +1443,258 @@ async fn run_mqtt_loop(
         router
             .route(format!("{disco_prefix}/status"), mqtt_homeassitant_status)
             .await?;
-
-        router
-            .route("gv2mqtt/light/:id/command", mqtt_light_command)
-            .await?;
         router
             .route(
-                "gv2mqtt/light/:id/command/:segment",
-                mqtt_light_segment_command,
+                format!("{disco_prefix}/:integration/:unique_id/config"),
+                mqtt_discovery_config,
             )
             .await?;
-        router
-            .route("gv2mqtt/switch/:id/command/:instance", mqtt_switch_command)
-            .await?;
 
-        router.route(oneclick_topic(), mqtt_oneclick).await?;
-        router.route(purge_cache_topic(), mqtt_purge_caches).await?;
-        router
-            .route(
-                "gv2mqtt/:id/request-platform-data",
-                mqtt_request_platform_data,
-            )
-            .await?;
-        router
-            .route(
-                "gv2mqtt/number/:id/command/:mode_name/:work_mode",
-                mqtt_number_command,
-            )
-            .await?;
-        router
-            .route("gv2mqtt/humidifier/:id/set-mode", mqtt_device_set_work_mode)
-            .await?;
-        router
-            .route("gv2mqtt/:id/set-work-mode", mqtt_device_set_work_mode)
-            .await?;
-        router
-            .route(
-                "gv2mqtt/humidifier/:id/set-target",
-                mqtt_humidifier_set_target,
-            )
-            .await?;
-        router
-            .route(
-                "gv2mqtt/:id/set-temperature/:instance/:units",
-                mqtt_set_temperature,
-            )
-            .await?;
-        router
-            .route("gv2mqtt/:id/set-mode-scene", mqtt_set_mode_scene)
-            .await?;
+        route(
+            &mut router,
+            format!("{}/light/:id/command", base_topic()),
+            mqtt_light_command,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/light/:id/command/:segment", base_topic()),
+            mqtt_light_segment_command,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/switch/:id/command/:instance", base_topic()),
+            mqtt_switch_command,
+        )
+        .await?;
+
+        route(&mut router, oneclick_topic(), mqtt_oneclick).await?;
+        route(&mut router, purge_cache_topic(), mqtt_purge_caches).await?;
+        route(&mut router, purge_discovery_topic(), mqtt_purge_discovery).await?;
+        route(
+            &mut router,
+            read_only_command_topic(),
+            mqtt_set_global_read_only,
+        )
+        .await?;
+        route(&mut router, log_filter_topic(), mqtt_set_log_filter).await?;
+        route(
+            &mut router,
+            reload_scene_overrides_topic(),
+            mqtt_reload_scene_overrides,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/:id/request-platform-data", base_topic()),
+            mqtt_request_platform_data,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/:id/release-control", base_topic()),
+            mqtt_release_control,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/number/:id/command/:mode_name/:work_mode", base_topic()),
+            mqtt_number_command,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/humidifier/:id/set-mode", base_topic()),
+            mqtt_device_set_work_mode,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/:id/set-work-mode", base_topic()),
+            mqtt_device_set_work_mode,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/humidifier/:id/set-target", base_topic()),
+            mqtt_humidifier_set_target,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/:id/set-temperature/:instance/:units", base_topic()),
+            mqtt_set_temperature,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/:id/set-mode-scene", base_topic()),
+            mqtt_set_mode_scene,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/:id/set-mist-level", base_topic()),
+            mqtt_set_mist_level,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/:id/set-segments", base_topic()),
+            mqtt_device_set_segments,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/:id/set-off-timer", base_topic()),
+            mqtt_set_off_timer,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/:id/rename", base_topic()),
+            mqtt_device_rename,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/:id/set-schedule", base_topic()),
+            mqtt_set_schedule_enabled,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/fan/:id/set-speed", base_topic()),
+            mqtt_fan_set_speed,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/fan/:id/set-preset", base_topic()),
+            mqtt_fan_set_preset,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/cover/:id/command", base_topic()),
+            mqtt_cover_command,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/cover/:id/set-position", base_topic()),
+            mqtt_cover_set_position,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/water_heater/:id/set-mode", base_topic()),
+            mqtt_water_heater_set_mode,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/climate/:id/set-mode", base_topic()),
+            mqtt_climate_set_mode,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/climate/:id/set-preset", base_topic()),
+            mqtt_climate_set_preset,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/:id/ramp", base_topic()),
+            mqtt_device_ramp,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/:id/preview-scenes", base_topic()),
+            mqtt_device_preview_scenes,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/:id/snapshot", base_topic()),
+            mqtt_device_snapshot,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/:id/flash", base_topic()),
+            mqtt_device_flash,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/:id/diagnose", base_topic()),
+            mqtt_device_diagnose,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/:id/install-firmware", base_topic()),
+            mqtt_device_install_firmware,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/:id/set-scene-code", base_topic()),
+            mqtt_set_scene_code,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/:id/command/raw", base_topic()),
+            mqtt_device_raw_command,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/:id/restore", base_topic()),
+            mqtt_device_restore,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/alarms/set", base_topic()),
+            mqtt_alarm_set,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/alarms/delete", base_topic()),
+            mqtt_alarm_delete,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/group/:id/command", base_topic()),
+            mqtt_light_group_command,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/light-groups/set", base_topic()),
+            mqtt_light_group_set,
+        )
+        .await?;
+        route(
+            &mut router,
+            format!("{}/light-groups/delete", base_topic()),
+            mqtt_light_group_delete,
+        )
+        .await?;
+        route(
+            &mut router,
+            scene_filters_config_topic(),
+            mqtt_scene_filters_config,
+        )
+        .await?;
 
         tokio::time::sleep(HASS_REGISTER_DELAY).await;
         state
@@ -577,6 +1704,8 @@ async fn run_mqtt_loop(
             .register_with_hass(&state)
             .await
             .context("register_with_hass")?;
+        state.publish_alarms().await?;
+        state.publish_light_groups().await?;
 
         Ok(Arc::new(router))
     }
@@ -590,17 +1719,37 @@ async fn run_mqtt_loop(
                 let router = router.clone();
                 let state = state.clone();
                 tokio::spawn(async move {
-                    if let Err(err) = router.dispatch(msg.clone(), state.clone()).await {
+                    let result = router.dispatch(msg.clone(), state.clone()).await;
+                    if let Err(err) = &result {
                         log::error!("While dispatching {msg:?}: {err:#}");
                     }
+                    state.publish_command_result(&msg, &result).await;
                 });
             }
             Event::Disconnected(reason) => {
                 log::warn!("MQTT disconnected with reason={reason}");
+                state
+                    .set_mqtt_health(
+                        SubsystemHealth::new(
+                            SubsystemStatus::Failed,
+                            Some(format!("disconnected with reason={reason}")),
+                        )
+                        .since(chrono::Utc::now()),
+                    )
+                    .await;
                 need_rebuild = true;
             }
             Event::Connected(status) => {
                 log::info!("MQTT connected with status={status}");
+                state
+                    .set_mqtt_health(
+                        SubsystemHealth::new(
+                            SubsystemStatus::Ok,
+                            Some(format!("connected with status={status}")),
+                        )
+                        .since(chrono::Utc::now()),
+                    )
+                    .await;
                 if need_rebuild {
                     router = rebuild_router(&client, &state).await?;
                 }
@@ -623,12 +1772,26 @@ pub async fn spawn_hass_integration(
     )?;
 
     state.set_temperature_scale(args.temperature_scale()?).await;
+    if let Some(threshold) = args.offline_threshold()? {
+        state.set_offline_threshold(threshold).await;
+    }
 
     let mqtt_host = args.mqtt_host()?;
     let mqtt_username = args.mqtt_username()?;
     let mqtt_password = args.mqtt_password()?;
     let mqtt_port = args.mqtt_port()?;
 
+    if let Some(protocol_version) = args.mqtt_protocol_version()? {
+        client
+            .set_option(&ClientOption::ProtocolVersion(protocol_version))
+            .with_context(|| format!("setting MQTT protocol version {protocol_version:?}"))?;
+    }
+
+    if let Some(tls) = args.mqtt_tls()? {
+        log::info!("Using TLS to connect to the mqtt broker");
+        tls.apply(&client)?;
+    }
+
     client.set_last_will(availability_topic(), "offline", QoS::AtMostOnce, false)?;
 
     if mqtt_username.is_some() != mqtt_password.is_some() {
@@ -647,18 +1810,84 @@ pub async fn spawn_hass_integration(
         .await
         .with_context(|| format!("connecting to mqtt broker {mqtt_host}:{mqtt_port}"))?;
     let subscriber = client.subscriber().expect("to own the subscriber");
-
     state
-        .set_hass_client(HassClient {
-            client: client.clone(),
-        })
+        .set_mqtt_health(
+            SubsystemHealth::new(
+                SubsystemStatus::Ok,
+                Some(format!("connected to {mqtt_host}:{mqtt_port}")),
+            )
+            .since(chrono::Utc::now()),
+        )
         .await;
 
+    let hass = HassClient {
+        client: client.clone(),
+        capture: None,
+    };
+    // Let hass know if we crash
+    install_crash_reporter(hass.clone());
+    state.set_hass_client(hass).await;
+
     let disco_prefix = args.hass_discovery_prefix.clone();
     state.set_hass_disco_prefix(disco_prefix).await;
 
-    tokio::spawn(async move {
-        let res = run_mqtt_loop(state, subscriber, client).await;
+    // Devices become unavailable through the passage of time
+    // rather than a state change, so periodically check for that
+    {
+        let state = state.clone();
+        state.shutdown_token().spawn(async move {
+            loop {
+                tokio::time::sleep(AVAILABILITY_CHECK_INTERVAL).await;
+                if let Err(err) = state.publish_all_device_availability(false).await {
+                    log::error!("publish_all_device_availability: {err:#}");
+                }
+            }
+        });
+    }
+
+    {
+        let heartbeat = Heartbeat::default();
+        let start = {
+            let state = state.clone();
+            let heartbeat = heartbeat.clone();
+            move || {
+                let token = state.shutdown_token();
+                let publisher = run_hass_publisher(state.clone(), heartbeat.clone());
+                tokio::spawn(async move {
+                    token.run_until(publisher).await;
+                })
+            }
+        };
+        state
+            .supervise(SupervisedTask::spawn(
+                Subsystem::HassPublisher,
+                heartbeat,
+                start,
+            ))
+            .await;
+    }
+
+    state
+        .shutdown_token()
+        .spawn(run_scene_override_watcher(state.clone()));
+
+    if state.get_platform_client().await.is_some() {
+        let state = state.clone();
+        state.shutdown_token().spawn(async move {
+            let sensor = PlatformQuotaSensor::new(&state);
+            loop {
+                tokio::time::sleep(QUOTA_REPORT_INTERVAL).await;
+                if let Some(client) = state.get_hass_client().await {
+                    if let Err(err) = sensor.notify_state(&client).await {
+                        log::error!("reporting platform quota: {err:#}");
+                    }
+                }
+            }
+        });
+    }
+
+    state.shutdown_token().spawn(async move {
+        let res = run_mqtt_loop(state.clone(), subscriber, client).await;
         if let Err(err) = res {
             log::error!("run_mqtt_loop: {err:#}");
             log::error!("FATAL: hass integration will not function.");
@@ -666,7 +1895,8 @@ pub async fn spawn_hass_integration(
             tokio::time::sleep(tokio::time::Duration::from_secs(30)).await;
             std::process::exit(1);
         } else {
-            log::info!("run_mqtt_loop exited. We should do something to shutdown gracefully here");
+            log::info!("run_mqtt_loop exited");
+            state.shutdown().await;
             std::process::exit(0);
         }
     });
@@ -694,3 +1924,218 @@ fn test_camel_case_to_space_separated() {
         "Oscillation Toggle"
     );
 }
+
+#[cfg(test)]
+#[test]
+fn test_scene_code_request() {
+    let parse = |s: &str| serde_json::from_str::<SceneCodeRequest>(s).unwrap();
+    assert_eq!(parse("123"), SceneCodeRequest::Code(123));
+    assert_eq!(
+        parse(r#"{"code": 123, "param_id": 456}"#),
+        SceneCodeRequest::WithParam {
+            code: 123,
+            param_id: Some(456)
+        }
+    );
+    assert_eq!(
+        parse(r#"{"code": 123}"#),
+        SceneCodeRequest::WithParam {
+            code: 123,
+            param_id: None
+        }
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_hass_color() {
+    let parse = |s: &str| serde_json::from_str::<HassColor>(s).unwrap();
+    assert_eq!(
+        parse(r#"{"r":255,"g":0,"b":0}"#),
+        HassColor::Rgb(DeviceColor { r: 255, g: 0, b: 0 })
+    );
+    assert_eq!(
+        parse(r#"{"h":120,"s":100.0}"#),
+        HassColor::Hs { h: 120., s: 100. }
+    );
+    assert_eq!(
+        parse(r#"{"x":0.64,"y":0.33}"#).to_rgb(),
+        DeviceColor { r: 255, g: 0, b: 0 }
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_topic_layout() {
+    let layout = TopicLayout::new("/home/govee/", "{room}-{sku}-{id}", true).unwrap();
+    let device = ServiceDevice::new("H6072", "AA:BB:CC:DD:EE:FF:00:11");
+    let id = layout.device_topic_id(&device);
+    assert_eq!(id, "unassigned-h6072-AABBCCDDEEFF0011");
+
+    assert_eq!(
+        layout.legacy_topic(&format!("home/govee/light/{id}/state")),
+        Some("gv2mqtt/light/AABBCCDDEEFF0011/state".to_string())
+    );
+    assert_eq!(layout.legacy_topic("homeassistant/status"), None);
+
+    let default = TopicLayout::new(LEGACY_BASE_TOPIC, DEFAULT_DEVICE_TOPIC_TEMPLATE, true).unwrap();
+    assert_eq!(default.legacy_topic("gv2mqtt/oneclick"), None);
+
+    assert!(TopicLayout::new("gv2mqtt", "{room}/{id}", false).is_err());
+    assert!(TopicLayout::new("gv2mqtt/#", "{id}", false).is_err());
+
+    // Templates that could expand to the same id for different devices
+    assert!(TopicLayout::new("gv2mqtt", "{room}-{sku}", false).is_err());
+    assert!(TopicLayout::new("gv2mqtt", "{name}", false).is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_mqtt_tls_args() {
+    use clap::Parser;
+    let parse = |args: &[&str]| {
+        HassArguments::try_parse_from(std::iter::once("test").chain(args.iter().copied())).unwrap()
+    };
+
+    let args = parse(&[]);
+    assert_eq!(args.mqtt_tls().unwrap(), None);
+    assert_eq!(args.mqtt_port().unwrap(), 1883);
+
+    let args = parse(&["--mqtt-tls"]);
+    assert_eq!(
+        args.mqtt_tls().unwrap(),
+        Some(MqttTlsConfig {
+            ca_file: None,
+            ca_path: Some(PathBuf::from(SYSTEM_CA_PATH)),
+            client_cert: None,
+            client_key: None,
+            alpn: None,
+        })
+    );
+    assert_eq!(args.mqtt_port().unwrap(), 8883);
+
+    let args = parse(&[
+        "--mqtt-ca-file",
+        "ca.pem",
+        "--mqtt-client-cert",
+        "client.pem",
+        "--mqtt-client-key",
+        "client.key",
+    ]);
+    assert_eq!(
+        args.mqtt_tls().unwrap(),
+        Some(MqttTlsConfig {
+            ca_file: Some(PathBuf::from("ca.pem")),
+            ca_path: None,
+            client_cert: Some(PathBuf::from("client.pem")),
+            client_key: Some(PathBuf::from("client.key")),
+            alpn: None,
+        })
+    );
+
+    assert!(parse(&["--mqtt-client-cert", "client.pem"])
+        .mqtt_tls()
+        .is_err());
+}
+
+#[cfg(test)]
+#[test]
+fn test_mqtt_protocol_version() {
+    use clap::Parser;
+    let version = |args: &[&str]| {
+        HassArguments::try_parse_from(std::iter::once("test").chain(args.iter().copied()))
+            .unwrap()
+            .mqtt_protocol_version()
+    };
+
+    assert_eq!(version(&[]).unwrap(), None);
+    assert_eq!(
+        version(&["--mqtt-protocol-version", "3.1.1"]).unwrap(),
+        Some(ProtocolVersion::V311)
+    );
+    assert_eq!(
+        version(&["--mqtt-protocol-version", "5"]).unwrap(),
+        Some(ProtocolVersion::V5)
+    );
+    assert!(version(&["--mqtt-protocol-version", "4"]).is_err());
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_notify_state_of_removed_device() {
+    use crate::hass_mqtt::sensor::{DeviceStatusDiagnostic, OffTimerSensor};
+
+    let state = StateHandle::default();
+    drop(state.device_mut("H6072", "AA:BB").await);
+    let device = state.device_by_id("AA:BB").await.unwrap();
+    let mut entities = EntityList::new();
+    entities.add(DeviceStatusDiagnostic::new(&device, &state));
+    entities.add(OffTimerSensor::new(&device, &state));
+
+    // The entities of a device that is removed while its state is
+    // queued for publishing skip it, rather than panicking
+    state.remove_device("AA:BB").await;
+    let capture = Arc::new(Mutex::new(vec![]));
+    let client = HassClient {
+        client: Client::with_auto_id().unwrap(),
+        capture: Some(capture.clone()),
+    };
+    entities.notify_state(&client).await.unwrap();
+    assert!(capture.lock().is_empty());
+}
+
+#[cfg(test)]
+#[tokio::test]
+async fn test_capture_retained() {
+    let capture = Arc::new(Mutex::new(vec![]));
+    let client = HassClient {
+        client: Client::with_auto_id().unwrap(),
+        capture: Some(capture.clone()),
+    };
+    client.publish("a", "1").await.unwrap();
+    client
+        .publish_retained_obj("b", serde_json::json!({"count": 2}))
+        .await
+        .unwrap();
+    client.clear_retained("c").await.unwrap();
+
+    let message = |topic: &str, payload: &str, retain| PublishedMessage {
+        topic: topic.to_string(),
+        payload: payload.to_string(),
+        retain,
+    };
+    assert_eq!(
+        *capture.lock(),
+        vec![
+            message("a", "1", false),
+            message("b", r#"{"count":2}"#, true),
+            message("c", "", true),
+        ]
+    );
+}
+
+#[cfg(test)]
+#[test]
+fn test_merge_light_commands() {
+    let command = |json: &str| serde_json::from_str::<HassLightCommand>(json).unwrap();
+
+    let merged = command(r#"{"state":"ON","brightness":50}"#)
+        .merge(command(r#"{"state":"ON","color":{"r":255,"g":0,"b":0}}"#))
+        .merge(command(r#"{"state":"ON","color":{"r":0,"g":0,"b":255}}"#));
+    assert_eq!(merged.state, "ON");
+    assert_eq!(merged.brightness, Some(50));
+    assert_eq!(
+        merged.color,
+        Some(HassColor::Rgb(DeviceColor { r: 0, g: 0, b: 255 }))
+    );
+
+    let merged = merged.merge(command(r#"{"state":"ON","color_temp":250}"#));
+    assert_eq!(merged.color, None);
+    assert_eq!(merged.color_temp, Some(250));
+    assert_eq!(merged.brightness, Some(50));
+
+    let merged = merged.merge(command(r#"{"state":"OFF"}"#));
+    assert_eq!(merged.state, "OFF");
+    assert_eq!(merged.brightness, None);
+    assert_eq!(merged.color_temp, None);
+}
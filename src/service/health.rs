@@ -0,0 +1,174 @@
+//! Summarizes the health of each of the subsystems, so that container
+//! orchestrators can probe whether the bridge is working and restart
+//! it when, for example, its credentials have expired.
+use crate::service::iot::IotConnectionStatus;
+use crate::service::state::State as ServiceState;
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::collections::BTreeMap;
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SubsystemStatus {
+    /// Not configured
+    Disabled,
+    Starting,
+    Ok,
+    Failed,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct SubsystemHealth {
+    pub status: SubsystemStatus,
+    /// When the status last changed, if known
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub since: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+}
+
+impl SubsystemHealth {
+    pub fn new<D: Into<String>>(status: SubsystemStatus, detail: Option<D>) -> Self {
+        Self {
+            status,
+            since: None,
+            detail: detail.map(Into::into),
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self::new::<String>(SubsystemStatus::Disabled, None)
+    }
+
+    pub fn since(mut self, since: DateTime<Utc>) -> Self {
+        self.since.replace(since);
+        self
+    }
+}
+
+#[derive(Serialize, Clone, Debug)]
+pub struct HealthReport {
+    /// False if any of the subsystems has failed
+    pub healthy: bool,
+    /// True once all of the configured subsystems are working
+    pub ready: bool,
+    pub subsystems: BTreeMap<&'static str, SubsystemHealth>,
+}
+
+impl HealthReport {
+    pub fn new(subsystems: BTreeMap<&'static str, SubsystemHealth>) -> Self {
+        let healthy = subsystems
+            .values()
+            .all(|s| s.status != SubsystemStatus::Failed);
+        let ready = subsystems
+            .values()
+            .all(|s| matches!(s.status, SubsystemStatus::Ok | SubsystemStatus::Disabled));
+        Self {
+            healthy,
+            ready,
+            subsystems,
+        }
+    }
+}
+
+impl ServiceState {
+    pub async fn health_report(&self) -> HealthReport {
+        let mut subsystems = BTreeMap::new();
+
+        let lan = match self.get_lan_client().await {
+            Some(_) => {
+                let count = self
+                    .devices()
+                    .await
+                    .iter()
+                    .filter(|d| d.lan_device.is_some())
+                    .count();
+                SubsystemHealth::new(
+                    SubsystemStatus::Ok,
+                    Some(format!("{count} device(s) discovered")),
+                )
+            }
+            None => SubsystemHealth::disabled(),
+        };
+        subsystems.insert("lan", lan);
+
+        let iot = match self.get_iot_health().await {
+            Some(health) => {
+                let status = match health.status {
+                    IotConnectionStatus::Connecting => SubsystemStatus::Starting,
+                    IotConnectionStatus::Connected => SubsystemStatus::Ok,
+                    IotConnectionStatus::Disconnected => SubsystemStatus::Failed,
+                };
+                SubsystemHealth::new(status, health.last_error).since(health.since)
+            }
+            None => SubsystemHealth::disabled(),
+        };
+        subsystems.insert("iot", iot);
+
+        let platform = match self.get_platform_client().await {
+            Some(client) => match client.auth_failure() {
+                Some(err) => SubsystemHealth::new(SubsystemStatus::Failed, Some(err)),
+                None => SubsystemHealth::new(
+                    SubsystemStatus::Ok,
+                    Some(format!(
+                        "{} request(s) remaining today",
+                        client.quota_remaining()
+                    )),
+                ),
+            },
+            None => SubsystemHealth::disabled(),
+        };
+        subsystems.insert("platform", platform);
+
+        let mqtt = match self.get_mqtt_health().await {
+            Some(health) => health,
+            None if self.get_hass_client().await.is_some() => {
+                SubsystemHealth::new::<String>(SubsystemStatus::Starting, None)
+            }
+            None => SubsystemHealth::disabled(),
+        };
+        subsystems.insert("mqtt", mqtt);
+
+        HealthReport::new(subsystems)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn report() {
+        let ok = || SubsystemHealth::new(SubsystemStatus::Ok, Some("fine"));
+        let report = HealthReport::new(BTreeMap::from([
+            ("lan", ok()),
+            ("iot", SubsystemHealth::disabled()),
+        ]));
+        assert!(report.healthy);
+        assert!(report.ready);
+
+        let report = HealthReport::new(BTreeMap::from([
+            ("lan", ok()),
+            (
+                "iot",
+                SubsystemHealth::new::<String>(SubsystemStatus::Starting, None),
+            ),
+        ]));
+        assert!(report.healthy);
+        assert!(!report.ready);
+
+        let report = HealthReport::new(BTreeMap::from([
+            ("lan", ok()),
+            (
+                "mqtt",
+                SubsystemHealth::new(SubsystemStatus::Failed, Some("connection lost")),
+            ),
+        ]));
+        assert!(!report.healthy);
+        assert!(!report.ready);
+        assert_eq!(
+            serde_json::to_value(&report.subsystems["mqtt"]).unwrap(),
+            serde_json::json!({"status": "failed", "detail": "connection lost"})
+        );
+    }
+}
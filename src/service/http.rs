@@ -175,6 +175,18 @@ async fn device_set_scene(
     Ok(response_with_code(StatusCode::OK, "ok"))
 }
 
+/// Renames a given device, both here and in the Govee Home app
+async fn device_rename(
+    State(state): State<StateHandle>,
+    Path((id, name)): Path<(String, String)>,
+) -> Result<Response, Response> {
+    let device = resolve_device_for_control(&state, &id).await?;
+
+    state.device_rename(&device, &name).await.map_err(generic)?;
+
+    Ok(response_with_code(StatusCode::OK, "ok"))
+}
+
 /// Returns a JSON array of the available scene names for a given device
 async fn device_list_scenes(
     State(state): State<StateHandle>,
@@ -187,6 +199,20 @@ async fn device_list_scenes(
     Ok(Json(scenes).into_response())
 }
 
+async fn device_install_firmware(
+    State(state): State<StateHandle>,
+    Path(id): Path<String>,
+) -> Result<Response, Response> {
+    let device = resolve_device_read_only(&state, &id).await?;
+
+    state
+        .device_install_firmware(&device)
+        .await
+        .map_err(generic)?;
+
+    Ok(response_with_code(StatusCode::OK, "ok"))
+}
+
 async fn list_one_clicks(State(state): State<StateHandle>) -> Result<Response, Response> {
     let undoc = state
         .get_undoc_client()
@@ -225,6 +251,48 @@ async fn activate_one_click(
     Ok(response_with_code(StatusCode::OK, "ok"))
 }
 
+/// Deletes the discovery configs of entities that no longer exist,
+/// and re-registers the rest. Returns the deleted config topics.
+async fn purge_discovery(State(state): State<StateHandle>) -> Result<Response, Response> {
+    let purged = state.purge_discovery().await.map_err(generic)?;
+    Ok(Json(serde_json::json!({"purged": purged})).into_response())
+}
+
+/// Returns the log filter that is layered over RUST_LOG
+async fn get_log_filter() -> Response {
+    Json(serde_json::json!({"filter": crate::logging::log_filter()})).into_response()
+}
+
+/// Sets the log filter from the request body, which uses the same
+/// syntax as RUST_LOG. An empty body restores the startup filter.
+async fn set_log_filter(body: String) -> Result<Response, Response> {
+    crate::logging::set_log_filter(&body).map_err(bad_request)?;
+    Ok(get_log_filter().await)
+}
+
+/// Liveness probe: fails if any of the subsystems has failed
+async fn healthz(State(state): State<StateHandle>) -> Response {
+    let report = state.health_report().await;
+    let code = if report.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(report)).into_response()
+}
+
+/// Readiness probe: fails until all of the configured
+/// subsystems are working
+async fn readyz(State(state): State<StateHandle>) -> Response {
+    let report = state.health_report().await;
+    let code = if report.ready {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    (code, Json(report)).into_response()
+}
+
 async fn redirect_to_index() -> Response {
     axum::response::Redirect::to("/assets/index.html").into_response()
 }
@@ -245,8 +313,17 @@ pub async fn run_http_server(state: StateHandle, port: u16) -> anyhow::Result<()
         .route("/api/device/:id/color/:color", get(device_set_color))
         .route("/api/device/:id/scene/:scene", get(device_set_scene))
         .route("/api/device/:id/scenes", get(device_list_scenes))
+        .route("/api/device/:id/rename/:name", get(device_rename))
+        .route(
+            "/api/device/:id/firmware/install",
+            get(device_install_firmware),
+        )
         .route("/api/oneclicks", get(list_one_clicks))
         .route("/api/oneclick/activate/:scene", get(activate_one_click))
+        .route("/api/log-filter", get(get_log_filter).put(set_log_filter))
+        .route("/api/purge-discovery", get(purge_discovery))
+        .route("/healthz", get(healthz))
+        .route("/readyz", get(readyz))
         .route("/", get(redirect_to_index))
         .nest_service("/assets", ServeDir::new("assets"))
         .with_state(state);
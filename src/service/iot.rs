@@ -1,15 +1,34 @@
-use crate::ble::{Base64HexBytes, GoveeBlePacket, HumidifierAutoMode, NotifyHumidifierMode};
+use crate::ble::{
+    Base64HexBytes, GoveeBlePacket, HumidifierAutoMode, NotifyChildLock, NotifyGradient,
+    NotifyHeaterMode, NotifyHeaterTargetTemperature, NotifyHumidifierMode, NotifyKettleMode,
+    NotifyKettleTemperature, NotifyMistLevel, NotifyOffTimer, NotifyOscillation,
+    NotifyPurifierMode, NotifySceneCode,
+};
+use crate::hass_mqtt::instance::EntityInstance;
+use crate::hass_mqtt::sensor::IotHealthSensor;
 use crate::lan_api::{DeviceColor, DeviceStatus};
 use crate::platform_api::from_json;
+use crate::service::device::{Device, DeviceToggle, MIST_LEVEL_WORK_MODE};
+use crate::service::events::DeviceEvent;
+use crate::service::iot_reply::IotReply;
+use crate::service::packet_capture::{capture_packet, Direction};
 use crate::service::state::StateHandle;
-use crate::undoc_api::{ms_timestamp, DeviceEntry, LoginAccountResponse, ParsedOneClick};
+use crate::service::transport::Transport;
+use crate::service::watchdog::{Heartbeat, Subsystem, SupervisedTask};
+use crate::temperature::TemperatureValue;
+use crate::undoc_api::{
+    ms_timestamp, DeviceEntry, GoveeUndocumentedApi, LoginAccountResponse, ParsedOneClick,
+};
 use crate::Args;
 use anyhow::Context;
 use async_channel::Receiver;
+use chrono::{DateTime, Utc};
 use mosquitto_rs::{Event, QoS};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
 use std::time::Duration;
-use tokio::time::timeout;
+use tokio::task::JoinHandle;
+use tokio::time::{sleep, timeout};
 
 #[derive(Clone)]
 pub struct IotClient {
@@ -17,6 +36,44 @@ pub struct IotClient {
 }
 
 impl IotClient {
+    /// Publish payload to the topic for device, capturing
+    /// it if packet capture is enabled
+    async fn publish_to_device(
+        &self,
+        device: &DeviceEntry,
+        device_topic: &str,
+        payload: String,
+    ) -> anyhow::Result<()> {
+        capture_packet("iot", Direction::Tx, &device.device, payload.as_bytes());
+        self.client
+            .publish(device_topic, payload, QoS::AtMostOnce, false)
+            .await?;
+        Ok(())
+    }
+
+    /// If topic is one of the device shadow topics that we subscribe
+    /// to with --iot-shadow, parse payload, returning the name of the
+    /// thing that it describes along with the state that it reports
+    fn parse_shadow_update(
+        topic: &str,
+        payload: &[u8],
+    ) -> Option<anyhow::Result<(String, StateUpdate)>> {
+        let rest = topic.strip_prefix("$aws/things/")?;
+        let (thing, kind) = rest.split_once("/shadow/")?;
+        let state = match kind {
+            "update/delta" => from_json::<ShadowDelta, _>(payload).map(|delta| delta.state),
+            "update/documents" => from_json::<ShadowDocuments, _>(payload).and_then(|docs| {
+                let state = docs.current.state;
+                state
+                    .reported
+                    .or(state.desired)
+                    .ok_or_else(|| anyhow::anyhow!("shadow document has no state"))
+            }),
+            _ => return None,
+        };
+        Some(state.map(|state| (thing.to_string(), state)))
+    }
+
     pub fn is_device_compatible(&self, device: &DeviceEntry) -> bool {
         device.device_ext.device_settings.topic.is_some()
     }
@@ -24,21 +81,19 @@ impl IotClient {
     pub async fn request_status_update(&self, device: &DeviceEntry) -> anyhow::Result<()> {
         let device_topic = device.device_topic()?;
 
-        self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "status",
-                        "cmdVersion": 2,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 0,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await?;
+        self.publish_to_device(
+            device,
+            device_topic,
+            serde_json::to_string(&serde_json::json!({
+                "msg": {
+                    "cmd": "status",
+                    "cmdVersion": 2,
+                    "transaction": format!("v_{}000", ms_timestamp()),
+                    "type": 0,
+                }
+            }))?,
+        )
+        .await?;
 
         Ok(())
     }
@@ -55,55 +110,52 @@ impl IotClient {
             }
         }
 
-        let power_state = match device.sku.as_str() {
-            "H5080" | "H5083" => pwr(on, 17, 16),
-            _ => pwr(on, 1, 0),
+        let power_state = if PLUG_POWER_SKUS.contains(&device.sku.as_str()) {
+            pwr(on, PLUG_ON, PLUG_OFF)
+        } else {
+            pwr(on, 1, 0)
         };
 
-        self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "turn",
-                        "data": {
-                            "val": power_state,
-                        },
-                        "cmdVersion": 0,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 1,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await
-            .context("IotClient::set_power_state")?;
+        self.publish_to_device(
+            device,
+            device_topic,
+            serde_json::to_string(&serde_json::json!({
+                "msg": {
+                    "cmd": "turn",
+                    "data": {
+                        "val": power_state,
+                    },
+                    "cmdVersion": 0,
+                    "transaction": format!("v_{}000", ms_timestamp()),
+                    "type": 1,
+                }
+            }))?,
+        )
+        .await
+        .context("IotClient::set_power_state")?;
         Ok(())
     }
 
     pub async fn set_brightness(&self, device: &DeviceEntry, percent: u8) -> anyhow::Result<()> {
         log::trace!("set_brightness for {} to {percent}", device.device);
         let device_topic = device.device_topic()?;
-        self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "brightness",
-                        "data": {
-                            "val": percent,
-                        },
-                        "cmdVersion": 0,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 1,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await
-            .context("IotClient::set_brightness")?;
+        self.publish_to_device(
+            device,
+            device_topic,
+            serde_json::to_string(&serde_json::json!({
+                "msg": {
+                    "cmd": "brightness",
+                    "data": {
+                        "val": percent,
+                    },
+                    "cmdVersion": 0,
+                    "transaction": format!("v_{}000", ms_timestamp()),
+                    "type": 1,
+                }
+            }))?,
+        )
+        .await
+        .context("IotClient::set_brightness")?;
         Ok(())
     }
 
@@ -115,30 +167,28 @@ impl IotClient {
         log::trace!("set_color_temperature for {} to {kelvin}", device.device);
         let device_topic = device.device_topic()?;
 
-        self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "colorwc",
-                        "data": {
-                            "color": {
-                                "r": 0,
-                                "g": 0,
-                                "b": 0,
-                            },
-                            "colorTemInKelvin": kelvin,
+        self.publish_to_device(
+            device,
+            device_topic,
+            serde_json::to_string(&serde_json::json!({
+                "msg": {
+                    "cmd": "colorwc",
+                    "data": {
+                        "color": {
+                            "r": 0,
+                            "g": 0,
+                            "b": 0,
                         },
-                        "cmdVersion": 0,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 1,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await
-            .context("IotClient::set_color_temperature")?;
+                        "colorTemInKelvin": kelvin,
+                    },
+                    "cmdVersion": 0,
+                    "transaction": format!("v_{}000", ms_timestamp()),
+                    "type": 1,
+                }
+            }))?,
+        )
+        .await
+        .context("IotClient::set_color_temperature")?;
         Ok(())
     }
 
@@ -152,30 +202,28 @@ impl IotClient {
         log::trace!("set_color_rgb for {} to {r},{g},{b}", device.device);
         let device_topic = device.device_topic()?;
 
-        self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "colorwc",
-                        "data": {
-                            "color":{
-                                "r": r,
-                                "g": g,
-                                "b": b,
-                            },
-                            "colorTemInKelvin": 0,
+        self.publish_to_device(
+            device,
+            device_topic,
+            serde_json::to_string(&serde_json::json!({
+                "msg": {
+                    "cmd": "colorwc",
+                    "data": {
+                        "color":{
+                            "r": r,
+                            "g": g,
+                            "b": b,
                         },
-                        "cmdVersion": 0,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 1,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await
-            .context("IotClient::set_color_rgb")?;
+                        "colorTemInKelvin": 0,
+                    },
+                    "cmdVersion": 0,
+                    "transaction": format!("v_{}000", ms_timestamp()),
+                    "type": 1,
+                }
+            }))?,
+        )
+        .await
+        .context("IotClient::set_color_rgb")?;
         Ok(())
     }
 
@@ -187,25 +235,23 @@ impl IotClient {
         log::trace!("send_real for {} to {commands:?}", device.device);
         let device_topic = device.device_topic()?;
 
-        self.client
-            .publish(
-                device_topic,
-                serde_json::to_string(&serde_json::json!({
-                    "msg": {
-                        "cmd": "ptReal",
-                        "data": {
-                            "command": commands,
-                        },
-                        "cmdVersion": 0,
-                        "transaction": format!("v_{}000", ms_timestamp()),
-                        "type": 1,
-                    }
-                }))?,
-                QoS::AtMostOnce,
-                false,
-            )
-            .await
-            .context("IotClient::send_real")?;
+        self.publish_to_device(
+            device,
+            device_topic,
+            serde_json::to_string(&serde_json::json!({
+                "msg": {
+                    "cmd": "ptReal",
+                    "data": {
+                        "command": commands,
+                    },
+                    "cmdVersion": 0,
+                    "transaction": format!("v_{}000", ms_timestamp()),
+                    "type": 1,
+                }
+            }))?,
+        )
+        .await
+        .context("IotClient::send_real")?;
         Ok(())
     }
 
@@ -227,12 +273,109 @@ impl IotClient {
     }
 }
 
-pub async fn start_iot_client(
-    args: &Args,
-    state: StateHandle,
+/// The base delay before creating a new IoT session after the
+/// prior one has failed. It is doubled for each failed attempt.
+const IOT_RECONNECT_MIN: Duration = Duration::from_secs(5);
+/// The upper bound on the delay between reconnection attempts
+const IOT_RECONNECT_MAX: Duration = Duration::from_secs(600);
+/// If the session hasn't reconnected by itself within this time, it
+/// is abandoned and a new one is created using fresh credentials
+const IOT_STALL_TIMEOUT: Duration = Duration::from_secs(300);
+/// How often the IoT loop wakes up to beat its heartbeat
+const IOT_HEARTBEAT_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum IotConnectionStatus {
+    Connecting,
+    Connected,
+    Disconnected,
+}
+
+impl IotConnectionStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Connecting => "connecting",
+            Self::Connected => "connected",
+            Self::Disconnected => "disconnected",
+        }
+    }
+}
+
+/// The health of the IoT connection, as reported to hass
+#[derive(Serialize, Clone, Debug)]
+pub struct IotHealth {
+    pub status: IotConnectionStatus,
+    /// When the status last changed
+    pub since: DateTime<Utc>,
+    /// How many times the connection has been re-established
+    pub reconnects: u32,
+    pub last_error: Option<String>,
+}
+
+impl Default for IotHealth {
+    fn default() -> Self {
+        Self {
+            status: IotConnectionStatus::Connecting,
+            since: Utc::now(),
+            reconnects: 0,
+            last_error: None,
+        }
+    }
+}
+
+impl IotHealth {
+    fn set_status(&mut self, status: IotConnectionStatus) {
+        if self.status != status {
+            self.status = status;
+            self.since = Utc::now();
+        }
+    }
+}
+
+/// Update the IoT health and report it to hass
+async fn update_iot_health<F: FnOnce(&mut IotHealth)>(state: &StateHandle, apply: F) {
+    let mut health = state.get_iot_health().await.unwrap_or_default();
+    (apply)(&mut health);
+    state.set_iot_health(health).await;
+
+    if let Some(client) = state.get_hass_client().await {
+        if let Err(err) = IotHealthSensor::new(state).notify_state(&client).await {
+            log::error!("reporting IoT health: {err:#}");
+        }
+    }
+}
+
+/// Compute an exponential backoff delay for the given attempt number,
+/// with jitter so that many instances don't all reconnect at the same
+/// moment after an outage. `random` is the source of the jitter.
+fn backoff_delay(attempt: u32, random: u64) -> Duration {
+    let base = IOT_RECONNECT_MIN
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(IOT_RECONNECT_MAX);
+    let half = base.as_millis() as u64 / 2;
+    Duration::from_millis(half + random % (half + 1))
+}
+
+/// The files used to authenticate with AWS IoT
+#[derive(Clone)]
+struct IotTlsFiles {
+    amazon_root_ca: PathBuf,
+    cert: PathBuf,
+    key: PathBuf,
+}
+
+struct IotSession {
+    client: mosquitto_rs::Client,
+    subscriptions: Receiver<Event>,
+    acct: LoginAccountResponse,
+}
+
+async fn connect_iot(
+    client: &GoveeUndocumentedApi,
+    files: &IotTlsFiles,
     acct: Option<LoginAccountResponse>,
-) -> anyhow::Result<()> {
-    let client = args.undoc_args.api_client()?;
+) -> anyhow::Result<IotSession> {
     let acct = match acct {
         Some(a) => a,
         None => client.login_account_cached().await?,
@@ -250,32 +393,37 @@ pub async fn start_iot_client(
         let pem = priv_key
             .private_key_to_pem_pkcs8()
             .context("to_pem_pkcs8")?;
-        std::fs::write(&args.undoc_args.govee_iot_key, &pem)?;
+        std::fs::write(&files.key, &pem)?;
     }
     for cert in container.cert_bags(&res.p12_pass).context("cert_bags")? {
         let cert = openssl::x509::X509::from_der(&cert).context("x509 from der")?;
         let pem = cert.to_pem().context("cert.to_pem")?;
-        std::fs::write(&args.undoc_args.govee_iot_cert, &pem)?;
+        std::fs::write(&files.cert, &pem)?;
     }
 
+    // The session is not clean, so that when mosquitto reconnects
+    // by itself, the broker can resume the session
     let client = mosquitto_rs::Client::with_id(
         &format!(
             "AP/{account_id}/{id}",
             account_id = *acct.account_id,
             id = uuid::Uuid::new_v4().simple()
         ),
-        true,
+        false,
     )
     .context("new client")?;
     client
         .configure_tls(
-            Some(&args.undoc_args.amazon_root_ca),
+            Some(&files.amazon_root_ca),
             None::<&std::path::Path>,
-            Some(&args.undoc_args.govee_iot_cert),
-            Some(&args.undoc_args.govee_iot_key),
+            Some(&files.cert),
+            Some(&files.key),
             None,
         )
         .context("configure_tls")?;
+    client
+        .set_reconnect_delay(IOT_RECONNECT_MIN, IOT_RECONNECT_MAX, true)
+        .context("set_reconnect_delay")?;
     log::trace!("Connecting to IoT {} port 8883", res.endpoint);
     let status = timeout(
         Duration::from_secs(60),
@@ -288,21 +436,177 @@ pub async fn start_iot_client(
 
     let subscriptions = client.subscriber().expect("first and only");
 
+    Ok(IotSession {
+        client,
+        subscriptions,
+        acct,
+    })
+}
+
+pub async fn start_iot_client(
+    args: &Args,
+    state: StateHandle,
+    acct: Option<LoginAccountResponse>,
+) -> anyhow::Result<()> {
+    let client = args.undoc_args.api_client()?;
+    let files = IotTlsFiles {
+        amazon_root_ca: args.undoc_args.amazon_root_ca.clone(),
+        cert: args.undoc_args.govee_iot_cert.clone(),
+        key: args.undoc_args.govee_iot_key.clone(),
+    };
+
+    update_iot_health(&state, |_| {}).await;
+    let session = match connect_iot(&client, &files, acct).await {
+        Ok(session) => session,
+        Err(err) => {
+            update_iot_health(&state, |h| {
+                h.set_status(IotConnectionStatus::Disconnected);
+                h.last_error.replace(format!("{err:#}"));
+            })
+            .await;
+            return Err(err);
+        }
+    };
+
+    let shadow = args.undoc_args.iot_shadow()?;
+    let heartbeat = Heartbeat::default();
+    let task = spawn_iot_supervisor(
+        client.clone(),
+        files.clone(),
+        state.clone(),
+        Some(session),
+        shadow,
+        heartbeat.clone(),
+    );
+    let restart = {
+        let state = state.clone();
+        let heartbeat = heartbeat.clone();
+        move || {
+            spawn_iot_supervisor(
+                client.clone(),
+                files.clone(),
+                state.clone(),
+                None,
+                shadow,
+                heartbeat.clone(),
+            )
+        }
+    };
     state
-        .set_iot_client(IotClient {
-            client: client.clone(),
-        })
+        .supervise(SupervisedTask::new(
+            Subsystem::Iot,
+            heartbeat,
+            task,
+            restart,
+        ))
         .await;
 
+    Ok(())
+}
+
+/// Spawns run_iot_supervisor as a task that stops when the shutdown
+/// is triggered. If session is None, a new one is created first.
+fn spawn_iot_supervisor(
+    client: GoveeUndocumentedApi,
+    files: IotTlsFiles,
+    state: StateHandle,
+    session: Option<IotSession>,
+    shadow: bool,
+    heartbeat: Heartbeat,
+) -> JoinHandle<()> {
+    let token = state.shutdown_token();
     tokio::spawn(async move {
-        if let Err(err) = run_iot_subscriber(subscriptions, state, client, acct).await {
-            log::error!("IoT loop failed: {err:#}");
-        }
-        log::info!("IoT loop terminated");
-        Ok::<(), anyhow::Error>(())
-    });
+        token
+            .run_until(run_iot_supervisor(
+                client, files, state, session, shadow, heartbeat,
+            ))
+            .await;
+    })
+}
 
-    Ok(())
+/// Runs the IoT session, creating a new one whenever it fails
+async fn run_iot_supervisor(
+    client: GoveeUndocumentedApi,
+    files: IotTlsFiles,
+    state: StateHandle,
+    mut session: Option<IotSession>,
+    shadow: bool,
+    heartbeat: Heartbeat,
+) {
+    // Only a session created by start_iot_client is the initial one;
+    // the watchdog restarts us without one
+    let mut initial_session = session.is_some();
+    loop {
+        let session = match session.take() {
+            Some(session) => session,
+            None => reconnect_iot(&client, &files, &state, &heartbeat).await,
+        };
+        state
+            .set_iot_client(IotClient {
+                client: session.client.clone(),
+            })
+            .await;
+
+        let IotSession {
+            client: iot,
+            subscriptions,
+            acct,
+        } = session;
+        let error = match run_iot_subscriber(
+            subscriptions,
+            state.clone(),
+            iot,
+            acct,
+            initial_session,
+            shadow,
+            &heartbeat,
+        )
+        .await
+        {
+            Ok(()) => "IoT session terminated".to_string(),
+            Err(err) => format!("IoT loop failed: {err:#}"),
+        };
+        log::error!("{error}");
+        initial_session = false;
+        update_iot_health(&state, |h| {
+            h.set_status(IotConnectionStatus::Disconnected);
+            h.last_error.replace(error);
+        })
+        .await;
+        state.emit_event(DeviceEvent::TransportLost {
+            transport: Transport::Iot,
+        });
+    }
+}
+
+/// Creates a new IoT session, retrying with backoff until it succeeds
+async fn reconnect_iot(
+    client: &GoveeUndocumentedApi,
+    files: &IotTlsFiles,
+    state: &StateHandle,
+    heartbeat: &Heartbeat,
+) -> IotSession {
+    let mut attempt = 0;
+    loop {
+        heartbeat.beat();
+        let delay = backoff_delay(attempt, uuid::Uuid::new_v4().as_u128() as u64);
+        log::info!("Reconnecting to IoT in {delay:?}");
+        sleep(delay).await;
+
+        update_iot_health(state, |h| h.set_status(IotConnectionStatus::Connecting)).await;
+        match connect_iot(client, files, None).await {
+            Ok(session) => return session,
+            Err(err) => {
+                log::error!("Reconnecting to IoT: {err:#}");
+                update_iot_health(state, |h| {
+                    h.set_status(IotConnectionStatus::Disconnected);
+                    h.last_error.replace(format!("{err:#}"));
+                })
+                .await;
+                attempt += 1;
+            }
+        }
+    }
 }
 
 #[derive(Deserialize, Debug)]
@@ -326,10 +630,143 @@ struct StateUpdate {
     pub color: Option<DeviceColor>,
     #[serde(rename = "colorTemInKelvin")]
     pub color_temperature_kelvin: Option<u32>,
+    /// Present in the response to a command; 1 indicates success
+    pub result: Option<i64>,
     pub sku: Option<String>,
     pub device: Option<String>,
 }
 
+impl StateUpdate {
+    /// Apply the light related fields to status.
+    /// The on/off field is not applied, as it should take
+    /// precedence over any other fields that imply "on".
+    fn apply_light_fields(&self, status: &mut DeviceStatus) {
+        if let Some(v) = self.brightness {
+            status.brightness = v;
+            status.on = v != 0;
+        }
+        if let Some(v) = self.color {
+            status.color = v;
+            status.on = true;
+        }
+        if let Some(v) = self.color_temperature_kelvin {
+            status.color_temperature_kelvin = v;
+            status.on = true;
+        }
+    }
+}
+
+/// Published to `$aws/things/THING/shadow/update/delta`
+#[derive(Deserialize, Debug)]
+struct ShadowDelta {
+    state: StateUpdate,
+}
+
+/// Published to `$aws/things/THING/shadow/update/documents`
+#[derive(Deserialize, Debug)]
+struct ShadowDocuments {
+    current: ShadowDocument,
+}
+
+#[derive(Deserialize, Debug)]
+struct ShadowDocument {
+    state: ShadowDocumentState,
+}
+
+#[derive(Deserialize, Debug)]
+struct ShadowDocumentState {
+    reported: Option<StateUpdate>,
+    desired: Option<StateUpdate>,
+}
+
+/// Plugs that use their own values for the power state
+const PLUG_POWER_SKUS: &[&str] = &["H5080", "H5083"];
+const PLUG_ON: u8 = 17;
+const PLUG_OFF: u8 = 16;
+
+/// Interpret the onOff field of a status report
+fn decode_on_off(sku: &str, value: u8) -> bool {
+    if PLUG_POWER_SKUS.contains(&sku) {
+        value == PLUG_ON
+    } else {
+        value != 0
+    }
+}
+
+/// The name of the AWS IoT thing for a device is the final
+/// component of its device topic
+fn shadow_thing_name(device_topic: &str) -> &str {
+    device_topic.rsplit('/').next().unwrap_or(device_topic)
+}
+
+/// The status that IoT updates for device are applied to
+fn iot_base_status(device: &Device) -> DeviceStatus {
+    match device.iot_device_status.clone() {
+        Some(status) => status,
+        None => match device.device_state() {
+            Some(state) => DeviceStatus {
+                on: state.on,
+                brightness: state.brightness,
+                color: state.color,
+                color_temperature_kelvin: state.kelvin,
+                op: None,
+            },
+            None => DeviceStatus::default(),
+        },
+    }
+}
+
+async fn subscribe_shadow_topics(client: &mosquitto_rs::Client, state: &StateHandle) {
+    for d in state.devices().await {
+        let Some(undoc) = &d.undoc_device_info else {
+            continue;
+        };
+        let Ok(topic) = undoc.entry.device_topic() else {
+            continue;
+        };
+        let thing = shadow_thing_name(topic);
+        for kind in ["update/delta", "update/documents"] {
+            let shadow_topic = format!("$aws/things/{thing}/shadow/{kind}");
+            if let Err(err) = client
+                .subscribe(&shadow_topic, mosquitto_rs::QoS::AtMostOnce)
+                .await
+            {
+                log::warn!("subscribe to shadow topic {shadow_topic} for {d}: {err:#}");
+            }
+        }
+    }
+}
+
+/// Apply a state update that was published to the shadow of thing
+async fn handle_shadow_update(
+    state: &StateHandle,
+    thing: &str,
+    update: StateUpdate,
+) -> anyhow::Result<()> {
+    let Some(device) = state.devices().await.into_iter().find(|d| {
+        d.undoc_device_info
+            .as_ref()
+            .and_then(|undoc| undoc.entry.device_topic().ok())
+            .map(|topic| shadow_thing_name(topic) == thing)
+            .unwrap_or(false)
+    }) else {
+        log::trace!("shadow update for unknown thing {thing}: {update:?}");
+        return Ok(());
+    };
+
+    {
+        let mut device = state.device_mut(&device.sku, &device.id).await;
+        let mut status = iot_base_status(&device);
+        update.apply_light_fields(&mut status);
+        if let Some(on_off) = update.on_off {
+            status.on = decode_on_off(&device.sku, on_off);
+        }
+        device.set_iot_device_status(status);
+    }
+    state.emit_state_changed(&device.id);
+    Ok(())
+}
+
 #[derive(Deserialize, Debug)]
 #[allow(unused)]
 struct OpData {
@@ -368,6 +805,16 @@ impl Packet {
         let device = self.device()?;
         Some((sku, device))
     }
+
+    /// The command that this packet reports on, which can also
+    /// be in a couple of different places
+    fn cmd(&self) -> Option<String> {
+        if let Some(cmd) = &self.cmd {
+            return Some(cmd.to_string());
+        }
+        let msg: serde_json::Value = serde_json::from_str(self.msg.as_deref()?).ok()?;
+        msg.get("cmd")?.as_str().map(|cmd| cmd.to_string())
+    }
 }
 
 async fn run_iot_subscriber(
@@ -375,47 +822,78 @@ async fn run_iot_subscriber(
     state: StateHandle,
     client: mosquitto_rs::Client,
     acct: LoginAccountResponse,
+    initial_session: bool,
+    shadow: bool,
+    heartbeat: &Heartbeat,
 ) -> anyhow::Result<()> {
-    while let Ok(event) = subscriptions.recv().await {
+    let mut connected = true;
+    // Only the very first connection of the process is not a reconnect
+    let mut first_connect = initial_session;
+    loop {
+        heartbeat.beat();
+        // While disconnected, mosquitto is trying to reconnect by itself,
+        // but we don't want to wait forever for that to happen
+        let event = if connected {
+            // Wake up periodically, so that the watchdog can tell that
+            // we are still alive when there is nothing to receive
+            match timeout(IOT_HEARTBEAT_INTERVAL, subscriptions.recv()).await {
+                Ok(event) => event,
+                Err(_) => continue,
+            }
+        } else {
+            timeout(IOT_STALL_TIMEOUT, subscriptions.recv())
+                .await
+                .map_err(|_| {
+                    anyhow::anyhow!("IoT did not reconnect within {IOT_STALL_TIMEOUT:?}")
+                })?
+        };
+        let Ok(event) = event else {
+            break;
+        };
         match event {
             Event::Message(msg) => {
                 let payload = String::from_utf8_lossy(&msg.payload);
                 log::trace!("{} -> {payload}", msg.topic);
 
+                if let Some(update) = IotClient::parse_shadow_update(&msg.topic, &msg.payload) {
+                    let result = match update {
+                        Ok((thing, update)) => {
+                            capture_packet("iot", Direction::Rx, &thing, &msg.payload);
+                            handle_shadow_update(&state, &thing, update).await
+                        }
+                        Err(err) => Err(err),
+                    };
+                    if let Err(err) = result {
+                        log::error!("Handling shadow update {}: {err:#} {payload}", msg.topic);
+                    }
+                    continue;
+                }
+
                 match from_json::<Packet, _>(&msg.payload) {
                     Ok(packet) => {
                         log::debug!("{packet:?}");
                         if let Some((sku, device_id)) = packet.sku_and_device() {
+                            capture_packet("iot", Direction::Rx, device_id, &msg.payload);
+                            let mut off_timer_reported = false;
+                            let mut scene_code_reported = None;
                             {
                                 let mut device = state.device_mut(sku, device_id).await;
-                                let mut state = match device.iot_device_status.clone() {
-                                    Some(state) => state,
-                                    None => match device.device_state() {
-                                        Some(state) => DeviceStatus {
-                                            on: state.on,
-                                            brightness: state.brightness,
-                                            color: state.color,
-                                            color_temperature_kelvin: state.kelvin,
-                                        },
-                                        None => DeviceStatus::default(),
-                                    },
-                                };
-
-                                if let Some(v) = packet.state.brightness {
-                                    state.brightness = v;
-                                    state.on = v != 0;
-                                }
-                                if let Some(v) = packet.state.color {
-                                    state.color = v;
-                                    state.on = true;
-                                }
-                                if let Some(v) = packet.state.color_temperature_kelvin {
-                                    state.color_temperature_kelvin = v;
-                                    state.on = true;
+                                if let Ok(payload) = serde_json::from_slice(&msg.payload) {
+                                    device.set_last_iot_payload(payload);
                                 }
+                                let mut state = iot_base_status(&device);
+                                packet.state.apply_light_fields(&mut state);
 
                                 if let Some(op) = &packet.op {
-                                    for cmd in &op.command {
+                                    if op.command.len() > 1 {
+                                        // Scenes are sent as multi-line commands
+                                        let decoded =
+                                            Base64HexBytes::concat(&op.command).decode_for_sku(sku);
+                                        log::debug!("Decoded all lines: {decoded:?} for {sku}");
+                                    }
+                                    // Some devices report their timers separately
+                                    // from the other status packets
+                                    for cmd in op.command.iter().chain(&op.timer_value) {
                                         let decoded = cmd.decode_for_sku(sku);
                                         log::debug!("Decoded: {decoded:?} for {sku}");
                                         match decoded {
@@ -442,10 +920,133 @@ async fn run_iot_subscriber(
                                                     mode, param,
                                                 );
                                             }
+                                            GoveeBlePacket::NotifyPurifierMode(
+                                                NotifyPurifierMode { mode, param },
+                                            ) => {
+                                                device.set_humidifier_work_mode_and_param(
+                                                    mode, param,
+                                                );
+                                            }
+                                            GoveeBlePacket::NotifyKettleMode(
+                                                NotifyKettleMode { mode, param },
+                                            ) => {
+                                                device.set_humidifier_work_mode_and_param(
+                                                    mode, param,
+                                                );
+                                            }
+                                            GoveeBlePacket::NotifyKettleTemperature(
+                                                NotifyKettleTemperature { current, target },
+                                            ) => {
+                                                device.set_kettle_temperatures(
+                                                    TemperatureValue::with_fahrenheit(
+                                                        current.into(),
+                                                    ),
+                                                    TemperatureValue::with_fahrenheit(
+                                                        target.into(),
+                                                    ),
+                                                );
+                                            }
+                                            GoveeBlePacket::NotifyHeaterMode(
+                                                NotifyHeaterMode { mode, param },
+                                            ) => {
+                                                device.set_humidifier_work_mode_and_param(
+                                                    mode, param,
+                                                );
+                                            }
+                                            GoveeBlePacket::NotifyHeaterTargetTemperature(
+                                                NotifyHeaterTargetTemperature { target, .. },
+                                            ) => {
+                                                device.set_heater_target_temperature(
+                                                    TemperatureValue::with_celsius(
+                                                        target.as_celsius(),
+                                                    ),
+                                                );
+                                            }
+                                            GoveeBlePacket::NotifyOscillation(
+                                                NotifyOscillation { on },
+                                            ) => {
+                                                device.set_toggle_state(
+                                                    DeviceToggle::Oscillation,
+                                                    on,
+                                                );
+                                            }
+                                            GoveeBlePacket::NotifyChildLock(NotifyChildLock {
+                                                on,
+                                            }) => {
+                                                device
+                                                    .set_toggle_state(DeviceToggle::ChildLock, on);
+                                            }
+                                            GoveeBlePacket::NotifyMistLevel(NotifyMistLevel {
+                                                level,
+                                            }) => {
+                                                device.set_humidifier_param(
+                                                    MIST_LEVEL_WORK_MODE,
+                                                    level,
+                                                );
+                                            }
+                                            GoveeBlePacket::NotifyOffTimer(NotifyOffTimer {
+                                                on,
+                                                minutes,
+                                            }) => {
+                                                device.set_off_timer(
+                                                    if on { minutes } else { 0 },
+                                                    false,
+                                                );
+                                                off_timer_reported = true;
+                                            }
+                                            GoveeBlePacket::NotifyWarnings(warnings) => {
+                                                device.set_warnings(warnings);
+                                            }
+                                            GoveeBlePacket::NotifyIceMakerStatus(status) => {
+                                                // An ice maker may be powered on and
+                                                // idle, so this doesn't imply power
+                                                device.set_ice_maker_status(status);
+                                            }
+                                            GoveeBlePacket::NotifyPresence(presence) => {
+                                                device.set_presence_status(presence);
+                                            }
+                                            GoveeBlePacket::NotifyPlugEnergy(energy) => {
+                                                device.set_plug_energy(energy);
+                                            }
+                                            GoveeBlePacket::NotifySegmentColors(colors) => {
+                                                device.set_segment_colors(&colors);
+                                            }
+                                            GoveeBlePacket::NotifyGradient(NotifyGradient {
+                                                on,
+                                            }) => {
+                                                device.gradient.replace(on);
+                                            }
+                                            GoveeBlePacket::NotifySceneCode(NotifySceneCode {
+                                                code,
+                                            }) => {
+                                                state.on = true;
+                                                scene_code_reported.replace(code);
+                                            }
+                                            GoveeBlePacket::SetSceneCode(scene) => {
+                                                // The final line of a scene that was
+                                                // activated via the Govee app
+                                                state.on = true;
+                                                scene_code_reported.replace(scene.code());
+                                            }
                                             GoveeBlePacket::Generic(_) => {
                                                 // Ignore packets that we can't decode
                                             }
+                                            GoveeBlePacket::Custom(_) => {
+                                                // Packets described by codec files are
+                                                // only logged, as above
+                                            }
                                             GoveeBlePacket::SetHumidifierMode(_)
+                                            | GoveeBlePacket::SetPurifierMode(_)
+                                            | GoveeBlePacket::SetKettleMode(_)
+                                            | GoveeBlePacket::SetHeaterMode(_)
+                                            | GoveeBlePacket::SetHeaterTargetTemperature(_)
+                                            | GoveeBlePacket::SetOscillation(_)
+                                            | GoveeBlePacket::SetChildLock(_)
+                                            | GoveeBlePacket::SetMistLevel(_)
+                                            | GoveeBlePacket::SetOffTimer(_)
+                                            | GoveeBlePacket::SetIceMakerMaking(_)
+                                            | GoveeBlePacket::SetSegmentBrightness(_)
+                                            | GoveeBlePacket::SetGradient(_)
                                             | GoveeBlePacket::SetHumidifierNightlight(_) => {
                                                 // Ignore packets that are essentially echoing
                                                 // commands sent to the device
@@ -464,11 +1065,22 @@ async fn run_iot_subscriber(
                                 // Check on/off last, as we can synthesize "on"
                                 // if the other fields are present
                                 if let Some(on_off) = packet.state.on_off {
-                                    state.on = on_off != 0;
+                                    state.on = decode_on_off(sku, on_off);
                                 }
                                 device.set_iot_device_status(state);
                             }
-                            state.notify_of_state_change(device_id).await?;
+                            if off_timer_reported {
+                                state.run_off_timer(device_id).await;
+                            }
+                            if let Some(code) = scene_code_reported {
+                                state.device_reported_scene_code(sku, device_id, code).await;
+                            }
+                            state.emit_state_changed(device_id);
+                            if let Some(reply) =
+                                IotReply::classify(packet.cmd().as_deref(), packet.state.result)
+                            {
+                                state.iot_replies().resolve(device_id, reply);
+                            }
                         }
                     }
                     Err(err) => {
@@ -478,14 +1090,58 @@ async fn run_iot_subscriber(
             }
             Event::Disconnected(reason) => {
                 log::warn!("IoT disconnected with reason {reason}");
+                connected = false;
+                update_iot_health(&state, |h| {
+                    h.set_status(IotConnectionStatus::Disconnected);
+                    h.last_error.replace(format!("disconnected: {reason}"));
+                })
+                .await;
+                state.emit_event(DeviceEvent::TransportLost {
+                    transport: Transport::Iot,
+                });
             }
             Event::Connected(status) => {
                 log::info!("IoT (re)connected with status {status}");
+                connected = true;
 
                 client
                     .subscribe(&acct.topic, mosquitto_rs::QoS::AtMostOnce)
                     .await
                     .context("subscribe to account topic")?;
+
+                if shadow {
+                    // The account topic only carries some of the changes
+                    // that are made outside of govee2mqtt, whereas the
+                    // shadow reflects all of them
+                    subscribe_shadow_topics(&client, &state).await;
+                }
+
+                let reconnected = !first_connect;
+                first_connect = false;
+                update_iot_health(&state, |h| {
+                    if reconnected {
+                        h.reconnects += 1;
+                    }
+                    h.set_status(IotConnectionStatus::Connected);
+                })
+                .await;
+
+                if reconnected {
+                    // We may have missed state changes while we were
+                    // disconnected, so ask the devices to report in
+                    let iot = IotClient {
+                        client: client.clone(),
+                    };
+                    for d in state.devices().await {
+                        if let Some(undoc) = &d.undoc_device_info {
+                            if iot.is_device_compatible(&undoc.entry) {
+                                if let Err(err) = iot.request_status_update(&undoc.entry).await {
+                                    log::warn!("requesting status of {d} after reconnect: {err:#}");
+                                }
+                            }
+                        }
+                    }
+                }
                 // This logic tries to subscribe to the same data that is
                 // being sent to the individual devices, but the server
                 // will close the connection on us when we try this.
@@ -509,3 +1165,73 @@ async fn run_iot_subscriber(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn shadow_updates() {
+        assert_eq!(shadow_thing_name("GD/0123456789abcdef"), "0123456789abcdef");
+
+        let (thing, update) = IotClient::parse_shadow_update(
+            "$aws/things/abc/shadow/update/delta",
+            br#"{"version":3,"state":{"onOff":1,"brightness":40}}"#,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(thing, "abc");
+        assert_eq!(update.on_off, Some(1));
+        assert_eq!(update.brightness, Some(40));
+
+        let (_, update) = IotClient::parse_shadow_update(
+            "$aws/things/abc/shadow/update/documents",
+            br#"{"current":{"state":{"reported":{"onOff":0,"colorTemInKelvin":2700}}}}"#,
+        )
+        .unwrap()
+        .unwrap();
+        let mut status = DeviceStatus::default();
+        update.apply_light_fields(&mut status);
+        assert_eq!(status.color_temperature_kelvin, 2700);
+        assert!(status.on);
+        assert_eq!(update.on_off, Some(0));
+
+        assert!(IotClient::parse_shadow_update("GA/account", b"{}").is_none());
+    }
+
+    #[test]
+    fn command_response() {
+        let packet: Packet = from_json(
+            br#"{"sku":"H6072","device":"AA:BB","cmd":"ptReal","state":{"onOff":1,"result":1}}"#,
+        )
+        .unwrap();
+        assert_eq!(packet.cmd().as_deref(), Some("ptReal"));
+        assert_eq!(packet.state.result, Some(1));
+
+        let packet: Packet = from_json(
+            br#"{"sku":"H6072","device":"AA:BB","msg":"{\"cmd\":\"status\"}","state":{}}"#,
+        )
+        .unwrap();
+        assert_eq!(packet.cmd().as_deref(), Some("status"));
+    }
+
+    #[test]
+    fn plug_power_state() {
+        assert!(decode_on_off("H5080", 17));
+        assert!(!decode_on_off("H5080", 16));
+        assert!(decode_on_off("H6159", 1));
+        assert!(!decode_on_off("H6159", 0));
+    }
+
+    #[test]
+    fn backoff() {
+        assert_eq!(backoff_delay(0, 0), Duration::from_millis(2500));
+        assert_eq!(backoff_delay(0, 2500), Duration::from_secs(5));
+        assert_eq!(backoff_delay(1, 0), Duration::from_secs(5));
+        for random in [0, 1, 12345, u64::MAX] {
+            let delay = backoff_delay(100, random);
+            assert!(delay >= IOT_RECONNECT_MAX / 2);
+            assert!(delay <= IOT_RECONNECT_MAX);
+        }
+    }
+}
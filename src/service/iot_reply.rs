@@ -0,0 +1,156 @@
+//! Correlates the packets that we send to a device via IoT with the
+//! report that the device publishes in response, so that the sender
+//! learns whether the device accepted them. The report itself is
+//! applied to the device state by the IoT subscriber before the sender
+//! is notified, so the sender sees the updated state without waiting
+//! for the next poll.
+use crate::service::iot::IotClient;
+use crate::service::state::State as ServiceState;
+use crate::undoc_api::DeviceEntry;
+use parking_lot::Mutex;
+use std::collections::{HashMap, VecDeque};
+use tokio::sync::oneshot;
+use tokio::time::{timeout, Duration};
+
+/// How long to wait for a device to respond to a command. Not every
+/// firmware responds, so this is kept short.
+pub const IOT_REPLY_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// How a device responded to a command
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IotReply {
+    Accepted,
+    /// The device reported a result other than success
+    Rejected {
+        result: i64,
+    },
+}
+
+impl IotReply {
+    /// Determine whether a report is a response to a ptReal command,
+    /// given its cmd and state.result fields
+    pub fn classify(cmd: Option<&str>, result: Option<i64>) -> Option<Self> {
+        match (cmd, result) {
+            (Some("ptReal"), None | Some(1)) => Some(Self::Accepted),
+            (Some("ptReal"), Some(result)) => Some(Self::Rejected { result }),
+            _ => None,
+        }
+    }
+}
+
+/// The outcome of sending a command that the device did not reject
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IotConfirmation {
+    Accepted,
+    /// The device didn't respond within IOT_REPLY_TIMEOUT, which
+    /// doesn't necessarily mean that the command was lost
+    NoReply,
+}
+
+/// The senders that are waiting for each device to respond, keyed
+/// by device id. Devices respond to commands in the order in which
+/// they were sent, so the oldest waiter receives each reply.
+#[derive(Default)]
+pub struct IotReplies {
+    pending: Mutex<HashMap<String, VecDeque<oneshot::Sender<IotReply>>>>,
+}
+
+impl IotReplies {
+    fn register(&self, device_id: &str) -> oneshot::Receiver<IotReply> {
+        let (tx, rx) = oneshot::channel();
+        self.pending
+            .lock()
+            .entry(device_id.to_string())
+            .or_default()
+            .push_back(tx);
+        rx
+    }
+
+    /// Deliver reply to the oldest sender that is still waiting.
+    /// Returns true if there was one.
+    pub fn resolve(&self, device_id: &str, reply: IotReply) -> bool {
+        let mut pending = self.pending.lock();
+        let Some(waiters) = pending.get_mut(device_id) else {
+            return false;
+        };
+        let mut delivered = false;
+        while let Some(tx) = waiters.pop_front() {
+            // Senders that timed out have dropped their receiver
+            if tx.send(reply).is_ok() {
+                delivered = true;
+                break;
+            }
+        }
+        if waiters.is_empty() {
+            pending.remove(device_id);
+        }
+        delivered
+    }
+}
+
+impl ServiceState {
+    /// Send packets to a device via IoT, and wait for it to respond.
+    /// Returns an error if the device rejects them.
+    pub async fn iot_send_real(
+        &self,
+        iot: &IotClient,
+        device: &DeviceEntry,
+        commands: Vec<String>,
+    ) -> anyhow::Result<IotConfirmation> {
+        let reply = self.iot_replies().register(&device.device);
+        iot.send_real(device, commands).await?;
+
+        match timeout(IOT_REPLY_TIMEOUT, reply).await {
+            Ok(Ok(IotReply::Accepted)) => Ok(IotConfirmation::Accepted),
+            Ok(Ok(IotReply::Rejected { result })) => {
+                anyhow::bail!(
+                    "{} rejected the command with result {result}",
+                    device.device
+                )
+            }
+            Ok(Err(_)) | Err(_) => {
+                log::debug!(
+                    "{} did not respond to ptReal within {IOT_REPLY_TIMEOUT:?}",
+                    device.device
+                );
+                Ok(IotConfirmation::NoReply)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn classify() {
+        assert_eq!(
+            IotReply::classify(Some("ptReal"), None),
+            Some(IotReply::Accepted)
+        );
+        assert_eq!(
+            IotReply::classify(Some("ptReal"), Some(0)),
+            Some(IotReply::Rejected { result: 0 })
+        );
+        assert_eq!(IotReply::classify(Some("status"), Some(1)), None);
+        assert_eq!(IotReply::classify(None, None), None);
+    }
+
+    #[tokio::test]
+    async fn oldest_waiter_first() {
+        let replies = IotReplies::default();
+        let abandoned = replies.register("dev");
+        let first = replies.register("dev");
+        let second = replies.register("dev");
+        drop(abandoned);
+
+        assert!(replies.resolve("dev", IotReply::Accepted));
+        assert!(replies.resolve("dev", IotReply::Rejected { result: 0 }));
+        assert!(!replies.resolve("dev", IotReply::Accepted));
+        assert!(!replies.resolve("other", IotReply::Accepted));
+
+        assert_eq!(first.await.unwrap(), IotReply::Accepted);
+        assert_eq!(second.await.unwrap(), IotReply::Rejected { result: 0 });
+    }
+}
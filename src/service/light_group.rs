@@ -0,0 +1,420 @@
+use crate::hass_mqtt::instance::{remove_entity_config, EntityInstance};
+use crate::hass_mqtt::light::GroupLight;
+use crate::lan_api::DeviceColor;
+use crate::service::device::{Device, DeviceState};
+use crate::service::hass::{
+    apply_light_command, kelvin_to_mired, light_group_state_topic, light_groups_state_topic,
+    topic_safe_string, HassClient, HassLightCommand, IdParameter,
+};
+use crate::service::state::{State as ServiceState, StateHandle};
+use anyhow::Context;
+use mosquitto_rs::router::{Params, Payload, State};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::Path;
+use std::sync::Arc;
+use tokio::task::JoinSet;
+
+/// A named set of devices that is presented to hass as a single light
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct LightGroupConfig {
+    /// Used to identify the group for replacement or deletion,
+    /// and as the name of the light entity
+    pub name: String,
+    /// The device names, ids or other labels that identify the members
+    pub devices: Vec<String>,
+}
+
+impl LightGroupConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.name.trim().is_empty() {
+            anyhow::bail!("light group name must not be empty");
+        }
+        if self.devices.is_empty() {
+            anyhow::bail!("light group {} has no devices", self.name);
+        }
+        Ok(())
+    }
+
+    /// The identifier used for the group in mqtt topics and unique ids
+    pub fn topic_id(&self) -> String {
+        topic_safe_string(&self.name)
+    }
+
+    fn matches(&self, label: &str) -> bool {
+        self.name.eq_ignore_ascii_case(label) || self.topic_id().eq_ignore_ascii_case(label)
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct LightGroupFile {
+    #[serde(default)]
+    pub groups: Vec<LightGroupConfig>,
+}
+
+pub fn load_light_groups_file(path: &Path) -> anyhow::Result<Vec<LightGroupConfig>> {
+    let data = std::fs::read_to_string(path)
+        .with_context(|| format!("reading light groups from {path:?}"))?;
+    let file: LightGroupFile = serde_json::from_str(&data)
+        .with_context(|| format!("parsing light groups from {path:?}"))?;
+    for group in &file.groups {
+        group.validate()?;
+    }
+    Ok(file.groups)
+}
+
+/// The state of a group, derived from the state of its members
+#[derive(Clone, Debug, PartialEq)]
+pub struct GroupState {
+    pub on: bool,
+    pub brightness: u8,
+    /// Set when the majority of the lit members are showing
+    /// a color temperature rather than an rgb color
+    pub kelvin: Option<u32>,
+    pub color: DeviceColor,
+    /// Set only when all of the lit members agree on the scene
+    pub scene: Option<String>,
+}
+
+fn mean<I: Iterator<Item = u32>>(iter: I) -> u32 {
+    let (sum, count) = iter.fold((0u64, 0u64), |(sum, count), v| (sum + v as u64, count + 1));
+    if count == 0 {
+        0
+    } else {
+        ((sum as f64) / (count as f64)).round() as u32
+    }
+}
+
+impl GroupState {
+    /// Compute the consensus of the member states.
+    /// The group is on if any member is on, and the brightness and
+    /// color are averaged over the members that are on.
+    pub fn consensus(states: &[DeviceState]) -> Self {
+        let lit: Vec<&DeviceState> = states
+            .iter()
+            .filter(|s| s.light_on.unwrap_or(false))
+            .collect();
+
+        if lit.is_empty() {
+            return Self {
+                on: false,
+                brightness: 0,
+                kelvin: None,
+                color: DeviceColor::default(),
+                scene: None,
+            };
+        }
+
+        let brightness = mean(lit.iter().map(|s| s.brightness as u32)) as u8;
+
+        let (temp, rgb): (Vec<&DeviceState>, Vec<&DeviceState>) =
+            lit.iter().partition(|s| s.kelvin != 0);
+        let kelvin = if temp.len() > rgb.len() {
+            Some(mean(temp.iter().map(|s| s.kelvin)))
+        } else {
+            None
+        };
+        let color = DeviceColor {
+            r: mean(rgb.iter().map(|s| s.color.r as u32)) as u8,
+            g: mean(rgb.iter().map(|s| s.color.g as u32)) as u8,
+            b: mean(rgb.iter().map(|s| s.color.b as u32)) as u8,
+        };
+
+        let scene = lit[0].scene.clone().filter(|scene| {
+            lit.iter()
+                .all(|s| s.scene.as_deref() == Some(scene.as_str()))
+        });
+
+        Self {
+            on: true,
+            brightness,
+            kelvin,
+            color,
+            scene,
+        }
+    }
+
+    pub fn to_json(&self) -> serde_json::Value {
+        if !self.on {
+            return json!({"state":"OFF"});
+        }
+        match self.kelvin {
+            Some(kelvin) => json!({
+                "state": "ON",
+                "color_mode": "color_temp",
+                "brightness": self.brightness,
+                "color_temp": kelvin_to_mired(kelvin),
+                "effect": self.scene,
+            }),
+            None => json!({
+                "state": "ON",
+                "color_mode": "rgb",
+                "color": {
+                    "r": self.color.r,
+                    "g": self.color.g,
+                    "b": self.color.b,
+                },
+                "brightness": self.brightness,
+                "effect": self.scene,
+            }),
+        }
+    }
+}
+
+impl ServiceState {
+    pub async fn light_group_by_label(&self, label: &str) -> Option<LightGroupConfig> {
+        self.get_light_groups()
+            .await
+            .into_iter()
+            .find(|g| g.matches(label))
+    }
+
+    /// Resolve the members of the group to the devices that we know about
+    pub async fn light_group_members(&self, group: &LightGroupConfig) -> Vec<Device> {
+        let mut members = vec![];
+        for label in &group.devices {
+            match self.resolve_device(label).await {
+                Some(device) => members.push(device),
+                None => log::trace!("light group {}: device '{label}' not found", group.name),
+            }
+        }
+        members
+    }
+
+    /// Publish the consensus state of the group to its state topic
+    pub async fn publish_light_group_state(
+        &self,
+        group: &LightGroupConfig,
+        client: &HassClient,
+    ) -> anyhow::Result<()> {
+        let states: Vec<DeviceState> = self
+            .light_group_members(group)
+            .await
+            .iter()
+            .filter_map(|d| d.device_state())
+            .collect();
+        let group_state = GroupState::consensus(&states);
+        client
+            .publish_obj(light_group_state_topic(group), group_state.to_json())
+            .await
+    }
+
+    /// Update the state of any groups that contain the device
+    pub async fn notify_light_groups_of_state_change(&self, device: &Device) -> anyhow::Result<()> {
+        let Some(client) = self.get_hass_client().await else {
+            return Ok(());
+        };
+        for group in self.get_light_groups().await {
+            if self
+                .light_group_members(&group)
+                .await
+                .iter()
+                .any(|d| d.id == device.id)
+            {
+                self.publish_light_group_state(&group, &client).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Apply the command to all members of the group concurrently
+    pub async fn light_group_command(
+        self: &Arc<Self>,
+        group: &LightGroupConfig,
+        command: HassLightCommand,
+    ) -> anyhow::Result<()> {
+        let mut tasks = JoinSet::new();
+        for member in self.light_group_members(group).await {
+            let state = self.clone();
+            let command = command.clone();
+            tasks.spawn(async move {
+                let device = state.resolve_device_for_control(&member.id).await?;
+                apply_light_command(&state, &device, &command)
+                    .await
+                    .with_context(|| format!("{device}"))
+            });
+        }
+
+        let mut failures = vec![];
+        while let Some(result) = tasks.join_next().await {
+            if let Err(err) = result? {
+                failures.push(format!("{err:#}"));
+            }
+        }
+
+        if !failures.is_empty() {
+            anyhow::bail!(
+                "light group {}: {} member(s) failed: {}",
+                group.name,
+                failures.len(),
+                failures.join(", ")
+            );
+        }
+        Ok(())
+    }
+
+    /// Add or replace a light group, matching by the topic id
+    /// derived from its name, so that groups cannot share topics
+    pub async fn set_light_group(&self, group: LightGroupConfig) -> anyhow::Result<()> {
+        group.validate()?;
+        let mut groups = self.get_light_groups().await;
+        let topic_id = group.topic_id();
+        groups.retain(|g| g.topic_id() != topic_id);
+        groups.push(group);
+        self.set_light_groups(groups).await;
+        Ok(())
+    }
+
+    pub async fn delete_light_group(&self, name: &str) -> Option<LightGroupConfig> {
+        let mut groups = self.get_light_groups().await;
+        let position = groups.iter().position(|g| g.matches(name))?;
+        let removed = groups.remove(position);
+        self.set_light_groups(groups).await;
+        Some(removed)
+    }
+
+    /// Publish the current set of light groups to the light groups state topic
+    pub async fn publish_light_groups(&self) -> anyhow::Result<()> {
+        if let Some(hass) = self.get_hass_client().await {
+            let groups = self.get_light_groups().await;
+            hass.publish_obj(light_groups_state_topic(), LightGroupFile { groups })
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// HASS is sending a command to a light group
+pub async fn mqtt_light_group_command(
+    Payload(payload): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let group = state
+        .light_group_by_label(&id)
+        .await
+        .ok_or_else(|| anyhow::anyhow!("light group '{id}' not found"))?;
+
+    let command: HassLightCommand = serde_json::from_str(&payload)?;
+    log::info!("Command for light group {}: {payload}", group.name);
+
+    state.light_group_command(&group, command).await
+}
+
+/// Add or replace a light group described by the JSON payload
+pub async fn mqtt_light_group_set(
+    Payload(payload): Payload<String>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let group: LightGroupConfig = serde_json::from_str(&payload)?;
+    log::info!("Setting light group {}", group.name);
+    state.set_light_group(group.clone()).await?;
+
+    if let Some(client) = state.get_hass_client().await {
+        let light = GroupLight::new(&group, &state).await;
+        light.publish_config(&state, &client).await?;
+        light.notify_state(&client).await?;
+    }
+    state.publish_light_groups().await
+}
+
+/// Delete the light group whose name is the payload
+pub async fn mqtt_light_group_delete(
+    Payload(name): Payload<String>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    match state.delete_light_group(name.trim()).await {
+        Some(group) => {
+            if let Some(client) = state.get_hass_client().await {
+                remove_entity_config("light", &state, &client, &GroupLight::unique_id(&group))
+                    .await?;
+            }
+        }
+        None => log::warn!("There is no light group named {name}"),
+    }
+    state.publish_light_groups().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn member(on: bool, brightness: u8, kelvin: u32, r: u8, scene: Option<&str>) -> DeviceState {
+        DeviceState {
+            on,
+            light_on: Some(on),
+            online: None,
+            kelvin,
+            color: DeviceColor { r, g: 0, b: 0 },
+            brightness,
+            scene: scene.map(|s| s.to_string()),
+            source: "test",
+            updated: chrono::Utc::now(),
+        }
+    }
+
+    #[test]
+    fn consensus() {
+        let off = GroupState::consensus(&[member(false, 50, 0, 0, None)]);
+        assert!(!off.on);
+        assert_eq!(off.to_json(), json!({"state":"OFF"}));
+
+        let mixed = GroupState::consensus(&[
+            member(true, 20, 0, 100, Some("Aurora")),
+            member(true, 60, 0, 200, Some("Aurora")),
+            member(false, 100, 4000, 0, None),
+        ]);
+        assert_eq!(
+            mixed,
+            GroupState {
+                on: true,
+                brightness: 40,
+                kelvin: None,
+                color: DeviceColor { r: 150, g: 0, b: 0 },
+                scene: Some("Aurora".to_string()),
+            }
+        );
+
+        let temp = GroupState::consensus(&[
+            member(true, 100, 3000, 0, Some("Aurora")),
+            member(true, 100, 5000, 0, None),
+            member(true, 100, 0, 255, None),
+        ]);
+        assert_eq!(temp.kelvin, Some(4000));
+        assert_eq!(temp.scene, None);
+    }
+
+    #[test]
+    fn group_file() {
+        let file: LightGroupFile = serde_json::from_str(
+            r#"{"groups": [{"name": "Living Room", "devices": ["Lamp", "AA:BB:CC"]}]}"#,
+        )
+        .unwrap();
+        let group = &file.groups[0];
+        group.validate().unwrap();
+        assert_eq!(group.topic_id(), "living_room");
+        assert!(group.matches("living room"));
+        assert!(group.matches("living_room"));
+    }
+
+    #[tokio::test]
+    async fn replace_group() {
+        let state = ServiceState::new();
+        let group = |name: &str, device: &str| LightGroupConfig {
+            name: name.to_string(),
+            devices: vec![device.to_string()],
+        };
+        state
+            .set_light_group(group("Kitchen", "Lamp"))
+            .await
+            .unwrap();
+        state
+            .set_light_group(group("kitchen", "Strip"))
+            .await
+            .unwrap();
+        assert_eq!(
+            state.get_light_groups().await,
+            vec![group("kitchen", "Strip")]
+        );
+    }
+}
@@ -0,0 +1,457 @@
+//! Advertises the bridge via mDNS/DNS-SD as `_govee2mqtt._tcp`,
+//! and optionally browses for other instances so that we can warn
+//! about multiple bridges controlling the same devices.
+//! This is a minimal responder that only knows about our own service.
+use crate::version_info::govee_version;
+use anyhow::Context;
+use std::collections::HashSet;
+use std::net::{Ipv4Addr, SocketAddr};
+use tokio::net::UdpSocket;
+use tokio::time::{sleep, Duration};
+
+const MDNS_ADDR: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const MDNS_PORT: u16 = 5353;
+const SERVICE_TYPE: &str = "_govee2mqtt._tcp.local";
+const TTL: u32 = 120;
+
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_TXT: u16 = 16;
+const TYPE_SRV: u16 = 33;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+/// Set on records that we are authoritative for
+const CACHE_FLUSH: u16 = 0x8000;
+
+/// Describes the service that we advertise
+#[derive(Debug, Clone)]
+pub struct ServiceInfo {
+    /// Uniquely identifies this running instance
+    pub id: String,
+    pub hostname: String,
+    pub http_port: u16,
+    pub addresses: Vec<Ipv4Addr>,
+}
+
+impl ServiceInfo {
+    fn instance_name(&self) -> String {
+        format!("govee2mqtt-{}.{SERVICE_TYPE}", self.hostname)
+    }
+
+    fn host_name(&self) -> String {
+        format!("{}.local", self.hostname)
+    }
+
+    fn txt(&self) -> Vec<String> {
+        vec![
+            format!("id={}", self.id),
+            format!("version={}", govee_version()),
+            format!("http_port={}", self.http_port),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Question {
+    name: String,
+    qtype: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum RecordData {
+    Ptr(String),
+    Txt(Vec<String>),
+    Other,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Record {
+    name: String,
+    data: RecordData,
+}
+
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Message {
+    is_response: bool,
+    questions: Vec<Question>,
+    /// The answer, authority and additional records
+    records: Vec<Record>,
+}
+
+fn encode_name(out: &mut Vec<u8>, name: &str) {
+    for label in name.split('.').filter(|l| !l.is_empty()) {
+        let label = &label.as_bytes()[..label.len().min(63)];
+        out.push(label.len() as u8);
+        out.extend_from_slice(label);
+    }
+    out.push(0);
+}
+
+fn encode_record(out: &mut Vec<u8>, name: &str, rtype: u16, class: u16, rdata: &[u8]) {
+    encode_name(out, name);
+    out.extend_from_slice(&rtype.to_be_bytes());
+    out.extend_from_slice(&class.to_be_bytes());
+    out.extend_from_slice(&TTL.to_be_bytes());
+    out.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+    out.extend_from_slice(rdata);
+}
+
+fn encode_header(out: &mut Vec<u8>, flags: u16, questions: u16, answers: u16, additional: u16) {
+    out.extend_from_slice(&0u16.to_be_bytes()); // id
+    out.extend_from_slice(&flags.to_be_bytes());
+    out.extend_from_slice(&questions.to_be_bytes());
+    out.extend_from_slice(&answers.to_be_bytes());
+    out.extend_from_slice(&0u16.to_be_bytes()); // authority
+    out.extend_from_slice(&additional.to_be_bytes());
+}
+
+/// Build the unsolicited announcement/response for our service
+fn encode_response(info: &ServiceInfo) -> Vec<u8> {
+    let instance = info.instance_name();
+    let host = info.host_name();
+
+    let mut out = vec![];
+    // QR + AA
+    encode_header(&mut out, 0x8400, 0, 1, 2 + info.addresses.len() as u16);
+
+    let mut ptr = vec![];
+    encode_name(&mut ptr, &instance);
+    encode_record(&mut out, SERVICE_TYPE, TYPE_PTR, CLASS_IN, &ptr);
+
+    let mut srv = vec![];
+    srv.extend_from_slice(&0u16.to_be_bytes()); // priority
+    srv.extend_from_slice(&0u16.to_be_bytes()); // weight
+    srv.extend_from_slice(&info.http_port.to_be_bytes());
+    encode_name(&mut srv, &host);
+    encode_record(&mut out, &instance, TYPE_SRV, CLASS_IN | CACHE_FLUSH, &srv);
+
+    let mut txt = vec![];
+    for entry in info.txt() {
+        txt.push(entry.len() as u8);
+        txt.extend_from_slice(entry.as_bytes());
+    }
+    encode_record(&mut out, &instance, TYPE_TXT, CLASS_IN | CACHE_FLUSH, &txt);
+
+    for addr in &info.addresses {
+        encode_record(
+            &mut out,
+            &host,
+            TYPE_A,
+            CLASS_IN | CACHE_FLUSH,
+            &addr.octets(),
+        );
+    }
+
+    out
+}
+
+/// Build a query for instances of our service type
+fn encode_browse_query() -> Vec<u8> {
+    let mut out = vec![];
+    encode_header(&mut out, 0, 1, 0, 0);
+    encode_name(&mut out, SERVICE_TYPE);
+    out.extend_from_slice(&TYPE_PTR.to_be_bytes());
+    out.extend_from_slice(&CLASS_IN.to_be_bytes());
+    out
+}
+
+fn read_u16(data: &[u8], pos: usize) -> anyhow::Result<u16> {
+    let bytes = data
+        .get(pos..pos + 2)
+        .ok_or_else(|| anyhow::anyhow!("EOF reading u16 at {pos}"))?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+}
+
+/// Decode a possibly compressed name starting at pos, returning
+/// the name and the position following it
+fn decode_name(data: &[u8], mut pos: usize) -> anyhow::Result<(String, usize)> {
+    let mut labels = vec![];
+    let mut end = None;
+    // Guard against pointer loops
+    for _ in 0..128 {
+        let len = *data
+            .get(pos)
+            .ok_or_else(|| anyhow::anyhow!("EOF reading name at {pos}"))?;
+        if len == 0 {
+            return Ok((labels.join("."), end.unwrap_or(pos + 1)));
+        }
+        if len & 0xc0 == 0xc0 {
+            let target = (read_u16(data, pos)? & 0x3fff) as usize;
+            end.get_or_insert(pos + 2);
+            pos = target;
+            continue;
+        }
+        let label = data
+            .get(pos + 1..pos + 1 + len as usize)
+            .ok_or_else(|| anyhow::anyhow!("EOF reading label at {pos}"))?;
+        labels.push(String::from_utf8_lossy(label).to_string());
+        pos += 1 + len as usize;
+    }
+    anyhow::bail!("name is too long or has a pointer loop");
+}
+
+fn decode_message(data: &[u8]) -> anyhow::Result<Message> {
+    let flags = read_u16(data, 2)?;
+    let num_questions = read_u16(data, 4)?;
+    let num_records =
+        read_u16(data, 6)? as usize + read_u16(data, 8)? as usize + read_u16(data, 10)? as usize;
+
+    let mut message = Message {
+        is_response: flags & 0x8000 != 0,
+        ..Message::default()
+    };
+
+    let mut pos = 12;
+    for _ in 0..num_questions {
+        let (name, next) = decode_name(data, pos)?;
+        let qtype = read_u16(data, next)?;
+        message.questions.push(Question { name, qtype });
+        pos = next + 4;
+    }
+
+    for _ in 0..num_records {
+        let (name, next) = decode_name(data, pos)?;
+        let rtype = read_u16(data, next)?;
+        let rdlen = read_u16(data, next + 8)? as usize;
+        let rdata_pos = next + 10;
+        let rdata = data
+            .get(rdata_pos..rdata_pos + rdlen)
+            .ok_or_else(|| anyhow::anyhow!("EOF reading rdata at {rdata_pos}"))?;
+
+        let record_data = match rtype {
+            TYPE_PTR => RecordData::Ptr(decode_name(data, rdata_pos)?.0),
+            TYPE_TXT => {
+                let mut entries = vec![];
+                let mut txt = rdata;
+                while let Some((&len, rest)) = txt.split_first() {
+                    let len = (len as usize).min(rest.len());
+                    entries.push(String::from_utf8_lossy(&rest[..len]).to_string());
+                    txt = &rest[len..];
+                }
+                RecordData::Txt(entries)
+            }
+            _ => RecordData::Other,
+        };
+        message.records.push(Record {
+            name,
+            data: record_data,
+        });
+        pos = rdata_pos + rdlen;
+    }
+
+    Ok(message)
+}
+
+/// Returns true if the query is asking about our service
+fn is_query_for_us(message: &Message, info: &ServiceInfo) -> bool {
+    let instance = info.instance_name();
+    !message.is_response
+        && message.questions.iter().any(|q| {
+            matches!(q.qtype, TYPE_PTR | TYPE_SRV | TYPE_TXT | TYPE_ANY)
+                && (q.name.eq_ignore_ascii_case(SERVICE_TYPE)
+                    || q.name.eq_ignore_ascii_case(&instance))
+        })
+}
+
+/// Extract the ids of other instances of our service from a response
+fn other_instance_ids(message: &Message, our_id: &str) -> Vec<(String, String)> {
+    if !message.is_response {
+        return vec![];
+    }
+    let mut result = vec![];
+    for record in &message.records {
+        let RecordData::Txt(entries) = &record.data else {
+            continue;
+        };
+        if !record
+            .name
+            .to_ascii_lowercase()
+            .ends_with(&format!(".{SERVICE_TYPE}").to_ascii_lowercase())
+        {
+            continue;
+        }
+        for entry in entries {
+            if let Some(id) = entry.strip_prefix("id=") {
+                if id != our_id {
+                    result.push((record.name.clone(), id.to_string()));
+                }
+            }
+        }
+    }
+    result
+}
+
+fn hostname() -> String {
+    #[cfg(unix)]
+    {
+        let mut buf = [0u8; 256];
+        let res = unsafe { libc::gethostname(buf.as_mut_ptr() as *mut libc::c_char, buf.len()) };
+        if res == 0 {
+            let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+            let name = String::from_utf8_lossy(&buf[..len]);
+            // Use only the first component of a fully qualified name
+            if let Some(name) = name.split('.').next().filter(|n| !n.is_empty()) {
+                return name.to_string();
+            }
+        }
+    }
+    "govee2mqtt".to_string()
+}
+
+fn local_addresses() -> Vec<Ipv4Addr> {
+    match if_addrs::get_if_addrs() {
+        Ok(ifaces) => ifaces
+            .into_iter()
+            .filter(|iface| !iface.is_loopback())
+            .filter_map(|iface| match iface.ip() {
+                std::net::IpAddr::V4(v4) => Some(v4),
+                std::net::IpAddr::V6(_) => None,
+            })
+            .collect(),
+        Err(err) => {
+            log::error!("get_if_addrs: {err:#}");
+            vec![]
+        }
+    }
+}
+
+fn mdns_socket() -> anyhow::Result<UdpSocket> {
+    use socket2::{Domain, Protocol, Socket, Type};
+
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    // Share the port with any other responders on this host
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    socket
+        .bind(&SocketAddr::from((Ipv4Addr::UNSPECIFIED, MDNS_PORT)).into())
+        .context("binding to the mDNS port")?;
+    socket.join_multicast_v4(&MDNS_ADDR, &Ipv4Addr::UNSPECIFIED)?;
+    socket.set_multicast_loop_v4(true)?;
+    socket.set_nonblocking(true)?;
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+/// Advertise our service, responding to queries for it until
+/// an error occurs. If browse is true, also look for other instances
+/// of the service and warn about them.
+pub async fn run_mdns_responder(http_port: u16, browse: bool) -> anyhow::Result<()> {
+    let info = ServiceInfo {
+        id: uuid::Uuid::new_v4().simple().to_string(),
+        hostname: hostname(),
+        http_port,
+        addresses: local_addresses(),
+    };
+    let socket = mdns_socket()?;
+    let group = SocketAddr::from((MDNS_ADDR, MDNS_PORT));
+    log::info!("Advertising {} via mDNS", info.instance_name());
+
+    let response = encode_response(&info);
+    // Announce ourselves twice, per RFC 6762 section 8.3
+    socket.send_to(&response, group).await?;
+    sleep(Duration::from_secs(1)).await;
+    socket.send_to(&response, group).await?;
+
+    if browse {
+        socket.send_to(&encode_browse_query(), group).await?;
+    }
+
+    let mut warned = HashSet::new();
+    let mut buf = [0u8; 9000];
+    loop {
+        let (len, addr) = socket.recv_from(&mut buf).await?;
+        let message = match decode_message(&buf[..len]) {
+            Ok(message) => message,
+            Err(err) => {
+                log::trace!("mdns: ignoring packet from {addr}: {err:#}");
+                continue;
+            }
+        };
+
+        if is_query_for_us(&message, &info) {
+            log::trace!("mdns: responding to query from {addr}");
+            socket.send_to(&response, group).await?;
+        }
+
+        if browse {
+            for (name, id) in other_instance_ids(&message, &info.id) {
+                if warned.insert(id) {
+                    log::warn!(
+                        "Found another govee2mqtt instance {name} at {addr}. \
+                        Running multiple bridges against the same devices \
+                        can lead to conflicting control and duplicate entities."
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn info() -> ServiceInfo {
+        ServiceInfo {
+            id: "abc123".to_string(),
+            hostname: "myhost".to_string(),
+            http_port: 8056,
+            addresses: vec![Ipv4Addr::new(10, 0, 0, 2)],
+        }
+    }
+
+    #[test]
+    fn response_round_trip() {
+        let info = info();
+        let message = decode_message(&encode_response(&info)).unwrap();
+        assert!(message.is_response);
+        assert_eq!(message.records.len(), 4);
+        assert_eq!(
+            message.records[0],
+            Record {
+                name: SERVICE_TYPE.to_string(),
+                data: RecordData::Ptr(info.instance_name()),
+            }
+        );
+        assert_eq!(
+            message.records[2].data,
+            RecordData::Txt(vec![
+                "id=abc123".to_string(),
+                format!("version={}", govee_version()),
+                "http_port=8056".to_string(),
+            ])
+        );
+
+        assert_eq!(other_instance_ids(&message, "abc123"), vec![]);
+        assert_eq!(
+            other_instance_ids(&message, "other"),
+            vec![(info.instance_name(), "abc123".to_string())]
+        );
+    }
+
+    #[test]
+    fn query() {
+        let message = decode_message(&encode_browse_query()).unwrap();
+        assert!(is_query_for_us(&message, &info()));
+
+        // A query for the instance name, using a compression pointer
+        // back to the service type in the first question
+        let mut data = vec![];
+        encode_header(&mut data, 0, 2, 0, 0);
+        encode_name(&mut data, SERVICE_TYPE);
+        data.extend_from_slice(&TYPE_A.to_be_bytes());
+        data.extend_from_slice(&CLASS_IN.to_be_bytes());
+        data.extend_from_slice(&[14]);
+        data.extend_from_slice(b"govee2mqtt-foo");
+        data.extend_from_slice(&[0xc0, 12]);
+        data.extend_from_slice(&TYPE_SRV.to_be_bytes());
+        data.extend_from_slice(&CLASS_IN.to_be_bytes());
+
+        let message = decode_message(&data).unwrap();
+        assert_eq!(
+            message.questions[1].name,
+            format!("govee2mqtt-foo.{SERVICE_TYPE}")
+        );
+        assert!(!is_query_for_us(&message, &info()));
+    }
+}
@@ -1,7 +1,47 @@
+pub mod account_sync;
+pub mod ble_scanner;
+pub mod command_ack;
+pub mod command_queue;
+pub mod control_transport;
 pub mod coordinator;
+pub mod crash;
+pub mod debounce;
 pub mod device;
+pub mod device_config;
+pub mod device_index;
+pub mod diagnostics;
+pub mod discovery_overrides;
+pub mod discovery_purge;
+pub mod effects;
+pub mod events;
+pub mod firmware;
 pub mod hass;
+pub mod health;
 pub mod http;
 pub mod iot;
+pub mod iot_reply;
+pub mod light_group;
+pub mod mdns;
+pub mod off_timer;
+pub mod optimistic;
+pub mod packet_capture;
+pub mod poll_intervals;
+pub mod poll_scheduler;
+pub mod power;
 pub mod quirks;
+pub mod raw_command;
+pub mod read_only;
+pub mod rename;
+pub mod rooms;
+pub mod scene_attributes;
+pub mod scene_categories;
+pub mod scene_filter;
+pub mod scene_preview;
+pub mod schedules;
+pub mod segments;
+pub mod shutdown;
+pub mod snapshot;
 pub mod state;
+pub mod transport;
+pub mod wakeup;
+pub mod watchdog;
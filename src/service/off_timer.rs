@@ -0,0 +1,120 @@
+//! Countdown timers that turn a device off after a number of minutes.
+//! Appliances that have their own off timer are programmed via packets;
+//! for everything else, the countdown is run by govee2mqtt.
+use crate::ble::{Base64HexBytes, SetOffTimer};
+use crate::service::device::Device;
+use crate::service::state::State as ServiceState;
+use serde_json::json;
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+
+/// The longest countdown that can be set
+pub const MAX_OFF_TIMER_MINUTES: u16 = 720;
+
+/// How often the remaining time is re-published while counting down
+const TICK_INTERVAL: Duration = Duration::from_secs(60);
+
+impl ServiceState {
+    /// Turn the device off after minutes, or cancel the
+    /// countdown if minutes is zero
+    pub async fn device_set_off_timer(
+        self: &Arc<Self>,
+        device: &Device,
+        minutes: u16,
+    ) -> anyhow::Result<()> {
+        self.run_device_command(device, "off_timer", json!({"minutes": minutes}), || {
+            self.device_set_off_timer_impl(device, minutes)
+        })
+        .await
+    }
+
+    async fn device_set_off_timer_impl(
+        self: &Arc<Self>,
+        device: &Device,
+        minutes: u16,
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            minutes <= MAX_OFF_TIMER_MINUTES,
+            "{minutes} minutes is longer than the maximum of {MAX_OFF_TIMER_MINUTES}"
+        );
+
+        let mut local = true;
+        if let Ok(command) = Base64HexBytes::encode_for_sku(
+            &device.sku,
+            &SetOffTimer {
+                on: minutes > 0,
+                minutes,
+            },
+        ) {
+            if let Some(iot) = self.get_iot_client().await {
+                if let Some(info) = &device.undoc_device_info {
+                    log::info!("Using IoT API to set {device} off timer to {minutes} minutes");
+                    self.iot_send_real(&iot, &info.entry, command.base64())
+                        .await?;
+                    local = false;
+                }
+            }
+        }
+        if local {
+            log::info!("Setting local off timer for {device} to {minutes} minutes");
+        }
+
+        self.device_mut(&device.sku, &device.id)
+            .await
+            .set_off_timer(minutes, local);
+        self.run_off_timer(&device.id).await;
+        Ok(())
+    }
+
+    /// (Re)start the countdown for the off timer of the device,
+    /// which keeps hass informed of the remaining time and, for
+    /// local timers, turns the device off when it expires.
+    pub async fn run_off_timer(self: &Arc<Self>, device_id: &str) {
+        if let Some(prior) = self.take_off_timer_task(device_id).await {
+            prior.abort();
+        }
+        let Some(timer) = self
+            .device_by_id(device_id)
+            .await
+            .and_then(|device| device.off_timer)
+        else {
+            return;
+        };
+
+        let state = self.clone();
+        let id = device_id.to_string();
+        let task = tokio::spawn(async move {
+            loop {
+                let remaining = timer.remaining().to_std().unwrap_or_default();
+                if remaining.is_zero() {
+                    break;
+                }
+                sleep(remaining.min(TICK_INTERVAL)).await;
+                state.emit_state_changed(&id);
+            }
+
+            if let Some(device) = state.device_by_id(&id).await {
+                if timer.local {
+                    log::info!("Off timer for {device} expired; turning it off");
+                    if let Err(err) = state.device_power_on(&device, false).await {
+                        log::error!("off timer for {device}: {err:#}");
+                    }
+                }
+                state
+                    .device_mut(&device.sku, &device.id)
+                    .await
+                    .off_timer
+                    .take();
+            }
+            state.take_off_timer_task(&id).await;
+            state.emit_state_changed(&id);
+        });
+
+        if let Some(prior) = self
+            .set_off_timer_task(device_id, task.abort_handle())
+            .await
+        {
+            prior.abort();
+        }
+    }
+}
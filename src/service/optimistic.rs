@@ -0,0 +1,75 @@
+//! Optimistic state updates. When enabled, the expected effect of a
+//! command is reported to Home Assistant as soon as the command is
+//! received, rather than once the device reports it, which removes
+//! the lag between toggling a light and the UI reflecting it.
+//! If the device subsequently reports a different state, or the
+//! command fails, the optimistic state is discarded.
+use crate::service::device::Device;
+use crate::service::state::State as ServiceState;
+use serde_json::Value as JsonValue;
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+
+/// How long the optimistic state is reported after the command
+/// was sent, unless the device reports a newer state first
+const OPTIMISTIC_TIMEOUT: Duration = Duration::from_secs(15);
+
+impl ServiceState {
+    /// Report the expected effect of command on device.
+    /// Returns true if the state was updated.
+    pub async fn apply_optimistic_command(
+        self: &Arc<Self>,
+        device: &Device,
+        command: &str,
+        payload: &JsonValue,
+    ) -> bool {
+        if !self.is_optimistic().await {
+            return false;
+        }
+
+        self.device_mut(&device.sku, &device.id)
+            .await
+            .apply_optimistic_command(command, payload);
+        self.emit_state_changed(&device.id);
+        true
+    }
+
+    /// The command was sent successfully. Allow the device to
+    /// override the optimistic state, and discard it after
+    /// OPTIMISTIC_TIMEOUT if the device hasn't.
+    pub async fn settle_optimistic_command(self: &Arc<Self>, device: &Device) {
+        let Some(settled_at) = self
+            .device_mut(&device.sku, &device.id)
+            .await
+            .settle_optimistic_status()
+        else {
+            return;
+        };
+
+        let state = self.clone();
+        let device = device.clone();
+        tokio::spawn(async move {
+            sleep(OPTIMISTIC_TIMEOUT).await;
+            state
+                .discard_optimistic_state(&device, Some(settled_at))
+                .await;
+        });
+    }
+
+    /// Discard the optimistic state and report the state that the
+    /// device last reported
+    pub async fn discard_optimistic_state(
+        self: &Arc<Self>,
+        device: &Device,
+        settled_at: Option<chrono::DateTime<chrono::Utc>>,
+    ) {
+        let cleared = self
+            .device_mut(&device.sku, &device.id)
+            .await
+            .clear_optimistic_status(settled_at);
+        if cleared {
+            log::trace!("Discarded optimistic state for {device}");
+            self.emit_state_changed(&device.id);
+        }
+    }
+}
@@ -0,0 +1,152 @@
+//! Opt-in capture of the raw traffic exchanged with devices via the
+//! LAN and IoT transports. Each packet is published to
+//! `gv2mqtt/debug/{id}` along with the hex and base64 forms of any
+//! encoded BLE commands that it carries, so that users can help to
+//! decode the protocol for SKUs that are not yet supported.
+use crate::service::hass::{base_topic, device_topic_id};
+use crate::service::state::StateHandle;
+use async_channel::{Receiver, Sender};
+use chrono::{DateTime, Utc};
+use once_cell::sync::OnceCell;
+use serde::Serialize;
+use serde_json::Value as JsonValue;
+
+/// How many packets may be waiting to be published before
+/// further packets are dropped
+const CAPTURE_BACKLOG: usize = 256;
+
+static CAPTURE: OnceCell<Sender<CapturedPacket>> = OnceCell::new();
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Direction {
+    Tx,
+    Rx,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+struct EncodedCommand {
+    hex: String,
+    base64: String,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct CapturedPacket {
+    /// The device id, or its ip address for LAN packets
+    /// that don't identify the device
+    device: String,
+    transport: &'static str,
+    direction: Direction,
+    payload: JsonValue,
+    commands: Vec<EncodedCommand>,
+    timestamp: DateTime<Utc>,
+}
+
+/// Record a packet. This is a no-op unless capture was enabled
+/// via enable_packet_capture.
+pub fn capture_packet(transport: &'static str, direction: Direction, device: &str, data: &[u8]) {
+    let Some(tx) = CAPTURE.get() else {
+        return;
+    };
+
+    let payload = serde_json::from_slice(data)
+        .unwrap_or_else(|_| JsonValue::String(String::from_utf8_lossy(data).to_string()));
+    let mut commands = vec![];
+    collect_commands(&payload, &mut commands);
+
+    let packet = CapturedPacket {
+        device: device.to_string(),
+        transport,
+        direction,
+        payload,
+        commands,
+        timestamp: Utc::now(),
+    };
+    if tx.try_send(packet).is_err() {
+        log::trace!("packet capture backlog is full; dropping packet");
+    }
+}
+
+/// Find the base64 encoded BLE commands that are carried by
+/// ptReal and similar messages in their `command` arrays
+fn collect_commands(value: &JsonValue, commands: &mut Vec<EncodedCommand>) {
+    match value {
+        JsonValue::Object(map) => {
+            for (key, value) in map {
+                match (key.as_str(), value) {
+                    ("command", JsonValue::Array(items)) => {
+                        for base64 in items.iter().filter_map(|item| item.as_str()) {
+                            if let Ok(bytes) = data_encoding::BASE64.decode(base64.as_bytes()) {
+                                commands.push(EncodedCommand {
+                                    hex: hex::encode(bytes),
+                                    base64: base64.to_string(),
+                                });
+                            }
+                        }
+                    }
+                    _ => collect_commands(value, commands),
+                }
+            }
+        }
+        JsonValue::Array(items) => {
+            for item in items {
+                collect_commands(item, commands);
+            }
+        }
+        _ => {}
+    }
+}
+
+/// Start capturing packets. The returned receiver must be
+/// passed to run_packet_capture to publish them.
+pub fn enable_packet_capture() -> Option<Receiver<CapturedPacket>> {
+    let (tx, rx) = async_channel::bounded(CAPTURE_BACKLOG);
+    CAPTURE.set(tx).ok()?;
+    log::warn!("Packet capture is enabled; raw device traffic will be published to MQTT");
+    Some(rx)
+}
+
+pub fn debug_topic(id: &str) -> String {
+    format!("{}/debug/{id}", base_topic())
+}
+
+pub async fn run_packet_capture(state: StateHandle, rx: Receiver<CapturedPacket>) {
+    while let Ok(packet) = rx.recv().await {
+        let Some(hass) = state.get_hass_client().await else {
+            continue;
+        };
+        let id = match state.resolve_device(&packet.device).await {
+            Some(device) => device_topic_id(&device),
+            None => packet.device.replace(':', ""),
+        };
+        if let Err(err) = hass.publish_obj(debug_topic(&id), &packet).await {
+            log::warn!("Failed to publish captured packet for {id}: {err:#}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn captured_commands() {
+        let payload = serde_json::json!({
+            "msg": {
+                "cmd": "ptReal",
+                "data": {
+                    "command": ["MwEBAAAAAAAAAAAAAAAAAAAAADM=", "not base64!"],
+                },
+            }
+        });
+        let mut commands = vec![];
+        collect_commands(&payload, &mut commands);
+        assert_eq!(
+            commands,
+            vec![EncodedCommand {
+                hex: "3301010000000000000000000000000000000033".to_string(),
+                base64: "MwEBAAAAAAAAAAAAAAAAAAAAADM=".to_string(),
+            }]
+        );
+    }
+}
@@ -0,0 +1,212 @@
+//! The intervals at which devices are polled via each transport,
+//! which may be configured globally, per SKU and per device.
+use crate::commands::serve::POLL_INTERVAL;
+use crate::service::device::Device;
+use crate::service::device_config::{load_config_file, DeviceConfigFile};
+use crate::service::state::State as ServiceState;
+use crate::service::transport::Transport;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use tokio::time::Duration;
+
+/// How frequently to query the status of a LAN device that discovery
+/// hasn't refreshed recently. Querying via the LAN is cheap.
+const DEFAULT_LAN_POLL_SECS: u64 = 60;
+/// How long to wait after controlling a device that can only be
+/// polled via the Platform API before polling it, so that the
+/// change is reflected in the state that it returns
+const DEFAULT_AFTER_CONTROL_SECS: u64 = 5;
+
+/// The Platform API has a daily quota, so we don't allow it to be
+/// polled more frequently than this
+const MIN_PLATFORM_POLL_SECS: u64 = 60;
+/// The poll scheduler only wakes up this often, so shorter
+/// intervals would be misleading
+const MIN_POLL_SECS: u64 = 10;
+const MAX_AFTER_CONTROL_SECS: u64 = 300;
+
+/// The poll intervals, in seconds. Those that are not specified
+/// are inherited from the next less specific level.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct PollIntervals {
+    pub lan: Option<u64>,
+    pub iot: Option<u64>,
+    pub platform: Option<u64>,
+    /// How long to wait before polling a device after controlling it
+    pub after_control: Option<u64>,
+}
+
+impl PollIntervals {
+    fn validate(&self, context: &str) -> anyhow::Result<()> {
+        for (name, value, min) in [
+            ("lan", self.lan, MIN_POLL_SECS),
+            ("iot", self.iot, MIN_POLL_SECS),
+            ("platform", self.platform, MIN_PLATFORM_POLL_SECS),
+        ] {
+            if let Some(value) = value {
+                anyhow::ensure!(
+                    value >= min,
+                    "{context}: the {name} poll interval of {value} seconds \
+                     is shorter than the minimum of {min} seconds"
+                );
+            }
+        }
+        if let Some(value) = self.after_control {
+            anyhow::ensure!(
+                value <= MAX_AFTER_CONTROL_SECS,
+                "{context}: the after_control delay of {value} seconds \
+                 is longer than the maximum of {MAX_AFTER_CONTROL_SECS} seconds"
+            );
+        }
+        Ok(())
+    }
+
+    /// Fill in the intervals that are not specified from fallback
+    fn or(self, fallback: Self) -> Self {
+        Self {
+            lan: self.lan.or(fallback.lan),
+            iot: self.iot.or(fallback.iot),
+            platform: self.platform.or(fallback.platform),
+            after_control: self.after_control.or(fallback.after_control),
+        }
+    }
+}
+
+/// The poll intervals that apply to a particular device
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DevicePollIntervals {
+    pub lan: chrono::Duration,
+    pub iot: chrono::Duration,
+    pub platform: chrono::Duration,
+    pub after_control: Duration,
+}
+
+impl DevicePollIntervals {
+    pub fn for_transport(&self, transport: Transport) -> chrono::Duration {
+        match transport {
+            Transport::Lan => self.lan,
+            Transport::Iot => self.iot,
+            Transport::Platform => self.platform,
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct PollIntervalsFile {
+    /// Applies to all devices
+    #[serde(default)]
+    pub default: PollIntervals,
+    #[serde(flatten)]
+    pub entries: DeviceConfigFile<PollIntervals>,
+}
+
+impl PollIntervalsFile {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.default.validate("default")?;
+        self.entries.validate_entries(PollIntervals::validate)
+    }
+
+    /// Determine the poll intervals for device, from the most
+    /// specific configuration that specifies each of them
+    pub fn intervals_for(&self, device: &Device) -> DevicePollIntervals {
+        let for_device = self.entries.for_device(device).copied().unwrap_or_default();
+        let for_sku = self.entries.for_sku(device).copied().unwrap_or_default();
+
+        let intervals = for_device.or(for_sku).or(self.default);
+        let secs = |value: Option<u64>, default: chrono::Duration| {
+            value
+                .map(|secs| chrono::Duration::seconds(secs as i64))
+                .unwrap_or(default)
+        };
+        DevicePollIntervals {
+            lan: secs(
+                intervals.lan,
+                chrono::Duration::seconds(DEFAULT_LAN_POLL_SECS as i64),
+            ),
+            iot: secs(intervals.iot, *POLL_INTERVAL),
+            platform: secs(intervals.platform, *POLL_INTERVAL),
+            after_control: Duration::from_secs(
+                intervals
+                    .after_control
+                    .unwrap_or(DEFAULT_AFTER_CONTROL_SECS),
+            ),
+        }
+    }
+}
+
+pub fn load_poll_intervals_file(path: &Path) -> anyhow::Result<PollIntervalsFile> {
+    let file: PollIntervalsFile = load_config_file(path, "poll intervals")?;
+    file.validate()
+        .with_context(|| format!("validating poll intervals from {path:?}"))?;
+    Ok(file)
+}
+
+impl ServiceState {
+    pub async fn poll_intervals(&self, device: &Device) -> DevicePollIntervals {
+        self.get_poll_intervals().await.intervals_for(device)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn intervals_for() {
+        let file: PollIntervalsFile = serde_json::from_str(
+            r#"{
+                "default": {"lan": 120, "after_control": 10},
+                "skus": {
+                    "H6072": {"iot": 300, "lan": 30}
+                },
+                "devices": {
+                    "AA:BB:CC:DD:EE:FF:00:11": {"iot": 60}
+                }
+            }"#,
+        )
+        .unwrap();
+        file.validate().unwrap();
+
+        let device = Device::new("H6072", "AA:BB:CC:DD:EE:FF:00:11");
+        let other = Device::new("H6072", "AA:BB:CC:DD:EE:FF:00:22");
+        let unlisted = Device::new("H6159", "AA:BB:CC:DD:EE:FF:00:33");
+
+        let intervals = file.intervals_for(&device);
+        assert_eq!(intervals.iot, chrono::Duration::seconds(60));
+        assert_eq!(intervals.lan, chrono::Duration::seconds(30));
+        assert_eq!(intervals.platform, *POLL_INTERVAL);
+        assert_eq!(intervals.after_control, Duration::from_secs(10));
+
+        assert_eq!(
+            file.intervals_for(&other).for_transport(Transport::Iot),
+            chrono::Duration::seconds(300)
+        );
+        assert_eq!(
+            file.intervals_for(&unlisted).lan,
+            chrono::Duration::seconds(120)
+        );
+        assert_eq!(
+            PollIntervalsFile::default()
+                .intervals_for(&unlisted)
+                .after_control,
+            Duration::from_secs(DEFAULT_AFTER_CONTROL_SECS)
+        );
+    }
+
+    #[test]
+    fn validate() {
+        let parse = |json: &str| -> anyhow::Result<()> {
+            serde_json::from_str::<PollIntervalsFile>(json)?.validate()
+        };
+        assert!(parse(r#"{"default": {"platform": 60}}"#).is_ok());
+        assert!(parse(r#"{"default": {"platform": 30}}"#).is_err());
+        assert!(parse(r#"{"skus": {"H6072": {"lan": 1}}}"#).is_err());
+        assert!(parse(r#"{"devices": {"Lamp": {"after_control": 3600}}}"#).is_err());
+        assert!(parse(r#"{"devices": {"Lamp": {"ipt": 60}}}"#).is_err());
+        assert!(parse(r#"{"device": {"Lamp": {"iot": 60}}}"#).is_err());
+        assert!(parse(r#"{"default": {"iot": 60}, "skus": {}, "devices": {}}"#).is_ok());
+    }
+}
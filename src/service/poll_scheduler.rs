@@ -0,0 +1,416 @@
+use crate::service::device::Device;
+use crate::service::state::StateHandle;
+use crate::service::transport::Transport;
+use crate::service::watchdog::Heartbeat;
+use chrono::{DateTime, Utc};
+use once_cell::sync::Lazy;
+use std::collections::VecDeque;
+use tokio::time::{sleep, Duration, Instant};
+
+/// How long after a device was controlled or observed to change
+/// that we consider it to be "active"
+static ACTIVE_WINDOW: Lazy<chrono::Duration> = Lazy::new(|| chrono::Duration::minutes(10));
+/// How frequently to poll an active device
+static ACTIVE_POLL_INTERVAL: Lazy<chrono::Duration> = Lazy::new(|| chrono::Duration::seconds(60));
+/// How long a device needs to have been unchanged before we
+/// consider it to be idle
+static IDLE_AFTER: Lazy<chrono::Duration> = Lazy::new(|| chrono::Duration::hours(3));
+/// How frequently to poll an idle device
+static IDLE_POLL_INTERVAL: Lazy<chrono::Duration> = Lazy::new(|| chrono::Duration::hours(1));
+
+/// How many consecutive polls a device may fail to respond to, via
+/// any transport, before it is quarantined
+const QUARANTINE_AFTER_FAILURES: u32 = 3;
+/// How frequently to poll a newly quarantined device. This doubles
+/// with each further poll that it fails to respond to.
+static QUARANTINE_POLL_INTERVAL: Lazy<chrono::Duration> =
+    Lazy::new(|| chrono::Duration::minutes(15));
+static QUARANTINE_MAX_POLL_INTERVAL: Lazy<chrono::Duration> =
+    Lazy::new(|| chrono::Duration::hours(6));
+
+/// How often the scheduler wakes up to see which devices are due
+const TICK_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The Platform API has a daily quota of 10,000 requests.
+/// We allow polling to consume at most 200 of those per hour,
+/// leaving the remainder available for control requests.
+const PLATFORM_POLLS_PER_HOUR: usize = 200;
+/// IoT requests are not metered by Govee, but we don't want
+/// to flood the broker with status requests either.
+const IOT_POLLS_PER_MINUTE: usize = 60;
+
+/// Computes the poll interval for a device from the configured
+/// interval, based on how recently it was controlled or observed
+/// to change state.
+pub fn adaptive_poll_interval(
+    device: &Device,
+    configured: chrono::Duration,
+    now: DateTime<Utc>,
+) -> chrono::Duration {
+    let preferred = device.preferred_poll_interval(configured);
+
+    let last_activity = [device.last_controlled, device.last_changed]
+        .into_iter()
+        .flatten()
+        .max();
+
+    match last_activity {
+        Some(when) if now - when < *ACTIVE_WINDOW => preferred.min(*ACTIVE_POLL_INTERVAL),
+        Some(when) if now - when > *IDLE_AFTER => preferred.max(*IDLE_POLL_INTERVAL),
+        _ => preferred,
+    }
+}
+
+/// Computes the poll interval for a quarantined device,
+/// backing off exponentially with the number of failed polls
+pub fn quarantine_poll_interval(poll_failures: u32) -> chrono::Duration {
+    let doublings = poll_failures
+        .saturating_sub(QUARANTINE_AFTER_FAILURES)
+        .min(16);
+    (*QUARANTINE_POLL_INTERVAL * 2i32.pow(doublings)).min(*QUARANTINE_MAX_POLL_INTERVAL)
+}
+
+/// Tracks the number of requests issued via a transport within
+/// a sliding window of time
+#[derive(Debug)]
+pub struct TransportBudget {
+    limit: usize,
+    window: Duration,
+    issued: VecDeque<Instant>,
+}
+
+impl TransportBudget {
+    pub fn new(limit: usize, window: Duration) -> Self {
+        Self {
+            limit,
+            window,
+            issued: VecDeque::new(),
+        }
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some(first) = self.issued.front() {
+            if now.duration_since(*first) >= self.window {
+                self.issued.pop_front();
+            } else {
+                break;
+            }
+        }
+    }
+
+    pub fn has_capacity(&mut self, now: Instant) -> bool {
+        self.expire(now);
+        self.issued.len() < self.limit
+    }
+
+    pub fn record(&mut self, now: Instant) {
+        self.issued.push_back(now);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PollOutcome {
+    NotDue,
+    Skipped,
+    Deferred,
+    Polled,
+}
+
+/// The PollScheduler periodically refreshes device state.
+/// Recently controlled or changed devices are polled more
+/// frequently, while devices that haven't changed in a long
+/// time are polled less often.  Requests are accounted against
+/// per-transport budgets so that polling cannot exhaust the
+/// Platform API quota.
+pub struct PollScheduler {
+    state: StateHandle,
+    heartbeat: Heartbeat,
+    iot_budget: TransportBudget,
+    platform_budget: TransportBudget,
+}
+
+impl PollScheduler {
+    pub fn new(state: StateHandle, heartbeat: Heartbeat) -> Self {
+        Self {
+            state,
+            heartbeat,
+            iot_budget: TransportBudget::new(IOT_POLLS_PER_MINUTE, Duration::from_secs(60)),
+            platform_budget: TransportBudget::new(
+                PLATFORM_POLLS_PER_HOUR,
+                Duration::from_secs(3600),
+            ),
+        }
+    }
+
+    pub async fn run(mut self) -> anyhow::Result<()> {
+        sleep(Duration::from_secs(20)).await;
+        loop {
+            self.heartbeat.beat();
+            self.tick().await;
+            sleep(TICK_INTERVAL).await;
+        }
+    }
+
+    async fn tick(&mut self) {
+        let now = Utc::now();
+        let mut devices = self.state.devices().await;
+
+        // Service the most overdue devices first, so that if we
+        // run out of budget, it is the fresher devices that wait
+        devices.sort_by_key(|d| d.last_polled);
+
+        for d in devices {
+            self.heartbeat.beat();
+            match self.poll_single_device(&d, now).await {
+                Ok(PollOutcome::Deferred) => {
+                    log::trace!("poll of {d} deferred; transport budget exhausted");
+                }
+                Ok(_) => {}
+                // We already know that it isn't responding
+                Err(err) if d.is_quarantined() => {
+                    log::debug!("while polling quarantined {d}: {err:#}");
+                }
+                Err(err) => {
+                    log::error!("while polling {d}: {err:#}");
+                }
+            }
+        }
+    }
+
+    async fn poll_single_device(
+        &mut self,
+        device: &Device,
+        now: DateTime<Utc>,
+    ) -> anyhow::Result<PollOutcome> {
+        if device.is_ble_only_device() == Some(true) {
+            // We can't poll this device, we have no ble support
+            return Ok(PollOutcome::Skipped);
+        }
+
+        if device.quarantined_since.is_some() && !device.is_quarantined() {
+            log::info!("{device} is responding again; resuming normal polling");
+            let mut device = self.state.device_mut(&device.sku, &device.id).await;
+            device.quarantined_since.take();
+            device.poll_failures = 0;
+        }
+
+        // Prefer the LAN, then IoT, and only use the Platform API
+        // when we have to, as it is subject to a daily quota
+        let needs_platform = device.needs_platform_poll();
+        let transport = if needs_platform {
+            Transport::Platform
+        } else if device.lan_device.is_some() {
+            Transport::Lan
+        } else if device.undoc_device_info.is_some() && self.state.get_iot_client().await.is_some()
+        {
+            Transport::Iot
+        } else {
+            Transport::Platform
+        };
+
+        let poll_interval = if device.is_quarantined() {
+            quarantine_poll_interval(device.poll_failures)
+        } else {
+            let configured = self
+                .state
+                .poll_intervals(device)
+                .await
+                .for_transport(transport);
+            adaptive_poll_interval(device, configured, now)
+        };
+
+        let can_update = match &device.last_polled {
+            None => true,
+            Some(last) => now - last > poll_interval,
+        };
+
+        if !can_update {
+            return Ok(PollOutcome::NotDue);
+        }
+
+        let device_state = device.device_state();
+        let needs_update = match &device_state {
+            None => true,
+            Some(state) => now - state.updated > poll_interval,
+        };
+
+        if !needs_update {
+            return Ok(PollOutcome::NotDue);
+        }
+
+        self.check_responded_to_last_poll(device, now).await;
+
+        // Don't interrogate via HTTP if we can use the LAN.
+        // Discovery usually keeps the status of LAN devices fresh,
+        // so if it is stale the device is likely offline, and there
+        // is little sense in burning up request quota to the
+        // platform API for it
+        if let (Transport::Lan, Some(lan_device)) = (transport, &device.lan_device) {
+            log::trace!("LAN-available device {device} needs a status update");
+            // Record the attempt first, so that a device that doesn't
+            // respond is counted as having missed the poll
+            self.state
+                .device_mut(&device.sku, &device.id)
+                .await
+                .set_last_polled();
+            self.state.poll_lan_api(lan_device, |_| true).await?;
+            return Ok(PollOutcome::Polled);
+        }
+
+        let instant = Instant::now();
+
+        if transport == Transport::Iot {
+            if !self.iot_budget.has_capacity(instant) {
+                return Ok(PollOutcome::Deferred);
+            }
+            if self.state.poll_iot_api(device).await? {
+                self.iot_budget.record(instant);
+                return Ok(PollOutcome::Polled);
+            }
+        }
+
+        if !self.platform_budget.has_capacity(instant) {
+            return Ok(PollOutcome::Deferred);
+        }
+        if device.http_device_info.is_some() && self.state.get_platform_client().await.is_some() {
+            // Account for the request even if it fails; it still
+            // counts against our quota
+            self.platform_budget.record(instant);
+        }
+        if self.state.poll_platform_api(device).await? {
+            return Ok(PollOutcome::Polled);
+        }
+
+        Ok(PollOutcome::Skipped)
+    }
+
+    /// Count the polls that the device has not responded to via any
+    /// transport, quarantining it once it has missed too many of them
+    async fn check_responded_to_last_poll(&self, device: &Device, now: DateTime<Utc>) {
+        let Some(last_polled) = device.last_polled else {
+            return;
+        };
+        let threshold = self.state.get_offline_threshold().await;
+        let responded = device
+            .last_reachable()
+            .is_some_and(|heard| heard >= last_polled)
+            || device.is_reachable(threshold);
+
+        let newly_quarantined = {
+            let mut device = self.state.device_mut(&device.sku, &device.id).await;
+            if responded {
+                device.poll_failures = 0;
+                return;
+            }
+            device.poll_failures += 1;
+            if device.poll_failures >= QUARANTINE_AFTER_FAILURES
+                && device.quarantined_since.is_none()
+            {
+                device.quarantined_since.replace(now);
+                true
+            } else {
+                false
+            }
+        };
+
+        if newly_quarantined {
+            log::warn!(
+                "{device} has not responded to {QUARANTINE_AFTER_FAILURES} polls via any \
+                 transport; quarantining it and polling it less often until it is seen again"
+            );
+            let device = self.state.device_by_id(&device.id).await;
+            if let Some(device) = device {
+                if let Err(err) = self.state.publish_device_availability(&device, true).await {
+                    log::error!("publishing availability of {device}: {err:#}");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::commands::serve::POLL_INTERVAL;
+
+    #[test]
+    fn adaptive_interval() {
+        let now = Utc::now();
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+
+        assert_eq!(
+            adaptive_poll_interval(&device, *POLL_INTERVAL, now),
+            *POLL_INTERVAL
+        );
+
+        device
+            .last_controlled
+            .replace(now - chrono::Duration::minutes(1));
+        assert_eq!(
+            adaptive_poll_interval(&device, *POLL_INTERVAL, now),
+            *ACTIVE_POLL_INTERVAL
+        );
+
+        device
+            .last_controlled
+            .replace(now - chrono::Duration::hours(1));
+        assert_eq!(
+            adaptive_poll_interval(&device, *POLL_INTERVAL, now),
+            *POLL_INTERVAL
+        );
+
+        device
+            .last_controlled
+            .replace(now - chrono::Duration::hours(5));
+        assert_eq!(
+            adaptive_poll_interval(&device, *POLL_INTERVAL, now),
+            *IDLE_POLL_INTERVAL
+        );
+
+        device
+            .last_changed
+            .replace(now - chrono::Duration::minutes(2));
+        assert_eq!(
+            adaptive_poll_interval(&device, *POLL_INTERVAL, now),
+            *ACTIVE_POLL_INTERVAL
+        );
+
+        // A configured interval that is shorter than the active
+        // interval applies even when the device is active
+        let configured = chrono::Duration::seconds(30);
+        assert_eq!(adaptive_poll_interval(&device, configured, now), configured);
+    }
+
+    #[test]
+    fn quarantine() {
+        assert_eq!(quarantine_poll_interval(3), *QUARANTINE_POLL_INTERVAL);
+        assert_eq!(quarantine_poll_interval(5), *QUARANTINE_POLL_INTERVAL * 4);
+        assert_eq!(quarantine_poll_interval(100), *QUARANTINE_MAX_POLL_INTERVAL);
+
+        let now = Utc::now();
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert!(!device.is_quarantined());
+        device
+            .last_iot_device_status_update
+            .replace(now - chrono::Duration::hours(2));
+        device
+            .quarantined_since
+            .replace(now - chrono::Duration::hours(1));
+        assert!(device.is_quarantined());
+
+        // Being rediscovered ends the quarantine
+        device.last_lan_device_update.replace(now);
+        assert!(!device.is_quarantined());
+    }
+
+    #[test]
+    fn budget() {
+        let start = Instant::now();
+        let mut budget = TransportBudget::new(2, Duration::from_secs(60));
+        assert!(budget.has_capacity(start));
+        budget.record(start);
+        budget.record(start);
+        assert!(!budget.has_capacity(start + Duration::from_secs(30)));
+        assert!(budget.has_capacity(start + Duration::from_secs(60)));
+    }
+}
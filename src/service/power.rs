@@ -0,0 +1,141 @@
+use crate::service::device::{Device, DeviceState};
+use crate::service::device_config::{load_config_file, DeviceConfigFile};
+use crate::service::state::State as ServiceState;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Describes how much power a light draws, so that its consumption
+/// can be estimated; Govee lights don't report it themselves
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq)]
+pub struct PowerModel {
+    /// The power drawn when white at full brightness
+    pub max_watts: f64,
+    /// The power drawn while the light is off
+    #[serde(default)]
+    pub standby_watts: f64,
+}
+
+impl PowerModel {
+    pub fn estimate_watts(&self, state: &DeviceState) -> f64 {
+        if !state.light_on.unwrap_or(state.on) {
+            return self.standby_watts;
+        }
+
+        // In color temperature mode all of the LEDs are lit, whereas
+        // an rgb color lights them in proportion to its components
+        let load = if state.kelvin != 0 {
+            1.
+        } else {
+            let c = state.color;
+            (c.r as f64 + c.g as f64 + c.b as f64) / (3. * 255.)
+        };
+        let brightness = state.brightness.min(100) as f64 / 100.;
+
+        self.standby_watts + (self.max_watts - self.standby_watts).max(0.) * brightness * load
+    }
+}
+
+pub type PowerModelFile = DeviceConfigFile<PowerModel>;
+
+impl PowerModelFile {
+    pub fn model_for(&self, device: &Device) -> Option<PowerModel> {
+        self.lookup(device).copied()
+    }
+}
+
+pub fn load_power_model_file(path: &Path) -> anyhow::Result<PowerModelFile> {
+    load_config_file(path, "power models")
+}
+
+/// Accumulates the estimated energy consumption of a device.
+/// The total starts from zero each time govee2mqtt is started.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct EnergyMeter {
+    /// The most recently estimated power
+    pub watts: f64,
+    /// The energy consumed since we started
+    pub kwh: f64,
+    updated: Option<DateTime<Utc>>,
+}
+
+impl EnergyMeter {
+    /// Account for the time spent at the prior power level,
+    /// and then switch to the new one
+    pub fn update(&mut self, watts: f64, now: DateTime<Utc>) {
+        if let Some(updated) = self.updated {
+            let hours = (now - updated).num_milliseconds().max(0) as f64 / 3_600_000.;
+            self.kwh += self.watts * hours / 1000.;
+        }
+        self.watts = watts;
+        self.updated.replace(now);
+    }
+}
+
+impl ServiceState {
+    pub async fn power_model_for_device(&self, device: &Device) -> Option<PowerModel> {
+        self.get_power_models().await.model_for(device)
+    }
+
+    /// Bring the energy estimate for device up to date with its
+    /// current state, returning None if it has no power model
+    pub async fn update_power_estimate(&self, device: &Device) -> Option<EnergyMeter> {
+        let model = self.power_model_for_device(device).await?;
+        let mut device = self.device_mut(&device.sku, &device.id).await;
+        let watts = match device.device_state() {
+            Some(state) => model.estimate_watts(&state),
+            // Carry on at the prior level until we learn more
+            None => device.energy_meter.watts,
+        };
+        device.energy_meter.update(watts, Utc::now());
+        Some(device.energy_meter)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::lan_api::DeviceColor;
+
+    fn state(on: bool, brightness: u8, kelvin: u32, color: DeviceColor) -> DeviceState {
+        DeviceState {
+            on,
+            light_on: Some(on),
+            online: None,
+            kelvin,
+            color,
+            brightness,
+            scene: None,
+            source: "test",
+            updated: Utc::now(),
+        }
+    }
+
+    #[test]
+    fn power_estimate() {
+        let model = PowerModel {
+            max_watts: 10.5,
+            standby_watts: 0.5,
+        };
+        let red = DeviceColor { r: 255, g: 0, b: 0 };
+        let white = DeviceColor {
+            r: 255,
+            g: 255,
+            b: 255,
+        };
+
+        assert_eq!(model.estimate_watts(&state(false, 100, 0, white)), 0.5);
+        assert_eq!(model.estimate_watts(&state(true, 100, 0, white)), 10.5);
+        assert_eq!(model.estimate_watts(&state(true, 50, 4000, red)), 5.5);
+        let watts = model.estimate_watts(&state(true, 100, 0, red));
+        assert!((watts - (0.5 + 10. / 3.)).abs() < 1e-9, "{watts}");
+
+        let mut meter = EnergyMeter::default();
+        let start = Utc::now();
+        meter.update(100., start);
+        meter.update(0., start + chrono::Duration::minutes(30));
+        assert!((meter.kwh - 0.05).abs() < 1e-9, "{}", meter.kwh);
+        meter.update(0., start + chrono::Duration::minutes(90));
+        assert!((meter.kwh - 0.05).abs() < 1e-9, "{}", meter.kwh);
+    }
+}
@@ -27,6 +27,10 @@ pub struct Quirk {
     pub supports_rgb: bool,
     pub supports_brightness: bool,
     pub color_temp_range: Option<(u32, u32)>,
+    /// The exact color temperature range supported by the device,
+    /// which takes precedence over the range reported by the
+    /// Platform API
+    pub kelvin_range: Option<(u32, u32)>,
     pub avoid_platform_api: bool,
     pub ble_only: bool,
     pub lan_api_capable: bool,
@@ -38,6 +42,9 @@ pub struct Quirk {
     /// their state.
     pub iot_api_supported: bool,
     pub show_as_preset_buttons: Option<&'static [&'static str]>,
+    /// The number of individually addressable segments, for devices
+    /// where the Platform API metadata is missing or wrong
+    pub segment_count: Option<u32>,
 }
 
 impl Quirk {
@@ -51,6 +58,7 @@ impl Quirk {
             supports_rgb: false,
             supports_brightness: false,
             color_temp_range: None,
+            kelvin_range: None,
             avoid_platform_api: false,
             ble_only: false,
             icon: icon.into(),
@@ -60,6 +68,7 @@ impl Quirk {
             platform_humidity_sensor_units: None,
             iot_api_supported: false,
             show_as_preset_buttons: None,
+            segment_count: None,
         }
     }
 
@@ -75,6 +84,12 @@ impl Quirk {
         Self::device(sku, DeviceType::IceMaker, "mdi:snowflake")
     }
 
+    pub fn plug<SKU: Into<Cow<'static, str>>>(sku: SKU) -> Self {
+        // Plugs don't have the LAN API, so IoT is the fastest way
+        // to switch them
+        Self::device(sku, DeviceType::Socket, "mdi:power-socket-us").with_iot_api_support(true)
+    }
+
     pub fn space_heater<SKU: Into<Cow<'static, str>>>(sku: SKU) -> Self {
         Self::device(sku, DeviceType::Heater, "mdi:heat-wave")
     }
@@ -117,6 +132,20 @@ impl Quirk {
         self
     }
 
+    /// Specify the exact color temperature range, for devices
+    /// where the Platform API metadata is missing or wrong
+    pub fn with_kelvin_range(mut self, min: u32, max: u32) -> Self {
+        self.kelvin_range = Some((min, max));
+        self
+    }
+
+    /// Specify the number of segments, for devices where
+    /// the Platform API metadata is missing or wrong
+    pub fn with_segment_count(mut self, count: u32) -> Self {
+        self.segment_count = Some(count);
+        self
+    }
+
     pub fn with_lan_api(mut self) -> Self {
         self.lan_api_capable = true;
         self
@@ -181,7 +210,11 @@ fn load_quirks() -> HashMap<String, Quirk> {
         // <https://github.com/wez/govee2mqtt/issues/14#issuecomment-1880050091>
         Quirk::light("H6159", STRIP).with_broken_platform(),
         // <https://github.com/wez/govee2mqtt/issues/152>
-        Quirk::light("H6003", BULB).with_broken_platform(),
+        // Without the platform metadata we'd otherwise advertise the
+        // generic range, rather than the 2700-6500K of this bulb
+        Quirk::light("H6003", BULB)
+            .with_broken_platform()
+            .with_kelvin_range(2700, 6500),
         // <https://github.com/wez/govee2mqtt/issues/40#issuecomment-1889726710>
         // indicates that this one doesn't work like the others with IoT
         Quirk::light("H6121", STRIP).with_iot_api_support(false),
@@ -200,15 +233,20 @@ fn load_quirks() -> HashMap<String, Quirk> {
         Quirk::light("H6053", STRIP)
             .with_broken_platform()
             .with_ble_only(true),
+        // These RGBIC strips are BLE-only, so there is no platform
+        // metadata to tell us that they have 15 segments
         Quirk::light("H617C", STRIP)
             .with_broken_platform()
-            .with_ble_only(true),
+            .with_ble_only(true)
+            .with_segment_count(15),
         Quirk::light("H617E", STRIP)
             .with_broken_platform()
-            .with_ble_only(true),
+            .with_ble_only(true)
+            .with_segment_count(15),
         Quirk::light("H617F", STRIP)
             .with_broken_platform()
-            .with_ble_only(true),
+            .with_ble_only(true)
+            .with_segment_count(15),
         Quirk::light("H6119", STRIP)
             .with_broken_platform()
             .with_ble_only(true),
@@ -245,6 +283,9 @@ fn load_quirks() -> HashMap<String, Quirk> {
             .with_platform_temperature_sensor_units(TemperatureUnits::Fahrenheit),
         // <https://github.com/wez/govee2mqtt/issues/343>
         Quirk::ice_maker("H7172").with_iot_api_support(false),
+        Quirk::plug("H5080"),
+        Quirk::plug("H5083"),
+        Quirk::plug("H5086"),
         Quirk::thermometer("H5051")
             .with_platform_temperature_sensor_units(TemperatureUnits::Fahrenheit)
             .with_platform_humidity_sensor_units(HumidityUnits::RelativePercent),
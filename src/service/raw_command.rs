@@ -0,0 +1,161 @@
+//! Sends packets supplied by the user to a device, the service
+//! equivalent of `govee lan-control command`, which is useful when
+//! experimenting with features that we don't yet model.
+use crate::ble::Base64HexBytes;
+use crate::service::control_transport::{control_transport, dispatch, TransportCommand};
+use crate::service::hass::IdParameter;
+use crate::service::state::StateHandle;
+use crate::service::transport::Transport;
+use mosquitto_rs::router::{Params, Payload, State};
+use serde::Deserialize;
+use serde_json::json;
+
+/// The longest packet, excluding the checksum
+const MAX_PACKET_LEN: usize = 19;
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(untagged)]
+pub enum RawCommandRequest {
+    /// A single packet
+    Packet(String),
+    Packets {
+        packets: Vec<String>,
+        /// Send via just this transport, rather than the
+        /// configured order for raw commands
+        #[serde(default)]
+        transport: Option<Transport>,
+    },
+}
+
+impl RawCommandRequest {
+    pub fn parse(payload: &str) -> anyhow::Result<Self> {
+        let payload = payload.trim();
+        if payload.starts_with('{') || payload.starts_with('"') {
+            return Ok(serde_json::from_str(payload)?);
+        }
+        Ok(Self::Packet(payload.to_string()))
+    }
+
+    fn packets(&self) -> &[String] {
+        match self {
+            Self::Packet(packet) => std::slice::from_ref(packet),
+            Self::Packets { packets, .. } => packets,
+        }
+    }
+
+    fn transport(&self) -> Option<Transport> {
+        match self {
+            Self::Packet(_) => None,
+            Self::Packets { transport, .. } => *transport,
+        }
+    }
+
+    /// Returns the base64 encoded packets, each padded
+    /// and completed with its checksum
+    pub fn encode(&self) -> anyhow::Result<Vec<String>> {
+        anyhow::ensure!(!self.packets().is_empty(), "no packets were specified");
+        let mut commands = vec![];
+        for packet in self.packets() {
+            let bytes = parse_packet(packet)?;
+            commands.extend(Base64HexBytes::with_bytes(bytes).base64());
+        }
+        Ok(commands)
+    }
+}
+
+/// Parse packet as hex, such as `33 01 01` or `0x330101`, falling
+/// back to base64. A complete packet may be given, as the checksum
+/// is recomputed.
+pub fn parse_packet(packet: &str) -> anyhow::Result<Vec<u8>> {
+    let packet = packet.trim();
+    let hex: String = packet
+        .trim_start_matches("0x")
+        .chars()
+        .filter(|c| !matches!(c, ' ' | ':' | '-'))
+        .collect();
+    let bytes = match data_encoding::HEXLOWER_PERMISSIVE.decode(hex.as_bytes()) {
+        Ok(bytes) => bytes,
+        Err(_) => Base64HexBytes::from_base64(packet)
+            .map_err(|err| anyhow::anyhow!("'{packet}' is neither hex nor {err:#}"))?
+            .bytes()
+            .to_vec(),
+    };
+    anyhow::ensure!(!bytes.is_empty(), "the packet is empty");
+    let len = match bytes.len() {
+        20 => MAX_PACKET_LEN,
+        len => len,
+    };
+    anyhow::ensure!(
+        len <= MAX_PACKET_LEN,
+        "'{packet}' is {} bytes long, but packets are at most {MAX_PACKET_LEN} bytes \
+         plus the checksum",
+        bytes.len()
+    );
+    Ok(bytes[..len].to_vec())
+}
+
+/// Send packets to a device. The payload is either a single packet
+/// in hex or base64, or a JSON object such as
+/// `{"packets": ["33 01 01"], "transport": "iot"}`
+pub async fn mqtt_device_raw_command(
+    Payload(payload): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_device_raw_command: {id}: {payload}");
+    let device = state.resolve_device_for_control(&id).await?;
+    let request = RawCommandRequest::parse(&payload)?;
+    let commands = request.encode()?;
+    let command = TransportCommand::Raw(commands.clone());
+
+    state
+        .run_device_command(
+            &device,
+            "raw",
+            json!({"commands": commands, "transport": request.transport()}),
+            || async {
+                match request.transport() {
+                    Some(transport) => {
+                        dispatch(&[control_transport(transport)], &state, &device, &command).await
+                    }
+                    None => state.send_transport_command(&device, command.clone()).await,
+                }
+                .map(|_| ())
+            },
+        )
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!(parse_packet("33 01 01").unwrap(), vec![0x33, 0x01, 0x01]);
+        assert_eq!(parse_packet("0x33:01:01").unwrap(), vec![0x33, 0x01, 0x01]);
+        assert_eq!(parse_packet("MwEB").unwrap(), vec![0x33, 0x01, 0x01]);
+        assert!(parse_packet("not a packet!").is_err());
+        assert!(parse_packet(&"00".repeat(21)).is_err());
+
+        let request = RawCommandRequest::parse("330101").unwrap();
+        let encoded = request.encode().unwrap();
+        // A complete packet is accepted, and is unchanged
+        assert_eq!(
+            RawCommandRequest::parse(&encoded[0])
+                .unwrap()
+                .encode()
+                .unwrap(),
+            encoded
+        );
+
+        assert_eq!(
+            RawCommandRequest::parse(r#"{"packets": ["330101", "MwEA"], "transport": "lan"}"#)
+                .unwrap(),
+            RawCommandRequest::Packets {
+                packets: vec!["330101".to_string(), "MwEA".to_string()],
+                transport: Some(Transport::Lan),
+            }
+        );
+    }
+}
@@ -0,0 +1,114 @@
+//! Read-only mode. The state of a read-only device continues to be
+//! reported to Home Assistant, but requests to control it are
+//! rejected, which is useful in households where automations that
+//! are configured in the Govee Home app must not be overridden.
+//! Read-only mode can be enabled for all devices, or per device,
+//! either via the command line or via switches in Home Assistant.
+use crate::service::device::Device;
+use crate::service::hass::{read_only_state_topic, switch_instance_state_topic};
+use crate::service::state::{device_matches_label, State as ServiceState, StateHandle};
+use mosquitto_rs::router::{Payload, State as RouterState};
+use std::sync::Arc;
+
+/// The switch instance name used to toggle read-only mode for a device
+pub const READ_ONLY_INSTANCE: &str = "readOnly";
+
+/// Returned when attempting to control a read-only device
+#[derive(thiserror::Error, Debug)]
+#[error("{device} is read-only, so it cannot be controlled")]
+pub struct ReadOnlyError {
+    pub device: String,
+}
+
+fn parse_on_off(payload: &str) -> anyhow::Result<bool> {
+    match payload.trim() {
+        "ON" | "on" => Ok(true),
+        "OFF" | "off" => Ok(false),
+        _ => anyhow::bail!("invalid read-only value {payload}"),
+    }
+}
+
+impl ServiceState {
+    /// Returns true if device was configured to be read-only, either
+    /// via its switch or via --read-only-device, disregarding the
+    /// global read-only mode
+    pub async fn device_read_only_setting(&self, device: &Device) -> bool {
+        match device.read_only {
+            Some(read_only) => read_only,
+            None => self
+                .get_read_only_devices()
+                .await
+                .iter()
+                .any(|label| device_matches_label(device, label)),
+        }
+    }
+
+    /// Returns true if requests to control device should be rejected
+    pub async fn is_device_read_only(&self, device: &Device) -> bool {
+        self.is_read_only().await || self.device_read_only_setting(device).await
+    }
+
+    /// Returns an error if device is read-only
+    pub async fn check_not_read_only(&self, device: &Device) -> anyhow::Result<()> {
+        if self.is_device_read_only(device).await {
+            return Err(ReadOnlyError {
+                device: device.to_string(),
+            }
+            .into());
+        }
+        Ok(())
+    }
+
+    /// Change whether device is read-only, and report the new setting
+    pub async fn set_device_read_only(
+        self: &Arc<Self>,
+        device: &Device,
+        read_only: bool,
+    ) -> anyhow::Result<()> {
+        log::info!("Setting read-only={read_only} for {device}");
+        self.device_mut(&device.sku, &device.id).await.read_only = Some(read_only);
+        if let Some(hass) = self.get_hass_client().await {
+            hass.publish(
+                switch_instance_state_topic(device, READ_ONLY_INSTANCE),
+                if read_only { "ON" } else { "OFF" },
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Change whether all devices are read-only, and report the new setting
+    pub async fn set_global_read_only(&self, read_only: bool) -> anyhow::Result<()> {
+        log::info!("Setting read-only={read_only} for all devices");
+        self.set_read_only(read_only).await;
+        if let Some(hass) = self.get_hass_client().await {
+            hass.publish(
+                read_only_state_topic(),
+                if read_only { "ON" } else { "OFF" },
+            )
+            .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Toggles read-only mode for a device
+pub async fn mqtt_set_device_read_only(
+    state: &StateHandle,
+    id: &str,
+    payload: &str,
+) -> anyhow::Result<()> {
+    let read_only = parse_on_off(payload)?;
+    // Note that this deliberately doesn't use resolve_device_for_control,
+    // as that would prevent turning read-only mode off again
+    let device = state.resolve_device_read_only(id).await?;
+    state.set_device_read_only(&device, read_only).await
+}
+
+/// Toggles read-only mode for all devices
+pub async fn mqtt_set_global_read_only(
+    Payload(payload): Payload<String>,
+    RouterState(state): RouterState<StateHandle>,
+) -> anyhow::Result<()> {
+    state.set_global_read_only(parse_on_off(&payload)?).await
+}
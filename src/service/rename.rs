@@ -0,0 +1,60 @@
+//! Renames devices via the Govee API, so that the name shown
+//! in the Govee Home app stays in sync with the name in hass.
+use crate::service::device::Device;
+use crate::service::hass::IdParameter;
+use crate::service::state::{State as ServiceState, StateHandle};
+use mosquitto_rs::router::{Params, Payload, State};
+use std::sync::Arc;
+
+impl ServiceState {
+    pub async fn device_rename(
+        self: &Arc<Self>,
+        device: &Device,
+        name: &str,
+    ) -> anyhow::Result<()> {
+        let name = name.trim();
+        anyhow::ensure!(!name.is_empty(), "the name of {device} must not be empty");
+
+        if self.is_dry_run().await {
+            log::info!("DRY RUN: not renaming {device} to {name}");
+            return Ok(());
+        }
+
+        let undoc = self
+            .get_undoc_client()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Undoc API client is not available"))?;
+        let acct = undoc.login_account_cached().await?;
+        undoc
+            .set_device_name(&acct.token, &device.sku, &device.id, name)
+            .await?;
+        log::info!("Renamed {device} to {name}");
+
+        if let Some(client) = self.get_platform_client().await {
+            client.invalidate_device_list();
+        }
+
+        let device = {
+            let mut device = self.device_mut(&device.sku, &device.id).await;
+            device.set_govee_name(name);
+            device.clone()
+        };
+
+        // Re-register the entities so that hass picks up the new name
+        if let Some(hass) = self.get_hass_client().await {
+            hass.advise_hass_of_new_device(&device, self).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Renames a device. The payload is the new name.
+pub async fn mqtt_device_rename(
+    Payload(name): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_device_rename: {id}: {name}");
+    let device = state.resolve_device_for_control(&id).await?;
+    state.device_rename(&device, &name).await
+}
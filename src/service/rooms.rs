@@ -0,0 +1,34 @@
+//! Keeps the rooms that devices were assigned to in the Govee Home app
+//! up to date. The room is used as the suggested area of the device in
+//! hass, so that newly discovered devices land in the matching area.
+//! The rooms are fetched again along with the account device list.
+use crate::service::state::State as ServiceState;
+use crate::undoc_api::DevicesResponse;
+use std::collections::HashMap;
+
+impl ServiceState {
+    /// Record the devices and room assignments from the Govee account,
+    /// returning the ids of the known devices whose room changed
+    pub async fn apply_undoc_device_list(&self, info: DevicesResponse) -> Vec<String> {
+        let room_by_id: HashMap<u64, String> = info
+            .groups
+            .into_iter()
+            .map(|group| (group.group_id, group.group_name))
+            .collect();
+
+        let mut changed = vec![];
+        for entry in info.devices {
+            let mut device = self.device_mut(&entry.sku, &entry.device).await;
+            let room_name = room_by_id.get(&entry.group_id).map(|name| name.as_str());
+            if device.undoc_device_info.is_some() && device.room_name() != room_name {
+                log::info!(
+                    "{device} moved from room {:?} to {room_name:?}",
+                    device.room_name()
+                );
+                changed.push(device.id.to_string());
+            }
+            device.set_undoc_device_info(entry, room_name);
+        }
+        changed
+    }
+}
@@ -0,0 +1,156 @@
+//! Publishes the metadata of the scenes that are known for each
+//! device, so that automations can select scenes by code or by the
+//! Govee scene that they belong to, rather than only by name.
+use crate::govee_scenes::{get_api_scenes_for_sku, ParsedScene};
+use crate::scene_resolver::scenes_for_sku;
+use crate::service::device::Device;
+use crate::service::hass::{base_topic, device_topic_id, HassClient};
+use crate::service::state::State as ServiceState;
+use serde::Serialize;
+use serde_json::json;
+
+/// Where the scene metadata for a device is published, retained
+pub fn scene_attributes_topic(device: &Device) -> String {
+    format!("{}/{id}/scenes", base_topic(), id = device_topic_id(device))
+}
+
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SceneSource {
+    /// The scene is in the Govee scene library for the SKU
+    Api,
+    /// The scene is only defined by an override file
+    Override,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct SceneAttributes {
+    pub name: String,
+    /// The code that activates the scene, for scenes from the API
+    pub code: Option<u16>,
+    /// The Govee scene that this is, or is an effect of
+    pub scene_name: String,
+    pub scene_id: Option<u32>,
+    pub effect: Option<String>,
+    pub param_id: Option<u32>,
+    /// The category of the scene in the Govee scene library
+    pub category: Option<String>,
+    pub source: SceneSource,
+    /// Whether the scene is activated using the packets from an
+    /// override file, rather than those from the API
+    pub has_override: bool,
+}
+
+/// Describe each of the scenes, given the merged scenes for a SKU
+/// and those that came from the API. Override scenes that replace
+/// an API scene report the code and ids of the API scene.
+pub fn scene_attributes(scenes: &[ParsedScene], api: &[ParsedScene]) -> Vec<SceneAttributes> {
+    scenes
+        .iter()
+        .map(|scene| {
+            let has_override = scene.override_cmd_b64.is_some();
+            let origin = if has_override {
+                api.iter()
+                    .find(|a| a.display_name.eq_ignore_ascii_case(&scene.display_name))
+            } else {
+                Some(scene)
+            };
+            SceneAttributes {
+                name: scene.display_name.to_string(),
+                code: origin.map(|s| s.scene_code),
+                scene_name: origin
+                    .map(|s| s.source_api_scene_name.to_string())
+                    .unwrap_or_else(|| scene.source_api_scene_name.to_string()),
+                scene_id: origin.map(|s| s.source_api_scene_id),
+                effect: origin.and_then(|s| s.source_api_effect_name.clone()),
+                param_id: origin.map(|s| s.source_api_scence_param_id),
+                category: origin.and_then(|s| s.category.clone()),
+                source: if origin.is_some() {
+                    SceneSource::Api
+                } else {
+                    SceneSource::Override
+                },
+                has_override,
+            }
+        })
+        .collect()
+}
+
+impl ServiceState {
+    pub async fn device_scene_attributes(
+        &self,
+        device: &Device,
+    ) -> anyhow::Result<Vec<SceneAttributes>> {
+        let scenes = scenes_for_sku(&device.sku).await?;
+        // The merged scenes omit the API scenes that are overridden
+        let api = get_api_scenes_for_sku(&device.sku)
+            .await
+            .unwrap_or_default();
+        Ok(scene_attributes(&scenes, &api))
+    }
+
+    /// Publish the scene metadata for the device, retained,
+    /// so that it is available to automations at any time
+    pub async fn publish_scene_attributes(
+        &self,
+        device: &Device,
+        client: &HassClient,
+    ) -> anyhow::Result<()> {
+        let scenes = self.device_scene_attributes(device).await?;
+        client
+            .publish_retained_obj(
+                scene_attributes_topic(device),
+                json!({"count": scenes.len(), "scenes": scenes}),
+            )
+            .await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn scene(name: &str, code: u16, override_cmd: bool) -> ParsedScene {
+        ParsedScene {
+            display_name: name.to_string(),
+            scene_code: code,
+            api_scence_param: String::new(),
+            sku: "H6072".to_string(),
+            source_api_scene_name: name.to_string(),
+            source_api_effect_name: None,
+            source_api_scene_id: code as u32 + 1000,
+            source_api_scence_param_id: code as u32 + 2000,
+            override_cmd_b64: override_cmd.then(|| vec!["MwUE".to_string()]),
+            category: None,
+        }
+    }
+
+    #[test]
+    fn attributes() {
+        let api = vec![scene("Aurora", 10, false), scene("Forest", 11, false)];
+        let merged = vec![
+            scene("Aurora", 10, false),
+            scene("Forest", 0, true),
+            scene("Custom", 0, true),
+        ];
+        let attributes = scene_attributes(&merged, &api);
+
+        assert_eq!(attributes[0].code, Some(10));
+        assert_eq!(attributes[0].source, SceneSource::Api);
+        assert!(!attributes[0].has_override);
+
+        // An override of an API scene reports the API code
+        assert_eq!(attributes[1].code, Some(11));
+        assert_eq!(attributes[1].scene_id, Some(1011));
+        assert_eq!(attributes[1].source, SceneSource::Api);
+        assert!(attributes[1].has_override);
+
+        assert_eq!(attributes[2].code, None);
+        assert_eq!(attributes[2].source, SceneSource::Override);
+        assert!(attributes[2].has_override);
+        assert_eq!(
+            serde_json::to_value(&attributes[2]).unwrap()["source"],
+            "override"
+        );
+    }
+}
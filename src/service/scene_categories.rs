@@ -0,0 +1,132 @@
+//! Presents the scenes of a device prefixed by the category that
+//! the Govee scene library assigns them, such as `Nature/Forest`,
+//! so that the kind of each scene is apparent from the effect list.
+//! Prefixing is enabled per SKU or per device via the scene filters.
+use crate::govee_scenes::ParsedScene;
+use crate::scene_resolver::scenes_for_sku;
+use crate::service::device::Device;
+use crate::service::state::State as ServiceState;
+use std::collections::HashMap;
+
+/// Separates the category from the name of the scene
+const CATEGORY_SEPARATOR: char = '/';
+
+/// Maps the lowercased scene names to their categories
+pub type SceneCategories = HashMap<String, String>;
+
+pub fn scene_categories(scenes: &[ParsedScene]) -> SceneCategories {
+    scenes
+        .iter()
+        .filter_map(|scene| {
+            let category = scene.category.as_ref()?;
+            Some((
+                scene.display_name.to_ascii_lowercase(),
+                category.to_string(),
+            ))
+        })
+        .collect()
+}
+
+/// Returns name prefixed by its category, if it has one
+pub fn prefix_category(categories: &SceneCategories, name: &str) -> String {
+    match categories.get(&name.to_ascii_lowercase()) {
+        Some(category) => format!("{category}{CATEGORY_SEPARATOR}{name}"),
+        None => name.to_string(),
+    }
+}
+
+/// Removes the category prefix from name, if it is the category of
+/// the remainder of the name, so that both forms are accepted
+pub fn strip_category<'a>(categories: &SceneCategories, name: &'a str) -> &'a str {
+    if categories.contains_key(&name.to_ascii_lowercase()) {
+        return name;
+    }
+    match name.split_once(CATEGORY_SEPARATOR) {
+        Some((category, scene))
+            if categories
+                .get(&scene.to_ascii_lowercase())
+                .is_some_and(|c| c.eq_ignore_ascii_case(category)) =>
+        {
+            scene
+        }
+        _ => name,
+    }
+}
+
+impl ServiceState {
+    async fn device_scene_categories(&self, device: &Device) -> SceneCategories {
+        match scenes_for_sku(&device.sku).await {
+            Ok(scenes) => scene_categories(&scenes),
+            Err(err) => {
+                log::debug!("Unable to determine scene categories for {device}: {err:#}");
+                SceneCategories::new()
+            }
+        }
+    }
+
+    /// Whether the scene names for device are prefixed by category
+    pub async fn prefixes_scene_categories(&self, device: &Device) -> bool {
+        self.get_scene_filters()
+            .await
+            .effective()
+            .filter_for(device)
+            .is_some_and(|filter| filter.prefix_categories)
+    }
+
+    /// Prefix the scene names by their categories, if that
+    /// is enabled for the device
+    pub async fn categorize_scene_names(&self, device: &Device, names: Vec<String>) -> Vec<String> {
+        if !self.prefixes_scene_categories(device).await {
+            return names;
+        }
+        let categories = self.device_scene_categories(device).await;
+        names
+            .iter()
+            .map(|name| prefix_category(&categories, name))
+            .collect()
+    }
+
+    /// The name under which the scene is presented to hass
+    pub async fn categorize_scene_name(&self, device: &Device, name: &str) -> String {
+        if !self.prefixes_scene_categories(device).await {
+            return name.to_string();
+        }
+        prefix_category(&self.device_scene_categories(device).await, name)
+    }
+
+    /// Accept a scene name with or without its category prefix
+    pub async fn uncategorize_scene_name(&self, device: &Device, name: &str) -> String {
+        if !name.contains(CATEGORY_SEPARATOR) {
+            return name.to_string();
+        }
+        strip_category(&self.device_scene_categories(device).await, name).to_string()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn categories() {
+        let categories: SceneCategories = [
+            ("forest", "Nature"),
+            ("party", "Party"),
+            ("day/night", "Life"),
+        ]
+        .into_iter()
+        .map(|(name, category)| (name.to_string(), category.to_string()))
+        .collect();
+
+        assert_eq!(prefix_category(&categories, "Forest"), "Nature/Forest");
+        assert_eq!(prefix_category(&categories, "Custom"), "Custom");
+
+        assert_eq!(strip_category(&categories, "Nature/Forest"), "Forest");
+        assert_eq!(strip_category(&categories, "nature/forest"), "forest");
+        assert_eq!(strip_category(&categories, "Party/Forest"), "Party/Forest");
+        assert_eq!(strip_category(&categories, "Forest"), "Forest");
+        // A scene whose name contains the separator is left alone
+        assert_eq!(strip_category(&categories, "Day/Night"), "Day/Night");
+        assert_eq!(strip_category(&categories, "Life/Day/Night"), "Day/Night");
+    }
+}
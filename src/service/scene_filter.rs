@@ -0,0 +1,177 @@
+use crate::service::device::Device;
+use crate::service::device_config::{load_config_file, DeviceConfigFile};
+use crate::service::state::{State as ServiceState, StateHandle};
+use anyhow::Context;
+use mosquitto_rs::router::{Payload, State};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+
+/// Controls which scenes are offered for a device, and in what order
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct SceneFilter {
+    /// If non-empty, only these scenes are offered
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub allow: Vec<String>,
+    /// These scenes are never offered
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub block: Vec<String>,
+    /// These scenes are offered ahead of the others, in the order
+    /// listed here. Favorites are offered even if they are not
+    /// in the allow list, but not if they are blocked.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub favorites: Vec<String>,
+    /// Present the scenes prefixed by their category, such as
+    /// `Nature/Forest`. The lists above use the plain names.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub prefix_categories: bool,
+}
+
+fn contains(list: &[String], scene: &str) -> bool {
+    list.iter().any(|s| s.eq_ignore_ascii_case(scene))
+}
+
+impl SceneFilter {
+    pub fn apply(&self, scenes: Vec<String>) -> Vec<String> {
+        let mut favorites = vec![];
+        let mut others = vec![];
+
+        for scene in scenes {
+            if contains(&self.block, &scene) {
+                continue;
+            }
+            if contains(&self.favorites, &scene) {
+                favorites.push(scene);
+            } else if self.allow.is_empty() || contains(&self.allow, &scene) {
+                others.push(scene);
+            }
+        }
+
+        favorites.sort_by_key(|scene| {
+            self.favorites
+                .iter()
+                .position(|f| f.eq_ignore_ascii_case(scene))
+        });
+        favorites.extend(others);
+        favorites
+    }
+}
+
+pub type SceneFilterFile = DeviceConfigFile<SceneFilter>;
+
+impl SceneFilterFile {
+    pub fn filter_for(&self, device: &Device) -> Option<&SceneFilter> {
+        self.lookup(device)
+    }
+}
+
+/// The scene filters that are in effect. Those received via mqtt
+/// replace those loaded from the file, until the retained mqtt
+/// message is cleared.
+#[derive(Clone, Debug, Default)]
+pub struct SceneFilters {
+    pub file: SceneFilterFile,
+    pub mqtt: Option<SceneFilterFile>,
+}
+
+impl SceneFilters {
+    pub fn effective(&self) -> &SceneFilterFile {
+        self.mqtt.as_ref().unwrap_or(&self.file)
+    }
+}
+
+pub fn load_scene_filter_file(path: &Path) -> anyhow::Result<SceneFilterFile> {
+    load_config_file(path, "scene filters")
+}
+
+impl ServiceState {
+    /// Apply the configured filter, if any, to the scenes for device
+    pub async fn filter_scenes(&self, device: &Device, scenes: Vec<String>) -> Vec<String> {
+        match self
+            .get_scene_filters()
+            .await
+            .effective()
+            .filter_for(device)
+        {
+            Some(filter) => filter.apply(scenes),
+            None => scenes,
+        }
+    }
+}
+
+/// The scene filters have been changed via the retained config topic.
+/// An empty payload reverts to the filters from the file.
+pub async fn mqtt_scene_filters_config(
+    Payload(payload): Payload<String>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let mqtt = if payload.trim().is_empty() {
+        log::info!("Scene filters cleared via MQTT");
+        None
+    } else {
+        log::info!("Scene filters set via MQTT");
+        Some(serde_json::from_str::<SceneFilterFile>(&payload).context("parsing scene filters")?)
+    };
+
+    let mut filters = state.get_scene_filters().await;
+    if filters.mqtt == mqtt {
+        return Ok(());
+    }
+    filters.mqtt = mqtt;
+    state.set_scene_filters(filters).await;
+
+    // Re-publish the entity configs so that the effect lists
+    // reflect the new filters
+    if let Some(client) = state.get_hass_client().await {
+        for device in state.devices().await {
+            client.advise_hass_of_new_device(&device, &state).await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn names(list: &[&str]) -> Vec<String> {
+        list.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn scene_filter() {
+        let scenes = names(&["Aurora", "Forest", "Ocean", "Sunrise", "Sunset"]);
+
+        let filter = SceneFilter {
+            allow: vec![],
+            block: names(&["ocean"]),
+            favorites: names(&["Sunset", "Forest"]),
+            ..Default::default()
+        };
+        assert_eq!(
+            filter.apply(scenes.clone()),
+            names(&["Sunset", "Forest", "Aurora", "Sunrise"])
+        );
+
+        let filter = SceneFilter {
+            allow: names(&["Aurora"]),
+            block: names(&["Sunset"]),
+            favorites: names(&["Sunset", "Ocean"]),
+            ..Default::default()
+        };
+        assert_eq!(filter.apply(scenes), names(&["Ocean", "Aurora"]));
+
+        let file: SceneFilterFile = serde_json::from_str(
+            r#"{
+                "skus": {"H6072": {"block": ["Forest"]}},
+                "devices": {"AA:BB:CC:DD:EE:FF:00:11": {"allow": ["Ocean"]}}
+            }"#,
+        )
+        .unwrap();
+        let device = Device::new("H6072", "AA:BB:CC:DD:EE:FF:00:11");
+        let other = Device::new("H6072", "AA:BB:CC:DD:EE:FF:00:22");
+        let unlisted = Device::new("H6159", "AA:BB:CC:DD:EE:FF:00:33");
+        assert_eq!(file.filter_for(&device).unwrap().allow, names(&["Ocean"]));
+        assert_eq!(file.filter_for(&other).unwrap().block, names(&["Forest"]));
+        assert!(file.filter_for(&unlisted).is_none());
+    }
+}
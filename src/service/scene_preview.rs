@@ -0,0 +1,268 @@
+//! Cycles a device through each of the scenes for its SKU, reporting
+//! the name and code of the current scene, so that users can audition
+//! the scenes and identify the codes of those that are undocumented.
+use crate::govee_scenes::ParsedScene;
+use crate::scene_match::resolve_scene_name;
+use crate::scene_resolver::{scenes_for_sku, SceneSelector};
+use crate::service::device::Device;
+use crate::service::hass::{base_topic, device_topic_id, IdParameter};
+use crate::service::state::{State as ServiceState, StateHandle};
+use mosquitto_rs::router::{Params, Payload, State};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+
+/// How long each scene is shown when no dwell time is specified
+pub const DEFAULT_DWELL: Duration = Duration::from_secs(5);
+
+#[derive(Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct ScenePreviewParams {
+    /// How many seconds to show each scene for
+    pub dwell_seconds: Option<f64>,
+    /// The name of the scene with which to begin, such as
+    /// when resuming an earlier preview
+    pub start: Option<String>,
+}
+
+impl ScenePreviewParams {
+    pub fn dwell(&self) -> anyhow::Result<Duration> {
+        match self.dwell_seconds {
+            Some(secs) => Duration::try_from_secs_f64(secs)
+                .map_err(|err| anyhow::anyhow!("invalid dwell_seconds {secs}: {err}")),
+            None => Ok(DEFAULT_DWELL),
+        }
+    }
+}
+
+/// Where the scene that a preview is currently showing is published
+pub fn scene_preview_state_topic(device: &Device) -> String {
+    format!(
+        "{}/{id}/preview-scenes/current",
+        base_topic(),
+        id = device_topic_id(device)
+    )
+}
+
+/// The scene that a preview is currently showing
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ScenePreviewStatus {
+    /// The 1-based position of the scene in the preview
+    pub index: usize,
+    pub count: usize,
+    pub name: String,
+    /// The scene code and param id, for scenes from the Govee API
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub code: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub param_id: Option<u32>,
+}
+
+impl ScenePreviewStatus {
+    pub fn new(index: usize, count: usize, scene: &ParsedScene) -> Self {
+        let from_api = scene.override_cmd_b64.is_none();
+        Self {
+            index: index + 1,
+            count,
+            name: scene.display_name.to_string(),
+            code: from_api.then_some(scene.scene_code),
+            param_id: from_api.then_some(scene.source_api_scence_param_id),
+        }
+    }
+}
+
+impl std::fmt::Display for ScenePreviewStatus {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "[{}/{}] {}", self.index, self.count, self.name)?;
+        if let Some(code) = self.code {
+            write!(fmt, " (code {code}")?;
+            if let Some(param_id) = self.param_id {
+                write!(fmt, ", param id {param_id}")?;
+            }
+            write!(fmt, ")")?;
+        }
+        Ok(())
+    }
+}
+
+/// Returns the scenes in the order in which they are previewed,
+/// beginning with the scene named start, if any
+pub fn preview_order<'a>(
+    scenes: &'a [ParsedScene],
+    start: Option<&str>,
+) -> anyhow::Result<&'a [ParsedScene]> {
+    let Some(start) = start else {
+        return Ok(scenes);
+    };
+    let names: Vec<&str> = scenes.iter().map(|s| s.display_name.as_str()).collect();
+    let name = resolve_scene_name(&names, start, false)?;
+    let position = scenes
+        .iter()
+        .position(|s| s.display_name == name)
+        .expect("resolved name to be one of the scenes");
+    Ok(&scenes[position..])
+}
+
+impl ServiceState {
+    /// Start cycling the device through its scenes, replacing
+    /// any preview that is already running for the device
+    pub async fn start_scene_preview(
+        self: &Arc<Self>,
+        label: &str,
+        params: ScenePreviewParams,
+    ) -> anyhow::Result<()> {
+        let device = self.resolve_device_read_only(label).await?;
+        let dwell = params.dwell()?;
+        let all_scenes = scenes_for_sku(&device.sku).await?;
+        anyhow::ensure!(!all_scenes.is_empty(), "There are no scenes for {device}");
+        // Check the start scene now, so that the error is reported
+        preview_order(&all_scenes, params.start.as_deref())?;
+
+        log::info!(
+            "Previewing {} scenes on {device} for {dwell:?} each",
+            all_scenes.len()
+        );
+
+        let state = self.clone();
+        let device_id = device.id.clone();
+        let task = tokio::spawn(async move {
+            let scenes = preview_order(&all_scenes, params.start.as_deref())
+                .expect("start to have been checked");
+            let offset = all_scenes.len() - scenes.len();
+            for (index, scene) in scenes.iter().enumerate() {
+                let status = ScenePreviewStatus::new(offset + index, all_scenes.len(), scene);
+                if let Err(err) = state.preview_scene(&device_id, scene, &status).await {
+                    log::error!("scene preview for {device_id}: {status}: {err:#}");
+                }
+                sleep(dwell).await;
+            }
+            log::info!("Scene preview for {device_id} finished");
+            state.take_scene_preview(&device_id).await;
+        });
+
+        if let Some(prior) = self
+            .set_scene_preview(&device.id, task.abort_handle())
+            .await
+        {
+            prior.abort();
+        }
+        Ok(())
+    }
+
+    async fn preview_scene(
+        self: &Arc<Self>,
+        device_id: &str,
+        scene: &ParsedScene,
+        status: &ScenePreviewStatus,
+    ) -> anyhow::Result<()> {
+        log::info!("Scene preview for {device_id}: {status}");
+        let device = self.resolve_device_for_control(device_id).await?;
+        if let Some(hass) = self.get_hass_client().await {
+            hass.publish_obj(scene_preview_state_topic(&device), status)
+                .await?;
+        }
+        let selector = SceneSelector::Name {
+            name: scene.display_name.to_string(),
+            fuzzy: false,
+        };
+        self.device_activate_scene(&device, &selector).await?;
+        self.emit_state_changed(&device.id);
+        Ok(())
+    }
+
+    /// Stop any preview that is running for the device.
+    /// Returns true if there was a preview to stop.
+    pub async fn cancel_scene_preview(&self, device_id: &str) -> bool {
+        match self.take_scene_preview(device_id).await {
+            Some(handle) => {
+                log::info!("Cancelling scene preview for {device_id}");
+                handle.abort();
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// Start a scene preview. The payload is empty to use the defaults,
+/// the number of seconds to show each scene, a JSON ScenePreviewParams
+/// object, or the string "cancel" to stop a preview that is running.
+pub async fn mqtt_device_preview_scenes(
+    Payload(payload): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let payload = payload.trim();
+    if payload.eq_ignore_ascii_case("cancel") {
+        let device = state.resolve_device_read_only(&id).await?;
+        state.cancel_scene_preview(&device.id).await;
+        return Ok(());
+    }
+
+    let params = if payload.is_empty() {
+        ScenePreviewParams::default()
+    } else if let Ok(secs) = payload.parse::<f64>() {
+        ScenePreviewParams {
+            dwell_seconds: Some(secs),
+            ..Default::default()
+        }
+    } else {
+        serde_json::from_str(payload)?
+    };
+    state.start_scene_preview(&id, params).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn scene(name: &str, code: u16) -> ParsedScene {
+        ParsedScene {
+            display_name: name.to_string(),
+            scene_code: code,
+            api_scence_param: String::new(),
+            sku: "H6072".to_string(),
+            source_api_scene_name: name.to_string(),
+            source_api_effect_name: None,
+            source_api_scene_id: 0,
+            source_api_scence_param_id: 7,
+            override_cmd_b64: None,
+            category: None,
+        }
+    }
+
+    #[test]
+    fn order() {
+        let scenes = vec![scene("Aurora", 1), scene("Forest", 2), scene("Sunrise", 3)];
+        assert_eq!(preview_order(&scenes, None).unwrap().len(), 3);
+        let from_forest = preview_order(&scenes, Some("forest")).unwrap();
+        assert_eq!(from_forest[0].display_name, "Forest");
+        assert_eq!(from_forest.len(), 2);
+        assert!(preview_order(&scenes, Some("Ocean")).is_err());
+
+        let status = ScenePreviewStatus::new(1, 3, &scenes[1]);
+        assert_eq!(status.to_string(), "[2/3] Forest (code 2, param id 7)");
+        assert_eq!(
+            serde_json::to_value(&status).unwrap(),
+            serde_json::json!({
+                "index": 2, "count": 3, "name": "Forest", "code": 2, "param_id": 7
+            })
+        );
+    }
+
+    #[test]
+    fn params() {
+        assert_eq!(
+            ScenePreviewParams::default().dwell().unwrap(),
+            DEFAULT_DWELL
+        );
+        let params: ScenePreviewParams =
+            serde_json::from_str(r#"{"dwell_seconds": 1.5, "start": "Forest"}"#).unwrap();
+        assert_eq!(params.dwell().unwrap(), Duration::from_millis(1500));
+        assert!(ScenePreviewParams {
+            dwell_seconds: Some(-1.),
+            ..Default::default()
+        }
+        .dwell()
+        .is_err());
+    }
+}
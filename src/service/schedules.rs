@@ -0,0 +1,105 @@
+//! Mirrors the timers that were configured for devices via the
+//! Govee Home app, so that they can be seen in hass, and allows
+//! them to be enabled or disabled.
+use crate::service::device::Device;
+use crate::service::hass::IdParameter;
+use crate::service::state::{State as ServiceState, StateHandle};
+use anyhow::Context;
+use mosquitto_rs::router::{Params, Payload, State};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::time::{sleep, Duration};
+
+/// How often the schedules are fetched from Govee
+const SCHEDULE_SYNC_INTERVAL: Duration = Duration::from_secs(30 * 60);
+
+impl ServiceState {
+    /// Fetch the schedules for device, returning true if they changed
+    pub async fn refresh_device_schedules(&self, device: &Device) -> anyhow::Result<bool> {
+        let undoc = self
+            .get_undoc_client()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Undoc API client is not available"))?;
+        let acct = undoc.login_account_cached().await?;
+        let schedules = undoc
+            .get_device_schedules(&acct.token, &device.sku, &device.id)
+            .await?;
+
+        Ok(self
+            .device_mut(&device.sku, &device.id)
+            .await
+            .set_schedules(schedules))
+    }
+
+    pub async fn device_set_schedule_enabled(
+        self: &Arc<Self>,
+        device: &Device,
+        timer_id: u64,
+        enabled: bool,
+    ) -> anyhow::Result<()> {
+        let schedule = device
+            .schedules
+            .as_ref()
+            .and_then(|schedules| schedules.iter().find(|s| s.timer_id == timer_id))
+            .ok_or_else(|| anyhow::anyhow!("{device} has no schedule with id {timer_id}"))?;
+
+        if self.is_dry_run().await {
+            log::info!("DRY RUN: not setting {device} schedule {timer_id} enabled={enabled}");
+            return Ok(());
+        }
+
+        let undoc = self
+            .get_undoc_client()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Undoc API client is not available"))?;
+        let acct = undoc.login_account_cached().await?;
+        undoc
+            .set_device_schedule_enabled(&acct.token, &device.sku, &device.id, schedule, enabled)
+            .await?;
+
+        self.refresh_device_schedules(device).await?;
+        self.emit_state_changed(&device.id);
+        Ok(())
+    }
+}
+
+/// Periodically fetch the schedules for the devices that
+/// are known to the Govee account
+pub async fn run_schedule_sync(state: StateHandle) {
+    loop {
+        for device in state.devices().await {
+            if device.undoc_device_info.is_none() {
+                continue;
+            }
+            match state.refresh_device_schedules(&device).await {
+                Ok(true) => state.emit_state_changed(&device.id),
+                Ok(false) => {}
+                Err(err) => log::warn!("Unable to fetch schedules for {device}: {err:#}"),
+            }
+        }
+        sleep(SCHEDULE_SYNC_INTERVAL).await;
+    }
+}
+
+#[derive(Deserialize)]
+struct SetScheduleEnabled {
+    id: u64,
+    enabled: bool,
+}
+
+/// Enables or disables a schedule.
+/// The payload looks like `{"id": 1183501, "enabled": false}`
+pub async fn mqtt_set_schedule_enabled(
+    Payload(payload): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_set_schedule_enabled: {id}: {payload}");
+    let device = state.resolve_device_for_control(&id).await?;
+    let request: SetScheduleEnabled =
+        serde_json::from_str(&payload).context("parsing schedule request")?;
+
+    state
+        .device_set_schedule_enabled(&device, request.id, request.enabled)
+        .await
+}
@@ -0,0 +1,188 @@
+//! Sets the brightness of several segments of a light in one command,
+//! rather than one segment at a time via the segment light entities.
+use crate::ble::{Base64HexBytes, SetSegmentBrightness};
+use crate::service::device::Device;
+use crate::service::hass::IdParameter;
+use crate::service::state::{State as ServiceState, StateHandle};
+use mosquitto_rs::router::{Params, Payload, State};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::BTreeMap;
+use std::sync::Arc;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SegmentSetting {
+    /// The zero-based index of the segment
+    pub idx: u32,
+    /// The brightness of the segment, in percent
+    pub brightness: u8,
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq, Eq)]
+#[serde(deny_unknown_fields)]
+pub struct SegmentsCommand {
+    pub segments: Vec<SegmentSetting>,
+}
+
+/// Group the segments by brightness, so that each group
+/// is set by a single packet
+pub fn segment_brightness_packets(
+    settings: &[SegmentSetting],
+) -> anyhow::Result<Vec<SetSegmentBrightness>> {
+    let mut by_brightness: BTreeMap<u8, Vec<u32>> = BTreeMap::new();
+    for setting in settings {
+        by_brightness
+            .entry(setting.brightness)
+            .or_default()
+            .push(setting.idx);
+    }
+    by_brightness
+        .into_iter()
+        .map(|(brightness, segments)| SetSegmentBrightness::new(brightness, &segments))
+        .collect()
+}
+
+impl ServiceState {
+    pub async fn device_set_segments(
+        self: &Arc<Self>,
+        device: &Device,
+        segments: &[SegmentSetting],
+    ) -> anyhow::Result<()> {
+        anyhow::ensure!(!segments.is_empty(), "no segments were specified");
+        // We can only check the segments of devices whose
+        // segment count we know
+        if device.segment_range().is_some() {
+            for setting in segments {
+                device.validate_segment(setting.idx)?;
+            }
+        }
+        for setting in segments {
+            anyhow::ensure!(
+                setting.brightness <= 100,
+                "segment {}: brightness {} is out of range 0-100",
+                setting.idx,
+                setting.brightness
+            );
+        }
+
+        self.run_device_command(device, "segments", json!({"segments": segments}), || {
+            self.device_set_segments_impl(device, segments)
+        })
+        .await
+    }
+
+    async fn device_set_segments_impl(
+        self: &Arc<Self>,
+        device: &Device,
+        segments: &[SegmentSetting],
+    ) -> anyhow::Result<()> {
+        let commands = segment_brightness_packets(segments).and_then(|packets| {
+            let mut commands = vec![];
+            for packet in &packets {
+                commands.extend(Base64HexBytes::encode_for_sku(&device.sku, packet)?.base64());
+            }
+            Ok(commands)
+        });
+
+        match commands {
+            Ok(commands) => {
+                if let Some(lan_dev) = &device.lan_device {
+                    log::info!("Using LAN API to set {device} segment brightness");
+                    lan_dev.send_real(commands).await?;
+                    self.record_segment_brightness(device, segments).await;
+                    return Ok(());
+                }
+                if let (Some(iot), Some(info)) =
+                    (self.get_iot_client().await, &device.undoc_device_info)
+                {
+                    log::info!("Using IoT API to set {device} segment brightness");
+                    self.iot_send_real(&iot, &info.entry, commands).await?;
+                    self.record_segment_brightness(device, segments).await;
+                    return Ok(());
+                }
+            }
+            Err(err) => {
+                log::debug!("Unable to encode segment brightness for {device}: {err:#}");
+            }
+        }
+
+        if let (Some(client), Some(info)) =
+            (self.get_platform_client().await, &device.http_device_info)
+        {
+            log::info!("Using Platform API to set {device} segment brightness");
+            for setting in segments {
+                client
+                    .set_segment_brightness(info, setting.idx, setting.brightness)
+                    .await?;
+            }
+            self.record_segment_brightness(device, segments).await;
+            return Ok(());
+        }
+
+        anyhow::bail!("Unable to set the segment brightness of {device}");
+    }
+
+    /// Reflect the new brightness in the segment colors that the
+    /// device previously reported, until it reports them again
+    async fn record_segment_brightness(&self, device: &Device, segments: &[SegmentSetting]) {
+        let mut device = self.device_mut(&device.sku, &device.id).await;
+        for setting in segments {
+            if let Some(color) = device.segment_colors.get_mut(&setting.idx) {
+                color.brightness = setting.brightness;
+            }
+        }
+    }
+}
+
+pub async fn mqtt_device_set_segments(
+    Payload(payload): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let device = state.resolve_device_for_control(&id).await?;
+    let command: SegmentsCommand = serde_json::from_str(&payload)?;
+    log::info!("Command for {device} segments: {payload}");
+    state.device_set_segments(&device, &command.segments).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn brightness_packets() {
+        let command: SegmentsCommand = serde_json::from_str(
+            r#"{"segments": [
+                {"idx": 0, "brightness": 50},
+                {"idx": 3, "brightness": 100},
+                {"idx": 1, "brightness": 50}
+            ]}"#,
+        )
+        .unwrap();
+        let packets = segment_brightness_packets(&command.segments).unwrap();
+        assert_eq!(
+            packets,
+            vec![
+                SetSegmentBrightness {
+                    brightness: 50,
+                    mask: 0b0011
+                },
+                SetSegmentBrightness {
+                    brightness: 100,
+                    mask: 0b1000
+                },
+            ]
+        );
+
+        let beyond = [SegmentSetting {
+            idx: 20,
+            brightness: 50,
+        }];
+        assert!(segment_brightness_packets(&beyond).is_err());
+        assert!(serde_json::from_str::<SegmentsCommand>(
+            r#"{"segments": [{"idx": 0, "brightness": 50, "rgb": 1}]}"#
+        )
+        .is_err());
+    }
+}
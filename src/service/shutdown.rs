@@ -0,0 +1,138 @@
+//! Coordinates an orderly shutdown when we are asked to terminate,
+//! so that hass learns that our devices are offline right away,
+//! rather than when the broker notices that we have gone.
+use crate::service::hass::{availability_topic, device_availability_topic};
+use crate::service::state::State as ServiceState;
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::{timeout, Duration};
+
+/// How long to allow for the shutdown before giving up on it
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Long-running tasks watch this to learn that we are shutting down
+#[derive(Clone)]
+pub struct ShutdownToken {
+    tx: Arc<watch::Sender<bool>>,
+}
+
+impl Default for ShutdownToken {
+    fn default() -> Self {
+        let (tx, _rx) = watch::channel(false);
+        Self { tx: Arc::new(tx) }
+    }
+}
+
+impl ShutdownToken {
+    pub fn trigger(&self) {
+        self.tx.send_replace(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.tx.borrow()
+    }
+
+    /// Wait until the shutdown is triggered
+    pub async fn wait(&self) {
+        let mut rx = self.tx.subscribe();
+        rx.wait_for(|triggered| *triggered).await.ok();
+    }
+
+    /// Run future until it completes, or is abandoned at its next
+    /// await point because the shutdown was triggered
+    pub async fn run_until<F: Future>(&self, future: F) -> Option<F::Output> {
+        tokio::select! {
+            biased;
+            _ = self.wait() => None,
+            output = future => Some(output),
+        }
+    }
+
+    /// Spawn a task that stops when the shutdown is triggered
+    pub fn spawn<F>(&self, future: F) -> JoinHandle<Option<F::Output>>
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        let token = self.clone();
+        tokio::spawn(async move { token.run_until(future).await })
+    }
+}
+
+/// Wait for SIGTERM, as sent by container runtimes, or SIGINT
+pub async fn wait_for_termination() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut term = signal(SignalKind::terminate())?;
+        tokio::select! {
+            _ = term.recv() => {}
+            result = tokio::signal::ctrl_c() => result?,
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await?;
+    Ok(())
+}
+
+impl ServiceState {
+    /// Stop the long-running tasks, tell hass that we and our
+    /// devices are going offline and flush the disk cache
+    pub async fn shutdown(self: &Arc<Self>) {
+        log::info!("Shutting down");
+        self.shutdown_token().trigger();
+
+        match timeout(SHUTDOWN_TIMEOUT, self.publish_offline()).await {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => log::error!("Publishing offline status: {err:#}"),
+            Err(_) => log::error!("Timed out publishing offline status"),
+        }
+
+        if let Err(err) = crate::cache::flush_cache() {
+            log::error!("Flushing cache: {err:#}");
+        }
+
+        // mosquitto-rs doesn't offer a way to disconnect its async
+        // client, so no DISCONNECT is sent and the broker treats our
+        // exit as a lost connection, publishing our will. That is
+        // why the offline status was published explicitly above.
+        log::info!("Shutdown complete");
+    }
+
+    /// Publish offline to our availability topic and to those of each
+    /// of our devices. Ours is published first, so that a failure for
+    /// a device cannot prevent hass from learning that we are offline.
+    async fn publish_offline(&self) -> anyhow::Result<()> {
+        let Some(hass) = self.get_hass_client().await else {
+            return Ok(());
+        };
+        hass.publish(availability_topic(), "offline").await?;
+        for device in self.devices().await {
+            if let Err(err) = hass
+                .publish(device_availability_topic(&device), "offline")
+                .await
+            {
+                log::error!("Publishing offline status of {device}: {err:#}");
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[tokio::test]
+    async fn token() {
+        let token = ShutdownToken::default();
+        assert_eq!(token.run_until(async { 42 }).await, Some(42));
+
+        let task = token.spawn(std::future::pending::<()>());
+        token.trigger();
+        assert_eq!(task.await.unwrap(), None);
+        assert_eq!(token.run_until(async { 42 }).await, None);
+    }
+}
@@ -0,0 +1,158 @@
+use crate::lan_api::DeviceColor;
+use crate::platform_api::DeviceType;
+use crate::service::device::{Device, DeviceState};
+use crate::service::hass::IdParameter;
+use crate::service::state::{State as ServiceState, StateHandle};
+use mosquitto_rs::router::{Params, State};
+use serde::Serialize;
+use std::sync::Arc;
+
+/// The light related state of a device at a point in time,
+/// which can be restored later
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub struct DeviceSnapshot {
+    pub on: bool,
+    pub brightness: u8,
+    /// Zero when the device is showing an rgb color
+    pub kelvin: u32,
+    pub color: DeviceColor,
+    pub scene: Option<String>,
+}
+
+impl DeviceSnapshot {
+    pub fn of_device(device: &Device) -> anyhow::Result<Self> {
+        let state = device
+            .device_state()
+            .ok_or_else(|| anyhow::anyhow!("The state of {device} is not known"))?;
+        Ok(Self::from_state(&state))
+    }
+
+    pub fn from_state(state: &DeviceState) -> Self {
+        Self {
+            on: state.light_on.unwrap_or(state.on),
+            brightness: state.brightness,
+            kelvin: state.kelvin,
+            color: state.color,
+            scene: state.scene.clone(),
+        }
+    }
+}
+
+impl ServiceState {
+    /// Capture the current state of the device, replacing any
+    /// snapshot that was previously taken
+    pub async fn snapshot_device(&self, device: &Device) -> anyhow::Result<DeviceSnapshot> {
+        let snapshot = DeviceSnapshot::of_device(device)?;
+        log::info!("Snapshot of {device}: {snapshot:?}");
+        self.set_snapshot(&device.id, snapshot.clone()).await;
+        Ok(snapshot)
+    }
+
+    /// Return the device to the state captured by snapshot_device.
+    /// The snapshot is consumed.
+    pub async fn restore_device(self: &Arc<Self>, label: &str) -> anyhow::Result<()> {
+        let device = self.resolve_device_for_control(label).await?;
+        let snapshot = self
+            .take_snapshot(&device.id)
+            .await
+            .ok_or_else(|| anyhow::anyhow!("There is no snapshot for {device}"))?;
+        self.apply_snapshot(&device, &snapshot).await
+    }
+
+    /// Return the device to the state in snapshot, without
+    /// affecting the snapshot that is stored for it
+    pub async fn restore_device_to(
+        self: &Arc<Self>,
+        label: &str,
+        snapshot: &DeviceSnapshot,
+    ) -> anyhow::Result<()> {
+        let device = self.resolve_device_for_control(label).await?;
+        self.apply_snapshot(&device, snapshot).await
+    }
+
+    async fn apply_snapshot(
+        self: &Arc<Self>,
+        device: &Device,
+        snapshot: &DeviceSnapshot,
+    ) -> anyhow::Result<()> {
+        log::info!("Restoring {device} to {snapshot:?}");
+
+        let is_light = device.device_type() == DeviceType::Light;
+
+        if !snapshot.on {
+            if is_light {
+                self.device_light_power_on(device, false).await?;
+            } else {
+                self.device_set_brightness(device, 0).await?;
+            }
+            return Ok(());
+        }
+
+        if is_light {
+            self.device_light_power_on(device, true).await?;
+        }
+
+        if let Some(scene) = &snapshot.scene {
+            self.device_set_scene(device, scene).await?;
+        } else if snapshot.kelvin != 0 {
+            self.device_set_color_temperature(device, snapshot.kelvin)
+                .await?;
+        } else if device.supports_rgb() {
+            let DeviceColor { r, g, b } = snapshot.color;
+            self.device_set_color_rgb(device, r, g, b).await?;
+        }
+
+        self.device_set_brightness(device, snapshot.brightness)
+            .await
+    }
+}
+
+/// Capture the current state of the device
+pub async fn mqtt_device_snapshot(
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let device = state.resolve_device_read_only(&id).await?;
+    state.snapshot_device(&device).await?;
+    Ok(())
+}
+
+/// Restore the device to its previously captured state
+pub async fn mqtt_device_restore(
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    state.restore_device(&id).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn snapshot_from_state() {
+        let state = DeviceState {
+            on: true,
+            light_on: Some(false),
+            online: None,
+            kelvin: 0,
+            color: DeviceColor { r: 255, g: 0, b: 0 },
+            brightness: 42,
+            scene: None,
+            source: "test",
+            updated: chrono::Utc::now(),
+        };
+        // For devices with a separate light function, it is the
+        // light that we are interested in
+        assert_eq!(
+            DeviceSnapshot::from_state(&state),
+            DeviceSnapshot {
+                on: false,
+                brightness: 42,
+                kelvin: 0,
+                color: DeviceColor { r: 255, g: 0, b: 0 },
+                scene: None,
+            }
+        );
+    }
+}
@@ -1,43 +1,207 @@
-use crate::ble::{Base64HexBytes, SetHumidifierMode, SetHumidifierNightlightParams, SetSceneCode};
-use crate::lan_api::{Client as LanClient, DeviceStatus as LanDeviceStatus, LanDevice};
-use crate::platform_api::{DeviceCapability, GoveeApiClient};
+use crate::ble::{
+    Base64HexBytes, HeaterTemperature, SetChildLock, SetGradient, SetHeaterMode,
+    SetHeaterTargetTemperature, SetHumidifierMode, SetHumidifierNightlightParams,
+    SetIceMakerMaking, SetKettleMode, SetMistLevel, SetOscillation, SetPurifierMode,
+    TargetHumidity, DIFFUSER_SKUS,
+};
+use crate::lan_api::{
+    Client as LanClient, DeviceColor, DeviceStatus as LanDeviceStatus, LanDevice,
+};
+use crate::platform_api::{DeviceCapability, GoveeApiClient, HttpDeviceInfo};
+use crate::scene_match::{find_scene, resolve_scene_name};
+use crate::scene_resolver::{resolve_scene, scenes_for_sku, SceneSelector};
+use crate::service::ble_scanner::{last_advert_heard, BLE_SILENCE_WINDOW};
+use crate::service::control_transport::TransportCommand;
 use crate::service::coordinator::Coordinator;
-use crate::service::device::Device;
-use crate::service::hass::{topic_safe_id, HassClient};
-use crate::service::iot::IotClient;
+use crate::service::debounce::{LightCommandSlot, DEFAULT_MIN_COMMAND_INTERVAL};
+use crate::service::device::{Device, DeviceToggle, UndocDeviceInfo, MIST_LEVEL_WORK_MODE};
+use crate::service::device_index::DeviceIndex;
+use crate::service::discovery_overrides::DiscoveryOverrideFile;
+use crate::service::effects::{LocalEffect, DEFAULT_TRANSITION_INTERVAL};
+use crate::service::events::{DeviceEvent, EventBus};
+use crate::service::hass::{
+    base_topic, device_availability_topic, device_topic_id, topic_safe_id, HassClient,
+    PublishedState,
+};
+use crate::service::health::SubsystemHealth;
+use crate::service::iot::{IotClient, IotHealth};
+use crate::service::iot_reply::IotReplies;
+use crate::service::light_group::LightGroupConfig;
+use crate::service::poll_intervals::PollIntervalsFile;
+use crate::service::power::PowerModelFile;
+use crate::service::scene_filter::SceneFilters;
+use crate::service::shutdown::ShutdownToken;
+use crate::service::snapshot::DeviceSnapshot;
+use crate::service::transport::TransportPriorityFile;
+use crate::service::wakeup::AlarmConfig;
+use crate::service::watchdog::SupervisedTask;
 use crate::temperature::{TemperatureScale, TemperatureValue};
-use crate::govee_scenes::{get_parsed_scenes_for_sku, ParsedScene}; // Import ParsedScene and the function
 use anyhow::Context;
-use serde_json::Value as JsonValue;
-use std::collections::HashMap;
-use std::sync::Arc;
+use arc_swap::ArcSwap;
+use serde_json::{json, Value as JsonValue};
+use std::collections::{HashMap, HashSet};
+use std::ops::{Deref, DerefMut};
+use std::sync::{Arc, Weak};
 use std::time::Instant;
-use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard, Semaphore};
-use tokio::time::{sleep, Duration};
+use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard, OwnedSemaphorePermit, Semaphore};
+use tokio::task::AbortHandle;
+use tokio::time::{sleep, timeout, Duration};
 
-// Definitions for ParsedScene and JsonSceneOverrideEntry are now solely in govee_scenes.rs
+/// How long to wait for a prior control request for a device
+/// to complete before giving up
+pub const DEFAULT_CONTROL_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// How long a device may go without responding via any transport
+/// before we report it as unavailable
+pub const DEFAULT_OFFLINE_THRESHOLD: chrono::Duration = chrono::Duration::minutes(35);
 
 #[derive(Default)]
 pub struct State {
+    /// Serializes changes to the devices
     devices_by_id: Mutex<HashMap<String, Device>>,
-    semaphore_by_id: Mutex<HashMap<String, Arc<Semaphore>>>,
+    /// A copy of devices_by_id that is updated after each change,
+    /// so that readers never wait for a writer
+    device_snapshot: ArcSwap<DeviceIndex>,
+    control_by_id: Mutex<HashMap<String, ControlSlot>>,
+    control_timeout: Mutex<Option<Duration>>,
+    transition_interval: Mutex<Option<Duration>>,
+    min_command_interval: Mutex<Option<Duration>>,
+    light_commands: Mutex<HashMap<String, LightCommandSlot>>,
+    dry_run: Mutex<bool>,
+    optimistic: Mutex<bool>,
+    read_only: Mutex<bool>,
+    read_only_devices: Mutex<Vec<String>>,
+    offline_threshold: Mutex<Option<chrono::Duration>>,
+    /// The availability that we last reported for each device
+    availability_by_id: Mutex<HashMap<String, bool>>,
     lan_client: Mutex<Option<LanClient>>,
     platform_client: Mutex<Option<GoveeApiClient>>,
     #[allow(dead_code)]
     undoc_client: Mutex<Option<crate::undoc_api::GoveeUndocumentedApi>>,
     iot_client: Mutex<Option<IotClient>>,
+    iot_health: Mutex<Option<IotHealth>>,
+    mqtt_health: Mutex<Option<SubsystemHealth>>,
     hass_client: Mutex<Option<HassClient>>,
     hass_discovery_prefix: Mutex<String>,
     temperature_scale: Mutex<TemperatureScale>,
+    local_effects: Mutex<HashMap<String, AbortHandle>>,
+    ramps: Mutex<HashMap<String, AbortHandle>>,
+    scene_previews: Mutex<HashMap<String, AbortHandle>>,
+    off_timers: Mutex<HashMap<String, AbortHandle>>,
+    alarms: Mutex<Vec<AlarmConfig>>,
+    light_groups: Mutex<Vec<LightGroupConfig>>,
+    snapshots: Mutex<HashMap<String, DeviceSnapshot>>,
+    transport_priority: Mutex<TransportPriorityFile>,
+    poll_intervals: Mutex<PollIntervalsFile>,
+    scene_filters: Mutex<SceneFilters>,
+    power_models: Mutex<PowerModelFile>,
+    discovery_overrides: Mutex<DiscoveryOverrideFile>,
+    /// The gv2mqtt discovery config topics that we have published,
+    /// or seen retained on the broker
+    discovery_topics: Mutex<HashSet<String>>,
+    /// The state most recently published to hass for each device,
+    /// so that unchanged state isn't published again
+    published_states: Mutex<HashMap<String, PublishedState>>,
+    shutdown: ShutdownToken,
+    events: EventBus,
+    iot_replies: IotReplies,
+    /// The long-running tasks that the watchdog restarts if they fail
+    supervised_tasks: Mutex<Vec<SupervisedTask>>,
 }
 
 pub type StateHandle = Arc<State>;
 
+/// Grants mutable access to a device. Readers continue to see the
+/// prior version of the device until the guard is dropped.
+pub struct DeviceGuard<'a> {
+    device: MappedMutexGuard<'a, Device>,
+    snapshot: &'a ArcSwap<DeviceIndex>,
+}
+
+impl Deref for DeviceGuard<'_> {
+    type Target = Device;
+
+    fn deref(&self) -> &Device {
+        &self.device
+    }
+}
+
+impl DerefMut for DeviceGuard<'_> {
+    fn deref_mut(&mut self) -> &mut Device {
+        &mut self.device
+    }
+}
+
+impl std::fmt::Display for DeviceGuard<'_> {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        self.device.fmt(fmt)
+    }
+}
+
+impl Drop for DeviceGuard<'_> {
+    fn drop(&mut self) {
+        // Writers are serialized by the mutex that we still hold
+        let index = self.snapshot.load().with_device(self.device.clone());
+        self.snapshot.store(Arc::new(index));
+    }
+}
+
+/// Tracks the permit that serializes control of a device,
+/// along with the Coordinator that currently holds it
+struct ControlSlot {
+    semaphore: Arc<Semaphore>,
+    holder: Weak<()>,
+    /// When the current holder acquired the permit
+    acquired: Option<chrono::DateTime<chrono::Utc>>,
+}
+
+impl Default for ControlSlot {
+    fn default() -> Self {
+        Self {
+            semaphore: Arc::new(Semaphore::new(1)),
+            holder: Weak::new(),
+            acquired: None,
+        }
+    }
+}
+
+impl ControlSlot {
+    /// When the Coordinator that holds the permit acquired it,
+    /// if it is still in flight
+    fn in_flight_since(&self) -> Option<chrono::DateTime<chrono::Utc>> {
+        if self.holder.strong_count() == 0 {
+            return None;
+        }
+        self.acquired
+    }
+}
+
 impl State {
     pub fn new() -> Self {
         Self::default()
     }
 
+    pub fn shutdown_token(&self) -> ShutdownToken {
+        self.shutdown.clone()
+    }
+
+    pub fn emit_event(&self, event: DeviceEvent) {
+        self.events.emit(event);
+    }
+
+    pub fn subscribe_events(&self) -> tokio::sync::broadcast::Receiver<DeviceEvent> {
+        self.events.subscribe()
+    }
+
+    pub fn supervised_tasks(&self) -> &Mutex<Vec<SupervisedTask>> {
+        &self.supervised_tasks
+    }
+
+    /// The commands sent via IoT that are awaiting a response
+    pub fn iot_replies(&self) -> &IotReplies {
+        &self.iot_replies
+    }
+
     pub async fn set_temperature_scale(&self, scale: TemperatureScale) {
         *self.temperature_scale.lock().await = scale;
     }
@@ -54,33 +218,215 @@ impl State {
         self.hass_discovery_prefix.lock().await.to_string()
     }
 
-    pub async fn device_mut(&self, sku: &str, id: &str) -> MappedMutexGuard<Device> {
+    pub async fn device_mut(&self, sku: &str, id: &str) -> DeviceGuard<'_> {
         let devices = self.devices_by_id.lock().await;
-        MutexGuard::map(devices, |devices| {
+        let device = MutexGuard::map(devices, |devices| {
             devices
                 .entry(id.to_string())
                 .or_insert_with(|| Device::new(sku, id))
-        })
+        });
+        DeviceGuard {
+            device,
+            snapshot: &self.device_snapshot,
+        }
+    }
+
+    /// Forget the device, such as when it was removed from
+    /// the Govee account, returning it if it was known
+    pub async fn remove_device(&self, id: &str) -> Option<Device> {
+        let mut devices = self.devices_by_id.lock().await;
+        let device = devices.remove(id)?;
+        // Writers are serialized by the mutex that we still hold
+        let index = self.device_snapshot.load().without_device(id);
+        self.device_snapshot.store(Arc::new(index));
+        self.published_states.lock().await.remove(id);
+        Some(device)
     }
 
     pub async fn devices(&self) -> Vec<Device> {
-        self.devices_by_id.lock().await.values().cloned().collect()
+        self.device_snapshot
+            .load()
+            .values()
+            .map(|device| Device::clone(device))
+            .collect()
     }
 
     pub async fn device_by_id(&self, id: &str) -> Option<Device> {
-        let devices = self.devices_by_id.lock().await;
-        devices.get(id).cloned()
+        self.device_snapshot
+            .load()
+            .get(id)
+            .map(|device| Device::clone(device))
+    }
+
+    /// Find the device that is advertising from the BLE address
+    pub async fn device_by_ble_address(&self, address: &str) -> Option<Device> {
+        self.device_snapshot
+            .load()
+            .find_by_ble_address(address)
+            .map(|device| Device::clone(device))
+    }
+
+    /// Look up the device of an hass entity. Devices can be removed
+    /// while their entities are still queued to publish their state,
+    /// so the caller should just skip the entity if it is gone.
+    pub async fn device_for_entity(&self, id: &str) -> Option<Device> {
+        let device = self.device_by_id(id).await;
+        if device.is_none() {
+            log::debug!("Not publishing the state of removed device {id}");
+        }
+        device
+    }
+
+    pub async fn set_control_timeout(&self, duration: Duration) {
+        self.control_timeout.lock().await.replace(duration);
+    }
+
+    pub async fn get_control_timeout(&self) -> Duration {
+        self.control_timeout
+            .lock()
+            .await
+            .unwrap_or(DEFAULT_CONTROL_TIMEOUT)
+    }
+
+    pub async fn set_transition_interval(&self, interval: Duration) {
+        self.transition_interval.lock().await.replace(interval);
+    }
+
+    pub async fn get_transition_interval(&self) -> Duration {
+        self.transition_interval
+            .lock()
+            .await
+            .unwrap_or(DEFAULT_TRANSITION_INTERVAL)
+    }
+
+    pub async fn set_min_command_interval(&self, interval: Duration) {
+        self.min_command_interval.lock().await.replace(interval);
+    }
+
+    pub async fn get_min_command_interval(&self) -> Duration {
+        self.min_command_interval
+            .lock()
+            .await
+            .unwrap_or(DEFAULT_MIN_COMMAND_INTERVAL)
+    }
+
+    pub async fn light_command_slot(
+        &self,
+        device_id: &str,
+    ) -> MappedMutexGuard<'_, LightCommandSlot> {
+        let slots = self.light_commands.lock().await;
+        MutexGuard::map(slots, |slots| {
+            slots.entry(device_id.to_string()).or_default()
+        })
+    }
+
+    pub async fn set_dry_run(&self, dry_run: bool) {
+        *self.dry_run.lock().await = dry_run;
+    }
+
+    pub async fn is_dry_run(&self) -> bool {
+        *self.dry_run.lock().await
+    }
+
+    pub async fn set_optimistic(&self, optimistic: bool) {
+        *self.optimistic.lock().await = optimistic;
+    }
+
+    pub async fn is_optimistic(&self) -> bool {
+        *self.optimistic.lock().await
+    }
+
+    pub async fn set_read_only(&self, read_only: bool) {
+        *self.read_only.lock().await = read_only;
+    }
+
+    pub async fn is_read_only(&self) -> bool {
+        *self.read_only.lock().await
+    }
+
+    pub async fn set_read_only_devices(&self, labels: Vec<String>) {
+        *self.read_only_devices.lock().await = labels;
+    }
+
+    pub async fn get_read_only_devices(&self) -> Vec<String> {
+        self.read_only_devices.lock().await.clone()
+    }
+
+    pub async fn set_offline_threshold(&self, threshold: chrono::Duration) {
+        self.offline_threshold.lock().await.replace(threshold);
+    }
+
+    pub async fn get_offline_threshold(&self) -> chrono::Duration {
+        self.offline_threshold
+            .lock()
+            .await
+            .unwrap_or(DEFAULT_OFFLINE_THRESHOLD)
     }
 
     async fn semaphore_for_device(&self, device: &Device) -> Arc<Semaphore> {
-        self.semaphore_by_id
+        self.control_by_id
             .lock()
             .await
             .entry(device.id.clone())
-            .or_insert_with(|| Arc::new(Semaphore::new(1)))
+            .or_default()
+            .semaphore
             .clone()
     }
 
+    /// Discard the control permit for a device, so that subsequent
+    /// control requests can proceed even though a prior request
+    /// has not completed. Should that request turn out not to be
+    /// stuck after all, it continues concurrently with them.
+    pub async fn force_release_control(&self, device: &Device) {
+        let prior = self
+            .control_by_id
+            .lock()
+            .await
+            .insert(device.id.clone(), ControlSlot::default());
+        match prior.and_then(|slot| slot.in_flight_since()) {
+            Some(acquired) => log::warn!(
+                "Force releasing control of {device}; the request that \
+                 acquired it at {acquired} is still in flight and may \
+                 run concurrently with subsequent requests"
+            ),
+            None => log::warn!("Force releasing control of {device}, which was not held"),
+        }
+    }
+
+    async fn acquire_control(
+        &self,
+        device: &Device,
+    ) -> anyhow::Result<(OwnedSemaphorePermit, Arc<()>)> {
+        let control_timeout = self.get_control_timeout().await;
+        let semaphore = self.semaphore_for_device(device).await;
+
+        let Ok(permit) = timeout(control_timeout, semaphore.clone().acquire_owned()).await else {
+            anyhow::bail!(
+                "Timed out after {control_timeout:?} waiting for a prior \
+                 control request for {device} to complete. \
+                 Publish to {base}/{id}/release-control to force release it",
+                base = base_topic(),
+                id = device_topic_id(device)
+            );
+        };
+        let permit = permit?;
+
+        let holder = Arc::new(());
+        // If the control was force released while we were waiting,
+        // the slot now belongs to a newer semaphore and its holder
+        if let Some(slot) = self
+            .control_by_id
+            .lock()
+            .await
+            .get_mut(&device.id)
+            .filter(|slot| Arc::ptr_eq(&slot.semaphore, &semaphore))
+        {
+            slot.holder = Arc::downgrade(&holder);
+            slot.acquired = Some(chrono::Utc::now());
+        }
+        Ok((permit, holder))
+    }
+
     pub async fn resolve_device_read_only(self: &Arc<Self>, label: &str) -> anyhow::Result<Device> {
         self.resolve_device(label)
             .await
@@ -95,8 +441,11 @@ impl State {
             .resolve_device(label)
             .await
             .ok_or_else(|| anyhow::anyhow!("device '{label}' not found"))?;
-        let semaphore = self.semaphore_for_device(&device).await;
-        let permit = semaphore.acquire_owned().await?;
+        self.check_not_read_only(&device).await?;
+        let (permit, holder) = self.acquire_control(&device).await?;
+        self.device_mut(&device.sku, &device.id)
+            .await
+            .set_last_controlled();
         let (tx, rx) = tokio::sync::oneshot::channel();
 
         let state = self.clone();
@@ -106,30 +455,189 @@ impl State {
             state.poll_after_control(device_id).await
         });
 
-        Ok(Coordinator::new(device, permit, tx))
+        Ok(Coordinator::new(device, permit, holder, tx))
     }
 
     pub async fn resolve_device(&self, label: &str) -> Option<Device> {
-        let devices = self.devices_by_id.lock().await;
+        self.device_snapshot
+            .load()
+            .resolve(label)
+            .map(|device| Device::clone(device))
+    }
+
+    /// Records the handle of a running local effect, returning
+    /// the handle of any effect that was previously running
+    pub async fn set_local_effect(
+        &self,
+        device_id: &str,
+        handle: AbortHandle,
+    ) -> Option<AbortHandle> {
+        self.local_effects
+            .lock()
+            .await
+            .insert(device_id.to_string(), handle)
+    }
+
+    pub async fn take_local_effect(&self, device_id: &str) -> Option<AbortHandle> {
+        self.local_effects.lock().await.remove(device_id)
+    }
+
+    /// Records the handle of a running wake-up ramp, returning
+    /// the handle of any ramp that was previously running
+    pub async fn set_ramp(&self, device_id: &str, handle: AbortHandle) -> Option<AbortHandle> {
+        self.ramps
+            .lock()
+            .await
+            .insert(device_id.to_string(), handle)
+    }
+
+    pub async fn take_ramp(&self, device_id: &str) -> Option<AbortHandle> {
+        self.ramps.lock().await.remove(device_id)
+    }
+
+    /// Records the handle of a running scene preview, returning
+    /// the handle of any preview that was previously running
+    pub async fn set_scene_preview(
+        &self,
+        device_id: &str,
+        handle: AbortHandle,
+    ) -> Option<AbortHandle> {
+        self.scene_previews
+            .lock()
+            .await
+            .insert(device_id.to_string(), handle)
+    }
+
+    pub async fn take_scene_preview(&self, device_id: &str) -> Option<AbortHandle> {
+        self.scene_previews.lock().await.remove(device_id)
+    }
+
+    /// Records the handle of a running off timer countdown, returning
+    /// the handle of any countdown that was previously running
+    pub async fn set_off_timer_task(
+        &self,
+        device_id: &str,
+        handle: AbortHandle,
+    ) -> Option<AbortHandle> {
+        self.off_timers
+            .lock()
+            .await
+            .insert(device_id.to_string(), handle)
+    }
+
+    pub async fn take_off_timer_task(&self, device_id: &str) -> Option<AbortHandle> {
+        self.off_timers.lock().await.remove(device_id)
+    }
+
+    pub async fn set_snapshot(&self, device_id: &str, snapshot: DeviceSnapshot) {
+        self.snapshots
+            .lock()
+            .await
+            .insert(device_id.to_string(), snapshot);
+    }
+
+    pub async fn take_snapshot(&self, device_id: &str) -> Option<DeviceSnapshot> {
+        self.snapshots.lock().await.remove(device_id)
+    }
+
+    pub async fn set_alarms(&self, alarms: Vec<AlarmConfig>) {
+        *self.alarms.lock().await = alarms;
+    }
+
+    pub async fn get_alarms(&self) -> Vec<AlarmConfig> {
+        self.alarms.lock().await.clone()
+    }
+
+    pub async fn set_light_groups(&self, groups: Vec<LightGroupConfig>) {
+        *self.light_groups.lock().await = groups;
+    }
+
+    pub async fn get_light_groups(&self) -> Vec<LightGroupConfig> {
+        self.light_groups.lock().await.clone()
+    }
+
+    pub async fn set_transport_priority(&self, priority: TransportPriorityFile) {
+        *self.transport_priority.lock().await = priority;
+    }
+
+    pub async fn get_transport_priority(&self) -> TransportPriorityFile {
+        self.transport_priority.lock().await.clone()
+    }
+
+    pub async fn set_poll_intervals(&self, intervals: PollIntervalsFile) {
+        *self.poll_intervals.lock().await = intervals;
+    }
+
+    pub async fn get_poll_intervals(&self) -> PollIntervalsFile {
+        self.poll_intervals.lock().await.clone()
+    }
+
+    pub async fn set_scene_filters(&self, filters: SceneFilters) {
+        *self.scene_filters.lock().await = filters;
+    }
+
+    pub async fn get_scene_filters(&self) -> SceneFilters {
+        self.scene_filters.lock().await.clone()
+    }
+
+    pub async fn set_power_models(&self, models: PowerModelFile) {
+        *self.power_models.lock().await = models;
+    }
 
-        if let Some(device) = devices.get(label) {
-            return Some(device.clone());
+    pub async fn get_power_models(&self) -> PowerModelFile {
+        self.power_models.lock().await.clone()
+    }
+
+    pub async fn set_discovery_overrides(&self, overrides: DiscoveryOverrideFile) {
+        *self.discovery_overrides.lock().await = overrides;
+    }
+
+    pub async fn get_discovery_overrides(&self) -> DiscoveryOverrideFile {
+        self.discovery_overrides.lock().await.clone()
+    }
+
+    /// Track whether an entity is currently configured via topic
+    pub async fn record_discovery_topic(&self, topic: &str, present: bool) {
+        let mut topics = self.discovery_topics.lock().await;
+        if present {
+            topics.insert(topic.to_string());
+        } else {
+            topics.remove(topic);
         }
+    }
 
-        for d in devices.values() {
-            if d.name().eq_ignore_ascii_case(label)
-                || d.id.eq_ignore_ascii_case(label)
-                || topic_safe_id(d).eq_ignore_ascii_case(label)
-                || d.ip_addr()
-                    .map(|ip| ip.to_string().eq_ignore_ascii_case(label))
-                    .unwrap_or(false)
-                || d.computed_name().eq_ignore_ascii_case(label)
-            {
-                return Some(d.clone());
-            }
+    /// Returns the known discovery topics, forgetting them so that
+    /// they can be collected afresh
+    pub async fn take_discovery_topics(&self) -> HashSet<String> {
+        std::mem::take(&mut *self.discovery_topics.lock().await)
+    }
+
+    pub async fn get_discovery_topics(&self) -> HashSet<String> {
+        self.discovery_topics.lock().await.clone()
+    }
+
+    /// Record the state that is about to be published for a device.
+    /// Returns false if it is the same as the state that was last
+    /// published, in which case there is no need to publish it,
+    /// unless force is set.
+    pub async fn record_published_state(
+        &self,
+        device_id: &str,
+        messages: &PublishedState,
+        force: bool,
+    ) -> bool {
+        let mut published = self.published_states.lock().await;
+        if !force && published.get(device_id) == Some(messages) {
+            return false;
         }
+        published.insert(device_id.to_string(), messages.clone());
+        true
+    }
 
-        None
+    /// Forget the state that was recorded for a device, because
+    /// it could not be published
+    pub async fn forget_published_state(&self, device_id: &str) {
+        self.published_states.lock().await.remove(device_id);
     }
 
     pub async fn set_hass_client(&self, client: HassClient) {
@@ -148,6 +656,22 @@ impl State {
         self.iot_client.lock().await.clone()
     }
 
+    pub async fn set_iot_health(&self, health: IotHealth) {
+        self.iot_health.lock().await.replace(health);
+    }
+
+    pub async fn get_iot_health(&self) -> Option<IotHealth> {
+        self.iot_health.lock().await.clone()
+    }
+
+    pub async fn set_mqtt_health(&self, health: SubsystemHealth) {
+        self.mqtt_health.lock().await.replace(health);
+    }
+
+    pub async fn get_mqtt_health(&self) -> Option<SubsystemHealth> {
+        self.mqtt_health.lock().await.clone()
+    }
+
     pub async fn set_lan_client(&self, client: LanClient) {
         self.lan_client.lock().await.replace(client);
     }
@@ -160,6 +684,31 @@ impl State {
         self.platform_client.lock().await.replace(client);
     }
 
+    /// Returns the IoT client and device info needed to control
+    /// device via IoT, if that is possible
+    pub(crate) async fn iot_for_device<'a>(
+        &self,
+        device: &'a Device,
+    ) -> Option<(IotClient, &'a UndocDeviceInfo)> {
+        if !device.iot_api_supported() {
+            return None;
+        }
+        let iot = self.get_iot_client().await?;
+        let info = device.undoc_device_info.as_ref()?;
+        Some((iot, info))
+    }
+
+    /// Returns the Platform API client and device info needed to
+    /// control device via the Platform API, if that is possible
+    pub(crate) async fn platform_for_device<'a>(
+        &self,
+        device: &'a Device,
+    ) -> Option<(GoveeApiClient, &'a HttpDeviceInfo)> {
+        let client = self.get_platform_client().await?;
+        let info = device.http_device_info.as_ref()?;
+        Some((client, info))
+    }
+
     pub async fn get_platform_client(&self) -> Option<GoveeApiClient> {
         self.platform_client.lock().await.clone()
     }
@@ -216,9 +765,7 @@ impl State {
                     device_mut.set_http_device_state(http_state);
                     device_mut.set_last_polled();
                 }
-                self.notify_of_state_change(&device.id)
-                    .await
-                    .context("state.notify_of_state_change")?;
+                self.emit_state_changed(&device.id);
                 return Ok(true);
             }
         } else {
@@ -229,7 +776,7 @@ impl State {
         Ok(false)
     }
 
-    async fn poll_lan_api<F: Fn(&LanDeviceStatus) -> bool>(
+    pub(crate) async fn poll_lan_api<F: Fn(&LanDeviceStatus) -> bool>(
         self: &Arc<Self>,
         device: &LanDevice,
         acceptor: F,
@@ -240,26 +787,95 @@ impl State {
                 while Instant::now() <= deadline {
                     let status = client.query_status(device).await?;
                     let accepted = (acceptor)(&status);
-                    self.device_mut(&device.sku, &device.device)
-                        .await
-                        .set_lan_device_status(status);
+                    self.record_lan_device_status(&device.sku, &device.device, status)
+                        .await;
                     if accepted {
                         break;
                     }
                     sleep(Duration::from_millis(100)).await;
                 }
-                self.notify_of_state_change(&device.device).await?;
+                self.emit_state_changed(&device.device);
                 Ok(())
             }
             None => anyhow::bail!("no lan client"),
         }
     }
 
+    /// Record a status that was obtained via the LAN API, along with
+    /// the segment colors, scene and gradient state that some firmwares
+    /// include with it. Returns true if the status changed.
+    pub async fn record_lan_device_status(
+        &self,
+        sku: &str,
+        id: &str,
+        status: LanDeviceStatus,
+    ) -> bool {
+        let packets = status.decode_op(sku);
+        let (changed, scene_code) = {
+            let mut device = self.device_mut(sku, id).await;
+            let changed = device.set_lan_device_status(status);
+            (changed, device.apply_status_op(&packets))
+        };
+        if changed {
+            if let Some(code) = scene_code {
+                self.device_reported_scene_code(sku, id, code).await;
+            }
+        }
+        changed
+    }
+
+    /// Record a status that was received via the LAN API without
+    /// our having polled for it, such as the status that some devices
+    /// broadcast when they are controlled via the Govee app, and let
+    /// Home Assistant know if it changed.
+    pub async fn handle_lan_status_update(
+        self: &Arc<Self>,
+        ip: std::net::IpAddr,
+        status: LanDeviceStatus,
+    ) -> anyhow::Result<()> {
+        let Some(device) = self.devices().await.into_iter().find(|d| {
+            d.lan_device
+                .as_ref()
+                .map(|lan| lan.ip == ip)
+                .unwrap_or(false)
+        }) else {
+            log::trace!("LAN status from unknown device {ip}: {status:?}");
+            return Ok(());
+        };
+
+        let changed = self
+            .record_lan_device_status(&device.sku, &device.id, status)
+            .await;
+        if changed {
+            log::debug!("LAN status update for {device}");
+            self.emit_state_changed(&device.id);
+        }
+        Ok(())
+    }
+
     pub async fn device_control<V: Into<JsonValue>>(
         self: &Arc<Self>,
         device: &Device,
         capability: &DeviceCapability,
         value: V,
+    ) -> anyhow::Result<()> {
+        let value: JsonValue = value.into();
+        // Reject out of range values before they are queued
+        capability.validate_value(&value)?;
+        self.run_device_command(
+            device,
+            "control",
+            json!({"instance": capability.instance, "value": value}),
+            || self.device_control_impl(device, capability, value.clone()),
+        )
+        .await
+    }
+
+    async fn device_control_impl<V: Into<JsonValue>>(
+        self: &Arc<Self>,
+        device: &Device,
+        capability: &DeviceCapability,
+        value: V,
     ) -> anyhow::Result<()> {
         let value: JsonValue = value.into();
         if let Some(client) = self.get_platform_client().await {
@@ -277,6 +893,17 @@ impl State {
         self: &Arc<Self>,
         device: &Device,
         on: bool,
+    ) -> anyhow::Result<()> {
+        self.run_device_command(device, "light_power", json!({"on": on}), || {
+            self.device_light_power_on_impl(device, on)
+        })
+        .await
+    }
+
+    async fn device_light_power_on_impl(
+        self: &Arc<Self>,
+        device: &Device,
+        on: bool,
     ) -> anyhow::Result<()> {
         if self
             .try_humidifier_set_nightlight(device, |p| p.on = on)
@@ -294,32 +921,15 @@ impl State {
                 )
             })?;
 
-        if let Some(lan_dev) = &device.lan_device {
-            log::info!("Using LAN API to set {device} light power state");
-            lan_dev.send_turn(on).await?;
-            self.poll_lan_api(lan_dev, |status| status.on == on).await?;
-            return Ok(());
-        }
-
-        if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
-                if let Some(info) = &device.undoc_device_info {
-                    log::info!("Using IoT API to set {device} light power state");
-                    iot.set_power_state(&info.entry, on).await?;
-                    return Ok(());
-                }
-            }
-        }
-
-        if let Some(client) = self.get_platform_client().await {
-            if let Some(info) = &device.http_device_info {
-                log::info!("Using Platform API to set {device} light {instance_name} state");
-                client.set_toggle_state(info, instance_name, on).await?;
-                return Ok(());
-            }
-        }
-
-        anyhow::bail!("Unable to control light power state for {device}");
+        self.send_transport_command(
+            device,
+            TransportCommand::LightPower {
+                on,
+                instance: instance_name.to_string(),
+            },
+        )
+        .await?;
+        Ok(())
     }
 
     pub async fn device_power_on(
@@ -327,38 +937,37 @@ impl State {
         device: &Device,
         on: bool,
     ) -> anyhow::Result<()> {
-        if let Some(lan_dev) = &device.lan_device {
-            log::info!("Using LAN API to set {device} power state");
-            lan_dev.send_turn(on).await?;
-            self.poll_lan_api(lan_dev, |status| status.on == on).await?;
-            return Ok(());
-        }
-
-        if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
-                if let Some(info) = &device.undoc_device_info {
-                    log::info!("Using IoT API to set {device} power state");
-                    iot.set_power_state(&info.entry, on).await?;
-                    return Ok(());
-                }
-            }
-        }
+        self.run_device_command(device, "power", json!({"on": on}), || {
+            self.device_power_on_impl(device, on)
+        })
+        .await
+    }
 
-        if let Some(client) = self.get_platform_client().await {
-            if let Some(info) = &device.http_device_info {
-                log::info!("Using Platform API to set {device} power state");
-                client.set_power_state(info, on).await?;
-                return Ok(());
-            }
-        }
-
-        anyhow::bail!("Unable to control power state for {device}");
+    async fn device_power_on_impl(
+        self: &Arc<Self>,
+        device: &Device,
+        on: bool,
+    ) -> anyhow::Result<()> {
+        self.send_transport_command(device, TransportCommand::Power(on))
+            .await?;
+        Ok(())
     }
 
     pub async fn device_set_brightness(
         self: &Arc<Self>,
         device: &Device,
         percent: u8,
+    ) -> anyhow::Result<()> {
+        self.run_device_command(device, "brightness", json!({"brightness": percent}), || {
+            self.device_set_brightness_impl(device, percent)
+        })
+        .await
+    }
+
+    async fn device_set_brightness_impl(
+        self: &Arc<Self>,
+        device: &Device,
+        percent: u8,
     ) -> anyhow::Result<()> {
         if self
             .try_humidifier_set_nightlight(device, |p| {
@@ -370,19 +979,95 @@ impl State {
             return Ok(());
         }
 
-        if let Some(lan_dev) = &device.lan_device {
-            log::info!("Using LAN API to set {device} brightness");
-            lan_dev.send_brightness(percent).await?;
-            self.poll_lan_api(lan_dev, |status| status.brightness == percent)
-                .await?;
-            return Ok(());
+        self.send_transport_command(device, TransportCommand::Brightness(percent))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn device_set_color_temperature(
+        self: &Arc<Self>,
+        device: &Device,
+        kelvin: u32,
+    ) -> anyhow::Result<()> {
+        let clamped = device.clamp_color_temperature(kelvin);
+        if clamped != kelvin {
+            log::debug!("{device}: {kelvin}K is out of range, using {clamped}K");
         }
+        let kelvin = clamped;
+        self.run_device_command(
+            device,
+            "color_temperature",
+            json!({"kelvin": kelvin}),
+            || self.device_set_color_temperature_impl(device, kelvin),
+        )
+        .await
+    }
 
-        if device.iot_api_supported() {
+    async fn device_set_color_temperature_impl(
+        self: &Arc<Self>,
+        device: &Device,
+        kelvin: u32,
+    ) -> anyhow::Result<()> {
+        self.send_transport_command(device, TransportCommand::ColorTemperature(kelvin))
+            .await?;
+        Ok(())
+    }
+
+    async fn try_humidifier_set_nightlight<F: Fn(&mut SetHumidifierNightlightParams)>(
+        self: &Arc<Self>,
+        device: &Device,
+        apply: F,
+    ) -> anyhow::Result<bool> {
+        let mut params: SetHumidifierNightlightParams =
+            device.nightlight_state.clone().unwrap_or_default().into();
+        (apply)(&mut params);
+
+        if let Ok(command) = Base64HexBytes::encode_for_sku(&device.sku, &params) {
             if let Some(iot) = self.get_iot_client().await {
                 if let Some(info) = &device.undoc_device_info {
-                    log::info!("Using IoT API to set {device} brightness");
-                    iot.set_brightness(&info.entry, percent).await?;
+                    log::info!("Using IoT API to set {device} color (via humidifier nightlight)");
+                    self.iot_send_real(&iot, &info.entry, command.base64())
+                        .await?;
+                    return Ok(true);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    pub async fn humidifier_set_parameter(
+        self: &Arc<Self>,
+        device: &Device,
+        work_mode: i64,
+        value: i64,
+    ) -> anyhow::Result<()> {
+        self.run_device_command(
+            device,
+            "humidifier_parameter",
+            json!({"work_mode": work_mode, "value": value}),
+            || self.humidifier_set_parameter_impl(device, work_mode, value),
+        )
+        .await
+    }
+
+    async fn humidifier_set_parameter_impl(
+        self: &Arc<Self>,
+        device: &Device,
+        work_mode: i64,
+        value: i64,
+    ) -> anyhow::Result<()> {
+        if let Ok(command) = Base64HexBytes::encode_for_sku(
+            &device.sku,
+            &SetHumidifierMode {
+                mode: work_mode as u8,
+                param: value as u8,
+            },
+        ) {
+            if let Some(iot) = self.get_iot_client().await {
+                if let Some(info) = &device.undoc_device_info {
+                    self.iot_send_real(&iot, &info.entry, command.base64())
+                        .await?;
                     return Ok(());
                 }
             }
@@ -390,91 +1075,417 @@ impl State {
 
         if let Some(client) = self.get_platform_client().await {
             if let Some(info) = &device.http_device_info {
-                log::info!("Using Platform API to set {device} brightness");
-                client.set_brightness(info, percent).await?;
+                client.set_work_mode(info, work_mode, value).await?;
                 return Ok(());
             }
         }
-        anyhow::bail!("Unable to control brightness for {device}");
+        anyhow::bail!("Unable to control humidifier parameter work_mode={work_mode} for {device}");
     }
 
-    pub async fn device_set_color_temperature(
+    /// Set the target humidity of a humidifier. auto_mode is the
+    /// work mode whose parameter is the target humidity.
+    pub async fn humidifier_set_target_humidity(
         self: &Arc<Self>,
         device: &Device,
-        kelvin: u32,
+        auto_mode: Option<i64>,
+        percent: u8,
     ) -> anyhow::Result<()> {
-        if let Some(lan_dev) = &device.lan_device {
-            log::info!("Using LAN API to set {device} color temperature");
-            lan_dev.send_color_temperature_kelvin(kelvin).await?;
-            self.poll_lan_api(lan_dev, |status| status.color_temperature_kelvin == kelvin)
-                .await?;
-            self.device_mut(&device.sku, &device.id)
-                .await
-                .set_active_scene(None);
-            return Ok(());
+        let use_iot = device.pollable_via_iot() && self.get_iot_client().await.is_some();
+
+        if !use_iot {
+            if let Some(info) = &device.http_device_info {
+                if let Some(cap) = info.capability_by_instance("humidity") {
+                    self.device_control(device, cap, percent).await?;
+
+                    // We're running in optimistic mode; stash
+                    // the last set value so that we can report it
+                    // to hass
+                    self.device_mut(&device.sku, &device.id)
+                        .await
+                        .set_target_humidity(percent);
+
+                    // For the H7160 at least, setting the humidity
+                    // will put the device into auto mode and turn
+                    // it on, however, we don't know that the device
+                    // is actually turned on.
+                    //
+                    // This is handled by the device_was_controlled
+                    // stuff; it will cause us to poll the device
+                    // after a short delay, and that should fix up
+                    // the reported device state.
+                    return Ok(());
+                }
+            }
         }
 
-        if device.iot_api_supported() {
+        let auto_mode = auto_mode
+            .ok_or_else(|| anyhow::anyhow!("{device} has no mode with a target humidity"))?;
+
+        self.run_device_command(
+            device,
+            "target_humidity",
+            json!({"auto_mode": auto_mode, "percent": percent}),
+            || self.humidifier_set_target_humidity_impl(device, auto_mode, percent),
+        )
+        .await
+    }
+
+    async fn humidifier_set_target_humidity_impl(
+        self: &Arc<Self>,
+        device: &Device,
+        auto_mode: i64,
+        percent: u8,
+    ) -> anyhow::Result<()> {
+        if let Ok(command) = Base64HexBytes::encode_for_sku(
+            &device.sku,
+            &SetHumidifierMode {
+                mode: auto_mode as u8,
+                param: TargetHumidity::from_percent(percent).into_inner(),
+            },
+        ) {
             if let Some(iot) = self.get_iot_client().await {
                 if let Some(info) = &device.undoc_device_info {
-                    log::info!("Using IoT API to set {device} color temperature");
-                    iot.set_color_temperature(&info.entry, kelvin).await?;
+                    log::info!("Using IoT API to set {device} target humidity");
+                    self.iot_send_real(&iot, &info.entry, command.base64())
+                        .await?;
                     return Ok(());
                 }
             }
         }
 
+        // The Platform API takes the plain percentage as the
+        // parameter of the auto mode
         if let Some(client) = self.get_platform_client().await {
             if let Some(info) = &device.http_device_info {
-                log::info!("Using Platform API to set {device} color temperature");
-                client.set_color_temperature(info, kelvin).await?;
-                self.device_mut(&device.sku, &device.id)
-                    .await
-                    .set_active_scene(None);
+                client
+                    .set_work_mode(info, auto_mode, percent.into())
+                    .await?;
                 return Ok(());
             }
         }
-        anyhow::bail!("Unable to control color temperature for {device}");
+        anyhow::bail!("Unable to set target humidity {percent}% for {device}");
     }
 
-    async fn try_humidifier_set_nightlight<F: Fn(&mut SetHumidifierNightlightParams)>(
+    pub async fn purifier_set_parameter(
         self: &Arc<Self>,
         device: &Device,
-        apply: F,
-    ) -> anyhow::Result<bool> {
-        let mut params: SetHumidifierNightlightParams =
-            device.nightlight_state.clone().unwrap_or_default().into();
-        (apply)(&mut params);
+        work_mode: i64,
+        value: i64,
+    ) -> anyhow::Result<()> {
+        self.run_device_command(
+            device,
+            "purifier_parameter",
+            json!({"work_mode": work_mode, "value": value}),
+            || self.purifier_set_parameter_impl(device, work_mode, value),
+        )
+        .await
+    }
 
-        if let Ok(command) = Base64HexBytes::encode_for_sku(&device.sku, &params) {
+    async fn purifier_set_parameter_impl(
+        self: &Arc<Self>,
+        device: &Device,
+        work_mode: i64,
+        value: i64,
+    ) -> anyhow::Result<()> {
+        let packet = SetPurifierMode {
+            mode: work_mode as u8,
+            param: value as u8,
+        };
+        self.work_mode_set_parameter_impl(device, &packet, work_mode, value)
+            .await
+    }
+
+    pub async fn cover_set_parameter(
+        self: &Arc<Self>,
+        device: &Device,
+        work_mode: i64,
+        value: i64,
+    ) -> anyhow::Result<()> {
+        self.run_device_command(
+            device,
+            "cover_parameter",
+            json!({"work_mode": work_mode, "value": value}),
+            || self.cover_set_parameter_impl(device, work_mode, value),
+        )
+        .await
+    }
+
+    /// Curtain motors are only controllable via the Platform API
+    async fn cover_set_parameter_impl(
+        self: &Arc<Self>,
+        device: &Device,
+        work_mode: i64,
+        value: i64,
+    ) -> anyhow::Result<()> {
+        let client = self
+            .get_platform_client()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Platform API client is not available"))?;
+        let info = device
+            .http_device_info
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No platform state available for {device}"))?;
+
+        log::info!("Using Platform API to set {device} work_mode={work_mode} value={value}");
+        client.set_work_mode(info, work_mode, value).await?;
+
+        self.device_mut(&device.sku, &device.id)
+            .await
+            .set_work_mode_state(work_mode, value);
+        self.emit_state_changed(&device.id);
+        Ok(())
+    }
+
+    pub async fn kettle_set_parameter(
+        self: &Arc<Self>,
+        device: &Device,
+        work_mode: i64,
+        value: i64,
+    ) -> anyhow::Result<()> {
+        self.run_device_command(
+            device,
+            "kettle_parameter",
+            json!({"work_mode": work_mode, "value": value}),
+            || self.kettle_set_parameter_impl(device, work_mode, value),
+        )
+        .await
+    }
+
+    async fn kettle_set_parameter_impl(
+        self: &Arc<Self>,
+        device: &Device,
+        work_mode: i64,
+        value: i64,
+    ) -> anyhow::Result<()> {
+        let packet = SetKettleMode {
+            mode: work_mode as u8,
+            param: value as u8,
+        };
+        self.work_mode_set_parameter_impl(device, &packet, work_mode, value)
+            .await
+    }
+
+    pub async fn heater_set_parameter(
+        self: &Arc<Self>,
+        device: &Device,
+        work_mode: i64,
+        value: i64,
+    ) -> anyhow::Result<()> {
+        self.run_device_command(
+            device,
+            "heater_parameter",
+            json!({"work_mode": work_mode, "value": value}),
+            || self.heater_set_parameter_impl(device, work_mode, value),
+        )
+        .await
+    }
+
+    async fn heater_set_parameter_impl(
+        self: &Arc<Self>,
+        device: &Device,
+        work_mode: i64,
+        value: i64,
+    ) -> anyhow::Result<()> {
+        let packet = SetHeaterMode {
+            mode: work_mode as u8,
+            param: value as u8,
+        };
+        self.work_mode_set_parameter_impl(device, &packet, work_mode, value)
+            .await
+    }
+
+    pub async fn device_set_toggle(
+        self: &Arc<Self>,
+        device: &Device,
+        toggle: DeviceToggle,
+        on: bool,
+    ) -> anyhow::Result<()> {
+        self.run_device_command(
+            device,
+            "toggle",
+            json!({"instance": toggle.instance(), "on": on}),
+            || self.device_set_toggle_impl(device, toggle, on),
+        )
+        .await
+    }
+
+    async fn device_set_toggle_impl(
+        self: &Arc<Self>,
+        device: &Device,
+        toggle: DeviceToggle,
+        on: bool,
+    ) -> anyhow::Result<()> {
+        let command = match toggle {
+            DeviceToggle::Oscillation => {
+                Base64HexBytes::encode_for_sku(&device.sku, &SetOscillation { on })
+            }
+            DeviceToggle::ChildLock => {
+                Base64HexBytes::encode_for_sku(&device.sku, &SetChildLock { on })
+            }
+            DeviceToggle::Gradient => {
+                Base64HexBytes::encode_for_sku(&device.sku, &SetGradient { on })
+            }
+        };
+        if let Ok(command) = command {
+            if let Some(lan_dev) = &device.lan_device {
+                log::info!("Using LAN API to set {device} {toggle:?} to {on}");
+                lan_dev.send_real(command.base64()).await?;
+                self.device_mut(&device.sku, &device.id)
+                    .await
+                    .set_toggle_state(toggle, on);
+                return Ok(());
+            }
             if let Some(iot) = self.get_iot_client().await {
                 if let Some(info) = &device.undoc_device_info {
-                    log::info!("Using IoT API to set {device} color (via humidifier nightlight)");
-                    iot.send_real(&info.entry, command.base64()).await?;
-                    return Ok(true);
+                    log::info!("Using IoT API to set {device} {toggle:?} to {on}");
+                    self.iot_send_real(&iot, &info.entry, command.base64())
+                        .await?;
+                    return Ok(());
                 }
             }
         }
 
-        Ok(false)
+        if let Some(client) = self.get_platform_client().await {
+            if let Some(info) = &device.http_device_info {
+                if info.capability_by_instance(toggle.instance()).is_some() {
+                    log::info!("Using Platform API to set {device} {toggle:?} to {on}");
+                    client.set_toggle_state(info, toggle.instance(), on).await?;
+                    return Ok(());
+                }
+            }
+        }
+        anyhow::bail!("Unable to set {toggle:?} for {device}");
     }
 
-    pub async fn humidifier_set_parameter(
+    /// Switch one of the outlets of a multi-outlet power strip,
+    /// leaving the others alone
+    pub async fn device_set_outlet(
+        self: &Arc<Self>,
+        device: &Device,
+        instance: &str,
+        on: bool,
+    ) -> anyhow::Result<()> {
+        self.run_device_command(
+            device,
+            "outlet",
+            json!({"instance": instance, "on": on}),
+            || self.device_set_outlet_impl(device, instance, on),
+        )
+        .await
+    }
+
+    async fn device_set_outlet_impl(
+        self: &Arc<Self>,
+        device: &Device,
+        instance: &str,
+        on: bool,
+    ) -> anyhow::Result<()> {
+        let client = self
+            .get_platform_client()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("Platform API client is not available"))?;
+        let info = device
+            .http_device_info
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("No platform state available for {device}"))?;
+        anyhow::ensure!(
+            info.capability_by_instance(instance).is_some(),
+            "{device} has no outlet {instance}"
+        );
+
+        log::info!("Using Platform API to set {device} {instance} to {on}");
+        client.set_toggle_state(info, instance, on).await?;
+
+        self.device_mut(&device.sku, &device.id)
+            .await
+            .set_outlet_state(instance, on);
+        self.emit_state_changed(&device.id);
+        Ok(())
+    }
+
+    /// Set the mist level used by the manual mode of a humidifier,
+    /// or the mist intensity of an aroma diffuser
+    pub async fn device_set_mist_level(
         self: &Arc<Self>,
         device: &Device,
+        level: u8,
+    ) -> anyhow::Result<()> {
+        self.run_device_command(device, "mist_level", json!({"level": level}), || {
+            self.device_set_mist_level_impl(device, level)
+        })
+        .await
+    }
+
+    async fn device_set_mist_level_impl(
+        self: &Arc<Self>,
+        device: &Device,
+        level: u8,
+    ) -> anyhow::Result<()> {
+        let work_mode = MIST_LEVEL_WORK_MODE as i64;
+        if DIFFUSER_SKUS.contains(&device.sku.as_str()) {
+            let packet = SetMistLevel { level };
+            self.work_mode_set_parameter_impl(device, &packet, work_mode, level.into())
+                .await
+        } else {
+            let packet = SetHumidifierMode {
+                mode: MIST_LEVEL_WORK_MODE,
+                param: level,
+            };
+            self.work_mode_set_parameter_impl(device, &packet, work_mode, level.into())
+                .await
+        }
+    }
+
+    pub async fn ice_maker_set_making(
+        self: &Arc<Self>,
+        device: &Device,
+        on: bool,
+    ) -> anyhow::Result<()> {
+        self.run_device_command(device, "ice_making", json!({"on": on}), || {
+            self.ice_maker_set_making_impl(device, on)
+        })
+        .await
+    }
+
+    async fn ice_maker_set_making_impl(
+        self: &Arc<Self>,
+        device: &Device,
+        on: bool,
+    ) -> anyhow::Result<()> {
+        if device.iot_api_supported() {
+            if let Ok(command) =
+                Base64HexBytes::encode_for_sku(&device.sku, &SetIceMakerMaking { on })
+            {
+                if let Some(iot) = self.get_iot_client().await {
+                    if let Some(info) = &device.undoc_device_info {
+                        log::info!("Using IoT API to set {device} ice making {on}");
+                        self.iot_send_real(&iot, &info.entry, command.base64())
+                            .await?;
+                        return Ok(());
+                    }
+                }
+            }
+        }
+
+        // The ice maker begins making ice as soon as it is powered on
+        self.device_power_on_impl(device, on).await
+    }
+
+    /// Select a work mode, preferring to send `packet` via IoT, falling
+    /// back to the equivalent Platform API work mode request
+    async fn work_mode_set_parameter_impl<P: 'static>(
+        self: &Arc<Self>,
+        device: &Device,
+        packet: &P,
         work_mode: i64,
         value: i64,
     ) -> anyhow::Result<()> {
-        if let Ok(command) = Base64HexBytes::encode_for_sku(
-            &device.sku,
-            &SetHumidifierMode {
-                mode: work_mode as u8,
-                param: value as u8,
-            },
-        ) {
+        if let Ok(command) = Base64HexBytes::encode_for_sku(&device.sku, packet) {
             if let Some(iot) = self.get_iot_client().await {
                 if let Some(info) = &device.undoc_device_info {
-                    iot.send_real(&info.entry, command.base64()).await?;
+                    log::info!("Using IoT API to set {device} work mode");
+                    self.iot_send_real(&iot, &info.entry, command.base64())
+                        .await?;
                     return Ok(());
                 }
             }
@@ -486,7 +1497,7 @@ impl State {
                 return Ok(());
             }
         }
-        anyhow::bail!("Unable to control humidifier parameter work_mode={work_mode} for {device}");
+        anyhow::bail!("Unable to set work_mode={work_mode} for {device}");
     }
 
     pub async fn device_set_color_rgb(
@@ -495,6 +1506,47 @@ impl State {
         r: u8,
         g: u8,
         b: u8,
+    ) -> anyhow::Result<()> {
+        self.run_device_command(device, "color_rgb", json!({"r": r, "g": g, "b": b}), || {
+            self.device_set_color_rgb_impl(device, r, g, b)
+        })
+        .await
+    }
+
+    /// Set the color from hue (0-360) and saturation (0-100).
+    /// None of the control transports accept these natively, so
+    /// the color is converted to rgb at full value; brightness
+    /// is controlled separately.
+    pub async fn device_set_color_hs(
+        self: &Arc<Self>,
+        device: &Device,
+        hue: f32,
+        saturation: f32,
+    ) -> anyhow::Result<()> {
+        let DeviceColor { r, g, b } = DeviceColor::from_hs(hue, saturation);
+        log::debug!("{device}: hs {hue},{saturation} is rgb {r},{g},{b}");
+        self.device_set_color_rgb(device, r, g, b).await
+    }
+
+    /// Set the color from CIE 1931 xy chromaticity coordinates,
+    /// converting to rgb in the same way as device_set_color_hs
+    pub async fn device_set_color_xy(
+        self: &Arc<Self>,
+        device: &Device,
+        x: f32,
+        y: f32,
+    ) -> anyhow::Result<()> {
+        let DeviceColor { r, g, b } = DeviceColor::from_xy(x, y);
+        log::debug!("{device}: xy {x},{y} is rgb {r},{g},{b}");
+        self.device_set_color_rgb(device, r, g, b).await
+    }
+
+    async fn device_set_color_rgb_impl(
+        self: &Arc<Self>,
+        device: &Device,
+        r: u8,
+        g: u8,
+        b: u8,
     ) -> anyhow::Result<()> {
         if self
             .try_humidifier_set_nightlight(device, |p| {
@@ -508,45 +1560,18 @@ impl State {
             return Ok(());
         }
 
-        if let Some(lan_dev) = &device.lan_device {
-            let color = crate::lan_api::DeviceColor { r, g, b };
-            log::info!("Using LAN API to set {device} color");
-            lan_dev.send_color_rgb(color).await?;
-            self.poll_lan_api(lan_dev, |status| status.color == color)
-                .await?;
-            self.device_mut(&device.sku, &device.id)
-                .await
-                .set_active_scene(None);
-            return Ok(());
-        }
-
-        if device.iot_api_supported() {
-            if let Some(iot) = self.get_iot_client().await {
-                if let Some(info) = &device.undoc_device_info {
-                    log::info!("Using IoT API to set {device} color");
-                    iot.set_color_rgb(&info.entry, r, g, b).await?;
-                    return Ok(());
-                }
-            }
-        }
-
-        if let Some(client) = self.get_platform_client().await {
-            if let Some(info) = &device.http_device_info {
-                log::info!("Using Platform API to set {device} color");
-                client.set_color_rgb(info, r, g, b).await?;
-                self.device_mut(&device.sku, &device.id)
-                    .await
-                    .set_active_scene(None);
-                return Ok(());
-            }
-        }
-        anyhow::bail!("Unable to control color for {device}");
+        self.send_transport_command(device, TransportCommand::ColorRgb(DeviceColor { r, g, b }))
+            .await?;
+        Ok(())
     }
 
     pub async fn poll_after_control(self: &Arc<Self>, id: String) {
         let Some(device) = self.device_by_id(&id).await else {
             return;
         };
+        if self.is_dry_run().await {
+            return;
+        }
 
         let iot_available = self.get_iot_client().await.is_some();
 
@@ -557,7 +1582,8 @@ impl State {
             return;
         }
 
-        sleep(Duration::from_secs(5)).await;
+        let delay = self.poll_intervals(&device).await.after_control;
+        sleep(delay).await;
 
         log::info!("Polling {device} to get latest state after control");
         if let Err(err) = self.poll_platform_api(&device).await {
@@ -566,6 +1592,19 @@ impl State {
     }
 
     pub async fn device_list_scenes(&self, device: &Device) -> anyhow::Result<Vec<String>> {
+        let mut scenes = self.device_list_device_scenes(device).await?;
+        if self.device_supports_local_effects(device).await {
+            scenes.extend(
+                LocalEffect::ALL
+                    .iter()
+                    .map(|effect| effect.name().to_string()),
+            );
+        }
+        let scenes = self.filter_scenes(device, scenes).await;
+        Ok(self.categorize_scene_names(device, scenes).await)
+    }
+
+    async fn device_list_device_scenes(&self, device: &Device) -> anyhow::Result<Vec<String>> {
         if let Some(client) = self.get_platform_client().await {
             if let Some(info) = &device.http_device_info {
                 let platform_scenes = client.list_scene_names(info).await?;
@@ -574,9 +1613,12 @@ impl State {
                 }
             }
         }
-        match get_parsed_scenes_for_sku(&device.sku).await { // Use imported function directly
+        match scenes_for_sku(&device.sku).await {
             Ok(parsed_scenes) => {
-                let names: Vec<String> = parsed_scenes.into_iter().map(|s| s.display_name).collect();
+                let names: Vec<String> = parsed_scenes
+                    .iter()
+                    .map(|s| s.display_name.to_string())
+                    .collect();
                 if !names.is_empty() {
                     return Ok(sort_and_dedup_scenes(names));
                 }
@@ -599,6 +1641,37 @@ impl State {
         instance_name: &str,
         target: TemperatureValue,
     ) -> anyhow::Result<()> {
+        self.run_device_command(
+            device,
+            "target_temperature",
+            json!({"instance": instance_name, "target": target.to_string()}),
+            || self.device_set_target_temperature_impl(device, instance_name, target),
+        )
+        .await
+    }
+
+    async fn device_set_target_temperature_impl(
+        self: &Arc<Self>,
+        device: &Device,
+        instance_name: &str,
+        target: TemperatureValue,
+    ) -> anyhow::Result<()> {
+        // Heaters can have their thermostat set via IoT
+        let packet = SetHeaterTargetTemperature {
+            auto_stop: false,
+            target: HeaterTemperature::from_celsius(target.as_celsius()),
+        };
+        if let Ok(command) = Base64HexBytes::encode_for_sku(&device.sku, &packet) {
+            if let Some(iot) = self.get_iot_client().await {
+                if let Some(info) = &device.undoc_device_info {
+                    log::info!("Using IoT API to set {device} target temperature to {target}");
+                    self.iot_send_real(&iot, &info.entry, command.base64())
+                        .await?;
+                    return Ok(());
+                }
+            }
+        }
+
         if let Some(client) = self.get_platform_client().await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to set {device} target temperature to {target}");
@@ -617,116 +1690,413 @@ impl State {
         device: &Device,
         scene_name_to_set: &str,
     ) -> anyhow::Result<()> {
-        let avoid_platform_api = device.avoid_platform_api();
+        self.run_device_command(device, "scene", json!({"scene": scene_name_to_set}), || {
+            self.device_set_scene_impl(device, scene_name_to_set)
+        })
+        .await
+    }
 
-        if !avoid_platform_api {
-            if let Some(client) = self.get_platform_client().await {
-                if let Some(info) = &device.http_device_info {
-                    log::info!("Using Platform API to set {device} to scene {scene_name_to_set}");
-                    match client.set_scene_by_name(info, scene_name_to_set).await {
-                        Ok(_) => {
-                            self.device_mut(&device.sku, &device.id)
-                                .await
-                                .set_active_scene(Some(scene_name_to_set));
-                            return Ok(());
-                        }
-                        Err(e) => {
-                            log::warn!("Platform API failed to set scene {scene_name_to_set} for {device}: {e}. Trying other methods.");
-                        }
-                    }
-                }
-            }
-        }
+    /// Activate a scene by its numeric code, and optionally its
+    /// param id, bypassing name resolution
+    pub async fn device_set_scene_code(
+        self: &Arc<Self>,
+        device: &Device,
+        code: u16,
+        param_id: Option<u32>,
+    ) -> anyhow::Result<()> {
+        self.run_device_command(
+            device,
+            "scene_code",
+            json!({"code": code, "param_id": param_id}),
+            || self.device_set_scene_code_impl(device, code, param_id),
+        )
+        .await
+    }
+
+    async fn device_set_scene_code_impl(
+        &self,
+        device: &Device,
+        code: u16,
+        param_id: Option<u32>,
+    ) -> anyhow::Result<()> {
+        self.device_activate_scene(device, &SceneSelector::Code { code, param_id })
+            .await
+    }
+
+    /// Send the packets that activate the selected scene via LAN,
+    /// or failing that, IoT, bypassing the Platform API and its
+    /// scene names
+    pub async fn device_activate_scene(
+        &self,
+        device: &Device,
+        selector: &SceneSelector,
+    ) -> anyhow::Result<()> {
+        let resolved = resolve_scene(&device.sku, selector).await?;
 
         if let Some(lan_dev) = &device.lan_device {
-            log::info!("Using LAN API to set {device} to scene {scene_name_to_set}");
-            match lan_dev.set_scene_by_name(scene_name_to_set).await {
-                Ok(_) => {
-                    self.device_mut(&device.sku, &device.id)
-                        .await
-                        .set_active_scene(Some(scene_name_to_set));
-                    return Ok(());
-                }
-                Err(e) => {
-                    log::warn!("LAN API failed to set scene {scene_name_to_set} for {device}: {e}. Trying other methods.");
-                }
+            log::info!("Using LAN API to set {device} to scene {selector:?}");
+            lan_dev.send_real(resolved.commands).await?;
+        } else if let (Some(iot), Some(info)) =
+            (self.get_iot_client().await, &device.undoc_device_info)
+        {
+            log::info!("Using IoT API to set {device} to scene {selector:?}");
+            self.iot_send_real(&iot, &info.entry, resolved.commands)
+                .await?;
+        } else {
+            anyhow::bail!(
+                "Unable to set a scene for {device}: neither the LAN nor IoT API is available"
+            );
+        }
+
+        self.device_mut(&device.sku, &device.id)
+            .await
+            .set_active_scene(resolved.name.as_deref());
+        Ok(())
+    }
+
+    /// Resolve the user supplied name to the name of one of the
+    /// scenes of the device, tolerating differences in case and
+    /// punctuation
+    async fn resolve_device_scene_name(
+        &self,
+        device: &Device,
+        name: &str,
+    ) -> anyhow::Result<String> {
+        let name = &self.uncategorize_scene_name(device, name).await;
+        let mut scenes = match self.device_list_device_scenes(device).await {
+            Ok(scenes) => scenes,
+            Err(err) => {
+                log::warn!("Unable to list scenes for {device}: {err:#}");
+                vec![]
             }
+        };
+        if scenes.is_empty() {
+            return Ok(name.to_string());
         }
+        scenes.extend(
+            LocalEffect::ALL
+                .iter()
+                .map(|effect| effect.name().to_string()),
+        );
+        resolve_scene_name(&scenes, name, false)
+    }
 
-        log::info!("Attempting to set scene '{scene_name_to_set}' for {device} via BLE/IoT.");
-        let all_parsed_scenes = get_parsed_scenes_for_sku(&device.sku).await // Use imported function directly
-            .with_context(|| format!("Failed to get parsed scenes for SKU {} to set scene via BLE", device.sku))?;
+    /// Called when a device reports that the scene with the specified
+    /// code is active, which happens when the scene was activated
+    /// outside of govee2mqtt, such as via the Govee app
+    pub async fn device_reported_scene_code(&self, sku: &str, id: &str, code: u16) {
+        let names: Vec<String> = match scenes_for_sku(sku).await {
+            Ok(scenes) => scenes
+                .iter()
+                .filter(|scene| scene.scene_code == code)
+                .map(|scene| scene.display_name.to_string())
+                .collect(),
+            Err(err) => {
+                log::warn!("Unable to resolve scene code {code} for {sku} {id}: {err:#}");
+                return;
+            }
+        };
+        let Some(name) = names.first() else {
+            log::debug!("{sku} {id} reported unknown scene code {code}");
+            return;
+        };
 
-        if let Some(target_scene) = all_parsed_scenes.into_iter().find(|ps: &ParsedScene| ps.display_name == scene_name_to_set) { // ParsedScene type from import
-            if let Some(iot) = self.get_iot_client().await {
-                if let Some(info) = &device.undoc_device_info {
-                    if let Some(ref override_commands_b64) = target_scene.override_cmd_b64 {
-                        log::info!("Using override BLE commands for scene: {}", target_scene.display_name);
-                        iot.send_real(&info.entry, override_commands_b64.clone()).await?;
-                        self.device_mut(&device.sku, &device.id)
-                            .await
-                            .set_active_scene(Some(scene_name_to_set));
-                        return Ok(());
-                    } else if !target_scene.api_scence_param.is_empty() {
-                        log::info!("Encoding API BLE commands for scene: {}", target_scene.display_name);
-                        let scene_encoder = SetSceneCode::new(
-                            target_scene.scene_code,
-                            target_scene.api_scence_param.clone(),
-                            device.sku.to_string(),
-                        );
-                        match scene_encoder.encode() {
-                            Ok(encoded_byte_stream) => {
-                                let commands_b64: Vec<String> = encoded_byte_stream.chunks(20)
-                                    .map(|chunk| data_encoding::BASE64.encode(chunk))
-                                    .collect();
-
-                                if !commands_b64.is_empty() {
-                                    iot.send_real(&info.entry, commands_b64).await?;
-                                    self.device_mut(&device.sku, &device.id)
-                                        .await
-                                        .set_active_scene(Some(scene_name_to_set));
-                                    return Ok(());
-                                } else {
-                                    log::error!("SetSceneCode::encode produced empty command for {}: {}", device, scene_name_to_set);
-                                }
-                            }
-                            Err(e) => {
-                                log::error!("Failed to encode scene {} for {device} using SetSceneCode: {e}", scene_name_to_set);
-                            }
-                        }
-                    } else {
-                        log::warn!("Scene '{scene_name_to_set}' found for {device}, but it has neither override commands nor API parameters for BLE encoding.");
-                    }
-                } else {
-                    log::warn!("IoT client or Govee device info not available for BLE scene control for {device}.");
-                }
-            } else {
-                 log::warn!("IoT client not available for BLE scene control for {device}.");
+        let mut device = self.device_mut(sku, id).await;
+        // Several scenes can share a code, so keep the name that
+        // we already have if it is one of them
+        if let Some(active) = device.active_scene() {
+            if find_scene(&names, active).is_some() {
+                return;
             }
-        } else {
-            log::warn!("Scene '{scene_name_to_set}' not found in parsed scenes for SKU {} of device {device}.", device.sku);
         }
+        log::info!("{device} reported that scene {name} (code {code}) is active");
+        device.set_active_scene(Some(name));
+    }
+
+    async fn device_set_scene_impl(
+        self: &Arc<Self>,
+        device: &Device,
+        scene_name_to_set: &str,
+    ) -> anyhow::Result<()> {
+        let scene_name_to_set = &self
+            .resolve_device_scene_name(device, scene_name_to_set)
+            .await?;
+
+        if let Some(effect) = LocalEffect::from_name(scene_name_to_set) {
+            self.start_local_effect(device, effect).await?;
+            self.device_mut(&device.sku, &device.id)
+                .await
+                .set_active_scene(Some(effect.name()));
+            return Ok(());
+        }
+
+        self.send_transport_command(device, TransportCommand::Scene(scene_name_to_set.clone()))
+            .await?;
+        self.device_mut(&device.sku, &device.id)
+            .await
+            .set_active_scene(Some(scene_name_to_set));
+        Ok(())
+    }
+
+    pub(crate) async fn try_set_scene_via_platform(
+        &self,
+        device: &Device,
+        scene_name_to_set: &str,
+    ) -> anyhow::Result<bool> {
+        if device.avoid_platform_api() {
+            return Ok(false);
+        }
+        let Some((client, info)) = self.platform_for_device(device).await else {
+            return Ok(false);
+        };
+        log::info!("Using Platform API to set {device} to scene {scene_name_to_set}");
+        client.set_scene_by_name(info, scene_name_to_set).await?;
+        Ok(true)
+    }
 
-        anyhow::bail!("Unable to set scene '{scene_name_to_set}' for {device} using any available method.");
+    pub(crate) async fn try_set_scene_via_lan(
+        &self,
+        device: &Device,
+        scene_name_to_set: &str,
+    ) -> anyhow::Result<bool> {
+        let Some(lan_dev) = &device.lan_device else {
+            return Ok(false);
+        };
+        log::info!("Using LAN API to set {device} to scene {scene_name_to_set}");
+        lan_dev.set_scene_by_name(scene_name_to_set).await?;
+        Ok(true)
+    }
+
+    pub(crate) async fn try_set_scene_via_iot(
+        &self,
+        device: &Device,
+        scene_name_to_set: &str,
+    ) -> anyhow::Result<bool> {
+        let Some(iot) = self.get_iot_client().await else {
+            log::warn!("IoT client not available for BLE scene control for {device}.");
+            return Ok(false);
+        };
+        let Some(info) = &device.undoc_device_info else {
+            log::warn!("Govee device info not available for BLE scene control for {device}.");
+            return Ok(false);
+        };
+
+        log::info!("Attempting to set scene '{scene_name_to_set}' for {device} via BLE/IoT.");
+        let resolved = resolve_scene(
+            &device.sku,
+            &SceneSelector::Name {
+                name: scene_name_to_set.to_string(),
+                fuzzy: false,
+            },
+        )
+        .await?;
+        self.iot_send_real(&iot, &info.entry, resolved.commands)
+            .await?;
+        Ok(true)
     }
 
 
-    pub async fn notify_of_state_change(self: &Arc<Self>, device_id: &str) -> anyhow::Result<()> {
-        let Some(canonical_device) = self.device_by_id(&device_id).await else {
-            anyhow::bail!("cannot find device {device_id}!?");
+    /// Publish the availability of the device to hass.
+    /// Unless force is true, this only publishes when
+    /// the availability has changed since we last reported it.
+    pub async fn publish_device_availability(
+        &self,
+        device: &Device,
+        force: bool,
+    ) -> anyhow::Result<()> {
+        let Some(hass) = self.get_hass_client().await else {
+            return Ok(());
         };
 
-        if let Some(hass) = self.get_hass_client().await {
-            hass.advise_hass_of_light_state(&canonical_device, self)
-                .await?;
+        let available = !device.is_quarantined()
+            && device.is_reachable(self.get_offline_threshold().await)
+            && !device.is_ble_silent(last_advert_heard(), BLE_SILENCE_WINDOW);
+        let prior = self
+            .availability_by_id
+            .lock()
+            .await
+            .insert(device.id.clone(), available);
+        if !force && prior == Some(available) {
+            return Ok(());
         }
 
+        if prior.is_some() && prior != Some(available) {
+            log::info!(
+                "{device} is now {}",
+                if available {
+                    "available"
+                } else {
+                    "unavailable"
+                }
+            );
+        }
+
+        hass.publish(
+            device_availability_topic(device),
+            if available { "online" } else { "offline" },
+        )
+        .await
+    }
+
+    pub async fn publish_all_device_availability(&self, force: bool) -> anyhow::Result<()> {
+        for device in self.devices().await {
+            self.publish_device_availability(&device, force).await?;
+        }
         Ok(())
     }
 }
 
+/// Returns the lowercased labels that refer to d: its name,
+/// id, topic id and address
+pub fn device_labels(d: &Device) -> Vec<String> {
+    let mut labels = vec![
+        d.name(),
+        d.id.to_string(),
+        topic_safe_id(d),
+        device_topic_id(d),
+        d.computed_name(),
+    ];
+    labels.extend(d.ip_addr().map(|ip| ip.to_string()));
+    for label in &mut labels {
+        label.make_ascii_lowercase();
+    }
+    labels
+}
+
+/// Returns true if label refers to d, by name, id, topic id or address
+pub fn device_matches_label(d: &Device, label: &str) -> bool {
+    device_labels(d)
+        .iter()
+        .any(|l| l.eq_ignore_ascii_case(label))
+}
+
 pub fn sort_and_dedup_scenes(mut scenes: Vec<String>) -> Vec<String> {
     scenes.sort_by_key(|s| s.to_ascii_lowercase());
     scenes.dedup();
     scenes
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::service::hass::PublishedMessage;
+
+    #[tokio::test]
+    async fn control_timeout_and_recovery() {
+        let state = State::new();
+        state.set_control_timeout(Duration::from_millis(50)).await;
+        let device = state.device_mut("H6000", "AA:BB").await.clone();
+
+        // A live holder causes a subsequent acquisition to time out
+        let (permit, holder) = state.acquire_control(&device).await.unwrap();
+        assert!(state.acquire_control(&device).await.is_err());
+
+        // and force releasing allows a new holder to proceed
+        // while the prior one is still in flight
+        state.force_release_control(&device).await;
+        let (_permit, _holder) = state.acquire_control(&device).await.unwrap();
+        drop((permit, holder));
+        assert!(state.acquire_control(&device).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn force_release_with_waiter() {
+        let state = Arc::new(State::new());
+        state.set_control_timeout(Duration::from_secs(5)).await;
+        let device = state.device_mut("H6000", "AA:BB").await.clone();
+
+        // A waiter is blocked on the original semaphore
+        let (permit, holder) = state.acquire_control(&device).await.unwrap();
+        let waiter = tokio::spawn({
+            let state = state.clone();
+            let device = device.clone();
+            async move { state.acquire_control(&device).await }
+        });
+        tokio::task::yield_now().await;
+
+        // when the control is force released and acquired again
+        state.force_release_control(&device).await;
+        let (_new_permit, new_holder) = state.acquire_control(&device).await.unwrap();
+
+        // so when the waiter acquires the original semaphore, it
+        // must not replace the holder of the new one
+        drop((permit, holder));
+        let _waiter = waiter.await.unwrap().unwrap();
+        let slots = state.control_by_id.lock().await;
+        let slot = slots.get(&device.id).unwrap();
+        assert!(Arc::ptr_eq(&slot.holder.upgrade().unwrap(), &new_holder));
+    }
+
+    #[tokio::test]
+    async fn device_snapshot() {
+        let state = State::new();
+        assert!(state.device_by_id("AA:BB").await.is_none());
+
+        drop(state.device_mut("H6000", "AA:BB").await);
+        let mut device = state.device_mut("H6000", "AA:BB").await;
+        device.set_capability_state(
+            crate::platform_api::DeviceCapabilityKind::OnOff,
+            "powerSwitch",
+            json!(1),
+        );
+        // Readers see the prior version until the change is complete
+        let prior = state.resolve_device("aa:bb").await.unwrap();
+        assert!(prior
+            .get_state_capability_by_instance("powerSwitch")
+            .is_none());
+        drop(device);
+
+        let device = state.device_by_id("AA:BB").await.unwrap();
+        assert!(device
+            .get_state_capability_by_instance("powerSwitch")
+            .is_some());
+        assert_eq!(state.devices().await.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn published_state() {
+        let state = State::new();
+        let message = |payload: &str| PublishedMessage {
+            topic: "gv2mqtt/light/AABB/state".to_string(),
+            payload: payload.to_string(),
+            retain: false,
+        };
+        let on = vec![message("ON")];
+        let off = vec![message("OFF")];
+
+        assert!(state.record_published_state("AA:BB", &on, false).await);
+        assert!(!state.record_published_state("AA:BB", &on, false).await);
+        assert!(state.record_published_state("AA:BB", &on, true).await);
+        assert!(state.record_published_state("AA:BB", &off, false).await);
+        assert!(state.record_published_state("CC:DD", &off, false).await);
+
+        // A state that failed to publish is not suppressed next time
+        state.forget_published_state("CC:DD").await;
+        assert!(state.record_published_state("CC:DD", &off, false).await);
+    }
+
+    #[tokio::test]
+    async fn read_only_devices() {
+        let state = Arc::new(State::new());
+        drop(state.device_mut("H6000", "AA:BB").await);
+        state.set_read_only_devices(vec!["aa:bb".to_string()]).await;
+
+        let err = state
+            .resolve_device_for_control("AA:BB")
+            .await
+            .err()
+            .unwrap();
+        assert!(err
+            .downcast_ref::<crate::service::read_only::ReadOnlyError>()
+            .is_some());
+
+        // The per-device setting takes precedence over the configuration
+        let device = state.resolve_device_read_only("AA:BB").await.unwrap();
+        state.set_device_read_only(&device, false).await.unwrap();
+        assert!(state.resolve_device_for_control("AA:BB").await.is_ok());
+
+        // but the global setting applies to every device
+        state.set_global_read_only(true).await.unwrap();
+        assert!(state.resolve_device_for_control("AA:BB").await.is_err());
+    }
+}
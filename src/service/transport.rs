@@ -0,0 +1,152 @@
+use crate::service::device::Device;
+use crate::service::device_config::{load_config_file, DeviceConfigFile};
+use crate::service::state::State as ServiceState;
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::Path;
+
+/// A means of controlling a device
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Transport {
+    Lan,
+    Iot,
+    Platform,
+}
+
+/// The command types that have a configurable transport order.
+/// These are the command names used by run_device_command.
+pub const TRANSPORT_COMMANDS: &[&str] = &[
+    "power",
+    "light_power",
+    "brightness",
+    "color_temperature",
+    "color_rgb",
+    "scene",
+    "raw",
+];
+
+/// The order in which the transports are tried when there is
+/// no configuration for the device
+pub fn default_transport_order(command: &str) -> Vec<Transport> {
+    match command {
+        // The Platform API knows about more scenes than we can
+        // reliably encode for the other transports
+        "scene" => vec![Transport::Platform, Transport::Lan, Transport::Iot],
+        _ => vec![Transport::Lan, Transport::Iot, Transport::Platform],
+    }
+}
+
+/// The transport order for each command type. The `default`
+/// entry applies to commands that are not otherwise listed.
+#[derive(Deserialize, Serialize, Clone, Debug, Default, PartialEq)]
+pub struct TransportPriority {
+    #[serde(flatten)]
+    pub commands: HashMap<String, Vec<Transport>>,
+}
+
+impl TransportPriority {
+    fn validate(&self, context: &str) -> anyhow::Result<()> {
+        for (command, order) in &self.commands {
+            if command != "default" && !TRANSPORT_COMMANDS.contains(&command.as_str()) {
+                anyhow::bail!(
+                    "{context}: unknown command type '{command}', expected one of default, {}",
+                    TRANSPORT_COMMANDS.join(", ")
+                );
+            }
+            if order.is_empty() {
+                anyhow::bail!("{context}: the transport list for '{command}' is empty");
+            }
+        }
+        Ok(())
+    }
+
+    fn order_for(&self, command: &str) -> Option<&Vec<Transport>> {
+        self.commands
+            .get(command)
+            .or_else(|| self.commands.get("default"))
+    }
+}
+
+pub type TransportPriorityFile = DeviceConfigFile<TransportPriority>;
+
+impl TransportPriorityFile {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.validate_entries(TransportPriority::validate)
+    }
+
+    /// Determine the order in which the transports should be tried
+    /// in order to send command to device
+    pub fn transport_order(&self, device: &Device, command: &str) -> Vec<Transport> {
+        self.device_entries(device)
+            .find_map(|priority| priority.order_for(command))
+            .or_else(|| {
+                self.sku_entries(device)
+                    .find_map(|priority| priority.order_for(command))
+            })
+            .cloned()
+            .unwrap_or_else(|| default_transport_order(command))
+    }
+}
+
+pub fn load_transport_priority_file(path: &Path) -> anyhow::Result<TransportPriorityFile> {
+    let file: TransportPriorityFile = load_config_file(path, "transport priorities")?;
+    file.validate()
+        .with_context(|| format!("validating transport priorities from {path:?}"))?;
+    Ok(file)
+}
+
+impl ServiceState {
+    pub async fn transport_order(&self, device: &Device, command: &str) -> Vec<Transport> {
+        self.get_transport_priority()
+            .await
+            .transport_order(device, command)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn transport_order() {
+        let file: TransportPriorityFile = serde_json::from_str(
+            r#"{
+                "skus": {
+                    "H6072": {"scene": ["iot", "platform"], "default": ["lan"]}
+                },
+                "devices": {
+                    "AA:BB:CC:DD:EE:FF:00:11": {"brightness": ["platform"]}
+                }
+            }"#,
+        )
+        .unwrap();
+        file.validate().unwrap();
+
+        let device = Device::new("H6072", "AA:BB:CC:DD:EE:FF:00:11");
+        let other = Device::new("H6072", "AA:BB:CC:DD:EE:FF:00:22");
+        let unlisted = Device::new("H6159", "AA:BB:CC:DD:EE:FF:00:33");
+
+        assert_eq!(
+            file.transport_order(&device, "brightness"),
+            vec![Transport::Platform]
+        );
+        assert_eq!(
+            file.transport_order(&device, "scene"),
+            vec![Transport::Iot, Transport::Platform]
+        );
+        assert_eq!(
+            file.transport_order(&other, "brightness"),
+            vec![Transport::Lan]
+        );
+        assert_eq!(
+            file.transport_order(&unlisted, "scene"),
+            default_transport_order("scene")
+        );
+
+        let bogus: TransportPriorityFile =
+            serde_json::from_str(r#"{"skus": {"H6072": {"brightnes": ["lan"]}}}"#).unwrap();
+        assert!(bogus.validate().is_err());
+    }
+}
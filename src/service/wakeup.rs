@@ -0,0 +1,426 @@
+use crate::service::hass::{alarms_state_topic, IdParameter};
+use crate::service::state::{State as ServiceState, StateHandle};
+use anyhow::Context;
+use chrono::{NaiveDate, NaiveTime, Utc, Weekday};
+use mosquitto_rs::router::{Params, Payload, State};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::sync::Arc;
+use tokio::time::{sleep, Duration, Instant};
+
+/// How often a running ramp re-evaluates its target
+const RAMP_STEP_INTERVAL: Duration = Duration::from_secs(5);
+/// How often we check whether an alarm needs to start
+const ALARM_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// The warm end of the ramp, used when the device
+/// doesn't tell us its color temperature range
+const DEFAULT_WARM_KELVIN: u32 = 2000;
+
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RampCurve {
+    #[default]
+    Linear,
+    /// Starts slowly and speeds up towards the end
+    EaseIn,
+    /// Starts quickly and slows down towards the end
+    EaseOut,
+}
+
+impl RampCurve {
+    /// Map linear progress in the range 0.0-1.0 to the curve
+    pub fn apply(&self, progress: f64) -> f64 {
+        let p = progress.clamp(0., 1.);
+        match self {
+            Self::Linear => p,
+            Self::EaseIn => p * p,
+            Self::EaseOut => 1. - (1. - p) * (1. - p),
+        }
+    }
+}
+
+/// Describes a gradual transition from darkness to the final state
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct RampParams {
+    /// How long the ramp takes to complete
+    #[serde(default = "default_duration_minutes")]
+    pub duration_minutes: u32,
+    #[serde(default)]
+    pub curve: RampCurve,
+    /// The brightness percentage at the end of the ramp
+    #[serde(default = "default_brightness")]
+    pub brightness: u8,
+    /// The color temperature at the end of the ramp.
+    /// If specified, the ramp will begin at the warmest
+    /// color temperature supported by the device.
+    #[serde(default)]
+    pub kelvin: Option<u32>,
+}
+
+fn default_duration_minutes() -> u32 {
+    30
+}
+
+fn default_brightness() -> u8 {
+    100
+}
+
+impl Default for RampParams {
+    fn default() -> Self {
+        Self {
+            duration_minutes: default_duration_minutes(),
+            curve: RampCurve::default(),
+            brightness: default_brightness(),
+            kelvin: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RampStep {
+    pub brightness: u8,
+    pub kelvin: Option<u32>,
+}
+
+impl RampParams {
+    pub fn duration(&self) -> Duration {
+        Duration::from_secs(self.duration_minutes as u64 * 60)
+    }
+
+    /// Compute the desired state at the given point in the ramp
+    pub fn step_at(&self, elapsed: Duration, start_kelvin: u32) -> RampStep {
+        let duration = self.duration();
+        let progress = if duration.is_zero() {
+            1.
+        } else {
+            elapsed.as_secs_f64() / duration.as_secs_f64()
+        };
+        let level = self.curve.apply(progress);
+
+        let brightness = (1. + (self.brightness.max(1) as f64 - 1.) * level).round() as u8;
+        let kelvin = self.kelvin.map(|target| {
+            let k = start_kelvin as f64 + (target as f64 - start_kelvin as f64) * level;
+            // Avoid sending a flurry of imperceptible changes
+            ((k / 50.).round() * 50.) as u32
+        });
+
+        RampStep { brightness, kelvin }
+    }
+}
+
+/// A scheduled wake-up ramp
+#[derive(Deserialize, Serialize, Clone, Debug, PartialEq)]
+pub struct AlarmConfig {
+    /// Used to identify the alarm for replacement or deletion
+    pub name: String,
+    /// The device name, id or other label that identifies the target
+    pub device: String,
+    /// The local time, in HH:MM format, at which the ramp completes
+    pub time: String,
+    /// The days on which the alarm is active. If empty, the
+    /// alarm is active every day.
+    #[serde(default)]
+    pub days: Vec<Weekday>,
+    #[serde(default = "default_true")]
+    pub enabled: bool,
+    #[serde(flatten)]
+    pub ramp: RampParams,
+}
+
+fn default_true() -> bool {
+    true
+}
+
+impl AlarmConfig {
+    fn wake_time(&self) -> anyhow::Result<NaiveTime> {
+        NaiveTime::parse_from_str(&self.time, "%H:%M")
+            .with_context(|| format!("alarm {}: invalid time {}", self.name, self.time))
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        self.wake_time()?;
+        Ok(())
+    }
+
+    /// Returns the date whose alarm should begin now, if any.
+    /// The ramp begins `duration` before the configured time.
+    pub fn due_start(&self, now: chrono::NaiveDateTime) -> Option<NaiveDate> {
+        if !self.enabled {
+            return None;
+        }
+        let wake_time = self.wake_time().ok()?;
+        let lead = chrono::Duration::minutes(self.ramp.duration_minutes as i64);
+
+        // The wake time may be tomorrow if the ramp crosses midnight
+        for date in [now.date(), now.date() + chrono::Duration::days(1)] {
+            use chrono::Datelike;
+            let wake = date.and_time(wake_time);
+            let start = wake - lead;
+            if now >= start
+                && now - start < chrono::Duration::from_std(ALARM_CHECK_INTERVAL * 2).ok()?
+                && (self.days.is_empty() || self.days.contains(&date.weekday()))
+            {
+                return Some(date);
+            }
+        }
+        None
+    }
+}
+
+#[derive(Deserialize, Serialize, Clone, Debug, Default)]
+pub struct AlarmFile {
+    #[serde(default)]
+    pub alarms: Vec<AlarmConfig>,
+}
+
+pub fn load_alarms_file(path: &Path) -> anyhow::Result<Vec<AlarmConfig>> {
+    let data =
+        std::fs::read_to_string(path).with_context(|| format!("reading alarms from {path:?}"))?;
+    let file: AlarmFile =
+        serde_json::from_str(&data).with_context(|| format!("parsing alarms from {path:?}"))?;
+    for alarm in &file.alarms {
+        alarm.validate()?;
+    }
+    Ok(file.alarms)
+}
+
+impl ServiceState {
+    /// Start ramping the device towards the final state described by params,
+    /// replacing any ramp that is already running for the device.
+    pub async fn start_ramp(
+        self: &Arc<Self>,
+        label: &str,
+        params: RampParams,
+    ) -> anyhow::Result<()> {
+        let device = self.resolve_device_read_only(label).await?;
+        let start_kelvin = device
+            .get_color_temperature_range()
+            .map(|(min, _max)| min)
+            .unwrap_or(DEFAULT_WARM_KELVIN);
+
+        log::info!("Starting wake-up ramp for {device}: {params:?}");
+
+        let state = self.clone();
+        let device_id = device.id.clone();
+        let task = tokio::spawn(async move {
+            let start = Instant::now();
+            let duration = params.duration();
+            let mut prior: Option<RampStep> = None;
+
+            loop {
+                let elapsed = start.elapsed().min(duration);
+                let step = params.step_at(elapsed, start_kelvin);
+
+                if let Some(current) = state.device_by_id(&device_id).await {
+                    let turned_off = current.device_state().map(|s| !s.on).unwrap_or(false);
+                    if prior.is_some() && turned_off {
+                        log::info!("{current} was turned off; abandoning wake-up ramp");
+                        break;
+                    }
+                }
+
+                if prior != Some(step) {
+                    if let Err(err) = state.apply_ramp_step(&device_id, step, prior).await {
+                        log::error!("wake-up ramp for {device_id}: {err:#}");
+                    }
+                    prior.replace(step);
+                }
+
+                if elapsed >= duration {
+                    break;
+                }
+                sleep(RAMP_STEP_INTERVAL).await;
+            }
+
+            log::info!("Wake-up ramp for {device_id} finished");
+            state.take_ramp(&device_id).await;
+        });
+
+        if let Some(prior) = self.set_ramp(&device.id, task.abort_handle()).await {
+            prior.abort();
+        }
+        Ok(())
+    }
+
+    async fn apply_ramp_step(
+        self: &Arc<Self>,
+        device_id: &str,
+        step: RampStep,
+        prior: Option<RampStep>,
+    ) -> anyhow::Result<()> {
+        let device = self.resolve_device_for_control(device_id).await?;
+        if prior.map(|p| p.kelvin != step.kelvin).unwrap_or(true) {
+            if let Some(kelvin) = step.kelvin {
+                self.device_set_color_temperature(&device, kelvin).await?;
+            }
+        }
+        if prior
+            .map(|p| p.brightness != step.brightness)
+            .unwrap_or(true)
+        {
+            self.device_set_brightness(&device, step.brightness).await?;
+        }
+        Ok(())
+    }
+
+    /// Cancel any ramp that is running for the device.
+    /// Returns true if there was a ramp to cancel.
+    pub async fn cancel_ramp(&self, device_id: &str) -> bool {
+        match self.take_ramp(device_id).await {
+            Some(handle) => {
+                log::info!("Cancelling wake-up ramp for {device_id}");
+                handle.abort();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Add or replace an alarm, matching by name
+    pub async fn set_alarm(&self, alarm: AlarmConfig) -> anyhow::Result<()> {
+        alarm.validate()?;
+        let mut alarms = self.get_alarms().await;
+        alarms.retain(|a| a.name != alarm.name);
+        alarms.push(alarm);
+        self.set_alarms(alarms).await;
+        Ok(())
+    }
+
+    pub async fn delete_alarm(&self, name: &str) -> bool {
+        let mut alarms = self.get_alarms().await;
+        let len = alarms.len();
+        alarms.retain(|a| a.name != name);
+        let removed = alarms.len() != len;
+        self.set_alarms(alarms).await;
+        removed
+    }
+
+    /// Publish the current set of alarms to the alarms state topic
+    pub async fn publish_alarms(&self) -> anyhow::Result<()> {
+        if let Some(hass) = self.get_hass_client().await {
+            let alarms = self.get_alarms().await;
+            hass.publish_obj(alarms_state_topic(), AlarmFile { alarms })
+                .await?;
+        }
+        Ok(())
+    }
+}
+
+/// Periodically checks for alarms that need to start their ramps
+pub async fn run_alarm_scheduler(state: StateHandle) -> anyhow::Result<()> {
+    let tz = crate::resolve_timezone();
+    let mut last_fired: std::collections::HashMap<String, NaiveDate> = Default::default();
+
+    loop {
+        let now = Utc::now().with_timezone(&tz).naive_local();
+        for alarm in state.get_alarms().await {
+            let Some(date) = alarm.due_start(now) else {
+                continue;
+            };
+            if last_fired.get(&alarm.name) == Some(&date) {
+                continue;
+            }
+            last_fired.insert(alarm.name.clone(), date);
+            log::info!("Alarm {} is starting", alarm.name);
+            if let Err(err) = state.start_ramp(&alarm.device, alarm.ramp.clone()).await {
+                log::error!("Alarm {}: {err:#}", alarm.name);
+            }
+        }
+        sleep(ALARM_CHECK_INTERVAL).await;
+    }
+}
+
+/// Start a ramp right now. The payload is either a JSON RampParams
+/// object, or the string "cancel" to stop a ramp that is in progress.
+pub async fn mqtt_device_ramp(
+    Payload(payload): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let payload = payload.trim();
+    if payload.is_empty() || payload.eq_ignore_ascii_case("cancel") {
+        let device = state.resolve_device_read_only(&id).await?;
+        state.cancel_ramp(&device.id).await;
+        return Ok(());
+    }
+
+    let params: RampParams = serde_json::from_str(payload)?;
+    state.start_ramp(&id, params).await
+}
+
+/// Add or replace an alarm described by the JSON payload
+pub async fn mqtt_alarm_set(
+    Payload(payload): Payload<String>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    let alarm: AlarmConfig = serde_json::from_str(&payload)?;
+    log::info!("Setting alarm {}", alarm.name);
+    state.set_alarm(alarm).await?;
+    state.publish_alarms().await
+}
+
+/// Delete the alarm whose name is the payload
+pub async fn mqtt_alarm_delete(
+    Payload(name): Payload<String>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    if !state.delete_alarm(name.trim()).await {
+        log::warn!("There is no alarm named {name}");
+    }
+    state.publish_alarms().await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn ramp_steps() {
+        let params = RampParams {
+            duration_minutes: 10,
+            curve: RampCurve::Linear,
+            brightness: 100,
+            kelvin: Some(4000),
+        };
+        assert_eq!(
+            params.step_at(Duration::ZERO, 2000),
+            RampStep {
+                brightness: 1,
+                kelvin: Some(2000)
+            }
+        );
+        assert_eq!(
+            params.step_at(Duration::from_secs(300), 2000),
+            RampStep {
+                brightness: 51,
+                kelvin: Some(3000)
+            }
+        );
+        assert_eq!(
+            params.step_at(params.duration(), 2000),
+            RampStep {
+                brightness: 100,
+                kelvin: Some(4000)
+            }
+        );
+    }
+
+    #[test]
+    fn alarm_due() {
+        let alarm: AlarmConfig = serde_json::from_str(
+            r#"{"name": "weekday", "device": "Bedroom", "time": "07:00",
+               "days": ["Mon", "Tue"], "duration_minutes": 30}"#,
+        )
+        .unwrap();
+
+        let monday = NaiveDate::from_ymd_opt(2024, 1, 1).unwrap();
+        let at = |h, m| monday.and_hms_opt(h, m, 0).unwrap();
+
+        assert_eq!(alarm.due_start(at(6, 29)), None);
+        assert_eq!(alarm.due_start(at(6, 30)), Some(monday));
+        assert_eq!(alarm.due_start(at(6, 35)), None);
+
+        let sunday = NaiveDate::from_ymd_opt(2024, 1, 7).unwrap();
+        assert_eq!(alarm.due_start(sunday.and_hms_opt(6, 30, 0).unwrap()), None);
+    }
+}
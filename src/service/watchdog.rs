@@ -0,0 +1,308 @@
+//! Supervises the long-running tasks that bring us updates from the
+//! devices. Should one of them panic, exit or stop making progress,
+//! it is restarted and a diagnostic event is published, rather than
+//! the updates silently halting until the service is restarted.
+use crate::service::crash::panic_message;
+use crate::service::hass::base_topic;
+use crate::service::state::{State as ServiceState, StateHandle};
+use chrono::{DateTime, Utc};
+use serde::Serialize;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::Arc;
+use tokio::task::{JoinError, JoinHandle};
+use tokio::time::{sleep, Duration};
+
+/// How often to check on the supervised tasks. This also limits
+/// how often a task that fails right away is restarted.
+const CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Subsystem {
+    /// Receives LAN discovery and status responses
+    LanListener,
+    /// Receives status updates via AWS IoT
+    Iot,
+    /// Periodically polls the devices
+    PollScheduler,
+    /// Publishes the changes to the devices to hass
+    HassPublisher,
+    /// Receives BLE advertisements
+    BleScanner,
+}
+
+impl Subsystem {
+    /// How long the task may go without a heartbeat before we consider
+    /// it to have stalled. This allows for the longest interval at
+    /// which the task normally wakes up.
+    pub fn stall_window(self) -> chrono::Duration {
+        match self {
+            // Rescans at least once per minute
+            Self::LanListener => chrono::Duration::minutes(5),
+            // Waits up to 10 minutes between reconnection attempts
+            Self::Iot => chrono::Duration::minutes(15),
+            // Beats for each device that it polls
+            Self::PollScheduler => chrono::Duration::minutes(10),
+            // Beats for each event, and once per minute when idle
+            Self::HassPublisher => chrono::Duration::minutes(10),
+            // Beats for every advertisement that it hears, from any
+            // device, which arrive several times per second
+            Self::BleScanner => chrono::Duration::minutes(5),
+        }
+    }
+}
+
+impl std::fmt::Display for Subsystem {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let name = match self {
+            Self::LanListener => "LAN listener",
+            Self::Iot => "IoT loop",
+            Self::PollScheduler => "poll scheduler",
+            Self::HassPublisher => "hass publisher",
+            Self::BleScanner => "BLE scanner",
+        };
+        fmt.write_str(name)
+    }
+}
+
+/// Records when a supervised task last made progress
+#[derive(Clone, Debug, Default)]
+pub struct Heartbeat {
+    /// Unix timestamp in milliseconds, or 0 if it never beat
+    last: Arc<AtomicI64>,
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        self.last
+            .store(Utc::now().timestamp_millis(), Ordering::Relaxed);
+    }
+
+    pub fn last_beat(&self) -> Option<DateTime<Utc>> {
+        match self.last.load(Ordering::Relaxed) {
+            0 => None,
+            millis => DateTime::from_timestamp_millis(millis),
+        }
+    }
+
+    /// Whether there has been no beat within window of now.
+    /// A task that is yet to beat is measured from when it started.
+    pub fn is_stalled(
+        &self,
+        started: DateTime<Utc>,
+        now: DateTime<Utc>,
+        window: chrono::Duration,
+    ) -> bool {
+        let last = self
+            .last_beat()
+            .map(|last| last.max(started))
+            .unwrap_or(started);
+        now - last > window
+    }
+}
+
+/// Published to `gv2mqtt/watchdog` when a task is restarted
+#[derive(Serialize, Clone, Debug, PartialEq, Eq)]
+pub struct WatchdogEvent {
+    pub subsystem: Subsystem,
+    pub reason: String,
+    /// How many times the task has been restarted, including this time
+    pub restarts: u32,
+    pub timestamp: DateTime<Utc>,
+}
+
+pub fn watchdog_topic() -> String {
+    format!("{}/watchdog", base_topic())
+}
+
+pub struct SupervisedTask {
+    subsystem: Subsystem,
+    heartbeat: Heartbeat,
+    task: JoinHandle<()>,
+    start: Box<dyn Fn() -> JoinHandle<()> + Send + Sync>,
+    started: DateTime<Utc>,
+    restarts: u32,
+}
+
+impl SupervisedTask {
+    /// Supervise the already running task, which the watchdog
+    /// restarts by calling start
+    pub fn new<F>(
+        subsystem: Subsystem,
+        heartbeat: Heartbeat,
+        task: JoinHandle<()>,
+        start: F,
+    ) -> Self
+    where
+        F: Fn() -> JoinHandle<()> + Send + Sync + 'static,
+    {
+        Self {
+            subsystem,
+            heartbeat,
+            task,
+            start: Box::new(start),
+            started: Utc::now(),
+            restarts: 0,
+        }
+    }
+
+    /// Start the task by calling start, and supervise it
+    pub fn spawn<F>(subsystem: Subsystem, heartbeat: Heartbeat, start: F) -> Self
+    where
+        F: Fn() -> JoinHandle<()> + Send + Sync + 'static,
+    {
+        let task = start();
+        Self::new(subsystem, heartbeat, task, start)
+    }
+
+    /// Returns the reason that the task needs to be restarted, if any
+    async fn check(&mut self, now: DateTime<Utc>) -> Option<String> {
+        if self.task.is_finished() {
+            return Some(match (&mut self.task).await {
+                Ok(()) => "the task exited".to_string(),
+                Err(err) => describe_join_error(err),
+            });
+        }
+
+        let window = self.subsystem.stall_window();
+        if self.heartbeat.is_stalled(self.started, now, window) {
+            return Some(match self.heartbeat.last_beat() {
+                Some(last) => format!("no heartbeat since {last}"),
+                None => format!("no heartbeat since it started at {}", self.started),
+            });
+        }
+
+        None
+    }
+
+    fn restart(&mut self) {
+        self.task.abort();
+        self.task = (self.start)();
+        self.started = Utc::now();
+        self.restarts += 1;
+    }
+}
+
+fn describe_join_error(err: JoinError) -> String {
+    if !err.is_panic() {
+        return "the task was cancelled".to_string();
+    }
+    let message = panic_message(err.into_panic().as_ref());
+    format!("the task panicked: {message}")
+}
+
+impl ServiceState {
+    /// Have the watchdog restart task should it stop or stall
+    pub async fn supervise(&self, task: SupervisedTask) {
+        self.supervised_tasks().lock().await.push(task);
+    }
+
+    /// Restart any of the supervised tasks that have stopped or
+    /// stalled, returning an event for each one. The tasks stop
+    /// when we shut down, so they are left alone after that.
+    async fn check_supervised_tasks(&self) -> Vec<WatchdogEvent> {
+        if self.shutdown_token().is_triggered() {
+            return vec![];
+        }
+        let now = Utc::now();
+        let mut events = vec![];
+        for task in self.supervised_tasks().lock().await.iter_mut() {
+            let Some(reason) = task.check(now).await else {
+                continue;
+            };
+            task.restart();
+            events.push(WatchdogEvent {
+                subsystem: task.subsystem,
+                reason,
+                restarts: task.restarts,
+                timestamp: now,
+            });
+        }
+        events
+    }
+}
+
+/// Checks on the supervised tasks until the shutdown is triggered
+pub async fn run_watchdog(state: StateHandle) {
+    let token = state.shutdown_token();
+    while token.run_until(sleep(CHECK_INTERVAL)).await.is_some() {
+        for event in state.check_supervised_tasks().await {
+            log::error!(
+                "Restarted the {} ({} restarts): {}",
+                event.subsystem,
+                event.restarts,
+                event.reason
+            );
+            if let Some(client) = state.get_hass_client().await {
+                if let Err(err) = client.publish_obj(watchdog_topic(), &event).await {
+                    log::error!("publishing watchdog event: {err:#}");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn stalled() {
+        let started = Utc::now();
+        let window = chrono::Duration::minutes(5);
+        let heartbeat = Heartbeat::default();
+        assert!(!heartbeat.is_stalled(started, started + window, window));
+        assert!(heartbeat.is_stalled(started, started + chrono::Duration::minutes(6), window));
+
+        heartbeat.beat();
+        let last = heartbeat.last_beat().unwrap();
+        assert!(!heartbeat.is_stalled(started, last + window, window));
+        assert!(heartbeat.is_stalled(started, last + chrono::Duration::minutes(6), window));
+    }
+
+    #[tokio::test]
+    async fn restarts_panicked_task() {
+        let state = StateHandle::default();
+        let heartbeat = Heartbeat::default();
+        state
+            .supervise(SupervisedTask::spawn(
+                Subsystem::PollScheduler,
+                heartbeat.clone(),
+                || tokio::spawn(async { panic!("oops") }),
+            ))
+            .await;
+        tokio::task::yield_now().await;
+        sleep(Duration::from_millis(50)).await;
+
+        let events = state.check_supervised_tasks().await;
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].subsystem, Subsystem::PollScheduler);
+        assert_eq!(events[0].reason, "the task panicked: oops");
+        assert_eq!(events[0].restarts, 1);
+
+        // A running task that is beating is left alone
+        let state = StateHandle::default();
+        state
+            .supervise(SupervisedTask::spawn(
+                Subsystem::Iot,
+                heartbeat.clone(),
+                || tokio::spawn(std::future::pending()),
+            ))
+            .await;
+        heartbeat.beat();
+        assert!(state.check_supervised_tasks().await.is_empty());
+
+        // Tasks that stop for the shutdown are not restarted
+        let state = StateHandle::default();
+        state
+            .supervise(SupervisedTask::spawn(
+                Subsystem::HassPublisher,
+                heartbeat.clone(),
+                || tokio::spawn(async {}),
+            ))
+            .await;
+        state.shutdown_token().trigger();
+        sleep(Duration::from_millis(50)).await;
+        assert!(state.check_supervised_tasks().await.is_empty());
+    }
+}
@@ -21,7 +21,7 @@ impl TemperatureUnits {
         }
     }
 
-    fn scale(&self) -> TemperatureScale {
+    pub fn scale(&self) -> TemperatureScale {
         match self {
             Self::Celsius | Self::CelsiusTimes100 => TemperatureScale::Celsius,
             Self::Fahrenheit | Self::FahrenheitTimes100 => TemperatureScale::Fahrenheit,
@@ -81,6 +81,22 @@ impl TemperatureScale {
             Self::Fahrenheit => UNIT_FAHRENHEIT,
         }
     }
+
+    /// The temperature_unit of HASS climate and water_heater entities
+    pub fn hass_temperature_unit(&self) -> &'static str {
+        match self {
+            Self::Celsius => "C",
+            Self::Fahrenheit => "F",
+        }
+    }
+
+    /// The unit as it is named in Platform API requests
+    pub fn platform_unit(&self) -> &'static str {
+        match self {
+            Self::Celsius => "Celsius",
+            Self::Fahrenheit => "Fahrenheit",
+        }
+    }
 }
 
 impl FromStr for TemperatureScale {
@@ -141,6 +157,21 @@ impl TemperatureValue {
         self.value
     }
 
+    pub fn unit(&self) -> TemperatureUnits {
+        self.unit
+    }
+
+    /// Convert to the units of min and max, constraining the
+    /// value to the range that they describe
+    pub fn constrain(&self, min: &Self, max: &Self) -> Self {
+        let unit = min.unit;
+        let max = max.as_unit(unit).value;
+        Self {
+            unit,
+            value: self.as_unit(unit).value.max(min.value).min(max),
+        }
+    }
+
     /// Normalize away scaled temperature units
     pub fn normalize(&self) -> Self {
         let normalized = self.value / self.unit.factor();
@@ -241,6 +272,24 @@ mod test {
         );
     }
 
+    #[test]
+    fn constrain() {
+        let min = TemperatureValue::with_fahrenheit(40.);
+        let max = TemperatureValue::with_fahrenheit(212.);
+        assert_eq!(
+            TemperatureValue::with_celsius(100.).constrain(&min, &max),
+            TemperatureValue::with_fahrenheit(212.)
+        );
+        assert_eq!(
+            TemperatureValue::with_celsius(0.).constrain(&min, &max),
+            TemperatureValue::with_fahrenheit(40.)
+        );
+        assert_eq!(
+            TemperatureValue::with_celsius(20.).constrain(&min, &max),
+            TemperatureValue::with_fahrenheit(68.)
+        );
+    }
+
     #[test]
     fn display() {
         assert_eq!(
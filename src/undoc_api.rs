@@ -6,6 +6,8 @@ use crate::platform_api::{
     from_json, http_response_body, DeviceCapability, DeviceCapabilityKind, DeviceParameters,
     EnumOption,
 };
+use crate::secrets::{secret_store, EMAIL_SECRET, PASSWORD_SECRET};
+use chrono::{DateTime, Utc};
 use reqwest::Method;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
@@ -21,6 +23,28 @@ const HALF_DAY: Duration = Duration::from_secs(3600 * 12);
 const ONE_DAY: Duration = Duration::from_secs(86400);
 const ONE_WEEK: Duration = Duration::from_secs(86400 * 7);
 const FIFTEEN_MINS: Duration = Duration::from_secs(60 * 15);
+/// How long before the login token expires that we log in again
+const LOGIN_REFRESH_MARGIN: Duration = Duration::from_secs(3600);
+
+/// Snapshots of the light effect libraries of the SKUs listed here,
+/// used when the library cannot be fetched, so that their scenes
+/// remain available to installations without internet access.
+/// Refresh or add to these using scripts/update-bundled-data.sh
+const BUNDLED_SCENE_LIBRARIES: &[(&str, &str)] =
+    &[("H6072", include_str!("../assets/scenes/H6072.json"))];
+
+fn bundled_scenes_for_sku(sku: &str) -> Option<Vec<LightEffectCategory>> {
+    let (_, data) = BUNDLED_SCENE_LIBRARIES
+        .iter()
+        .find(|(bundled_sku, _)| bundled_sku.eq_ignore_ascii_case(sku))?;
+    match from_json::<LightEffectLibraryResponse, _>(data) {
+        Ok(resp) => Some(resp.data.categories),
+        Err(err) => {
+            log::error!("bundled scenes for {sku} are invalid: {err:#}");
+            None
+        }
+    }
+}
 
 /// Some data is not meant for human eyes except in very unusual circumstances.
 #[derive(Deserialize, Serialize, Clone)]
@@ -91,13 +115,23 @@ pub struct UndocApiArguments {
     /// Where to find the AWS root CA certificate
     #[arg(long, global = true, default_value = "AmazonRootCA1.pem")]
     pub amazon_root_ca: PathBuf,
+
+    /// Also subscribe to the AWS IoT device shadow topics of each
+    /// device, so that changes made via the Govee app or a voice
+    /// assistant are picked up as soon as they happen.
+    /// You may also set GOVEE_IOT_SHADOW=true via the environment.
+    #[arg(long, global = true)]
+    pub iot_shadow: bool,
 }
 
 impl UndocApiArguments {
     pub fn opt_email(&self) -> anyhow::Result<Option<String>> {
         match &self.govee_email {
             Some(key) => Ok(Some(key.to_string())),
-            None => opt_env_var("GOVEE_EMAIL"),
+            None => match opt_env_var("GOVEE_EMAIL")? {
+                Some(email) => Ok(Some(email)),
+                None => opt_secret(EMAIL_SECRET),
+            },
         }
     }
 
@@ -113,7 +147,10 @@ impl UndocApiArguments {
     pub fn opt_password(&self) -> anyhow::Result<Option<String>> {
         match &self.govee_password {
             Some(key) => Ok(Some(key.to_string())),
-            None => opt_env_var("GOVEE_PASSWORD"),
+            None => match opt_env_var("GOVEE_PASSWORD")? {
+                Some(password) => Ok(Some(password)),
+                None => opt_secret(PASSWORD_SECRET),
+            },
         }
     }
 
@@ -126,6 +163,13 @@ impl UndocApiArguments {
         })
     }
 
+    pub fn iot_shadow(&self) -> anyhow::Result<bool> {
+        if let Some(v) = opt_env_var::<String>("GOVEE_IOT_SHADOW")? {
+            return truthy(&v);
+        }
+        Ok(self.iot_shadow)
+    }
+
     pub fn api_client(&self) -> anyhow::Result<GoveeUndocumentedApi> {
         let email = self.email()?;
         let password = self.password()?;
@@ -133,6 +177,36 @@ impl UndocApiArguments {
     }
 }
 
+fn opt_secret(key: &str) -> anyhow::Result<Option<String>> {
+    match secret_store() {
+        Some(store) => store.get(key),
+        None => Ok(None),
+    }
+}
+
+/// The result of logging in to the account, along with
+/// when its token expires
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct StoredLogin {
+    pub login: LoginAccountResponse,
+    pub expires: DateTime<Utc>,
+}
+
+impl StoredLogin {
+    fn new(login: LoginAccountResponse) -> Self {
+        let expires = Utc::now() + chrono::Duration::seconds(login.token_expire_cycle as i64);
+        Self { login, expires }
+    }
+
+    /// How long until we should log in again
+    fn refresh_delay(&self) -> Duration {
+        (self.expires - Utc::now())
+            .to_std()
+            .unwrap_or_default()
+            .saturating_sub(LOGIN_REFRESH_MARGIN)
+    }
+}
+
 #[derive(Clone)]
 pub struct GoveeUndocumentedApi {
     email: String,
@@ -179,6 +253,10 @@ impl GoveeUndocumentedApi {
                     .send()
                     .await?;
 
+                if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+                    self.invalidate_account_login().await;
+                }
+
                 #[derive(Deserialize, Debug)]
                 #[allow(non_snake_case, dead_code)]
                 struct Response {
@@ -195,11 +273,20 @@ impl GoveeUndocumentedApi {
         .await
     }
 
-    pub fn invalidate_account_login(&self) {
-        crate::cache::invalidate_key("undoc-api", "account-info").ok();
+    pub async fn invalidate_account_login(&self) {
+        crate::cache::invalidate_key("undoc-api", "account-login").ok();
+        if let Some(store) = secret_store() {
+            if let Err(err) = store.remove(&self.login_secret_key()).await {
+                log::warn!("Removing login token from {:?}: {err:#}", store.path());
+            }
+        }
     }
 
-    async fn login_account_impl(&self) -> anyhow::Result<CacheComputeResult<LoginAccountResponse>> {
+    fn login_secret_key(&self) -> String {
+        format!("account_login/{}", self.email)
+    }
+
+    async fn login_account_impl(&self) -> anyhow::Result<CacheComputeResult<StoredLogin>> {
         let response = reqwest::Client::builder()
             .timeout(Duration::from_secs(30))
             .build()?
@@ -225,29 +312,65 @@ impl GoveeUndocumentedApi {
             status: u64,
         }
 
-        let ttl = Duration::from_secs(resp.client.token_expire_cycle as u64);
-        Ok(CacheComputeResult::WithTtl(resp.client, ttl))
+        let login = StoredLogin::new(resp.client);
+        let ttl = login.refresh_delay();
+        Ok(CacheComputeResult::WithTtl(login, ttl))
     }
 
     pub async fn login_account_cached(&self) -> anyhow::Result<LoginAccountResponse> {
-        cache_get(
-            CacheGetOptions {
-                topic: "undoc-api",
-                key: "account-info",
-                soft_ttl: HALF_DAY,
-                hard_ttl: HALF_DAY,
-                negative_ttl: FIFTEEN_MINS,
-                allow_stale: false,
-            },
-            async { self.login_account_impl().await },
-        )
-        .await
+        Ok(self.stored_login().await?.login)
+    }
+
+    /// Returns the current login, logging in again if we don't
+    /// have one, or if its token is about to expire.
+    /// The login is kept in the secret store if there is one,
+    /// otherwise in the cache.
+    async fn stored_login(&self) -> anyhow::Result<StoredLogin> {
+        let Some(store) = secret_store() else {
+            return cache_get(
+                CacheGetOptions {
+                    topic: "undoc-api",
+                    key: "account-login",
+                    soft_ttl: HALF_DAY,
+                    hard_ttl: HALF_DAY,
+                    negative_ttl: FIFTEEN_MINS,
+                    allow_stale: false,
+                },
+                async { self.login_account_impl().await },
+            )
+            .await;
+        };
+
+        let key = self.login_secret_key();
+        if let Some(login) = store.get::<StoredLogin>(&key)? {
+            if !login.refresh_delay().is_zero() {
+                return Ok(login);
+            }
+        }
+        let login = self.login_account_impl().await?.into_inner();
+        store.set(&key, &login).await?;
+        Ok(login)
+    }
+
+    /// Keeps the login fresh by logging in again shortly before
+    /// its token expires, so that requests don't fail because
+    /// the token expired, or stall while we log in again
+    pub async fn run_login_refresh(self) {
+        loop {
+            let delay = match self.stored_login().await {
+                Ok(login) => login.refresh_delay().max(Duration::from_secs(60)),
+                Err(err) => {
+                    log::warn!("Refreshing Govee account login: {err:#}");
+                    FIFTEEN_MINS
+                }
+            };
+            tokio::time::sleep(delay).await;
+        }
     }
 
-    #[allow(dead_code)]
     pub async fn login_account(&self) -> anyhow::Result<LoginAccountResponse> {
         let value = self.login_account_impl().await?;
-        Ok(value.into_inner())
+        Ok(value.into_inner().login)
     }
 
     pub async fn get_device_list(&self, token: &str) -> anyhow::Result<DevicesResponse> {
@@ -269,7 +392,7 @@ impl GoveeUndocumentedApi {
             .await?;
 
         if response.status() == reqwest::StatusCode::UNAUTHORIZED {
-            self.invalidate_account_login();
+            self.invalidate_account_login().await;
         }
 
         let resp: DevicesResponse = http_response_body(response).await?;
@@ -277,6 +400,223 @@ impl GoveeUndocumentedApi {
         Ok(resp)
     }
 
+    /// Fetch the timers that have been configured for the device
+    /// via the Govee Home app
+    pub async fn get_device_schedules(
+        &self,
+        token: &str,
+        sku: &str,
+        device: &str,
+    ) -> anyhow::Result<Vec<DeviceSchedule>> {
+        let response = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?
+            .request(
+                Method::POST,
+                "https://app2.govee.com/device/rest/devices/v1/timer/list",
+            )
+            .header("Authorization", format!("Bearer {token}"))
+            .header("appVersion", APP_VERSION)
+            .header("clientId", &self.client_id)
+            .header("clientType", "1")
+            .header("iotVersion", "0")
+            .header("timestamp", ms_timestamp())
+            .header("User-Agent", user_agent())
+            .json(&json!({"sku": sku, "device": device}))
+            .send()
+            .await?;
+
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            self.invalidate_account_login().await;
+        }
+
+        let resp: DeviceSchedulesResponse = http_response_body(response).await?;
+        anyhow::ensure!(
+            resp.status == 200,
+            "failed to list schedules for {sku} {device}: {}",
+            resp.message
+        );
+
+        Ok(resp.data.timers)
+    }
+
+    /// Enable or disable one of the timers of the device.
+    /// The other settings of the timer are left unchanged.
+    pub async fn set_device_schedule_enabled(
+        &self,
+        token: &str,
+        sku: &str,
+        device: &str,
+        schedule: &DeviceSchedule,
+        enabled: bool,
+    ) -> anyhow::Result<()> {
+        let mut timer = schedule.clone();
+        timer.enabled = enabled;
+
+        let response = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?
+            .request(
+                Method::POST,
+                "https://app2.govee.com/device/rest/devices/v1/timer/update",
+            )
+            .header("Authorization", format!("Bearer {token}"))
+            .header("appVersion", APP_VERSION)
+            .header("clientId", &self.client_id)
+            .header("clientType", "1")
+            .header("iotVersion", "0")
+            .header("timestamp", ms_timestamp())
+            .header("User-Agent", user_agent())
+            .json(&json!({"sku": sku, "device": device, "timer": timer}))
+            .send()
+            .await?;
+
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            self.invalidate_account_login().await;
+        }
+
+        let resp: StatusResponse = http_response_body(response).await?;
+        anyhow::ensure!(
+            resp.status == 200,
+            "failed to update schedule {} for {sku} {device}: {}",
+            schedule.timer_id,
+            resp.message
+        );
+        Ok(())
+    }
+
+    /// Change the name of the device, as shown in the Govee Home app
+    pub async fn set_device_name(
+        &self,
+        token: &str,
+        sku: &str,
+        device: &str,
+        name: &str,
+    ) -> anyhow::Result<()> {
+        let response = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?
+            .request(
+                Method::POST,
+                "https://app2.govee.com/device/rest/devices/v1/name",
+            )
+            .header("Authorization", format!("Bearer {token}"))
+            .header("appVersion", APP_VERSION)
+            .header("clientId", &self.client_id)
+            .header("clientType", "1")
+            .header("iotVersion", "0")
+            .header("timestamp", ms_timestamp())
+            .header("User-Agent", user_agent())
+            .json(&json!({"sku": sku, "device": device, "deviceName": name}))
+            .send()
+            .await?;
+
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            self.invalidate_account_login().await;
+        }
+
+        let resp: StatusResponse = http_response_body(response).await?;
+        anyhow::ensure!(
+            resp.status == 200,
+            "failed to rename {sku} {device}: {}",
+            resp.message
+        );
+        Ok(())
+    }
+
+    /// Ask Govee whether newer firmware is available for the device,
+    /// given the versions that it is currently running
+    pub async fn check_firmware_update(
+        &self,
+        token: &str,
+        entry: &DeviceEntry,
+    ) -> anyhow::Result<FirmwareUpdateInfo> {
+        let response = self.ota_request(token, "check", entry).await?;
+        let resp: FirmwareUpdateResponse = http_response_body(response).await?;
+        anyhow::ensure!(
+            resp.status == 200,
+            "failed to check firmware for {} {}: {}",
+            entry.sku,
+            entry.device,
+            resp.message
+        );
+        Ok(resp.data)
+    }
+
+    /// Ask Govee to begin installing the latest firmware on the device.
+    /// The device downloads it over WiFi, so this only works for
+    /// devices that are online.
+    pub async fn start_firmware_update(
+        &self,
+        token: &str,
+        entry: &DeviceEntry,
+    ) -> anyhow::Result<()> {
+        let response = self.ota_request(token, "upgrade", entry).await?;
+        let resp: StatusResponse = http_response_body(response).await?;
+        anyhow::ensure!(
+            resp.status == 200,
+            "failed to start firmware update for {} {}: {}",
+            entry.sku,
+            entry.device,
+            resp.message
+        );
+        Ok(())
+    }
+
+    /// Returns the progress of a firmware update that was
+    /// started via start_firmware_update
+    pub async fn get_firmware_update_progress(
+        &self,
+        token: &str,
+        entry: &DeviceEntry,
+    ) -> anyhow::Result<FirmwareUpdateProgress> {
+        let response = self.ota_request(token, "progress", entry).await?;
+        let resp: FirmwareUpdateProgressResponse = http_response_body(response).await?;
+        anyhow::ensure!(
+            resp.status == 200,
+            "failed to get firmware update progress for {} {}: {}",
+            entry.sku,
+            entry.device,
+            resp.message
+        );
+        Ok(resp.data)
+    }
+
+    async fn ota_request(
+        &self,
+        token: &str,
+        action: &str,
+        entry: &DeviceEntry,
+    ) -> anyhow::Result<reqwest::Response> {
+        let response = reqwest::Client::builder()
+            .timeout(Duration::from_secs(30))
+            .build()?
+            .request(
+                Method::POST,
+                format!("https://app2.govee.com/device/rest/devices/v1/ota/{action}"),
+            )
+            .header("Authorization", format!("Bearer {token}"))
+            .header("appVersion", APP_VERSION)
+            .header("clientId", &self.client_id)
+            .header("clientType", "1")
+            .header("iotVersion", "0")
+            .header("timestamp", ms_timestamp())
+            .header("User-Agent", user_agent())
+            .json(&json!({
+                "sku": entry.sku,
+                "device": entry.device,
+                "versionSoft": entry.version_soft,
+                "versionHard": entry.version_hard,
+            }))
+            .send()
+            .await?;
+
+        if response.status() == reqwest::StatusCode::UNAUTHORIZED {
+            self.invalidate_account_login().await;
+        }
+        Ok(response)
+    }
+
     pub fn invalidate_community_login(&self) {
         crate::cache::invalidate_key("undoc-api", "community-login").ok();
     }
@@ -342,7 +682,7 @@ impl GoveeUndocumentedApi {
     pub async fn get_scenes_for_device(sku: &str) -> anyhow::Result<Vec<LightEffectCategory>> {
         let key = format!("scenes-{sku}");
 
-        cache_get(
+        let result = cache_get(
             CacheGetOptions {
                 topic: "undoc-api",
                 key: &key,
@@ -371,7 +711,18 @@ impl GoveeUndocumentedApi {
                 Ok(CacheComputeResult::Value(resp.data.categories))
             },
         )
-        .await
+        .await;
+
+        match result {
+            Ok(categories) => Ok(categories),
+            Err(err) => match bundled_scenes_for_sku(sku) {
+                Some(categories) => {
+                    log::warn!("Using the bundled scenes for {sku}: {err:#}");
+                    Ok(categories)
+                }
+                None => Err(err),
+            },
+        }
     }
 
     /// This is present primarily to workaround a bug where Govee aren't returning
@@ -762,6 +1113,124 @@ pub struct DevicesResponse {
     pub status: u16,
 }
 
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct StatusResponse {
+    pub message: String,
+    pub status: u16,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceSchedulesResponse {
+    pub data: DeviceScheduleList,
+    pub message: String,
+    pub status: u16,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceScheduleList {
+    #[serde(default)]
+    pub timers: Vec<DeviceSchedule>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FirmwareUpdateResponse {
+    pub data: FirmwareUpdateInfo,
+    pub message: String,
+    pub status: u16,
+}
+
+/// Describes the firmware that Govee offers for a device
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FirmwareUpdateInfo {
+    #[serde(default, deserialize_with = "boolean_int")]
+    pub need_update: bool,
+    /// The latest available software version
+    pub version_soft: Option<String>,
+    /// Release notes for the latest version
+    #[serde(default)]
+    pub update_desc: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct FirmwareUpdateProgressResponse {
+    pub data: FirmwareUpdateProgress,
+    pub message: String,
+    pub status: u16,
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct FirmwareUpdateProgress {
+    /// Percentage of the update that has been completed
+    pub progress: u8,
+    #[serde(default, rename = "fail", deserialize_with = "boolean_int")]
+    pub failed: bool,
+}
+
+/// A timer that was configured via the Govee Home app.
+/// The fields that we don't interpret are retained so that
+/// the timer can be sent back intact when it is updated.
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct DeviceSchedule {
+    pub timer_id: u64,
+    #[serde(default)]
+    pub name: String,
+    #[serde(
+        rename = "enable",
+        deserialize_with = "boolean_int",
+        serialize_with = "as_int"
+    )]
+    pub enabled: bool,
+    pub hour: u8,
+    pub minute: u8,
+    /// A bitmask of the days on which the timer runs, where
+    /// bit 0 is Monday. Zero means that it runs only once.
+    #[serde(default)]
+    pub repeat: u8,
+    /// Whether the timer turns the device on, rather than off
+    #[serde(
+        default,
+        rename = "open",
+        deserialize_with = "boolean_int",
+        serialize_with = "as_int"
+    )]
+    pub turn_on: bool,
+    #[serde(flatten)]
+    pub extra: serde_json::Map<String, JsonValue>,
+}
+
+impl DeviceSchedule {
+    const DAYS: [&'static str; 7] = [
+        "monday",
+        "tuesday",
+        "wednesday",
+        "thursday",
+        "friday",
+        "saturday",
+        "sunday",
+    ];
+
+    pub fn time(&self) -> String {
+        format!("{:02}:{:02}", self.hour, self.minute)
+    }
+
+    pub fn days(&self) -> Vec<&'static str> {
+        Self::DAYS
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| self.repeat & (1 << bit) != 0)
+            .map(|(_, day)| *day)
+            .collect()
+    }
+}
+
 #[derive(Deserialize, Debug)]
 #[serde(rename_all = "camelCase")]
 pub struct GroupEntry {
@@ -769,7 +1238,7 @@ pub struct GroupEntry {
     pub group_name: String,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 #[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
 pub struct DeviceEntry {
@@ -808,7 +1277,7 @@ impl DeviceEntry {
     }
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 #[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
 pub struct DeviceEntryExt {
@@ -820,7 +1289,7 @@ pub struct DeviceEntryExt {
     pub last_device_data: LastDeviceData,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 #[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
 pub struct DeviceSettings {
@@ -892,7 +1361,7 @@ pub struct DeviceSettings {
     pub support_ble_broad_v3: Option<bool>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 #[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
 pub struct ExtResources {
@@ -905,7 +1374,7 @@ pub struct ExtResources {
     pub ic: Option<u32>,
 }
 
-#[derive(Deserialize, Debug, Clone)]
+#[derive(Deserialize, Serialize, Debug, Clone)]
 #[serde(rename_all = "camelCase")]
 #[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
 pub struct LastDeviceData {
@@ -932,6 +1401,14 @@ where
     s.serialize(serializer)
 }
 
+/// Serializes a bool as the 0 or 1 that the app uses
+pub fn as_int<S>(value: &bool, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    serializer.serialize_u8(u8::from(*value))
+}
+
 pub fn embedded_json<'de, T: DeserializeOwned, D: serde::de::Deserializer<'de>>(
     deserializer: D,
 ) -> Result<T, D::Error> {
@@ -957,6 +1434,59 @@ mod test {
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
+    #[test]
+    fn firmware_update() {
+        let resp: FirmwareUpdateResponse =
+            from_json(include_str!("../test-data/undoc-firmware-check.json")).unwrap();
+        assert!(resp.data.need_update);
+        assert_eq!(resp.data.version_soft.as_deref(), Some("1.02.11"));
+        assert_eq!(
+            resp.data.update_desc.as_deref(),
+            Some("Improved stability of the WiFi connection")
+        );
+    }
+
+    #[test]
+    fn firmware_update_progress() {
+        let resp: FirmwareUpdateProgressResponse =
+            from_json(r#"{"data": {"progress": 45, "fail": 0}, "message": "", "status": 200}"#)
+                .unwrap();
+        assert_eq!(
+            resp.data,
+            FirmwareUpdateProgress {
+                progress: 45,
+                failed: false
+            }
+        );
+    }
+
+    #[test]
+    fn device_schedules() {
+        let resp: DeviceSchedulesResponse =
+            from_json(include_str!("../test-data/undoc-device-timers.json")).unwrap();
+        let timers = resp.data.timers;
+        assert_eq!(timers.len(), 2);
+
+        let morning = &timers[0];
+        assert!(morning.enabled && morning.turn_on);
+        assert_eq!(morning.time(), "06:30");
+        assert_eq!(
+            morning.days(),
+            vec!["monday", "tuesday", "wednesday", "thursday", "friday"]
+        );
+
+        let night = &timers[1];
+        assert!(!night.enabled && !night.turn_on);
+        assert_eq!(night.time(), "23:05");
+        assert!(night.days().is_empty());
+
+        // The fields we don't interpret must survive an update
+        let value = serde_json::to_value(morning).unwrap();
+        assert_eq!(value["enable"], 1);
+        assert_eq!(value["brightness"], 80);
+        assert_eq!(value["timeZone"], "America/Phoenix");
+    }
+
     #[test]
     fn get_one_click() {
         let resp: OneClickResponse =
@@ -978,6 +1508,16 @@ mod test {
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
+    #[test]
+    fn bundled_scene_libraries() {
+        for (sku, _) in BUNDLED_SCENE_LIBRARIES {
+            let categories = bundled_scenes_for_sku(sku).unwrap();
+            assert!(!categories.is_empty(), "{sku} has no scenes");
+        }
+        assert!(bundled_scenes_for_sku("h6072").is_some());
+        assert!(bundled_scenes_for_sku("H0000").is_none());
+    }
+
     #[test]
     fn issue_14() {
         let resp: DevicesResponse = from_json(include_str!("../test-data/issue14.json")).unwrap();